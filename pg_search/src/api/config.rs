@@ -13,6 +13,7 @@ pub fn field(
     expand_dots: default!(Option<bool>, "NULL"),
     tokenizer: default!(Option<JsonB>, "NULL"),
     normalizer: default!(Option<String>, "NULL"),
+    on_error: default!(Option<String>, "NULL"),
 ) -> JsonB {
     let mut config = Map::new();
 
@@ -24,6 +25,7 @@ pub fn field(
     expand_dots.map(|v| config.insert("expand_dots".to_string(), Value::Bool(v)));
     tokenizer.map(|v| config.insert("tokenizer".to_string(), v.0));
     normalizer.map(|v| config.insert("normalizer".to_string(), Value::String(v)));
+    on_error.map(|v| config.insert("on_error".to_string(), Value::String(v)));
 
     JsonB(json!({ name: config }))
 }
@@ -62,7 +64,8 @@ mod tests {
                 "record": "position",
                 "expand_dots": true,
                 "tokenizer": {"type": "ngram", "min_gram": 4, "max_gram": 4, "prefix_only": false},
-                "normalizer": "lowercase"
+                "normalizer": "lowercase",
+                "on_error": "skip"
             }
         });
 
@@ -76,6 +79,7 @@ mod tests {
             Some(true),
             Some(tokenizer("ngram", Some(4), Some(4), Some(false), None)),
             Some("lowercase".to_string()),
+            Some("skip".to_string()),
         );
 
         assert_eq!(expected, actual);
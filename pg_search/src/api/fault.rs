@@ -0,0 +1,62 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::{iter::TableIterator, *};
+
+use crate::index::fault::{FaultAction, FaultPoint, Faults};
+use crate::postgres::utils::bm25_index_name;
+use crate::writer::WriterDirectory;
+
+/// Make the next process that reaches `point` for the index fail there, either with an
+/// error or by crashing. The fault fires once. Only built with the `fault_injection` feature.
+#[pg_extern]
+pub fn inject_fault(index_name: &str, point: &str, action: default!(&str, "'crash'")) {
+    let point = point
+        .parse::<FaultPoint>()
+        .unwrap_or_else(|err| panic!("{err}"));
+    let action = action
+        .parse::<FaultAction>()
+        .unwrap_or_else(|err| panic!("{err}"));
+    Faults::inject(&fault_directory(index_name), point, action)
+        .unwrap_or_else(|err| panic!("error injecting fault into index '{index_name}': {err}"));
+}
+
+#[pg_extern]
+pub fn clear_faults(index_name: &str) {
+    Faults::clear(&fault_directory(index_name))
+        .unwrap_or_else(|err| panic!("error clearing faults of index '{index_name}': {err}"));
+}
+
+/// The faults injected into an index that haven't fired yet.
+#[pg_extern]
+pub fn index_faults(
+    index_name: &str,
+) -> TableIterator<'static, (name!(point, String), name!(action, String))> {
+    let faults = Faults::load(&fault_directory(index_name))
+        .unwrap_or_else(|err| panic!("error loading faults of index '{index_name}': {err}"));
+    TableIterator::new(
+        faults
+            .0
+            .into_iter()
+            .map(|(point, action)| (point.name().to_string(), action.name().to_string())),
+    )
+}
+
+fn fault_directory(index_name: &str) -> WriterDirectory {
+    let bm25_index_name = bm25_index_name(index_name);
+    WriterDirectory::from_index_name(&bm25_index_name)
+}
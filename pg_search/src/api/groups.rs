@@ -0,0 +1,94 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+
+use crate::index::SearchIndex;
+use crate::postgres::utils::bm25_index_name;
+use crate::query::groups::{FieldGroup, ALL_FIELDS_GROUP};
+use crate::schema::SearchFieldType;
+use crate::writer::WriterDirectory;
+
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.field_groups (
+    index_name text NOT NULL,
+    name text NOT NULL,
+    fields jsonb NOT NULL,
+    PRIMARY KEY (index_name, name)
+);
+
+SELECT pg_catalog.pg_extension_config_dump('paradedb.field_groups', '');
+"#,
+    name = "field_groups_table"
+);
+
+/// Save `fields` as the field group `name` of the index, replacing any group saved under it
+/// before. `fields` maps each text field of the group to its boost. Queries on the group
+/// search each of its fields, and score as the best of them.
+#[pg_extern]
+pub fn save_field_group(index_name: &str, name: &str, fields: JsonB) {
+    let JsonB(fields_json) = fields;
+    let group: FieldGroup = serde_json::from_value(fields_json.clone())
+        .unwrap_or_else(|err| panic!("invalid field group '{name}': {err}"));
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    let schema = &search_index.schema;
+    if name == ALL_FIELDS_GROUP || schema.get_search_field(name).is_some() {
+        panic!("cannot name a field group '{name}', the index already searches it");
+    }
+    if group.is_empty() {
+        panic!("field group '{name}' must have at least one field");
+    }
+    for (field, boost) in &group {
+        let is_text = schema
+            .get_search_field(field.as_str())
+            .is_some_and(|field| field.type_ == SearchFieldType::Text);
+        if !is_text {
+            panic!("cannot group '{field}', it is not a text field of index {index_name}");
+        }
+        if *boost <= 0.0 {
+            panic!("the boost of '{field}' must be positive");
+        }
+    }
+
+    Spi::run(&format!(
+        "INSERT INTO paradedb.field_groups (index_name, name, fields) \
+         VALUES ({}, {}, {}::jsonb) \
+         ON CONFLICT (index_name, name) DO UPDATE SET fields = EXCLUDED.fields",
+        spi::quote_literal(&bm25_index_name),
+        spi::quote_literal(name),
+        spi::quote_literal(fields_json.to_string()),
+    ))
+    .unwrap_or_else(|err| panic!("error saving field group '{name}': {err}"));
+}
+
+#[pg_extern]
+pub fn drop_field_group(index_name: &str, name: &str) -> bool {
+    Spi::get_one::<bool>(&format!(
+        "WITH deleted AS (DELETE FROM paradedb.field_groups \
+         WHERE index_name = {} AND name = {} RETURNING 1) \
+         SELECT count(*) > 0 FROM deleted",
+        spi::quote_literal(bm25_index_name(index_name)),
+        spi::quote_literal(name)
+    ))
+    .unwrap_or_else(|err| panic!("error dropping field group '{name}': {err}"))
+    .unwrap_or_default()
+}
@@ -19,7 +19,8 @@ use pgrx::{iter::TableIterator, *};
 use tantivy::schema::*;
 
 use crate::index::SearchIndex;
-use crate::postgres::types::TantivyValue;
+use crate::postgres::types::{TantivyValue, TantivyValueError};
+use crate::postgres::utils::bm25_index_name;
 use crate::query::SearchQueryInput;
 use crate::schema::ToString;
 use crate::writer::WriterDirectory;
@@ -42,7 +43,7 @@ pub fn schema_bm25(
     name!(record, Option<String>),
     name!(normalizer, Option<String>),
 )> {
-    let bm25_index_name = format!("{}_bm25_index", index_name);
+    let bm25_index_name = bm25_index_name(index_name);
     let directory = WriterDirectory::from_index_name(&bm25_index_name);
     let search_index = SearchIndex::from_disk(&directory)
         .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
@@ -91,6 +92,7 @@ pub fn schema_bm25(
                     )
                 }
                 FieldType::Date(_) => ("Date".to_string(), None, None, None, None),
+                FieldType::Bytes(_) => ("Vector".to_string(), None, None, None, None),
                 _ => ("Other".to_string(), None, None, None, None),
             };
 
@@ -152,6 +154,14 @@ pub fn boost(boost: f32, query: SearchQueryInput) -> SearchQueryInput {
     }
 }
 
+#[pg_extern(immutable, parallel_safe)]
+pub fn named(name: String, query: SearchQueryInput) -> SearchQueryInput {
+    SearchQueryInput::Named {
+        name,
+        query: Box::new(query),
+    }
+}
+
 #[pg_extern(immutable, parallel_safe)]
 pub fn const_score(score: f32, query: SearchQueryInput) -> SearchQueryInput {
     SearchQueryInput::ConstScore {
@@ -176,33 +186,48 @@ pub fn empty() -> SearchQueryInput {
     SearchQueryInput::Empty
 }
 
-// Not clear on whether this query makes sense to support, as only our "key_field" is a fast
-// field... and the user can just use SQL to select a range. We'll keep the implementation here
-// for now, but we should remove when we decide definitively that we don't need this.
-#[allow(unused)]
-pub fn fast_field_range_weight(field: String, range: pgrx::Range<i32>) -> SearchQueryInput {
-    match range.into_inner() {
-        None => SearchQueryInput::FastFieldRangeWeight {
-            field,
-            lower_bound: Bound::Included(0),
-            upper_bound: Bound::Excluded(0),
-        },
-        Some((lower, upper)) => SearchQueryInput::FastFieldRangeWeight {
-            field,
-            lower_bound: match lower {
-                RangeBound::Infinite => Bound::Unbounded,
-                RangeBound::Inclusive(n) => Bound::Included(n as u64),
-                RangeBound::Exclusive(n) => Bound::Excluded(n as u64),
-            },
-            upper_bound: match upper {
+macro_rules! fast_field_range_fn {
+    ($func_name:ident, $value_type:ty, $to_value:expr) => {
+        #[pg_extern(name = "fast_field_range_weight", immutable, parallel_safe)]
+        pub fn $func_name(field: String, range: Range<$value_type>) -> SearchQueryInput {
+            let to_bound = |bound: RangeBound<$value_type>| match bound {
                 RangeBound::Infinite => Bound::Unbounded,
-                RangeBound::Inclusive(n) => Bound::Included(n as u64),
-                RangeBound::Exclusive(n) => Bound::Excluded(n as u64),
-            },
-        },
-    }
+                RangeBound::Inclusive(n) => Bound::Included(($to_value)(field.as_str(), n)),
+                RangeBound::Exclusive(n) => Bound::Excluded(($to_value)(field.as_str(), n)),
+            };
+            match range.into_inner() {
+                None => SearchQueryInput::Empty,
+                Some((lower, upper)) => SearchQueryInput::FastFieldRangeWeight {
+                    lower_bound: to_bound(lower),
+                    upper_bound: to_bound(upper),
+                    field,
+                },
+            }
+        }
+    };
 }
 
+fast_field_range_fn!(fast_field_range_weight_i32, i32, |_, n| OwnedValue::I64(
+    i64::from(n)
+));
+fast_field_range_fn!(fast_field_range_weight_i64, i64, |_, n| OwnedValue::I64(n));
+fast_field_range_fn!(
+    fast_field_range_weight_numeric,
+    pgrx::AnyNumeric,
+    numeric_value
+);
+fast_field_range_fn!(fast_field_range_weight_date, pgrx::Date, datetime_value);
+fast_field_range_fn!(
+    fast_field_range_weight_timestamp,
+    pgrx::Timestamp,
+    datetime_value
+);
+fast_field_range_fn!(
+    fast_field_range_weight_timestamptz,
+    pgrx::TimestampWithTimeZone,
+    datetime_value
+);
+
 #[pg_extern(immutable, parallel_safe)]
 pub fn fuzzy_term(
     field: String,
@@ -210,6 +235,7 @@ pub fn fuzzy_term(
     distance: default!(Option<i32>, "NULL"),
     transposition_cost_one: default!(Option<bool>, "NULL"),
     prefix: default!(Option<bool>, "NULL"),
+    max_expansions: default!(Option<i32>, "NULL"),
 ) -> SearchQueryInput {
     SearchQueryInput::FuzzyTerm {
         field,
@@ -217,15 +243,23 @@ pub fn fuzzy_term(
         distance: distance.map(|n| n as u8),
         transposition_cost_one,
         prefix,
+        max_expansions: max_expansions.map(|n| n as u32),
     }
 }
 
-// Avoid exposing more_like_this for now until we can decide on the exact API.
-// Lucene and Elasticsearch seem to have different interfaces for this query,
-// and Tantivy doesn't have any examples of its use, so its unclear what the best
-// way to use it is with pg_search.
-#[allow(unused)]
+#[pg_extern(immutable, parallel_safe)]
+pub fn key_in(field: String, index_name: String, query: SearchQueryInput) -> SearchQueryInput {
+    SearchQueryInput::KeyIn {
+        field,
+        index: index_name,
+        query: Box::new(query),
+    }
+}
+
+/// Matches documents that share the most distinctive terms of `fields`, which are given as
+/// `paradedb.term` queries, like the field values of a document to find the peers of.
 #[allow(clippy::too_many_arguments)]
+#[pg_extern(immutable, parallel_safe)]
 pub fn more_like_this(
     min_doc_frequency: default!(Option<i32>, "NULL"),
     max_doc_frequency: default!(Option<i32>, "NULL"),
@@ -254,9 +288,76 @@ pub fn more_like_this(
     }
 }
 
+/// `more_like_this` with the field values given as a JSON object, like a row passed through
+/// `to_jsonb`. An array searches each of its values, and nulls are left out. The values are
+/// converted to the type of their field when the query is built, so dates are given as text.
+#[allow(clippy::too_many_arguments)]
+#[pg_extern(name = "more_like_this", immutable, parallel_safe)]
+pub fn more_like_this_jsonb(
+    fields: JsonB,
+    min_doc_frequency: default!(Option<i32>, "NULL"),
+    max_doc_frequency: default!(Option<i32>, "NULL"),
+    min_term_frequency: default!(Option<i32>, "NULL"),
+    max_query_terms: default!(Option<i32>, "NULL"),
+    min_word_length: default!(Option<i32>, "NULL"),
+    max_word_length: default!(Option<i32>, "NULL"),
+    boost_factor: default!(Option<f32>, "NULL"),
+    stop_words: default!(Option<Vec<String>>, "NULL"),
+) -> SearchQueryInput {
+    let JsonB(serde_json::Value::Object(fields)) = fields else {
+        panic!("the fields of more_like_this must be a json object");
+    };
+    let mut values = vec![];
+    for (field, value) in fields {
+        match value {
+            serde_json::Value::Array(elements) => {
+                for element in elements {
+                    values.extend(json_term_value(&field, element).map(|v| (field.clone(), v)));
+                }
+            }
+            value => values.extend(json_term_value(&field, value).map(|v| (field.clone(), v))),
+        }
+    }
+    SearchQueryInput::MoreLikeThis {
+        min_doc_frequency: min_doc_frequency.map(|n| n as u64),
+        max_doc_frequency: max_doc_frequency.map(|n| n as u64),
+        min_term_frequency: min_term_frequency.map(|n| n as usize),
+        max_query_terms: max_query_terms.map(|n| n as usize),
+        min_word_length: min_word_length.map(|n| n as usize),
+        max_word_length: max_word_length.map(|n| n as usize),
+        boost_factor,
+        stop_words,
+        fields: values,
+    }
+}
+
+/// The term value of a scalar json value of `field`, or `None` for a null.
+fn json_term_value(field: &str, value: serde_json::Value) -> Option<Value> {
+    match value {
+        serde_json::Value::Null => None,
+        serde_json::Value::Bool(bool) => Some(Value::Bool(bool)),
+        serde_json::Value::String(text) => Some(Value::Str(text)),
+        serde_json::Value::Number(number) => Some(
+            number
+                .as_u64()
+                .map(Value::U64)
+                .or_else(|| number.as_i64().map(Value::I64))
+                .or_else(|| number.as_f64().map(Value::F64))
+                .unwrap_or_else(|| panic!("'{number}' of field '{field}' is out of range")),
+        ),
+        _ => panic!("the value of field '{field}' must be a scalar or an array of scalars"),
+    }
+}
+
 #[pg_extern(immutable, parallel_safe)]
-pub fn parse(query_string: String) -> SearchQueryInput {
-    SearchQueryInput::Parse { query_string }
+pub fn parse(
+    query_string: String,
+    conjunction: default!(Option<bool>, "NULL"),
+) -> SearchQueryInput {
+    SearchQueryInput::Parse {
+        query_string,
+        conjunction,
+    }
 }
 
 #[pg_extern(immutable, parallel_safe)]
@@ -342,21 +443,31 @@ pub fn range_numeric(field: String, range: Range<pgrx::AnyNumeric>) -> SearchQue
             upper_bound: Bound::Excluded(OwnedValue::F64(0.0)),
         },
         Some((lower, upper)) => SearchQueryInput::Range {
-            field,
             lower_bound: match lower {
                 RangeBound::Infinite => Bound::Unbounded,
-                RangeBound::Inclusive(n) => Bound::Included(OwnedValue::F64(n.try_into().unwrap())),
-                RangeBound::Exclusive(n) => Bound::Excluded(OwnedValue::F64(n.try_into().unwrap())),
+                RangeBound::Inclusive(n) => Bound::Included(numeric_value(&field, n)),
+                RangeBound::Exclusive(n) => Bound::Excluded(numeric_value(&field, n)),
             },
             upper_bound: match upper {
                 RangeBound::Infinite => Bound::Unbounded,
-                RangeBound::Inclusive(n) => Bound::Included(OwnedValue::F64(n.try_into().unwrap())),
-                RangeBound::Exclusive(n) => Bound::Excluded(OwnedValue::F64(n.try_into().unwrap())),
+                RangeBound::Inclusive(n) => Bound::Included(numeric_value(&field, n)),
+                RangeBound::Exclusive(n) => Bound::Excluded(numeric_value(&field, n)),
             },
+            field,
         },
     }
 }
 
+/// A numeric bound of `field` as the float that numeric fields are indexed as. NaN and values
+/// too large for a float can't bound a range.
+fn numeric_value(field: &str, n: pgrx::AnyNumeric) -> OwnedValue {
+    let text = n.to_string();
+    match f64::try_from(n) {
+        Ok(value) if value.is_finite() => OwnedValue::F64(value),
+        _ => panic!("numeric bound {text} of field '{field}' can't be converted to a float"),
+    }
+}
+
 macro_rules! datetime_range_fn {
     ($func_name:ident, $value_type:ty) => {
         #[pg_extern(name = "range", immutable, parallel_safe)]
@@ -372,37 +483,17 @@ macro_rules! datetime_range_fn {
                     )),
                 },
                 Some((lower, upper)) => SearchQueryInput::Range {
-                    field,
                     lower_bound: match lower {
                         RangeBound::Infinite => Bound::Unbounded,
-                        RangeBound::Inclusive(n) => Bound::Included(
-                            (&TantivyValue::try_from(n).unwrap().tantivy_schema_value())
-                                .as_datetime()
-                                .unwrap()
-                                .into(),
-                        ),
-                        RangeBound::Exclusive(n) => Bound::Excluded(
-                            (&TantivyValue::try_from(n).unwrap().tantivy_schema_value())
-                                .as_datetime()
-                                .unwrap()
-                                .into(),
-                        ),
+                        RangeBound::Inclusive(n) => Bound::Included(datetime_value(&field, n)),
+                        RangeBound::Exclusive(n) => Bound::Excluded(datetime_value(&field, n)),
                     },
                     upper_bound: match upper {
                         RangeBound::Infinite => Bound::Unbounded,
-                        RangeBound::Inclusive(n) => Bound::Included(
-                            (&TantivyValue::try_from(n).unwrap().tantivy_schema_value())
-                                .as_datetime()
-                                .unwrap()
-                                .into(),
-                        ),
-                        RangeBound::Exclusive(n) => Bound::Excluded(
-                            (&TantivyValue::try_from(n).unwrap().tantivy_schema_value())
-                                .as_datetime()
-                                .unwrap()
-                                .into(),
-                        ),
+                        RangeBound::Inclusive(n) => Bound::Included(datetime_value(&field, n)),
+                        RangeBound::Exclusive(n) => Bound::Excluded(datetime_value(&field, n)),
                     },
+                    field,
                 },
             }
         }
@@ -413,9 +504,120 @@ datetime_range_fn!(range_date, pgrx::Date);
 datetime_range_fn!(range_timestamp, pgrx::Timestamp);
 datetime_range_fn!(range_timestamptz, pgrx::TimestampWithTimeZone);
 
+/// Whether the lower and upper bounds of `paradedb.range` are inclusive, from `bounds` written
+/// like the bounds of a Postgres range, such as `'[)'`.
+fn inclusive_bounds(bounds: &str) -> (bool, bool) {
+    match bounds {
+        "[)" => (true, false),
+        "[]" => (true, true),
+        "(]" => (false, true),
+        "()" => (false, false),
+        _ => panic!("invalid range bounds '{bounds}', use '[)', '[]', '(]' or '()'"),
+    }
+}
+
+/// The bound of a `paradedb.range` value, which is unbounded when the value is NULL.
+fn range_bound(value: Option<OwnedValue>, inclusive: bool) -> Bound<OwnedValue> {
+    match value {
+        None => Bound::Unbounded,
+        Some(value) if inclusive => Bound::Included(value),
+        Some(value) => Bound::Excluded(value),
+    }
+}
+
+macro_rules! bounds_range_fn {
+    ($func_name:ident, $value_type:ty, $to_value:expr) => {
+        #[pg_extern(name = "range", immutable, parallel_safe)]
+        pub fn $func_name(
+            field: String,
+            low: Option<$value_type>,
+            high: Option<$value_type>,
+            bounds: default!(String, "'[)'"),
+        ) -> SearchQueryInput {
+            let (lower_inclusive, upper_inclusive) = inclusive_bounds(&bounds);
+            let to_value = |value| ($to_value)(field.as_str(), value);
+            SearchQueryInput::Range {
+                lower_bound: range_bound(low.map(to_value), lower_inclusive),
+                upper_bound: range_bound(high.map(to_value), upper_inclusive),
+                field,
+            }
+        }
+    };
+}
+
+bounds_range_fn!(range_bounds_i32, i32, |_, n| OwnedValue::I64(n as i64));
+bounds_range_fn!(range_bounds_i64, i64, |_, n| OwnedValue::I64(n));
+bounds_range_fn!(range_bounds_numeric, pgrx::AnyNumeric, numeric_value);
+// Text bounds are read for the type of the field when the query is built. Untyped NULLs
+// resolve to this overload, so `paradedb.range('rating', NULL, NULL)` isn't ambiguous.
+bounds_range_fn!(range_bounds_text, String, |_, text| OwnedValue::Str(text));
+bounds_range_fn!(range_bounds_date, pgrx::Date, datetime_value);
+bounds_range_fn!(range_bounds_timestamp, pgrx::Timestamp, datetime_value);
+bounds_range_fn!(
+    range_bounds_timestamptz,
+    pgrx::TimestampWithTimeZone,
+    datetime_value
+);
+
+/// A date or timestamp bound of `field` as the datetime that date fields are indexed as.
+fn datetime_value<T>(field: &str, value: T) -> OwnedValue
+where
+    TantivyValue: TryFrom<T, Error = TantivyValueError>,
+{
+    let value = TantivyValue::try_from(value)
+        .unwrap_or_else(|err| panic!("bound of field '{field}' is not a valid date: {err}"));
+    (&value.tantivy_schema_value())
+        .as_datetime()
+        .expect("dates should convert to datetimes")
+        .into()
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn regex(
+    field: String,
+    pattern: String,
+    max_expansions: default!(Option<i32>, "NULL"),
+) -> SearchQueryInput {
+    SearchQueryInput::Regex {
+        field,
+        pattern,
+        max_expansions: max_expansions.map(|n| n as u32),
+    }
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn rerank_by_vector(
+    query: SearchQueryInput,
+    vector: Array<f32>,
+    metric: default!(String, "'cosine'"),
+    top_n: default!(i32, 100),
+    field: default!(Option<String>, "NULL"),
+) -> SearchQueryInput {
+    let vector: Vec<f32> = vector.iter_deny_null().collect();
+    if vector.is_empty() {
+        panic!("the vector to rerank by cannot be empty");
+    }
+    if top_n < 1 {
+        panic!("top_n must be at least 1, got {top_n}");
+    }
+    let metric = metric
+        .parse()
+        .unwrap_or_else(|_| panic!("unknown vector metric '{metric}', use 'cosine' or 'dot'"));
+
+    SearchQueryInput::RerankByVector {
+        query: Box::new(query),
+        field,
+        vector,
+        metric,
+        top_n: top_n as usize,
+    }
+}
+
 #[pg_extern(immutable, parallel_safe)]
-pub fn regex(field: String, pattern: String) -> SearchQueryInput {
-    SearchQueryInput::Regex { field, pattern }
+pub fn sparse_vector(field: String, weights: JsonB) -> SearchQueryInput {
+    let weights = crate::schema::sparse_weights_from_json(&weights.0)
+        .unwrap_or_else(|err| panic!("invalid sparse vector: {err}"));
+    SearchQueryInput::SparseVector { field, weights }
 }
 
 macro_rules! term_fn {
@@ -505,3 +707,41 @@ pub fn term_set(
 
     SearchQueryInput::TermSet { terms }
 }
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn query_and(left: SearchQueryInput, right: SearchQueryInput) -> SearchQueryInput {
+    left & right
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn query_or(left: SearchQueryInput, right: SearchQueryInput) -> SearchQueryInput {
+    left | right
+}
+
+#[pg_extern(immutable, parallel_safe)]
+pub fn query_not(query: SearchQueryInput) -> SearchQueryInput {
+    !query
+}
+
+extension_sql!(
+    r#"
+CREATE OPERATOR pg_catalog.&& (
+    PROCEDURE = query_and,
+    LEFTARG = searchqueryinput,
+    RIGHTARG = searchqueryinput
+);
+
+CREATE OPERATOR pg_catalog.|| (
+    PROCEDURE = query_or,
+    LEFTARG = searchqueryinput,
+    RIGHTARG = searchqueryinput
+);
+
+CREATE OPERATOR pg_catalog.!! (
+    PROCEDURE = query_not,
+    RIGHTARG = searchqueryinput
+);
+"#,
+    name = "searchqueryinput_operators",
+    requires = [query_and, query_or, query_not]
+);
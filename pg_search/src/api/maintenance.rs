@@ -0,0 +1,1240 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::{iter::TableIterator, *};
+
+use crate::env::postgres_data_dir_path;
+use crate::globals::{
+    WriterGlobal, COLD_TIER_READS, COLD_TIER_READ_NANOS, HOT_TIER_READS, HOT_TIER_READ_NANOS,
+    QUERY_CACHE_HITS, QUERY_CACHE_MISSES, QUERY_STATS, SEARCHES,
+};
+use crate::index::backup::IndexBackup;
+use crate::index::check::IndexCheck;
+use crate::index::export::{ExportFormat, IndexExport};
+use crate::index::heal::HealStatus;
+use crate::index::merge::{Maintenance, MergeStatus};
+use crate::index::orphan::{find_orphans, OrphanReason};
+use crate::index::progress::BuildProgress;
+use crate::index::readonly::ReadOnly;
+use crate::index::remote::{StorageTier, TierUsage};
+use crate::index::report::IndexReport;
+use crate::index::stats::{SearchIndexStats, WriterStatus};
+use crate::index::SearchIndex;
+use crate::postgres::alter::sync_options;
+use crate::postgres::follower::Follower;
+use crate::postgres::heal::heal_next_blocks;
+use crate::postgres::options::SearchIndexCreateOptions;
+use crate::postgres::resync::resync;
+use crate::postgres::source::refresh_source;
+use crate::postgres::utils::{bm25_index_name, index_name_of};
+use crate::postgres::wait::SearchWaitEvent;
+use crate::query::stats::{discard_pending_searches, flush_pending_searches};
+use crate::query::SearchQueryInput;
+use crate::schema::SearchFieldType;
+use crate::writer::WriterDirectory;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+/// Merge the segments of an index until at most `max_segments` remain. Unless `wait` is
+/// set, the merge runs in the background and can be followed with `merge_status`. Returns the
+/// number of segments before the merge, and after it if `wait` is set. Only one merge of an
+/// index is requested at a time, a second one waits for the first to commit.
+#[pg_extern]
+pub fn force_merge(
+    index_name: &str,
+    max_segments: default!(i32, 1),
+    wait: default!(bool, false),
+) -> TableIterator<
+    'static,
+    (
+        name!(segments_before, i64),
+        name!(segments_after, Option<i64>),
+    ),
+> {
+    if max_segments < 1 {
+        panic!("max_segments must be at least 1, got {max_segments}");
+    }
+
+    let index_relation = owned_bm25_index_relation(
+        index_name,
+        pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE,
+    );
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    let num_segments = |search_index: &SearchIndex| {
+        search_index
+            .reader
+            .reload()
+            .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+        search_index.searcher().segment_readers().len() as i64
+    };
+
+    let segments_before = num_segments(search_index);
+    search_index
+        .force_merge(&WriterGlobal::client(), max_segments as usize, wait)
+        .unwrap_or_else(|err| panic!("error merging index '{index_name}': {err}"));
+    let segments_after = wait.then(|| num_segments(search_index));
+    TableIterator::once((segments_before, segments_after))
+}
+
+/// Commit an index right away and reload its reader, so that the documents of committed
+/// transactions are searchable without waiting on its `refresh_interval`, as after a bulk
+/// load. Documents inserted by the calling transaction are still only sent when it commits.
+/// Returns the opstamp of the commit, see `index_generation`.
+#[pg_extern]
+pub fn refresh_index(index_name: &str) -> i64 {
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .refresh(&WriterGlobal::client())
+        .unwrap_or_else(|err| panic!("error refreshing index '{index_name}': {err}"));
+    search_index
+        .underlying_index
+        .load_metas()
+        .unwrap_or_else(|err| panic!("error reading metadata of '{index_name}': {err}"))
+        .opstamp as i64
+}
+
+/// Stop merging the segments of an index in the background, until `resume_maintenance`
+/// is called. Explicit calls to `force_merge` still go through.
+#[pg_extern]
+pub fn pause_maintenance(index_name: &str) {
+    let index_relation =
+        owned_bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    Maintenance::pause(&directory)
+        .unwrap_or_else(|err| panic!("error pausing maintenance of index '{index_name}': {err}"));
+}
+
+#[pg_extern]
+pub fn resume_maintenance(index_name: &str) {
+    let index_relation =
+        owned_bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    Maintenance::resume(&directory)
+        .unwrap_or_else(|err| panic!("error resuming maintenance of index '{index_name}': {err}"));
+}
+
+/// Reject changes to the documents of an index, while it can still be searched, until
+/// it's called again with `readonly` set to false.
+#[pg_extern]
+pub fn set_index_readonly(index_name: &str, readonly: bool) {
+    let index_relation =
+        owned_bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    ReadOnly::set(&directory, readonly)
+        .unwrap_or_else(|err| panic!("error setting index '{index_name}' read-only: {err}"));
+}
+
+/// Change the options of an index that don't affect its documents, with immediate effect.
+/// The same options can be changed with `ALTER INDEX ... SET`, in which case they take
+/// effect the next time the index is used. Options left NULL are unchanged.
+#[pg_extern]
+pub fn alter_index_options(
+    index_name: &str,
+    merge_policy: default!(Option<String>, "NULL"),
+    refresh_interval: default!(Option<i32>, "NULL"),
+    writer_memory_budget: default!(Option<i32>, "NULL"),
+    max_index_size: default!(Option<i32>, "NULL"),
+) {
+    let options = [
+        (
+            "merge_policy",
+            merge_policy.map(|policy| spi::quote_literal(&policy)),
+        ),
+        (
+            "refresh_interval",
+            refresh_interval.map(|millis| millis.to_string()),
+        ),
+        (
+            "writer_memory_budget",
+            writer_memory_budget.map(|mb| mb.to_string()),
+        ),
+        ("max_index_size", max_index_size.map(|mb| mb.to_string())),
+    ]
+    .into_iter()
+    .filter_map(|(name, value)| Some(format!("{name} = {}", value?)))
+    .collect::<Vec<_>>();
+    if options.is_empty() {
+        panic!("no options to alter for index '{index_name}'");
+    }
+
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    Spi::run(&format!(
+        "ALTER INDEX {}.{} SET ({})",
+        spi::quote_identifier(index_relation.namespace()),
+        spi::quote_identifier(index_relation.name()),
+        options.join(", ")
+    ))
+    .unwrap_or_else(|err| panic!("error altering index '{index_name}': {err}"));
+
+    // The relation is opened again to read the new options.
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let rdopts =
+        unsafe { PgBox::from_pg(index_relation.rd_options as *mut SearchIndexCreateOptions) };
+    if rdopts.get_writer_memory_budget() > 0 && rdopts.get_refresh_interval() == 0 {
+        panic!("writer_memory_budget requires a refresh_interval")
+    }
+    sync_options(&index_relation);
+}
+
+/// Read an index ahead of the first queries, after a restart or a failover. Defaults to
+/// the term dictionaries and fast fields of every field, along with the stored documents.
+/// Returns the bytes of fast fields and stored documents read.
+#[pg_extern]
+pub fn warm_index(
+    index_name: &str,
+    fields: default!(Option<Vec<String>>, "NULL"),
+    docstore: default!(bool, true),
+) -> i64 {
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+    search_index
+        .warm(fields.as_deref(), docstore)
+        .unwrap_or_else(|err| panic!("error warming index '{index_name}': {err}")) as i64
+}
+
+/// Rebuild an index from its table, for an index that drifted from it, like after restoring
+/// the data directory from a file system snapshot. Returns the number of rows indexed.
+#[pg_extern]
+pub fn resync_index(index_name: &str) -> i64 {
+    if unsafe { pg_sys::RecoveryInProgress() } {
+        panic!("cannot resync index '{index_name}' during recovery");
+    }
+
+    // Only one connection rebuilds an index at a time, see `resync_if_needed`.
+    let index_relation = owned_bm25_index_relation(
+        index_name,
+        pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE,
+    );
+    resync(&index_relation) as i64
+}
+
+/// Copy the rows of the source of an index built from a foreign table, view or materialized
+/// view that were modified since the last refresh, or all of them if `full`, and return the
+/// number of rows copied. Rows deleted from the source are only removed by a full refresh.
+#[pg_extern]
+pub fn refresh_bm25(index_name: &str, full: default!(bool, false)) -> i64 {
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::RowExclusiveLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    refresh_source(&directory, full) as i64
+}
+
+/// Compare every row of the table of an index with the index right away, rather than waiting
+/// on the heal workers, and reindex the rows it's missing when the transaction commits.
+#[pg_extern]
+pub fn heal_index(
+    index_name: &str,
+) -> TableIterator<'static, (name!(rows_checked, i64), name!(rows_repaired, i64))> {
+    let index_relation =
+        owned_bm25_index_relation(index_name, pg_sys::RowExclusiveLock as pg_sys::LOCKMODE);
+
+    // The pass starts over from the first block of the table.
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let status = HealStatus::load(&directory)
+        .unwrap_or_else(|err| panic!("error loading heal status: {err}"));
+    HealStatus {
+        next_block: 0,
+        ..status
+    }
+    .save(&directory)
+    .unwrap_or_else(|err| panic!("error saving heal status: {err}"));
+
+    let (mut rows_checked, mut rows_repaired) = (0, 0);
+    loop {
+        let step = heal_next_blocks(&index_relation);
+        rows_checked += step.rows_checked;
+        rows_repaired += step.rows_repaired;
+        if step.pass_completed {
+            break;
+        }
+    }
+    TableIterator::once((rows_checked as i64, rows_repaired as i64))
+}
+
+// The connection strings of followers may hold passwords, so the table isn't readable by
+// other roles.
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.followers (
+    index_name text PRIMARY KEY,
+    conninfo text NOT NULL,
+    slot_name text NOT NULL,
+    publication text NOT NULL,
+    applied_lsn pg_lsn,
+    applied_at timestamptz
+);
+
+SELECT pg_catalog.pg_extension_config_dump('paradedb.followers', '');
+"#,
+    name = "followers_table"
+);
+
+/// Make an index follow a table of another server: the changes to that table, read from the
+/// logical replication slot `slot_name` of the server through `dblink`, are applied to the
+/// table of the index by the follower workers. The slot must use the `pgoutput` plugin and
+/// `publication` must include the table.
+#[pg_extern]
+pub fn create_follower(index_name: &str, conninfo: &str, slot_name: &str, publication: &str) {
+    // Only checks that the index exists.
+    bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+
+    let dblink_installed =
+        Spi::get_one::<bool>("SELECT EXISTS (SELECT 1 FROM pg_extension WHERE extname = 'dblink')")
+            .unwrap_or_else(|err| panic!("error checking for the dblink extension: {err}"))
+            .unwrap_or_default();
+    if !dblink_installed {
+        panic!("followers read changes through dblink, run CREATE EXTENSION dblink first");
+    }
+
+    Spi::run(&format!(
+        "INSERT INTO paradedb.followers (index_name, conninfo, slot_name, publication) \
+         VALUES ({}, {}, {}, {}) \
+         ON CONFLICT (index_name) DO UPDATE SET conninfo = EXCLUDED.conninfo, \
+         slot_name = EXCLUDED.slot_name, publication = EXCLUDED.publication, \
+         applied_lsn = NULL, applied_at = NULL",
+        spi::quote_literal(index_name),
+        spi::quote_literal(conninfo),
+        spi::quote_literal(slot_name),
+        spi::quote_literal(publication),
+    ))
+    .unwrap_or_else(|err| panic!("error creating follower of '{index_name}': {err}"));
+}
+
+#[pg_extern]
+pub fn drop_follower(index_name: &str) -> bool {
+    Spi::get_one::<bool>(&format!(
+        "WITH deleted AS (DELETE FROM paradedb.followers WHERE index_name = {} RETURNING 1) \
+         SELECT count(*) > 0 FROM deleted",
+        spi::quote_literal(index_name)
+    ))
+    .unwrap_or_else(|err| panic!("error dropping follower of '{index_name}': {err}"))
+    .unwrap_or_default()
+}
+
+/// Apply the next changes of the replication slot of a follower right away, rather than
+/// waiting on the follower workers. Returns the number of rows changed, which are indexed
+/// when the transaction commits.
+#[pg_extern]
+pub fn follow_index(index_name: &str) -> i64 {
+    let follower = Follower::load(index_name)
+        .unwrap_or_else(|| panic!("index '{index_name}' has no follower, see create_follower"));
+    follower.follow() as i64
+}
+
+/// Copy the committed segments of an index to a directory of the database server, which
+/// must not exist or be empty. Returns the number of documents backed up.
+#[pg_extern]
+pub fn backup_index(index_name: &str, path: &str) -> i64 {
+    check_server_path(path, "back up or restore an index");
+
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    IndexBackup::backup(search_index, Path::new(path))
+        .unwrap_or_else(|err| panic!("error backing up index '{index_name}': {err}"))
+        .num_docs as i64
+}
+
+/// Replace the contents of an index with a backup taken by `backup_index`. Returns the
+/// number of documents restored.
+#[pg_extern]
+pub fn restore_index(index_name: &str, path: &str) -> i64 {
+    check_server_path(path, "back up or restore an index");
+    if unsafe { pg_sys::RecoveryInProgress() } {
+        panic!("cannot restore index '{index_name}' during recovery");
+    }
+
+    // Like a rebuild, the restore keeps writes to the table out until it commits.
+    let index_relation = bm25_index_relation(
+        index_name,
+        pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE,
+    );
+    unsafe {
+        pg_sys::LockRelationOid(
+            (*index_relation.rd_index).indrelid,
+            pg_sys::ShareLock as pg_sys::LOCKMODE,
+        )
+    };
+    let uuid = unsafe { (index_relation.rd_options as *mut SearchIndexCreateOptions).as_ref() }
+        .expect("index rd_options are unexpectedly null")
+        .get_uuid()
+        .unwrap_or_else(|| panic!("no uuid in the options of index '{index_name}'"));
+
+    // The backup is checked before anything is removed.
+    let manifest = IndexBackup::manifest(Path::new(path))
+        .unwrap_or_else(|err| panic!("error reading backup of index '{index_name}': {err}"));
+    if manifest.uuid != uuid {
+        panic!(
+            "backup in '{path}' is of another index than '{index_name}', its uuid is {}",
+            manifest.uuid
+        );
+    }
+
+    // If the restore fails from here on, the index is missing, and is rebuilt from its table
+    // on its next use, see `Resync`.
+    let bm25_index_name = bm25_index_name(index_name);
+    SearchIndex::drop_index(&WriterGlobal::client(), &bm25_index_name)
+        .unwrap_or_else(|err| panic!("error removing index before restore: {err}"));
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    IndexBackup::restore(Path::new(path), &directory, &uuid)
+        .unwrap_or_else(|err| panic!("error restoring index '{index_name}': {err}"))
+        .num_docs as i64
+}
+
+/// Write the stored and fast fields of the committed rows of an index that match `query` to a
+/// Parquet or Arrow file of the database server, which must not exist. Returns the number of
+/// rows exported.
+#[pg_extern]
+pub fn export_index(
+    index_name: &str,
+    path: &str,
+    format: default!(&str, "'parquet'"),
+    query: default!(Option<SearchQueryInput>, "NULL"),
+) -> i64 {
+    check_server_path(path, "export an index");
+    let format: ExportFormat = format
+        .parse()
+        .unwrap_or_else(|err| panic!("error exporting index '{index_name}': {err}"));
+
+    let index_relation =
+        bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index '{index_name}': {err}"));
+
+    let query = query
+        .unwrap_or(SearchQueryInput::All)
+        .into_tantivy_query(&search_index.schema, &mut search_index.query_parser())
+        .unwrap_or_else(|err| panic!("error parsing export query: {err}"));
+    IndexExport::export(search_index, &*query, Path::new(path), format)
+        .unwrap_or_else(|err| panic!("error exporting index '{index_name}': {err}")) as i64
+}
+
+/// Backups and exports are read and written by the server process, so like `COPY` to a file,
+/// only superusers may choose where.
+fn check_server_path(path: &str, action: &str) {
+    if !unsafe { pg_sys::superuser() } {
+        panic!("must be superuser to {action}");
+    }
+    if path.contains("://") {
+        panic!("'{path}' is not a path, only paths of the database server are supported");
+    }
+}
+
+/// The relation of a bm25 index, opened with `lockmode`.
+fn bm25_index_relation(index_name: &str, lockmode: pg_sys::LOCKMODE) -> PgRelation {
+    let bm25_index_name = bm25_index_name(index_name);
+    let index_oid = Spi::get_one::<pg_sys::Oid>(&format!(
+        "SELECT c.oid FROM pg_class c \
+         JOIN pg_am a ON a.oid = c.relam \
+         WHERE a.amname = 'bm25' AND c.relname = {} LIMIT 1",
+        spi::quote_literal(&bm25_index_name)
+    ))
+    .unwrap_or_else(|err| panic!("error looking up index '{index_name}': {err}"))
+    .unwrap_or_else(|| panic!("no bm25 index named '{index_name}' exists"));
+
+    unsafe { PgRelation::with_lock(index_oid, lockmode) }
+}
+
+/// The relation of a bm25 index, opened with `lockmode`, for a change that like
+/// `ALTER INDEX` only the owner of the index may make.
+fn owned_bm25_index_relation(index_name: &str, lockmode: pg_sys::LOCKMODE) -> PgRelation {
+    let index_relation = bm25_index_relation(index_name, lockmode);
+
+    #[cfg(any(feature = "pg12", feature = "pg13", feature = "pg14", feature = "pg15"))]
+    let is_owner =
+        unsafe { pg_sys::pg_class_ownercheck(index_relation.oid(), pg_sys::GetUserId()) };
+    #[cfg(feature = "pg16")]
+    let is_owner = unsafe {
+        pg_sys::object_ownercheck(
+            pg_sys::RelationRelationId,
+            index_relation.oid(),
+            pg_sys::GetUserId(),
+        )
+    };
+
+    if !is_owner {
+        let relname = std::ffi::CString::new(index_relation.name())
+            .expect("index name should not contain a nul byte");
+        unsafe {
+            pg_sys::aclcheck_error(
+                pg_sys::AclResult_ACLCHECK_NOT_OWNER,
+                pg_sys::ObjectType_OBJECT_INDEX,
+                relname.as_ptr(),
+            )
+        };
+    }
+    index_relation
+}
+
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn merge_status(
+    index_name: &str,
+) -> TableIterator<(
+    name!(segments, i64),
+    name!(num_docs, i64),
+    name!(num_deleted_docs, i64),
+    name!(merges_in_progress, i64),
+    name!(merges_completed, i64),
+    name!(last_error, Option<String>),
+    name!(maintenance_paused, bool),
+)> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+    let searcher = search_index.searcher();
+    let segment_readers = searcher.segment_readers();
+
+    let status = MergeStatus::load(&directory)
+        .unwrap_or_else(|err| panic!("error loading merge status: {err}"));
+    let paused = Maintenance::is_paused(&directory)
+        .unwrap_or_else(|err| panic!("error loading maintenance state: {err}"));
+
+    TableIterator::once((
+        segment_readers.len() as i64,
+        segment_readers
+            .iter()
+            .map(|reader| reader.num_docs() as i64)
+            .sum(),
+        segment_readers
+            .iter()
+            .map(|reader| reader.num_deleted_docs() as i64)
+            .sum(),
+        status.merges_in_progress as i64,
+        status.merges_completed as i64,
+        status.last_error,
+        paused,
+    ))
+}
+
+/// How many segments of an index, and how many bytes of them, are on local disk and in
+/// object storage. See the `storage` index option.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn storage_tiers(
+    index_name: &str,
+) -> TableIterator<'static, (name!(tier, String), name!(segments, i64), name!(bytes, i64))> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    let usage = TierUsage::collect(search_index)
+        .unwrap_or_else(|err| panic!("error reading storage tiers of '{index_name}': {err}"));
+
+    TableIterator::new(vec![
+        (
+            StorageTier::Hot.as_str().to_string(),
+            usage.hot_segments as i64,
+            usage.hot_bytes as i64,
+        ),
+        (
+            StorageTier::Cold.as_str().to_string(),
+            usage.cold_segments as i64,
+            usage.cold_bytes as i64,
+        ),
+    ])
+}
+
+/// How the bytes of an index are spread over its segments and fields, with the actions that
+/// would make it smaller or faster and about how many bytes they would save. See
+/// `IndexReport`.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn index_report(
+    index_name: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(kind, String),
+        name!(field, Option<String>),
+        name!(bytes, i64),
+        name!(detail, String),
+        name!(action, Option<String>),
+        name!(estimated_savings, i64),
+    ),
+> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let report = IndexReport::collect(search_index)
+        .unwrap_or_else(|err| panic!("error reporting on index '{index_name}': {err}"));
+    TableIterator::new(report.entries.into_iter().map(|entry| {
+        (
+            entry.kind,
+            entry.field,
+            entry.bytes as i64,
+            entry.detail,
+            entry.action,
+            entry.estimated_savings as i64,
+        )
+    }))
+}
+
+/// The BM25 indexes of the current database being built, with how far their builds got. Also
+/// exposed as the `paradedb.index_build_progress` view, along with the blocks scanned from
+/// `pg_stat_progress_create_index`. Indexes being created aren't visible to other
+/// connections yet, so they're found by their directory.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn index_builds() -> TableIterator<
+    'static,
+    (
+        name!(index_name, String),
+        name!(pid, i32),
+        name!(phase, String),
+        name!(docs_indexed, i64),
+        name!(segments_flushed, i64),
+        name!(merges_in_progress, i64),
+    ),
+> {
+    let database_oid = unsafe { pg_sys::MyDatabaseId.as_u32() };
+    let directories = WriterDirectory::list_all(&postgres_data_dir_path())
+        .unwrap_or_else(|err| panic!("error listing bm25 indexes: {err}"));
+
+    let rows = directories
+        .into_iter()
+        .filter(|directory| directory.database_oid == database_oid)
+        .filter_map(|directory| {
+            let progress = BuildProgress::load(&directory)
+                .unwrap_or_else(|err| panic!("error loading build progress: {err}"))?;
+            let segments_flushed = BuildProgress::segments_flushed(&directory)
+                .unwrap_or_else(|err| panic!("error counting segments: {err}"));
+            let merge_status = MergeStatus::load(&directory)
+                .unwrap_or_else(|err| panic!("error loading merge status: {err}"));
+
+            let index_name = index_name_of(&directory.index_name);
+            Some((
+                index_name,
+                progress.pid,
+                progress.phase.name().to_string(),
+                progress.docs_indexed as i64,
+                segments_flushed as i64,
+                merge_status.merges_in_progress as i64,
+            ))
+        })
+        .collect::<Vec<_>>();
+
+    TableIterator::new(rows)
+}
+
+// A connection that crashed mid-build leaves its progress behind, so only the builds of live
+// connections are shown. Blocks are only reported while the table is scanned.
+extension_sql!(
+    r#"
+CREATE VIEW paradedb.index_build_progress AS
+SELECT b.index_name, b.pid, b.phase,
+       p.blocks_total AS heap_blocks_total, p.blocks_done AS heap_blocks_done,
+       b.docs_indexed, b.segments_flushed, b.merges_in_progress
+FROM paradedb.index_builds() b
+JOIN pg_stat_activity a ON a.pid = b.pid
+LEFT JOIN pg_stat_progress_create_index p ON p.pid = b.pid;
+"#,
+    name = "index_build_progress_view",
+    requires = [index_builds]
+);
+
+/// The names of the BM25 indexes of the current database, as used by their writer directory.
+fn bm25_index_names() -> Vec<String> {
+    Spi::connect(|client| {
+        client
+            .select(
+                "SELECT c.relname::text FROM pg_class c JOIN pg_am a ON c.relam = a.oid \
+                 WHERE a.amname = 'bm25' ORDER BY c.relname",
+                None,
+                None,
+            )?
+            .filter_map(|row| row.get::<String>(1).transpose())
+            .collect::<Result<_, _>>()
+    })
+    .unwrap_or_else(|err| panic!("error listing bm25 indexes: {err}"))
+}
+
+/// Sizes and counts of every BM25 index, for capacity planning and monitoring. Also exposed
+/// as the `paradedb.pg_search_indexes` view.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn index_stats() -> TableIterator<
+    'static,
+    (
+        name!(index_name, String),
+        name!(num_docs, i64),
+        name!(num_deleted_docs, i64),
+        name!(segments, i64),
+        name!(postings_bytes, i64),
+        name!(docstore_bytes, i64),
+        name!(fast_fields_bytes, i64),
+        name!(total_bytes, i64),
+        name!(last_commit, Option<TimestampWithTimeZone>),
+        name!(writer_queue_depth, i64),
+        name!(uuid, String),
+        name!(read_only, bool),
+        name!(skipped_values, i64),
+    ),
+> {
+    let rows = bm25_index_names()
+        .into_iter()
+        .map(|bm25_index_name| {
+            let directory = WriterDirectory::from_index_name(&bm25_index_name);
+            let search_index = SearchIndex::from_disk(&directory)
+                .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+            let stats = SearchIndexStats::collect(&search_index)
+                .unwrap_or_else(|err| panic!("error reading stats of '{bm25_index_name}': {err}"));
+            let status = WriterStatus::load(&directory)
+                .unwrap_or_else(|err| panic!("error loading writer status: {err}"));
+            let read_only = ReadOnly::is_set(&directory)
+                .unwrap_or_else(|err| panic!("error checking if index is read-only: {err}"));
+
+            let last_commit = stats.last_commit.and_then(|time| {
+                let since_epoch = time.duration_since(std::time::UNIX_EPOCH).ok()?;
+                Some(to_timestamp(since_epoch.as_secs_f64()))
+            });
+            let index_name = index_name_of(&bm25_index_name);
+
+            (
+                index_name,
+                stats.num_docs as i64,
+                stats.num_deleted_docs as i64,
+                stats.segments as i64,
+                stats.postings_bytes as i64,
+                stats.docstore_bytes as i64,
+                stats.fast_fields_bytes as i64,
+                stats.total_bytes as i64,
+                last_commit,
+                status.uncommitted_documents as i64,
+                search_index.uuid.clone(),
+                read_only,
+                status.skipped_values as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    TableIterator::new(rows)
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW paradedb.pg_search_indexes AS SELECT * FROM paradedb.index_stats();
+"#,
+    name = "pg_search_indexes_view",
+    requires = [index_stats]
+);
+
+/// The opstamp of the latest commit of an index, when it was made, and how many documents
+/// were sent to the writer but aren't searchable yet. The opstamp increases with every commit,
+/// so an application can remember it after a write and compare it with a later call to check
+/// that its reads include the write.
+#[pg_extern]
+pub fn index_generation(
+    index_name: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(opstamp, i64),
+        name!(last_commit, Option<TimestampWithTimeZone>),
+        name!(pending_operations, i64),
+    ),
+> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    let meta = search_index
+        .underlying_index
+        .load_metas()
+        .unwrap_or_else(|err| panic!("error reading metadata of '{index_name}': {err}"));
+    let stats = SearchIndexStats::collect(&search_index)
+        .unwrap_or_else(|err| panic!("error reading stats of '{index_name}': {err}"));
+    let status = WriterStatus::load(&directory)
+        .unwrap_or_else(|err| panic!("error loading writer status: {err}"));
+
+    let last_commit = stats.last_commit.and_then(|time| {
+        let since_epoch = time.duration_since(std::time::UNIX_EPOCH).ok()?;
+        Some(to_timestamp(since_epoch.as_secs_f64()))
+    });
+    TableIterator::once((
+        meta.opstamp as i64,
+        last_commit,
+        status.uncommitted_documents as i64,
+    ))
+}
+
+/// Index directories that no longer belong to any index, with the reason and the bytes they
+/// take up. Directories of the current database are checked against its BM25 indexes, and
+/// those of other databases only against the databases that still exist.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn orphaned_directories() -> TableIterator<
+    'static,
+    (
+        name!(database_oid, pg_sys::Oid),
+        name!(relfilenode, pg_sys::Oid),
+        name!(index_name, String),
+        name!(reason, String),
+        name!(total_bytes, i64),
+    ),
+> {
+    let rows = find_orphaned_directories()
+        .into_iter()
+        .map(|(directory, reason)| {
+            let total_bytes = directory
+                .total_bytes()
+                .unwrap_or_else(|err| panic!("error reading size of {directory:?}: {err}"));
+            (
+                pg_sys::Oid::from(directory.database_oid),
+                pg_sys::Oid::from(directory.relfilenode),
+                directory.index_name,
+                reason.name().to_string(),
+                total_bytes as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    TableIterator::new(rows)
+}
+
+/// Remove the directories listed by `orphaned_directories`, along with their segments in
+/// object storage, and return how many were removed. The directories of other databases are
+/// removed too, so like dropping a database, it's reserved to superusers.
+#[pg_extern]
+pub fn gc_directories() -> i64 {
+    if !unsafe { pg_sys::superuser() } {
+        panic!("must be superuser to remove orphaned directories");
+    }
+    let writer_client = WriterGlobal::client();
+    let mut removed = 0;
+    for (directory, _) in find_orphaned_directories() {
+        SearchIndex::drop_directory(&writer_client, directory.clone())
+            .unwrap_or_else(|err| panic!("error removing {directory:?}: {err}"));
+        removed += 1;
+    }
+    removed
+}
+
+fn find_orphaned_directories() -> Vec<(WriterDirectory, OrphanReason)> {
+    let directories = WriterDirectory::list_all(&postgres_data_dir_path())
+        .unwrap_or_else(|err| panic!("error listing bm25 indexes: {err}"));
+    let databases = Spi::connect(|client| {
+        client
+            .select("SELECT oid FROM pg_database", None, None)?
+            .filter_map(|row| row.get::<pg_sys::Oid>(1).transpose())
+            .map(|oid| oid.map(|oid| oid.as_u32()))
+            .collect::<Result<HashSet<_>, _>>()
+    })
+    .unwrap_or_else(|err| panic!("error listing databases: {err}"));
+    let backends = Spi::connect(|client| {
+        client
+            .select("SELECT pid FROM pg_stat_activity", None, None)?
+            .filter_map(|row| row.get::<i32>(1).transpose())
+            .collect::<Result<HashSet<_>, _>>()
+    })
+    .unwrap_or_else(|err| panic!("error listing backends: {err}"));
+    let indexes = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT c.relname::text, pg_relation_filenode(c.oid) FROM pg_class c \
+                 JOIN pg_am a ON c.relam = a.oid WHERE a.amname = 'bm25'",
+                None,
+                None,
+            )?
+            .map(|row| {
+                let index_name = row.get::<String>(1)?.unwrap_or_default();
+                let relfilenode = row.get::<pg_sys::Oid>(2)?.map_or(0, |oid| oid.as_u32());
+                Ok((index_name, relfilenode))
+            })
+            .collect::<Result<HashMap<_, _>, pgrx::spi::Error>>()
+    })
+    .unwrap_or_else(|err| panic!("error listing bm25 indexes: {err}"));
+    let database_oid = unsafe { pg_sys::MyDatabaseId.as_u32() };
+
+    find_orphans(directories, database_oid, &databases, &indexes)
+        .into_iter()
+        // Indexes being created aren't visible to other connections until their build
+        // commits, so directories with a build in progress are left alone.
+        .filter(|(directory, _)| {
+            let progress = BuildProgress::load(directory)
+                .unwrap_or_else(|err| panic!("error loading build progress: {err}"));
+            !progress.is_some_and(|progress| backends.contains(&progress.pid))
+        })
+        .collect()
+}
+
+/// Statistics of the searches run in the current database since the last `stat_queries_reset`,
+/// by index and query shape. Queries that only differ by their values, like search terms,
+/// have the same shape.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn stat_queries() -> TableIterator<
+    'static,
+    (
+        name!(index_name, String),
+        name!(fingerprint, i64),
+        name!(query, String),
+        name!(calls, i64),
+        name!(total_time_ms, f64),
+        name!(mean_time_ms, f64),
+        name!(p50_time_ms, f64),
+        name!(p95_time_ms, f64),
+        name!(p99_time_ms, f64),
+        name!(rows, i64),
+    ),
+> {
+    let millis = |duration: std::time::Duration| duration.as_secs_f64() * 1000.0;
+    flush_pending_searches();
+    let rows = QUERY_STATS
+        .share()
+        .entries(unsafe { pg_sys::MyDatabaseId })
+        .map(|(fingerprint, entry)| {
+            let index_name = index_name_of(&entry.index_name);
+            (
+                index_name,
+                fingerprint as i64,
+                entry.query.to_string(),
+                entry.calls as i64,
+                millis(entry.total_time),
+                millis(entry.mean_time()),
+                millis(entry.percentile(0.5)),
+                millis(entry.percentile(0.95)),
+                millis(entry.percentile(0.99)),
+                entry.rows as i64,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    TableIterator::new(rows)
+}
+
+#[pg_extern]
+pub fn stat_queries_reset() {
+    let database = unsafe { pg_sys::MyDatabaseId };
+    discard_pending_searches(database);
+    QUERY_STATS.exclusive().reset(database);
+}
+
+/// Counters and gauges of search health, one per row, named after Prometheus conventions so
+/// that exporters which scrape SQL queries can pass them on as is. Per-index metrics have the
+/// index name, and the others are summed over every connection since the server started.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn metrics() -> TableIterator<
+    'static,
+    (
+        name!(metric, String),
+        name!(metric_type, String),
+        name!(index_name, Option<String>),
+        name!(value, f64),
+    ),
+> {
+    let mut rows = vec![];
+    let mut push = |metric: &str, metric_type: &str, index_name: Option<&str>, value: f64| {
+        rows.push((
+            metric.to_string(),
+            metric_type.to_string(),
+            index_name.map(str::to_string),
+            value,
+        ))
+    };
+
+    let searches = SEARCHES.get().load(Ordering::Relaxed);
+    let cache_hits = QUERY_CACHE_HITS.get().load(Ordering::Relaxed);
+    let cache_misses = QUERY_CACHE_MISSES.get().load(Ordering::Relaxed);
+    push("pg_search_searches_total", "counter", None, searches as f64);
+    push(
+        "pg_search_query_cache_hits_total",
+        "counter",
+        None,
+        cache_hits as f64,
+    );
+    push(
+        "pg_search_query_cache_misses_total",
+        "counter",
+        None,
+        cache_misses as f64,
+    );
+    push(
+        "pg_search_query_cache_hit_ratio",
+        "gauge",
+        None,
+        cache_hits as f64 / (cache_hits + cache_misses).max(1) as f64,
+    );
+    for (tier, reads, nanos) in [
+        ("hot", &HOT_TIER_READS, &HOT_TIER_READ_NANOS),
+        ("cold", &COLD_TIER_READS, &COLD_TIER_READ_NANOS),
+    ] {
+        push(
+            &format!("pg_search_{tier}_tier_reads_total"),
+            "counter",
+            None,
+            reads.get().load(Ordering::Relaxed) as f64,
+        );
+        push(
+            &format!("pg_search_{tier}_tier_read_seconds_total"),
+            "counter",
+            None,
+            nanos.get().load(Ordering::Relaxed) as f64 / 1e9,
+        );
+    }
+
+    for bm25_index_name in bm25_index_names() {
+        let directory = WriterDirectory::from_index_name(&bm25_index_name);
+        let search_index = SearchIndex::from_disk(&directory)
+            .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+        let stats = SearchIndexStats::collect(&search_index)
+            .unwrap_or_else(|err| panic!("error reading stats of '{bm25_index_name}': {err}"));
+        let writer_status = WriterStatus::load(&directory)
+            .unwrap_or_else(|err| panic!("error loading writer status: {err}"));
+        let merge_status = MergeStatus::load(&directory)
+            .unwrap_or_else(|err| panic!("error loading merge status: {err}"));
+        let heal_status = HealStatus::load(&directory)
+            .unwrap_or_else(|err| panic!("error loading heal status: {err}"));
+
+        let index_name = index_name_of(&bm25_index_name);
+        let index = Some(index_name.as_str());
+        push(
+            "pg_search_documents_indexed_total",
+            "counter",
+            index,
+            writer_status.documents_indexed as f64,
+        );
+        push(
+            "pg_search_commits_total",
+            "counter",
+            index,
+            writer_status.commits as f64,
+        );
+        push(
+            "pg_search_writer_errors_total",
+            "counter",
+            index,
+            writer_status.errors as f64,
+        );
+        push(
+            "pg_search_skipped_values_total",
+            "counter",
+            index,
+            writer_status.skipped_values as f64,
+        );
+        push(
+            "pg_search_writer_queue_depth",
+            "gauge",
+            index,
+            writer_status.uncommitted_documents as f64,
+        );
+        push(
+            "pg_search_merges_total",
+            "counter",
+            index,
+            merge_status.merges_completed as f64,
+        );
+        push(
+            "pg_search_merges_in_progress",
+            "gauge",
+            index,
+            merge_status.merges_in_progress as f64,
+        );
+        push("pg_search_docs", "gauge", index, stats.num_docs as f64);
+        push(
+            "pg_search_deleted_docs",
+            "gauge",
+            index,
+            stats.num_deleted_docs as f64,
+        );
+        push("pg_search_segments", "gauge", index, stats.segments as f64);
+        push(
+            "pg_search_index_bytes",
+            "gauge",
+            index,
+            stats.total_bytes as f64,
+        );
+        push(
+            "pg_search_heal_rows_checked_total",
+            "counter",
+            index,
+            heal_status.rows_checked as f64,
+        );
+        push(
+            "pg_search_heal_rows_repaired_total",
+            "counter",
+            index,
+            heal_status.rows_repaired as f64,
+        );
+    }
+
+    TableIterator::new(rows)
+}
+
+/// The search wait event of the backend `pid`, like `SearchQueryExecute`, or NULL if it isn't
+/// waiting on search. Postgres reports these as the `Extension` wait event.
+#[pg_extern]
+pub fn search_wait_event(pid: i32) -> Option<String> {
+    SearchWaitEvent::of_backend(pid).map(|event| event.name().to_string())
+}
+
+extension_sql!(
+    r#"
+CREATE VIEW paradedb.wait_events AS
+SELECT * FROM (
+    SELECT a.pid, a.datname, a.usename, a.state, a.query,
+           paradedb.search_wait_event(a.pid) AS wait_event
+    FROM pg_stat_activity a
+    WHERE a.wait_event_type = 'Extension'
+) w
+WHERE w.wait_event IS NOT NULL;
+"#,
+    name = "wait_events_view",
+    requires = [search_wait_event]
+);
+
+/// Look for corruption of an index, and for drift from its table: segment files that are
+/// missing or don't match their checksum, a document count far from the table's row
+/// estimate, and keys of rows sampled from the table that aren't in the index.
+#[pg_extern]
+pub fn check_index(
+    index_name: &str,
+    sample_size: default!(i32, 100),
+) -> TableIterator<
+    'static,
+    (
+        name!(check, String),
+        name!(status, String),
+        name!(detail, String),
+    ),
+> {
+    if sample_size < 0 {
+        panic!("sample_size must not be negative, got {sample_size}");
+    }
+
+    // Keeps the index from being dropped while it's checked.
+    bm25_index_relation(index_name, pg_sys::AccessShareLock as pg_sys::LOCKMODE);
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let (table_name, heap_rows, partial) = Spi::connect(|client| {
+        let row = client
+            .select(
+                &format!(
+                    "SELECT i.indrelid::regclass::text, t.reltuples::float8, i.indpred IS NOT NULL \
+                     FROM pg_index i \
+                     JOIN pg_class c ON c.oid = i.indexrelid \
+                     JOIN pg_class t ON t.oid = i.indrelid \
+                     WHERE c.relname = {} LIMIT 1",
+                    spi::quote_literal(&bm25_index_name)
+                ),
+                None,
+                None,
+            )?
+            .first();
+        Ok::<_, spi::Error>((
+            row.get::<String>(1)?,
+            row.get::<f64>(2)?,
+            row.get::<bool>(3)?.unwrap_or_default(),
+        ))
+    })
+    .unwrap_or_else(|err| panic!("error looking up the table of '{index_name}': {err}"));
+    let table_name =
+        table_name.unwrap_or_else(|| panic!("no bm25 index named '{index_name}' exists"));
+
+    let key_field = search_index.schema.key_field();
+    let key_cast = match key_field.type_ {
+        SearchFieldType::I64 => Some("bigint"),
+        SearchFieldType::F64 => Some("float8"),
+        SearchFieldType::Text => Some("text"),
+        _ => None,
+    };
+    let sampled_keys = match key_cast {
+        Some(key_cast) if sample_size > 0 => Spi::connect(|client| {
+            let rows = client.select(
+                &format!(
+                    "SELECT {}::{key_cast} FROM {table_name} ORDER BY random() LIMIT {sample_size}",
+                    spi::quote_identifier(&key_field.name.0)
+                ),
+                None,
+                None,
+            )?;
+            rows.filter_map(|row| match key_field.type_ {
+                SearchFieldType::I64 => {
+                    row.get::<i64>(1).transpose().map(|key| key.map(Into::into))
+                }
+                SearchFieldType::F64 => {
+                    row.get::<f64>(1).transpose().map(|key| key.map(Into::into))
+                }
+                _ => row
+                    .get::<String>(1)
+                    .transpose()
+                    .map(|key| key.map(Into::into)),
+            })
+            .collect::<Result<Vec<tantivy::schema::OwnedValue>, _>>()
+        })
+        .unwrap_or_else(|err| panic!("error sampling the keys of '{table_name}': {err}")),
+        _ => vec![],
+    };
+
+    let mut checks = IndexCheck::files(&search_index)
+        .unwrap_or_else(|err| panic!("error checking the files of '{index_name}': {err}"));
+    checks.push(IndexCheck::doc_count(&search_index, heap_rows, partial));
+    checks.push(IndexCheck::keys(&search_index, &sampled_keys));
+
+    TableIterator::new(checks.into_iter().map(|check| {
+        (
+            check.name.to_string(),
+            check.status.as_str().to_string(),
+            check.detail,
+        )
+    }))
+}
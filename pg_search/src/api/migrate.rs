@@ -0,0 +1,392 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use pgrx::{iter::TableIterator, *};
+use serde_json::{json, Map};
+use tokenizers::SearchTokenizer;
+
+/// A dictionary that words go through in a text search configuration.
+#[derive(Debug)]
+struct TsDictionary {
+    name: String,
+    template: String,
+    options: Option<String>,
+}
+
+/// How a tsvector column is computed: from which columns, with which configuration, and
+/// whether its lexemes are weighted.
+struct TsVectorSource {
+    columns: Vec<String>,
+    config: Option<String>,
+    weighted: bool,
+}
+
+/// Generate the `create_bm25` call indexing the columns that `tsvector_column` is computed
+/// from, with the tokenizer closest to the dictionaries of its text search configuration,
+/// and list what searches of the index will do differently. The column must be generated,
+/// or kept up to date by `tsvector_update_trigger`.
+#[pg_extern]
+pub fn migrate_from_tsvector(
+    table_name: &str,
+    tsvector_column: &str,
+    config: default!(Option<String>, "NULL"),
+    schema_name: default!(&str, "'public'"),
+) -> TableIterator<'static, (name!(create_bm25, String), name!(differences, Vec<String>))> {
+    let relation = format!(
+        "{}.{}",
+        spi::quote_identifier(schema_name),
+        spi::quote_identifier(table_name)
+    );
+    let source = tsvector_source(&relation, tsvector_column);
+    let mut differences = vec![];
+
+    let config = match (config, source.config) {
+        (Some(config), Some(detected)) if config != detected => {
+            differences.push(format!(
+                "'{tsvector_column}' is computed with the '{detected}' configuration, but '{config}' was migrated"
+            ));
+            config
+        }
+        (Some(config), _) | (None, Some(config)) => config,
+        (None, None) => {
+            Spi::get_one::<String>("SELECT current_setting('default_text_search_config')")
+                .unwrap_or_else(|err| panic!("error reading default_text_search_config: {err}"))
+                .unwrap_or_else(|| "simple".to_string())
+        }
+    };
+    let tokenizer = tokenizer_for(&config_dictionaries(&config), &mut differences);
+
+    if source.weighted {
+        differences.push(
+            "the weights given with setweight aren't indexed, boost the fields of queries with paradedb.boost instead"
+                .to_string(),
+        );
+    }
+    if source.columns.len() > 1 {
+        differences.push(format!(
+            "{} are indexed as separate fields, so queries name the fields they search, like '{}:word OR {}:word'",
+            source.columns.join(", "),
+            source.columns[0],
+            source.columns[1],
+        ));
+    }
+    differences.push(
+        "results are ranked by BM25 rather than ts_rank, and queries use the syntax of paradedb.parse rather than tsquery"
+            .to_string(),
+    );
+
+    let key_field = primary_key(&relation).unwrap_or_else(|| {
+        panic!("{relation} has no single-column primary key to use as the key_field of the index")
+    });
+    let statement = create_bm25_call(
+        schema_name,
+        table_name,
+        &key_field,
+        &source.columns,
+        &tokenizer,
+    );
+    TableIterator::new(vec![(statement, differences)])
+}
+
+fn tsvector_source(relation: &str, tsvector_column: &str) -> TsVectorSource {
+    // Joined to a single row, so that a missing column reads as NULLs.
+    let (attnum, type_name, expression) = Spi::get_three::<i16, String, String>(&format!(
+        "SELECT a.attnum, a.atttypid::regtype::text, pg_get_expr(d.adbin, d.adrelid) \
+         FROM (SELECT 1) AS one \
+         LEFT JOIN pg_attribute a \
+             ON a.attrelid = {}::regclass AND a.attname = {} AND NOT a.attisdropped \
+         LEFT JOIN pg_attrdef d \
+             ON d.adrelid = a.attrelid AND d.adnum = a.attnum AND a.attgenerated = 's'",
+        spi::quote_literal(relation),
+        spi::quote_literal(tsvector_column)
+    ))
+    .unwrap_or_else(|err| {
+        panic!("error looking up column '{tsvector_column}' of {relation}: {err}")
+    });
+    let (Some(attnum), Some(type_name)) = (attnum, type_name) else {
+        panic!("{relation} has no column named '{tsvector_column}'")
+    };
+    if type_name != "tsvector" {
+        panic!("'{tsvector_column}' is a {type_name} column, not a tsvector column");
+    }
+
+    // A generated column depends on the columns its expression reads.
+    if let Some(expression) = expression {
+        let columns = Spi::connect(|client| {
+            client
+                .select(
+                    &format!(
+                        "SELECT DISTINCT a.attnum, a.attname::text FROM pg_attrdef ad \
+                         JOIN pg_depend d ON d.classid = 'pg_attrdef'::regclass AND d.objid = ad.oid \
+                         JOIN pg_attribute a ON a.attrelid = d.refobjid AND a.attnum = d.refobjsubid \
+                         WHERE ad.adrelid = {}::regclass AND ad.adnum = {attnum} AND a.attnum <> {attnum} \
+                         ORDER BY a.attnum",
+                        spi::quote_literal(relation)
+                    ),
+                    None,
+                    None,
+                )?
+                .filter_map(|row| row.get::<String>(2).transpose())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .unwrap_or_else(|err| panic!("error listing the columns '{tsvector_column}' reads: {err}"));
+
+        return TsVectorSource {
+            columns,
+            config: expression_config(&expression),
+            weighted: expression.contains("setweight("),
+        };
+    }
+
+    // tsvector_update_trigger takes the tsvector column, the configuration and then the
+    // columns it reads, as arguments separated by NUL bytes.
+    let arguments = Spi::get_one::<Vec<u8>>(&format!(
+        "SELECT (SELECT tgargs FROM pg_trigger \
+         WHERE tgrelid = {}::regclass AND tgfoid = 'tsvector_update_trigger'::regproc LIMIT 1)",
+        spi::quote_literal(relation)
+    ))
+    .unwrap_or_else(|err| panic!("error looking up the triggers of {relation}: {err}"));
+    let arguments: Vec<String> = arguments
+        .unwrap_or_default()
+        .split(|byte| *byte == 0)
+        .filter(|argument| !argument.is_empty())
+        .map(|argument| String::from_utf8_lossy(argument).into_owned())
+        .collect();
+    match &arguments[..] {
+        [column, config, columns @ ..] if column == tsvector_column && !columns.is_empty() => {
+            TsVectorSource {
+                columns: columns.to_vec(),
+                // The configuration may be schema-qualified, like pg_catalog.english.
+                config: config.rsplit('.').next().map(str::to_string),
+                weighted: false,
+            }
+        }
+        _ => panic!(
+            "can't find the columns '{tsvector_column}' is computed from, it must be a generated column or be kept up to date by tsvector_update_trigger"
+        ),
+    }
+}
+
+/// The configuration of the first `to_tsvector` call of an expression, as written by
+/// `pg_get_expr`, like `to_tsvector('english'::regconfig, title)`.
+fn expression_config(expression: &str) -> Option<String> {
+    let config = expression
+        .split("to_tsvector('")
+        .nth(1)?
+        .split('\'')
+        .next()?;
+    Some(config.to_string())
+}
+
+/// The dictionaries that the words of a configuration go through, in order.
+fn config_dictionaries(config: &str) -> Vec<TsDictionary> {
+    Spi::connect(|client| {
+        client
+            .select(
+                &format!(
+                    "SELECT d.dictname::text, t.tmplname::text, d.dictinitoption \
+                     FROM pg_ts_config_map m \
+                     JOIN pg_ts_dict d ON d.oid = m.mapdict \
+                     JOIN pg_ts_template t ON t.oid = d.dicttemplate \
+                     WHERE m.mapcfg = {config}::regconfig AND m.maptokentype = ( \
+                         SELECT tokid FROM ts_token_type( \
+                             (SELECT cfgparser FROM pg_ts_config WHERE oid = {config}::regconfig) \
+                         ) WHERE alias = 'asciiword' \
+                     ) \
+                     ORDER BY m.mapseqno",
+                    config = spi::quote_literal(config)
+                ),
+                None,
+                None,
+            )?
+            .map(|row| {
+                Ok(TsDictionary {
+                    name: row.get::<String>(1)?.unwrap_or_default(),
+                    template: row.get::<String>(2)?.unwrap_or_default(),
+                    options: row.get::<String>(3)?,
+                })
+            })
+            .collect::<Result<Vec<_>, spi::Error>>()
+    })
+    .unwrap_or_else(|err| panic!("error reading text search configuration '{config}': {err}"))
+}
+
+/// The tokenizer closest to a chain of dictionaries. Only the dictionary that ends the chain
+/// for most words, a stemmer or the simple dictionary, has an equivalent.
+fn tokenizer_for(dictionaries: &[TsDictionary], differences: &mut Vec<String>) -> SearchTokenizer {
+    let mut tokenizer = SearchTokenizer::Default;
+    for dictionary in dictionaries {
+        let options = dictionary.options.as_deref().unwrap_or_default();
+        match dictionary.template.as_str() {
+            "snowball" => {
+                let language = dictionary_option(options, "language").unwrap_or_default();
+                match stem_language(&language) {
+                    Some(language) => tokenizer = SearchTokenizer::Stem { language },
+                    None => differences.push(format!(
+                        "the '{}' dictionary stems {language}, which has no stemmer in pg_search, so words aren't stemmed",
+                        dictionary.name
+                    )),
+                }
+            }
+            "simple" => {}
+            template => {
+                differences.push(format!(
+                    "the '{}' dictionary uses the {template} template, which has no equivalent and is left out",
+                    dictionary.name
+                ));
+                continue;
+            }
+        }
+        if let Some(stopwords) = dictionary_option(options, "stopwords") {
+            differences.push(format!(
+                "the '{}' dictionary drops the {stopwords} stopwords, which are indexed, as BM25 gives common words little weight",
+                dictionary.name
+            ));
+        }
+    }
+    tokenizer
+}
+
+/// An option of a dictionary, as listed in `dictinitoption`, like
+/// `language = 'english', stopwords = 'english'`.
+fn dictionary_option(options: &str, name: &str) -> Option<String> {
+    options.split(',').find_map(|option| {
+        let (key, value) = option.split_once('=')?;
+        (key.trim().eq_ignore_ascii_case(name)).then(|| value.trim().trim_matches('\'').to_string())
+    })
+}
+
+fn stem_language(language: &str) -> Option<tantivy::tokenizer::Language> {
+    let mut chars = language.chars();
+    let first = chars.next()?;
+    let capitalized = first
+        .to_uppercase()
+        .chain(chars.flat_map(char::to_lowercase));
+    serde_json::from_value(json!(capitalized.collect::<String>())).ok()
+}
+
+fn primary_key(relation: &str) -> Option<String> {
+    Spi::get_one::<String>(&format!(
+        "SELECT (SELECT a.attname::text FROM pg_index i \
+         JOIN pg_attribute a ON a.attrelid = i.indrelid AND a.attnum = i.indkey[0] \
+         WHERE i.indrelid = {}::regclass AND i.indisprimary AND i.indnatts = 1)",
+        spi::quote_literal(relation)
+    ))
+    .unwrap_or_else(|err| panic!("error looking up the primary key of {relation}: {err}"))
+}
+
+fn create_bm25_call(
+    schema_name: &str,
+    table_name: &str,
+    key_field: &str,
+    columns: &[String],
+    tokenizer: &SearchTokenizer,
+) -> String {
+    let text_fields: Map<_, _> = columns
+        .iter()
+        .map(|column| {
+            (
+                column.clone(),
+                json!({ "tokenizer": tokenizer.to_json_value() }),
+            )
+        })
+        .collect();
+    format!(
+        "CALL paradedb.create_bm25(\n    index_name => {},\n    schema_name => {},\n    table_name => {},\n    key_field => {},\n    text_fields => {}\n);",
+        spi::quote_literal(format!("{table_name}_idx")),
+        spi::quote_literal(schema_name),
+        spi::quote_literal(table_name),
+        spi::quote_literal(key_field),
+        spi::quote_literal(serde_json::Value::Object(text_fields).to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dictionary(name: &str, template: &str, options: Option<&str>) -> TsDictionary {
+        TsDictionary {
+            name: name.into(),
+            template: template.into(),
+            options: options.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn test_tokenizer_for() {
+        let mut differences = vec![];
+        let tokenizer = tokenizer_for(
+            &[dictionary(
+                "english_stem",
+                "snowball",
+                Some("language = 'english', stopwords = 'english'"),
+            )],
+            &mut differences,
+        );
+        assert_eq!(
+            tokenizer,
+            SearchTokenizer::Stem {
+                language: tantivy::tokenizer::Language::English
+            }
+        );
+        assert_eq!(differences.len(), 1);
+        assert!(differences[0].contains("english stopwords"));
+
+        let mut differences = vec![];
+        let tokenizer = tokenizer_for(
+            &[
+                dictionary("english_ispell", "ispell", Some("dictfile = 'english'")),
+                dictionary("simple", "simple", None),
+            ],
+            &mut differences,
+        );
+        assert_eq!(tokenizer, SearchTokenizer::Default);
+        assert_eq!(differences.len(), 1);
+        assert!(differences[0].contains("ispell template"));
+
+        let mut differences = vec![];
+        let tokenizer = tokenizer_for(
+            &[dictionary(
+                "nepali_stem",
+                "snowball",
+                Some("language = 'nepali'"),
+            )],
+            &mut differences,
+        );
+        assert_eq!(tokenizer, SearchTokenizer::Default);
+        assert!(differences[0].contains("no stemmer"));
+    }
+
+    #[test]
+    fn test_expression_config() {
+        assert_eq!(
+            expression_config(
+                "to_tsvector('english'::regconfig, ((COALESCE(title, ''::text) || ' '::text) || body))"
+            ),
+            Some("english".to_string())
+        );
+        assert_eq!(
+            expression_config("setweight(title_vector, 'A'::\"char\")"),
+            None
+        );
+        assert_eq!(
+            dictionary_option("language = 'english', stopwords = 'english'", "stopwords"),
+            Some("english".to_string())
+        );
+        assert_eq!(dictionary_option("language = 'english'", "stopwords"), None);
+    }
+}
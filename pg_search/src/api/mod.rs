@@ -16,6 +16,15 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 mod config;
+#[cfg(feature = "fault_injection")]
+mod fault;
+mod groups;
 mod index;
+mod maintenance;
+mod migrate;
 mod operator;
+mod percolate;
+mod ranking;
+mod relevance;
+mod saved;
 mod search;
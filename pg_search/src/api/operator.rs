@@ -19,7 +19,9 @@ use crate::env::needs_commit;
 use crate::globals::WriterGlobal;
 use crate::index::state::SearchStateManager;
 use crate::index::SearchIndex;
+use crate::postgres::resync::check_shipped;
 use crate::postgres::types::TantivyValue;
+use crate::postgres::wait::SearchWaitEvent;
 use crate::schema::SearchConfig;
 use crate::writer::WriterDirectory;
 use pgrx::*;
@@ -38,6 +40,7 @@ fn search_tantivy(
 
         let writer_client = WriterGlobal::client();
         let directory = WriterDirectory::from_index_name(&search_config.index_name);
+        check_shipped(&directory);
         let search_index = SearchIndex::from_cache(&directory, &search_config.uuid)
             .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
         let scan_state = search_index
@@ -47,7 +50,10 @@ fn search_tantivy(
                 needs_commit(&search_config.index_name),
             )
             .unwrap();
-        let top_docs = scan_state.search(SearchIndex::executor());
+        let top_docs = {
+            let _wait = SearchWaitEvent::QueryExecute.start();
+            scan_state.search(&SearchIndex::executor())
+        };
         let mut hs = FxHashSet::default();
 
         for (_score, _doc_address, key, _ctid) in top_docs {
@@ -88,3 +94,92 @@ CREATE OPERATOR CLASS anyelement_bm25_ops DEFAULT FOR TYPE anyelement USING bm25
 "#,
     name = "bm25_ops_anyelement_operator"
 );
+
+extension_sql!(
+    r#"
+-- Comparisons of indexed columns with constants are searched for along with the @@@ query.
+ALTER OPERATOR FAMILY anyelement_bm25_ops USING bm25 ADD
+    OPERATOR 2 pg_catalog.<(int2, int2),
+    OPERATOR 3 pg_catalog.<=(int2, int2),
+    OPERATOR 4 pg_catalog.=(int2, int2),
+    OPERATOR 5 pg_catalog.>=(int2, int2),
+    OPERATOR 6 pg_catalog.>(int2, int2),
+    OPERATOR 2 pg_catalog.<(int2, int4),
+    OPERATOR 3 pg_catalog.<=(int2, int4),
+    OPERATOR 4 pg_catalog.=(int2, int4),
+    OPERATOR 5 pg_catalog.>=(int2, int4),
+    OPERATOR 6 pg_catalog.>(int2, int4),
+    OPERATOR 2 pg_catalog.<(int2, int8),
+    OPERATOR 3 pg_catalog.<=(int2, int8),
+    OPERATOR 4 pg_catalog.=(int2, int8),
+    OPERATOR 5 pg_catalog.>=(int2, int8),
+    OPERATOR 6 pg_catalog.>(int2, int8),
+    OPERATOR 2 pg_catalog.<(int4, int2),
+    OPERATOR 3 pg_catalog.<=(int4, int2),
+    OPERATOR 4 pg_catalog.=(int4, int2),
+    OPERATOR 5 pg_catalog.>=(int4, int2),
+    OPERATOR 6 pg_catalog.>(int4, int2),
+    OPERATOR 2 pg_catalog.<(int4, int4),
+    OPERATOR 3 pg_catalog.<=(int4, int4),
+    OPERATOR 4 pg_catalog.=(int4, int4),
+    OPERATOR 5 pg_catalog.>=(int4, int4),
+    OPERATOR 6 pg_catalog.>(int4, int4),
+    OPERATOR 2 pg_catalog.<(int4, int8),
+    OPERATOR 3 pg_catalog.<=(int4, int8),
+    OPERATOR 4 pg_catalog.=(int4, int8),
+    OPERATOR 5 pg_catalog.>=(int4, int8),
+    OPERATOR 6 pg_catalog.>(int4, int8),
+    OPERATOR 2 pg_catalog.<(int8, int2),
+    OPERATOR 3 pg_catalog.<=(int8, int2),
+    OPERATOR 4 pg_catalog.=(int8, int2),
+    OPERATOR 5 pg_catalog.>=(int8, int2),
+    OPERATOR 6 pg_catalog.>(int8, int2),
+    OPERATOR 2 pg_catalog.<(int8, int4),
+    OPERATOR 3 pg_catalog.<=(int8, int4),
+    OPERATOR 4 pg_catalog.=(int8, int4),
+    OPERATOR 5 pg_catalog.>=(int8, int4),
+    OPERATOR 6 pg_catalog.>(int8, int4),
+    OPERATOR 2 pg_catalog.<(int8, int8),
+    OPERATOR 3 pg_catalog.<=(int8, int8),
+    OPERATOR 4 pg_catalog.=(int8, int8),
+    OPERATOR 5 pg_catalog.>=(int8, int8),
+    OPERATOR 6 pg_catalog.>(int8, int8),
+    OPERATOR 2 pg_catalog.<(float4, float4),
+    OPERATOR 3 pg_catalog.<=(float4, float4),
+    OPERATOR 4 pg_catalog.=(float4, float4),
+    OPERATOR 5 pg_catalog.>=(float4, float4),
+    OPERATOR 6 pg_catalog.>(float4, float4),
+    OPERATOR 2 pg_catalog.<(float4, float8),
+    OPERATOR 3 pg_catalog.<=(float4, float8),
+    OPERATOR 4 pg_catalog.=(float4, float8),
+    OPERATOR 5 pg_catalog.>=(float4, float8),
+    OPERATOR 6 pg_catalog.>(float4, float8),
+    OPERATOR 2 pg_catalog.<(float8, float4),
+    OPERATOR 3 pg_catalog.<=(float8, float4),
+    OPERATOR 4 pg_catalog.=(float8, float4),
+    OPERATOR 5 pg_catalog.>=(float8, float4),
+    OPERATOR 6 pg_catalog.>(float8, float4),
+    OPERATOR 2 pg_catalog.<(float8, float8),
+    OPERATOR 3 pg_catalog.<=(float8, float8),
+    OPERATOR 4 pg_catalog.=(float8, float8),
+    OPERATOR 5 pg_catalog.>=(float8, float8),
+    OPERATOR 6 pg_catalog.>(float8, float8),
+    OPERATOR 2 pg_catalog.<(date, date),
+    OPERATOR 3 pg_catalog.<=(date, date),
+    OPERATOR 4 pg_catalog.=(date, date),
+    OPERATOR 5 pg_catalog.>=(date, date),
+    OPERATOR 6 pg_catalog.>(date, date),
+    OPERATOR 2 pg_catalog.<(timestamp, timestamp),
+    OPERATOR 3 pg_catalog.<=(timestamp, timestamp),
+    OPERATOR 4 pg_catalog.=(timestamp, timestamp),
+    OPERATOR 5 pg_catalog.>=(timestamp, timestamp),
+    OPERATOR 6 pg_catalog.>(timestamp, timestamp),
+    OPERATOR 2 pg_catalog.<(timestamptz, timestamptz),
+    OPERATOR 3 pg_catalog.<=(timestamptz, timestamptz),
+    OPERATOR 4 pg_catalog.=(timestamptz, timestamptz),
+    OPERATOR 5 pg_catalog.>=(timestamptz, timestamptz),
+    OPERATOR 6 pg_catalog.>(timestamptz, timestamptz);
+"#,
+    name = "bm25_ops_anyelement_comparison_operators",
+    requires = ["bm25_ops_anyelement_operator"]
+);
@@ -0,0 +1,146 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+use serde_json::Value;
+
+use crate::index::percolate::{anchor_terms, Percolator};
+use crate::index::SearchIndex;
+use crate::postgres::utils::bm25_index_name;
+use crate::query::SearchQueryInput;
+use crate::writer::WriterDirectory;
+
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.percolator_queries (
+    index_name text NOT NULL,
+    name text NOT NULL,
+    query jsonb NOT NULL,
+    anchors text[],
+    PRIMARY KEY (index_name, name)
+);
+
+-- Percolating a document only runs the queries sharing an anchor with it, found through this
+-- index, and the queries without anchors.
+CREATE INDEX percolator_queries_anchors_idx ON paradedb.percolator_queries USING gin (anchors);
+
+SELECT pg_catalog.pg_extension_config_dump('paradedb.percolator_queries', '');
+"#,
+    name = "percolator_queries_table"
+);
+
+fn search_index(index_name: &str) -> &'static mut SearchIndex {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"))
+}
+
+/// Store `query` under `name` in the percolator of the index, replacing any query stored
+/// under it before, so that `percolate` reports the documents it matches.
+#[pg_extern]
+pub fn register_query(index_name: &str, name: &str, query: SearchQueryInput) {
+    let search_index = search_index(index_name);
+    let query_json = serde_json::to_value(&query)
+        .unwrap_or_else(|err| panic!("error serializing query '{name}': {err}"));
+    let anchors = anchor_terms(&query, &search_index.schema).map_or_else(
+        || "NULL".to_string(),
+        |anchors| {
+            let anchors: Vec<String> = anchors.iter().map(spi::quote_literal).collect();
+            format!("ARRAY[{}]::text[]", anchors.join(", "))
+        },
+    );
+
+    Spi::run(&format!(
+        "INSERT INTO paradedb.percolator_queries (index_name, name, query, anchors) \
+         VALUES ({}, {}, {}::jsonb, {anchors}) \
+         ON CONFLICT (index_name, name) DO UPDATE SET query = EXCLUDED.query, \
+         anchors = EXCLUDED.anchors",
+        spi::quote_literal(index_name),
+        spi::quote_literal(name),
+        spi::quote_literal(query_json.to_string()),
+    ))
+    .unwrap_or_else(|err| panic!("error registering query '{name}': {err}"));
+}
+
+#[pg_extern]
+pub fn unregister_query(index_name: &str, name: &str) -> bool {
+    Spi::get_one::<bool>(&format!(
+        "WITH deleted AS (DELETE FROM paradedb.percolator_queries \
+         WHERE index_name = {} AND name = {} RETURNING 1) \
+         SELECT count(*) > 0 FROM deleted",
+        spi::quote_literal(index_name),
+        spi::quote_literal(name)
+    ))
+    .unwrap_or_else(|err| panic!("error unregistering query '{name}': {err}"))
+    .unwrap_or_default()
+}
+
+/// The names of the queries registered with the percolator of the index that match
+/// `document`, a JSON object of column names to values such as `to_jsonb(NEW)` in a trigger.
+/// The document is indexed on its own in memory, so the table isn't scanned, and only the
+/// queries that could match its terms are run.
+#[pg_extern(stable)]
+pub fn percolate(
+    index_name: &str,
+    document: JsonB,
+) -> TableIterator<'static, (name!(query_name, String),)> {
+    let JsonB(document) = document;
+    let Value::Object(document) = document else {
+        panic!("the document percolated through index '{index_name}' must be a JSON object")
+    };
+
+    let percolator = Percolator::new(search_index(index_name), &document)
+        .unwrap_or_else(|err| panic!("error indexing document to percolate: {err}"));
+    let terms: Vec<String> = percolator
+        .terms()
+        .unwrap_or_else(|err| panic!("error reading terms of percolated document: {err}"))
+        .into_iter()
+        .collect();
+
+    let candidates = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT name, query FROM paradedb.percolator_queries \
+                 WHERE index_name = $1 AND (anchors IS NULL OR anchors && $2) ORDER BY name",
+                None,
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), index_name.into_datum()),
+                    (PgBuiltInOids::TEXTARRAYOID.oid(), terms.into_datum()),
+                ]),
+            )?
+            .map(|row| Ok((row.get::<String>(1)?, row.get::<JsonB>(2)?)))
+            .collect::<Result<Vec<_>, spi::Error>>()
+    })
+    .unwrap_or_else(|err| panic!("error loading queries of index '{index_name}': {err}"));
+
+    let mut matches = vec![];
+    for (name, query) in candidates {
+        let (Some(name), Some(JsonB(query))) = (name, query) else {
+            continue;
+        };
+        let query: SearchQueryInput = serde_json::from_value(query)
+            .unwrap_or_else(|err| panic!("error deserializing query '{name}': {err}"));
+        if percolator
+            .matches(query)
+            .unwrap_or_else(|err| panic!("error running query '{name}': {err}"))
+        {
+            matches.push((name,));
+        }
+    }
+    TableIterator::new(matches)
+}
@@ -0,0 +1,119 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+
+use crate::index::SearchIndex;
+use crate::postgres::utils::bm25_index_name;
+use crate::query::ranking::RankingProfile;
+use crate::schema::SearchFieldType;
+use crate::writer::WriterDirectory;
+
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.ranking_profiles (
+    index_name text NOT NULL,
+    name text NOT NULL,
+    profile jsonb NOT NULL,
+    previous_profile jsonb,
+    updated_at timestamptz NOT NULL DEFAULT now(),
+    PRIMARY KEY (index_name, name)
+);
+
+SELECT pg_catalog.pg_extension_config_dump('paradedb.ranking_profiles', '');
+"#,
+    name = "ranking_profiles_table"
+);
+
+/// Save `profile` as the ranking profile `name` of the index, replacing any profile saved
+/// under it before, which `rollback_ranking_profile` can restore. A profile has `boosts`,
+/// which multiply the scores of the clauses on each field, and a `decay`, which lowers the
+/// scores of documents with the age of a fast date field.
+#[pg_extern]
+pub fn save_ranking_profile(index_name: &str, name: &str, profile: JsonB) {
+    let JsonB(profile_json) = profile;
+    let profile: RankingProfile = serde_json::from_value(profile_json.clone())
+        .unwrap_or_else(|err| panic!("invalid ranking profile '{name}': {err}"));
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    let schema = &search_index.schema;
+    for field in profile.boosts.keys() {
+        if schema.get_search_field(field.as_str()).is_none() {
+            panic!("cannot boost '{field}', it is not a field of index {index_name}");
+        }
+    }
+    if let Some(decay) = &profile.decay {
+        let is_fast_date = schema
+            .get_search_field(decay.field.as_str())
+            .filter(|field| field.type_ == SearchFieldType::Date)
+            .is_some_and(|field| schema.schema.get_field_entry(field.id.0).is_fast());
+        if !is_fast_date {
+            panic!(
+                "cannot decay by '{}', it is not a fast date field",
+                decay.field
+            );
+        }
+        if decay.scale_days <= 0.0 || !(0.0..=1.0).contains(&decay.decay) {
+            panic!("decay scale_days must be positive, and decay between 0 and 1");
+        }
+    }
+
+    Spi::run(&format!(
+        "INSERT INTO paradedb.ranking_profiles (index_name, name, profile) \
+         VALUES ({}, {}, {}::jsonb) \
+         ON CONFLICT (index_name, name) DO UPDATE SET profile = EXCLUDED.profile, \
+         previous_profile = ranking_profiles.profile, updated_at = now()",
+        spi::quote_literal(&bm25_index_name),
+        spi::quote_literal(name),
+        spi::quote_literal(profile_json.to_string()),
+    ))
+    .unwrap_or_else(|err| panic!("error saving ranking profile '{name}': {err}"));
+}
+
+/// Restore the ranking profile `name` of the index to what it was before it was last saved.
+#[pg_extern]
+pub fn rollback_ranking_profile(index_name: &str, name: &str) {
+    let rolled_back = Spi::get_one::<bool>(&format!(
+        "WITH rolled_back AS (UPDATE paradedb.ranking_profiles \
+         SET profile = previous_profile, previous_profile = profile, updated_at = now() \
+         WHERE index_name = {} AND name = {} AND previous_profile IS NOT NULL RETURNING 1) \
+         SELECT count(*) > 0 FROM rolled_back",
+        spi::quote_literal(bm25_index_name(index_name)),
+        spi::quote_literal(name)
+    ))
+    .unwrap_or_else(|err| panic!("error rolling back ranking profile '{name}': {err}"))
+    .unwrap_or_default();
+    if !rolled_back {
+        panic!("ranking profile '{name}' of index {index_name} has no previous version");
+    }
+}
+
+#[pg_extern]
+pub fn drop_ranking_profile(index_name: &str, name: &str) -> bool {
+    Spi::get_one::<bool>(&format!(
+        "WITH deleted AS (DELETE FROM paradedb.ranking_profiles \
+         WHERE index_name = {} AND name = {} RETURNING 1) \
+         SELECT count(*) > 0 FROM deleted",
+        spi::quote_literal(bm25_index_name(index_name)),
+        spi::quote_literal(name)
+    ))
+    .unwrap_or_else(|err| panic!("error dropping ranking profile '{name}': {err}"))
+    .unwrap_or_default()
+}
@@ -0,0 +1,236 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::env::needs_commit;
+use crate::globals::WriterGlobal;
+use crate::index::relevance::{RankCorrelation, RankingMetrics};
+use crate::index::SearchIndex;
+use crate::postgres::utils::bm25_index_name;
+use crate::query::SearchQueryInput;
+use crate::schema::SearchConfig;
+use crate::writer::WriterDirectory;
+
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.judgments (
+    list_name text NOT NULL,
+    query jsonb NOT NULL,
+    key text NOT NULL,
+    grade real NOT NULL CHECK (grade >= 0),
+    PRIMARY KEY (list_name, query, key)
+);
+
+SELECT pg_catalog.pg_extension_config_dump('paradedb.judgments', '');
+"#,
+    name = "judgments_table"
+);
+
+/// Grade how relevant the row with `key` is to `query` in the judgment list `list_name`,
+/// replacing any grade given to it before. Grades are 0 for irrelevant rows and higher for
+/// more relevant ones, usually up to 3.
+#[pg_extern]
+pub fn add_judgment(list_name: &str, query: SearchQueryInput, key: &str, grade: f32) {
+    if grade < 0.0 {
+        panic!("grade must be at least 0, got {grade}");
+    }
+    let query_json =
+        serde_json::to_value(&query).unwrap_or_else(|err| panic!("error serializing query: {err}"));
+
+    Spi::run(&format!(
+        "INSERT INTO paradedb.judgments (list_name, query, key, grade) \
+         VALUES ({}, {}::jsonb, {}, {grade}) \
+         ON CONFLICT (list_name, query, key) DO UPDATE SET grade = EXCLUDED.grade",
+        spi::quote_literal(list_name),
+        spi::quote_literal(query_json.to_string()),
+        spi::quote_literal(key),
+    ))
+    .unwrap_or_else(|err| panic!("error adding judgment to list '{list_name}': {err}"));
+}
+
+#[pg_extern]
+pub fn drop_judgments(list_name: &str) -> i64 {
+    Spi::get_one::<i64>(&format!(
+        "WITH deleted AS (DELETE FROM paradedb.judgments WHERE list_name = {} RETURNING 1) \
+         SELECT count(*) FROM deleted",
+        spi::quote_literal(list_name)
+    ))
+    .unwrap_or_else(|err| panic!("error dropping judgment list '{list_name}': {err}"))
+    .unwrap_or_default()
+}
+
+/// Run each query of the judgment list `list_name` against the index, and measure how well
+/// its top `k` results match the grades of the list. Evaluating the same list against
+/// indexes with different tokenizers, or queries with different boosts, shows which ranks
+/// better before it's rolled out. Like aggregates, searches read committed documents.
+#[pg_extern]
+pub fn evaluate_relevance(
+    index_name: &str,
+    list_name: &str,
+    k: default!(i32, 10),
+) -> TableIterator<
+    'static,
+    (
+        name!(query, JsonB),
+        name!(ndcg, f64),
+        name!(mrr, f64),
+        name!(precision, f64),
+        name!(recall, f64),
+    ),
+> {
+    if k < 1 {
+        panic!("k must be at least 1, got {k}");
+    }
+
+    let judgments = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT query, key, grade FROM paradedb.judgments WHERE list_name = $1",
+                None,
+                Some(vec![(PgBuiltInOids::TEXTOID.oid(), list_name.into_datum())]),
+            )?
+            .map(|row| {
+                Ok((
+                    row.get::<JsonB>(1)?,
+                    row.get::<String>(2)?,
+                    row.get::<f32>(3)?,
+                ))
+            })
+            .collect::<Result<Vec<_>, spi::Error>>()
+    })
+    .unwrap_or_else(|err| panic!("error loading judgment list '{list_name}': {err}"));
+
+    // The grades of each query, by key. Queries are grouped by their serialized form, which
+    // also orders the results.
+    let mut queries: BTreeMap<String, (Value, HashMap<String, f64>)> = BTreeMap::new();
+    for (query, key, grade) in judgments {
+        let (Some(JsonB(query)), Some(key), Some(grade)) = (query, key, grade) else {
+            continue;
+        };
+        queries
+            .entry(query.to_string())
+            .or_insert_with(|| (query, HashMap::new()))
+            .1
+            .insert(key, grade as f64);
+    }
+    if queries.is_empty() {
+        panic!("judgment list '{list_name}' is empty");
+    }
+
+    let search_index = load_index(index_name);
+    let mut rows = vec![];
+    for (query_json, grades) in queries.into_values() {
+        let query: SearchQueryInput = serde_json::from_value(query_json.clone())
+            .unwrap_or_else(|err| panic!("error deserializing query {query_json}: {err}"));
+        let ranked = top_keys(search_index, query, None, k as usize);
+
+        let metrics = RankingMetrics::new(&ranked, &grades, k as usize);
+        rows.push((
+            JsonB(query_json),
+            metrics.ndcg,
+            metrics.mrr,
+            metrics.precision,
+            metrics.recall,
+        ));
+    }
+    TableIterator::new(rows)
+}
+
+/// Run `query` against the index ranked two ways, by the ranking profiles `profile_a` and
+/// `profile_b`, which default to the plain ranking, and optionally against another index
+/// `index_b` of the same table, and return the top `k` keys of both rankings along with how
+/// much they agree. It evaluates a change of relevance on real queries before it's rolled out.
+#[pg_extern]
+pub fn compare_rankings(
+    index_name: &str,
+    query: SearchQueryInput,
+    profile_a: default!(Option<String>, "NULL"),
+    profile_b: default!(Option<String>, "NULL"),
+    index_b: default!(Option<String>, "NULL"),
+    k: default!(i32, 10),
+) -> TableIterator<
+    'static,
+    (
+        name!(ranking_a, Vec<String>),
+        name!(ranking_b, Vec<String>),
+        name!(overlap, f64),
+        name!(kendall_tau, Option<f64>),
+        name!(rbo, f64),
+    ),
+> {
+    if k < 1 {
+        panic!("k must be at least 1, got {k}");
+    }
+
+    let search_index_a = load_index(index_name);
+    let ranking_a = top_keys(search_index_a, query.clone(), profile_a, k as usize);
+    let search_index_b = load_index(index_b.as_deref().unwrap_or(index_name));
+    let ranking_b = top_keys(search_index_b, query, profile_b, k as usize);
+
+    let correlation = RankCorrelation::new(&ranking_a, &ranking_b);
+    TableIterator::once((
+        ranking_a,
+        ranking_b,
+        correlation.overlap,
+        correlation.kendall_tau,
+        correlation.rbo,
+    ))
+}
+
+fn load_index(index_name: &str) -> &'static mut SearchIndex {
+    let directory = WriterDirectory::from_index_name(&bm25_index_name(index_name));
+    SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"))
+}
+
+/// The keys of the top `k` results of `query`, ranked by the ranking profile `profile` if
+/// it's set. Like aggregates, searches read committed documents.
+fn top_keys(
+    search_index: &SearchIndex,
+    query: SearchQueryInput,
+    profile: Option<String>,
+    k: usize,
+) -> Vec<String> {
+    let bm25_index_name = search_index.directory.index_name.clone();
+    let search_config = SearchConfig {
+        query,
+        index_name: bm25_index_name.clone(),
+        key_field: search_index.schema.key_field().name.0,
+        limit_rows: Some(k),
+        stable_sort: Some(true),
+        profile,
+        uuid: search_index.uuid.clone(),
+        ..Default::default()
+    };
+    let mut search_state = search_index
+        .search_state(
+            &WriterGlobal::client(),
+            &search_config,
+            needs_commit(&bm25_index_name),
+        )
+        .unwrap_or_else(|err| panic!("error preparing search: {err}"));
+    let top_docs: Vec<_> = search_state
+        .search_dedup(&SearchIndex::executor())
+        .collect();
+    top_docs
+        .into_iter()
+        .map(|(_, doc_address)| search_state.key_value(doc_address).to_string())
+        .collect()
+}
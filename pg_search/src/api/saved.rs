@@ -0,0 +1,131 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+use serde_json::{Map, Value};
+
+use crate::index::SearchIndex;
+use crate::postgres::utils::bm25_index_name;
+use crate::query::template::{QueryStringTemplate, QueryTemplate};
+use crate::query::SearchQueryInput;
+use crate::writer::WriterDirectory;
+
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.saved_queries (
+    name text PRIMARY KEY,
+    query jsonb NOT NULL,
+    parameters text[] NOT NULL DEFAULT '{}',
+    description text,
+    owner name NOT NULL DEFAULT current_user,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- Saved queries are user data, so they're included in dumps of the database.
+SELECT pg_catalog.pg_extension_config_dump('paradedb.saved_queries', '');
+"#,
+    name = "saved_queries_table"
+);
+
+/// Save `query` under `name`, replacing any query saved under it before. Strings of the query
+/// that are exactly `$` followed by a name, like the value of `paradedb.term('description',
+/// '$term')`, are parameters that `run_saved` binds.
+#[pg_extern]
+pub fn save_query(
+    name: &str,
+    query: SearchQueryInput,
+    description: default!(Option<String>, "NULL"),
+) {
+    let query_json = serde_json::to_value(&query)
+        .unwrap_or_else(|err| panic!("error serializing query '{name}': {err}"));
+    let parameters: Vec<String> = QueryTemplate(query_json.clone())
+        .parameters()
+        .iter()
+        .map(spi::quote_literal)
+        .collect();
+
+    Spi::run(&format!(
+        "INSERT INTO paradedb.saved_queries (name, query, parameters, description) \
+         VALUES ({}, {}::jsonb, ARRAY[{}]::text[], {}) \
+         ON CONFLICT (name) DO UPDATE SET query = EXCLUDED.query, \
+         parameters = EXCLUDED.parameters, description = EXCLUDED.description, \
+         owner = current_user, updated_at = now()",
+        spi::quote_literal(name),
+        spi::quote_literal(query_json.to_string()),
+        parameters.join(", "),
+        description.map_or("NULL".to_string(), spi::quote_literal)
+    ))
+    .unwrap_or_else(|err| panic!("error saving query '{name}': {err}"));
+}
+
+/// The query saved under `name`, with its parameters bound to the values of `params`, to be
+/// passed to a search like any other query object.
+#[pg_extern(stable, parallel_safe)]
+pub fn run_saved(name: &str, params: default!(JsonB, "'{}'::jsonb")) -> SearchQueryInput {
+    let JsonB(params) = params;
+    let params: Map<String, Value> = match params {
+        Value::Object(params) => params,
+        _ => panic!("the parameters of saved query '{name}' must be a JSON object"),
+    };
+
+    let JsonB(query_json) = Spi::get_one::<JsonB>(&format!(
+        "SELECT query FROM paradedb.saved_queries WHERE name = {}",
+        spi::quote_literal(name)
+    ))
+    .unwrap_or_else(|err| panic!("error loading saved query '{name}': {err}"))
+    .unwrap_or_else(|| panic!("no query is saved as '{name}'"));
+
+    QueryTemplate(query_json)
+        .bind(&params)
+        .unwrap_or_else(|err| panic!("error running saved query '{name}': {err}"))
+}
+
+/// The query string `template` of the index with its `{{name}}` placeholders bound to the
+/// values of `params`, checked against the types of their fields, to be passed to a search
+/// like any other query object.
+#[pg_extern(stable, parallel_safe)]
+pub fn search_template(index_name: &str, template: &str, params: JsonB) -> SearchQueryInput {
+    let JsonB(params) = params;
+    let params: Map<String, Value> = match params {
+        Value::Object(params) => params,
+        _ => panic!("the parameters of a search template must be a JSON object"),
+    };
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    let query_string = QueryStringTemplate(template)
+        .bind(&search_index.schema, &params)
+        .unwrap_or_else(|err| panic!("error binding search template: {err}"));
+    SearchQueryInput::Parse {
+        query_string,
+        conjunction: None,
+    }
+}
+
+#[pg_extern]
+pub fn drop_saved_query(name: &str) -> bool {
+    Spi::get_one::<bool>(&format!(
+        "WITH deleted AS (DELETE FROM paradedb.saved_queries WHERE name = {} RETURNING 1) \
+         SELECT count(*) > 0 FROM deleted",
+        spi::quote_literal(name)
+    ))
+    .unwrap_or_else(|err| panic!("error dropping saved query '{name}': {err}"))
+    .unwrap_or_default()
+}
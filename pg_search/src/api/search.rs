@@ -1,13 +1,24 @@
 use crate::env::needs_commit;
+use crate::index::history::IndexHistory;
 use crate::index::state::{SearchAlias, SearchStateManager};
-use crate::schema::SearchConfig;
+use crate::index::suggest::completions;
+use crate::index::terms;
+use crate::postgres::security::{secure_query, security_query};
+use crate::postgres::types::TantivyValue;
+use crate::postgres::utils::{bm25_index_name, index_config};
+use crate::postgres::wait::SearchWaitEvent;
+use crate::query::SearchQueryInput;
+use crate::schema::{field_value, SearchConfig};
 use crate::writer::{WriterClient, WriterDirectory};
+use crate::SEARCH_GUCS;
 use crate::{globals::WriterGlobal, index::SearchIndex, postgres::utils::get_search_index};
 use anyhow::{anyhow, Result};
 use pgrx::{prelude::TableIterator, *};
+use serde_json::Value;
 use tantivy::aggregation::agg_req::Aggregations;
 use tantivy::aggregation::agg_result::AggregationResults;
-use tantivy::aggregation::AggregationCollector;
+use tantivy::aggregation::{AggregationCollector, AggregationLimits};
+use tantivy::TantivyDocument;
 
 const DEFAULT_SNIPPET_PREFIX: &str = "<b>";
 const DEFAULT_SNIPPET_POSTFIX: &str = "</b>";
@@ -18,6 +29,26 @@ pub fn rank_bm25(key: i64, alias: default!(Option<String>, "NULL")) -> f32 {
         .expect("could not lookup doc address for search query")
 }
 
+/// The position of the result with `key` in the ordering of the search, starting at 1, so
+/// that it can be logged or interleaved with other rankings without a window function.
+#[pg_extern]
+pub fn rank_position(key: i64, alias: default!(Option<String>, "NULL")) -> i64 {
+    let key = TantivyValue::try_from(key).expect("could not convert key for ranking");
+    SearchStateManager::get_rank(key, alias.map(SearchAlias::from))
+        .expect("could not lookup rank for search query") as i64
+}
+
+/// The number of documents matching a search that ran earlier in the transaction with
+/// `total_hits`, and whether it is exact or a lower bound of the total.
+#[pg_extern]
+pub fn total_hits(
+    alias: default!(Option<String>, "NULL"),
+) -> TableIterator<'static, (name!(total, i64), name!(exact, bool))> {
+    let total_hits = SearchStateManager::get_total_hits(alias.map(SearchAlias::from))
+        .unwrap_or_else(|err| panic!("{err}"));
+    TableIterator::once((total_hits.count as i64, total_hits.exact))
+}
+
 #[pg_extern]
 pub fn highlight(
     key: i64,
@@ -49,6 +80,50 @@ pub fn highlight(
     snippet.to_html()
 }
 
+/// Where the terms of the query are in `field` of the result with `key`, as byte and
+/// character offsets into the text of the column, for frontends that highlight the text
+/// themselves instead of using `highlight`. Only the results of the search are read, so the
+/// security filter of the index applies.
+#[pg_extern]
+pub fn highlight_offsets(
+    key: i64,
+    field: &str,
+    alias: default!(Option<String>, "NULL"),
+) -> TableIterator<
+    'static,
+    (
+        name!(term, String),
+        name!(start_byte, i32),
+        name!(end_byte, i32),
+        name!(start_char, i32),
+        name!(end_char, i32),
+    ),
+> {
+    let key = TantivyValue::try_from(key).expect("could not convert key for highlighting");
+    let offsets =
+        SearchStateManager::get_highlight_offsets(key, field, alias.map(SearchAlias::from))
+            .expect("could not find offsets for highlighting");
+
+    TableIterator::new(offsets.into_iter().map(|offset| {
+        (
+            offset.term,
+            offset.start_byte as i32,
+            offset.end_byte as i32,
+            offset.start_char as i32,
+            offset.end_char as i32,
+        )
+    }))
+}
+
+/// The names of the queries labeled with `paradedb.named` that match the result with `key`,
+/// to explain why it matched without running each of them again.
+#[pg_extern]
+pub fn matched_queries(key: i64, alias: default!(Option<String>, "NULL")) -> Vec<String> {
+    let key = TantivyValue::try_from(key).expect("could not convert key for matched queries");
+    SearchStateManager::get_matched_queries(key, alias.map(SearchAlias::from))
+        .expect("could not find matched queries")
+}
+
 #[allow(clippy::not_unsafe_ptr_arg_deref)]
 #[pg_extern]
 pub fn minmax_bm25(
@@ -65,7 +140,7 @@ pub fn minmax_bm25(
         .unwrap();
 
     // Collect into a Vec to allow multiple iterations
-    let top_docs: Vec<_> = scan_state.search_dedup(search_index.executor).collect();
+    let top_docs: Vec<_> = scan_state.search_dedup(&SearchIndex::executor()).collect();
 
     // Calculate min and max scores
     let (min_score, max_score) = top_docs
@@ -101,6 +176,7 @@ fn drop_bm25_internal(index_name: &str) {
             .expect("could not lock writer on drop_bm25")
             .request(crate::writer::WriterRequest::Commit {
                 directory: writer_directory,
+                pid: std::process::id(),
             })
             .expect("error committing existing transaction during drop_bm25");
     }
@@ -126,19 +202,438 @@ pub fn aggregate_internal(
 
     let tantivy_aggs: Aggregations = serde_json::from_str(&aggs)?;
     let tantivy_query = search_config
-        .query
+        .search_query(&search_index.schema)
         .into_tantivy_query(&search_index.schema, &mut search_index.query_parser())?;
-    let collector = AggregationCollector::from_aggs(tantivy_aggs, Default::default());
+    // The limits are shared by the collectors of every segment, so they account for the memory
+    // and buckets of the whole aggregation, however many threads it runs on.
+    let limits = AggregationLimits::new(
+        Some(SEARCH_GUCS.aggregate_memory_limit.get() as u64 * 1_000_000),
+        Some(SEARCH_GUCS.aggregate_bucket_limit.get() as u32),
+    );
+    let collector = AggregationCollector::from_aggs(tantivy_aggs, limits);
 
+    // Each segment is aggregated on a thread of the search pool, and the intermediate results
+    // of the segments are then merged together.
     let searcher = search_index.searcher();
+    let _wait = SearchWaitEvent::QueryExecute.start();
     let results: AggregationResults = searcher.search_with_executor(
         &tantivy_query,
         &collector,
-        &search_index.executor,
-        tantivy::query::EnableScoring::Enabled {
-            searcher: &searcher,
-            statistics_provider: &searcher,
-        },
+        &SearchIndex::executor(),
+        tantivy::query::EnableScoring::disabled_from_searcher(&searcher),
     )?;
     Ok(JsonB(serde_json::to_value(results)?))
 }
+
+/// Stream the fast field values of the documents matching `query` as columns, one row per
+/// field and batch of `batch_size` documents. Each row holds a JSON array of values, in the
+/// same document order for every field of a batch.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+pub fn fast_fields(
+    index_name: &str,
+    query: SearchQueryInput,
+    fields: Vec<String>,
+    batch_size: default!(i32, 10000),
+) -> TableIterator<
+    'static,
+    (
+        name!(batch, i64),
+        name!(field, String),
+        name!(field_values, JsonB),
+    ),
+> {
+    if batch_size < 1 {
+        panic!("batch_size must be at least 1, got {batch_size}");
+    }
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let query = secure_query(&bm25_index_name, &search_index.schema, query);
+    let columns = search_index
+        .fast_field_columns(query, &fields)
+        .unwrap_or_else(|err| panic!("error reading fast fields of index '{index_name}': {err}"));
+    let num_docs = columns.first().map_or(0, Vec::len);
+
+    let mut rows = vec![];
+    for (batch, start) in (0..num_docs).step_by(batch_size as usize).enumerate() {
+        let end = (start + batch_size as usize).min(num_docs);
+        for (field, column) in fields.iter().zip(&columns) {
+            rows.push((
+                batch as i64,
+                field.clone(),
+                JsonB(Value::Array(column[start..end].to_vec())),
+            ));
+        }
+    }
+    TableIterator::new(rows)
+}
+
+/// The stored `fields` of the row with key `key`, or all of its stored fields, read from the
+/// documents of the index instead of the table. Like aggregates, it reads committed documents,
+/// and returns NULL if the index has no row with that key.
+#[pg_extern]
+pub fn stored_fields(
+    index_name: &str,
+    key: &str,
+    fields: default!(Option<Vec<String>>, "NULL"),
+) -> Option<JsonB> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let schema = &search_index.schema;
+    let stored_fields: Vec<_> = match fields {
+        Some(names) => names
+            .into_iter()
+            .map(|name| {
+                let search_field = schema
+                    .get_search_field(name.as_str())
+                    .unwrap_or_else(|| panic!("field '{name}' does not exist in the index"));
+                if !schema.schema.get_field_entry(search_field.id.0).is_stored() {
+                    panic!("field '{name}' is not stored in the index");
+                }
+                (name, search_field.id.0)
+            })
+            .collect(),
+        // The ctid of a document is where its row was, which isn't a field of the row.
+        None => schema
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(index, search_field)| {
+                *index != schema.ctid
+                    && schema.schema.get_field_entry(search_field.id.0).is_stored()
+            })
+            .map(|(_, search_field)| (search_field.name.0.clone(), search_field.id.0))
+            .collect(),
+    };
+
+    let key_field = schema.key_field().name.0;
+    let value = field_value(schema, &key_field, key)
+        .unwrap_or_else(|err| panic!("key '{key}' of index '{index_name}': {err}"));
+    let query = SearchQueryInput::Term {
+        field: Some(key_field),
+        value,
+    };
+    let query = secure_query(&bm25_index_name, schema, query);
+    let document = search_index.stored_document(query).unwrap_or_else(|err| {
+        panic!("error reading stored fields of index '{index_name}': {err}")
+    })?;
+
+    let doc: serde_json::Map<String, Value> = stored_fields
+        .into_iter()
+        .map(|(name, field)| {
+            let value = document
+                .get_first(field)
+                .map(|value| {
+                    serde_json::to_value(value).expect("could not convert stored field to json")
+                })
+                .unwrap_or(Value::Null);
+            (name, value)
+        })
+        .collect();
+    Some(JsonB(Value::Object(doc)))
+}
+
+/// The search config that the `search` function of `index_name` passes to the `@@@` operator
+/// for `query`, to search the index in a `WHERE` clause of its own. Comparisons of indexed
+/// columns with constants in the same clause, like `rating >= 4`, are searched for along with
+/// the query instead of filtering the rows it returns.
+#[pg_extern(stable)]
+pub fn search_config(index_name: &str, query: SearchQueryInput) -> JsonB {
+    let mut config =
+        index_config(index_name).unwrap_or_else(|| panic!("no bm25 index named '{index_name}'"));
+    config["query"] = serde_json::to_value(query).expect("could not convert query to json");
+    JsonB(config)
+}
+
+/// Autocomplete `prefix` with the terms of a text field, heaviest first. Terms weigh the
+/// number of documents that contain them, or the sum of the numeric fast field
+/// `weight_field` over those documents.
+#[pg_extern]
+pub fn suggest(
+    index_name: &str,
+    field: &str,
+    prefix: &str,
+    size: default!(i32, 5),
+    weight_field: default!(Option<String>, "NULL"),
+) -> TableIterator<'static, (name!(suggestion, String), name!(weight, f64))> {
+    if size < 1 {
+        panic!("size must be at least 1, got {size}");
+    }
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let filter = security_query(&bm25_index_name, &search_index.schema);
+    let suggestions = completions(
+        search_index,
+        field,
+        prefix,
+        size as usize,
+        weight_field.as_deref(),
+        filter,
+    )
+    .unwrap_or_else(|err| panic!("error suggesting completions from index '{index_name}': {err}"));
+    TableIterator::new(
+        suggestions
+            .into_iter()
+            .map(|suggestion| (suggestion.term, suggestion.weight)),
+    )
+}
+
+/// The number of live documents whose text field `field` contains `term`, the number of times
+/// it occurs in them, the number of live documents of the index, and the inverse document
+/// frequency that BM25 weighs the term with. The term is looked up as it was indexed. Like
+/// searches, it only counts the documents that the security filter of the index lets through.
+#[pg_extern]
+pub fn term_stats(
+    index_name: &str,
+    field: &str,
+    term: &str,
+) -> TableIterator<
+    'static,
+    (
+        name!(doc_freq, i64),
+        name!(total_term_freq, i64),
+        name!(num_docs, i64),
+        name!(idf, f64),
+    ),
+> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let filter = security_query(&bm25_index_name, &search_index.schema);
+    let stats = terms::term_stats(search_index, field, term, filter)
+        .unwrap_or_else(|err| panic!("error reading term stats of index '{index_name}': {err}"));
+    TableIterator::once((
+        stats.frequency.doc_freq as i64,
+        stats.frequency.total_term_freq as i64,
+        stats.num_docs as i64,
+        stats.idf(),
+    ))
+}
+
+/// The `size` terms of the text field `field` that the most live documents contain, with
+/// the number of those documents and of times the term occurs in them.
+#[pg_extern]
+pub fn top_terms(
+    index_name: &str,
+    field: &str,
+    size: default!(i32, 10),
+) -> TableIterator<
+    'static,
+    (
+        name!(term, String),
+        name!(doc_freq, i64),
+        name!(total_term_freq, i64),
+    ),
+> {
+    if size < 1 {
+        panic!("size must be at least 1, got {size}");
+    }
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let filter = security_query(&bm25_index_name, &search_index.schema);
+    let frequencies = terms::top_terms(search_index, field, size as usize, filter)
+        .unwrap_or_else(|err| panic!("error reading top terms of index '{index_name}': {err}"));
+    TableIterator::new(frequencies.into_iter().map(|frequency| {
+        (
+            frequency.term,
+            frequency.doc_freq as i64,
+            frequency.total_term_freq as i64,
+        )
+    }))
+}
+
+/// The distinct values of the text field `field` that start with `prefix`, in order, with the
+/// number of live documents that have them, read from the term dictionaries of the index
+/// instead of the table.
+#[pg_extern]
+pub fn field_values(
+    index_name: &str,
+    field: &str,
+    prefix: default!(&str, "''"),
+    size: default!(i32, 100),
+) -> TableIterator<'static, (name!(value, String), name!(doc_freq, i64))> {
+    if size < 1 {
+        panic!("size must be at least 1, got {size}");
+    }
+
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+
+    let filter = security_query(&bm25_index_name, &search_index.schema);
+    let values = terms::field_values(search_index, field, prefix, size as usize, filter)
+        .unwrap_or_else(|err| panic!("error reading values of index '{index_name}': {err}"));
+    TableIterator::new(
+        values
+            .into_iter()
+            .map(|value| (value.term, value.doc_freq as i64)),
+    )
+}
+
+/// Check `query` against the schema of the index without searching it. The result has every
+/// problem found, each with its kind, its path in the query, its field and a message, and if
+/// there are none, the tantivy query that the search would run, with query strings parsed
+/// and terms typed for their fields.
+#[pg_extern(stable, parallel_safe)]
+pub fn validate_query(index_name: &str, query: SearchQueryInput) -> JsonB {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    let mut parser = search_index.query_parser();
+    let problems = query.validate(&search_index.schema, &mut parser);
+    let query_tree = if problems.is_empty() {
+        query
+            .into_tantivy_query(&search_index.schema, &mut parser)
+            .map(|query| Value::String(format!("{query:#?}")))
+            .unwrap_or_else(|err| panic!("error building query: {err}"))
+    } else {
+        Value::Null
+    };
+
+    JsonB(serde_json::json!({
+        "valid": problems.is_empty(),
+        "problems": problems,
+        "query_tree": query_tree,
+    }))
+}
+
+/// The score and stored `fields` of each document matching the search in `config_json`,
+/// read from the index without going back to the table. It backs the `search_tab` function
+/// of an index, which casts the fields to the types of their columns. Like aggregates, it
+/// reads committed documents, so deleted rows are returned until they're vacuumed.
+#[pg_extern]
+pub fn search_tab_internal(
+    config_json: JsonB,
+    fields: Vec<String>,
+) -> TableIterator<'static, (name!(score, f32), name!(doc, JsonB))> {
+    let JsonB(search_config_json) = config_json;
+    let search_config: SearchConfig =
+        serde_json::from_value(search_config_json).expect("could not parse search config");
+
+    let writer_client = WriterGlobal::client();
+    let directory = WriterDirectory::from_index_name(&search_config.index_name);
+    let search_index = SearchIndex::from_cache(&directory, &search_config.uuid)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    let key_field = search_index.schema.key_field();
+    let mut stored_fields = vec![(key_field.name.0.clone(), key_field.id.0)];
+    for name in &fields {
+        let search_field = search_index
+            .schema
+            .get_search_field(name.as_str())
+            .unwrap_or_else(|| panic!("field '{name}' does not exist in the index"));
+        if !search_index
+            .schema
+            .schema
+            .get_field_entry(search_field.id.0)
+            .is_stored()
+        {
+            panic!("field '{name}' is not stored in the index");
+        }
+        stored_fields.push((name.clone(), search_field.id.0));
+    }
+    let stored_json = |document: &TantivyDocument| {
+        let doc: serde_json::Map<String, Value> = stored_fields
+            .iter()
+            .map(|(name, field)| {
+                let value = document
+                    .get_first(*field)
+                    .map(|value| {
+                        serde_json::to_value(value).expect("could not convert stored field to json")
+                    })
+                    .unwrap_or(Value::Null);
+                (name.clone(), value)
+            })
+            .collect();
+        JsonB(Value::Object(doc))
+    };
+
+    // Searches of a past state also read the replaced and deleted versions of rows, which are
+    // only kept in the index.
+    if let Some(as_of) = search_config.as_of_datetime() {
+        if !IndexHistory::keeps_history(search_index) {
+            panic!(
+                "index {} does not keep history, create it with keep_history to search it as_of a past time",
+                search_config.index_name
+            );
+        }
+        let query = search_config
+            .search_query(&search_index.schema)
+            .into_tantivy_query(&search_index.schema, &mut search_index.query_parser())
+            .unwrap_or_else(|err| panic!("error building query: {err}"));
+        let rows: Vec<_> = IndexHistory::search_as_of(
+            search_index,
+            query.as_ref(),
+            as_of,
+            search_config.limit_rows.unwrap_or(usize::MAX),
+            search_config.offset_rows.unwrap_or(0),
+        )
+        .unwrap_or_else(|err| panic!("error searching index as of {as_of:?}: {err}"))
+        .into_iter()
+        .map(|(score, document)| (score, stored_json(&document)))
+        .collect();
+        return TableIterator::new(rows);
+    }
+
+    let mut scan_state = search_index
+        .search_state(
+            &writer_client,
+            &search_config,
+            needs_commit(&search_config.index_name),
+        )
+        .unwrap_or_else(|err| panic!("error preparing search: {err}"));
+    let top_docs: Vec<_> = {
+        let _wait = SearchWaitEvent::QueryExecute.start();
+        scan_state.search_dedup(&SearchIndex::executor()).collect()
+    };
+
+    let rows: Vec<_> = top_docs
+        .into_iter()
+        .map(|(score, doc_address)| (score, stored_json(&scan_state.doc(doc_address))))
+        .collect();
+    TableIterator::new(rows)
+}
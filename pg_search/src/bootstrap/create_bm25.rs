@@ -3,12 +3,45 @@ use pgrx::prelude::*;
 use pgrx::Spi;
 use serde_json::{json, Value};
 use std::collections::HashSet;
+use uuid::Uuid;
+
+use crate::index::shipping::unix_time;
+use crate::index::source::IndexSource;
+use crate::postgres::utils::bm25_index_name;
+use crate::writer::WriterDirectory;
 
 use super::format::format_aggregate_function;
 use super::format::format_bm25_function;
 use super::format::format_empty_function;
 use super::format::format_hybrid_function;
 
+/// The table of the schema of an index that the rows of its source are copied to, when the
+/// source isn't a table.
+const SOURCE_COPY_TABLE_NAME: &str = "source_rows";
+
+// The config that the functions of each index search with, which names the bm25 index they
+// search, so that paradedb.swap_bm25 swaps indexes with one update. Every role reads it to
+// search, but only changes the rows of the indexes that it created.
+extension_sql!(
+    r#"
+CREATE TABLE paradedb.bm25_indexes (
+    index_name text PRIMARY KEY,
+    config jsonb NOT NULL,
+    search_tab_fields text[] NOT NULL DEFAULT '{}',
+    owner name NOT NULL DEFAULT current_user
+);
+
+ALTER TABLE paradedb.bm25_indexes ENABLE ROW LEVEL SECURITY;
+CREATE POLICY bm25_indexes_read ON paradedb.bm25_indexes FOR SELECT USING (true);
+CREATE POLICY bm25_indexes_write ON paradedb.bm25_indexes
+    USING (pg_has_role(owner, 'USAGE')) WITH CHECK (pg_has_role(owner, 'USAGE'));
+GRANT SELECT, INSERT, UPDATE, DELETE ON paradedb.bm25_indexes TO PUBLIC;
+
+SELECT pg_catalog.pg_extension_config_dump('paradedb.bm25_indexes', '');
+"#,
+    name = "bm25_indexes_table"
+);
+
 #[pg_extern(sql = "
 CREATE OR REPLACE PROCEDURE paradedb.create_bm25(
     index_name text DEFAULT '',
@@ -19,7 +52,23 @@ CREATE OR REPLACE PROCEDURE paradedb.create_bm25(
     numeric_fields text DEFAULT '{}',
     boolean_fields text DEFAULT '{}',
     json_fields text DEFAULT '{}',
-    datetime_fields text DEFAULT '{}'
+    datetime_fields text DEFAULT '{}',
+    vector_fields text DEFAULT '{}',
+    sparse_fields text DEFAULT '{}',
+    document_fields text DEFAULT '{}',
+    merge_policy text DEFAULT '{}',
+    refresh_interval integer DEFAULT 0,
+    directory_mode text DEFAULT 'mmap',
+    writer_memory_budget integer DEFAULT 0,
+    max_index_size integer DEFAULT 0,
+    storage text DEFAULT '',
+    search_tab_fields text[] DEFAULT '{}',
+    modified_column text DEFAULT '',
+    source_refresh_interval integer DEFAULT 0,
+    tenant_field text DEFAULT '',
+    keep_history boolean DEFAULT false,
+    security_filter text DEFAULT '',
+    conjunction_by_default boolean DEFAULT false
 )
 LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
 ")]
@@ -34,6 +83,22 @@ fn create_bm25(
     boolean_fields: &str,
     json_fields: &str,
     datetime_fields: &str,
+    vector_fields: &str,
+    sparse_fields: &str,
+    document_fields: &str,
+    merge_policy: &str,
+    refresh_interval: i32,
+    directory_mode: &str,
+    writer_memory_budget: i32,
+    max_index_size: i32,
+    storage: &str,
+    search_tab_fields: Vec<String>,
+    modified_column: &str,
+    source_refresh_interval: i32,
+    tenant_field: &str,
+    keep_history: bool,
+    security_filter: &str,
+    conjunction_by_default: bool,
 ) -> Result<()> {
     let original_client_min_messages =
         Spi::get_one::<String>("SHOW client_min_messages")?.unwrap_or_default();
@@ -69,25 +134,78 @@ fn create_bm25(
         && boolean_fields == "{}"
         && json_fields == "{}"
         && datetime_fields == "{}"
+        && vector_fields == "{}"
+        && sparse_fields == "{}"
+        && document_fields == "{}"
     {
         bail!(
-            "no text_fields, numeric_fields, boolean_fields, json_fields, or datetime_fields were specified for index {}",
+            "no text_fields, numeric_fields, boolean_fields, json_fields, datetime_fields, vector_fields, sparse_fields, or document_fields were specified for index {}",
             spi::quote_literal(index_name)
         );
     }
 
+    Spi::run(&format!(
+        "CREATE SCHEMA {}",
+        spi::quote_identifier(index_name)
+    ))?;
+
+    // Indexes can only be built on tables, so the rows of other relations, like foreign
+    // tables, are copied to a table of the schema of the index, which is indexed instead.
+    let source_name = format!(
+        "{}.{}",
+        spi::quote_identifier(schema_name),
+        spi::quote_identifier(table_name)
+    );
+    let relkind = Spi::get_one::<String>(&format!(
+        "SELECT relkind::text FROM pg_class WHERE oid = {}::regclass",
+        spi::quote_literal(&source_name)
+    ))?
+    .unwrap_or_default();
+    let source = match relkind.as_str() {
+        "f" | "v" | "m" => {
+            if !modified_column.is_empty() {
+                column_type(schema_name, table_name, modified_column)?;
+            }
+            let copy = spi::quote_qualified_identifier(index_name, SOURCE_COPY_TABLE_NAME);
+            Spi::run(&format!(
+                "CREATE TABLE {copy} (LIKE {source_name}); INSERT INTO {copy} SELECT * FROM {source_name}"
+            ))?;
+            Some(IndexSource {
+                source: source_name,
+                copy,
+                key_field: key_field.to_string(),
+                modified_column: Some(modified_column.to_string())
+                    .filter(|column| !column.is_empty()),
+                refresh_interval: source_refresh_interval.max(0) as u64,
+                refreshed_at: unix_time(),
+            })
+        }
+        _ if !modified_column.is_empty() || source_refresh_interval > 0 => {
+            bail!(
+                "modified_column and source_refresh_interval only apply to foreign tables, views and materialized views, but {} is a table",
+                spi::quote_literal(table_name)
+            );
+        }
+        _ => None,
+    };
+    let (schema_name, table_name) = match source {
+        Some(_) => (index_name, SOURCE_COPY_TABLE_NAME),
+        None => (schema_name, table_name),
+    };
+
+    // The uuid is saved both in the options of the index and in the config that its functions
+    // search with, so that a dump of the database restores an index that its functions can find.
+    let uuid = Uuid::new_v4().to_string();
     let index_json = json!({
         "index_name": format!("{}_bm25_index", index_name),
         "table_name": table_name,
         "key_field": key_field,
-        "schema_name": schema_name
+        "schema_name": schema_name,
+        "tenant_field": Some(tenant_field).filter(|field| !field.is_empty()),
+        "conjunction_by_default": conjunction_by_default,
+        "uuid": uuid
     });
 
-    Spi::run(&format!(
-        "CREATE SCHEMA {}",
-        spi::quote_identifier(index_name)
-    ))?;
-
     let mut column_names = HashSet::new();
     for fields in [
         text_fields,
@@ -95,6 +213,9 @@ fn create_bm25(
         boolean_fields,
         json_fields,
         datetime_fields,
+        vector_fields,
+        sparse_fields,
+        document_fields,
     ] {
         match json5::from_str::<Value>(fields) {
             Ok(obj) => {
@@ -109,6 +230,45 @@ fn create_bm25(
             }
         }
     }
+    for field in &search_tab_fields {
+        if field != key_field && !column_names.contains(field) {
+            bail!(
+                "search_tab_fields must be fields of bm25 index {}, but {} is not",
+                spi::quote_literal(index_name),
+                spi::quote_literal(field)
+            );
+        }
+    }
+
+    if !tenant_field.is_empty() && !column_names.contains(tenant_field) {
+        bail!(
+            "tenant_field must be a field of bm25 index {}, but {} is not",
+            spi::quote_literal(index_name),
+            spi::quote_literal(tenant_field)
+        );
+    }
+    // A tenant is searched as a single term, so a text tenant field must keep its values whole.
+    if !tenant_field.is_empty() {
+        let numeric = json5::from_str::<Value>(numeric_fields)?
+            .get(tenant_field)
+            .is_some();
+        match json5::from_str::<Value>(text_fields)?.get(tenant_field) {
+            Some(config) => {
+                if config.pointer("/tokenizer/type").and_then(Value::as_str) != Some("raw") {
+                    bail!(
+                        "tenant_field {} must use the raw tokenizer",
+                        spi::quote_literal(tenant_field)
+                    );
+                }
+            }
+            None if numeric => {}
+            None => bail!(
+                "tenant_field {} must be a text or integer field",
+                spi::quote_literal(tenant_field)
+            ),
+        }
+    }
+
     let column_names_csv = column_names
         .clone()
         .into_iter()
@@ -116,7 +276,7 @@ fn create_bm25(
         .join(", ");
 
     Spi::run(&format!(
-        "CREATE INDEX {} ON {}.{} USING bm25 ({}, {}) WITH (key_field={}, text_fields={}, numeric_fields={}, boolean_fields={}, json_fields={}, datetime_fields={});",
+        "CREATE INDEX {} ON {}.{} USING bm25 ({}, {}) WITH (key_field={}, text_fields={}, numeric_fields={}, boolean_fields={}, json_fields={}, datetime_fields={}, vector_fields={}, sparse_fields={}, document_fields={}, merge_policy={}, refresh_interval={}, directory_mode={}, writer_memory_budget={}, max_index_size={}, storage={}, keep_history={}, security_filter={}, uuid={});",
         spi::quote_identifier(format!("{}_bm25_index", index_name)),
         spi::quote_identifier(schema_name),
         spi::quote_identifier(table_name),
@@ -127,9 +287,47 @@ fn create_bm25(
         spi::quote_literal(numeric_fields),
         spi::quote_literal(boolean_fields),
         spi::quote_literal(json_fields),
-        spi::quote_literal(datetime_fields)
+        spi::quote_literal(datetime_fields),
+        spi::quote_literal(vector_fields),
+        spi::quote_literal(sparse_fields),
+        spi::quote_literal(document_fields),
+        spi::quote_literal(merge_policy),
+        refresh_interval,
+        spi::quote_literal(directory_mode),
+        writer_memory_budget,
+        max_index_size,
+        spi::quote_literal(storage),
+        keep_history,
+        spi::quote_literal(security_filter),
+        spi::quote_literal(&uuid)
     ))?;
 
+    if let Some(source) = source {
+        source.save(&WriterDirectory::from_index_name(&format!(
+            "{}_bm25_index",
+            index_name
+        )))?;
+    }
+
+    // The schema of the index didn't exist, so a row under its name was left by a schema that
+    // was dropped without drop_bm25, and is replaced.
+    Spi::run_with_args(
+        "INSERT INTO paradedb.bm25_indexes (index_name, config, search_tab_fields) \
+         VALUES ($1, $2, $3) ON CONFLICT (index_name) DO UPDATE \
+         SET config = EXCLUDED.config, search_tab_fields = EXCLUDED.search_tab_fields",
+        Some(vec![
+            (PgBuiltInOids::TEXTOID.oid(), index_name.into_datum()),
+            (
+                PgBuiltInOids::JSONBOID.oid(),
+                pgrx::JsonB(index_json).into_datum(),
+            ),
+            (
+                PgBuiltInOids::TEXTARRAYOID.oid(),
+                search_tab_fields.clone().into_datum(),
+            ),
+        ]),
+    )?;
+
     Spi::run(&format_bm25_function(
         &spi::quote_qualified_identifier(index_name, "search"),
         &format!(
@@ -143,7 +341,7 @@ fn create_bm25(
             spi::quote_identifier(table_name),
             spi::quote_identifier(key_field)
         ),
-        &index_json,
+        index_name,
     ))?;
 
     Spi::run(&format_bm25_function(
@@ -155,7 +353,40 @@ fn create_bm25(
             spi::quote_identifier(table_name),
             spi::quote_identifier(key_field)
         ),
-        &index_json,
+        index_name,
+    ))?;
+
+    // The search_tab function returns the key, the score, and the stored fields chosen here
+    // from the index, typed like their columns, so that they don't have to be read from the
+    // table.
+    let key_type = column_type(schema_name, table_name, key_field)?;
+    let mut tab_return_columns = vec![
+        format!("{} {key_type}", spi::quote_identifier(key_field)),
+        "rank_bm25 real".to_string(),
+    ];
+    let mut tab_select_list = vec![
+        search_tab_column(key_field, &key_type),
+        "__paradedb_tab__.score".to_string(),
+    ];
+    for field in search_tab_fields.iter().filter(|field| *field != key_field) {
+        let field_type = column_type(schema_name, table_name, field)?;
+        tab_return_columns.push(format!("{} {field_type}", spi::quote_identifier(field)));
+        tab_select_list.push(search_tab_column(field, &field_type));
+    }
+    let tab_fields_array = search_tab_fields
+        .iter()
+        .map(spi::quote_literal)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Spi::run(&format_bm25_function(
+        &spi::quote_qualified_identifier(index_name, "search_tab"),
+        &format!("TABLE({})", tab_return_columns.join(", ")),
+        &format!(
+            "RETURN QUERY SELECT {} FROM paradedb.search_tab_internal(__paradedb_search_config__, ARRAY[{tab_fields_array}]::text[]) AS __paradedb_tab__",
+            tab_select_list.join(", ")
+        ),
+        index_name,
     ))?;
 
     Spi::run(&format_empty_function(
@@ -199,12 +430,12 @@ fn create_bm25(
             spi::quote_identifier(schema_name),
             spi::quote_identifier(table_name)
         ),
-        &index_json
+        index_name
     ))?;
 
     Spi::run(&format_aggregate_function(
         &spi::quote_qualified_identifier(index_name, "aggregate"),
-        &index_json,
+        index_name,
     ))?;
 
     Spi::run(&format!(
@@ -215,6 +446,34 @@ fn create_bm25(
     Ok(())
 }
 
+/// The SQL type of a column, like `character varying(255)`.
+fn column_type(schema_name: &str, table_name: &str, column_name: &str) -> Result<String> {
+    let qualified_table_name = format!(
+        "{}.{}",
+        spi::quote_identifier(schema_name),
+        spi::quote_identifier(table_name)
+    );
+    Spi::get_one::<String>(&format!(
+        "SELECT format_type(a.atttypid, a.atttypmod) FROM pg_attribute a WHERE a.attrelid = {}::regclass AND a.attname = {} AND NOT a.attisdropped",
+        spi::quote_literal(&qualified_table_name),
+        spi::quote_literal(column_name)
+    ))?
+    .ok_or_else(|| anyhow::anyhow!("column {} does not exist in table {qualified_table_name}", spi::quote_literal(column_name)))
+}
+
+/// A field of the document returned by `search_tab_internal`, cast to the type of its column.
+/// JSON values are cast from their JSON, and others from their text.
+fn search_tab_column(name: &str, type_name: &str) -> String {
+    let operator = match type_name {
+        "json" | "jsonb" => "->",
+        _ => "->>",
+    };
+    format!(
+        "(__paradedb_tab__.doc {operator} {})::{type_name}",
+        spi::quote_literal(name)
+    )
+}
+
 #[pg_extern(sql = "
 CREATE OR REPLACE PROCEDURE paradedb.drop_bm25(
     index_name text,
@@ -224,6 +483,7 @@ LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
 ")]
 fn drop_bm25(index_name: &str, schema_name: Option<&str>) -> Result<()> {
     let schema_name = schema_name.unwrap_or("current_schema()");
+    let bm25_index_name = bm25_index_name(index_name);
 
     Spi::run(&format!(
         r#"
@@ -234,19 +494,92 @@ fn drop_bm25(index_name: &str, schema_name: Option<&str>) -> Result<()> {
             SELECT INTO original_client_min_messages current_setting('client_min_messages');
             SET client_min_messages TO WARNING;
 
-            EXECUTE 'DROP INDEX IF EXISTS {}.{}_bm25_index'; 
+            EXECUTE 'DROP INDEX IF EXISTS {}.{}'; 
             EXECUTE 'DROP SCHEMA IF EXISTS {} CASCADE';
             PERFORM paradedb.drop_bm25_internal({});
+            DELETE FROM paradedb.bm25_indexes WHERE index_name = {};
 
             EXECUTE 'SET client_min_messages TO ' || quote_literal(original_client_min_messages);
         END;
         $$;
         "#,
         spi::quote_identifier(schema_name),
+        spi::quote_identifier(&bm25_index_name),
         spi::quote_identifier(index_name),
-        spi::quote_identifier(index_name),
+        spi::quote_literal(index_name),
         spi::quote_literal(index_name)
     ))?;
 
     Ok(())
 }
+
+/// Swap the bm25 indexes that the functions of the schemas `index_name` and `other_index_name`
+/// search, by swapping their configs in `paradedb.bm25_indexes` with one update. A new version
+/// of an index, built in the background under another name, goes live at once, and swapping
+/// again rolls it back.
+#[pg_extern(sql = "
+CREATE OR REPLACE PROCEDURE paradedb.swap_bm25(
+    index_name text,
+    other_index_name text
+)
+LANGUAGE c AS 'MODULE_PATHNAME', '@FUNCTION_NAME@';
+")]
+fn swap_bm25(index_name: &str, other_index_name: &str) -> Result<()> {
+    if index_name == other_index_name {
+        bail!(
+            "cannot swap bm25 index {} with itself",
+            spi::quote_literal(index_name)
+        );
+    }
+
+    let mut searched = vec![];
+    for name in [index_name, other_index_name] {
+        let (table, key_field, search_tab_fields) =
+            Spi::get_three_with_args::<pg_sys::Oid, String, Vec<String>>(
+                "SELECT (SELECT i.indrelid FROM pg_index i \
+                 JOIN pg_class c ON c.oid = i.indexrelid JOIN pg_am a ON a.oid = c.relam \
+                 WHERE a.amname = 'bm25' AND c.relname = b.config ->> 'index_name' LIMIT 1), \
+                 b.config ->> 'key_field', b.search_tab_fields \
+                 FROM (SELECT $1 AS index_name) n \
+                 LEFT JOIN paradedb.bm25_indexes b ON b.index_name = n.index_name",
+                vec![(PgBuiltInOids::TEXTOID.oid(), name.into_datum())],
+            )?;
+        match table {
+            Some(table) => searched.push((table, key_field, search_tab_fields)),
+            None => bail!("bm25 index {} does not exist", spi::quote_literal(name)),
+        }
+    }
+    // The functions of an index select the rows of its table by its key field and return its
+    // search_tab_fields, and an index that copies the rows of its source searches the copy in
+    // its own schema, so only indexes that agree on these can trade functions.
+    if searched[0] != searched[1] {
+        bail!(
+            "bm25 indexes {} and {} must index the same table with the same key_field and search_tab_fields to be swapped",
+            spi::quote_literal(index_name),
+            spi::quote_literal(other_index_name)
+        );
+    }
+
+    let swapped = Spi::get_one_with_args::<i64>(
+        "WITH swapped AS ( \
+             UPDATE paradedb.bm25_indexes a SET config = b.config \
+             FROM paradedb.bm25_indexes b \
+             WHERE (a.index_name, b.index_name) IN (($1, $2), ($2, $1)) \
+             RETURNING a.index_name \
+         ) SELECT count(*) FROM swapped",
+        vec![
+            (PgBuiltInOids::TEXTOID.oid(), index_name.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), other_index_name.into_datum()),
+        ],
+    )?;
+    // Rows of indexes that other roles created aren't visible to the update.
+    if swapped != Some(2) {
+        bail!(
+            "must be a member of the roles that created bm25 indexes {} and {} to swap them",
+            spi::quote_literal(index_name),
+            spi::quote_literal(other_index_name)
+        );
+    }
+
+    Ok(())
+}
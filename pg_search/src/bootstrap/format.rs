@@ -1,12 +1,21 @@
-use serde_json::Value;
+use pgrx::spi;
+
+/// The config of the index `index_name`, which its functions read from `paradedb.bm25_indexes`
+/// when they're called, so that `paradedb.swap_bm25` can point them at another index.
+fn index_config(index_name: &str) -> String {
+    format!(
+        "(SELECT config FROM paradedb.bm25_indexes WHERE index_name = {})",
+        spi::quote_literal(index_name)
+    )
+}
 
 pub fn format_bm25_function(
     function_name: &str,
     return_type: &str,
     function_body: &str,
-    index_json: &Value,
+    index_name: &str,
 ) -> String {
-    let index_json_str = serde_json::to_string(&index_json).unwrap();
+    let index_config = index_config(index_name);
     let formatted_sql = format!(
         r#"
         CREATE OR REPLACE FUNCTION {function_name}(
@@ -14,7 +23,17 @@ pub fn format_bm25_function(
             offset_rows integer DEFAULT NULL,
             limit_rows integer DEFAULT NULL,
             alias text DEFAULT NULL,
-            stable_sort boolean DEFAULT NULL
+            stable_sort boolean DEFAULT NULL,
+            exact boolean DEFAULT NULL,
+            scored boolean DEFAULT NULL,
+            total_hits text DEFAULT NULL,
+            total_hits_threshold integer DEFAULT NULL,
+            timeout_ms integer DEFAULT NULL,
+            sort_by text DEFAULT NULL,
+            random_seed bigint DEFAULT NULL,
+            tenant text DEFAULT NULL,
+            as_of timestamptz DEFAULT NULL,
+            profile text DEFAULT NULL
         ) RETURNS {return_type} AS $func$
         BEGIN
             RETURN QUERY SELECT * FROM {function_name}(
@@ -22,7 +41,17 @@ pub fn format_bm25_function(
                 offset_rows => offset_rows,
                 limit_rows => limit_rows,
                 alias => alias,
-                stable_sort => stable_sort
+                stable_sort => stable_sort,
+                exact => exact,
+                scored => scored,
+                total_hits => total_hits,
+                total_hits_threshold => total_hits_threshold,
+                timeout_ms => timeout_ms,
+                sort_by => sort_by,
+                random_seed => random_seed,
+                tenant => tenant,
+                as_of => as_of,
+                profile => profile
             );
         END
         $func$ LANGUAGE plpgsql;
@@ -32,17 +61,37 @@ pub fn format_bm25_function(
             offset_rows integer DEFAULT NULL,
             limit_rows integer DEFAULT NULL,
             alias text DEFAULT NULL,
-            stable_sort boolean DEFAULT NULL
+            stable_sort boolean DEFAULT NULL,
+            exact boolean DEFAULT NULL,
+            scored boolean DEFAULT NULL,
+            total_hits text DEFAULT NULL,
+            total_hits_threshold integer DEFAULT NULL,
+            timeout_ms integer DEFAULT NULL,
+            sort_by text DEFAULT NULL,
+            random_seed bigint DEFAULT NULL,
+            tenant text DEFAULT NULL,
+            as_of timestamptz DEFAULT NULL,
+            profile text DEFAULT NULL
         ) RETURNS {return_type} AS $func$
         DECLARE
             __paradedb_search_config__ JSONB;
         BEGIN
-            __paradedb_search_config__ := '{index_json_str}'::jsonb || jsonb_build_object(
+            __paradedb_search_config__ := {index_config} || jsonb_build_object(
                 'query', query::text::jsonb,
                 'offset_rows', offset_rows,
                 'limit_rows', limit_rows,
                 'alias', alias,
-                'stable_sort', stable_sort
+                'stable_sort', stable_sort,
+                'exact', exact,
+                'scored', scored,
+                'total_hits', total_hits,
+                'total_hits_threshold', total_hits_threshold,
+                'timeout_ms', timeout_ms,
+                'sort_by', sort_by::jsonb,
+                'random_seed', random_seed,
+                'tenant', tenant,
+                'as_of', as_of,
+                'profile', profile
             );
             {function_body};
         END
@@ -57,7 +106,7 @@ pub fn format_hybrid_function(
     function_name: &str,
     return_type: &str,
     function_body: &str,
-    index_json: &Value,
+    index_name: &str,
 ) -> String {
     let formatted_sql = format!(
         r#"
@@ -94,7 +143,7 @@ pub fn format_hybrid_function(
             query text;
         BEGIN
             __paradedb_search_config__ := jsonb_strip_nulls(
-                '{index_json}'::jsonb || jsonb_build_object(
+                {index_config} || jsonb_build_object(
                     'query', bm25_query::text::jsonb,
                     'limit_rows', bm25_limit_n
                 )
@@ -110,7 +159,7 @@ pub fn format_hybrid_function(
         "#,
         function_name = function_name,
         return_type = return_type,
-        index_json = serde_json::to_string(&index_json).unwrap(),
+        index_config = index_config(index_name),
         function_body = function_body
     );
 
@@ -138,8 +187,8 @@ pub fn format_empty_function(
     formatted_sql
 }
 
-pub fn format_aggregate_function(function_name: &str, index_json: &Value) -> String {
-    let index_json_str = serde_json::to_string(&index_json).unwrap();
+pub fn format_aggregate_function(function_name: &str, index_name: &str) -> String {
+    let index_config = index_config(index_name);
     let formatted_sql = format!(
         r#"
         CREATE OR REPLACE FUNCTION {function_name}(
@@ -148,7 +197,7 @@ pub fn format_aggregate_function(function_name: &str, index_json: &Value) -> Str
         BEGIN
             RETURN paradedb.aggregate_internal(
                 aggs,
-                '{index_json_str}'::jsonb || jsonb_build_object(
+                {index_config} || jsonb_build_object(
                     'query', paradedb.all()::text::jsonb
                 )
             );
@@ -162,7 +211,7 @@ pub fn format_aggregate_function(function_name: &str, index_json: &Value) -> Str
         BEGIN
             RETURN paradedb.aggregate_internal(
                 aggs,
-                '{index_json_str}'::jsonb || jsonb_build_object(
+                {index_config} || jsonb_build_object(
                     'query', paradedb.parse(query)::text::jsonb
                 )
             );
@@ -176,7 +225,7 @@ pub fn format_aggregate_function(function_name: &str, index_json: &Value) -> Str
         BEGIN
             RETURN paradedb.aggregate_internal(
                 aggs,
-                '{index_json_str}'::jsonb || jsonb_build_object(
+                {index_config} || jsonb_build_object(
                     'query', query::text::jsonb
                 )
             );
@@ -17,14 +17,26 @@
 
 use anyhow::{anyhow, Result};
 use once_cell::sync::Lazy;
+use pgrx::{pg_sys, IntoDatum, PgBuiltInOids, Spi};
 use shared::postgres::transaction::{Transaction, TransactionError};
 use std::{
     ffi::CStr,
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
-use crate::writer::{WriterClient, WriterDirectory, WriterRequest};
+use crate::index::fault::FaultPoint;
+use crate::index::pending::{PendingDocument, PendingInserts, TakenInserts};
+use crate::index::prepared::PreparedInserts;
+use crate::index::SearchIndex;
+use crate::postgres::wait::SearchWaitEvent;
+use crate::writer::{
+    ClientError, SearchFs, WriterClient, WriterDirectory, WriterRequest, WriterTransferPipeFilePath,
+};
+
+/// How many times a transaction sends its documents again if the writer restarts while
+/// it's committing.
+const MAX_WRITER_REPLAYS: usize = 3;
 
 /// We use this global variable to cache any values that can be re-used
 /// after initialization.
@@ -60,13 +72,53 @@ pub fn postgres_database_oid() -> u32 {
         .get_or_insert_with(|| unsafe { pgrx::pg_sys::MyDatabaseId.as_u32() })
 }
 
+/// The file node of a relation, which Postgres replaces whenever it rewrites the relation,
+/// like on TRUNCATE, REINDEX or VACUUM FULL.
+#[cfg(feature = "pg16")]
+pub fn relation_filenode(relation: &pgrx::PgRelation) -> u32 {
+    relation.rd_locator.relNumber.as_u32()
+}
+
+#[cfg(any(feature = "pg12", feature = "pg13", feature = "pg14", feature = "pg15"))]
+pub fn relation_filenode(relation: &pgrx::PgRelation) -> u32 {
+    relation.rd_node.relNode.as_u32()
+}
+
+/// The file node of the BM25 index named `index_name` in the current database, as seen by
+/// the current transaction, or None if there is no such index.
+pub fn index_filenode(index_name: &str) -> Option<u32> {
+    Spi::get_one_with_args::<pg_sys::Oid>(
+        "SELECT pg_relation_filenode(c.oid) FROM pg_class c JOIN pg_am a ON c.relam = a.oid \
+         WHERE a.amname = 'bm25' AND c.relname = $1 LIMIT 1",
+        vec![(PgBuiltInOids::TEXTOID.oid(), index_name.into_datum())],
+    )
+    .unwrap_or_else(|err| panic!("error looking up the file node of '{index_name}': {err}"))
+    .map(|filenode| filenode.as_u32())
+}
+
+/// The WAL timeline the cluster writes to. It changes when a standby is promoted or a
+/// backup is restored to a point in time, which is how stale indexes are detected.
+#[cfg(any(feature = "pg15", feature = "pg16"))]
+pub fn wal_timeline() -> u32 {
+    unsafe { pgrx::pg_sys::GetWALInsertionTimeLine() }
+}
+
+#[cfg(any(feature = "pg12", feature = "pg13", feature = "pg14"))]
+pub fn wal_timeline() -> u32 {
+    unsafe {
+        // Initializes `ThisTimeLineID` in processes that haven't written WAL yet.
+        pgrx::pg_sys::RecoveryInProgress();
+        pgrx::pg_sys::ThisTimeLineID
+    }
+}
+
 pub fn register_commit_callback<W: WriterClient<WriterRequest> + Send + Sync + 'static>(
     writer: &Arc<Mutex<W>>,
     directory: WriterDirectory,
 ) -> Result<(), TransactionError> {
     let writer_client = writer.clone();
     let commit_directory = directory.clone();
-    Transaction::call_once_on_precommit(directory.clone().index_name, move || {
+    Transaction::call_once_on_precommit(directory.dir_name(), move || {
         let mut error: Option<anyhow::Error> = None;
         {
             // This lock must happen in an enclosing block so it is dropped and
@@ -77,9 +129,9 @@ pub fn register_commit_callback<W: WriterClient<WriterRequest> + Send + Sync + '
                     panic!("could not lock client in commit callback: {err}");
                 }
                 Ok(mut client) => {
-                    if let Err(err) = client.request(WriterRequest::Commit {
-                        directory: commit_directory.clone(),
-                    }) {
+                    // Documents inserted during this transaction have been buffered in this
+                    // process, so they must be sent to the writer before committing.
+                    if let Err(err) = flush_and_commit(&mut *client, &commit_directory) {
                         error = Some(anyhow!(
                             "error with request to writer in commit callback: {err}"
                         ));
@@ -93,9 +145,27 @@ pub fn register_commit_callback<W: WriterClient<WriterRequest> + Send + Sync + '
         }
     })?;
 
+    let prepare_directory = directory.clone();
+    Transaction::call_once_on_pre_prepare(directory.dir_name(), move || {
+        // A prepared transaction can be committed from another connection, so the buffered
+        // documents are persisted to disk until it resolves. See `PreparedInserts::resolve`.
+        let documents = PendingInserts::take(&prepare_directory)
+            .expect("could not take pending inserts in prepare callback")
+            .into_vec()
+            .expect("could not read spilled pending inserts in prepare callback");
+        let xid = unsafe { pgrx::pg_sys::GetTopTransactionId() }.into_inner();
+        let encrypted = !documents.is_empty()
+            && prepare_directory
+                .load_index::<SearchIndex>()
+                .expect("could not load index in prepare callback")
+                .encrypted;
+        PreparedInserts::persist(&prepare_directory, xid, documents, encrypted)
+            .unwrap_or_else(|err| panic!("error persisting inserts in prepare callback: {err}"));
+    })?;
+
     let writer_client = writer.clone();
     let abort_directory = directory.clone();
-    Transaction::call_once_on_abort(directory.clone().index_name, move || {
+    Transaction::call_once_on_abort(directory.dir_name(), move || {
         let mut error: Option<anyhow::Error> = None;
         {
             // This lock must happen in an enclosing block so it is dropped and
@@ -106,12 +176,29 @@ pub fn register_commit_callback<W: WriterClient<WriterRequest> + Send + Sync + '
                     panic!("could not lock client in abort callback: {err}");
                 }
                 Ok(mut client) => {
-                    if let Err(err) = client.request(WriterRequest::Abort {
+                    // If the transaction only buffered inserts, nothing was sent to the writer
+                    // and discarding the buffer is enough. Rolling back the writer in that case
+                    // could throw away documents that other connections are committing.
+                    let buffered_only = !PendingInserts::is_empty(&abort_directory)
+                        .expect("could not check pending inserts in abort callback");
+                    PendingInserts::discard(&abort_directory)
+                        .expect("could not discard pending inserts in abort callback");
+
+                    if buffered_only {
+                        return;
+                    }
+
+                    match client.request(WriterRequest::Abort {
                         directory: abort_directory,
+                        pid: std::process::id(),
                     }) {
-                        error = Some(anyhow!(
-                            "error with request to writer in abort callback: {err}"
-                        ));
+                        // A restarted writer has nothing left to roll back.
+                        Ok(()) | Err(ClientError::WriterRestarted(_)) => {}
+                        Err(err) => {
+                            error = Some(anyhow!(
+                                "error with request to writer in abort callback: {err}"
+                            ));
+                        }
                     }
                 }
             }
@@ -125,6 +212,60 @@ pub fn register_commit_callback<W: WriterClient<WriterRequest> + Send + Sync + '
     Ok(())
 }
 
+/// Send the documents buffered by this transaction to the writer, and commit them.
+///
+/// If the writer restarts along the way, it loses every uncommitted document it received,
+/// so the documents are sent again. They're taken out of the buffer once, and read again by
+/// each attempt, from disk for the ones that were spilled.
+fn flush_and_commit<W: WriterClient<WriterRequest>>(
+    client: &mut W,
+    directory: &WriterDirectory,
+) -> Result<()> {
+    // Prepared transactions that committed before this one are applied first, so that the
+    // writer receives the documents in commit order.
+    PreparedInserts::resolve(directory, client)?;
+
+    // On failure the transaction aborts, and the writer must be asked to roll back whatever
+    // it received, which the abort callback does once the buffer is empty.
+    let mut inserts = PendingInserts::take(directory)?;
+    let WriterTransferPipeFilePath(pipe_path) = directory.writer_transfer_pipe_path(true)?;
+    let mut replays = 0;
+    let result = loop {
+        match send_and_commit(client, directory, &pipe_path, &mut inserts) {
+            Err(ClientError::WriterRestarted(addr)) if replays < MAX_WRITER_REPLAYS => {
+                replays += 1;
+                pgrx::warning!("pg_search writer restarted at {addr}, replaying transaction");
+            }
+            result => break result,
+        }
+    };
+    Ok(result?)
+}
+
+fn send_and_commit<W: WriterClient<WriterRequest>>(
+    client: &mut W,
+    directory: &WriterDirectory,
+    pipe_path: &Path,
+    inserts: &mut TakenInserts,
+) -> Result<(), ClientError> {
+    FaultPoint::BackendBeforeSend.hit(directory);
+    let send_wait = SearchWaitEvent::WriterSend.start();
+    inserts.for_each_batch(|documents| {
+        for request in PendingDocument::into_requests(directory, documents) {
+            client.transfer(pipe_path, &request)?;
+        }
+        Ok::<_, ClientError>(())
+    })?;
+    std::mem::drop(send_wait);
+
+    FaultPoint::BackendBeforeCommit.hit(directory);
+    let _commit_wait = SearchWaitEvent::WriterCommit.start();
+    client.request(WriterRequest::Commit {
+        directory: directory.clone(),
+        pid: std::process::id(),
+    })
+}
+
 pub fn needs_commit(index_name: &str) -> bool {
     Transaction::needs_commit(index_name)
         .expect("error performing commit check in transaction cache")
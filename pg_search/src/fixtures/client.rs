@@ -53,13 +53,15 @@ impl WriterClient<WriterRequest> for TestClient {
 
     fn transfer<P: AsRef<std::path::Path>>(
         &mut self,
-        _pipe_path: P,
-        request: WriterRequest,
+        pipe_path: P,
+        request: &WriterRequest,
     ) -> Result<(), ClientError> {
         // Serialize the data to emulate the real transfer process.
-        let serialized_request = bincode::serialize(&request).unwrap();
+        let serialized_request = bincode::serialize(request).unwrap();
         let deserialized_request: WriterRequest =
             bincode::deserialize(&serialized_request).unwrap();
-        self.request(deserialized_request)
+        self.writer
+            .handle_transfer(pipe_path.as_ref(), deserialized_request)
+            .map_err(|err| ClientError::ServerError(err.to_string()))
     }
 }
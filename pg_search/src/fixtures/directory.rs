@@ -17,7 +17,8 @@
 
 pub use crate::writer::SearchFs;
 use crate::writer::{
-    SearchDirectoryError, TantivyDirPath, WriterDirectory, WriterTransferPipeFilePath,
+    PreparedInsertsDirPath, SearchDirectoryError, TantivyDirPath, WriterDirectory,
+    WriterTransferPipeFilePath,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
@@ -40,6 +41,7 @@ impl MockWriterDirectory {
             writer_dir: WriterDirectory {
                 index_name: index_name.to_string(),
                 database_oid: 0,
+                relfilenode: 16384,
                 postgres_data_dir_path: temp_path,
             },
         }
@@ -68,4 +70,10 @@ impl SearchFs for MockWriterDirectory {
     ) -> Result<WriterTransferPipeFilePath, SearchDirectoryError> {
         self.writer_dir.writer_transfer_pipe_path(ensure_exists)
     }
+    fn prepared_inserts_dir_path(
+        &self,
+        ensure_exists: bool,
+    ) -> Result<PreparedInsertsDirPath, SearchDirectoryError> {
+        self.writer_dir.prepared_inserts_dir_path(ensure_exists)
+    }
 }
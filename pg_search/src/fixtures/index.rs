@@ -18,7 +18,7 @@
 use uuid::Uuid;
 
 use crate::{
-    index::SearchIndex,
+    index::{merge::SearchMergePolicy, storage::SearchDirectoryMode, SearchIndex},
     schema::{SearchFieldConfig, SearchFieldName, SearchFieldType},
     writer::Writer,
 };
@@ -41,7 +41,18 @@ impl MockSearchIndex {
         let mut writer = Writer::new();
         let uuid = Uuid::new_v4().to_string();
         writer
-            .create_index(directory.writer_dir.clone(), fields, uuid, key_field_index)
+            .create_index(
+                directory.writer_dir.clone(),
+                fields,
+                uuid,
+                key_field_index,
+                SearchMergePolicy::default(),
+                0,
+                SearchDirectoryMode::default(),
+                0,
+                0,
+                None,
+            )
             .expect("error creating index instance");
 
         let index = SearchIndex::from_disk(&directory.writer_dir)
@@ -16,17 +16,47 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use once_cell::sync::Lazy;
-use pgrx::{PGRXSharedMemory, PgLwLock};
+use pgrx::{PGRXSharedMemory, PgAtomic, PgLwLock};
 use std::{
     net::SocketAddr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
+use crate::index::remote::StorageTier;
+use crate::query::stats::QueryStatsTable;
 use crate::writer::{self, WriterRequest};
 
 // This is global shared state for the writer background worker.
 pub static WRITER_GLOBAL: PgLwLock<WriterGlobal> = PgLwLock::new();
 
+// Statistics of the searches run by every connection, see `paradedb.stat_queries`.
+pub static QUERY_STATS: PgLwLock<QueryStatsTable> = PgLwLock::new();
+
+// Counters of every connection, reported by `paradedb.metrics`.
+pub static SEARCHES: PgAtomic<AtomicU64> = PgAtomic::new();
+pub static QUERY_CACHE_HITS: PgAtomic<AtomicU64> = PgAtomic::new();
+pub static QUERY_CACHE_MISSES: PgAtomic<AtomicU64> = PgAtomic::new();
+pub static HOT_TIER_READS: PgAtomic<AtomicU64> = PgAtomic::new();
+pub static HOT_TIER_READ_NANOS: PgAtomic<AtomicU64> = PgAtomic::new();
+pub static COLD_TIER_READS: PgAtomic<AtomicU64> = PgAtomic::new();
+pub static COLD_TIER_READ_NANOS: PgAtomic<AtomicU64> = PgAtomic::new();
+
+/// Count a read of an index file in object storage or local disk, see `set_read_observer`.
+pub fn record_tier_read(tier: StorageTier, duration: Duration) {
+    let (reads, nanos) = match tier {
+        StorageTier::Hot => (&HOT_TIER_READS, &HOT_TIER_READ_NANOS),
+        StorageTier::Cold => (&COLD_TIER_READS, &COLD_TIER_READ_NANOS),
+    };
+    reads.get().fetch_add(1, Ordering::Relaxed);
+    nanos
+        .get()
+        .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+}
+
 /// A global singleton for the instance of the client to the background writer process.
 /// The client is agnostic to which index we're writing to, so keeping a global one
 /// ensures that the instance can be re-used if a single transaction needs to write to
@@ -0,0 +1,459 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting};
+
+/// Settings specific to pg_search. Settings shared by all ParadeDB extensions, like
+/// telemetry, live in `shared::gucs`.
+pub struct PgSearchGucSettings {
+    /// How often the merge background worker checks indexes for segments to merge.
+    pub merge_interval: GucSetting<i32>,
+    /// How many merges the merge background worker may start per index at each check.
+    pub max_merges_per_interval: GucSetting<i32>,
+    /// How many rows a multi-row insert or COPY accumulates before buffering them.
+    pub insert_batch_size: GucSetting<i32>,
+    /// The memory, in MB, that a transaction may buffer inserts in, or 0 for no limit.
+    pub pending_inserts_memory_limit: GucSetting<i32>,
+    /// The I/O rate, in MB/s, at which the writer indexes documents.
+    pub index_io_limit: GucSetting<i32>,
+    /// The I/O rate, in MB/s, at which the writer starts background merges.
+    pub merge_io_limit: GucSetting<i32>,
+    /// How many threads a query uses to search index segments in parallel.
+    pub search_threads: GucSetting<i32>,
+    /// How many queries a connection keeps the results of, to answer identical queries.
+    pub query_cache_size: GucSetting<i32>,
+    /// How many compiled queries a connection keeps, to skip compiling identical queries.
+    pub query_plan_cache_size: GucSetting<i32>,
+    /// How many terms a regex or fuzzy query may expand to, or 0 for no limit.
+    pub max_term_expansions: GucSetting<i32>,
+    /// Whether queries expanding to too many terms search their most frequent ones instead
+    /// of failing.
+    pub rewrite_term_expansions: GucSetting<bool>,
+    /// How many heap pages an index scan prefetches ahead of the results it returns.
+    pub heap_prefetch_distance: GucSetting<i32>,
+    /// How much memory, in MB, an aggregation may use across all of its threads.
+    pub aggregate_memory_limit: GucSetting<i32>,
+    /// How many buckets an aggregation may create.
+    pub aggregate_bucket_limit: GucSetting<i32>,
+    /// The duration, in milliseconds, after which a search is logged with its profile.
+    pub log_min_search_duration: GucSetting<i32>,
+    /// The fraction of searches recorded in the audit log.
+    pub audit_sample_rate: GucSetting<f64>,
+    /// The table searches are recorded in, or None to write them to the Postgres log.
+    pub audit_table: GucSetting<Option<&'static str>>,
+    /// The comma-separated names of the indexes whose searches are audited, or `*` for all.
+    pub audit_indexes: GucSetting<Option<&'static str>>,
+    /// The duration, in milliseconds, after which a search is canceled.
+    pub search_timeout: GucSetting<i32>,
+    /// The shell command printing the key that encrypts new indexes, if any.
+    pub encryption_key_command: GucSetting<Option<&'static str>>,
+    /// The shell command printing the text of the binary documents of document fields.
+    pub extract_command: GucSetting<Option<&'static str>>,
+    /// The free disk space, in MB, below which inserts into indexes fail.
+    pub min_free_disk_space: GucSetting<i32>,
+    /// How many rows per second the heal workers compare with their indexes.
+    pub heal_rows_per_second: GucSetting<i32>,
+    /// How often follower indexes apply the changes of their replication slot.
+    pub follower_interval: GucSetting<i32>,
+    /// The directory the primary ships the segments of indexes to, for its standbys.
+    pub segment_shipping_path: GucSetting<Option<&'static str>>,
+    /// How often segments are shipped by the primary and installed by standbys.
+    pub segment_shipping_interval: GucSetting<i32>,
+    /// How stale, in seconds, an index shipped to a standby may be for searches to use it.
+    pub replica_max_staleness: GucSetting<i32>,
+    /// The port of the HTTP search endpoint, which is disabled if 0.
+    pub http_port: GucSetting<i32>,
+    /// The address the HTTP search endpoint listens at.
+    pub http_listen_address: GucSetting<Option<&'static str>>,
+    /// The database the HTTP search endpoint searches the indexes of.
+    pub http_database: GucSetting<Option<&'static str>>,
+}
+
+impl PgSearchGucSettings {
+    pub const fn new() -> Self {
+        Self {
+            merge_interval: GucSetting::<i32>::new(10),
+            max_merges_per_interval: GucSetting::<i32>::new(1),
+            insert_batch_size: GucSetting::<i32>::new(1000),
+            pending_inserts_memory_limit: GucSetting::<i32>::new(1024),
+            index_io_limit: GucSetting::<i32>::new(0),
+            merge_io_limit: GucSetting::<i32>::new(0),
+            search_threads: GucSetting::<i32>::new(0),
+            query_cache_size: GucSetting::<i32>::new(0),
+            query_plan_cache_size: GucSetting::<i32>::new(0),
+            max_term_expansions: GucSetting::<i32>::new(0),
+            rewrite_term_expansions: GucSetting::<bool>::new(false),
+            heap_prefetch_distance: GucSetting::<i32>::new(64),
+            aggregate_memory_limit: GucSetting::<i32>::new(500),
+            aggregate_bucket_limit: GucSetting::<i32>::new(65000),
+            log_min_search_duration: GucSetting::<i32>::new(-1),
+            audit_sample_rate: GucSetting::<f64>::new(0.0),
+            audit_table: GucSetting::<Option<&'static str>>::new(None),
+            audit_indexes: GucSetting::<Option<&'static str>>::new(None),
+            search_timeout: GucSetting::<i32>::new(0),
+            encryption_key_command: GucSetting::<Option<&'static str>>::new(None),
+            extract_command: GucSetting::<Option<&'static str>>::new(None),
+            min_free_disk_space: GucSetting::<i32>::new(0),
+            heal_rows_per_second: GucSetting::<i32>::new(0),
+            follower_interval: GucSetting::<i32>::new(0),
+            segment_shipping_path: GucSetting::<Option<&'static str>>::new(None),
+            segment_shipping_interval: GucSetting::<i32>::new(10),
+            replica_max_staleness: GucSetting::<i32>::new(0),
+            http_port: GucSetting::<i32>::new(0),
+            http_listen_address: GucSetting::<Option<&'static str>>::new(Some("localhost")),
+            http_database: GucSetting::<Option<&'static str>>::new(Some("postgres")),
+        }
+    }
+
+    pub fn init(&self) {
+        // The merge settings are read by the merge background worker, so they can only be
+        // changed in postgresql.conf and are picked up on reload.
+        GucRegistry::define_int_guc(
+            "paradedb.merge_interval",
+            "Seconds between background merges of pg_search index segments.",
+            "Seconds between background merges of pg_search index segments.",
+            &self.merge_interval,
+            1,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::UNIT_S,
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.max_merges_per_interval",
+            "Maximum number of segment merges started per index at each merge interval.",
+            "Maximum number of segment merges started per index at each merge interval. Set to 0 to disable background merges.",
+            &self.max_merges_per_interval,
+            0,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.insert_batch_size",
+            "Number of rows accumulated by a bulk insert into a bm25 index before they are buffered.",
+            "Number of rows accumulated by a multi-row INSERT or COPY into a bm25 index before they are buffered for the end of the transaction.",
+            &self.insert_batch_size,
+            1,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.pending_inserts_memory_limit",
+            "Maximum memory, in MB, of the rows a transaction buffers for bm25 indexes.",
+            "Maximum memory, in MB, of the rows a transaction buffers for bm25 indexes until it commits. Past the limit, the rows are moved to temporary files on disk. Rows are buffered in batches of paradedb.insert_batch_size, so the limit can be exceeded by one batch. Set to 0 for no limit.",
+            &self.pending_inserts_memory_limit,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        // The writer process can't reload its configuration, so the I/O limits are
+        // forwarded to it by the merge background worker.
+        GucRegistry::define_int_guc(
+            "paradedb.index_io_limit",
+            "Maximum rate, in MB/s, at which pg_search indexes documents.",
+            "Maximum rate, in MB/s, at which the pg_search writer indexes documents. Set to 0 for no limit.",
+            &self.index_io_limit,
+            0,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.merge_io_limit",
+            "Maximum rate, in MB/s, at which pg_search merges index segments in the background.",
+            "Maximum rate, in MB/s, at which pg_search merges index segments in the background. Set to 0 for no limit.",
+            &self.merge_io_limit,
+            0,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.search_threads",
+            "Number of threads used to search the segments of a bm25 index in parallel.",
+            "Number of threads used by a query to search the segments of a bm25 index in parallel. Set to 0 to use one thread per CPU, or to 1 to search segments serially.",
+            &self.search_threads,
+            0,
+            1024,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.query_cache_size",
+            "Number of bm25 search queries whose results are cached by each connection.",
+            "Number of bm25 search queries whose results are cached by each connection. They are reused by identical queries until the index changes. Set to 0 to disable the cache.",
+            &self.query_cache_size,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.query_plan_cache_size",
+            "Number of compiled bm25 search queries cached by each connection.",
+            "Number of compiled bm25 search queries cached by each connection. Identical queries reuse them instead of parsing and building their query tree again, until the index is rebuilt. Set to 0 to disable the cache.",
+            &self.query_plan_cache_size,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.max_term_expansions",
+            "Maximum number of terms a regex or fuzzy query may expand to.",
+            "Maximum number of terms a regex or fuzzy query may expand to, unless the query sets its own max_expansions. A query expanding to more terms fails, see paradedb.rewrite_term_expansions. Set to 0 for no limit.",
+            &self.max_term_expansions,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_bool_guc(
+            "paradedb.rewrite_term_expansions",
+            "Search the most frequent terms of regex or fuzzy queries that expand to too many terms.",
+            "Instead of failing, a regex or fuzzy query that expands to more terms than its limit searches the terms that are found in the most documents, up to the limit.",
+            &self.rewrite_term_expansions,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.heap_prefetch_distance",
+            "Maximum number of bm25 index scan results whose heap pages are prefetched.",
+            "Maximum number of bm25 index scan results whose heap pages are prefetched, in block order, before they are fetched. Set to 0 to disable prefetching.",
+            &self.heap_prefetch_distance,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.aggregate_memory_limit",
+            "Maximum memory, in MB, used by a bm25 aggregation.",
+            "Maximum memory, in MB, used by a bm25 aggregation, summed over the threads aggregating the segments of the index. Aggregations over the limit fail.",
+            &self.aggregate_memory_limit,
+            1,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.aggregate_bucket_limit",
+            "Maximum number of buckets created by a bm25 aggregation.",
+            "Maximum number of buckets created by a bm25 aggregation, including the buckets of nested aggregations. Aggregations over the limit fail.",
+            &self.aggregate_bucket_limit,
+            1,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::default(),
+        );
+
+        // Like log_min_duration_statement, only superusers may change what gets logged.
+        GucRegistry::define_int_guc(
+            "paradedb.log_min_search_duration",
+            "Logs bm25 searches that take at least this many milliseconds.",
+            "Logs bm25 searches that take at least this many milliseconds, with the time spent parsing the query, creating its weights and collecting each segment. Set to 0 to log every search, or to -1 to disable logging.",
+            &self.log_min_search_duration,
+            -1,
+            i32::MAX,
+            GucContext::Suset,
+            GucFlags::UNIT_MS,
+        );
+
+        // Audit logging must not be turned off by the users being audited.
+        GucRegistry::define_float_guc(
+            "paradedb.audit_sample_rate",
+            "Fraction of bm25 searches recorded in the audit log.",
+            "Fraction of bm25 searches recorded with the user running them, their query and the number of rows they returned, between 0 and 1. Set to 0 to disable audit logging, or to 1 to record every search.",
+            &self.audit_sample_rate,
+            0.0,
+            1.0,
+            GucContext::Suset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_string_guc(
+            "paradedb.audit_table",
+            "Table that audited bm25 searches are inserted into.",
+            "Table that audited bm25 searches are inserted into, with searched_at, user_name, index_name, query and rows_returned columns. If not set, audited searches are written to the server log.",
+            &self.audit_table,
+            GucContext::Suset,
+            GucFlags::SUPERUSER_ONLY,
+        );
+
+        GucRegistry::define_string_guc(
+            "paradedb.audit_indexes",
+            "Indexes whose bm25 searches are audited.",
+            "Comma-separated names of the indexes whose bm25 searches are recorded in the audit log, as sampled by paradedb.audit_sample_rate, or * for every index. If not set, no search is audited.",
+            &self.audit_indexes,
+            GucContext::Suset,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.search_timeout",
+            "Cancels bm25 searches that take longer than this many milliseconds.",
+            "Cancels bm25 searches that take longer than this many milliseconds, checked as the segments of the index are searched, so that queries matching huge numbers of documents don't hold the connection. Searches can override it with timeout_ms. Set to 0 to disable the timeout.",
+            &self.search_timeout,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::UNIT_MS,
+        );
+
+        // The writer process and every connection must agree on the key, so it can only be
+        // changed with a restart.
+        GucRegistry::define_string_guc(
+            "paradedb.encryption_key_command",
+            "Shell command printing the key that encrypts the files of new bm25 indexes.",
+            "Shell command printing the 256-bit key that encrypts the files of new bm25 indexes, as 64 hex digits. Indexes created while it is not set are not encrypted.",
+            &self.encryption_key_command,
+            GucContext::Postmaster,
+            GucFlags::SUPERUSER_ONLY,
+        );
+
+        // Documents are extracted by the connections that index them, so the command only
+        // has to be set where the rows of document fields are written.
+        GucRegistry::define_string_guc(
+            "paradedb.extract_command",
+            "Shell command printing the text of the binary documents of bm25 document fields.",
+            "Shell command given the content of a bytea column indexed as a document field on its standard input, which prints the text to index on its standard output.",
+            &self.extract_command,
+            GucContext::Suset,
+            GucFlags::SUPERUSER_ONLY,
+        );
+
+        // Like the I/O limits, this is forwarded to the writer process by the merge
+        // background worker.
+        GucRegistry::define_int_guc(
+            "paradedb.min_free_disk_space",
+            "Minimum free disk space, in MB, below which inserts into bm25 indexes fail.",
+            "Minimum free disk space, in MB, below which inserts into bm25 indexes fail and background merges are skipped. Set to 0 to only skip merges that don't fit on the disk.",
+            &self.min_free_disk_space,
+            0,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::UNIT_MB,
+        );
+
+        // Read by the heal workers, which are started by the merge background worker.
+        GucRegistry::define_int_guc(
+            "paradedb.heal_rows_per_second",
+            "Rate at which table rows are compared with their bm25 index in the background.",
+            "Rate, in rows per second, at which the heal background workers compare table rows with their bm25 index and reindex the rows it is missing. Set to 0 to disable healing.",
+            &self.heal_rows_per_second,
+            0,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::default(),
+        );
+
+        // Read by the follower workers, which are started by the merge background worker.
+        GucRegistry::define_int_guc(
+            "paradedb.follower_interval",
+            "Seconds between two reads of the replication slots of follower bm25 indexes.",
+            "Seconds between two reads of the changes of the replication slots of follower bm25 indexes, which are applied to their tables. Set to 0 to disable followers.",
+            &self.follower_interval,
+            0,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::UNIT_S,
+        );
+
+        // Read by the shipping background worker, on the primary and on standbys.
+        GucRegistry::define_string_guc(
+            "paradedb.segment_shipping_path",
+            "Directory shared with standbys, where bm25 index segments are shipped.",
+            "Directory shared between a primary and its physical replicas. The primary copies the segments of its bm25 indexes to it, and standbys install them so that their indexes can be searched. Shipping is disabled while it is not set.",
+            &self.segment_shipping_path,
+            GucContext::Sighup,
+            GucFlags::SUPERUSER_ONLY,
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.segment_shipping_interval",
+            "Seconds between two shipments of bm25 index segments to standbys.",
+            "Seconds between two shipments of bm25 index segments by the primary, and between two installs by standbys.",
+            &self.segment_shipping_interval,
+            1,
+            i32::MAX,
+            GucContext::Sighup,
+            GucFlags::UNIT_S,
+        );
+
+        GucRegistry::define_int_guc(
+            "paradedb.replica_max_staleness",
+            "Maximum age of the bm25 index segments a standby searches.",
+            "Maximum age, in seconds, of the bm25 index segments shipped to a standby. Searches of an index shipped longer ago fail. Set to 0 for no limit.",
+            &self.replica_max_staleness,
+            0,
+            i32::MAX,
+            GucContext::Userset,
+            GucFlags::UNIT_S,
+        );
+
+        // The HTTP background worker is only registered when the server starts with a port.
+        GucRegistry::define_int_guc(
+            "paradedb.http_port",
+            "Port of the HTTP search endpoint.",
+            "Port of the read-only HTTP search endpoint, which clients authenticate to with the name and password of a Postgres role. Set to 0 to disable the endpoint.",
+            &self.http_port,
+            0,
+            65535,
+            GucContext::Postmaster,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_string_guc(
+            "paradedb.http_listen_address",
+            "Address the HTTP search endpoint listens at.",
+            "Host name or IP address the HTTP search endpoint listens at.",
+            &self.http_listen_address,
+            GucContext::Postmaster,
+            GucFlags::default(),
+        );
+
+        GucRegistry::define_string_guc(
+            "paradedb.http_database",
+            "Database searched by the HTTP search endpoint.",
+            "Database whose bm25 indexes are searched by the HTTP search endpoint.",
+            &self.http_database,
+            GucContext::Postmaster,
+            GucFlags::default(),
+        );
+    }
+}
+
+impl Default for PgSearchGucSettings {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,538 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::query::SearchQueryInput;
+use base64::Engine;
+use pgrx::pg_sys::panic::CaughtError;
+use pgrx::*;
+use serde::{Deserialize, Serialize};
+use std::ffi::{c_char, c_int, CString};
+use std::io::{self, Cursor, Read};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
+use tiny_http::{Header, Method, Response};
+use utoipa::openapi::security::{Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+/// The largest request body the endpoint reads, in bytes.
+const MAX_BODY_BYTES: u64 = 1024 * 1024;
+
+/// The number of threads that read the bodies of requests and send the responses. A client
+/// that stalls while sending its body only holds up one of them.
+const REQUEST_THREADS: usize = 4;
+
+/// How long the response to invalid credentials is held back. As it holds up a request
+/// thread, it also caps how many passwords can be tried per second across all clients.
+const UNAUTHORIZED_DELAY: Duration = Duration::from_secs(1);
+
+#[pg_guard]
+extern "C" {
+    fn get_role_password(role: *const c_char, logdetail: *mut *const c_char) -> *mut c_char;
+    fn plain_crypt_verify(
+        role: *const c_char,
+        shadow_pass: *const c_char,
+        client_pass: *const c_char,
+        logdetail: *mut *const c_char,
+    ) -> c_int;
+}
+
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "pg_search", description = "Read-only search of bm25 indexes."),
+    paths(search_as),
+    components(schemas(SearchRequest, SearchRequestQuery, SearchResponse, ErrorResponse, SearchQueryInput)),
+    modifiers(&BasicAuth)
+)]
+pub struct SearchApi;
+
+struct BasicAuth;
+
+impl Modify for BasicAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        if let Some(components) = openapi.components.as_mut() {
+            components.add_security_scheme(
+                "basic",
+                SecurityScheme::Http(Http::new(HttpAuthScheme::Basic)),
+            );
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SearchRequest {
+    /// The name of the bm25 index to search.
+    pub index: String,
+    pub query: SearchRequestQuery,
+    /// The number of results to skip.
+    #[serde(default)]
+    pub offset: Option<i32>,
+    /// The maximum number of results to return.
+    #[serde(default)]
+    pub limit: Option<i32>,
+}
+
+/// A query string, or a query object like those returned by the query functions of the
+/// `paradedb` schema.
+#[derive(Debug, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum SearchRequestQuery {
+    QueryString(String),
+    Query(SearchQueryInput),
+}
+
+impl From<SearchRequestQuery> for SearchQueryInput {
+    fn from(query: SearchRequestQuery) -> Self {
+        match query {
+            SearchRequestQuery::QueryString(query_string) => SearchQueryInput::Parse {
+                query_string,
+                conjunction: None,
+            },
+            SearchRequestQuery::Query(query) => query,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SearchResponse {
+    /// The rows of the indexed table that match the query, as objects of their columns.
+    #[schema(value_type = Vec<Object>)]
+    pub rows: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+/// The name and password of the Postgres role a request is made as, sent with HTTP basic
+/// authentication.
+#[derive(Debug, PartialEq)]
+pub struct Credentials {
+    pub role: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn from_header(value: &str) -> Result<Self, HttpError> {
+        let encoded = value
+            .strip_prefix("Basic ")
+            .ok_or(HttpError::Unauthorized)?;
+        let decoded = base64::engine::general_purpose::STANDARD
+            .decode(encoded.trim())
+            .map_err(|_| HttpError::Unauthorized)?;
+        let decoded = String::from_utf8(decoded).map_err(|_| HttpError::Unauthorized)?;
+        let (role, password) = decoded.split_once(':').ok_or(HttpError::Unauthorized)?;
+        Ok(Self {
+            role: role.to_string(),
+            password: password.to_string(),
+        })
+    }
+
+    /// Check the password against the one of the role, and return the role if it may log in.
+    fn authenticate(&self) -> Result<pg_sys::Oid, HttpError> {
+        let role_oid = Spi::get_one_with_args::<pg_sys::Oid>(
+            "SELECT oid FROM pg_roles WHERE rolname = $1 AND rolcanlogin",
+            vec![(PgBuiltInOids::TEXTOID.oid(), self.role.clone().into_datum())],
+        )
+        .map_err(|err| HttpError::Search(err.to_string()))?
+        .ok_or(HttpError::Unauthorized)?;
+
+        let role = CString::new(self.role.as_str()).map_err(|_| HttpError::Unauthorized)?;
+        let password = CString::new(self.password.as_str()).map_err(|_| HttpError::Unauthorized)?;
+        let mut logdetail = std::ptr::null();
+        let verified = unsafe {
+            // Null for roles without a password, or whose password expired.
+            let shadow_pass = get_role_password(role.as_ptr(), &mut logdetail);
+            !shadow_pass.is_null()
+                && plain_crypt_verify(
+                    role.as_ptr(),
+                    shadow_pass,
+                    password.as_ptr(),
+                    &mut logdetail,
+                ) == pg_sys::STATUS_OK as c_int
+        };
+        if !verified {
+            return Err(HttpError::Unauthorized);
+        }
+        Ok(role_oid)
+    }
+}
+
+/// A read-only search endpoint, so that clients without a Postgres driver can search the
+/// bm25 indexes of a database. Requests are read by a few threads, but answered one at a time
+/// by a background worker connected to the database, in read-only transactions with the
+/// privileges of the role they authenticate as. The endpoint checks passwords itself, over
+/// plain HTTP: `pg_hba.conf` doesn't apply to it, so it should only listen at addresses
+/// trusted clients can reach.
+pub struct HttpServer {
+    http: Arc<tiny_http::Server>,
+    requests: mpsc::Receiver<ReadRequest>,
+    errors: mpsc::Receiver<io::Error>,
+}
+
+/// A request whose body was read, waiting to be answered by the background worker, as
+/// searches can only run in the thread connected to the database.
+struct ReadRequest {
+    method: Method,
+    url: String,
+    authorization: Option<String>,
+    body: io::Result<String>,
+    response: mpsc::SyncSender<Result<String, HttpError>>,
+}
+
+impl HttpServer {
+    pub fn new(addr: &str) -> Result<Self, HttpError> {
+        let http = tiny_http::Server::http(addr)
+            .map_err(|err| HttpError::AddressBindFailed(addr.to_string(), err.to_string()))?;
+        let http = Arc::new(http);
+        let (request_sender, requests) = mpsc::channel();
+        let (error_sender, errors) = mpsc::channel();
+        for _ in 0..REQUEST_THREADS {
+            let http = http.clone();
+            let request_sender = request_sender.clone();
+            let error_sender = error_sender.clone();
+            thread::spawn(move || Self::serve(&http, request_sender, error_sender));
+        }
+        Ok(Self {
+            http,
+            requests,
+            errors,
+        })
+    }
+
+    /// Answer the next request, if one is read within `timeout`. Returns the first error a
+    /// request thread ran into while responding since the last call.
+    pub fn handle_next(&self, timeout: Duration) -> io::Result<()> {
+        if let Ok(request) = self.requests.recv_timeout(timeout) {
+            let result = Self::route(&request);
+            // The thread is gone only if the server is being dropped.
+            let _ = request.response.send(result);
+        }
+        match self.errors.try_recv() {
+            Ok(err) => Err(err),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Read requests and send their responses, until the server is dropped. Runs in a
+    /// request thread, which must not call into Postgres.
+    fn serve(
+        http: &tiny_http::Server,
+        requests: mpsc::Sender<ReadRequest>,
+        errors: mpsc::Sender<io::Error>,
+    ) {
+        while let Ok(mut request) = http.recv() {
+            let authorization = request
+                .headers()
+                .iter()
+                .find(|header| header.field.equiv("Authorization"))
+                .map(|header| header.value.as_str().to_string());
+            let mut body = String::new();
+            let body = request
+                .as_reader()
+                .take(MAX_BODY_BYTES)
+                .read_to_string(&mut body)
+                .map(|_| body);
+
+            let (response_sender, response) = mpsc::sync_channel(1);
+            let read_request = ReadRequest {
+                method: request.method().clone(),
+                url: request.url().to_string(),
+                authorization,
+                body,
+                response: response_sender,
+            };
+            if requests.send(read_request).is_err() {
+                return;
+            }
+            let Ok(result) = response.recv() else {
+                return;
+            };
+
+            if matches!(result, Err(HttpError::Unauthorized)) {
+                thread::sleep(UNAUTHORIZED_DELAY);
+            }
+            if let Err(err) = request.respond(Self::response(result)) {
+                let _ = errors.send(err);
+            }
+        }
+    }
+
+    fn response(result: Result<String, HttpError>) -> Response<Cursor<Vec<u8>>> {
+        let response = match result {
+            Ok(body) => Response::from_string(body),
+            Err(err) => {
+                let body = serde_json::to_string(&ErrorResponse {
+                    error: err.to_string(),
+                })
+                .unwrap_or_default();
+                let response = Response::from_string(body).with_status_code(err.status_code());
+                match err {
+                    HttpError::Unauthorized => response.with_header(
+                        Header::from_bytes("WWW-Authenticate", "Basic realm=\"pg_search\"")
+                            .expect("header should be valid"),
+                    ),
+                    _ => response,
+                }
+            }
+        };
+        response.with_header(
+            Header::from_bytes("Content-Type", "application/json").expect("header should be valid"),
+        )
+    }
+
+    fn route(request: &ReadRequest) -> Result<String, HttpError> {
+        match (&request.method, request.url.as_str()) {
+            (Method::Get, "/openapi.json") => Ok(SearchApi::openapi()
+                .to_pretty_json()
+                .map_err(HttpError::Serialize)?),
+            (Method::Post, "/search") => {
+                let credentials = request
+                    .authorization
+                    .as_deref()
+                    .ok_or(HttpError::Unauthorized)
+                    .and_then(Credentials::from_header)?;
+
+                let body = request
+                    .body
+                    .as_ref()
+                    .map_err(|err| HttpError::InvalidBody(err.to_string()))?;
+                let search_request: SearchRequest = serde_json::from_str(body)
+                    .map_err(|err| HttpError::InvalidBody(err.to_string()))?;
+
+                let response = search_as(&credentials, search_request)?;
+                serde_json::to_string(&response).map_err(HttpError::Serialize)
+            }
+            _ => Err(HttpError::NotFound),
+        }
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        // Each call unblocks one of the request threads waiting on a request.
+        for _ in 0..REQUEST_THREADS {
+            self.http.unblock();
+        }
+    }
+}
+
+/// Search an index in a transaction of its own, with the privileges of the role of the
+/// credentials. Errors of the search are returned instead of stopping the background worker.
+#[utoipa::path(
+    post,
+    path = "/search",
+    operation_id = "search",
+    request_body = SearchRequest,
+    responses(
+        (status = 200, description = "The rows that match the query.", body = SearchResponse),
+        (status = 400, description = "The request or the search is invalid.", body = ErrorResponse),
+        (status = 401, description = "The credentials are missing or invalid.", body = ErrorResponse),
+    ),
+    security(("basic" = []))
+)]
+fn search_as(
+    credentials: &Credentials,
+    request: SearchRequest,
+) -> Result<SearchResponse, HttpError> {
+    let SearchRequest {
+        index,
+        query,
+        offset,
+        limit,
+    } = request;
+    let query =
+        serde_json::to_string(&SearchQueryInput::from(query)).map_err(HttpError::Serialize)?;
+
+    // The transaction is read-only, as if started with SET TRANSACTION READ ONLY, so that
+    // functions reached by the search, like those of a security filter, can't write.
+    unsafe {
+        pg_sys::SetCurrentStatementStartTimestamp();
+        pg_sys::StartTransactionCommand();
+        pg_sys::XactReadOnly = true;
+    }
+    let result = PgTryBuilder::new(|| {
+        let role_oid = credentials.authenticate()?;
+
+        let mut previous_role_oid = pg_sys::InvalidOid;
+        let mut previous_context = 0;
+        unsafe {
+            pg_sys::GetUserIdAndSecContext(&mut previous_role_oid, &mut previous_context);
+            pg_sys::SetUserIdAndSecContext(
+                role_oid,
+                previous_context | pg_sys::SECURITY_LOCAL_USERID_CHANGE as c_int,
+            );
+        }
+        let rows = search(&index, &query, offset, limit);
+        unsafe { pg_sys::SetUserIdAndSecContext(previous_role_oid, previous_context) };
+        Ok(SearchResponse { rows })
+    })
+    .catch_others(|err| Err(HttpError::Search(error_message(err))))
+    .execute();
+
+    // An error aborts the transaction, which also resets the role.
+    unsafe {
+        match &result {
+            Ok(_) => pg_sys::CommitTransactionCommand(),
+            Err(_) => pg_sys::AbortCurrentTransaction(),
+        }
+    }
+    result
+}
+
+/// The matching rows, in the order of the search.
+fn search(
+    index: &str,
+    query: &str,
+    offset: Option<i32>,
+    limit: Option<i32>,
+) -> Vec<serde_json::Value> {
+    let sql = format!(
+        "SELECT coalesce(jsonb_agg(to_jsonb(r) - 'ordinality' ORDER BY r.ordinality), '[]') \
+         FROM {}.search(query => $1::text::paradedb.searchqueryinput, offset_rows => $2, limit_rows => $3) \
+         WITH ORDINALITY r",
+        spi::quote_identifier(index)
+    );
+    let rows = Spi::get_one_with_args::<JsonB>(
+        &sql,
+        vec![
+            (PgBuiltInOids::TEXTOID.oid(), query.into_datum()),
+            (PgBuiltInOids::INT4OID.oid(), offset.into_datum()),
+            (PgBuiltInOids::INT4OID.oid(), limit.into_datum()),
+        ],
+    )
+    .unwrap_or_else(|err| panic!("error searching index {index}: {err}"));
+    match rows {
+        Some(JsonB(serde_json::Value::Array(rows))) => rows,
+        _ => vec![],
+    }
+}
+
+fn error_message(err: CaughtError) -> String {
+    match err {
+        CaughtError::PostgresError(report)
+        | CaughtError::ErrorReport(report)
+        | CaughtError::RustPanic {
+            ereport: report, ..
+        } => report.message().to_string(),
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum HttpError {
+    #[error("could not listen at {0}: {1}")]
+    AddressBindFailed(String, String),
+
+    #[error("missing or invalid credentials")]
+    Unauthorized,
+
+    #[error("not found")]
+    NotFound,
+
+    #[error("invalid request body: {0}")]
+    InvalidBody(String),
+
+    #[error("{0}")]
+    Search(String),
+
+    #[error(transparent)]
+    Serialize(#[from] serde_json::Error),
+}
+
+impl HttpError {
+    fn status_code(&self) -> u16 {
+        match self {
+            Self::Unauthorized => 401,
+            Self::NotFound => 404,
+            Self::InvalidBody(_) | Self::Search(_) => 400,
+            Self::AddressBindFailed(..) | Self::Serialize(_) => 500,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_credentials_from_header() {
+        // "alice:open:sesame", as the password may contain colons.
+        let credentials = Credentials::from_header("Basic YWxpY2U6b3BlbjpzZXNhbWU=").unwrap();
+        assert_eq!(
+            credentials,
+            Credentials {
+                role: "alice".into(),
+                password: "open:sesame".into()
+            }
+        );
+
+        for header in [
+            "Bearer YWxpY2U6b3BlbjpzZXNhbWU=",
+            "Basic !!!",
+            "Basic YWxpY2U=",
+        ] {
+            assert!(matches!(
+                Credentials::from_header(header),
+                Err(HttpError::Unauthorized)
+            ));
+        }
+    }
+
+    #[rstest]
+    fn test_search_request() {
+        let request: SearchRequest =
+            serde_json::from_str(r#"{"index": "search_idx", "query": "description:keyboard"}"#)
+                .unwrap();
+        assert_eq!(
+            SearchQueryInput::from(request.query),
+            SearchQueryInput::Parse {
+                query_string: "description:keyboard".into(),
+                conjunction: None,
+            }
+        );
+        assert_eq!((request.offset, request.limit), (None, None));
+
+        let request: SearchRequest = serde_json::from_str(
+            r#"{"index": "search_idx", "query": {"Regex": {"field": "description", "pattern": "key.*"}}, "limit": 5}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            SearchQueryInput::from(request.query),
+            SearchQueryInput::Regex {
+                field: "description".into(),
+                pattern: "key.*".into(),
+                max_expansions: None
+            }
+        );
+        assert_eq!(request.limit, Some(5));
+    }
+
+    #[rstest]
+    fn test_openapi() {
+        let openapi: serde_json::Value =
+            serde_json::from_str(&SearchApi::openapi().to_json().unwrap()).unwrap();
+        assert!(openapi["paths"]["/search"]["post"].is_object());
+        assert!(openapi["components"]["schemas"]["SearchQueryInput"].is_object());
+        assert_eq!(
+            openapi["components"]["securitySchemes"]["basic"]["scheme"],
+            "basic"
+        );
+    }
+}
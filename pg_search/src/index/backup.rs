@@ -0,0 +1,204 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use crate::writer::{SearchDirectoryError, SearchFs, TantivyDirPath, WriterDirectory};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+static MANIFEST_FILE_NAME: &str = "backup.json";
+static CONFIG_FILE_NAME: &str = "search-index.json";
+static TANTIVY_DIR_NAME: &str = "tantivy";
+static META_FILE_NAME: &str = "meta.json";
+static MANAGED_FILE_NAME: &str = ".managed.json";
+
+// A merge that completes while files are being opened can remove some of them. The backup
+// is then started again from the new list of segments.
+const MAX_BACKUP_ATTEMPTS: usize = 5;
+
+/// Describes the backup of an index. Saved next to the backed up files.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BackupManifest {
+    pub index_name: String,
+    pub uuid: String,
+    pub num_docs: u64,
+    /// The files of the segments, relative to the tantivy directory of the backup.
+    pub files: Vec<PathBuf>,
+}
+
+/// A copy of the committed segments of an index, along with its configuration, that can be
+/// restored in place of the index, for instance along with a base backup of the cluster.
+pub struct IndexBackup {}
+
+impl IndexBackup {
+    /// Copy the segments of the last commit of an index to `path`, which must not exist or
+    /// be an empty directory. The segments are copied while the index keeps being written.
+    pub fn backup(search_index: &SearchIndex, path: &Path) -> Result<BackupManifest, BackupError> {
+        if path.exists() && fs::read_dir(path)?.next().is_some() {
+            return Err(BackupError::NotEmpty(path.to_path_buf()));
+        }
+        // Segments in object storage aren't on local disk to be copied.
+        if let Some(storage) = &search_index.storage {
+            return Err(BackupError::RemoteStorage(storage.url.clone()));
+        }
+        let TantivyDirPath(source_path) = search_index.directory.tantivy_dir_path(false)?;
+        let backup_tantivy_path = path.join(TANTIVY_DIR_NAME);
+        fs::create_dir_all(&backup_tantivy_path)?;
+
+        for _ in 0..MAX_BACKUP_ATTEMPTS {
+            let meta = search_index.underlying_index.load_metas()?;
+            let mut files: Vec<PathBuf> = meta
+                .segments
+                .iter()
+                .flat_map(|segment| segment.list_files())
+                .collect();
+            files.sort();
+
+            // Once open, the files can be read even if they're removed by a merge.
+            let Some(opened) = Self::open_all(&source_path, &files)? else {
+                continue;
+            };
+            for (file, mut source) in files.iter().zip(opened) {
+                let mut target = File::create(backup_tantivy_path.join(file))?;
+                io::copy(&mut source, &mut target)?;
+                target.sync_all()?;
+            }
+
+            // The meta file is written from the segments that were copied, rather than
+            // copied itself, as it may have changed since.
+            fs::write(
+                backup_tantivy_path.join(META_FILE_NAME),
+                serde_json::to_string_pretty(&meta)?,
+            )?;
+            let mut managed = files.clone();
+            managed.push(META_FILE_NAME.into());
+            fs::write(
+                backup_tantivy_path.join(MANAGED_FILE_NAME),
+                serde_json::to_string(&managed)?,
+            )?;
+            fs::write(
+                path.join(CONFIG_FILE_NAME),
+                serde_json::to_string(search_index)?,
+            )?;
+
+            let manifest = BackupManifest {
+                index_name: search_index.directory.index_name.clone(),
+                uuid: search_index.uuid.clone(),
+                num_docs: meta
+                    .segments
+                    .iter()
+                    .map(|segment| segment.num_docs() as u64)
+                    .sum(),
+                files,
+            };
+            fs::write(
+                path.join(MANIFEST_FILE_NAME),
+                serde_json::to_string_pretty(&manifest)?,
+            )?;
+            return Ok(manifest);
+        }
+
+        Err(BackupError::TooManyAttempts(MAX_BACKUP_ATTEMPTS))
+    }
+
+    /// The open files, or None if one of them was removed.
+    fn open_all(root: &Path, files: &[PathBuf]) -> Result<Option<Vec<File>>, BackupError> {
+        let mut opened = vec![];
+        for file in files {
+            match File::open(root.join(file)) {
+                Ok(file) => opened.push(file),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(Some(opened))
+    }
+
+    /// The manifest of the backup at `path`.
+    pub fn manifest(path: &Path) -> Result<BackupManifest, BackupError> {
+        let serialized = fs::read_to_string(path.join(MANIFEST_FILE_NAME))
+            .map_err(|err| BackupError::ManifestRead(path.to_path_buf(), err))?;
+        Ok(serde_json::from_str(&serialized)?)
+    }
+
+    /// Copy the backup at `path` into `directory`, which must have been emptied, for an index
+    /// with the same uuid as the backed up one.
+    pub fn restore(
+        path: &Path,
+        directory: &WriterDirectory,
+        uuid: &str,
+    ) -> Result<BackupManifest, BackupError> {
+        let manifest = Self::manifest(path)?;
+        if manifest.uuid != uuid {
+            return Err(BackupError::UuidMismatch(manifest.uuid, uuid.to_string()));
+        }
+
+        let TantivyDirPath(target_path) = directory.tantivy_dir_path(true)?;
+        let backup_tantivy_path = path.join(TANTIVY_DIR_NAME);
+        for file in manifest.files.iter().chain([
+            &PathBuf::from(META_FILE_NAME),
+            &PathBuf::from(MANAGED_FILE_NAME),
+        ]) {
+            fs::copy(backup_tantivy_path.join(file), target_path.join(file))?;
+        }
+
+        // The backup may come from another cluster or database, so the configuration is
+        // pointed at the directory it's restored to.
+        let mut config: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(path.join(CONFIG_FILE_NAME))?)?;
+        config["directory"] = serde_json::to_value(directory)?;
+        directory.save_index(&config)?;
+
+        Ok(manifest)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error("backup directory {0:?} is not empty")]
+    NotEmpty(PathBuf),
+
+    #[error("could not read backup manifest in {0:?}: {1}")]
+    ManifestRead(PathBuf, #[source] io::Error),
+
+    #[error("backup is of an index with uuid {0}, not {1}")]
+    UuidMismatch(String, String),
+
+    #[error("index is kept in object storage at {0}, back up the store instead")]
+    RemoteStorage(String),
+
+    #[error("segments kept changing during backup, gave up after {0} attempts")]
+    TooManyAttempts(usize),
+
+    #[error(transparent)]
+    SearchIndexError(#[from] SearchIndexError),
+
+    #[error(transparent)]
+    SearchDirectoryError(#[from] SearchDirectoryError),
+
+    #[error(transparent)]
+    TantivyError(#[from] tantivy::TantivyError),
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+}
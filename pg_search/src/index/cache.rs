@@ -0,0 +1,348 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::globals::{QUERY_CACHE_HITS, QUERY_CACHE_MISSES};
+use crate::postgres::types::TantivyValue;
+use crate::query::SearchQueryInput;
+use crate::schema::{SearchConfig, SortField};
+use indexmap::IndexMap;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, PoisonError};
+use tantivy::query::Query;
+use tantivy::{DocAddress, Opstamp, Score, Searcher, SegmentId};
+
+/// The results of a search, as returned by `SearchState::search`.
+pub type SearchResults = Vec<(Score, DocAddress, TantivyValue, u64)>;
+
+/// Like `SEARCH_INDEX_MEMORY`, the cache is local to a backend process, so it mostly helps
+/// connections that run the same queries over and over, like the ones of a dashboard.
+static QUERY_CACHE: Lazy<Mutex<QueryCache>> = Lazy::new(|| Mutex::new(QueryCache::new(0)));
+
+/// The compiled queries of the searches of a connection, which only depend on the query and
+/// on the schema of the index, so that prepared statements running the same query skip
+/// parsing it. Their terms are still looked up in the segments by every search.
+static QUERY_PLAN_CACHE: Lazy<Mutex<QueryCache<QueryPlanKey, QueryPlan>>> =
+    Lazy::new(|| Mutex::new(QueryCache::new(0)));
+
+/// Identifies the committed state of an index seen by a searcher: a commit that adds, merges
+/// or deletes documents changes its segments or their delete opstamps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IndexGeneration(Vec<(SegmentId, Option<Opstamp>)>);
+
+impl From<&Searcher> for IndexGeneration {
+    fn from(searcher: &Searcher) -> Self {
+        Self(
+            searcher
+                .segment_readers()
+                .iter()
+                .map(|reader| (reader.segment_id(), reader.delete_opstamp()))
+                .collect(),
+        )
+    }
+}
+
+/// Everything in a search config that changes its results.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct QueryCacheKey {
+    index_name: String,
+    query: String,
+    offset_rows: Option<usize>,
+    limit_rows: Option<usize>,
+    stable_sort: bool,
+    scored: bool,
+    sort_by: Option<Vec<SortField>>,
+    random_seed: Option<i64>,
+    tenant: Option<String>,
+    latest_only: bool,
+}
+
+impl From<&SearchConfig> for QueryCacheKey {
+    fn from(config: &SearchConfig) -> Self {
+        Self {
+            index_name: config.index_name.clone(),
+            query: serde_json::to_string(&config.query)
+                .expect("could not serialize query for the query cache"),
+            offset_rows: config.offset_rows,
+            limit_rows: config.limit_rows,
+            stable_sort: config.stable_sort(),
+            scored: config.scored.unwrap_or(true),
+            sort_by: config.sort_by.clone(),
+            random_seed: config.random_seed,
+            tenant: config.tenant.clone(),
+            latest_only: false,
+        }
+    }
+}
+
+impl QueryCacheKey {
+    /// The query that is searched, whose filters can depend on settings of the session.
+    pub fn search_query(mut self, search_query: &SearchQueryInput) -> Self {
+        self.query = serde_json::to_string(search_query)
+            .expect("could not serialize query for the query cache");
+        self
+    }
+
+    /// Searches that skip replaced versions of rows can return other results.
+    pub fn latest_only(mut self, latest_only: bool) -> Self {
+        self.latest_only = latest_only;
+        self
+    }
+}
+
+/// The compiled queries of a search: its query and its named sub-queries.
+#[derive(Clone)]
+pub struct QueryPlan {
+    pub query: Arc<dyn Query>,
+    pub named_queries: Vec<(String, Arc<dyn Query>)>,
+}
+
+/// Identifies the compiled queries of a search, by the fingerprint of the query searched.
+/// Commits don't change them, but a rebuild of the index, which can change its schema, gives
+/// it another uuid.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct QueryPlanKey {
+    index_name: String,
+    uuid: String,
+    query: String,
+    max_term_expansions: (Option<usize>, bool),
+}
+
+impl QueryPlanKey {
+    pub fn new(index_name: &str, uuid: &str, search_query: &SearchQueryInput) -> Self {
+        Self {
+            index_name: index_name.to_string(),
+            uuid: uuid.to_string(),
+            query: serde_json::to_string(search_query)
+                .expect("could not serialize query for the query plan cache"),
+            max_term_expansions: (None, false),
+        }
+    }
+
+    /// Regex and fuzzy queries are compiled with the limit of their terms of the session.
+    pub fn max_term_expansions(mut self, max_term_expansions: (Option<usize>, bool)) -> Self {
+        self.max_term_expansions = max_term_expansions;
+        self
+    }
+}
+
+/// The keys of a `QueryCache` belong to an index, whose entries are dropped together.
+pub trait IndexCacheKey: Clone + Hash + Eq {
+    fn index_name(&self) -> &str;
+}
+
+impl IndexCacheKey for QueryCacheKey {
+    fn index_name(&self) -> &str {
+        &self.index_name
+    }
+}
+
+impl IndexCacheKey for QueryPlanKey {
+    fn index_name(&self) -> &str {
+        &self.index_name
+    }
+}
+
+/// A least recently used cache of search results, or of query plans. The results of an
+/// index are dropped as soon as a search sees a new generation of it.
+pub struct QueryCache<K = QueryCacheKey, V = SearchResults> {
+    capacity: usize,
+    generations: HashMap<String, IndexGeneration>,
+    entries: IndexMap<K, V>,
+}
+
+impl QueryCache {
+    /// Run `search` unless its results are cached for this generation of the index. With a
+    /// capacity of 0, the cache is disabled and emptied.
+    pub fn get_or_search(
+        capacity: usize,
+        key: QueryCacheKey,
+        generation: impl FnOnce() -> IndexGeneration,
+        search: impl FnOnce() -> SearchResults,
+    ) -> SearchResults {
+        let mut cache = QUERY_CACHE.lock().unwrap_or_else(PoisonError::into_inner);
+        if capacity > 0 {
+            cache.refresh(key.index_name(), generation());
+        }
+        let (results, hit) = cache.get_or_insert_with(capacity, key, search);
+        if capacity > 0 {
+            let counter = if hit {
+                &QUERY_CACHE_HITS
+            } else {
+                &QUERY_CACHE_MISSES
+            };
+            counter.get().fetch_add(1, Ordering::Relaxed);
+        }
+        results
+    }
+}
+
+impl QueryCache<QueryPlanKey, QueryPlan> {
+    /// Compile the queries of a search with `compile`, unless they were already compiled, so
+    /// that repeated searches skip parsing and building them. Unlike results, they're kept
+    /// when the index is committed.
+    pub fn get_or_compile(
+        capacity: usize,
+        key: QueryPlanKey,
+        compile: impl FnOnce() -> QueryPlan,
+    ) -> QueryPlan {
+        let mut cache = QUERY_PLAN_CACHE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        cache.get_or_insert_with(capacity, key, compile).0
+    }
+}
+
+impl<K: IndexCacheKey, V: Clone> QueryCache<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            generations: HashMap::new(),
+            entries: IndexMap::new(),
+        }
+    }
+
+    /// The cached value of `key`, or the one computed by `compute` and cached, along with
+    /// whether it was cached.
+    fn get_or_insert_with(
+        &mut self,
+        capacity: usize,
+        key: K,
+        compute: impl FnOnce() -> V,
+    ) -> (V, bool) {
+        self.set_capacity(capacity);
+        if capacity == 0 {
+            return (compute(), false);
+        }
+
+        if let Some(value) = self.get(&key) {
+            return (value, true);
+        }
+
+        let value = compute();
+        self.insert(key, value.clone());
+        (value, false)
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.entries.len() > capacity {
+            self.entries.shift_remove_index(0);
+        }
+        if capacity == 0 {
+            self.generations.clear();
+        }
+    }
+
+    /// Drop the cached entries of an index if it was committed since they were cached.
+    fn refresh(&mut self, index_name: &str, generation: IndexGeneration) {
+        if self.generations.get(index_name) != Some(&generation) {
+            self.entries.retain(|key, _| key.index_name() != index_name);
+            self.generations.insert(index_name.to_string(), generation);
+        }
+    }
+
+    fn get(&mut self, key: &K) -> Option<V> {
+        // Move the entry to the back, so that it's evicted last.
+        let value = self.entries.shift_remove(key)?;
+        self.entries.insert(key.clone(), value.clone());
+        Some(value)
+    }
+
+    fn insert(&mut self, key: K, value: V) {
+        self.entries.shift_remove(&key);
+        if self.entries.len() >= self.capacity {
+            self.entries.shift_remove_index(0);
+        }
+        self.entries.insert(key, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IndexGeneration, QueryCache, QueryCacheKey, QueryPlanKey, SearchResults};
+    use crate::postgres::types::TantivyValue;
+    use crate::query::SearchQueryInput;
+    use tantivy::{DocAddress, SegmentId};
+
+    fn key(index_name: &str, query: &str) -> QueryCacheKey {
+        QueryCacheKey {
+            index_name: index_name.into(),
+            query: query.into(),
+            offset_rows: None,
+            limit_rows: None,
+            stable_sort: false,
+            scored: true,
+            sort_by: None,
+            random_seed: None,
+            tenant: None,
+            latest_only: false,
+        }
+    }
+
+    fn results(ctid: u64) -> SearchResults {
+        vec![(1.0, DocAddress::new(0, 0), TantivyValue(1i64.into()), ctid)]
+    }
+
+    #[test]
+    fn test_query_cache() {
+        let generation = IndexGeneration(vec![(SegmentId::generate_random(), None)]);
+        let mut cache = QueryCache::new(2);
+
+        cache.refresh("index", generation.clone());
+        cache.insert(key("index", "a"), results(1));
+        cache.insert(key("index", "b"), results(2));
+        assert_eq!(cache.get(&key("index", "a")), Some(results(1)));
+
+        // "b" is now the least recently used, so it's evicted first.
+        cache.insert(key("index", "c"), results(3));
+        assert_eq!(cache.get(&key("index", "b")), None);
+        assert_eq!(cache.get(&key("index", "c")), Some(results(3)));
+
+        // The same generation keeps the results, a new one drops them.
+        cache.refresh("index", generation);
+        assert_eq!(cache.get(&key("index", "a")), Some(results(1)));
+        cache.refresh(
+            "index",
+            IndexGeneration(vec![(SegmentId::generate_random(), None)]),
+        );
+        assert_eq!(cache.get(&key("index", "a")), None);
+        assert_eq!(cache.get(&key("index", "c")), None);
+    }
+
+    #[test]
+    fn test_query_plan_cache() {
+        let mut cache: QueryCache<QueryPlanKey, u32> = QueryCache::new(0);
+        let key = QueryPlanKey::new("index", "uuid", &SearchQueryInput::All);
+
+        // A capacity of 0 compiles every time, without caching.
+        let (_, hit) = cache.get_or_insert_with(0, key.clone(), || 1);
+        assert!(!hit);
+        assert_eq!(cache.entries.len(), 0);
+
+        assert_eq!(cache.get_or_insert_with(2, key.clone(), || 1), (1, false));
+        assert_eq!(cache.get_or_insert_with(2, key.clone(), || 2), (1, true));
+
+        // Another query, or a rebuild of the index, is compiled again.
+        let other = QueryPlanKey::new("index", "uuid", &SearchQueryInput::Empty);
+        assert_eq!(cache.get_or_insert_with(2, other, || 3), (3, false));
+        let rebuilt = QueryPlanKey::new("index", "other uuid", &SearchQueryInput::All);
+        assert_eq!(cache.get_or_insert_with(2, rebuilt, || 4), (4, false));
+    }
+}
@@ -0,0 +1,212 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use std::fmt::Display;
+use tantivy::collector::Count;
+use tantivy::query::TermQuery;
+use tantivy::schema::{Field, IndexRecordOption, OwnedValue};
+use tantivy::{Directory, Term};
+
+// Row estimates are only refreshed by VACUUM, ANALYZE and index builds, so the document
+// count of an index is only expected to be close to them.
+const ROW_ESTIMATE_TOLERANCE: f64 = 0.1;
+const MIN_ROW_ESTIMATE_SLACK: f64 = 10.0;
+/// How many missing files or keys are listed in the detail of a failed check.
+const MAX_LISTED: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    Skipped,
+}
+
+impl CheckStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Failed => "failed",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+/// The outcome of one of the checks of `paradedb.check_index`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IndexCheck {
+    pub name: &'static str,
+    pub status: CheckStatus,
+    pub detail: String,
+}
+
+impl IndexCheck {
+    fn new(name: &'static str, status: CheckStatus, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+
+    /// Check that the files of every searchable segment exist, and that their checksums
+    /// match their content. Checksums are only verified once no file is missing.
+    pub fn files(search_index: &SearchIndex) -> Result<Vec<Self>, SearchIndexError> {
+        // Files are looked up through the index directory, as they may be in object storage.
+        let directory = search_index.underlying_index.directory();
+        let mut missing: Vec<String> = search_index
+            .underlying_index
+            .searchable_segment_metas()?
+            .iter()
+            .flat_map(|meta| meta.list_files())
+            .filter(|file| !matches!(directory.exists(file), Ok(true)))
+            .map(|file| file.display().to_string())
+            .collect();
+        missing.sort();
+
+        if !missing.is_empty() {
+            return Ok(vec![
+                Self::new(
+                    "files",
+                    CheckStatus::Failed,
+                    format!("missing files: {}", listed(&missing)),
+                ),
+                Self::new(
+                    "checksums",
+                    CheckStatus::Skipped,
+                    "checksums can't be verified while files are missing",
+                ),
+            ]);
+        }
+
+        let mut corrupted: Vec<String> = search_index
+            .underlying_index
+            .validate_checksum()?
+            .iter()
+            .map(|file| file.display().to_string())
+            .collect();
+        corrupted.sort();
+
+        let checksums = if corrupted.is_empty() {
+            Self::new("checksums", CheckStatus::Ok, "")
+        } else {
+            Self::new(
+                "checksums",
+                CheckStatus::Failed,
+                format!("files with a wrong checksum: {}", listed(&corrupted)),
+            )
+        };
+        Ok(vec![Self::new("files", CheckStatus::Ok, ""), checksums])
+    }
+
+    /// Compare the documents of the index with the row estimate of its table, if there is
+    /// one. Partial indexes don't hold every row, so they can't be compared.
+    pub fn doc_count(search_index: &SearchIndex, heap_rows: Option<f64>, partial: bool) -> Self {
+        if partial {
+            return Self::new("doc_count", CheckStatus::Skipped, "the index is partial");
+        }
+        let Some(heap_rows) = heap_rows.filter(|rows| *rows >= 0.0) else {
+            return Self::new(
+                "doc_count",
+                CheckStatus::Skipped,
+                "the table has no row estimate, run ANALYZE on it first",
+            );
+        };
+
+        let num_docs = search_index.searcher().num_docs() as f64;
+        let slack = (heap_rows * ROW_ESTIMATE_TOLERANCE).max(MIN_ROW_ESTIMATE_SLACK);
+        let status = if (num_docs - heap_rows).abs() <= slack {
+            CheckStatus::Ok
+        } else {
+            CheckStatus::Failed
+        };
+        Self::new(
+            "doc_count",
+            status,
+            format!("the index has {num_docs} documents, the table has about {heap_rows} rows"),
+        )
+    }
+
+    /// Check that the keys of rows sampled from the table are in the index.
+    pub fn keys(search_index: &SearchIndex, sampled_keys: &[OwnedValue]) -> Self {
+        if sampled_keys.is_empty() {
+            return Self::new("keys", CheckStatus::Skipped, "no rows were sampled");
+        }
+
+        let key_field: Field = (&search_index.schema.key_field()).into();
+        let searcher = search_index.searcher();
+        let missing: Vec<String> = sampled_keys
+            .iter()
+            .filter(|key| {
+                let term = match key {
+                    OwnedValue::I64(key) => Term::from_field_i64(key_field, *key),
+                    OwnedValue::U64(key) => Term::from_field_u64(key_field, *key),
+                    OwnedValue::F64(key) => Term::from_field_f64(key_field, *key),
+                    OwnedValue::Str(key) => Term::from_field_text(key_field, key),
+                    _ => return false,
+                };
+                let query = TermQuery::new(term, IndexRecordOption::Basic);
+                searcher.search(&query, &Count).unwrap_or_default() == 0
+            })
+            .map(|key| format!("{key:?}"))
+            .collect();
+
+        if missing.is_empty() {
+            Self::new(
+                "keys",
+                CheckStatus::Ok,
+                format!("{} sampled keys are in the index", sampled_keys.len()),
+            )
+        } else {
+            Self::new(
+                "keys",
+                CheckStatus::Failed,
+                format!(
+                    "{} of {} sampled keys are missing from the index: {}",
+                    missing.len(),
+                    sampled_keys.len(),
+                    listed(&missing)
+                ),
+            )
+        }
+    }
+}
+
+fn listed(items: &[impl Display]) -> String {
+    let mut listed = items
+        .iter()
+        .take(MAX_LISTED)
+        .map(|item| item.to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if items.len() > MAX_LISTED {
+        listed.push_str(&format!(" and {} more", items.len() - MAX_LISTED));
+    }
+    listed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::listed;
+
+    #[test]
+    fn test_listed() {
+        assert_eq!(listed(&["a", "b"]), "a, b");
+        let items: Vec<usize> = (0..12).collect();
+        assert_eq!(listed(&items), "0, 1, 2, 3, 4, 5, 6, 7, 8, 9 and 2 more");
+    }
+}
@@ -0,0 +1,729 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::score::SearchIndexScore;
+use crate::postgres::types::TantivyValue;
+use crate::schema::{SearchFieldType, SearchIndexSchema, SortField, SortOrder};
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::hash::{Hash, Hasher};
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::query::Weight;
+use tantivy::{DocAddress, DocId, Score, SegmentOrdinal, SegmentReader, TantivyError};
+
+/// Reads the value of the key field for a document of a single segment.
+pub type KeyReader = Box<dyn FnMut(DocId) -> TantivyValue + Send>;
+
+/// Build a reader for the fast field values of the index's key field.
+pub fn key_reader(
+    schema: &SearchIndexSchema,
+    key_field_name: &str,
+    segment_reader: &SegmentReader,
+) -> KeyReader {
+    let fast_fields = segment_reader.fast_fields();
+
+    // Check the type of the field from the schema
+    match schema
+        .get_search_field(key_field_name)
+        .unwrap_or_else(|| panic!("key field {} not found", key_field_name))
+        .type_
+    {
+        SearchFieldType::I64 => {
+            let key_field_reader = fast_fields
+                .i64(key_field_name)
+                .unwrap_or_else(|err| panic!("key field {} is not a i64: {err:?}", key_field_name))
+                .first_or_default_col(0);
+            Box::new(move |doc: DocId| TantivyValue(key_field_reader.get_val(doc).into()))
+        }
+        SearchFieldType::U64 => {
+            let key_field_reader = fast_fields
+                .u64(key_field_name)
+                .unwrap_or_else(|err| panic!("key field {} is not a u64: {err:?}", key_field_name))
+                .first_or_default_col(0);
+            Box::new(move |doc: DocId| TantivyValue(key_field_reader.get_val(doc).into()))
+        }
+        SearchFieldType::F64 => {
+            let key_field_reader = fast_fields
+                .f64(key_field_name)
+                .unwrap_or_else(|err| panic!("key field {} is not a f64: {err:?}", key_field_name))
+                .first_or_default_col(0.0);
+            Box::new(move |doc: DocId| TantivyValue(key_field_reader.get_val(doc).into()))
+        }
+        SearchFieldType::Text => {
+            let key_field_reader = fast_fields
+                .str(key_field_name)
+                .unwrap_or_else(|err| {
+                    panic!("key field {} is not a string: {err:?}", key_field_name)
+                })
+                .unwrap();
+            Box::new(move |doc: DocId| {
+                let mut tok_str: String = Default::default();
+                let ord = key_field_reader.term_ords(doc).nth(0).unwrap();
+                key_field_reader
+                    .ord_to_str(ord, &mut tok_str)
+                    .expect("no string!!");
+                TantivyValue(tok_str.into())
+            })
+        }
+        SearchFieldType::Bool => {
+            let key_field_reader = fast_fields
+                .bool(key_field_name)
+                .unwrap_or_else(|err| panic!("key field {} is not a bool: {err:?}", key_field_name))
+                .first_or_default_col(false);
+            Box::new(move |doc: DocId| TantivyValue(key_field_reader.get_val(doc).into()))
+        }
+        SearchFieldType::Date => {
+            let key_field_reader = fast_fields
+                .date(key_field_name)
+                .unwrap_or_else(|err| panic!("key field {} is not a date: {err:?}", key_field_name))
+                .first_or_default_col(tantivy::DateTime::MIN);
+            Box::new(move |doc: DocId| TantivyValue(key_field_reader.get_val(doc).into()))
+        }
+        _ => panic!("key field {} is not a supported field type", key_field_name),
+    }
+}
+
+/// A pseudo-random score in `[0, 1)` for a document, derived from a seed and the document's
+/// key. It doesn't depend on where the document is stored, so the order of the results of a
+/// seed survives merges and is the same across searchers.
+pub fn random_score(seed: i64, key: &TantivyValue) -> Score {
+    let mut hasher = SeededHasher::new(seed);
+    key.hash(&mut hasher);
+    // The top 24 bits fit exactly in the mantissa of a f32.
+    (hasher.finish() >> 40) as Score / (1u64 << 24) as Score
+}
+
+/// FNV-1a, finished with the SplitMix64 mixer. Unlike the standard library's hasher, its
+/// output is guaranteed not to change between releases.
+struct SeededHasher(u64);
+
+impl SeededHasher {
+    fn new(seed: i64) -> Self {
+        Self(0xcbf29ce484222325 ^ seed as u64)
+    }
+}
+
+impl Hasher for SeededHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        let mut z = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+/// Collects the top documents ordered by descending score and, in case of a tie, by ascending
+/// key, like `SearchIndexScore`.
+///
+/// With `prune` set, documents are passed to the query's weight with a score threshold, so
+/// that disjunctions can skip whole blocks of postings whose maximum score can't reach the
+/// top results (block-max WAND). Documents tied with the last top score are still collected,
+/// as their keys decide which of them are returned. Otherwise, every matching document is
+/// scored.
+pub struct StableTopDocs {
+    schema: SearchIndexSchema,
+    key_field_name: String,
+    limit: usize,
+    offset: usize,
+    prune: bool,
+}
+
+impl StableTopDocs {
+    pub fn new(
+        schema: SearchIndexSchema,
+        key_field_name: String,
+        limit: usize,
+        offset: usize,
+        prune: bool,
+    ) -> Self {
+        Self {
+            schema,
+            key_field_name,
+            limit,
+            offset,
+            prune,
+        }
+    }
+}
+
+impl Collector for StableTopDocs {
+    type Fruit = Vec<(SearchIndexScore, DocAddress)>;
+    type Child = StableTopDocsSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_ord: SegmentOrdinal,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        Ok(StableTopDocsSegmentCollector {
+            segment_ord,
+            key_reader: key_reader(&self.schema, &self.key_field_name, segment_reader),
+            candidates: TopScores::new(self.limit + self.offset),
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut merged: Self::Fruit = segment_fruits.into_iter().flatten().collect();
+        merged.sort_by(|(score_a, _), (score_b, _)| {
+            score_b.partial_cmp(score_a).unwrap_or(Ordering::Equal)
+        });
+        Ok(merged
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect())
+    }
+
+    fn collect_segment(
+        &self,
+        weight: &dyn Weight,
+        segment_ord: SegmentOrdinal,
+        reader: &SegmentReader,
+    ) -> tantivy::Result<Vec<(SearchIndexScore, DocAddress)>> {
+        let mut segment_collector = self.for_segment(segment_ord, reader)?;
+        let alive_bitset = reader.alive_bitset();
+        let is_alive = |doc: DocId| alive_bitset.map_or(true, |bitset| bitset.is_alive(doc));
+
+        if self.prune {
+            weight.for_each_pruning(Score::MIN, reader, &mut |doc, score| {
+                if is_alive(doc) {
+                    segment_collector.collect(doc, score);
+                }
+                segment_collector.candidates.pruning_threshold()
+            })?;
+        } else {
+            weight.for_each(reader, &mut |doc, score| {
+                if is_alive(doc) {
+                    segment_collector.collect(doc, score);
+                }
+            })?;
+        }
+
+        Ok(segment_collector.harvest())
+    }
+}
+
+pub struct StableTopDocsSegmentCollector {
+    segment_ord: SegmentOrdinal,
+    key_reader: KeyReader,
+    candidates: TopScores,
+}
+
+impl StableTopDocsSegmentCollector {
+    /// Many documents tied with the lowest top score can only be told apart by their keys, so
+    /// they're read to keep the top `limit` candidates, and the others are dropped.
+    fn truncate_ties(&mut self) {
+        let mut keyed: Vec<_> = self
+            .candidates
+            .docs
+            .drain(..)
+            .map(|(doc, score)| {
+                let score = SearchIndexScore {
+                    bm25: score,
+                    key: (self.key_reader)(doc),
+                };
+                (score, doc)
+            })
+            .collect();
+        keyed.sort_by(|(score_a, _), (score_b, _)| {
+            score_b.partial_cmp(score_a).unwrap_or(Ordering::Equal)
+        });
+        keyed.truncate(self.candidates.limit);
+        self.candidates.docs = keyed
+            .into_iter()
+            .map(|(score, doc)| (doc, score.bm25))
+            .collect();
+    }
+}
+
+impl SegmentCollector for StableTopDocsSegmentCollector {
+    type Fruit = Vec<(SearchIndexScore, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        self.candidates.push(doc, score);
+        if self.candidates.is_crowded() {
+            self.truncate_ties();
+        }
+    }
+
+    fn harvest(mut self) -> Self::Fruit {
+        let limit = self.candidates.limit;
+        // Keys are only read for the remaining candidates, which is what makes this cheaper
+        // than tweaking the score of every matching document.
+        let mut fruit: Self::Fruit = self
+            .candidates
+            .into_docs()
+            .into_iter()
+            .map(|(doc, score)| {
+                let score = SearchIndexScore {
+                    bm25: score,
+                    key: (self.key_reader)(doc),
+                };
+                (score, DocAddress::new(self.segment_ord, doc))
+            })
+            .collect();
+        fruit.sort_by(|(score_a, _), (score_b, _)| {
+            score_b.partial_cmp(score_a).unwrap_or(Ordering::Equal)
+        });
+        fruit.truncate(limit);
+        fruit
+    }
+}
+
+/// The documents of a segment that may still be among the top `limit`, which are the ones
+/// scoring at least as high as the `limit`-th best score seen so far.
+struct TopScores {
+    limit: usize,
+    heap: BinaryHeap<Reverse<OrderedScore>>,
+    docs: Vec<(DocId, Score)>,
+}
+
+impl TopScores {
+    fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            heap: BinaryHeap::with_capacity(limit),
+            docs: vec![],
+        }
+    }
+
+    fn push(&mut self, doc: DocId, score: Score) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.heap.len() < self.limit {
+            self.heap.push(Reverse(OrderedScore(score)));
+        } else if let Some(mut lowest) = self.heap.peek_mut() {
+            if score > lowest.0 .0 {
+                *lowest = Reverse(OrderedScore(score));
+            } else if score < lowest.0 .0 {
+                return;
+            }
+        }
+        self.docs.push((doc, score));
+
+        // Drop the documents that fell out of the top results, without doing it on every push.
+        if self.is_crowded() {
+            let lowest = self.lowest();
+            self.docs.retain(|(_, score)| *score >= lowest);
+        }
+    }
+
+    /// Whether there are enough documents to be worth dropping some. If there still are after
+    /// dropping the ones below the lowest top score, they're tied with it.
+    fn is_crowded(&self) -> bool {
+        self.docs.len() >= 2 * self.limit.max(64)
+    }
+
+    fn lowest(&self) -> Score {
+        match self.heap.peek() {
+            Some(Reverse(OrderedScore(score))) if self.heap.len() == self.limit => *score,
+            _ => Score::MIN,
+        }
+    }
+
+    /// The score a document must beat to be collected. It's just below the lowest top score,
+    /// so that documents tied with it aren't skipped.
+    fn pruning_threshold(&self) -> Score {
+        let lowest = self.lowest();
+        if lowest > 0.0 && lowest.is_finite() {
+            f32::from_bits(lowest.to_bits() - 1)
+        } else {
+            Score::MIN
+        }
+    }
+
+    fn into_docs(mut self) -> Vec<(DocId, Score)> {
+        let lowest = self.lowest();
+        self.docs.retain(|(_, score)| *score >= lowest);
+        self.docs
+    }
+}
+
+#[derive(PartialEq, PartialOrd)]
+struct OrderedScore(Score);
+
+impl Eq for OrderedScore {}
+
+impl Ord for OrderedScore {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.partial_cmp(other).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// The value of a sort field for a document. A field only produces one kind of value, so
+/// comparing values of different kinds doesn't happen.
+#[derive(Clone, Debug, PartialEq, PartialOrd)]
+pub enum SortValue {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Str(String),
+}
+
+/// The values of the `sort_by` fields of a document, `None` where the document has no value.
+pub type SortKey = Vec<Option<SortValue>>;
+
+/// Reads the values of the `sort_by` fields for a document of a single segment.
+pub struct SortKeyReader {
+    columns: Vec<Box<dyn FnMut(DocId) -> Option<SortValue>>>,
+}
+
+impl SortKeyReader {
+    pub fn new(
+        schema: &SearchIndexSchema,
+        sort_by: &[SortField],
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<Self> {
+        let fast_fields = segment_reader.fast_fields();
+        let not_fast = |name: &str| {
+            TantivyError::InvalidArgument(format!(
+                "sort field '{name}' must be a fast field of the index"
+            ))
+        };
+
+        let mut columns: Vec<Box<dyn FnMut(DocId) -> Option<SortValue>>> = vec![];
+        for SortField { field, .. } in sort_by {
+            let name = field.as_str();
+            let search_field = schema.get_search_field(name).ok_or_else(|| {
+                TantivyError::InvalidArgument(format!(
+                    "sort field '{field}' does not exist in the index"
+                ))
+            })?;
+            match search_field.type_ {
+                SearchFieldType::I64 => {
+                    let column = fast_fields.i64(name).map_err(|_| not_fast(name))?;
+                    columns.push(Box::new(move |doc| column.first(doc).map(SortValue::I64)));
+                }
+                SearchFieldType::U64 => {
+                    let column = fast_fields.u64(name).map_err(|_| not_fast(name))?;
+                    columns.push(Box::new(move |doc| column.first(doc).map(SortValue::U64)));
+                }
+                SearchFieldType::F64 => {
+                    let column = fast_fields.f64(name).map_err(|_| not_fast(name))?;
+                    columns.push(Box::new(move |doc| column.first(doc).map(SortValue::F64)));
+                }
+                SearchFieldType::Bool => {
+                    let column = fast_fields.bool(name).map_err(|_| not_fast(name))?;
+                    columns.push(Box::new(move |doc| {
+                        column.first(doc).map(|value| SortValue::U64(value as u64))
+                    }));
+                }
+                SearchFieldType::Date => {
+                    let column = fast_fields.date(name).map_err(|_| not_fast(name))?;
+                    columns.push(Box::new(move |doc| {
+                        column
+                            .first(doc)
+                            .map(|value| SortValue::I64(value.into_timestamp_micros()))
+                    }));
+                }
+                SearchFieldType::Text => {
+                    let column = fast_fields
+                        .str(name)
+                        .map_err(|_| not_fast(name))?
+                        .ok_or_else(|| not_fast(name))?;
+                    columns.push(Box::new(move |doc| {
+                        let ord = column.term_ords(doc).next()?;
+                        let mut value = String::new();
+                        column.ord_to_str(ord, &mut value).ok()?;
+                        Some(SortValue::Str(value))
+                    }));
+                }
+                _ => {
+                    return Err(TantivyError::InvalidArgument(format!(
+                        "sort field '{field}' is not of a sortable type"
+                    )))
+                }
+            }
+        }
+        Ok(Self { columns })
+    }
+
+    pub fn read(&mut self, doc: DocId) -> SortKey {
+        self.columns.iter_mut().map(|column| column(doc)).collect()
+    }
+}
+
+/// Compare sort keys in the directions of the `sort_by` fields. Missing values come last,
+/// whatever the direction.
+pub fn compare_sort_keys(
+    sort_by: &[SortField],
+    a: &[Option<SortValue>],
+    b: &[Option<SortValue>],
+) -> Ordering {
+    for (sort_field, (value_a, value_b)) in sort_by.iter().zip(a.iter().zip(b.iter())) {
+        let ordering = match (value_a, value_b) {
+            (None, None) => Ordering::Equal,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(_), None) => Ordering::Less,
+            (Some(value_a), Some(value_b)) => {
+                let ordering = value_a.partial_cmp(value_b).unwrap_or(Ordering::Equal);
+                match sort_field.order {
+                    SortOrder::Asc => ordering,
+                    SortOrder::Desc => ordering.reverse(),
+                }
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    Ordering::Equal
+}
+
+/// Compare by the `sort_by` fields, then by descending score, then by the key field, which
+/// is the last of the sort fields.
+fn compare_sorted(
+    sort_by: &[SortField],
+    (key_a, score_a, _): &(SortKey, Score, DocAddress),
+    (key_b, score_b, _): &(SortKey, Score, DocAddress),
+) -> Ordering {
+    let fields = sort_by.len() - 1;
+    compare_sort_keys(&sort_by[..fields], &key_a[..fields], &key_b[..fields])
+        .then_with(|| score_b.partial_cmp(score_a).unwrap_or(Ordering::Equal))
+        .then_with(|| compare_sort_keys(&sort_by[fields..], &key_a[fields..], &key_b[fields..]))
+}
+
+/// Collects the top documents ordered by the fast fields of `sort_by`, by descending score
+/// when their values are tied, and then by ascending key. The values of every matching
+/// document are read, but only the top `limit + offset` documents of each segment are kept.
+pub struct FieldSortTopDocs {
+    schema: SearchIndexSchema,
+    sort_by: Vec<SortField>,
+    limit: usize,
+    offset: usize,
+}
+
+impl FieldSortTopDocs {
+    pub fn new(
+        schema: SearchIndexSchema,
+        sort_by: Vec<SortField>,
+        key_field_name: String,
+        limit: usize,
+        offset: usize,
+    ) -> Self {
+        let mut sort_by = sort_by;
+        sort_by.push(SortField {
+            field: key_field_name,
+            order: SortOrder::Asc,
+        });
+        Self {
+            schema,
+            sort_by,
+            limit,
+            offset,
+        }
+    }
+}
+
+impl Collector for FieldSortTopDocs {
+    type Fruit = Vec<(SortKey, Score, DocAddress)>;
+    type Child = FieldSortSegmentCollector;
+
+    fn for_segment(
+        &self,
+        segment_ord: SegmentOrdinal,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        Ok(FieldSortSegmentCollector {
+            segment_ord,
+            sort_by: self.sort_by.clone(),
+            sort_key_reader: SortKeyReader::new(&self.schema, &self.sort_by, segment_reader)?,
+            limit: self.limit + self.offset,
+            docs: vec![],
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        true
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut merged: Self::Fruit = segment_fruits.into_iter().flatten().collect();
+        merged.sort_by(|a, b| compare_sorted(&self.sort_by, a, b));
+        Ok(merged
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit)
+            .collect())
+    }
+}
+
+pub struct FieldSortSegmentCollector {
+    segment_ord: SegmentOrdinal,
+    sort_by: Vec<SortField>,
+    sort_key_reader: SortKeyReader,
+    limit: usize,
+    docs: Vec<(SortKey, Score, DocAddress)>,
+}
+
+impl FieldSortSegmentCollector {
+    fn truncate(&mut self) {
+        let sort_by = &self.sort_by;
+        self.docs.sort_by(|a, b| compare_sorted(sort_by, a, b));
+        self.docs.truncate(self.limit);
+    }
+}
+
+impl SegmentCollector for FieldSortSegmentCollector {
+    type Fruit = Vec<(SortKey, Score, DocAddress)>;
+
+    fn collect(&mut self, doc: DocId, score: Score) {
+        if self.limit == 0 {
+            return;
+        }
+        let key = self.sort_key_reader.read(doc);
+        self.docs
+            .push((key, score, DocAddress::new(self.segment_ord, doc)));
+
+        // Drop the documents that fell out of the top results, without doing it on every push.
+        if self.docs.len() >= 2 * self.limit.max(64) {
+            self.truncate();
+        }
+    }
+
+    fn harvest(mut self) -> Self::Fruit {
+        self.truncate();
+        self.docs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compare_sort_keys, random_score, SortValue, StableTopDocsSegmentCollector, TopScores,
+    };
+    use crate::postgres::types::TantivyValue;
+    use crate::schema::{SortField, SortOrder};
+    use std::cmp::Ordering;
+    use tantivy::collector::SegmentCollector;
+
+    #[test]
+    fn test_top_scores_keeps_ties() {
+        let mut top = TopScores::new(2);
+        assert_eq!(top.pruning_threshold(), f32::MIN);
+
+        top.push(0, 1.0);
+        top.push(1, 3.0);
+        top.push(2, 2.0);
+        top.push(3, 2.0);
+        top.push(4, 0.5);
+
+        // Documents tied with the second best score must still reach the collector.
+        let threshold = top.pruning_threshold();
+        assert!(threshold < 2.0 && threshold > 1.9);
+
+        let mut docs = top.into_docs();
+        docs.sort_by_key(|(doc, _)| *doc);
+        assert_eq!(docs, vec![(1, 3.0), (2, 2.0), (3, 2.0)]);
+    }
+
+    #[test]
+    fn test_compare_sort_keys() {
+        let sort_by = vec![
+            SortField {
+                field: "rating".into(),
+                order: SortOrder::Desc,
+            },
+            SortField {
+                field: "name".into(),
+                order: SortOrder::Asc,
+            },
+        ];
+        let key = |rating: Option<i64>, name: &str| {
+            vec![
+                rating.map(SortValue::I64),
+                Some(SortValue::Str(name.into())),
+            ]
+        };
+
+        assert_eq!(
+            compare_sort_keys(&sort_by, &key(Some(5), "b"), &key(Some(4), "a")),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_sort_keys(&sort_by, &key(Some(4), "a"), &key(Some(4), "b")),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_sort_keys(&sort_by, &key(Some(4), "a"), &key(Some(4), "a")),
+            Ordering::Equal
+        );
+        // Missing values come last, even in descending order.
+        assert_eq!(
+            compare_sort_keys(&sort_by, &key(None, "a"), &key(Some(1), "b")),
+            Ordering::Greater
+        );
+    }
+
+    #[test]
+    fn test_random_score() {
+        let key = |id: i64| TantivyValue::try_from(id).unwrap();
+
+        for id in 0..100 {
+            let score = random_score(42, &key(id));
+            assert!((0.0..1.0).contains(&score));
+            assert_eq!(score, random_score(42, &key(id)));
+        }
+
+        // Another seed shuffles the keys differently.
+        let order = |seed: i64| {
+            let mut ids: Vec<i64> = (0..100).collect();
+            ids.sort_by(|a, b| {
+                random_score(seed, &key(*a))
+                    .partial_cmp(&random_score(seed, &key(*b)))
+                    .unwrap()
+            });
+            ids
+        };
+        assert_eq!(order(1), order(1));
+        assert_ne!(order(1), order(2));
+    }
+
+    #[test]
+    fn test_stable_top_docs_truncates_ties() {
+        let mut collector = StableTopDocsSegmentCollector {
+            segment_ord: 0,
+            key_reader: Box::new(|doc| TantivyValue::try_from(1000 - doc as i64).unwrap()),
+            candidates: TopScores::new(2),
+        };
+
+        // Tied documents are told apart by key as they're collected, instead of piling up.
+        for doc in 0..300 {
+            collector.collect(doc, 1.0);
+            assert!(collector.candidates.docs.len() < 128);
+        }
+
+        let docs: Vec<_> = collector
+            .harvest()
+            .into_iter()
+            .map(|(_, address)| address.doc_id)
+            .collect();
+        assert_eq!(docs, vec![299, 298]);
+    }
+}
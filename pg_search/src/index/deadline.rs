@@ -0,0 +1,379 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::pg_sys;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use tantivy::query::{EmptyScorer, Explanation, Scorer, Weight};
+use tantivy::{DocId, DocSet, Score, SegmentReader, TERMINATED};
+use tantivy_fst::Automaton;
+
+/// How many documents are matched, or term dictionary nodes visited, between two checks of
+/// the deadline, so that reading the clock doesn't slow down the search.
+const DEADLINE_CHECK_INTERVAL: u32 = 4096;
+
+/// The deadline of the search whose weights are being created, see `Deadline::scope`.
+static CURRENT_DEADLINE: Mutex<Option<Deadline>> = Mutex::new(None);
+
+/// The time after which a search gives up, shared by the threads searching its segments.
+/// A search without a timeout still stops when its query is canceled.
+#[derive(Clone, Debug)]
+pub struct Deadline {
+    pub timeout: Option<Duration>,
+    at: Option<Instant>,
+    expired: Arc<AtomicBool>,
+}
+
+impl Deadline {
+    pub fn new(timeout: Option<Duration>) -> Self {
+        Self {
+            timeout,
+            at: timeout.map(|timeout| Instant::now() + timeout),
+            expired: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Whether the deadline has passed, or the query was canceled. Once it has, every thread
+    /// sees it without reading the clock again.
+    pub fn expired(&self) -> bool {
+        if self.expired.load(Ordering::Relaxed) {
+            return true;
+        }
+        if interrupt_pending() || self.at.is_some_and(|at| Instant::now() >= at) {
+            self.expired.store(true, Ordering::Relaxed);
+            return true;
+        }
+        false
+    }
+
+    /// Make this the deadline returned by `Deadline::current` while `create_weight` runs,
+    /// for the queries that only expand their terms once their scorers are built.
+    pub fn scope<T>(&self, create_weight: impl FnOnce() -> T) -> T {
+        let previous = CURRENT_DEADLINE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .replace(self.clone());
+        // Restored even if creating the weights raises an error.
+        let _restore = DeadlineScope { previous };
+        create_weight()
+    }
+
+    /// The deadline of the search whose weights are being created, or one without a timeout
+    /// outside of a search.
+    pub fn current() -> Self {
+        CURRENT_DEADLINE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+            .unwrap_or_else(|| Self::new(None))
+    }
+}
+
+/// Restores the deadline that `Deadline::scope` replaced when dropped.
+struct DeadlineScope {
+    previous: Option<Deadline>,
+}
+
+impl Drop for DeadlineScope {
+    fn drop(&mut self) {
+        *CURRENT_DEADLINE
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = self.previous.take();
+    }
+}
+
+/// Whether the query was canceled, or the connection terminated. Like `CHECK_FOR_INTERRUPTS`,
+/// but only reading the flags, so that it can be called from the threads of a search. The
+/// search then stops, and its thread raises the error with `check_for_interrupts!`.
+fn interrupt_pending() -> bool {
+    unsafe {
+        std::ptr::read_volatile(std::ptr::addr_of!(pg_sys::QueryCancelPending)) != 0
+            || std::ptr::read_volatile(std::ptr::addr_of!(pg_sys::ProcDiePending)) != 0
+    }
+}
+
+/// A weight whose scorers stop matching documents once the deadline has passed, so that
+/// a query matching a huge number of documents is cut short instead of running to the end.
+/// The search then has to check `Deadline::expired` to tell an interrupted search from one
+/// that matched nothing more.
+pub struct DeadlineWeight {
+    inner: Box<dyn Weight>,
+    deadline: Deadline,
+}
+
+impl DeadlineWeight {
+    pub fn new(inner: Box<dyn Weight>, deadline: Deadline) -> Self {
+        Self { inner, deadline }
+    }
+}
+
+impl Weight for DeadlineWeight {
+    /// Regex and fuzzy queries expand their terms while their scorers are built, which
+    /// checks the deadline on its own, see `DeadlineAutomaton`.
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        if self.deadline.expired() {
+            return Ok(Box::new(EmptyScorer));
+        }
+        Ok(Box::new(DeadlineScorer {
+            inner: self.inner.scorer(reader, boost)?,
+            deadline: self.deadline.clone(),
+            matched: 0,
+            stopped: false,
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        self.inner.explain(reader, doc)
+    }
+
+    fn count(&self, reader: &SegmentReader) -> tantivy::Result<u32> {
+        self.inner.count(reader)
+    }
+
+    /// Keeps the pruning of the inner weight, like block-max WAND, and checks the deadline
+    /// as documents are collected. Once it has passed, the threshold is raised above any
+    /// score, so that the remaining documents are skipped.
+    fn for_each_pruning(
+        &self,
+        threshold: Score,
+        reader: &SegmentReader,
+        callback: &mut dyn FnMut(DocId, Score) -> Score,
+    ) -> tantivy::Result<()> {
+        if self.deadline.expired() {
+            return Ok(());
+        }
+        let mut collected = 0u32;
+        self.inner
+            .for_each_pruning(threshold, reader, &mut |doc, score| {
+                collected += 1;
+                if collected % DEADLINE_CHECK_INTERVAL == 0 && self.deadline.expired() {
+                    return Score::MAX;
+                }
+                callback(doc, score)
+            })
+    }
+}
+
+struct DeadlineScorer {
+    inner: Box<dyn Scorer>,
+    deadline: Deadline,
+    matched: u32,
+    stopped: bool,
+}
+
+impl DeadlineScorer {
+    fn check(&mut self) -> bool {
+        self.matched += 1;
+        if self.matched % DEADLINE_CHECK_INTERVAL == 0 && self.deadline.expired() {
+            self.stopped = true;
+        }
+        self.stopped
+    }
+}
+
+impl DocSet for DeadlineScorer {
+    fn advance(&mut self) -> DocId {
+        if self.check() {
+            return TERMINATED;
+        }
+        self.inner.advance()
+    }
+
+    fn seek(&mut self, target: DocId) -> DocId {
+        if self.check() {
+            return TERMINATED;
+        }
+        self.inner.seek(target)
+    }
+
+    fn doc(&self) -> DocId {
+        if self.stopped {
+            return TERMINATED;
+        }
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for DeadlineScorer {
+    fn score(&mut self) -> Score {
+        self.inner.score()
+    }
+}
+
+/// An automaton over the terms of a term dictionary that stops matching them once the
+/// deadline has passed, so that a regex or fuzzy query expanding to a huge number of terms
+/// is cut short while its terms are looked up.
+pub struct DeadlineAutomaton<A> {
+    inner: A,
+    deadline: Deadline,
+    visited: AtomicU32,
+}
+
+impl<A> DeadlineAutomaton<A> {
+    pub fn new(inner: A, deadline: Deadline) -> Self {
+        Self {
+            inner,
+            deadline,
+            visited: AtomicU32::new(0),
+        }
+    }
+}
+
+impl<A: Automaton> Automaton for DeadlineAutomaton<A> {
+    type State = A::State;
+
+    fn start(&self) -> Self::State {
+        self.inner.start()
+    }
+
+    fn is_match(&self, state: &Self::State) -> bool {
+        self.inner.is_match(state)
+    }
+
+    /// Checked for every node of the term dictionary that the stream visits, so that once the
+    /// deadline has passed, it visits no more.
+    fn can_match(&self, state: &Self::State) -> bool {
+        let visited = self.visited.fetch_add(1, Ordering::Relaxed);
+        if visited % DEADLINE_CHECK_INTERVAL == 0 && self.deadline.expired() {
+            return false;
+        }
+        self.inner.can_match(state)
+    }
+
+    /// The stream must keep calling `can_match`.
+    fn will_always_match(&self, _state: &Self::State) -> bool {
+        false
+    }
+
+    fn accept(&self, state: &Self::State, byte: u8) -> Self::State {
+        self.inner.accept(state, byte)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Deadline, DeadlineScorer, DeadlineWeight};
+    use crate::query::expansion::{Expansion, ExpansionQuery};
+    use std::time::Duration;
+    use tantivy::query::{AllQuery, EnableScoring, Query};
+    use tantivy::schema::{Schema, INDEXED, STRING};
+    use tantivy::{doc, DocSet, Index, TERMINATED};
+
+    #[test]
+    fn test_deadline() {
+        let mut builder = Schema::builder();
+        let field = builder.add_u64_field("value", INDEXED);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer(15_000_000).unwrap();
+        for value in 0..10_000u64 {
+            writer.add_document(doc!(field => value)).unwrap();
+        }
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let segment_reader = searcher.segment_reader(0);
+
+        let count = |deadline: Deadline| {
+            let weight = AllQuery
+                .weight(EnableScoring::disabled_from_searcher(&searcher))
+                .unwrap();
+            let weight = DeadlineWeight::new(weight, deadline);
+            let mut scorer = tantivy::query::Weight::scorer(&weight, segment_reader, 1.0).unwrap();
+            let mut count = 0;
+            while scorer.doc() != TERMINATED {
+                count += 1;
+                scorer.advance();
+            }
+            count
+        };
+
+        assert_eq!(
+            count(Deadline::new(Some(Duration::from_secs(3600)))),
+            10_000
+        );
+
+        // Matching stops at the first check after the deadline.
+        let expired = Deadline::new(Some(Duration::ZERO));
+        let weight = AllQuery
+            .weight(EnableScoring::disabled_from_searcher(&searcher))
+            .unwrap();
+        let mut scorer = DeadlineScorer {
+            inner: tantivy::query::Weight::scorer(weight.as_ref(), segment_reader, 1.0).unwrap(),
+            deadline: expired.clone(),
+            matched: 0,
+            stopped: false,
+        };
+        let mut matched = 0;
+        while scorer.doc() != TERMINATED {
+            matched += 1;
+            scorer.advance();
+        }
+        assert_eq!(matched, 4096);
+
+        // A deadline that has already passed doesn't build the scorer.
+        assert!(expired.expired());
+        assert_eq!(count(expired), 0);
+    }
+
+    #[test]
+    fn test_deadline_of_term_expansion() {
+        let mut builder = Schema::builder();
+        let field = builder.add_text_field("description", STRING);
+        let index = Index::create_in_ram(builder.build());
+        let mut writer = index.writer(15_000_000).unwrap();
+        for value in 0..20_000u64 {
+            writer
+                .add_document(doc!(field => format!("term{value}")))
+                .unwrap();
+        }
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+        let segment_reader = searcher.segment_reader(0);
+
+        // The regex expands to every term, which are only looked up as its scorer is built.
+        let expansion = Expansion::Regex {
+            pattern: "term[0-9]*".into(),
+        };
+        let count = |deadline: Deadline| {
+            let query = ExpansionQuery::new(field, expansion.clone()).unwrap();
+            let weight = deadline
+                .scope(|| query.weight(EnableScoring::disabled_from_searcher(&searcher)))
+                .unwrap();
+            let mut scorer = weight.scorer(segment_reader, 1.0).unwrap();
+            let mut count = 0;
+            while scorer.doc() != TERMINATED {
+                count += 1;
+                scorer.advance();
+            }
+            count
+        };
+
+        assert_eq!(
+            count(Deadline::new(Some(Duration::from_secs(3600)))),
+            20_000
+        );
+
+        // Past the deadline, the expansion stops before it reaches any term.
+        let expired = Deadline::new(Some(Duration::ZERO));
+        assert!(expired.expired());
+        assert_eq!(count(expired), 0);
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::collector::key_reader;
+use crate::postgres::types::TantivyValue;
+use crate::schema::{value_term, SearchIndexSchema};
+use std::sync::Arc;
+use tantivy::query::{Explanation, Scorer, Weight};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::{DocId, DocSet, Score, SegmentReader, TERMINATED};
+
+/// The segments of a search, committed ones first, in the order of their shifted segment
+/// ordinals, which is used to tell the latest version of a row apart. The document with the
+/// highest address wins, which puts the uncommitted documents of the transaction ahead of
+/// committed ones.
+pub struct LatestVersions {
+    schema: SearchIndexSchema,
+    key_field: Field,
+    key_field_name: String,
+    segments: Vec<SegmentReader>,
+}
+
+impl LatestVersions {
+    pub fn new(
+        schema: SearchIndexSchema,
+        key_field_name: String,
+        segments: Vec<SegmentReader>,
+    ) -> Self {
+        Self {
+            key_field: schema.key_field().id.0,
+            schema,
+            key_field_name,
+            segments,
+        }
+    }
+
+    /// Whether no live document with the same key comes after this one. The key's postings
+    /// are only read in this segment and the following ones.
+    pub fn is_latest(
+        &self,
+        segment_index: usize,
+        doc: DocId,
+        key: &TantivyValue,
+    ) -> tantivy::Result<bool> {
+        let Some(term) = value_term(self.key_field, &key.tantivy_schema_value()) else {
+            return Ok(true);
+        };
+        for (index, segment_reader) in self.segments.iter().enumerate().skip(segment_index) {
+            let inverted_index = segment_reader.inverted_index(self.key_field)?;
+            let Some(mut postings) =
+                inverted_index.read_postings(&term, IndexRecordOption::Basic)?
+            else {
+                continue;
+            };
+            let alive_bitset = segment_reader.alive_bitset();
+            let mut other = postings.doc();
+            while other != TERMINATED {
+                let later = index > segment_index || other > doc;
+                if later && alive_bitset.map_or(true, |bitset| bitset.is_alive(other)) {
+                    return Ok(false);
+                }
+                other = postings.advance();
+            }
+        }
+        Ok(true)
+    }
+
+    fn segment_index(&self, reader: &SegmentReader) -> usize {
+        self.segments
+            .iter()
+            .position(|segment| segment.segment_id() == reader.segment_id())
+            .expect("segment should belong to the searchers of the search")
+    }
+}
+
+/// A weight that skips the documents replaced by a later version of their row, so that the
+/// collectors never see two documents with the same key, and fill their results with other
+/// rows instead. With pruning, only the documents that could make it into the top results are
+/// checked.
+pub struct LatestVersionWeight {
+    inner: Box<dyn Weight>,
+    latest: Arc<LatestVersions>,
+}
+
+impl LatestVersionWeight {
+    pub fn new(inner: Box<dyn Weight>, latest: Arc<LatestVersions>) -> Self {
+        Self { inner, latest }
+    }
+
+    fn is_latest_in(&self, reader: &SegmentReader) -> impl FnMut(DocId) -> bool + Send {
+        let latest = self.latest.clone();
+        let segment_index = latest.segment_index(reader);
+        let mut key_reader = key_reader(&latest.schema, &latest.key_field_name, reader);
+        move |doc| {
+            latest
+                .is_latest(segment_index, doc, &key_reader(doc))
+                .expect("could not read the versions of a row")
+        }
+    }
+}
+
+impl Weight for LatestVersionWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        let mut scorer = LatestVersionScorer {
+            inner: self.inner.scorer(reader, boost)?,
+            is_latest: Box::new(self.is_latest_in(reader)),
+        };
+        scorer.skip_replaced();
+        Ok(Box::new(scorer))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        self.inner.explain(reader, doc)
+    }
+
+    fn for_each_pruning(
+        &self,
+        threshold: Score,
+        reader: &SegmentReader,
+        callback: &mut dyn FnMut(DocId, Score) -> Score,
+    ) -> tantivy::Result<()> {
+        let mut is_latest = self.is_latest_in(reader);
+        let mut threshold = threshold;
+        self.inner
+            .for_each_pruning(threshold, reader, &mut |doc, score| {
+                if is_latest(doc) {
+                    threshold = callback(doc, score);
+                }
+                threshold
+            })
+    }
+}
+
+struct LatestVersionScorer {
+    inner: Box<dyn Scorer>,
+    is_latest: Box<dyn FnMut(DocId) -> bool + Send>,
+}
+
+impl LatestVersionScorer {
+    fn skip_replaced(&mut self) -> DocId {
+        let mut doc = self.inner.doc();
+        while doc != TERMINATED && !(self.is_latest)(doc) {
+            doc = self.inner.advance();
+        }
+        doc
+    }
+}
+
+impl DocSet for LatestVersionScorer {
+    fn advance(&mut self) -> DocId {
+        self.inner.advance();
+        self.skip_replaced()
+    }
+
+    fn seek(&mut self, target: DocId) -> DocId {
+        self.inner.seek(target);
+        self.skip_replaced()
+    }
+
+    fn doc(&self) -> DocId {
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for LatestVersionScorer {
+    fn score(&mut self) -> Score {
+        self.inner.score()
+    }
+}
@@ -0,0 +1,485 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::remote::block_ranges;
+use crate::SEARCH_GUCS;
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use once_cell::sync::OnceCell;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use tantivy::directory::error::{DeleteError, LockError, OpenReadError, OpenWriteError};
+use tantivy::directory::{
+    AntiCallToken, DirectoryClone, DirectoryLock, FileHandle, Lock, OwnedBytes, TerminatingWrite,
+    WatchCallback, WatchHandle, WritePtr,
+};
+use tantivy::{Directory, HasLen};
+use thiserror::Error;
+
+/// Files are encrypted in blocks of this many bytes, so that any range can be read without
+/// decrypting the whole file.
+const BLOCK_SIZE: usize = 16 * 1024;
+/// The authentication tag that follows each encrypted block.
+const TAG_SIZE: usize = 16;
+const MAGIC: &[u8; 8] = b"PDBENC01";
+/// The magic bytes, then the random prefix of the nonces of the blocks of the file.
+const HEADER_SIZE: usize = MAGIC.len() + 8;
+
+/// The key of the current process, fetched once with `paradedb.encryption_key_command`.
+static KEY: OnceCell<Arc<Aes256Gcm>> = OnceCell::new();
+
+/// The key encrypting index files, as printed by `paradedb.encryption_key_command` in hex.
+/// Indexes are encrypted if they were created while the command was set.
+pub struct EncryptionKey {}
+
+impl EncryptionKey {
+    pub fn is_configured() -> bool {
+        Self::command().is_some()
+    }
+
+    pub fn load() -> Result<Arc<Aes256Gcm>, EncryptionError> {
+        KEY.get_or_try_init(|| {
+            let command = Self::command().ok_or(EncryptionError::NotConfigured)?;
+            let output = Command::new("sh").arg("-c").arg(&command).output()?;
+            if !output.status.success() {
+                return Err(EncryptionError::CommandFailed(
+                    output.status.to_string(),
+                    String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                ));
+            }
+
+            let key = parse_key(String::from_utf8_lossy(&output.stdout).trim())?;
+            Ok(Arc::new(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key))))
+        })
+        .cloned()
+    }
+
+    fn command() -> Option<String> {
+        SEARCH_GUCS
+            .encryption_key_command
+            .get()
+            .filter(|command| !command.is_empty())
+    }
+}
+
+fn parse_key(hex: &str) -> Result<[u8; 32], EncryptionError> {
+    let mut key = [0u8; 32];
+    if hex.len() != key.len() * 2 || !hex.is_ascii() {
+        return Err(EncryptionError::InvalidKey);
+    }
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).map_err(|_| EncryptionError::InvalidKey)?;
+        *byte = u8::from_str_radix(digits, 16).map_err(|_| EncryptionError::InvalidKey)?;
+    }
+    Ok(key)
+}
+
+/// A directory that encrypts the files of another, with AES-256-GCM. Each file starts with
+/// a header holding a random nonce prefix, followed by blocks of `BLOCK_SIZE` bytes that are
+/// each encrypted with that prefix and their position. The last block is authenticated as
+/// such, so a truncated file fails to decrypt rather than reading as a shorter one.
+pub struct EncryptedDirectory {
+    inner: Box<dyn Directory>,
+    cipher: Arc<Aes256Gcm>,
+}
+
+impl Clone for EncryptedDirectory {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.box_clone(),
+            cipher: self.cipher.clone(),
+        }
+    }
+}
+
+impl std::fmt::Debug for EncryptedDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedDirectory")
+            .field("inner", &self.inner)
+            .finish()
+    }
+}
+
+impl EncryptedDirectory {
+    pub fn new(inner: Box<dyn Directory>, cipher: Arc<Aes256Gcm>) -> Self {
+        Self { inner, cipher }
+    }
+}
+
+impl Directory for EncryptedDirectory {
+    fn get_file_handle(&self, path: &Path) -> Result<Arc<dyn FileHandle>, OpenReadError> {
+        let inner = self.inner.get_file_handle(path)?;
+        let handle = EncryptedFileHandle::open(inner, self.cipher.clone()).map_err(|err| {
+            OpenReadError::IoError {
+                io_error: Arc::new(err),
+                filepath: path.to_path_buf(),
+            }
+        })?;
+        Ok(Arc::new(handle))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, OpenReadError> {
+        self.inner.exists(path)
+    }
+
+    fn open_write(&self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        let mut inner = self.inner.open_write(path)?;
+        let encryptor = BlockEncryptor::new(self.cipher.clone());
+        encryptor
+            .write_header(&mut inner)
+            .map_err(|err| OpenWriteError::wrap_io_error(err, path.to_path_buf()))?;
+        Ok(WritePtr::new(Box::new(EncryptingWriter {
+            inner,
+            encryptor,
+        })))
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        let encrypted = self.inner.atomic_read(path)?;
+        decrypt(&self.cipher, encrypted).map_err(|err| OpenReadError::IoError {
+            io_error: Arc::new(err),
+            filepath: path.to_path_buf(),
+        })
+    }
+
+    fn atomic_write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.inner.atomic_write(path, &encrypt(&self.cipher, data)?)
+    }
+
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        self.inner.acquire_lock(lock)
+    }
+
+    fn sync_directory(&self) -> io::Result<()> {
+        self.inner.sync_directory()
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        self.inner.watch(watch_callback)
+    }
+}
+
+/// Encrypt the whole contents of a file kept outside of an index directory, in the same
+/// format as the files of `EncryptedDirectory`.
+pub fn encrypt(cipher: &Arc<Aes256Gcm>, data: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encrypted = vec![];
+    let mut encryptor = BlockEncryptor::new(cipher.clone());
+    encryptor.write_header(&mut encrypted)?;
+    encryptor.write(data, &mut encrypted)?;
+    encryptor.finish(&mut encrypted)?;
+    Ok(encrypted)
+}
+
+/// Decrypt the whole contents of a file written by `encrypt`.
+pub fn decrypt(cipher: &Arc<Aes256Gcm>, encrypted: Vec<u8>) -> io::Result<Vec<u8>> {
+    let handle = EncryptedFileHandle::open(Arc::new(OwnedBytes::new(encrypted)), cipher.clone())?;
+    Ok(handle.read_bytes(0..handle.len())?.to_vec())
+}
+
+/// Whether the contents of a file were written by `encrypt`.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(MAGIC)
+}
+
+fn nonce(prefix: &[u8; 8], block: usize) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(prefix);
+    nonce[8..].copy_from_slice(&(block as u32).to_be_bytes());
+    nonce
+}
+
+/// Encrypts a file as it's written. A full block is only encrypted once more data follows,
+/// as the last block of the file is encrypted differently.
+struct BlockEncryptor {
+    cipher: Arc<Aes256Gcm>,
+    prefix: [u8; 8],
+    block: usize,
+    buffer: Vec<u8>,
+}
+
+impl BlockEncryptor {
+    fn new(cipher: Arc<Aes256Gcm>) -> Self {
+        let mut prefix = [0u8; 8];
+        OsRng.fill_bytes(&mut prefix);
+        Self {
+            cipher,
+            prefix,
+            block: 0,
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+        }
+    }
+
+    fn write_header<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(MAGIC)?;
+        out.write_all(&self.prefix)
+    }
+
+    fn write<W: Write>(&mut self, mut data: &[u8], out: &mut W) -> io::Result<()> {
+        while !data.is_empty() {
+            if self.buffer.len() == BLOCK_SIZE {
+                self.encrypt_block(false, out)?;
+            }
+            let len = data.len().min(BLOCK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(())
+    }
+
+    fn finish<W: Write>(&mut self, out: &mut W) -> io::Result<()> {
+        self.encrypt_block(true, out)
+    }
+
+    fn encrypt_block<W: Write>(&mut self, last: bool, out: &mut W) -> io::Result<()> {
+        let nonce = nonce(&self.prefix, self.block);
+        let encrypted = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &self.buffer,
+                    aad: &[last as u8],
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "could not encrypt block"))?;
+        out.write_all(&encrypted)?;
+        self.buffer.clear();
+        self.block += 1;
+        Ok(())
+    }
+}
+
+struct EncryptingWriter {
+    inner: WritePtr,
+    encryptor: BlockEncryptor,
+}
+
+impl Write for EncryptingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.encryptor.write(buf, &mut self.inner)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // A partial block can't be encrypted until it's complete, or the file is.
+        self.inner.flush()
+    }
+}
+
+impl TerminatingWrite for EncryptingWriter {
+    fn terminate_ref(&mut self, token: AntiCallToken) -> io::Result<()> {
+        self.encryptor.finish(&mut self.inner)?;
+        self.inner.terminate_ref(token)
+    }
+}
+
+/// Decrypts the blocks of a file as they're read. The last block read is kept, as Tantivy
+/// reads files in many small, mostly sequential ranges.
+struct EncryptedFileHandle {
+    inner: Arc<dyn FileHandle>,
+    cipher: Arc<Aes256Gcm>,
+    prefix: [u8; 8],
+    num_blocks: usize,
+    len: usize,
+    last_block: Mutex<Option<(usize, Arc<Vec<u8>>)>>,
+}
+
+impl std::fmt::Debug for EncryptedFileHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("EncryptedFileHandle")
+            .field("inner", &self.inner)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl EncryptedFileHandle {
+    fn open(inner: Arc<dyn FileHandle>, cipher: Arc<Aes256Gcm>) -> io::Result<Self> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        if inner.len() < HEADER_SIZE + TAG_SIZE {
+            return Err(invalid("encrypted file is truncated"));
+        }
+        let header = inner.read_bytes(0..HEADER_SIZE)?;
+        if &header.as_slice()[..MAGIC.len()] != MAGIC {
+            return Err(invalid("file is not encrypted"));
+        }
+        let mut prefix = [0u8; 8];
+        prefix.copy_from_slice(&header.as_slice()[MAGIC.len()..]);
+
+        let encrypted_len = inner.len() - HEADER_SIZE;
+        let num_blocks = encrypted_len.div_ceil(BLOCK_SIZE + TAG_SIZE);
+        Ok(Self {
+            len: encrypted_len - num_blocks * TAG_SIZE,
+            inner,
+            cipher,
+            prefix,
+            num_blocks,
+            last_block: Mutex::new(None),
+        })
+    }
+
+    fn block(&self, block: usize) -> io::Result<Arc<Vec<u8>>> {
+        let mut last_block = self
+            .last_block
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "block cache lock poisoned"))?;
+        if let Some((cached, data)) = last_block.as_ref() {
+            if *cached == block {
+                return Ok(data.clone());
+            }
+        }
+
+        let start = HEADER_SIZE + block * (BLOCK_SIZE + TAG_SIZE);
+        let end = (start + BLOCK_SIZE + TAG_SIZE).min(self.inner.len());
+        let encrypted = self.inner.read_bytes(start..end)?;
+        let nonce = nonce(&self.prefix, block);
+        let data = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: encrypted.as_slice(),
+                    aad: &[(block + 1 == self.num_blocks) as u8],
+                },
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "could not decrypt block, the file is corrupted or the encryption key is wrong",
+                )
+            })?;
+
+        let data = Arc::new(data);
+        *last_block = Some((block, data.clone()));
+        Ok(data)
+    }
+}
+
+impl HasLen for EncryptedFileHandle {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl FileHandle for EncryptedFileHandle {
+    fn read_bytes(&self, range: Range<usize>) -> io::Result<OwnedBytes> {
+        if range.end > self.len {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "read past the end of an encrypted file",
+            ));
+        }
+        let mut buffer = Vec::with_capacity(range.len());
+        for (block, block_range) in block_ranges(range, BLOCK_SIZE) {
+            buffer.extend_from_slice(&self.block(block)?[block_range]);
+        }
+        Ok(OwnedBytes::new(buffer))
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum EncryptionError {
+    #[error("paradedb.encryption_key_command is not set")]
+    NotConfigured,
+
+    #[error("paradedb.encryption_key_command failed with {0}: {1}")]
+    CommandFailed(String, String),
+
+    #[error("paradedb.encryption_key_command must print a 256-bit key as 64 hex digits")]
+    InvalidKey,
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_encrypted, parse_key, EncryptedDirectory, BLOCK_SIZE};
+    use aes_gcm::aead::KeyInit;
+    use aes_gcm::{Aes256Gcm, Key};
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tantivy::directory::{RamDirectory, TerminatingWrite};
+    use tantivy::Directory;
+
+    fn cipher(byte: u8) -> Arc<Aes256Gcm> {
+        Arc::new(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&[byte; 32])))
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert_eq!(parse_key(&"ab".repeat(32)).unwrap(), [0xab; 32]);
+        assert!(parse_key("abcd").is_err());
+        assert!(parse_key(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_encrypted_roundtrip() {
+        let ram = RamDirectory::create();
+        let directory = EncryptedDirectory::new(Box::new(ram.clone()), cipher(1));
+        let path = Path::new("segment.store");
+
+        // Spans several blocks, and ends exactly on a block boundary.
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3).map(|i| (i % 251) as u8).collect();
+        let mut writer = directory.open_write(path).unwrap();
+        writer.write_all(&data).unwrap();
+        writer.terminate().unwrap();
+
+        let handle = directory.get_file_handle(path).unwrap();
+        assert_eq!(handle.len(), data.len());
+        let range = BLOCK_SIZE - 10..BLOCK_SIZE * 2 + 10;
+        assert_eq!(
+            handle.read_bytes(range.clone()).unwrap().as_slice(),
+            &data[range]
+        );
+        assert!(!ram
+            .atomic_read(path)
+            .unwrap()
+            .windows(64)
+            .any(|window| window == &data[..64]));
+
+        directory
+            .atomic_write(Path::new("meta.json"), b"{}")
+            .unwrap();
+        assert_eq!(
+            directory.atomic_read(Path::new("meta.json")).unwrap(),
+            b"{}"
+        );
+        assert!(is_encrypted(
+            &ram.atomic_read(Path::new("meta.json")).unwrap()
+        ));
+        assert!(!is_encrypted(b"{}"));
+
+        // Another key, or a truncated file, fails to decrypt.
+        let wrong_key = EncryptedDirectory::new(Box::new(ram.clone()), cipher(2));
+        assert!(wrong_key.atomic_read(Path::new("meta.json")).is_err());
+        let encrypted = ram.atomic_read(path).unwrap();
+        ram.atomic_write(path, &encrypted[..encrypted.len() - BLOCK_SIZE - 16])
+            .unwrap();
+        let truncated = directory.get_file_handle(path).unwrap();
+        assert!(truncated.read_bytes(0..truncated.len()).is_err());
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::dedup::{LatestVersionWeight, LatestVersions};
+use super::fast_fields::FastFieldReader;
+use super::SearchIndex;
+use crate::schema::{decode_vector, SearchField, SearchFieldType};
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Float32Builder, Float64Builder, Int64Builder, ListBuilder,
+    StringBuilder, TimestampMicrosecondBuilder, UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use arrow::error::ArrowError;
+use arrow::ipc::writer::FileWriter;
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::errors::ParquetError;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tantivy::query::{EnableScoring, Query, Weight};
+use tantivy::schema::OwnedValue;
+use tantivy::store::StoreReader;
+use tantivy::{DocSet, TantivyDocument, TERMINATED};
+use thiserror::Error;
+
+// Rows are written in record batches of this many rows, which bounds the memory of an export
+// regardless of the size of the index.
+const BATCH_SIZE: usize = 8192;
+// Documents are read in order, so a few cached blocks of the store avoid decompressing a
+// block again for each of its documents.
+const STORE_CACHE_BLOCKS: usize = 4;
+
+static DATE_TIMEZONE: &str = "UTC";
+
+/// The file format of an export.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Parquet,
+    /// The Arrow IPC file format, also known as Feather.
+    Arrow,
+}
+
+impl FromStr for ExportFormat {
+    type Err = ExportError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "parquet" => Ok(Self::Parquet),
+            "arrow" | "feather" => Ok(Self::Arrow),
+            _ => Err(ExportError::UnknownFormat(format.to_string())),
+        }
+    }
+}
+
+/// Where the values of a column are read from. Stored values are preferred, as they hold
+/// JSON objects and full text, fast fields are used for the fields that aren't stored.
+enum ColumnSource {
+    Stored,
+    Fast,
+}
+
+/// A column of an export, one per field of the index that is stored or fast.
+struct ExportColumn {
+    field: SearchField,
+    source: ColumnSource,
+}
+
+/// Builds the values of a column for a record batch.
+enum ColumnBuilder {
+    Str(StringBuilder),
+    I64(Int64Builder),
+    U64(UInt64Builder),
+    F64(Float64Builder),
+    Bool(BooleanBuilder),
+    Date(TimestampMicrosecondBuilder),
+    Vector(ListBuilder<Float32Builder>),
+}
+
+impl ColumnBuilder {
+    fn new(type_: &SearchFieldType) -> Self {
+        match type_ {
+            // Sparse vectors are neither stored nor fast, so they're never exported.
+            SearchFieldType::Text | SearchFieldType::Json | SearchFieldType::Sparse => {
+                Self::Str(StringBuilder::new())
+            }
+            SearchFieldType::I64 => Self::I64(Int64Builder::new()),
+            SearchFieldType::U64 => Self::U64(UInt64Builder::new()),
+            SearchFieldType::F64 => Self::F64(Float64Builder::new()),
+            SearchFieldType::Bool => Self::Bool(BooleanBuilder::new()),
+            SearchFieldType::Date => {
+                Self::Date(TimestampMicrosecondBuilder::new().with_timezone(DATE_TIMEZONE))
+            }
+            SearchFieldType::Vector => Self::Vector(ListBuilder::new(Float32Builder::new())),
+        }
+    }
+
+    fn data_type(type_: &SearchFieldType) -> DataType {
+        match type_ {
+            SearchFieldType::Text | SearchFieldType::Json | SearchFieldType::Sparse => {
+                DataType::Utf8
+            }
+            SearchFieldType::I64 => DataType::Int64,
+            SearchFieldType::U64 => DataType::UInt64,
+            SearchFieldType::F64 => DataType::Float64,
+            SearchFieldType::Bool => DataType::Boolean,
+            SearchFieldType::Date => {
+                DataType::Timestamp(TimeUnit::Microsecond, Some(DATE_TIMEZONE.into()))
+            }
+            SearchFieldType::Vector => {
+                DataType::List(Arc::new(Field::new("item", DataType::Float32, true)))
+            }
+        }
+    }
+
+    /// Append a value, or a null if the document has none or it has another type than the
+    /// column. JSON values are written as JSON text, and vectors as lists of floats.
+    fn append(&mut self, value: Option<&OwnedValue>) {
+        match (self, value) {
+            (Self::Str(builder), Some(OwnedValue::Str(value))) => builder.append_value(value),
+            (Self::Str(builder), Some(value)) => {
+                builder.append_option(serde_json::to_string(value).ok())
+            }
+            (Self::I64(builder), Some(OwnedValue::I64(value))) => builder.append_value(*value),
+            (Self::U64(builder), Some(OwnedValue::U64(value))) => builder.append_value(*value),
+            (Self::F64(builder), Some(OwnedValue::F64(value))) => builder.append_value(*value),
+            (Self::Bool(builder), Some(OwnedValue::Bool(value))) => builder.append_value(*value),
+            (Self::Date(builder), Some(OwnedValue::Date(value))) => {
+                builder.append_value(value.into_timestamp_micros())
+            }
+            (Self::Vector(builder), Some(OwnedValue::Bytes(bytes))) => {
+                builder.values().append_slice(&decode_vector(bytes));
+                builder.append(true)
+            }
+            (Self::Str(builder), None) => builder.append_null(),
+            (Self::I64(builder), _) => builder.append_null(),
+            (Self::U64(builder), _) => builder.append_null(),
+            (Self::F64(builder), _) => builder.append_null(),
+            (Self::Bool(builder), _) => builder.append_null(),
+            (Self::Date(builder), _) => builder.append_null(),
+            (Self::Vector(builder), _) => builder.append_null(),
+        }
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Self::Str(builder) => Arc::new(builder.finish()),
+            Self::I64(builder) => Arc::new(builder.finish()),
+            Self::U64(builder) => Arc::new(builder.finish()),
+            Self::F64(builder) => Arc::new(builder.finish()),
+            Self::Bool(builder) => Arc::new(builder.finish()),
+            Self::Date(builder) => Arc::new(builder.finish()),
+            Self::Vector(builder) => Arc::new(builder.finish()),
+        }
+    }
+}
+
+/// Writes the record batches of an export to its file.
+enum ExportWriter {
+    Parquet(ArrowWriter<File>),
+    Arrow(FileWriter<File>),
+}
+
+impl ExportWriter {
+    fn new(format: ExportFormat, file: File, schema: SchemaRef) -> Result<Self, ExportError> {
+        Ok(match format {
+            ExportFormat::Parquet => Self::Parquet(ArrowWriter::try_new(file, schema, None)?),
+            ExportFormat::Arrow => Self::Arrow(FileWriter::try_new(file, &schema)?),
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<(), ExportError> {
+        match self {
+            Self::Parquet(writer) => writer.write(batch)?,
+            Self::Arrow(writer) => writer.write(batch)?,
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), ExportError> {
+        match self {
+            Self::Parquet(writer) => {
+                writer.close()?;
+            }
+            Self::Arrow(mut writer) => writer.finish()?,
+        }
+        Ok(())
+    }
+}
+
+/// An export of the documents of an index to a file, one row per document and one column per
+/// field that is stored or fast, so that they can be read without going through Postgres.
+pub struct IndexExport {}
+
+impl IndexExport {
+    /// Write the last committed version of each row matching `query` to `path`, which must
+    /// not exist. Returns the number of rows written. If the export fails, the partial file
+    /// is removed.
+    pub fn export(
+        search_index: &SearchIndex,
+        query: &dyn Query,
+        path: &Path,
+        format: ExportFormat,
+    ) -> Result<u64, ExportError> {
+        let file = match File::options().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(ExportError::Exists(path.to_path_buf()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        Self::write(search_index, query, file, format).map_err(|err| {
+            let _ = fs::remove_file(path);
+            err
+        })
+    }
+
+    /// The columns of an export. The ctid field is left out, as it's only meaningful to the
+    /// table, and so are the fields that are neither stored nor fast. JSON fields are only
+    /// exported if they're stored.
+    fn columns(search_index: &SearchIndex) -> Vec<ExportColumn> {
+        let schema = &search_index.schema;
+        let ctid = schema.ctid_field().id;
+        schema
+            .fields
+            .iter()
+            .filter(|field| field.id != ctid)
+            .filter_map(|field| {
+                let entry = schema.schema.get_field_entry(field.id.0);
+                let source = if entry.is_stored() {
+                    ColumnSource::Stored
+                } else if entry.is_fast() && field.type_ != SearchFieldType::Json {
+                    ColumnSource::Fast
+                } else {
+                    return None;
+                };
+                Some(ExportColumn {
+                    field: field.clone(),
+                    source,
+                })
+            })
+            .collect()
+    }
+
+    fn write(
+        search_index: &SearchIndex,
+        query: &dyn Query,
+        file: File,
+        format: ExportFormat,
+    ) -> Result<u64, ExportError> {
+        let columns = Self::columns(search_index);
+        let schema: SchemaRef = Arc::new(Schema::new(
+            columns
+                .iter()
+                .map(|column| {
+                    Field::new(
+                        column.field.name.0.clone(),
+                        ColumnBuilder::data_type(&column.field.type_),
+                        true,
+                    )
+                })
+                .collect::<Vec<_>>(),
+        ));
+        let mut writer = ExportWriter::new(format, file, schema.clone())?;
+        let mut builders: Vec<ColumnBuilder> = columns
+            .iter()
+            .map(|column| ColumnBuilder::new(&column.field.type_))
+            .collect();
+        let mut flush = |builders: &mut Vec<ColumnBuilder>| -> Result<(), ExportError> {
+            let arrays = builders.iter_mut().map(ColumnBuilder::finish).collect();
+            writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)
+        };
+
+        // Rows updated since the last merge have several documents, only the latest is kept.
+        let searcher = search_index.searcher();
+        let latest = Arc::new(LatestVersions::new(
+            search_index.schema.clone(),
+            search_index.schema.key_field().name.0,
+            searcher.segment_readers().to_vec(),
+        ));
+        let weight = LatestVersionWeight::new(
+            query.weight(EnableScoring::disabled_from_searcher(&searcher))?,
+            latest,
+        );
+        let reads_store = columns
+            .iter()
+            .any(|column| matches!(column.source, ColumnSource::Stored));
+
+        let mut num_rows = 0;
+        let mut batch_rows = 0;
+        for segment_reader in searcher.segment_readers() {
+            let store: Option<StoreReader> = if reads_store {
+                Some(segment_reader.get_store_reader(STORE_CACHE_BLOCKS)?)
+            } else {
+                None
+            };
+            let fast_fields = columns
+                .iter()
+                .map(|column| match column.source {
+                    ColumnSource::Fast => {
+                        FastFieldReader::open(segment_reader, &column.field.name.0).map(Some)
+                    }
+                    ColumnSource::Stored => Ok(None),
+                })
+                .collect::<tantivy::Result<Vec<_>>>()?;
+            let alive_bitset = segment_reader.alive_bitset();
+
+            let mut scorer = weight.scorer(segment_reader, 1.0)?;
+            let mut doc = scorer.doc();
+            while doc != TERMINATED {
+                if alive_bitset.map_or(true, |bitset| bitset.is_alive(doc)) {
+                    let document: Option<TantivyDocument> =
+                        store.as_ref().map(|store| store.get(doc)).transpose()?;
+                    for ((column, fast_field), builder) in
+                        columns.iter().zip(&fast_fields).zip(builders.iter_mut())
+                    {
+                        match (fast_field, &document) {
+                            (Some(fast_field), _) => {
+                                builder.append(fast_field.owned_value(doc).as_ref())
+                            }
+                            (None, Some(document)) => {
+                                builder.append(document.get_first(column.field.id.0))
+                            }
+                            (None, None) => builder.append(None),
+                        }
+                    }
+                    num_rows += 1;
+                    batch_rows += 1;
+                    if batch_rows == BATCH_SIZE {
+                        flush(&mut builders)?;
+                        batch_rows = 0;
+                    }
+                }
+                doc = scorer.advance();
+            }
+        }
+        if batch_rows > 0 {
+            flush(&mut builders)?;
+        }
+        writer.finish()?;
+        Ok(num_rows)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ExportError {
+    #[error("unknown export format '{0}', expected 'parquet' or 'arrow'")]
+    UnknownFormat(String),
+
+    #[error("'{0}' already exists")]
+    Exists(PathBuf),
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+
+    #[error(transparent)]
+    TantivyError(#[from] tantivy::TantivyError),
+
+    #[error(transparent)]
+    ArrowError(#[from] ArrowError),
+
+    #[error(transparent)]
+    ParquetError(#[from] ParquetError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ExportFormat, IndexExport};
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::schema::SearchDocument;
+    use arrow::array::{Array, Int64Array, StringArray};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+    use rstest::*;
+    use std::fs::File;
+    use std::path::Path;
+    use tantivy::query::AllQuery;
+
+    fn document(index: &SearchIndex, id: i64, description: &str, rating: i64) -> SearchDocument {
+        let field = |name: &str| index.schema.get_search_field(name).unwrap().id;
+        let mut document = index.schema.new_document();
+        document.insert(index.schema.key_field().id, id.into());
+        document.insert(field("description"), description.into());
+        document.insert(field("rating"), rating.into());
+        document
+    }
+
+    fn read_rows(path: &Path) -> Vec<(i64, String, Option<i64>)> {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(File::open(path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut rows = vec![];
+        for batch in reader {
+            let batch = batch.unwrap();
+            let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+            let ids = column("id");
+            let ids = ids.as_any().downcast_ref::<Int64Array>().unwrap();
+            let descriptions = column("description");
+            let descriptions = descriptions.as_any().downcast_ref::<StringArray>().unwrap();
+            let ratings = column("rating");
+            let ratings = ratings.as_any().downcast_ref::<Int64Array>().unwrap();
+            for row in 0..batch.num_rows() {
+                rows.push((
+                    ids.value(row),
+                    descriptions.value(row).to_string(),
+                    (!ratings.is_null(row)).then(|| ratings.value(row)),
+                ));
+            }
+        }
+        rows.sort();
+        rows
+    }
+
+    #[rstest]
+    fn test_export_parquet(default_index: MockSearchIndex) {
+        let index = default_index.index;
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        for (id, description, rating) in [
+            (1, "Ergonomic metal keyboard", 4),
+            (2, "Plastic Keyboard", 4),
+            (3, "Sleek running shoes", 5),
+            // A later version of the first row, only this one is exported.
+            (1, "Ergonomic wooden keyboard", 3),
+        ] {
+            writer
+                .add_document(document(index, id, description, rating).into())
+                .unwrap();
+        }
+        writer.commit().unwrap();
+        index.reader.reload().unwrap();
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("all.parquet");
+        let num_rows = IndexExport::export(index, &AllQuery, &path, ExportFormat::Parquet).unwrap();
+        assert_eq!(num_rows, 3);
+        assert_eq!(
+            read_rows(&path),
+            vec![
+                (1, "Ergonomic wooden keyboard".into(), Some(3)),
+                (2, "Plastic Keyboard".into(), Some(4)),
+                (3, "Sleek running shoes".into(), Some(5)),
+            ]
+        );
+        // An existing file is never overwritten.
+        assert!(IndexExport::export(index, &AllQuery, &path, ExportFormat::Parquet).is_err());
+
+        let query = index
+            .query_parser()
+            .parse_query("description:keyboard")
+            .unwrap();
+        let path = tempdir.path().join("keyboards.parquet");
+        let num_rows = IndexExport::export(index, &*query, &path, ExportFormat::Parquet).unwrap();
+        assert_eq!(num_rows, 2);
+        let ids: Vec<i64> = read_rows(&path).into_iter().map(|row| row.0).collect();
+        assert_eq!(ids, vec![1, 2]);
+
+        assert_eq!(
+            "Feather".parse::<ExportFormat>().unwrap(),
+            ExportFormat::Arrow
+        );
+        assert!("csv".parse::<ExportFormat>().is_err());
+    }
+}
@@ -0,0 +1,200 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::schema::decode_vector;
+use serde_json::Value;
+use tantivy::collector::{Collector, SegmentCollector};
+use tantivy::columnar::{BytesColumn, Column, DynamicColumn, StrColumn};
+use tantivy::schema::OwnedValue;
+use tantivy::{DocId, Score, SegmentOrdinal, SegmentReader, TantivyError};
+
+/// The values of a fast field for each matching document, in the order of the documents.
+pub type FastFieldColumn = Vec<Value>;
+
+/// Reads the first value of a fast field for the documents of a single segment.
+pub(crate) enum FastFieldReader {
+    Bool(Column<bool>),
+    I64(Column<i64>),
+    U64(Column<u64>),
+    F64(Column<f64>),
+    Date(Column<tantivy::DateTime>),
+    Str(StrColumn),
+    /// The bytes of a vector, see `encode_vector`.
+    Bytes(BytesColumn),
+    /// The segment has no values for the field.
+    Empty,
+}
+
+impl FastFieldReader {
+    pub(crate) fn open(segment_reader: &SegmentReader, field_name: &str) -> tantivy::Result<Self> {
+        let Some(handle) = segment_reader
+            .fast_fields()
+            .dynamic_column_handles(field_name)?
+            .into_iter()
+            .next()
+        else {
+            return Ok(Self::Empty);
+        };
+
+        Ok(match handle.open()? {
+            DynamicColumn::Bool(column) => Self::Bool(column),
+            DynamicColumn::I64(column) => Self::I64(column),
+            DynamicColumn::U64(column) => Self::U64(column),
+            DynamicColumn::F64(column) => Self::F64(column),
+            DynamicColumn::DateTime(column) => Self::Date(column),
+            DynamicColumn::Str(column) => Self::Str(column),
+            DynamicColumn::Bytes(column) => Self::Bytes(column),
+            _ => {
+                return Err(TantivyError::SchemaError(format!(
+                    "fast field '{field_name}' has an unsupported type"
+                )))
+            }
+        })
+    }
+
+    fn value(&self, doc: DocId) -> Value {
+        match self {
+            Self::Bool(column) => column.first(doc).into(),
+            Self::I64(column) => column.first(doc).into(),
+            Self::U64(column) => column.first(doc).into(),
+            Self::F64(column) => column.first(doc).into(),
+            Self::Date(column) => column
+                .first(doc)
+                .and_then(|date| {
+                    chrono::DateTime::from_timestamp_micros(date.into_timestamp_micros())
+                })
+                .map(|date| date.to_rfc3339())
+                .into(),
+            Self::Str(column) => {
+                let Some(ord) = column.term_ords(doc).next() else {
+                    return Value::Null;
+                };
+                let mut value = String::new();
+                match column.ord_to_str(ord, &mut value) {
+                    Ok(true) => value.into(),
+                    _ => Value::Null,
+                }
+            }
+            Self::Bytes(column) => {
+                let Some(ord) = column.term_ords(doc).next() else {
+                    return Value::Null;
+                };
+                let mut bytes = vec![];
+                match column.ord_to_bytes(ord, &mut bytes) {
+                    Ok(true) => decode_vector(&bytes).into(),
+                    _ => Value::Null,
+                }
+            }
+            Self::Empty => Value::Null,
+        }
+    }
+
+    /// The first value of the field for a document, as it would be read from the store.
+    pub(crate) fn owned_value(&self, doc: DocId) -> Option<OwnedValue> {
+        match self {
+            Self::Bool(column) => column.first(doc).map(OwnedValue::Bool),
+            Self::I64(column) => column.first(doc).map(OwnedValue::I64),
+            Self::U64(column) => column.first(doc).map(OwnedValue::U64),
+            Self::F64(column) => column.first(doc).map(OwnedValue::F64),
+            Self::Date(column) => column.first(doc).map(OwnedValue::Date),
+            Self::Str(column) => {
+                let ord = column.term_ords(doc).next()?;
+                let mut value = String::new();
+                match column.ord_to_str(ord, &mut value) {
+                    Ok(true) => Some(OwnedValue::Str(value)),
+                    _ => None,
+                }
+            }
+            Self::Bytes(column) => {
+                let ord = column.term_ords(doc).next()?;
+                let mut bytes = vec![];
+                match column.ord_to_bytes(ord, &mut bytes) {
+                    Ok(true) => Some(OwnedValue::Bytes(bytes)),
+                    _ => None,
+                }
+            }
+            Self::Empty => None,
+        }
+    }
+}
+
+/// Collects the fast field values of the matching documents column by column, reading them
+/// while the query is executed instead of looking up every document afterwards.
+pub struct FastFieldsCollector {
+    fields: Vec<String>,
+}
+
+impl FastFieldsCollector {
+    pub fn new(fields: Vec<String>) -> Self {
+        Self { fields }
+    }
+}
+
+impl Collector for FastFieldsCollector {
+    /// One column per field.
+    type Fruit = Vec<FastFieldColumn>;
+    type Child = FastFieldsSegmentCollector;
+
+    fn for_segment(
+        &self,
+        _segment_ord: SegmentOrdinal,
+        segment_reader: &SegmentReader,
+    ) -> tantivy::Result<Self::Child> {
+        let readers = self
+            .fields
+            .iter()
+            .map(|field_name| FastFieldReader::open(segment_reader, field_name))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        Ok(FastFieldsSegmentCollector {
+            columns: vec![vec![]; readers.len()],
+            readers,
+        })
+    }
+
+    fn requires_scoring(&self) -> bool {
+        false
+    }
+
+    fn merge_fruits(&self, segment_fruits: Vec<Self::Fruit>) -> tantivy::Result<Self::Fruit> {
+        let mut columns = vec![vec![]; self.fields.len()];
+        for segment_columns in segment_fruits {
+            for (column, segment_column) in columns.iter_mut().zip(segment_columns) {
+                column.extend(segment_column);
+            }
+        }
+        Ok(columns)
+    }
+}
+
+pub struct FastFieldsSegmentCollector {
+    readers: Vec<FastFieldReader>,
+    columns: Vec<FastFieldColumn>,
+}
+
+impl SegmentCollector for FastFieldsSegmentCollector {
+    type Fruit = Vec<FastFieldColumn>;
+
+    fn collect(&mut self, doc: DocId, _score: Score) {
+        for (reader, column) in self.readers.iter().zip(self.columns.iter_mut()) {
+            column.push(reader.value(doc));
+        }
+    }
+
+    fn harvest(self) -> Self::Fruit {
+        self.columns
+    }
+}
@@ -0,0 +1,255 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+// Faults are only injected and fired through functions built with the `fault_injection` feature.
+#![cfg_attr(not(feature = "fault_injection"), allow(dead_code))]
+
+use crate::writer::{FaultsFilePath, SearchDirectoryError, WriterDirectory};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// A place in the write path of an index where a fault can be injected, to test that the
+/// index and its table agree again after the process fails there. Points do nothing in
+/// builds without the `fault_injection` feature.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultPoint {
+    /// In a committing backend, before its documents are sent to the writer.
+    BackendBeforeSend,
+    /// In a committing backend, after its documents are sent to the writer, but before the
+    /// writer is asked to commit them.
+    BackendBeforeCommit,
+    /// In the writer, before a commit of the index is prepared.
+    WriterBeforeCommit,
+    /// In the writer, after a commit of the index is on disk, but before it's reported.
+    WriterAfterCommit,
+    /// In a backend building the index, after the table is scanned.
+    BuildAfterScan,
+}
+
+impl FaultPoint {
+    pub const ALL: [FaultPoint; 5] = [
+        Self::BackendBeforeSend,
+        Self::BackendBeforeCommit,
+        Self::WriterBeforeCommit,
+        Self::WriterAfterCommit,
+        Self::BuildAfterScan,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::BackendBeforeSend => "backend_before_send",
+            Self::BackendBeforeCommit => "backend_before_commit",
+            Self::WriterBeforeCommit => "writer_before_commit",
+            Self::WriterAfterCommit => "writer_after_commit",
+            Self::BuildAfterScan => "build_after_scan",
+        }
+    }
+
+    /// Fire the fault injected at this point for the index, if any. A fault fires once: it's
+    /// removed before it acts, so that the process doesn't fail again when it restarts.
+    #[cfg(feature = "fault_injection")]
+    pub fn hit(self, directory: &WriterDirectory) {
+        let action = match Faults::take(directory, self) {
+            Ok(Some(action)) => action,
+            Ok(None) => return,
+            Err(err) => panic!("error reading faults of {directory:?}: {err}"),
+        };
+
+        match action {
+            FaultAction::Error => panic!("injected fault at {}", self.name()),
+            FaultAction::Crash => {
+                pgrx::warning!("injected crash at {}", self.name());
+                std::process::abort()
+            }
+        }
+    }
+
+    #[cfg(not(feature = "fault_injection"))]
+    #[inline(always)]
+    pub fn hit(self, _directory: &WriterDirectory) {}
+}
+
+impl FromStr for FaultPoint {
+    type Err = FaultError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|point| point.name() == name)
+            .ok_or_else(|| FaultError::UnknownPoint(name.to_string()))
+    }
+}
+
+/// What a process does when it hits a fault point.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultAction {
+    /// Raise an error, which aborts the transaction of a backend, or restarts the writer.
+    Error,
+    /// Abort the process, which makes Postgres restart every backend and recover.
+    Crash,
+}
+
+impl FaultAction {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Crash => "crash",
+        }
+    }
+}
+
+impl FromStr for FaultAction {
+    type Err = FaultError;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "error" => Ok(Self::Error),
+            "crash" => Ok(Self::Crash),
+            _ => Err(FaultError::UnknownAction(name.to_string())),
+        }
+    }
+}
+
+/// The faults injected into an index. They're kept in a file next to the index rather than
+/// in memory, so that they reach the writer and every backend alike.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Faults(pub BTreeMap<FaultPoint, FaultAction>);
+
+impl Faults {
+    pub fn load(directory: &WriterDirectory) -> Result<Self, SearchDirectoryError> {
+        let FaultsFilePath(path) = directory.faults_file_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let serialized = fs::read_to_string(&path)
+            .map_err(|err| SearchDirectoryError::IndexFileRead(directory.clone(), path, err))?;
+        serde_json::from_str(&serialized)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let FaultsFilePath(path) = directory.faults_file_path()?;
+        if self.0.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                    Err(SearchDirectoryError::RemoveFile(path, err))
+                }
+                _ => Ok(()),
+            };
+        }
+
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    pub fn inject(
+        directory: &WriterDirectory,
+        point: FaultPoint,
+        action: FaultAction,
+    ) -> Result<(), SearchDirectoryError> {
+        let mut faults = Self::load(directory)?;
+        faults.0.insert(point, action);
+        faults.save(directory)
+    }
+
+    pub fn clear(directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        Self::default().save(directory)
+    }
+
+    /// Remove the fault injected at a point, and return what it was to do.
+    pub fn take(
+        directory: &WriterDirectory,
+        point: FaultPoint,
+    ) -> Result<Option<FaultAction>, SearchDirectoryError> {
+        let FaultsFilePath(path) = directory.faults_file_path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut faults = Self::load(directory)?;
+        let action = faults.0.remove(&point);
+        if action.is_some() {
+            faults.save(directory)?;
+        }
+        Ok(action)
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FaultError {
+    #[error("unknown fault point '{0}', expected one of: backend_before_send, backend_before_commit, writer_before_commit, writer_after_commit, build_after_scan")]
+    UnknownPoint(String),
+
+    #[error("unknown fault action '{0}', expected 'error' or 'crash'")]
+    UnknownAction(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FaultAction, FaultPoint, Faults};
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_faults(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert_eq!(
+            Faults::take(&directory, FaultPoint::WriterAfterCommit).unwrap(),
+            None
+        );
+
+        Faults::inject(
+            &directory,
+            FaultPoint::WriterAfterCommit,
+            FaultAction::Crash,
+        )
+        .unwrap();
+        Faults::inject(&directory, FaultPoint::BuildAfterScan, FaultAction::Error).unwrap();
+        assert_eq!(Faults::load(&directory).unwrap().0.len(), 2);
+
+        // A fault fires once.
+        assert_eq!(
+            Faults::take(&directory, FaultPoint::WriterAfterCommit).unwrap(),
+            Some(FaultAction::Crash)
+        );
+        assert_eq!(
+            Faults::take(&directory, FaultPoint::WriterAfterCommit).unwrap(),
+            None
+        );
+
+        Faults::clear(&directory).unwrap();
+        assert_eq!(Faults::load(&directory).unwrap(), Faults::default());
+    }
+
+    #[rstest]
+    fn test_fault_names() {
+        for point in FaultPoint::ALL {
+            assert_eq!(point.name().parse::<FaultPoint>().unwrap(), point);
+        }
+        assert!("writer_sometimes".parse::<FaultPoint>().is_err());
+        assert_eq!("crash".parse::<FaultAction>().unwrap(), FaultAction::Crash);
+        assert!("hang".parse::<FaultAction>().is_err());
+    }
+}
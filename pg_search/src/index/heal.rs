@@ -0,0 +1,151 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use crate::schema::SearchDocument;
+use crate::writer::{HealStatusFilePath, SearchDirectoryError, WriterDirectory};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use tantivy::collector::DocSetCollector;
+use tantivy::query::TermSetQuery;
+use tantivy::schema::OwnedValue;
+
+/// Progress of the heal worker through the table of an index, saved next to the index so
+/// that a pass resumes where it stopped after a restart. See `heal_next_blocks`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HealStatus {
+    /// The heap block the next step of the current pass starts from.
+    pub next_block: u32,
+    /// The number of passes over the whole table completed so far.
+    pub passes_completed: u64,
+    /// The number of rows compared with the index so far.
+    pub rows_checked: u64,
+    /// The number of rows reindexed because the index was missing them, or had a stale
+    /// version of them.
+    pub rows_repaired: u64,
+}
+
+impl HealStatus {
+    pub fn load(directory: &WriterDirectory) -> Result<Self, SearchDirectoryError> {
+        let HealStatusFilePath(path) = directory.heal_status_file_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let serialized = fs::read_to_string(&path)
+            .map_err(|err| SearchDirectoryError::IndexFileRead(directory.clone(), path, err))?;
+        serde_json::from_str(&serialized)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let HealStatusFilePath(path) = directory.heal_status_file_path()?;
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+}
+
+/// The documents of rows that the index doesn't have as they are in the table. A row is
+/// looked up by its key, and is up to date if a document with that key points to its ctid.
+/// Otherwise, the write of the row was lost, or the index still has an older version of it.
+pub fn unindexed_documents(
+    search_index: &SearchIndex,
+    documents: Vec<SearchDocument>,
+) -> Result<Vec<SearchDocument>, SearchIndexError> {
+    let key_terms = documents.iter().filter_map(|document| document.key_term());
+    let query = TermSetQuery::new(key_terms);
+    let searcher = search_index.searcher();
+    let ctid_field_name = search_index.schema.ctid_field().name.0;
+
+    let mut indexed_ctids = HashSet::new();
+    for address in searcher.search(&query, &DocSetCollector)? {
+        let ctid_column = searcher
+            .segment_reader(address.segment_ord)
+            .fast_fields()
+            .u64(&ctid_field_name)?;
+        indexed_ctids.extend(ctid_column.first(address.doc_id));
+    }
+
+    Ok(documents
+        .into_iter()
+        .filter(|document| {
+            let ctid = document.doc.get_first(document.ctid.0);
+            !matches!(ctid, Some(OwnedValue::U64(ctid)) if indexed_ctids.contains(ctid))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{unindexed_documents, HealStatus};
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::schema::SearchDocument;
+    use rstest::*;
+    use tantivy::schema::OwnedValue;
+
+    fn document(index: &SearchIndex, id: i64, ctid: u64) -> SearchDocument {
+        let mut document = index.schema.new_document();
+        document.insert(index.schema.key_field().id, id.into());
+        document.insert(index.schema.ctid_field().id, ctid.into());
+        document
+    }
+
+    #[rstest]
+    fn test_unindexed_documents(default_index: MockSearchIndex) {
+        let index = default_index.index;
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        writer.add_document(document(index, 1, 10).into()).unwrap();
+        writer.add_document(document(index, 2, 20).into()).unwrap();
+        writer.commit().unwrap();
+        index.reader.reload().unwrap();
+
+        // Row 2 was updated to ctid 21, and the insert of row 3 was lost.
+        let documents = vec![
+            document(index, 1, 10),
+            document(index, 2, 21),
+            document(index, 3, 30),
+        ];
+        let unindexed: Vec<u64> = unindexed_documents(index, documents)
+            .unwrap()
+            .iter()
+            .filter_map(|document| match document.doc.get_first(document.ctid.0) {
+                Some(OwnedValue::U64(ctid)) => Some(*ctid),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(unindexed, vec![21, 30]);
+    }
+
+    #[rstest]
+    fn test_heal_status_roundtrip(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert_eq!(HealStatus::load(&directory).unwrap(), HealStatus::default());
+
+        let status = HealStatus {
+            next_block: 64,
+            passes_completed: 1,
+            rows_checked: 1000,
+            rows_repaired: 2,
+        };
+        status.save(&directory).unwrap();
+        assert_eq!(HealStatus::load(&directory).unwrap(), status);
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use super::collector::key_reader;
+use super::encryption::{EncryptedDirectory, EncryptionError, EncryptionKey};
+use super::SearchIndex;
+use crate::postgres::types::TantivyValue;
+use crate::writer::{HistoryDirPath, SearchDirectoryError};
+use pgrx::pg_sys;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use tantivy::query::{EnableScoring, Query};
+use tantivy::schema::{DateOptions, Schema};
+use tantivy::{
+    DateTime, DocAddress, DocSet, Index, IndexWriter, Searcher, TantivyDocument, TERMINATED,
+};
+use thiserror::Error;
+
+/// The field dating each version of a row, in indexes that keep the history of their rows.
+pub const VALID_FROM_FIELD: &str = "_valid_from";
+/// The field dating when a version of a row was replaced or deleted, in the history index.
+pub const VALID_TO_FIELD: &str = "_valid_to";
+
+const HISTORY_TANTIVY_MEMORY_BUDGET: usize = 50_000_000;
+
+/// Microseconds between the Unix epoch and the Postgres epoch, 2000-01-01.
+const POSTGRES_EPOCH_MICROS: i64 = 946_684_800_000_000;
+
+/// The start of the current transaction, which dates the versions of rows that it writes.
+pub fn transaction_timestamp() -> DateTime {
+    let timestamp = unsafe { pg_sys::GetCurrentTransactionStartTimestamp() };
+    DateTime::from_timestamp_micros(timestamp + POSTGRES_EPOCH_MICROS)
+}
+
+/// A version of a row in the index, ordered by when it was written.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Version {
+    valid_from: DateTime,
+    address: DocAddress,
+}
+
+/// The replaced and deleted versions of the rows of an index with `keep_history`. Vacuum moves
+/// versions here instead of dropping them, along with the time they stopped being current,
+/// and `as_of` searches read them back. The history index has the fields of the index, with
+/// the same ids, so queries built for the index search it too. Only stored fields are kept.
+pub struct IndexHistory {}
+
+impl IndexHistory {
+    pub fn keeps_history(search_index: &SearchIndex) -> bool {
+        search_index
+            .schema
+            .get_search_field(VALID_FROM_FIELD)
+            .is_some()
+    }
+
+    /// Open the history index, or create it if `create` is set. None if it doesn't exist yet,
+    /// as no version has been moved to it.
+    fn open(search_index: &SearchIndex, create: bool) -> Result<Option<Index>, HistoryError> {
+        let HistoryDirPath(path) = search_index.directory.history_dir_path(create)?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let mut directory = search_index.directory_mode.open(&path)?;
+        if search_index.encrypted {
+            directory = Box::new(EncryptedDirectory::new(directory, EncryptionKey::load()?));
+        }
+        let mut builder = Schema::builder();
+        for (_, field_entry) in search_index.schema.schema.fields() {
+            builder.add_field(field_entry.clone());
+        }
+        builder.add_date_field(
+            VALID_TO_FIELD,
+            DateOptions::default().set_indexed().set_fast().set_stored(),
+        );
+
+        let mut index = Index::open_or_create(directory, builder.build())?;
+        SearchIndex::setup_tokenizers(&mut index, &search_index.schema);
+        Ok(Some(index))
+    }
+
+    /// The committed versions of the rows of the index, by key and in the order they were
+    /// written.
+    fn versions(
+        search_index: &SearchIndex,
+        searcher: &Searcher,
+    ) -> Result<HashMap<TantivyValue, Vec<Version>>, HistoryError> {
+        let key_field_name = search_index.schema.key_field().name.0;
+        let mut versions: HashMap<TantivyValue, Vec<Version>> = HashMap::new();
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            let mut read_key = key_reader(&search_index.schema, &key_field_name, segment_reader);
+            let valid_from = segment_reader.fast_fields().date(VALID_FROM_FIELD)?;
+            for doc in segment_reader.doc_ids_alive() {
+                if let Some(valid_from) = valid_from.first(doc) {
+                    versions.entry(read_key(doc)).or_default().push(Version {
+                        valid_from,
+                        address: DocAddress::new(segment_ord as u32, doc),
+                    });
+                }
+            }
+        }
+        for key_versions in versions.values_mut() {
+            key_versions.sort();
+        }
+        Ok(versions)
+    }
+
+    /// Copy the versions of rows whose ctid matches `should_delete` to the history index,
+    /// before vacuum deletes them. A version stopped being current when the next version of
+    /// its row was written, or, if it is the last one, when the row was deleted, which is only
+    /// known to have happened by the time of the vacuum.
+    pub fn archive(
+        search_index: &SearchIndex,
+        should_delete: impl Fn(u64) -> bool,
+    ) -> Result<u64, HistoryError> {
+        let searcher = search_index.searcher();
+        let ctid_field_name = search_index.schema.ctid_field().name.0;
+        let ctid_columns = searcher
+            .segment_readers()
+            .iter()
+            .map(|segment_reader| segment_reader.fast_fields().u64(&ctid_field_name))
+            .collect::<tantivy::Result<Vec<_>>>()?;
+        let now = transaction_timestamp();
+
+        let mut archived = vec![];
+        for versions in Self::versions(search_index, &searcher)?.into_values() {
+            for (position, version) in versions.iter().enumerate() {
+                let ctid = ctid_columns[version.address.segment_ord as usize]
+                    .first(version.address.doc_id);
+                if !ctid.map_or(false, &should_delete) {
+                    continue;
+                }
+                let valid_to = versions
+                    .get(position + 1)
+                    .map_or(now, |next| next.valid_from);
+                archived.push((version.address, valid_to));
+            }
+        }
+        if archived.is_empty() {
+            return Ok(0);
+        }
+
+        let index = Self::open(search_index, true)?.expect("history index was just created");
+        let valid_to_field = index.schema().get_field(VALID_TO_FIELD)?;
+        let mut writer: IndexWriter = index.writer(HISTORY_TANTIVY_MEMORY_BUDGET)?;
+        for (address, valid_to) in &archived {
+            let mut document: TantivyDocument = searcher.doc(*address)?;
+            document.add_date(valid_to_field, *valid_to);
+            writer.add_document(document)?;
+        }
+        writer.commit()?;
+        Ok(archived.len() as u64)
+    }
+
+    /// The documents matching `query` as they were at `as_of`, with their scores, best first.
+    /// The current version of a row at that time is either still in the index, as the latest
+    /// of its versions written before then, or was moved to the history index by a vacuum.
+    pub fn search_as_of(
+        search_index: &SearchIndex,
+        query: &dyn Query,
+        as_of: DateTime,
+        limit: usize,
+        offset: usize,
+    ) -> Result<Vec<(f32, TantivyDocument)>, HistoryError> {
+        search_index.reader.reload()?;
+        let searcher = search_index.searcher();
+        let mut current = vec![];
+        for versions in Self::versions(search_index, &searcher)?.into_values() {
+            if let Some(version) = versions
+                .iter()
+                .rev()
+                .find(|version| version.valid_from <= as_of)
+            {
+                current.push(version.address);
+            }
+        }
+        current.sort();
+
+        let mut results = vec![];
+        Self::collect_matches(&searcher, query, &mut results, |address| {
+            current.binary_search(&address).is_ok()
+        })?;
+
+        if let Some(history_index) = Self::open(search_index, false)? {
+            let history_searcher = SearchIndex::reader(&history_index)?.searcher();
+            let validity = history_searcher
+                .segment_readers()
+                .iter()
+                .map(|segment_reader| {
+                    let fast_fields = segment_reader.fast_fields();
+                    Ok((
+                        fast_fields.date(VALID_FROM_FIELD)?,
+                        fast_fields.date(VALID_TO_FIELD)?,
+                    ))
+                })
+                .collect::<tantivy::Result<Vec<_>>>()?;
+            Self::collect_matches(&history_searcher, query, &mut results, |address| {
+                let (valid_from, valid_to) = &validity[address.segment_ord as usize];
+                matches!(
+                    (valid_from.first(address.doc_id), valid_to.first(address.doc_id)),
+                    (Some(valid_from), Some(valid_to)) if valid_from <= as_of && as_of < valid_to
+                )
+            })?;
+        }
+
+        results.sort_by(|(left, _), (right, _)| right.partial_cmp(left).unwrap_or(Ordering::Equal));
+        Ok(results.into_iter().skip(offset).take(limit).collect())
+    }
+
+    /// Add the documents of a searcher that match `query` and `filter` to `results`.
+    fn collect_matches(
+        searcher: &Searcher,
+        query: &dyn Query,
+        results: &mut Vec<(f32, TantivyDocument)>,
+        filter: impl Fn(DocAddress) -> bool,
+    ) -> Result<(), HistoryError> {
+        let weight = query.weight(EnableScoring::enabled_from_searcher(searcher))?;
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            let alive_bitset = segment_reader.alive_bitset();
+            let mut scorer = weight.scorer(segment_reader, 1.0)?;
+            let mut doc = scorer.doc();
+            while doc != TERMINATED {
+                let address = DocAddress::new(segment_ord as u32, doc);
+                if alive_bitset.map_or(true, |bitset| bitset.is_alive(doc)) && filter(address) {
+                    results.push((scorer.score(), searcher.doc(address)?));
+                }
+                doc = scorer.advance();
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum HistoryError {
+    #[error(transparent)]
+    TantivyError(#[from] tantivy::TantivyError),
+
+    #[error(transparent)]
+    EncryptionError(#[from] EncryptionError),
+
+    #[error(transparent)]
+    WriterDirectoryError(#[from] SearchDirectoryError),
+}
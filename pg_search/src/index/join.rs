@@ -0,0 +1,72 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use super::collector::key_reader;
+use super::dedup::{LatestVersionWeight, LatestVersions};
+use super::{SearchIndex, SearchIndexError};
+use crate::postgres::security::secure_query;
+use crate::postgres::utils::bm25_index_name;
+use crate::query::SearchQueryInput;
+use crate::schema::value_term;
+use crate::writer::WriterDirectory;
+use std::sync::Arc;
+use tantivy::query::{EnableScoring, Weight};
+use tantivy::schema::Field;
+use tantivy::{DocSet, Term, TERMINATED};
+
+/// The terms of `field` for the keys of the rows of another index that match a query, so
+/// that a semi-join on the key is searched as a term set instead of returning every key to
+/// Postgres first. Only the latest version of each row counts, the security filter of the
+/// other index applies, and a key that can't be a term of `field`, like a key of another type,
+/// matches nothing.
+pub fn key_terms(
+    index_name: &str,
+    query: SearchQueryInput,
+    field: Field,
+) -> Result<Vec<Term>, SearchIndexError> {
+    let bm25_index_name = bm25_index_name(index_name);
+    let directory = WriterDirectory::from_index_name(&bm25_index_name);
+    let search_index = SearchIndex::from_disk(&directory)?;
+    let query = secure_query(&bm25_index_name, &search_index.schema, query);
+    let query = query.into_tantivy_query(&search_index.schema, &mut search_index.query_parser())?;
+
+    let searcher = search_index.searcher();
+    let key_field_name = search_index.schema.key_field().name.0;
+    let latest = Arc::new(LatestVersions::new(
+        search_index.schema.clone(),
+        key_field_name.clone(),
+        searcher.segment_readers().to_vec(),
+    ));
+    let weight = LatestVersionWeight::new(
+        query.weight(EnableScoring::disabled_from_searcher(&searcher))?,
+        latest,
+    );
+
+    let mut terms = vec![];
+    for segment_reader in searcher.segment_readers() {
+        let mut read_key = key_reader(&search_index.schema, &key_field_name, segment_reader);
+        let alive_bitset = segment_reader.alive_bitset();
+        let mut scorer = weight.scorer(segment_reader, 1.0)?;
+        let mut doc = scorer.doc();
+        while doc != TERMINATED {
+            if alive_bitset.map_or(true, |bitset| bitset.is_alive(doc)) {
+                terms.extend(value_term(field, &read_key(doc).tantivy_schema_value()));
+            }
+            doc = scorer.advance();
+        }
+    }
+    Ok(terms)
+}
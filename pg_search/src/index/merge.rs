@@ -0,0 +1,252 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::writer::{
+    MaintenancePausedFilePath, MergeStatusFilePath, SearchDirectoryError, WriterDirectory,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use tantivy::merge_policy::{LogMergePolicy, MergePolicy};
+use tantivy::{SegmentId, SegmentMeta};
+
+/// Merge settings for an index, passed as the `merge_policy` index option.
+///
+/// Merges are not triggered by the index writer on commit. Instead, the merge background
+/// worker periodically asks the writer to merge each index, so that merge I/O happens at a
+/// predictable rate rather than right after every commit.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SearchMergePolicy {
+    /// The minimum number of segments in a level before they are merged together.
+    pub min_num_segments: usize,
+    /// Segments with more documents than this are never merged.
+    pub max_docs_before_merge: usize,
+    /// Segments smaller than this are all considered to be in the same level.
+    pub min_layer_size: u32,
+    /// The log ratio between the sizes of consecutive levels.
+    pub level_log_size: f64,
+    /// Segments with a higher ratio of deleted documents are merged regardless of their level.
+    pub del_docs_ratio_before_merge: f32,
+    /// If set, the smallest segments are merged until at most this many remain.
+    pub target_segment_count: Option<usize>,
+    /// After a vacuum deletes documents, segments with a higher ratio of deleted documents
+    /// are merged right away to reclaim their space.
+    pub vacuum_del_docs_ratio: f32,
+}
+
+impl Default for SearchMergePolicy {
+    fn default() -> Self {
+        // These match the defaults of Tantivy's LogMergePolicy.
+        Self {
+            min_num_segments: 8,
+            max_docs_before_merge: 10_000_000,
+            min_layer_size: 10_000,
+            level_log_size: 0.75,
+            del_docs_ratio_before_merge: 1.0,
+            target_segment_count: None,
+            vacuum_del_docs_ratio: 0.2,
+        }
+    }
+}
+
+impl SearchMergePolicy {
+    pub fn log_merge_policy(&self) -> LogMergePolicy {
+        let mut policy = LogMergePolicy::default();
+        policy.set_min_num_segments(self.min_num_segments);
+        policy.set_max_docs_before_merge(self.max_docs_before_merge);
+        policy.set_min_layer_size(self.min_layer_size);
+        policy.set_level_log_size(self.level_log_size);
+        policy.set_del_docs_ratio_before_merge(self.del_docs_ratio_before_merge);
+        policy
+    }
+
+    /// The groups of segments that should be merged together, at most `max_merges` of them.
+    pub fn merge_candidates(
+        &self,
+        segments: &[SegmentMeta],
+        max_merges: usize,
+    ) -> Vec<Vec<SegmentId>> {
+        let mut candidates: Vec<Vec<SegmentId>> = self
+            .log_merge_policy()
+            .compute_merge_candidates(segments)
+            .into_iter()
+            .map(|candidate| candidate.0)
+            .collect();
+
+        if let Some(target) = self.target_segment_count {
+            let merged: usize = candidates.iter().map(|ids| ids.len() - 1).sum();
+            let remaining = segments.len() - merged;
+            if remaining > target.max(1) {
+                candidates.push(Self::smallest_segments(
+                    segments,
+                    &candidates,
+                    remaining - target.max(1) + 1,
+                ));
+            }
+        }
+
+        candidates.retain(|ids| ids.len() > 1);
+        candidates.truncate(max_merges);
+        candidates
+    }
+
+    /// The segments to merge together so that at most `max_segments` remain.
+    pub fn force_merge_candidate(segments: &[SegmentMeta], max_segments: usize) -> Vec<SegmentId> {
+        let max_segments = max_segments.max(1);
+        if segments.len() <= max_segments {
+            return vec![];
+        }
+        Self::smallest_segments(segments, &[], segments.len() - max_segments + 1)
+    }
+
+    /// The segments with enough deleted documents to be rewritten after a vacuum. They are
+    /// merged together, so that a single segment is rewritten on its own as well.
+    pub fn reclaim_candidate(&self, segments: &[SegmentMeta]) -> Vec<SegmentId> {
+        segments
+            .iter()
+            .filter(|meta| {
+                meta.has_deletes()
+                    && meta.num_deleted_docs() as f32
+                        >= meta.max_doc() as f32 * self.vacuum_del_docs_ratio
+            })
+            .map(|meta| meta.id())
+            .collect()
+    }
+
+    /// The `count` smallest segments that aren't already part of a merge candidate.
+    fn smallest_segments(
+        segments: &[SegmentMeta],
+        candidates: &[Vec<SegmentId>],
+        count: usize,
+    ) -> Vec<SegmentId> {
+        let mut free: Vec<&SegmentMeta> = segments
+            .iter()
+            .filter(|meta| !candidates.iter().flatten().any(|id| *id == meta.id()))
+            .collect();
+        free.sort_by_key(|meta| meta.num_docs());
+        free.into_iter().take(count).map(|meta| meta.id()).collect()
+    }
+}
+
+/// Progress of the merges running on an index, saved next to the index by the writer
+/// process so that it can be read from any connection.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct MergeStatus {
+    pub merges_in_progress: usize,
+    pub merges_completed: u64,
+    pub last_error: Option<String>,
+}
+
+impl MergeStatus {
+    pub fn load(directory: &WriterDirectory) -> Result<Self, SearchDirectoryError> {
+        let MergeStatusFilePath(path) = directory.merge_status_file_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let serialized = fs::read_to_string(&path)
+            .map_err(|err| SearchDirectoryError::IndexFileRead(directory.clone(), path, err))?;
+        serde_json::from_str(&serialized)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let MergeStatusFilePath(path) = directory.merge_status_file_path()?;
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+}
+
+/// Background maintenance of an index can be paused, for instance to give a bulk load all
+/// of the available I/O, and resumed during an off-peak window. The state is kept as a file
+/// next to the index, so that it survives restarts of the writer process.
+pub struct Maintenance {}
+
+impl Maintenance {
+    pub fn pause(directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let MaintenancePausedFilePath(path) = directory.maintenance_paused_file_path()?;
+        fs::write(path, "")
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    pub fn resume(directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let MaintenancePausedFilePath(path) = directory.maintenance_paused_file_path()?;
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn is_paused(directory: &WriterDirectory) -> Result<bool, SearchDirectoryError> {
+        let MaintenancePausedFilePath(path) = directory.maintenance_paused_file_path()?;
+        Ok(path.exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Maintenance, MergeStatus, SearchMergePolicy};
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_merge_policy_partial_config() {
+        let policy: SearchMergePolicy =
+            json5::from_str("{min_num_segments: 4, target_segment_count: 1}").unwrap();
+        assert_eq!(policy.min_num_segments, 4);
+        assert_eq!(policy.target_segment_count, Some(1));
+        assert_eq!(
+            policy.max_docs_before_merge,
+            SearchMergePolicy::default().max_docs_before_merge
+        );
+    }
+
+    #[rstest]
+    fn test_merge_status_roundtrip(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert_eq!(
+            MergeStatus::load(&directory).unwrap(),
+            MergeStatus::default()
+        );
+
+        let status = MergeStatus {
+            merges_in_progress: 1,
+            merges_completed: 2,
+            last_error: None,
+        };
+        status.save(&directory).unwrap();
+        assert_eq!(MergeStatus::load(&directory).unwrap(), status);
+    }
+
+    #[rstest]
+    fn test_maintenance_pause_resume(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert!(!Maintenance::is_paused(&directory).unwrap());
+
+        Maintenance::pause(&directory).unwrap();
+        assert!(Maintenance::is_paused(&directory).unwrap());
+
+        // Resuming twice is harmless.
+        Maintenance::resume(&directory).unwrap();
+        Maintenance::resume(&directory).unwrap();
+        assert!(!Maintenance::is_paused(&directory).unwrap());
+    }
+}
@@ -15,8 +15,40 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+pub mod backup;
+pub mod cache;
+pub mod check;
+pub mod collector;
+pub mod deadline;
+pub mod dedup;
+pub mod encryption;
+pub mod export;
+pub mod fast_fields;
+pub mod fault;
+pub mod heal;
+pub mod history;
+pub mod join;
+pub mod merge;
+pub mod orphan;
+pub mod pending;
+pub mod percolate;
+pub mod pin;
+pub mod prepared;
+pub mod profile;
+pub mod progress;
+pub mod readonly;
+pub mod recovery;
+pub mod relevance;
+pub mod report;
+pub mod remote;
 pub mod score;
 pub mod search;
+pub mod shipping;
+pub mod source;
 pub mod state;
+pub mod stats;
+pub mod storage;
+pub mod suggest;
+pub mod terms;
 
 pub use search::*;
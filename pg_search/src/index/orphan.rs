@@ -0,0 +1,148 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::writer::WriterDirectory;
+use std::collections::{HashMap, HashSet};
+
+/// Why an index directory no longer belongs to any index.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OrphanReason {
+    /// The database of the index was dropped, so its indexes were never dropped one by one.
+    DatabaseDropped,
+    /// No BM25 index of the database has the name of the directory anymore, for instance
+    /// because the writer process was down when it was dropped.
+    IndexDropped,
+    /// The index was rewritten into a new file node, as on TRUNCATE or REINDEX, and is kept
+    /// in the directory of the new one.
+    IndexRebuilt,
+}
+
+impl OrphanReason {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::DatabaseDropped => "database dropped",
+            Self::IndexDropped => "index dropped",
+            Self::IndexRebuilt => "index rebuilt",
+        }
+    }
+}
+
+/// The directories left behind by dropped indexes or databases, see
+/// `paradedb.orphaned_directories`. `databases` are the oids of every database, and
+/// `indexes` the file nodes of the BM25 indexes of `database_oid` by name. The catalogs of
+/// other databases can't be read, so only their dropping is detected. Directories created
+/// before file nodes were part of their name belong to the index of the same name.
+pub fn find_orphans(
+    directories: Vec<WriterDirectory>,
+    database_oid: u32,
+    databases: &HashSet<u32>,
+    indexes: &HashMap<String, u32>,
+) -> Vec<(WriterDirectory, OrphanReason)> {
+    directories
+        .into_iter()
+        .filter_map(|directory| {
+            if !databases.contains(&directory.database_oid) {
+                return Some((directory, OrphanReason::DatabaseDropped));
+            }
+            if directory.database_oid != database_oid {
+                return None;
+            }
+            match indexes.get(&directory.index_name) {
+                None => Some((directory, OrphanReason::IndexDropped)),
+                Some(&relfilenode)
+                    if directory.relfilenode != 0 && directory.relfilenode != relfilenode =>
+                {
+                    Some((directory, OrphanReason::IndexRebuilt))
+                }
+                Some(_) => None,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_orphans, OrphanReason};
+    use crate::fixtures::*;
+    use crate::writer::WriterDirectory;
+    use rstest::*;
+    use std::collections::{HashMap, HashSet};
+
+    #[rstest]
+    fn test_find_orphans(mock_dir: MockWriterDirectory) {
+        let data_dir = mock_dir.writer_dir.postgres_data_dir_path.clone();
+        let directory = |database_oid: u32, relfilenode: u32, index_name: &str| WriterDirectory {
+            index_name: index_name.into(),
+            database_oid,
+            relfilenode,
+            postgres_data_dir_path: data_dir.clone(),
+        };
+        let directories = vec![
+            directory(1, 100, "live_bm25_index"),
+            directory(1, 90, "live_bm25_index"),
+            directory(1, 0, "legacy_bm25_index"),
+            directory(1, 110, "dropped_bm25_index"),
+            directory(2, 120, "other_bm25_index"),
+            directory(3, 130, "gone_bm25_index"),
+        ];
+        let databases = HashSet::from([1, 2]);
+        let indexes = HashMap::from([
+            ("live_bm25_index".to_string(), 100),
+            ("legacy_bm25_index".to_string(), 105),
+        ]);
+
+        let orphans = find_orphans(directories, 1, &databases, &indexes);
+        assert_eq!(
+            orphans,
+            vec![
+                (
+                    directory(1, 90, "live_bm25_index"),
+                    OrphanReason::IndexRebuilt
+                ),
+                (
+                    directory(1, 110, "dropped_bm25_index"),
+                    OrphanReason::IndexDropped
+                ),
+                (
+                    directory(3, 130, "gone_bm25_index"),
+                    OrphanReason::DatabaseDropped
+                ),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_dir_name_roundtrip(mock_dir: MockWriterDirectory) {
+        let data_dir = mock_dir.writer_dir.postgres_data_dir_path.clone();
+        for (relfilenode, index_name) in [(16384, "my_bm25_index"), (0, "my_bm25_index")] {
+            let directory = WriterDirectory {
+                index_name: index_name.into(),
+                database_oid: 5,
+                relfilenode,
+                postgres_data_dir_path: data_dir.clone(),
+            };
+            assert_eq!(
+                WriterDirectory::from_dir_name(&directory.dir_name(), &data_dir),
+                Some(directory)
+            );
+        }
+        assert_eq!(
+            WriterDirectory::from_dir_name("writer_transfer", &data_dir),
+            None
+        );
+    }
+}
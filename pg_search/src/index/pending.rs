@@ -0,0 +1,838 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::schema::SearchDocument;
+use crate::writer::{WriterDirectory, WriterRequest};
+use crate::SEARCH_GUCS;
+use once_cell::sync::Lazy;
+use pgrx::{pg_guard, pg_sys};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use tantivy::directory::{MmapDirectory, RamDirectory};
+use tantivy::{Directory, Index, IndexReader, IndexWriter, Searcher, TantivyError, Term};
+use tempfile::TempDir;
+use thiserror::Error;
+
+/// Documents inserted by the current transaction that have not been sent to the writer yet.
+///
+/// Inserts are buffered in the backend process until the owning transaction commits, so that
+/// other connections can never observe documents from an uncommitted transaction. The owning
+/// transaction can still see its own documents, as they are searched from an index of the
+/// buffer (see `PendingIndex`). Once the buffers of a transaction outgrow
+/// `paradedb.pending_inserts_memory_limit`, their documents are moved to temporary files.
+static PENDING_INSERTS: Lazy<Arc<Mutex<HashMap<WriterDirectory, PendingBuffer>>>> =
+    Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+
+/// The maximum number of documents sent to the writer in a single request.
+const INSERT_MANY_BATCH_SIZE: usize = 1000;
+
+// The index holding a transaction's pending inserts is short-lived, so we give it the
+// minimum budget Tantivy allows.
+const PENDING_TANTIVY_MEMORY_BUDGET: usize = 15_000_000;
+
+/// Postgres subtransaction callbacks live for the whole backend, so we only register ours once.
+static SUBXACT_CALLBACK_REGISTERED: AtomicBool = AtomicBool::new(false);
+
+/// A buffered document, tagged with the subtransaction that inserted it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingDocument {
+    pub subxact: pg_sys::SubTransactionId,
+    pub document: SearchDocument,
+    /// Whether the document is a new version of a row that is already in the index, as
+    /// written by an UPDATE or an INSERT ... ON CONFLICT DO UPDATE.
+    pub upsert: bool,
+}
+
+impl PendingDocument {
+    /// The requests sending these documents to the writer, in order. Consecutive inserts
+    /// are sent together, which is much cheaper than one request per document.
+    pub fn into_requests(
+        directory: &WriterDirectory,
+        documents: impl IntoIterator<Item = PendingDocument>,
+    ) -> Vec<WriterRequest> {
+        let mut requests = vec![];
+        let mut inserts = vec![];
+        for pending in documents {
+            if pending.upsert {
+                Self::push_inserts(&mut requests, directory, &mut inserts);
+                requests.push(WriterRequest::Upsert {
+                    directory: directory.clone(),
+                    document: pending.document,
+                });
+            } else {
+                inserts.push(pending.document);
+                if inserts.len() >= INSERT_MANY_BATCH_SIZE {
+                    Self::push_inserts(&mut requests, directory, &mut inserts);
+                }
+            }
+        }
+        Self::push_inserts(&mut requests, directory, &mut inserts);
+        requests
+    }
+
+    fn push_inserts(
+        requests: &mut Vec<WriterRequest>,
+        directory: &WriterDirectory,
+        inserts: &mut Vec<SearchDocument>,
+    ) {
+        if !inserts.is_empty() {
+            requests.push(WriterRequest::InsertMany {
+                directory: directory.clone(),
+                documents: std::mem::take(inserts),
+            });
+        }
+    }
+
+    /// The memory the document takes in the buffer, estimated by its serialized size like
+    /// the writer does for its uncommitted documents.
+    fn size(&self) -> usize {
+        bincode::serialized_size(&self.document).unwrap_or_default() as usize
+    }
+}
+
+/// The documents buffered for an index by the current transaction.
+#[derive(Default)]
+struct PendingBuffer {
+    /// The documents moved to disk, which were buffered before `documents`.
+    spilled: Option<SpillFile>,
+    documents: Vec<PendingDocument>,
+    /// The total size of the documents in memory, see `PendingDocument::size`.
+    bytes: usize,
+    /// The documents indexed for searches, once the transaction searches them.
+    index: Option<PendingIndex>,
+}
+
+impl PendingBuffer {
+    fn extend(&mut self, documents: impl IntoIterator<Item = PendingDocument>) {
+        for pending in documents {
+            self.bytes += pending.size();
+            self.documents.push(pending);
+        }
+    }
+
+    /// The number of documents buffered, on disk and in memory.
+    fn len(&self) -> usize {
+        self.spilled.as_ref().map_or(0, |spilled| spilled.len) + self.documents.len()
+    }
+
+    /// Move the documents in memory to the spill file. An index of the documents that is
+    /// kept in memory is dropped as well, to be built again on disk.
+    fn spill(&mut self, directory: &WriterDirectory) -> Result<(), PendingInsertsError> {
+        if self.documents.is_empty() {
+            return Ok(());
+        }
+        let spilled = match &mut self.spilled {
+            Some(spilled) => spilled,
+            None => self.spilled.insert(SpillFile::create(directory)?),
+        };
+        spilled.append(&self.documents)?;
+        self.documents.clear();
+        self.bytes = 0;
+        if self.index.as_ref().is_some_and(|index| index.dir.is_none()) {
+            self.index = None;
+        }
+        Ok(())
+    }
+
+    /// Call `visit` with every buffered document, in the order they were buffered.
+    fn for_each(
+        &mut self,
+        mut visit: impl FnMut(PendingDocument) -> Result<(), PendingInsertsError>,
+    ) -> Result<(), PendingInsertsError> {
+        if let Some(spilled) = &mut self.spilled {
+            for batch in spilled.batches()? {
+                batch?.into_iter().try_for_each(&mut visit)?;
+            }
+        }
+        self.documents.iter().cloned().try_for_each(visit)
+    }
+
+    /// Discard the documents tagged with `subxact` or a later subtransaction.
+    fn rollback(
+        &mut self,
+        directory: &WriterDirectory,
+        subxact: pg_sys::SubTransactionId,
+    ) -> Result<(), PendingInsertsError> {
+        let len = self.len();
+        if let Some(mut spilled) = self.spilled.take() {
+            // Files can't be cut short in the middle, so the documents to keep are copied.
+            let mut kept = SpillFile::create(directory)?;
+            for batch in spilled.batches()? {
+                let batch: Vec<PendingDocument> = batch?
+                    .into_iter()
+                    .filter(|pending| pending.subxact < subxact)
+                    .collect();
+                kept.append(&batch)?;
+            }
+            self.spilled = Some(kept).filter(|kept| kept.len > 0);
+        }
+        self.documents.retain(|pending| pending.subxact < subxact);
+        if self.len() < len {
+            // The index can't take documents back, so it's built again.
+            self.bytes = self.documents.iter().map(PendingDocument::size).sum();
+            self.index = None;
+        }
+        Ok(())
+    }
+}
+
+/// Documents moved out of memory, in batches serialized with bincode to an unnamed temporary
+/// file in the temporary directory of Postgres, which goes away with the buffer.
+struct SpillFile {
+    file: File,
+    /// The number of batches written to the file.
+    batches: usize,
+    /// The number of documents written to the file.
+    len: usize,
+}
+
+impl SpillFile {
+    fn create(directory: &WriterDirectory) -> io::Result<Self> {
+        Ok(Self {
+            file: tempfile::tempfile_in(spill_dir_path(directory)?)?,
+            batches: 0,
+            len: 0,
+        })
+    }
+
+    fn append(&mut self, documents: &[PendingDocument]) -> io::Result<()> {
+        if documents.is_empty() {
+            return Ok(());
+        }
+        self.file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&self.file);
+        bincode::serialize_into(&mut writer, documents).map_err(bincode_to_io_error)?;
+        writer.flush()?;
+        self.batches += 1;
+        self.len += documents.len();
+        Ok(())
+    }
+
+    /// Read the batches back, from the start of the file.
+    fn batches(
+        &mut self,
+    ) -> io::Result<impl Iterator<Item = io::Result<Vec<PendingDocument>>> + '_> {
+        self.file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(&self.file);
+        Ok((0..self.batches)
+            .map(move |_| bincode::deserialize_from(&mut reader).map_err(bincode_to_io_error)))
+    }
+}
+
+fn bincode_to_io_error(err: bincode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err)
+}
+
+/// Where Postgres writes the temporary files of sorts and hashes that don't fit in memory,
+/// which it clears out when it starts.
+fn spill_dir_path(directory: &WriterDirectory) -> io::Result<PathBuf> {
+    let path = directory
+        .postgres_data_dir_path
+        .join("base")
+        .join("pgsql_tmp");
+    fs::create_dir_all(&path)?;
+    Ok(path)
+}
+
+/// The buffered documents of an index, indexed with its schema and tokenizers so that the
+/// owning transaction can search them alongside the committed index. It's built at the first
+/// search of the transaction, and each later search only adds the documents that were
+/// buffered since. It's kept in memory, unless the documents were spilled to disk.
+struct PendingIndex {
+    writer: IndexWriter,
+    reader: IndexReader,
+    /// How many of the buffered documents have been added to the index.
+    indexed: usize,
+    /// The temporary directory of the index, if it's on disk.
+    dir: Option<TempDir>,
+}
+
+impl PendingIndex {
+    fn new(
+        directory: &WriterDirectory,
+        new_index: impl FnOnce(Box<dyn Directory>) -> Result<Index, TantivyError>,
+        on_disk: bool,
+    ) -> Result<Self, PendingInsertsError> {
+        let (index, dir) = if on_disk {
+            let dir = TempDir::new_in(spill_dir_path(directory)?)?;
+            let directory = MmapDirectory::open(dir.path()).map_err(TantivyError::from)?;
+            (new_index(Box::new(directory))?, Some(dir))
+        } else {
+            (new_index(Box::new(RamDirectory::create()))?, None)
+        };
+        Ok(Self {
+            writer: index.writer(PENDING_TANTIVY_MEMORY_BUDGET)?,
+            reader: index
+                .reader_builder()
+                .reload_policy(tantivy::ReloadPolicy::Manual)
+                .try_into()?,
+            indexed: 0,
+            dir,
+        })
+    }
+
+    fn add(&mut self, pending: PendingDocument) -> Result<(), TantivyError> {
+        // An UPDATE of a row inserted earlier in the transaction replaces it, so that the
+        // row isn't found twice.
+        if let Some(key_term) = pending.document.key_term() {
+            self.writer.delete_term(key_term);
+        }
+        self.writer.add_document(pending.document.into())?;
+        self.indexed += 1;
+        Ok(())
+    }
+
+    /// Add the documents buffered since the last search, and return a searcher of them all.
+    fn searcher(&mut self, buffer: &mut PendingBuffer) -> Result<Searcher, PendingInsertsError> {
+        let indexed = self.indexed;
+        if indexed < buffer.len() {
+            let mut position = 0;
+            buffer.for_each(|pending| {
+                if position >= indexed {
+                    self.add(pending)?;
+                }
+                position += 1;
+                Ok(())
+            })?;
+            self.writer.commit()?;
+            self.reader.reload()?;
+        }
+        Ok(self.reader.searcher())
+    }
+}
+
+/// The documents taken from the buffer of a transaction that commits, to be sent to the
+/// writer. Only the latest version of each row is kept, see `PendingInserts::latest_versions`.
+pub struct TakenInserts {
+    spilled: Option<SpillFile>,
+    documents: Vec<PendingDocument>,
+    /// The position of the latest version of each row, if some of the documents are on disk.
+    /// The documents in memory are deduplicated at once otherwise.
+    latest: Option<HashMap<Term, usize>>,
+}
+
+impl TakenInserts {
+    fn new(buffer: PendingBuffer) -> Result<Self, PendingInsertsError> {
+        let PendingBuffer {
+            spilled, documents, ..
+        } = buffer;
+        let Some(mut spilled) = spilled else {
+            return Ok(Self {
+                spilled: None,
+                documents: PendingInserts::latest_versions(documents),
+                latest: None,
+            });
+        };
+
+        // Only the keys are kept in memory, to tell the latest versions apart.
+        let mut latest = HashMap::new();
+        let mut position = 0;
+        let mut record = |pending: &PendingDocument| {
+            if let Some(key) = pending.document.key_term() {
+                latest.insert(key, position);
+            }
+            position += 1;
+        };
+        for batch in spilled.batches()? {
+            batch?.iter().for_each(&mut record);
+        }
+        documents.iter().for_each(record);
+
+        Ok(Self {
+            spilled: Some(spilled),
+            documents,
+            latest: Some(latest),
+        })
+    }
+
+    /// Call `visit` with the documents in batches, in the order they were buffered. It can be
+    /// called again, to send the documents again after the writer restarted.
+    pub fn for_each_batch<E: From<io::Error>>(
+        &mut self,
+        mut visit: impl FnMut(Vec<PendingDocument>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let Some(latest) = &self.latest else {
+            return visit(self.documents.clone());
+        };
+
+        let mut position = 0;
+        let mut latest_only = |batch: Vec<PendingDocument>| -> Vec<PendingDocument> {
+            batch
+                .into_iter()
+                .filter(|pending| {
+                    let is_latest = pending
+                        .document
+                        .key_term()
+                        .map_or(true, |key| latest.get(&key) == Some(&position));
+                    position += 1;
+                    is_latest
+                })
+                .collect()
+        };
+        if let Some(spilled) = &mut self.spilled {
+            for batch in spilled.batches()? {
+                visit(latest_only(batch?))?;
+            }
+        }
+        visit(latest_only(self.documents.clone()))
+    }
+
+    /// All the documents at once, which reads the ones spilled to disk back into memory.
+    pub fn into_vec(mut self) -> io::Result<Vec<PendingDocument>> {
+        let mut documents = vec![];
+        self.for_each_batch(|batch| {
+            documents.extend(batch);
+            Ok::<_, io::Error>(())
+        })?;
+        Ok(documents)
+    }
+}
+
+pub struct PendingInserts {}
+
+impl PendingInserts {
+    /// Buffer a document until the end of the current transaction. The subtransaction id is
+    /// kept so that a `ROLLBACK TO SAVEPOINT` can discard the documents inserted after it.
+    pub fn push(
+        directory: &WriterDirectory,
+        pending: PendingDocument,
+    ) -> Result<(), PendingInsertsError> {
+        Self::extend(directory, [pending])
+    }
+
+    /// Buffer a batch of documents at once, as accumulated by a multi-row insert or COPY.
+    pub fn extend(
+        directory: &WriterDirectory,
+        documents: impl IntoIterator<Item = PendingDocument>,
+    ) -> Result<(), PendingInsertsError> {
+        PENDING_INSERTS
+            .lock()?
+            .entry(directory.clone())
+            .or_default()
+            .extend(documents);
+        Ok(())
+    }
+
+    /// Move the buffered documents to temporary files once the buffers of the transaction,
+    /// across all indexes, are larger than `paradedb.pending_inserts_memory_limit`. Documents
+    /// are buffered in batches, so the limit can be exceeded by a batch before this is checked.
+    pub fn spill_over_memory_limit() -> Result<(), PendingInsertsError> {
+        let limit_mb = SEARCH_GUCS.pending_inserts_memory_limit.get();
+        let mut buffers = PENDING_INSERTS.lock()?;
+        let bytes: usize = buffers.values().map(|buffer| buffer.bytes).sum();
+        if limit_mb > 0 && bytes > limit_mb as usize * 1024 * 1024 {
+            for (directory, buffer) in buffers.iter_mut() {
+                buffer.spill(directory)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Discard the documents inserted by an aborted subtransaction, across all indexes.
+    ///
+    /// Subtransaction ids are assigned in increasing order, and only the innermost open
+    /// subtransaction can abort. Any document tagged with an id greater than or equal to the
+    /// aborted one was therefore inserted by it or by one of its children.
+    pub fn rollback_subxact(subxact: pg_sys::SubTransactionId) -> Result<(), PendingInsertsError> {
+        for (directory, buffer) in PENDING_INSERTS.lock()?.iter_mut() {
+            buffer.rollback(directory, subxact)?;
+        }
+        Ok(())
+    }
+
+    pub fn register_subxact_callback() {
+        if !SUBXACT_CALLBACK_REGISTERED.swap(true, Ordering::SeqCst) {
+            unsafe {
+                pg_sys::RegisterSubXactCallback(
+                    Some(pending_subxact_callback),
+                    std::ptr::null_mut(),
+                )
+            };
+        }
+    }
+
+    /// A searcher of the documents buffered for this index, to search uncommitted documents
+    /// from within the owning transaction, or `None` if there are none. `new_index` creates
+    /// the empty index that they're added to in the given directory, the first time they're
+    /// searched.
+    pub fn searcher(
+        directory: &WriterDirectory,
+        new_index: impl FnOnce(Box<dyn Directory>) -> Result<Index, TantivyError>,
+    ) -> Result<Option<Searcher>, PendingInsertsError> {
+        let mut buffers = PENDING_INSERTS.lock()?;
+        let Some(buffer) = buffers.get_mut(directory).filter(|buffer| buffer.len() > 0) else {
+            return Ok(None);
+        };
+        let mut pending_index = match buffer.index.take() {
+            Some(pending_index) => pending_index,
+            None => PendingIndex::new(directory, new_index, buffer.spilled.is_some())?,
+        };
+        let searcher = pending_index.searcher(buffer);
+        buffer.index = Some(pending_index);
+        searcher.map(Some)
+    }
+
+    /// A copy of the latest version of each document buffered for this index.
+    pub fn pending(
+        directory: &WriterDirectory,
+    ) -> Result<Vec<PendingDocument>, PendingInsertsError> {
+        let mut documents = vec![];
+        if let Some(buffer) = PENDING_INSERTS.lock()?.get_mut(directory) {
+            buffer.for_each(|pending| {
+                documents.push(pending);
+                Ok(())
+            })?;
+        }
+        Ok(Self::latest_versions(documents))
+    }
+
+    pub fn is_empty(directory: &WriterDirectory) -> Result<bool, PendingInsertsError> {
+        Ok(PENDING_INSERTS
+            .lock()?
+            .get(directory)
+            .map_or(true, |buffer| buffer.len() == 0))
+    }
+
+    /// Remove the buffered documents, so they can be sent to the writer.
+    pub fn take(directory: &WriterDirectory) -> Result<TakenInserts, PendingInsertsError> {
+        let buffer = PENDING_INSERTS
+            .lock()?
+            .remove(directory)
+            .unwrap_or_default();
+        TakenInserts::new(buffer)
+    }
+
+    /// An UPDATE of a row inserted earlier in the same transaction buffers a second document
+    /// with the same key. Only the last one is kept, so that the row isn't indexed twice.
+    /// All versions stay buffered until then, as a `ROLLBACK TO SAVEPOINT` can bring an
+    /// earlier one back.
+    fn latest_versions(documents: Vec<PendingDocument>) -> Vec<PendingDocument> {
+        let mut seen_keys = HashSet::new();
+        let mut latest: Vec<PendingDocument> = documents
+            .into_iter()
+            .rev()
+            .filter(|pending| {
+                pending
+                    .document
+                    .key_term()
+                    .map_or(true, |key| seen_keys.insert(key))
+            })
+            .collect();
+        latest.reverse();
+        latest
+    }
+
+    /// Throw away the buffered documents, called when the owning transaction aborts.
+    pub fn discard(directory: &WriterDirectory) -> Result<(), PendingInsertsError> {
+        PENDING_INSERTS.lock()?.remove(directory);
+        Ok(())
+    }
+}
+
+#[pg_guard]
+unsafe extern "C" fn pending_subxact_callback(
+    event: pg_sys::SubXactEvent,
+    my_subid: pg_sys::SubTransactionId,
+    _parent_subid: pg_sys::SubTransactionId,
+    _arg: *mut std::os::raw::c_void,
+) {
+    if event == pg_sys::SubXactEvent_SUBXACT_EVENT_ABORT_SUB {
+        PendingInserts::rollback_subxact(my_subid)
+            .expect("could not discard pending inserts on subtransaction abort");
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PendingInsertsError {
+    #[error("could not lock the pending inserts buffer: {0}")]
+    Lock(String),
+
+    #[error("could not index pending inserts: {0}")]
+    Index(#[from] TantivyError),
+
+    #[error("could not spill pending inserts to disk: {0}")]
+    Spill(#[from] io::Error),
+}
+
+impl<T> From<PoisonError<T>> for PendingInsertsError {
+    fn from(err: PoisonError<T>) -> Self {
+        PendingInsertsError::Lock(format!("{err}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PendingDocument, PendingInserts, PENDING_INSERTS};
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::schema::{SearchDocument, SearchIndexSchema};
+    use crate::writer::WriterRequest;
+    use rstest::*;
+    use tantivy::{Directory, Index, IndexSettings};
+
+    fn pending(subxact: u32, document: &SearchDocument) -> PendingDocument {
+        PendingDocument {
+            subxact,
+            document: document.clone(),
+            upsert: false,
+        }
+    }
+
+    #[rstest]
+    fn test_pending_inserts_take_and_discard(
+        mock_dir: MockWriterDirectory,
+        simple_schema: SearchIndexSchema,
+        simple_doc: SearchDocument,
+    ) {
+        let directory = mock_dir.writer_dir.clone();
+        let mut other_doc = simple_schema.new_document();
+        other_doc.insert(simple_schema.key_field().id, 1i64.into());
+
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        PendingInserts::push(&directory, pending(1, &other_doc)).unwrap();
+        assert_eq!(PendingInserts::pending(&directory).unwrap().len(), 2);
+
+        let taken = PendingInserts::take(&directory)
+            .unwrap()
+            .into_vec()
+            .unwrap();
+        assert_eq!(taken, vec![pending(1, &simple_doc), pending(1, &other_doc)]);
+        assert!(PendingInserts::is_empty(&directory).unwrap());
+
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        PendingInserts::discard(&directory).unwrap();
+        assert!(PendingInserts::is_empty(&directory).unwrap());
+    }
+
+    #[rstest]
+    fn test_pending_inserts_rollback_subxact(
+        mock_dir: MockWriterDirectory,
+        simple_doc: SearchDocument,
+    ) {
+        let directory = mock_dir.writer_dir.clone();
+
+        // One document in the top-level transaction, one in a savepoint, and one
+        // in a savepoint nested inside of it.
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        PendingInserts::push(&directory, pending(2, &simple_doc)).unwrap();
+        PendingInserts::push(&directory, pending(3, &simple_doc)).unwrap();
+
+        // Rolling back the outer savepoint discards the nested one as well.
+        PendingInserts::rollback_subxact(2).unwrap();
+        assert_eq!(
+            PendingInserts::pending(&directory).unwrap(),
+            vec![pending(1, &simple_doc)]
+        );
+
+        PendingInserts::discard(&directory).unwrap();
+    }
+
+    #[rstest]
+    fn test_pending_inserts_latest_version(
+        mock_dir: MockWriterDirectory,
+        simple_schema: SearchIndexSchema,
+        simple_doc: SearchDocument,
+    ) {
+        let directory = mock_dir.writer_dir.clone();
+        let mut updated_doc = simple_doc.clone();
+        updated_doc.insert(simple_schema.fields[2].id, "Updated keyboard".into());
+
+        // A row inserted, then updated, by the same transaction is only indexed once.
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        PendingInserts::push(&directory, pending(1, &updated_doc)).unwrap();
+        assert_eq!(
+            PendingInserts::pending(&directory).unwrap(),
+            vec![pending(1, &updated_doc)]
+        );
+
+        PendingInserts::discard(&directory).unwrap();
+    }
+
+    #[rstest]
+    fn test_pending_inserts_bytes(mock_dir: MockWriterDirectory, simple_doc: SearchDocument) {
+        let directory = mock_dir.writer_dir.clone();
+        let bytes = || PENDING_INSERTS.lock().unwrap()[&directory].bytes;
+
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        let document_bytes = bytes();
+        assert!(document_bytes > 0);
+        PendingInserts::push(&directory, pending(2, &simple_doc)).unwrap();
+        assert_eq!(bytes(), 2 * document_bytes);
+
+        // Rolled back documents no longer count against the memory limit.
+        PendingInserts::rollback_subxact(2).unwrap();
+        assert_eq!(bytes(), document_bytes);
+
+        PendingInserts::discard(&directory).unwrap();
+    }
+
+    #[rstest]
+    fn test_pending_inserts_searcher(
+        mock_dir: MockWriterDirectory,
+        simple_schema: SearchIndexSchema,
+        simple_doc: SearchDocument,
+    ) {
+        let directory = mock_dir.writer_dir.clone();
+        let new_index = |index_directory: Box<dyn Directory>| -> tantivy::Result<Index> {
+            let mut index = Index::create(
+                index_directory,
+                simple_schema.schema.clone(),
+                IndexSettings::default(),
+            )?;
+            SearchIndex::setup_tokenizers(&mut index, &simple_schema);
+            Ok(index)
+        };
+        let num_docs = || {
+            PendingInserts::searcher(&directory, new_index)
+                .unwrap()
+                .map_or(0, |searcher| searcher.num_docs())
+        };
+        let mut other_doc = simple_schema.new_document();
+        other_doc.insert(simple_schema.key_field().id, 1i64.into());
+        let mut updated_doc = simple_doc.clone();
+        updated_doc.insert(simple_schema.fields[2].id, "Updated keyboard".into());
+
+        assert_eq!(num_docs(), 0);
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        assert_eq!(num_docs(), 1);
+
+        // Later searches add the documents buffered since, and an update of a row replaces
+        // its earlier version.
+        PendingInserts::push(&directory, pending(1, &other_doc)).unwrap();
+        PendingInserts::push(&directory, pending(2, &updated_doc)).unwrap();
+        assert_eq!(num_docs(), 2);
+
+        // Rolling back the update brings the earlier version back.
+        PendingInserts::rollback_subxact(2).unwrap();
+        assert_eq!(num_docs(), 2);
+
+        PendingInserts::discard(&directory).unwrap();
+    }
+
+    #[rstest]
+    fn test_pending_document_requests(
+        mock_dir: MockWriterDirectory,
+        simple_schema: SearchIndexSchema,
+        simple_doc: SearchDocument,
+    ) {
+        let directory = mock_dir.writer_dir.clone();
+        let mut other_doc = simple_schema.new_document();
+        other_doc.insert(simple_schema.key_field().id, 1i64.into());
+        let upsert = PendingDocument {
+            upsert: true,
+            ..pending(1, &other_doc)
+        };
+
+        // Inserts are batched together, without reordering them around upserts.
+        let requests = PendingDocument::into_requests(
+            &directory,
+            vec![
+                pending(1, &simple_doc),
+                pending(1, &simple_doc),
+                upsert,
+                pending(1, &simple_doc),
+            ],
+        );
+        assert_eq!(
+            requests,
+            vec![
+                WriterRequest::InsertMany {
+                    directory: directory.clone(),
+                    documents: vec![simple_doc.clone(), simple_doc.clone()],
+                },
+                WriterRequest::Upsert {
+                    directory: directory.clone(),
+                    document: other_doc,
+                },
+                WriterRequest::InsertMany {
+                    directory: directory.clone(),
+                    documents: vec![simple_doc],
+                },
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_pending_inserts_spill(
+        mock_dir: MockWriterDirectory,
+        simple_schema: SearchIndexSchema,
+        simple_doc: SearchDocument,
+    ) {
+        let directory = mock_dir.writer_dir.clone();
+        let spill = || {
+            let mut buffers = PENDING_INSERTS.lock().unwrap();
+            let buffer = buffers.get_mut(&directory).unwrap();
+            buffer.spill(&directory).unwrap();
+            assert!(buffer.documents.is_empty());
+            assert_eq!(buffer.bytes, 0);
+        };
+        let num_docs = || {
+            PendingInserts::searcher(&directory, |index_directory| {
+                let mut index = Index::create(
+                    index_directory,
+                    simple_schema.schema.clone(),
+                    IndexSettings::default(),
+                )?;
+                SearchIndex::setup_tokenizers(&mut index, &simple_schema);
+                Ok(index)
+            })
+            .unwrap()
+            .map_or(0, |searcher| searcher.num_docs())
+        };
+        let mut other_doc = simple_schema.new_document();
+        other_doc.insert(simple_schema.key_field().id, 1i64.into());
+        let mut updated_doc = simple_doc.clone();
+        updated_doc.insert(simple_schema.fields[2].id, "Updated keyboard".into());
+
+        // Documents moved to disk are still searched, and come before the ones in memory.
+        PendingInserts::push(&directory, pending(1, &simple_doc)).unwrap();
+        PendingInserts::push(&directory, pending(1, &other_doc)).unwrap();
+        spill();
+        assert_eq!(num_docs(), 2);
+        PendingInserts::push(&directory, pending(2, &updated_doc)).unwrap();
+        spill();
+        assert_eq!(num_docs(), 2);
+        assert_eq!(
+            PendingInserts::pending(&directory).unwrap(),
+            vec![pending(1, &other_doc), pending(2, &updated_doc)]
+        );
+
+        // Rolling back a savepoint discards its documents from the disk as well.
+        PendingInserts::rollback_subxact(2).unwrap();
+        assert_eq!(
+            PendingInserts::pending(&directory).unwrap(),
+            vec![pending(1, &simple_doc), pending(1, &other_doc)]
+        );
+
+        // Only the latest version of each row is taken, across the disk and memory.
+        PendingInserts::push(&directory, pending(1, &updated_doc)).unwrap();
+        let taken = PendingInserts::take(&directory)
+            .unwrap()
+            .into_vec()
+            .unwrap();
+        assert_eq!(
+            taken,
+            vec![pending(1, &other_doc), pending(1, &updated_doc)]
+        );
+        assert!(PendingInserts::is_empty(&directory).unwrap());
+    }
+}
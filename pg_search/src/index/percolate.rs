@@ -0,0 +1,348 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use crate::query::SearchQueryInput;
+use crate::schema::{
+    encode_vector, sparse_tokens, sparse_weights_from_json, SearchFieldType, SearchIndexSchema,
+};
+use anyhow::anyhow;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use tantivy::collector::Count;
+use tantivy::schema::{FieldType, OwnedValue};
+use tantivy::{Index, IndexWriter, Searcher};
+
+/// The memory budget of the writer of a percolated document, the smallest tantivy allows.
+const PERCOLATOR_TANTIVY_MEMORY_BUDGET: usize = 15_000_000;
+
+/// A single document, indexed in memory with the schema and tokenizers of an index, to find
+/// the stored queries that match it. This is a search turned around: instead of running one
+/// query against many documents, many queries run against one document, which is what
+/// alerting on new rows needs.
+///
+/// Running every stored query would get slower with each query stored, so each one is stored
+/// along with its anchors (see `anchor_terms`), and only the queries that have no anchors or
+/// share one with the `terms` of the document need to run.
+pub struct Percolator {
+    schema: SearchIndexSchema,
+    index: Index,
+    searcher: Searcher,
+}
+
+impl Percolator {
+    /// Index `document`, a JSON object of column names to values as made by `to_jsonb` of a
+    /// row. Keys that aren't fields of the index are ignored, and arrays add each of their
+    /// values to the field.
+    pub fn new(
+        search_index: &SearchIndex,
+        document: &Map<String, Value>,
+    ) -> Result<Self, SearchIndexError> {
+        let schema = search_index.schema.clone();
+        let mut search_document = schema.new_document();
+        for (name, value) in document {
+            let Some(search_field) = schema.get_search_field(name.as_str()) else {
+                continue;
+            };
+            let values = match value {
+                Value::Array(values) if search_field.type_ != SearchFieldType::Json => {
+                    values.clone()
+                }
+                value => vec![value.clone()],
+            };
+            for value in values.into_iter().filter(|value| !value.is_null()) {
+                let owned_value = field_value(name, &search_field.type_, value)?;
+                search_document.insert(search_field.id, owned_value);
+            }
+        }
+
+        let mut index = Index::create_in_ram(schema.schema.clone());
+        SearchIndex::setup_tokenizers(&mut index, &schema);
+        let mut writer: IndexWriter = index.writer(PERCOLATOR_TANTIVY_MEMORY_BUDGET)?;
+        writer.add_document(search_document.into())?;
+        writer.commit()?;
+        let searcher = SearchIndex::reader(&index)?.searcher();
+
+        Ok(Self {
+            schema,
+            index,
+            searcher,
+        })
+    }
+
+    /// The terms of the text fields of the document as they were indexed, in the same form
+    /// as the anchors of stored queries.
+    pub fn terms(&self) -> Result<BTreeSet<String>, SearchIndexError> {
+        let mut terms = BTreeSet::new();
+        for search_field in &self.schema.fields {
+            let field = search_field.id.0;
+            let field_entry = self.schema.schema.get_field_entry(field);
+            if !matches!(field_entry.field_type(), FieldType::Str(_)) || !field_entry.is_indexed() {
+                continue;
+            }
+            for segment_reader in self.searcher.segment_readers() {
+                let inverted_index = segment_reader.inverted_index(field)?;
+                let mut stream = inverted_index.terms().stream()?;
+                while stream.advance() {
+                    if let Ok(term) = std::str::from_utf8(stream.key()) {
+                        terms.insert(anchor(search_field.name.as_ref(), term));
+                    }
+                }
+            }
+        }
+        Ok(terms)
+    }
+
+    /// Whether the document matches `query`.
+    pub fn matches(&self, query: SearchQueryInput) -> Result<bool, SearchIndexError> {
+        let mut parser = tantivy::query::QueryParser::for_index(
+            &self.index,
+            self.schema
+                .fields
+                .iter()
+                .map(|search_field| search_field.id.0)
+                .collect::<Vec<_>>(),
+        );
+        let query = query.into_tantivy_query(&self.schema, &mut parser)?;
+        Ok(self.searcher.search(&query, &Count)? > 0)
+    }
+}
+
+/// Terms that any document matching `query` must contain at least one of, or None if the
+/// query could match documents without any particular term, as a range or a negation can.
+/// Only terms of text fields are anchors, written as `field:term`, and they're taken as is
+/// from term and phrase queries, which aren't tokenized either.
+pub fn anchor_terms(
+    query: &SearchQueryInput,
+    schema: &SearchIndexSchema,
+) -> Option<BTreeSet<String>> {
+    let is_text = |field: &str| {
+        schema
+            .get_search_field(field)
+            .is_some_and(|search_field| search_field.type_ == SearchFieldType::Text)
+    };
+    // The anchors of a disjunction are the union of the anchors of its clauses, so each
+    // clause needs some.
+    let union = |queries: &[SearchQueryInput]| {
+        let mut anchors = BTreeSet::new();
+        for query in queries {
+            anchors.extend(anchor_terms(query, schema)?);
+        }
+        (!anchors.is_empty()).then_some(anchors)
+    };
+
+    match query {
+        SearchQueryInput::Term {
+            field: Some(field),
+            value: tantivy::schema::Value::Str(value),
+        } if is_text(field) => Some(BTreeSet::from([anchor(field, value)])),
+        SearchQueryInput::TermSet { terms } => {
+            let mut anchors = BTreeSet::new();
+            for (field, value) in terms {
+                match value {
+                    tantivy::schema::Value::Str(value) if is_text(field) => {
+                        anchors.insert(anchor(field, value));
+                    }
+                    _ => return None,
+                }
+            }
+            (!anchors.is_empty()).then_some(anchors)
+        }
+        SearchQueryInput::Phrase { field, phrases, .. } if is_text(field) => phrases
+            .first()
+            .map(|phrase| BTreeSet::from([anchor(field, phrase)])),
+        SearchQueryInput::Boost { query, .. }
+        | SearchQueryInput::ConstScore { query, .. }
+        | SearchQueryInput::Named { query, .. }
+        | SearchQueryInput::RankingProfile { query, .. }
+        | SearchQueryInput::RerankByVector { query, .. } => anchor_terms(query, schema),
+        SearchQueryInput::DisjunctionMax { disjuncts, .. } => union(disjuncts),
+        // A conjunction only needs the anchors of one of its required clauses, and the
+        // smallest set lets the fewest documents through.
+        SearchQueryInput::Boolean { must, .. } if !must.is_empty() => must
+            .iter()
+            .filter_map(|query| anchor_terms(query, schema))
+            .min_by_key(|anchors| anchors.len()),
+        SearchQueryInput::Boolean { should, .. } => union(should),
+        _ => None,
+    }
+}
+
+fn anchor(field: &str, term: &str) -> String {
+    format!("{field}:{term}")
+}
+
+/// The value of a field of the index from the value of a JSON document.
+fn field_value(
+    name: &str,
+    field_type: &SearchFieldType,
+    value: Value,
+) -> Result<OwnedValue, SearchIndexError> {
+    let mismatch = || anyhow!("value {value} of field '{name}' is not a {field_type:?}");
+    Ok(match field_type {
+        SearchFieldType::Text => match &value {
+            Value::String(text) => OwnedValue::Str(text.clone()),
+            _ => return Err(mismatch().into()),
+        },
+        SearchFieldType::I64 => OwnedValue::I64(value.as_i64().ok_or_else(mismatch)?),
+        SearchFieldType::U64 => OwnedValue::U64(value.as_u64().ok_or_else(mismatch)?),
+        SearchFieldType::F64 => OwnedValue::F64(value.as_f64().ok_or_else(mismatch)?),
+        SearchFieldType::Bool => OwnedValue::Bool(value.as_bool().ok_or_else(mismatch)?),
+        SearchFieldType::Json => OwnedValue::from(value),
+        SearchFieldType::Date => {
+            let text = value.as_str().ok_or_else(mismatch)?;
+            // to_jsonb writes timestamps with a time zone in RFC 3339, timestamps without
+            // one as ISO 8601 without an offset, and dates and times in ISO 8601. Times are
+            // indexed on the day of the epoch, as in `datetime_components_to_tantivy_date`.
+            let micros = chrono::DateTime::parse_from_rfc3339(text)
+                .map(|datetime| datetime.timestamp_micros())
+                .or_else(|_| {
+                    chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f")
+                        .map(|datetime| datetime.and_utc().timestamp_micros())
+                })
+                .or_else(|_| {
+                    chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d").map(|date| {
+                        date.and_hms_opt(0, 0, 0)
+                            .unwrap_or_default()
+                            .and_utc()
+                            .timestamp_micros()
+                    })
+                })
+                .or_else(|_| {
+                    chrono::NaiveTime::parse_from_str(text, "%H:%M:%S%.f").map(|time| {
+                        chrono::NaiveDateTime::UNIX_EPOCH
+                            .date()
+                            .and_time(time)
+                            .and_utc()
+                            .timestamp_micros()
+                    })
+                })
+                .map_err(|_| mismatch())?;
+            OwnedValue::Date(tantivy::DateTime::from_timestamp_micros(micros))
+        }
+        SearchFieldType::Vector => {
+            let vector = value
+                .as_array()
+                .and_then(|elements| {
+                    elements
+                        .iter()
+                        .map(|element| element.as_f64().map(|element| element as f32))
+                        .collect::<Option<Vec<_>>>()
+                })
+                .ok_or_else(mismatch)?;
+            OwnedValue::Bytes(encode_vector(&vector))
+        }
+        SearchFieldType::Sparse => {
+            let weights = sparse_weights_from_json(&value).map_err(|_| mismatch())?;
+            OwnedValue::PreTokStr(sparse_tokens(&weights).map_err(|err| anyhow!("{err}"))?)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{anchor_terms, Percolator};
+    use crate::fixtures::*;
+    use crate::query::SearchQueryInput;
+    use rstest::*;
+    use serde_json::json;
+    use std::collections::BTreeSet;
+    use tantivy::schema::Value;
+
+    fn term(field: &str, value: &str) -> SearchQueryInput {
+        SearchQueryInput::Term {
+            field: Some(field.into()),
+            value: Value::Str(value.into()),
+        }
+    }
+
+    #[rstest]
+    fn test_percolate(default_index: MockSearchIndex) {
+        let search_index = default_index.index;
+        let document = json!({
+            "id": 1,
+            "description": "Ergonomic metal keyboard",
+            "rating": 4,
+            "in_stock": true,
+            "unindexed": "ignored"
+        });
+        let percolator = Percolator::new(search_index, document.as_object().unwrap()).unwrap();
+
+        let terms = percolator.terms().unwrap();
+        assert!(terms.contains("description:keyboard"));
+        assert!(terms.contains("description:ergonomic"));
+        assert!(!terms.contains("description:Ergonomic"));
+
+        assert!(percolator.matches(term("description", "keyboard")).unwrap());
+        assert!(!percolator.matches(term("description", "shoes")).unwrap());
+        assert!(percolator
+            .matches(term("description", "metal") & !term("description", "plastic"))
+            .unwrap());
+        assert!(!percolator
+            .matches(term("description", "metal") & term("description", "shoes"))
+            .unwrap());
+    }
+
+    #[rstest]
+    fn test_anchor_terms(default_index: MockSearchIndex) {
+        let schema = &default_index.index.schema;
+
+        assert_eq!(
+            anchor_terms(&term("description", "keyboard"), schema),
+            Some(BTreeSet::from(["description:keyboard".to_string()]))
+        );
+        // Conjunctions keep the anchors of one clause, disjunctions need all of them.
+        assert_eq!(
+            anchor_terms(
+                &(term("description", "keyboard") & !term("category", "electronics")),
+                schema
+            ),
+            Some(BTreeSet::from(["description:keyboard".to_string()]))
+        );
+        assert_eq!(
+            anchor_terms(
+                &(term("description", "keyboard") | term("category", "electronics")),
+                schema
+            ),
+            Some(BTreeSet::from([
+                "category:electronics".to_string(),
+                "description:keyboard".to_string()
+            ]))
+        );
+        assert_eq!(
+            anchor_terms(
+                &(term("description", "keyboard") | SearchQueryInput::All),
+                schema
+            ),
+            None
+        );
+        assert_eq!(
+            anchor_terms(&!term("description", "keyboard"), schema),
+            None
+        );
+        assert_eq!(
+            anchor_terms(
+                &SearchQueryInput::Term {
+                    field: Some("in_stock".into()),
+                    value: Value::Bool(true)
+                },
+                schema
+            ),
+            None
+        );
+    }
+}
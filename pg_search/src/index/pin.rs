@@ -0,0 +1,88 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::writer::WriterDirectory;
+use once_cell::sync::Lazy;
+use pgrx::pg_sys;
+use std::collections::HashMap;
+use std::sync::{Mutex, PoisonError};
+use tantivy::Searcher;
+
+/// The searchers pinned by this backend, by index.
+static PINNED_SEARCHERS: Lazy<Mutex<HashMap<WriterDirectory, (SnapshotScope, Searcher)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The span during which searches must see the same generation of an index. Like the heap
+/// snapshot that search results are checked against, it's the whole transaction at the
+/// REPEATABLE READ and SERIALIZABLE isolation levels, and each statement otherwise.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct SnapshotScope {
+    local_xid: pg_sys::LocalTransactionId,
+    statement_start: Option<pg_sys::TimestampTz>,
+}
+
+impl SnapshotScope {
+    fn current() -> Self {
+        unsafe {
+            let statement_start = if pg_sys::XactIsoLevel >= pg_sys::XACT_REPEATABLE_READ as i32 {
+                None
+            } else {
+                Some(pg_sys::GetCurrentStatementStartTimestamp())
+            };
+            Self {
+                local_xid: (*pg_sys::MyProc).lxid,
+                statement_start,
+            }
+        }
+    }
+}
+
+/// Pins one searcher per index and snapshot, so that the searches of a transaction don't see
+/// the index change between them when another transaction commits to it.
+pub struct SearcherPin;
+
+impl SearcherPin {
+    /// The searcher pinned for the current snapshot. If there is none, the one returned by
+    /// `open` is pinned.
+    pub fn get_or_pin<E>(
+        directory: &WriterDirectory,
+        open: impl FnOnce() -> Result<Searcher, E>,
+    ) -> Result<Searcher, E> {
+        let scope = SnapshotScope::current();
+        let mut pinned = PINNED_SEARCHERS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some((pinned_scope, searcher)) = pinned.get(directory) {
+            if *pinned_scope == scope {
+                return Ok(searcher.clone());
+            }
+        }
+
+        let searcher = open()?;
+        pinned.insert(directory.clone(), (scope, searcher.clone()));
+        Ok(searcher)
+    }
+
+    /// Release the pinned searcher of an index. A transaction that commits its own changes to
+    /// the index must see them in its next searches.
+    pub fn unpin(directory: &WriterDirectory) {
+        PINNED_SEARCHERS
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(directory);
+    }
+}
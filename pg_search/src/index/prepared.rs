@@ -0,0 +1,415 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::index::encryption::{self, EncryptionError, EncryptionKey};
+use crate::index::pending::PendingDocument;
+use crate::writer::{
+    ClientError, PreparedInsertsDirPath, SearchDirectoryError, SearchFs, WriterClient,
+    WriterDirectory, WriterRequest, WriterTransferPipeFilePath,
+};
+use fs2::FileExt;
+use pgrx::{pg_sys, spi, IntoDatum, PgBuiltInOids, PgRelation, Spi};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+static PREPARED_INSERTS_FILE_EXTENSION: &str = "bin";
+
+/// The outcome of a prepared transaction, as far as its index inserts are concerned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreparedStatus {
+    /// Still waiting on `COMMIT PREPARED` or `ROLLBACK PREPARED`.
+    InProgress,
+    Committed,
+    Aborted,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PreparedInsertsFile {
+    xid: u32,
+    documents: Vec<PendingDocument>,
+}
+
+/// Inserts buffered by a transaction that went through `PREPARE TRANSACTION`.
+///
+/// A prepared transaction outlives the backend that prepared it, and can be committed from
+/// any other connection, or even after a server restart. The pending inserts are therefore
+/// written to a file next to the index, named after the transaction id. Once Postgres reports
+/// the transaction as committed, they're applied by the next transaction committing changes
+/// to the index, ahead of its own, or by the next search or vacuum of the index. The file is
+/// encrypted if the index is, see `EncryptionKey`.
+pub struct PreparedInserts {}
+
+impl PreparedInserts {
+    /// Persist the documents of a transaction being prepared.
+    pub fn persist(
+        directory: &WriterDirectory,
+        xid: u32,
+        documents: Vec<PendingDocument>,
+        encrypted: bool,
+    ) -> Result<(), PreparedInsertsError> {
+        if documents.is_empty() {
+            return Ok(());
+        }
+
+        let PreparedInsertsDirPath(dir_path) = directory.prepared_inserts_dir_path(true)?;
+        let file_path = Self::file_path(&dir_path, xid);
+        let mut serialized = bincode::serialize(&PreparedInsertsFile { xid, documents })?;
+        if encrypted {
+            serialized = encryption::encrypt(&EncryptionKey::load()?, &serialized)?;
+        }
+
+        // Write to a temporary file first, so that a crash can't leave a truncated file behind.
+        // The directory is removed once everything in it is resolved, so it's created again if
+        // that just happened.
+        let temp_path = file_path.with_extension("tmp");
+        let mut file = match File::create(&temp_path) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                directory.prepared_inserts_dir_path(true)?;
+                File::create(&temp_path)?
+            }
+            result => result?,
+        };
+        file.write_all(&serialized)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &file_path)?;
+
+        Ok(())
+    }
+
+    /// Whether the index has prepared transactions left to resolve. As their directory is
+    /// removed once they're all resolved, this doesn't need to list it, so that searches can
+    /// check it cheaply.
+    pub fn any(directory: &WriterDirectory) -> Result<bool, PreparedInsertsError> {
+        // Avoid creating the directory, most indexes never see a prepared transaction.
+        if !directory.exists()? {
+            return Ok(false);
+        }
+        let PreparedInsertsDirPath(dir_path) = directory.prepared_inserts_dir_path(false)?;
+        Ok(dir_path.exists())
+    }
+
+    /// Apply the inserts of committed prepared transactions, and throw away the inserts of
+    /// aborted ones. Returns the number of prepared transactions that were resolved.
+    pub fn resolve<W: WriterClient<WriterRequest>>(
+        directory: &WriterDirectory,
+        client: &mut W,
+    ) -> Result<usize, PreparedInsertsError> {
+        if !Self::any(directory)? {
+            return Ok(0);
+        }
+        let live_rows = LiveRows::open(directory)?;
+        Self::resolve_with(directory, client, Self::transaction_status, |ctid| {
+            live_rows.contains(ctid)
+        })
+    }
+
+    /// Resolve the prepared transactions of the index in the order of their ids. They may have
+    /// committed in another order, but the rows that a later transaction updated or deleted
+    /// are no longer live, and their documents are skipped. A document can therefore neither
+    /// replace the document of a newer version of its row, nor bring back a deleted row, and
+    /// the index ends up with the latest version of each row whatever the order.
+    pub fn resolve_with<W: WriterClient<WriterRequest>>(
+        directory: &WriterDirectory,
+        client: &mut W,
+        status: impl Fn(u32) -> PreparedStatus,
+        is_live: impl Fn(u64) -> bool,
+    ) -> Result<usize, PreparedInsertsError> {
+        if !Self::any(directory)? {
+            return Ok(0);
+        }
+        let PreparedInsertsDirPath(dir_path) = directory.prepared_inserts_dir_path(false)?;
+
+        let mut xids = vec![];
+        for entry in fs::read_dir(&dir_path)? {
+            let file_path = entry?.path();
+            if file_path.extension().and_then(|ext| ext.to_str())
+                != Some(PREPARED_INSERTS_FILE_EXTENSION)
+            {
+                continue;
+            }
+            if let Some(xid) = file_path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u32>().ok())
+            {
+                xids.push(xid);
+            }
+        }
+        xids.sort_unstable();
+
+        let mut resolved = 0;
+        for xid in xids {
+            let file_path = Self::file_path(&dir_path, xid);
+
+            // Several connections may try to resolve the same transaction at once, so we take
+            // a lock on the file and make sure it's still around before applying it.
+            let file = match File::open(&file_path) {
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                result => result?,
+            };
+            file.lock_exclusive()?;
+            if !file_path.exists() {
+                continue;
+            }
+
+            let mut serialized = fs::read(&file_path)?;
+            if encryption::is_encrypted(&serialized) {
+                serialized = encryption::decrypt(&EncryptionKey::load()?, serialized)?;
+            }
+            let PreparedInsertsFile { xid, documents } = bincode::deserialize(&serialized)?;
+
+            match status(xid) {
+                PreparedStatus::InProgress => continue,
+                PreparedStatus::Committed => {
+                    let documents = documents
+                        .into_iter()
+                        .filter(|pending| pending.document.ctid().map_or(true, &is_live));
+                    let WriterTransferPipeFilePath(pipe_path) =
+                        directory.writer_transfer_pipe_path(true)?;
+                    for request in PendingDocument::into_requests(directory, documents) {
+                        client.transfer(&pipe_path, &request)?;
+                    }
+                    client.request(WriterRequest::Commit {
+                        directory: directory.clone(),
+                        pid: std::process::id(),
+                    })?;
+                }
+                PreparedStatus::Aborted => {}
+            }
+
+            fs::remove_file(&file_path)?;
+            resolved += 1;
+        }
+
+        // Only succeeds if no transaction is left to resolve, and none was just prepared.
+        fs::remove_dir(&dir_path).ok();
+
+        Ok(resolved)
+    }
+
+    /// Prepared transactions are reported as in progress until they are committed or
+    /// rolled back, just like regular running transactions.
+    fn transaction_status(xid: u32) -> PreparedStatus {
+        let xid = pg_sys::TransactionId::from_inner(xid);
+        unsafe {
+            if pg_sys::TransactionIdIsInProgress(xid) {
+                PreparedStatus::InProgress
+            } else if pg_sys::TransactionIdDidCommit(xid) {
+                PreparedStatus::Committed
+            } else {
+                // Aborted, or crashed before it could resolve.
+                PreparedStatus::Aborted
+            }
+        }
+    }
+
+    fn file_path(dir_path: &Path, xid: u32) -> PathBuf {
+        dir_path.join(format!("{xid}.{PREPARED_INSERTS_FILE_EXTENSION}"))
+    }
+}
+
+/// The rows of the table of an index, as seen by a new snapshot, to tell whether the row of
+/// a document was updated or deleted since its prepared transaction committed.
+struct LiveRows {
+    heap_relation: PgRelation,
+    num_blocks: pg_sys::BlockNumber,
+}
+
+impl LiveRows {
+    fn open(directory: &WriterDirectory) -> Result<Self, PreparedInsertsError> {
+        let heap_oid = Spi::get_one_with_args::<pg_sys::Oid>(
+            "SELECT i.indrelid FROM pg_index i \
+             JOIN pg_class c ON c.oid = i.indexrelid \
+             WHERE c.relname = $1 LIMIT 1",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                directory.index_name.clone().into_datum(),
+            )],
+        )?
+        .ok_or_else(|| PreparedInsertsError::IndexNotFound(directory.index_name.clone()))?;
+
+        let heap_relation =
+            unsafe { PgRelation::with_lock(heap_oid, pg_sys::AccessShareLock as pg_sys::LOCKMODE) };
+        let num_blocks = unsafe {
+            pg_sys::RelationGetNumberOfBlocksInFork(
+                heap_relation.as_ptr(),
+                pg_sys::ForkNumber_MAIN_FORKNUM,
+            )
+        };
+        Ok(Self {
+            heap_relation,
+            num_blocks,
+        })
+    }
+
+    /// Whether the row is visible to the latest snapshot. The table is read directly rather
+    /// than queried, so that neither the privileges of the current role nor row level
+    /// security policies get in the way.
+    fn contains(&self, ctid: u64) -> bool {
+        // The block number is in the upper half, see `pgrx::item_pointer_to_u64`. A vacuum
+        // may have truncated the table past the row since.
+        if (ctid >> 32) as pg_sys::BlockNumber >= self.num_blocks {
+            return false;
+        }
+        let mut tid = pg_sys::ItemPointerData::default();
+        pgrx::u64_to_item_pointer(ctid, &mut tid);
+
+        unsafe {
+            let relation = self.heap_relation.as_ptr();
+            let fetch_row_version = (*(*relation).rd_tableam)
+                .tuple_fetch_row_version
+                .expect("table access method should fetch rows");
+            let snapshot = pg_sys::RegisterSnapshot(pg_sys::GetLatestSnapshot());
+            let slot = pg_sys::table_slot_create(relation, std::ptr::null_mut());
+            let live = fetch_row_version(relation, &mut tid, snapshot, slot);
+            pg_sys::ExecDropSingleTupleTableSlot(slot);
+            pg_sys::UnregisterSnapshot(snapshot);
+            live
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum PreparedInsertsError {
+    #[error(transparent)]
+    WriterDirectoryError(#[from] SearchDirectoryError),
+
+    #[error(transparent)]
+    WriterClientError(#[from] ClientError),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+
+    #[error(transparent)]
+    EncryptionError(#[from] EncryptionError),
+
+    #[error(transparent)]
+    SpiError(#[from] spi::Error),
+
+    #[error("the table of index {0} could not be found")]
+    IndexNotFound(String),
+
+    #[error("could not serialize prepared inserts: {0}")]
+    BincodeError(#[from] bincode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PreparedInserts, PreparedStatus};
+    use crate::fixtures::*;
+    use crate::index::pending::PendingDocument;
+    use crate::schema::SearchDocument;
+    use crate::writer::{ClientError, PreparedInsertsDirPath, WriterClient, WriterRequest};
+    use rstest::*;
+    use tantivy::TantivyDocument;
+
+    /// Records requests instead of sending them, so we can check what reached the writer.
+    #[derive(Default)]
+    struct RecordingClient {
+        requests: Vec<WriterRequest>,
+    }
+
+    impl WriterClient<WriterRequest> for RecordingClient {
+        fn request(&mut self, request: WriterRequest) -> Result<(), ClientError> {
+            self.requests.push(request);
+            Ok(())
+        }
+
+        fn transfer<P: AsRef<std::path::Path>>(
+            &mut self,
+            _pipe_path: P,
+            request: &WriterRequest,
+        ) -> Result<(), ClientError> {
+            self.request(request.clone())
+        }
+    }
+
+    #[rstest]
+    fn test_prepared_inserts_resolve(mock_dir: MockWriterDirectory, simple_doc: SearchDocument) {
+        let directory = mock_dir.writer_dir.clone();
+        let mut client = RecordingClient::default();
+        let pending = PendingDocument {
+            subxact: 1,
+            document: simple_doc.clone(),
+            upsert: false,
+        };
+        // A row that was updated or deleted after its transaction committed.
+        let mut replaced = SearchDocument {
+            doc: TantivyDocument::new(),
+            key: simple_doc.key,
+            ctid: simple_doc.ctid,
+        };
+        replaced.insert(simple_doc.ctid, 7u64.into());
+        let replaced = PendingDocument {
+            subxact: 1,
+            document: replaced,
+            upsert: true,
+        };
+
+        PreparedInserts::persist(&directory, 100, vec![pending.clone(), replaced], false).unwrap();
+        PreparedInserts::persist(&directory, 101, vec![pending.clone()], false).unwrap();
+        PreparedInserts::persist(&directory, 102, vec![pending], false).unwrap();
+        assert!(PreparedInserts::any(&directory).unwrap());
+
+        let is_live = |ctid| ctid != 7;
+        let resolved = PreparedInserts::resolve_with(
+            &directory,
+            &mut client,
+            |xid| match xid {
+                100 => PreparedStatus::Committed,
+                101 => PreparedStatus::Aborted,
+                _ => PreparedStatus::InProgress,
+            },
+            is_live,
+        )
+        .unwrap();
+        assert_eq!(resolved, 2);
+
+        // Only the live row of the committed transaction reached the writer, followed by a
+        // commit.
+        assert_eq!(
+            client.requests,
+            vec![
+                WriterRequest::InsertMany {
+                    directory: directory.clone(),
+                    documents: vec![simple_doc],
+                },
+                WriterRequest::Commit {
+                    directory: directory.clone(),
+                    pid: std::process::id(),
+                },
+            ]
+        );
+
+        // The transaction that is still prepared is kept around.
+        let PreparedInsertsDirPath(dir_path) = directory.prepared_inserts_dir_path(false).unwrap();
+        assert_eq!(std::fs::read_dir(dir_path).unwrap().count(), 1);
+
+        // Once it's resolved, there's nothing left for searches to check.
+        PreparedInserts::resolve_with(
+            &directory,
+            &mut client,
+            |_| PreparedStatus::Aborted,
+            is_live,
+        )
+        .unwrap();
+        assert!(!PreparedInserts::any(&directory).unwrap());
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::{ereport, PgLogLevel, PgSqlErrorCode};
+use std::fmt::Write;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// Where the time of a search went. Searches slower than `paradedb.log_min_search_duration`
+/// are logged with their profile, so that slow queries can be found in the Postgres logs.
+#[derive(Debug, Default)]
+pub struct SearchProfile {
+    /// Time spent turning the search config into a Tantivy query.
+    parse: Duration,
+    /// Time spent creating the weights of the query, which reads the term statistics needed
+    /// to score it.
+    weight: Mutex<Duration>,
+    /// Segments are collected in parallel, so they record their profile concurrently.
+    segments: Mutex<Vec<SegmentProfile>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentProfile {
+    pub segment_ord: u32,
+    pub duration: Duration,
+    /// The number of results collected from the segment, before they are merged with the
+    /// results of the other segments.
+    pub hits: usize,
+}
+
+impl SearchProfile {
+    pub fn new(parse: Duration) -> Self {
+        Self {
+            parse,
+            ..Self::default()
+        }
+    }
+
+    pub fn record_weight(&self, duration: Duration) {
+        *self.weight.lock().unwrap_or_else(PoisonError::into_inner) += duration;
+    }
+
+    pub fn record_segment(&self, segment_ord: u32, duration: Duration, hits: usize) {
+        self.segments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(SegmentProfile {
+                segment_ord,
+                duration,
+                hits,
+            });
+    }
+
+    /// Log the profile of a search that took `total`, in the standard Postgres log.
+    pub fn report(&self, index_name: &str, query: &str, total: Duration) {
+        ereport!(
+            PgLogLevel::LOG,
+            PgSqlErrorCode::ERRCODE_SUCCESSFUL_COMPLETION,
+            format!(
+                "bm25 search on index \"{index_name}\" took {:.3} ms",
+                millis(total)
+            ),
+            format!("{} query: {query}", self.detail())
+        );
+    }
+
+    fn detail(&self) -> String {
+        let mut segments = self
+            .segments
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        segments.sort_by_key(|segment| segment.segment_ord);
+
+        let collect: Duration = segments.iter().map(|segment| segment.duration).sum();
+        let hits: usize = segments.iter().map(|segment| segment.hits).sum();
+        let mut detail = format!(
+            "parse: {:.3} ms, weight: {:.3} ms, collect: {:.3} ms, hits: {hits}.",
+            millis(self.parse),
+            millis(*self.weight.lock().unwrap_or_else(PoisonError::into_inner)),
+            millis(collect),
+        );
+        for segment in segments {
+            let _ = write!(
+                detail,
+                " segment {}: {:.3} ms, {} hits.",
+                segment.segment_ord,
+                millis(segment.duration),
+                segment.hits
+            );
+        }
+        detail
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SearchProfile;
+    use std::time::Duration;
+
+    #[test]
+    fn test_profile_detail() {
+        let profile = SearchProfile::new(Duration::from_micros(250));
+        profile.record_weight(Duration::from_millis(1));
+        // Segments can finish in any order, but are reported in segment order.
+        profile.record_segment(1, Duration::from_millis(3), 2);
+        profile.record_segment(0, Duration::from_millis(5), 10);
+
+        assert_eq!(
+            profile.detail(),
+            "parse: 0.250 ms, weight: 1.000 ms, collect: 8.000 ms, hits: 12. \
+             segment 0: 5.000 ms, 10 hits. segment 1: 3.000 ms, 2 hits."
+        );
+    }
+}
@@ -0,0 +1,156 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::writer::{
+    BuildProgressFilePath, SearchDirectoryError, SearchFs, TantivyDirPath, WriterDirectory,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// PROGRESS_CREATEIDX_SUBPHASE_INITIALIZE in commands/progress.h.
+const PROGRESS_CREATEIDX_SUBPHASE_INITIALIZE: i64 = 1;
+
+/// The phases of an index build, after Postgres' own initialization. They're reported as
+/// the subphases of the `building index` phase of `pg_stat_progress_create_index`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BuildPhase {
+    /// Rows are read from the table and sent to the writer process.
+    #[default]
+    IndexingRows,
+    /// Every row was sent, and the writer commits them when the transaction commits,
+    /// merging segments as it goes.
+    CommittingIndex,
+}
+
+impl BuildPhase {
+    const ALL: [Self; 2] = [Self::IndexingRows, Self::CommittingIndex];
+
+    /// Subphases of index access methods follow PROGRESS_CREATEIDX_SUBPHASE_INITIALIZE.
+    pub fn subphase(&self) -> i64 {
+        PROGRESS_CREATEIDX_SUBPHASE_INITIALIZE + 1 + *self as i64
+    }
+
+    pub fn from_subphase(subphase: i64) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|phase| phase.subphase() == subphase)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::IndexingRows => "indexing rows",
+            Self::CommittingIndex => "committing index",
+        }
+    }
+}
+
+/// The progress of a connection building an index, saved next to the index so that it can
+/// be read from any connection, see `paradedb.index_build_progress`. It's removed once the
+/// transaction of the build ends.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BuildProgress {
+    /// The process id of the connection building the index.
+    pub pid: i32,
+    pub phase: BuildPhase,
+    /// Rows sent to the writer process.
+    pub docs_indexed: u64,
+}
+
+impl BuildProgress {
+    pub fn load(directory: &WriterDirectory) -> Result<Option<Self>, SearchDirectoryError> {
+        let BuildProgressFilePath(path) = directory.build_progress_file_path()?;
+        let serialized = match fs::read_to_string(&path) {
+            Ok(serialized) => serialized,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(SearchDirectoryError::IndexFileRead(
+                    directory.clone(),
+                    path,
+                    err,
+                ))
+            }
+        };
+        serde_json::from_str(&serialized)
+            .map(Some)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let BuildProgressFilePath(path) = directory.build_progress_file_path()?;
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    pub fn clear(directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let BuildProgressFilePath(path) = directory.build_progress_file_path()?;
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Segments the writer flushed to local disk for the index, including those it hasn't
+    /// committed yet. Each segment has exactly one docstore file.
+    pub fn segments_flushed(directory: &WriterDirectory) -> Result<usize, SearchDirectoryError> {
+        let TantivyDirPath(path) = directory.tantivy_dir_path(false)?;
+        let Ok(entries) = fs::read_dir(&path) else {
+            return Ok(0);
+        };
+        Ok(entries
+            .flatten()
+            .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "store"))
+            .count())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildPhase, BuildProgress};
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_build_progress_roundtrip(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert_eq!(BuildProgress::load(&directory).unwrap(), None);
+
+        let progress = BuildProgress {
+            pid: 42,
+            phase: BuildPhase::CommittingIndex,
+            docs_indexed: 1000,
+        };
+        progress.save(&directory).unwrap();
+        assert_eq!(BuildProgress::load(&directory).unwrap(), Some(progress));
+
+        BuildProgress::clear(&directory).unwrap();
+        assert_eq!(BuildProgress::load(&directory).unwrap(), None);
+    }
+
+    #[test]
+    fn test_build_phase_subphases() {
+        for phase in BuildPhase::ALL {
+            assert_eq!(BuildPhase::from_subphase(phase.subphase()), Some(phase));
+        }
+        assert_eq!(BuildPhase::from_subphase(0), None);
+    }
+}
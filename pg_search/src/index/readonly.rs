@@ -0,0 +1,66 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::writer::{ReadOnlyFilePath, SearchDirectoryError, WriterDirectory};
+use std::fs;
+
+/// An index can be made read-only, for instance while it's restored from a backup or while
+/// an incident of the writer is investigated. Searches keep working, but the writer rejects
+/// any change to its documents, and doesn't merge its segments. Like paused maintenance, the
+/// state is kept as a file next to the index, so that it survives restarts of the writer.
+pub struct ReadOnly {}
+
+impl ReadOnly {
+    pub fn set(directory: &WriterDirectory, read_only: bool) -> Result<(), SearchDirectoryError> {
+        let ReadOnlyFilePath(path) = directory.read_only_file_path()?;
+        let result = if read_only {
+            fs::write(path, "")
+        } else {
+            match fs::remove_file(path) {
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+                result => result,
+            }
+        };
+        result.map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    pub fn is_set(directory: &WriterDirectory) -> Result<bool, SearchDirectoryError> {
+        let ReadOnlyFilePath(path) = directory.read_only_file_path()?;
+        Ok(path.exists())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadOnly;
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_read_only(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert!(!ReadOnly::is_set(&directory).unwrap());
+
+        ReadOnly::set(&directory, true).unwrap();
+        assert!(ReadOnly::is_set(&directory).unwrap());
+
+        // Allowing writes twice is harmless.
+        ReadOnly::set(&directory, false).unwrap();
+        ReadOnly::set(&directory, false).unwrap();
+        assert!(!ReadOnly::is_set(&directory).unwrap());
+    }
+}
@@ -0,0 +1,132 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::stats::WriterStatus;
+use crate::writer::{NeedsResyncFilePath, SearchDirectoryError, WriterDirectory};
+use std::fs;
+use std::path::Path;
+
+/// Indexes are kept outside of the WAL, so they aren't replicated to standbys, and a crash can
+/// lose documents that the writer hadn't committed yet. Instead of trying to replay changes,
+/// an index that may have drifted from its table is marked, and rebuilt from the table by the
+/// next connection that uses it. The mark is a file next to the index, so that it survives
+/// restarts until the rebuild commits.
+pub struct Resync {}
+
+impl Resync {
+    pub fn mark(directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let NeedsResyncFilePath(path) = directory.needs_resync_file_path()?;
+        fs::write(path, "")
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    pub fn clear(directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let NeedsResyncFilePath(path) = directory.needs_resync_file_path()?;
+        match fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                Err(SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether the index must be rebuilt before it's used: it was marked, or its files are
+    /// missing, as on a standby promoted from a base backup taken before it was created.
+    pub fn is_needed(directory: &WriterDirectory) -> Result<bool, SearchDirectoryError> {
+        if !directory.exists()? {
+            return Ok(true);
+        }
+        let NeedsResyncFilePath(path) = directory.needs_resync_file_path()?;
+        Ok(path.exists())
+    }
+
+    /// Mark the indexes that can't be trusted when the writer process starts, and return them.
+    /// An index is stale if it was last written on another timeline, which means the cluster
+    /// was promoted from a standby or restored from a backup since, or if the previous writer
+    /// process stopped with documents it hadn't committed.
+    pub fn mark_stale_indexes(
+        postgres_data_dir_path: &Path,
+        timeline: u32,
+    ) -> Result<Vec<WriterDirectory>, SearchDirectoryError> {
+        let mut marked = vec![];
+        for directory in WriterDirectory::list_all(postgres_data_dir_path)? {
+            let status = WriterStatus::load(&directory)?;
+            if Self::is_stale(&status, timeline) {
+                Self::mark(&directory)?;
+                marked.push(directory.clone());
+            }
+
+            // The index will be rebuilt on this timeline, if it isn't already on it.
+            WriterStatus {
+                uncommitted_documents: 0,
+                timeline,
+                ..status
+            }
+            .save(&directory)?;
+        }
+        Ok(marked)
+    }
+
+    fn is_stale(status: &WriterStatus, timeline: u32) -> bool {
+        // Indexes written before timelines were recorded are assumed to be current.
+        let other_timeline = status.timeline != 0 && status.timeline != timeline;
+        other_timeline || status.uncommitted_documents > 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Resync;
+    use crate::fixtures::*;
+    use crate::index::stats::WriterStatus;
+    use crate::writer::SearchFs;
+    use rstest::*;
+
+    #[rstest]
+    fn test_mark_stale_indexes(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        let data_dir = directory.postgres_data_dir_path.clone();
+        directory.tantivy_dir_path(true).unwrap();
+
+        // An index from before timelines were recorded is adopted by the current timeline.
+        assert!(Resync::mark_stale_indexes(&data_dir, 1).unwrap().is_empty());
+        assert_eq!(WriterStatus::load(&directory).unwrap().timeline, 1);
+        assert!(!Resync::is_needed(&directory).unwrap());
+
+        // After a promotion, the index was written on the previous timeline.
+        assert_eq!(
+            Resync::mark_stale_indexes(&data_dir, 2).unwrap(),
+            vec![directory.clone()]
+        );
+        assert!(Resync::is_needed(&directory).unwrap());
+        Resync::clear(&directory).unwrap();
+        assert!(!Resync::is_needed(&directory).unwrap());
+
+        // Documents that weren't committed before a crash are lost.
+        WriterStatus {
+            uncommitted_documents: 5,
+            timeline: 2,
+            ..Default::default()
+        }
+        .save(&directory)
+        .unwrap();
+        assert_eq!(
+            Resync::mark_stale_indexes(&data_dir, 2).unwrap(),
+            vec![directory.clone()]
+        );
+    }
+}
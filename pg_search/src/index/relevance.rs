@@ -0,0 +1,230 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, HashSet};
+
+/// How well a ranking of documents matches the grades a judgment list gives them, to compare
+/// tokenizers, boosts or queries on the same judgments. Documents that aren't in the list
+/// have a grade of 0, and documents with a grade above 0 are relevant.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RankingMetrics {
+    /// Normalized discounted cumulative gain of the top `k` documents: the gain of each
+    /// document, 2^grade - 1, discounted by the log of its rank, relative to the best
+    /// possible ranking of the judged documents. 1 is a perfect ranking.
+    pub ndcg: f64,
+    /// Reciprocal rank of the first relevant document, or 0 if none is ranked.
+    pub mrr: f64,
+    /// Share of the top `k` documents that are relevant.
+    pub precision: f64,
+    /// Share of the relevant documents that are in the top `k`.
+    pub recall: f64,
+}
+
+impl RankingMetrics {
+    /// The metrics of the top `k` keys of `ranked`, given the `grades` of the judgment list
+    /// by key.
+    pub fn new(ranked: &[String], grades: &HashMap<String, f64>, k: usize) -> Self {
+        let grade = |key: &String| grades.get(key).copied().unwrap_or(0.0);
+        let top = &ranked[..ranked.len().min(k)];
+
+        let dcg = discounted_gain(top.iter().map(grade));
+        let mut ideal: Vec<f64> = grades.values().copied().collect();
+        ideal.sort_by(|a, b| b.total_cmp(a));
+        ideal.truncate(k);
+        let ideal_dcg = discounted_gain(ideal.into_iter());
+
+        let relevant = grades.values().filter(|grade| **grade > 0.0).count();
+        let relevant_in_top = top.iter().filter(|key| grade(key) > 0.0).count();
+
+        Self {
+            ndcg: if ideal_dcg > 0.0 {
+                dcg / ideal_dcg
+            } else {
+                0.0
+            },
+            mrr: top
+                .iter()
+                .position(|key| grade(key) > 0.0)
+                .map_or(0.0, |rank| 1.0 / (rank + 1) as f64),
+            precision: if k > 0 {
+                relevant_in_top as f64 / k as f64
+            } else {
+                0.0
+            },
+            recall: if relevant > 0 {
+                relevant_in_top as f64 / relevant as f64
+            } else {
+                0.0
+            },
+        }
+    }
+}
+
+/// How much two rankings of the same query agree, to compare a ranking profile or an index
+/// against another on real queries without judgments.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RankCorrelation {
+    /// Share of the keys of the longer ranking that are in both.
+    pub overlap: f64,
+    /// Kendall's tau of the keys in both rankings: 1 if they're in the same order, -1 if
+    /// they're in reverse order, or None if fewer than two keys are in both.
+    pub kendall_tau: Option<f64>,
+    /// Rank-biased overlap, which weighs agreement at the top of the rankings more, with a
+    /// persistence of `RBO_PERSISTENCE`. 1 for identical rankings, 0 for disjoint ones.
+    pub rbo: f64,
+}
+
+/// The weight of each rank relative to the one before it in rank-biased overlap.
+const RBO_PERSISTENCE: f64 = 0.9;
+
+impl RankCorrelation {
+    pub fn new(a: &[String], b: &[String]) -> Self {
+        let ranks_b: HashMap<&String, usize> = b
+            .iter()
+            .enumerate()
+            .map(|(rank, key)| (key, rank))
+            .collect();
+        let shared: Vec<(usize, usize)> = a
+            .iter()
+            .enumerate()
+            .filter_map(|(rank, key)| ranks_b.get(key).map(|rank_b| (rank, *rank_b)))
+            .collect();
+
+        let longest = a.len().max(b.len());
+        let overlap = if longest > 0 {
+            shared.len() as f64 / longest as f64
+        } else {
+            1.0
+        };
+
+        let kendall_tau = (shared.len() >= 2).then(|| {
+            let mut agreement = 0i64;
+            for (i, (rank_a, rank_b)) in shared.iter().enumerate() {
+                for (other_a, other_b) in &shared[i + 1..] {
+                    let order_a = rank_a.cmp(other_a);
+                    let order_b = rank_b.cmp(other_b);
+                    agreement += if order_a == order_b { 1 } else { -1 };
+                }
+            }
+            let pairs = shared.len() * (shared.len() - 1) / 2;
+            agreement as f64 / pairs as f64
+        });
+
+        Self {
+            overlap,
+            kendall_tau,
+            rbo: rank_biased_overlap(a, b),
+        }
+    }
+}
+
+/// Extrapolated rank-biased overlap of two rankings, down to the depth of the shorter one.
+fn rank_biased_overlap(a: &[String], b: &[String]) -> f64 {
+    let depth = a.len().min(b.len());
+    if depth == 0 {
+        return if a.len() == b.len() { 1.0 } else { 0.0 };
+    }
+
+    let p = RBO_PERSISTENCE;
+    let (mut seen_a, mut seen_b) = (HashSet::new(), HashSet::new());
+    let (mut shared, mut sum) = (0usize, 0.0);
+    for d in 1..=depth {
+        let (key_a, key_b) = (&a[d - 1], &b[d - 1]);
+        if key_a == key_b {
+            shared += 1;
+        } else {
+            shared += seen_b.contains(key_a) as usize + seen_a.contains(key_b) as usize;
+        }
+        seen_a.insert(key_a);
+        seen_b.insert(key_b);
+        sum += (shared as f64 / d as f64) * p.powi(d as i32);
+    }
+    (shared as f64 / depth as f64) * p.powi(depth as i32) + (1.0 - p) / p * sum
+}
+
+fn discounted_gain(grades: impl Iterator<Item = f64>) -> f64 {
+    grades
+        .enumerate()
+        .map(|(rank, grade)| (2f64.powf(grade) - 1.0) / ((rank + 2) as f64).log2())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RankCorrelation, RankingMetrics};
+    use std::collections::HashMap;
+
+    fn keys(keys: &[&str]) -> Vec<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    }
+
+    #[test]
+    fn test_ranking_metrics() {
+        let grades = HashMap::from([
+            ("a".to_string(), 3.0),
+            ("b".to_string(), 1.0),
+            ("c".to_string(), 0.0),
+        ]);
+
+        let perfect = RankingMetrics::new(&keys(&["a", "b", "c"]), &grades, 3);
+        assert_eq!(perfect.ndcg, 1.0);
+        assert_eq!(perfect.mrr, 1.0);
+        assert_eq!(perfect.precision, 2.0 / 3.0);
+        assert_eq!(perfect.recall, 1.0);
+
+        let swapped = RankingMetrics::new(&keys(&["b", "a", "c"]), &grades, 3);
+        let dcg = 1.0 + 7.0 / 3f64.log2();
+        let ideal_dcg = 7.0 + 1.0 / 3f64.log2();
+        assert!((swapped.ndcg - dcg / ideal_dcg).abs() < 1e-9);
+        assert_eq!(swapped.mrr, 1.0);
+
+        // Unjudged documents aren't relevant, and only the top k count.
+        let late = RankingMetrics::new(&keys(&["x", "c", "b", "a"]), &grades, 2);
+        assert_eq!(late.ndcg, 0.0);
+        assert_eq!(late.mrr, 0.0);
+        assert_eq!(late.precision, 0.0);
+        assert_eq!(late.recall, 0.0);
+
+        let second = RankingMetrics::new(&keys(&["x", "b"]), &grades, 2);
+        assert_eq!(second.mrr, 0.5);
+        assert_eq!(second.precision, 0.5);
+        assert_eq!(second.recall, 0.5);
+    }
+
+    #[test]
+    fn test_rank_correlation() {
+        let same = RankCorrelation::new(&keys(&["a", "b", "c"]), &keys(&["a", "b", "c"]));
+        assert_eq!(same.overlap, 1.0);
+        assert_eq!(same.kendall_tau, Some(1.0));
+        assert!((same.rbo - 1.0).abs() < 1e-9);
+
+        let reversed = RankCorrelation::new(&keys(&["a", "b", "c"]), &keys(&["c", "b", "a"]));
+        assert_eq!(reversed.overlap, 1.0);
+        assert_eq!(reversed.kendall_tau, Some(-1.0));
+        assert!(reversed.rbo < same.rbo);
+
+        // Agreeing at the top counts more than agreeing at the bottom.
+        let top = RankCorrelation::new(&keys(&["a", "b", "c"]), &keys(&["a", "c", "b"]));
+        let bottom = RankCorrelation::new(&keys(&["a", "b", "c"]), &keys(&["b", "a", "c"]));
+        assert!(top.rbo > bottom.rbo);
+
+        let disjoint = RankCorrelation::new(&keys(&["a", "b"]), &keys(&["c", "d"]));
+        assert_eq!(disjoint.overlap, 0.0);
+        assert_eq!(disjoint.kendall_tau, None);
+        assert_eq!(disjoint.rbo, 0.0);
+    }
+}
@@ -0,0 +1,798 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use crate::writer::{SearchFs, TantivyDirPath, WriterDirectory};
+use object_store::path::Path as ObjectPath;
+use object_store::{ObjectStore, PutPayload};
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tantivy::directory::error::{DeleteError, LockError, OpenReadError, OpenWriteError};
+use tantivy::directory::{
+    AntiCallToken, DirectoryLock, FileHandle, Lock, MmapDirectory, OwnedBytes, TerminatingWrite,
+    WatchCallback, WatchHandle, WritePtr,
+};
+use tantivy::{Directory, HasLen, SegmentMeta, TantivyError};
+use url::Url;
+
+/// Files are read from object storage, and cached, in blocks of this size.
+const BLOCK_SIZE: usize = 1024 * 1024;
+/// How many blocks are cached between two checks of the size of the cache.
+const EVICTION_CHECK_INTERVAL: u64 = 64;
+static CACHE_DIR_NAME: &str = "remote-cache";
+/// Credentials are read from the environment of the server, like the AWS command line does,
+/// so that they don't end up in the catalog.
+const CREDENTIAL_ENV_PREFIXES: [&str; 3] = ["AWS_", "GOOGLE_", "AZURE_"];
+
+/// Requests to object storage are async, but Tantivy reads files synchronously.
+static RUNTIME: Lazy<tokio::runtime::Runtime> = Lazy::new(|| {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .thread_name("pg-search-remote")
+        .enable_all()
+        .build()
+        .expect("could not start runtime for object storage")
+});
+
+/// Reports the latency of each read of an index file, see `set_read_observer`.
+static READ_OBSERVER: OnceCell<fn(StorageTier, Duration)> = OnceCell::new();
+
+fn default_cache_size_mb() -> u64 {
+    1024
+}
+
+/// Where the files of a segment are read from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StorageTier {
+    /// On local disk.
+    Hot,
+    /// In object storage, through the block cache.
+    Cold,
+}
+
+impl StorageTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hot => "hot",
+            Self::Cold => "cold",
+        }
+    }
+}
+
+/// Set the function told how long each read of an index file in object storage, or of a
+/// hot segment of a tiered index, took. Only the first observer set is kept.
+pub fn set_read_observer(observer: fn(StorageTier, Duration)) {
+    let _ = READ_OBSERVER.set(observer);
+}
+
+fn observe_read(tier: StorageTier, started: Instant) {
+    if let Some(observer) = READ_OBSERVER.get() {
+        observer(tier, started.elapsed());
+    }
+}
+
+/// Object storage holding the segments of an index, set with the `storage` index option.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteStorage {
+    /// Where the files of indexes are kept, like `s3://bucket/prefix`. Each index gets its
+    /// own prefix under it.
+    pub url: String,
+    /// Options of the store, like `region` or `endpoint`, as accepted by `object_store`.
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    /// Megabytes of blocks cached on local disk, shared by every connection.
+    #[serde(default = "default_cache_size_mb")]
+    pub cache_size_mb: u64,
+    /// Seconds after which segments are moved to object storage. Recent segments are the
+    /// most searched, and the most likely to be merged away soon. If 0, and there's no
+    /// `hot_max_size_mb` either, segments are moved as soon as they're written.
+    #[serde(default)]
+    pub hot_max_age_secs: u64,
+    /// Megabytes of segments kept on local disk, beyond which the oldest ones are moved to
+    /// object storage. If 0, only their age is considered.
+    #[serde(default)]
+    pub hot_max_size_mb: u64,
+}
+
+impl RemoteStorage {
+    /// Whether segments are kept on local disk for a while before they're moved to object
+    /// storage, see `RemoteDirectory::move_cold_segments`.
+    pub fn is_tiered(&self) -> bool {
+        self.hot_max_age_secs > 0 || self.hot_max_size_mb > 0
+    }
+
+    fn open_store(
+        &self,
+        directory: &WriterDirectory,
+    ) -> tantivy::Result<(Arc<dyn ObjectStore>, ObjectPath)> {
+        let url = Url::parse(&self.url).map_err(|err| {
+            TantivyError::InvalidArgument(format!("invalid storage url '{}': {err}", self.url))
+        })?;
+        let options = std::env::vars()
+            .filter(|(key, _)| {
+                CREDENTIAL_ENV_PREFIXES
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix))
+            })
+            .map(|(key, value)| (key.to_ascii_lowercase(), value))
+            .chain(self.options.clone());
+        let (store, prefix) = object_store::parse_url_opts(&url, options).map_err(|err| {
+            TantivyError::InvalidArgument(format!("invalid storage url '{}': {err}", self.url))
+        })?;
+
+        let index_prefix = prefix.child(directory.dir_name());
+        Ok((Arc::from(store), index_prefix))
+    }
+
+    /// Remove every file of an index from object storage.
+    pub fn remove_all(&self, directory: &WriterDirectory) -> tantivy::Result<()> {
+        let (store, prefix) = self.open_store(directory)?;
+        RUNTIME.block_on(async {
+            use futures::TryStreamExt;
+            let locations: Vec<ObjectPath> = store
+                .list(Some(&prefix))
+                .map_ok(|meta| meta.location)
+                .try_collect()
+                .await
+                .map_err(to_io_error)?;
+            for location in locations {
+                store.delete(&location).await.map_err(to_io_error)?;
+            }
+            Ok::<_, io::Error>(())
+        })?;
+        Ok(())
+    }
+}
+
+/// A directory whose segment files live in object storage. Files are written locally, then
+/// uploaded and removed once complete, and read back from the store in blocks that are
+/// cached on local disk. Small files written atomically, like the index meta, are kept
+/// locally as well, which lets Tantivy watch them for new commits.
+///
+/// With a tiered storage, files stay on local disk when they're complete, and are moved to
+/// object storage later by the writer. Reads look for a file locally first, so they find it
+/// in either tier.
+#[derive(Clone)]
+pub struct RemoteDirectory {
+    root: PathBuf,
+    tiered: bool,
+    local: MmapDirectory,
+    store: Arc<dyn ObjectStore>,
+    prefix: ObjectPath,
+    cache: Arc<BlockCache>,
+}
+
+impl std::fmt::Debug for RemoteDirectory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteDirectory")
+            .field("store", &self.store.to_string())
+            .field("prefix", &self.prefix)
+            .finish()
+    }
+}
+
+impl RemoteDirectory {
+    pub fn open(
+        path: &Path,
+        storage: &RemoteStorage,
+        directory: &WriterDirectory,
+    ) -> tantivy::Result<Self> {
+        let (store, prefix) = storage.open_store(directory)?;
+        let cache_dir = path.parent().unwrap_or(path).join(CACHE_DIR_NAME);
+        fs::create_dir_all(&cache_dir)?;
+
+        Ok(Self {
+            root: path.to_path_buf(),
+            tiered: storage.is_tiered(),
+            local: MmapDirectory::open(path)?,
+            store,
+            prefix,
+            cache: Arc::new(BlockCache::new(
+                cache_dir,
+                storage.cache_size_mb * 1024 * 1024,
+            )),
+        })
+    }
+
+    fn location(&self, path: &Path) -> ObjectPath {
+        self.prefix.child(path.to_string_lossy().as_ref())
+    }
+
+    fn upload(&self, path: &Path) -> io::Result<()> {
+        let local_path = self.local_path(path);
+        let data = fs::read(&local_path)?;
+        RUNTIME
+            .block_on(self.store.put(&self.location(path), PutPayload::from(data)))
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn local_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+
+    /// Move segments from local disk to object storage, oldest first, once they're older
+    /// than `hot_max_age_secs` or while the local segments take more than `hot_max_size_mb`.
+    /// Only committed segments are moved, as `metas` must be. Returns how many were moved.
+    pub fn move_cold_segments(
+        &self,
+        storage: &RemoteStorage,
+        metas: &[SegmentMeta],
+    ) -> io::Result<usize> {
+        let mut hot = self.hot_segments(metas)?;
+        // The oldest segment is the first to go.
+        hot.sort_by_key(|segment| segment.modified);
+
+        let max_age = Duration::from_secs(storage.hot_max_age_secs);
+        let max_bytes = storage.hot_max_size_mb * 1024 * 1024;
+        let mut hot_bytes: u64 = hot.iter().map(|segment| segment.bytes).sum();
+        let mut moved = 0;
+        for segment in hot {
+            let age = segment.modified.elapsed().unwrap_or_default();
+            let too_old = storage.hot_max_age_secs > 0 && age > max_age;
+            let over_budget = storage.hot_max_size_mb > 0 && hot_bytes > max_bytes;
+            if !too_old && !over_budget {
+                continue;
+            }
+
+            // Connections that already opened a file keep reading their mapping of it, and
+            // the others read it from object storage once it's removed.
+            for file in &segment.files {
+                self.upload(file)?;
+            }
+            for file in &segment.files {
+                fs::remove_file(self.local_path(file))?;
+            }
+            hot_bytes -= segment.bytes;
+            moved += 1;
+        }
+        Ok(moved)
+    }
+
+    /// How many of the segments of `metas` are in each tier, and their bytes.
+    pub fn tier_usage(&self, metas: &[SegmentMeta]) -> io::Result<TierUsage> {
+        let hot = self.hot_segments(metas)?;
+        let mut usage = TierUsage {
+            hot_segments: hot.len(),
+            hot_bytes: hot.iter().map(|segment| segment.bytes).sum(),
+            ..Default::default()
+        };
+
+        let remote_sizes: HashMap<String, u64> = RUNTIME
+            .block_on(async {
+                use futures::TryStreamExt;
+                self.store
+                    .list(Some(&self.prefix))
+                    .map_ok(|meta| {
+                        let name = meta.location.filename().unwrap_or_default().to_string();
+                        (name, meta.size as u64)
+                    })
+                    .try_collect()
+                    .await
+            })
+            .map_err(to_io_error)?;
+        for meta in metas {
+            let files = meta.list_files();
+            if files.iter().any(|file| self.root.join(file).exists()) {
+                continue;
+            }
+            usage.cold_segments += 1;
+            usage.cold_bytes += files
+                .iter()
+                .filter_map(|file| remote_sizes.get(file.to_string_lossy().as_ref()))
+                .sum::<u64>();
+        }
+        Ok(usage)
+    }
+
+    /// The segments with files on local disk, with the time the oldest file was written.
+    fn hot_segments(&self, metas: &[SegmentMeta]) -> io::Result<Vec<HotSegment>> {
+        let mut hot = vec![];
+        for meta in metas {
+            let mut segment = HotSegment {
+                files: vec![],
+                bytes: 0,
+                modified: SystemTime::now(),
+            };
+            for file in meta.list_files() {
+                let metadata = match fs::metadata(self.local_path(&file)) {
+                    Ok(metadata) => metadata,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err),
+                };
+                segment.bytes += metadata.len();
+                segment.modified = segment.modified.min(metadata.modified()?);
+                segment.files.push(file);
+            }
+            if !segment.files.is_empty() {
+                hot.push(segment);
+            }
+        }
+        Ok(hot)
+    }
+
+    fn head(&self, path: &Path) -> Result<usize, OpenReadError> {
+        RUNTIME
+            .block_on(self.store.head(&self.location(path)))
+            .map(|meta| meta.size)
+            .map_err(|err| match err {
+                object_store::Error::NotFound { .. } => {
+                    OpenReadError::FileDoesNotExist(path.to_path_buf())
+                }
+                err => OpenReadError::IoError {
+                    io_error: Arc::new(to_io_error(err)),
+                    filepath: path.to_path_buf(),
+                },
+            })
+    }
+}
+
+impl Directory for RemoteDirectory {
+    fn get_file_handle(&self, path: &Path) -> Result<Arc<dyn FileHandle>, OpenReadError> {
+        // Files that are still being written, were written atomically, or are in the hot
+        // tier are local. They can be moved to object storage at any time, in which case
+        // they're read from there instead.
+        match self.local.get_file_handle(path) {
+            Ok(handle) => return Ok(Arc::new(HotFileHandle { inner: handle })),
+            Err(OpenReadError::FileDoesNotExist(_)) => {}
+            Err(err) => return Err(err),
+        }
+
+        let len = self.head(path)?;
+        Ok(Arc::new(RemoteFileHandle {
+            store: self.store.clone(),
+            location: self.location(path),
+            cache_key: path.to_string_lossy().into_owned(),
+            len,
+            cache: self.cache.clone(),
+        }))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        match self.local.delete(path) {
+            Ok(()) | Err(DeleteError::FileDoesNotExist(_)) => {}
+            Err(err) => return Err(err),
+        }
+        self.cache.remove(&path.to_string_lossy());
+        match RUNTIME.block_on(self.store.delete(&self.location(path))) {
+            Ok(()) | Err(object_store::Error::NotFound { .. }) => Ok(()),
+            Err(err) => Err(DeleteError::IoError {
+                io_error: Arc::new(to_io_error(err)),
+                filepath: path.to_path_buf(),
+            }),
+        }
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, OpenReadError> {
+        if self.local.exists(path)? {
+            return Ok(true);
+        }
+        match self.head(path) {
+            Ok(_) => Ok(true),
+            Err(OpenReadError::FileDoesNotExist(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn open_write(&self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        let inner = self.local.open_write(path)?;
+        if self.tiered {
+            return Ok(inner);
+        }
+        Ok(WritePtr::new(Box::new(UploadOnTerminate {
+            inner,
+            directory: self.clone(),
+            path: path.to_path_buf(),
+        })))
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        match self.local.atomic_read(path) {
+            Err(OpenReadError::FileDoesNotExist(_)) => {}
+            result => return result,
+        }
+        RUNTIME
+            .block_on(async {
+                let result = self.store.get(&self.location(path)).await?;
+                result.bytes().await
+            })
+            .map(|bytes| bytes.to_vec())
+            .map_err(|err| match err {
+                object_store::Error::NotFound { .. } => {
+                    OpenReadError::FileDoesNotExist(path.to_path_buf())
+                }
+                err => OpenReadError::IoError {
+                    io_error: Arc::new(to_io_error(err)),
+                    filepath: path.to_path_buf(),
+                },
+            })
+    }
+
+    fn atomic_write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.local.atomic_write(path, data)?;
+        RUNTIME
+            .block_on(
+                self.store
+                    .put(&self.location(path), PutPayload::from(data.to_vec())),
+            )
+            .map_err(to_io_error)?;
+        Ok(())
+    }
+
+    fn acquire_lock(&self, lock: &Lock) -> Result<DirectoryLock, LockError> {
+        // Locks are only shared by the processes of this server.
+        self.local.acquire_lock(lock)
+    }
+
+    fn sync_directory(&self) -> io::Result<()> {
+        self.local.sync_directory()
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        self.local.watch(watch_callback)
+    }
+}
+
+/// Writes a file locally, and moves it to object storage once it's complete. Tantivy never
+/// changes a file after that.
+struct UploadOnTerminate {
+    inner: WritePtr,
+    directory: RemoteDirectory,
+    path: PathBuf,
+}
+
+impl Write for UploadOnTerminate {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl TerminatingWrite for UploadOnTerminate {
+    fn terminate_ref(&mut self, token: AntiCallToken) -> io::Result<()> {
+        self.inner.terminate_ref(token)?;
+        self.directory.upload(&self.path)?;
+        fs::remove_file(self.directory.local_path(&self.path))
+    }
+}
+
+/// Segments in each tier of an index, see `RemoteDirectory::tier_usage`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TierUsage {
+    pub hot_segments: usize,
+    pub hot_bytes: u64,
+    pub cold_segments: usize,
+    pub cold_bytes: u64,
+}
+
+impl TierUsage {
+    pub fn collect(search_index: &SearchIndex) -> Result<Self, SearchIndexError> {
+        let metas = search_index.underlying_index.searchable_segment_metas()?;
+        match &search_index.storage {
+            Some(storage) => {
+                let TantivyDirPath(path) = search_index.directory.tantivy_dir_path(false)?;
+                let directory = RemoteDirectory::open(&path, storage, &search_index.directory)?;
+                Ok(directory.tier_usage(&metas)?)
+            }
+            // Without object storage, every segment is on local disk.
+            None => {
+                let stats = super::stats::SearchIndexStats::collect(search_index)?;
+                Ok(Self {
+                    hot_segments: stats.segments,
+                    hot_bytes: stats.total_bytes,
+                    ..Default::default()
+                })
+            }
+        }
+    }
+}
+
+struct HotSegment {
+    files: Vec<PathBuf>,
+    bytes: u64,
+    modified: SystemTime,
+}
+
+/// A file on local disk, timed like the files in object storage so that both tiers can be
+/// compared.
+#[derive(Debug)]
+struct HotFileHandle {
+    inner: Arc<dyn FileHandle>,
+}
+
+impl HasLen for HotFileHandle {
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl FileHandle for HotFileHandle {
+    fn read_bytes(&self, range: Range<usize>) -> io::Result<OwnedBytes> {
+        let started = Instant::now();
+        let bytes = self.inner.read_bytes(range)?;
+        observe_read(StorageTier::Hot, started);
+        Ok(bytes)
+    }
+}
+
+#[derive(Debug)]
+struct RemoteFileHandle {
+    store: Arc<dyn ObjectStore>,
+    location: ObjectPath,
+    cache_key: String,
+    len: usize,
+    cache: Arc<BlockCache>,
+}
+
+impl RemoteFileHandle {
+    fn block(&self, block: usize) -> io::Result<Vec<u8>> {
+        if let Some(data) = self.cache.get(&self.cache_key, block) {
+            return Ok(data);
+        }
+
+        let range = block * BLOCK_SIZE..((block + 1) * BLOCK_SIZE).min(self.len);
+        let data = RUNTIME
+            .block_on(self.store.get_range(&self.location, range))
+            .map_err(to_io_error)?
+            .to_vec();
+        self.cache.put(&self.cache_key, block, &data);
+        Ok(data)
+    }
+}
+
+impl HasLen for RemoteFileHandle {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl FileHandle for RemoteFileHandle {
+    fn read_bytes(&self, range: Range<usize>) -> io::Result<OwnedBytes> {
+        let started = Instant::now();
+        let mut buffer = Vec::with_capacity(range.len());
+        for (block, block_range) in block_ranges(range, BLOCK_SIZE) {
+            let data = self.block(block)?;
+            let end = block_range.end.min(data.len());
+            buffer.extend_from_slice(&data[block_range.start.min(end)..end]);
+        }
+        observe_read(StorageTier::Cold, started);
+        Ok(OwnedBytes::new(buffer))
+    }
+}
+
+/// The blocks covering a byte range, with the range to read within each of them.
+pub(super) fn block_ranges(
+    range: Range<usize>,
+    block_size: usize,
+) -> impl Iterator<Item = (usize, Range<usize>)> {
+    let first = range.start / block_size;
+    let last = if range.is_empty() {
+        first
+    } else {
+        range.end.div_ceil(block_size)
+    };
+    (first..last).map(move |block| {
+        let block_start = block * block_size;
+        let start = range.start.max(block_start) - block_start;
+        let end = range.end.min(block_start + block_size) - block_start;
+        (block, start..end)
+    })
+}
+
+/// Blocks of remote files, cached as files on local disk so that they're shared by every
+/// connection and survive restarts. The least recently read blocks are evicted once the
+/// cache grows over its capacity.
+#[derive(Debug)]
+struct BlockCache {
+    dir: PathBuf,
+    capacity_bytes: u64,
+    inserted: AtomicU64,
+}
+
+impl BlockCache {
+    fn new(dir: PathBuf, capacity_bytes: u64) -> Self {
+        Self {
+            dir,
+            capacity_bytes,
+            inserted: AtomicU64::new(0),
+        }
+    }
+
+    fn block_path(&self, key: &str, block: usize) -> PathBuf {
+        self.dir.join(format!("{key}.{block}"))
+    }
+
+    fn get(&self, key: &str, block: usize) -> Option<Vec<u8>> {
+        let path = self.block_path(key, block);
+        let data = fs::read(&path).ok()?;
+        // The modification time tracks when a block was last read, for eviction.
+        if let Ok(file) = File::options().write(true).open(&path) {
+            let _ = file.set_modified(SystemTime::now());
+        }
+        Some(data)
+    }
+
+    fn put(&self, key: &str, block: usize, data: &[u8]) {
+        if self.capacity_bytes == 0 {
+            return;
+        }
+
+        // Written under a temporary name first, so that other connections never read a
+        // partial block.
+        let path = self.block_path(key, block);
+        let temp_path = path.with_extension(format!("{block}.{}.tmp", std::process::id()));
+        if fs::write(&temp_path, data).is_err() || fs::rename(&temp_path, &path).is_err() {
+            let _ = fs::remove_file(&temp_path);
+            return;
+        }
+
+        if self.inserted.fetch_add(1, Ordering::Relaxed) % EVICTION_CHECK_INTERVAL == 0 {
+            self.evict();
+        }
+    }
+
+    /// Remove the cached blocks of a file.
+    fn remove(&self, key: &str) {
+        let prefix = format!("{key}.");
+        if let Ok(entries) = fs::read_dir(&self.dir) {
+            for entry in entries.flatten() {
+                if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                    let _ = fs::remove_file(entry.path());
+                }
+            }
+        }
+    }
+
+    fn evict(&self) {
+        let Ok(entries) = fs::read_dir(&self.dir) else {
+            return;
+        };
+        let mut blocks: Vec<(SystemTime, u64, PathBuf)> = entries
+            .flatten()
+            .filter_map(|entry| {
+                let metadata = entry.metadata().ok()?;
+                Some((metadata.modified().ok()?, metadata.len(), entry.path()))
+            })
+            .collect();
+
+        let mut total: u64 = blocks.iter().map(|(_, len, _)| len).sum();
+        blocks.sort();
+        for (_, len, path) in blocks {
+            if total <= self.capacity_bytes {
+                break;
+            }
+            if fs::remove_file(path).is_ok() {
+                total -= len;
+            }
+        }
+    }
+}
+
+fn to_io_error(err: object_store::Error) -> io::Error {
+    match err {
+        object_store::Error::NotFound { .. } => io::Error::new(io::ErrorKind::NotFound, err),
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block_ranges, BlockCache, RemoteDirectory, RemoteStorage, TierUsage, BLOCK_SIZE};
+    use crate::fixtures::*;
+    use crate::writer::{SearchFs, TantivyDirPath};
+    use rstest::*;
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+    use tantivy::schema::{Schema, Value, STORED, TEXT};
+    use tantivy::{doc, DocAddress, Index, IndexSettings, TantivyDocument};
+
+    #[test]
+    fn test_block_ranges() {
+        assert_eq!(
+            block_ranges(10..20, BLOCK_SIZE).collect::<Vec<_>>(),
+            vec![(0, 10..20)]
+        );
+        assert_eq!(
+            block_ranges(BLOCK_SIZE - 1..BLOCK_SIZE + 1, BLOCK_SIZE).collect::<Vec<_>>(),
+            vec![(0, BLOCK_SIZE - 1..BLOCK_SIZE), (1, 0..1)]
+        );
+        assert_eq!(block_ranges(5..5, BLOCK_SIZE).count(), 0);
+    }
+
+    #[test]
+    fn test_block_cache_eviction() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cache = BlockCache::new(tempdir.path().to_path_buf(), 10);
+
+        cache.put("segment.idx", 0, b"0123456789");
+        assert_eq!(cache.get("segment.idx", 0), Some(b"0123456789".to_vec()));
+        assert_eq!(cache.get("segment.idx", 1), None);
+
+        // Over capacity, the least recently read blocks go first.
+        cache.put("segment.idx", 1, b"abcde");
+        cache.evict();
+        assert_eq!(cache.get("segment.idx", 0), None);
+        assert_eq!(cache.get("segment.idx", 1), Some(b"abcde".to_vec()));
+
+        cache.remove("segment.idx");
+        assert_eq!(cache.get("segment.idx", 1), None);
+    }
+
+    #[rstest]
+    fn test_move_cold_segments(mock_dir: MockWriterDirectory) {
+        let storage = RemoteStorage {
+            url: "memory:///".to_string(),
+            options: Default::default(),
+            cache_size_mb: 1,
+            hot_max_age_secs: 60,
+            hot_max_size_mb: 0,
+        };
+        let TantivyDirPath(path) = mock_dir.tantivy_dir_path(true).unwrap();
+        let directory = RemoteDirectory::open(&path, &storage, &mock_dir.writer_dir).unwrap();
+
+        let mut schema = Schema::builder();
+        let body = schema.add_text_field("body", TEXT | STORED);
+        let index =
+            Index::create(directory.clone(), schema.build(), IndexSettings::default()).unwrap();
+        let mut writer = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        writer.add_document(doc!(body => "cold storage")).unwrap();
+        writer.commit().unwrap();
+
+        // A new segment stays on local disk until it's old enough.
+        let metas = index.searchable_segment_metas().unwrap();
+        assert_eq!(directory.move_cold_segments(&storage, &metas).unwrap(), 0);
+        let usage = directory.tier_usage(&metas).unwrap();
+        assert_eq!((usage.hot_segments, usage.cold_segments), (1, 0));
+
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        for file in metas[0].list_files() {
+            if let Ok(file) = File::options().write(true).open(path.join(file)) {
+                file.set_modified(an_hour_ago).unwrap();
+            }
+        }
+        assert_eq!(directory.move_cold_segments(&storage, &metas).unwrap(), 1);
+        assert_eq!(
+            directory.tier_usage(&metas).unwrap(),
+            TierUsage {
+                hot_segments: 0,
+                hot_bytes: 0,
+                cold_segments: 1,
+                cold_bytes: usage.hot_bytes,
+            }
+        );
+
+        // The segment is read back from object storage.
+        let searcher = index.reader().unwrap().searcher();
+        let document: TantivyDocument = searcher.doc(DocAddress::new(0, 0)).unwrap();
+        assert_eq!(
+            document.get_first(body).and_then(|value| value.as_str()),
+            Some("cold storage")
+        );
+    }
+}
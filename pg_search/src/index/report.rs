@@ -0,0 +1,234 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use std::collections::HashMap;
+use tantivy::schema::Field;
+use tantivy::space_usage::PerFieldSpaceUsage;
+use tantivy::{DocAddress, TantivyDocument};
+
+/// Segments beyond this many slow down searches, which visit every segment.
+const MAX_SEGMENTS: usize = 20;
+/// The share of deleted documents after which merging the segments is worth it.
+const MAX_DELETED_RATIO: f64 = 0.2;
+/// The share of the index after which a field's positions, stored values or fast values are
+/// worth questioning.
+const LARGE_FIELD_RATIO: f64 = 0.1;
+/// The number of documents whose stored fields are read to estimate their sizes.
+const STORED_SAMPLE_SIZE: usize = 1000;
+
+/// A finding of `IndexReport`, with an action that would shrink or speed up the index and
+/// about how many bytes it would save.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReportEntry {
+    /// What the entry is about: `segments`, `deletes`, `postings`, `positions`, `fast`,
+    /// `stored` or `fieldnorms`.
+    pub kind: String,
+    /// The field the entry is about, or None for the whole index.
+    pub field: Option<String>,
+    pub bytes: u64,
+    pub detail: String,
+    pub action: Option<String>,
+    pub estimated_savings: u64,
+}
+
+/// How the bytes of the searchable segments of an index are spread over its segments and
+/// fields, with suggestions to make it smaller or faster. The sizes of stored fields are
+/// estimated from a sample of documents, as segments only know the size of the whole store.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IndexReport {
+    pub entries: Vec<ReportEntry>,
+}
+
+impl IndexReport {
+    pub fn collect(search_index: &SearchIndex) -> Result<Self, SearchIndexError> {
+        let searcher = search_index.searcher();
+        let space_usage = searcher.space_usage()?;
+        let total_bytes = space_usage.total().get_bytes();
+        let schema = &search_index.schema;
+        let mut entries = vec![];
+
+        let mut segment_bytes: Vec<u64> = space_usage
+            .segments()
+            .iter()
+            .map(|segment| segment.total().get_bytes())
+            .collect();
+        segment_bytes.sort_unstable();
+        let segments = segment_bytes.len();
+        entries.push(ReportEntry {
+            kind: "segments".into(),
+            field: None,
+            bytes: total_bytes,
+            detail: match (segment_bytes.first(), segment_bytes.last()) {
+                (Some(smallest), Some(largest)) => format!(
+                    "{segments} segments, smallest {smallest} bytes, median {} bytes, largest {largest} bytes",
+                    segment_bytes[segments / 2]
+                ),
+                _ => "no segments".into(),
+            },
+            action: (segments > MAX_SEGMENTS).then(|| {
+                format!("merge the segments with paradedb.force_merge, searches visit each of the {segments} segments")
+            }),
+            estimated_savings: 0,
+        });
+
+        let (num_docs, num_deleted_docs) =
+            searcher
+                .segment_readers()
+                .iter()
+                .fold((0, 0), |(docs, deleted), reader| {
+                    (
+                        docs + reader.num_docs() as u64,
+                        deleted + reader.num_deleted_docs() as u64,
+                    )
+                });
+        let deleted_ratio = match num_docs + num_deleted_docs {
+            0 => 0.0,
+            all_docs => num_deleted_docs as f64 / all_docs as f64,
+        };
+        // Merging drops deleted documents from every part of the segments.
+        let deleted_bytes = (total_bytes as f64 * deleted_ratio) as u64;
+        entries.push(ReportEntry {
+            kind: "deletes".into(),
+            field: None,
+            bytes: deleted_bytes,
+            detail: format!(
+                "{num_deleted_docs} of {} documents are deleted ({:.1}%)",
+                num_docs + num_deleted_docs,
+                deleted_ratio * 100.0
+            ),
+            action: (deleted_ratio > MAX_DELETED_RATIO).then(|| {
+                "merge the segments with paradedb.force_merge to drop the deleted documents"
+                    .to_string()
+            }),
+            estimated_savings: if deleted_ratio > MAX_DELETED_RATIO {
+                deleted_bytes
+            } else {
+                0
+            },
+        });
+
+        let mut postings = HashMap::new();
+        let mut positions = HashMap::new();
+        let mut fast_fields = HashMap::new();
+        let mut fieldnorms = HashMap::new();
+        let mut store_bytes = 0;
+        for segment in space_usage.segments() {
+            add_field_bytes(&mut postings, segment.termdict());
+            add_field_bytes(&mut postings, segment.postings());
+            add_field_bytes(&mut positions, segment.positions());
+            add_field_bytes(&mut fast_fields, segment.fast_fields());
+            add_field_bytes(&mut fieldnorms, segment.fieldnorms());
+            store_bytes += segment.store().total().get_bytes();
+        }
+        let stored_shares = stored_shares(search_index)?;
+
+        let is_large = |bytes: u64| bytes as f64 > total_bytes as f64 * LARGE_FIELD_RATIO;
+        let key_field = schema.key_field().id.0;
+        let ctid_field = schema.fields[schema.ctid].id.0;
+        for search_field in &schema.fields {
+            let field = search_field.id.0;
+            let name = search_field.name.0.clone();
+            // The key and ctid of a document are how results find their rows.
+            let required = field == key_field || field == ctid_field;
+
+            let bytes = postings.get(&field).copied().unwrap_or_default();
+            if bytes > 0 {
+                entries.push(field_entry("postings", &name, bytes, None));
+            }
+
+            let bytes = positions.get(&field).copied().unwrap_or_default();
+            if bytes > 0 {
+                let action = is_large(bytes).then(|| {
+                    format!(
+                        "index {name} with \"record\": \"freq\" if it isn't searched with phrases"
+                    )
+                });
+                entries.push(field_entry("positions", &name, bytes, action));
+            }
+
+            let bytes = fast_fields.get(&field).copied().unwrap_or_default();
+            if bytes > 0 {
+                let action = (is_large(bytes) && !required).then(|| {
+                    format!("index {name} with \"fast\": false if it isn't sorted or aggregated on")
+                });
+                entries.push(field_entry("fast", &name, bytes, action));
+            }
+
+            let bytes =
+                (store_bytes as f64 * stored_shares.get(&field).copied().unwrap_or(0.0)) as u64;
+            if bytes > 0 {
+                let action = (is_large(bytes) && !required).then(|| {
+                    format!("index {name} with \"stored\": false if search_tab and highlighting don't read it")
+                });
+                entries.push(field_entry("stored", &name, bytes, action));
+            }
+
+            let bytes = fieldnorms.get(&field).copied().unwrap_or_default();
+            if bytes > 0 {
+                entries.push(field_entry("fieldnorms", &name, bytes, None));
+            }
+        }
+
+        Ok(Self { entries })
+    }
+}
+
+fn add_field_bytes(bytes: &mut HashMap<Field, u64>, usage: &PerFieldSpaceUsage) {
+    for (field, field_usage) in usage.fields() {
+        *bytes.entry(*field).or_default() += field_usage.total().get_bytes();
+    }
+}
+
+/// An entry for the bytes of a field, whose action would save all of them.
+fn field_entry(kind: &str, name: &str, bytes: u64, action: Option<String>) -> ReportEntry {
+    ReportEntry {
+        kind: kind.into(),
+        field: Some(name.into()),
+        bytes,
+        detail: String::new(),
+        estimated_savings: if action.is_some() { bytes } else { 0 },
+        action,
+    }
+}
+
+/// The share of the stored bytes of a sample of documents that each field takes up, as JSON.
+fn stored_shares(search_index: &SearchIndex) -> Result<HashMap<Field, f64>, SearchIndexError> {
+    let searcher = search_index.searcher();
+    let mut bytes: HashMap<Field, u64> = HashMap::new();
+    let mut sampled = 0;
+    'segments: for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+        for doc_id in segment_reader.doc_ids_alive() {
+            if sampled == STORED_SAMPLE_SIZE {
+                break 'segments;
+            }
+            let document: TantivyDocument =
+                searcher.doc(DocAddress::new(segment_ord as u32, doc_id))?;
+            for field_value in document.field_values() {
+                let size = serde_json::to_vec(field_value.value()).map_or(0, |json| json.len());
+                *bytes.entry(field_value.field()).or_default() += size as u64;
+            }
+            sampled += 1;
+        }
+    }
+
+    let total = bytes.values().sum::<u64>().max(1) as f64;
+    Ok(bytes
+        .into_iter()
+        .map(|(field, bytes)| (field, bytes as f64 / total))
+        .collect())
+}
@@ -15,30 +15,47 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Deserializer, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, PoisonError};
-use tantivy::{query::QueryParser, Executor, Index, Searcher};
-use tantivy::{schema::Value, IndexReader, IndexWriter, TantivyDocument, TantivyError};
+use tantivy::query::{EnableScoring, QueryParser, Weight};
+use tantivy::{merge_policy::NoMergePolicy, IndexReader, IndexWriter, TantivyError};
+use tantivy::{
+    Directory, DocAddress, DocSet, Executor, Index, IndexSettings, Searcher, SegmentComponent,
+    TantivyDocument, TERMINATED,
+};
 use thiserror::Error;
 use tokenizers::{create_normalizer_manager, create_tokenizer_manager};
 use tracing::{error, info};
 
+use super::dedup::{LatestVersionWeight, LatestVersions};
+use super::encryption::{EncryptedDirectory, EncryptionError, EncryptionKey};
+use super::fast_fields::{FastFieldColumn, FastFieldsCollector};
+use super::merge::SearchMergePolicy;
+use super::pending::{PendingInserts, PendingInsertsError};
+use super::pin::SearcherPin;
+use super::prepared::{PreparedInserts, PreparedInsertsError};
+use super::remote::{RemoteDirectory, RemoteStorage};
 use super::state::SearchState;
+use super::storage::SearchDirectoryMode;
+use crate::query::SearchQueryInput;
 use crate::schema::{
-    SearchConfig, SearchDocument, SearchFieldConfig, SearchFieldName, SearchFieldType,
+    SearchConfig, SearchDocument, SearchField, SearchFieldConfig, SearchFieldName, SearchFieldType,
     SearchIndexSchema, SearchIndexSchemaError,
 };
 use crate::writer::{
     self, SearchDirectoryError, SearchFs, TantivyDirPath, WriterClient, WriterDirectory,
     WriterRequest, WriterTransferPipeFilePath,
 };
+use crate::SEARCH_GUCS;
 
 // Must be at least 15,000,000 or Tantivy will panic.
 const INDEX_TANTIVY_MEMORY_BUDGET: usize = 500_000_000;
-const CACHE_NUM_BLOCKS: usize = 10;
+// How many ctids a vacuum sends to the writer at once. Keeps requests to a bounded size
+// and lets the vacuum report progress on very large indexes.
+const DELETE_BATCH_SIZE: usize = 100_000;
 
 /// PostgreSQL operates in a process-per-client model, meaning every client connection
 /// to PostgreSQL results in a new backend process being spawned on the PostgreSQL server.
@@ -58,10 +75,11 @@ const CACHE_NUM_BLOCKS: usize = 10;
 pub static mut SEARCH_INDEX_MEMORY: Lazy<HashMap<WriterDirectory, SearchIndex>> =
     Lazy::new(HashMap::new);
 
-pub static mut SEARCH_EXECUTOR: Lazy<Executor> = Lazy::new(|| {
-    let num_threads = num_cpus::get();
-    Executor::multi_thread(num_threads, "prefix-here").expect("could not create search executor")
-});
+/// The thread pool used to search the segments of an index in parallel, along with its number
+/// of threads. Like `SEARCH_INDEX_MEMORY`, it lives as long as the backend process, and is only
+/// rebuilt when `paradedb.search_threads` changes.
+static SEARCH_EXECUTOR: Lazy<Mutex<Option<(usize, Arc<Executor>)>>> =
+    Lazy::new(|| Mutex::new(None));
 
 #[derive(Serialize)]
 pub struct SearchIndex {
@@ -72,6 +90,22 @@ pub struct SearchIndex {
     #[serde(skip_serializing)]
     pub underlying_index: Index,
     pub uuid: String,
+    pub merge_policy: SearchMergePolicy,
+    /// Milliseconds between background commits, see `Writer::commit`. If 0, changes are
+    /// committed at the end of each transaction.
+    pub refresh_interval: u64,
+    /// How backends read the index files.
+    pub directory_mode: SearchDirectoryMode,
+    /// Megabytes of uncommitted documents after which the writer commits the index, see
+    /// `Writer::track_memory`. If 0, commits only depend on the refresh interval.
+    pub writer_memory_budget: u64,
+    /// Megabytes of disk space the index may take up before inserts into it fail, see
+    /// `Writer::check_disk_space`. If 0, its size isn't limited.
+    pub max_index_size: u64,
+    /// Object storage holding the segments of the index, or None if they're on local disk.
+    pub storage: Option<RemoteStorage>,
+    /// Whether the files of the index are encrypted, see `EncryptionKey`.
+    pub encrypted: bool,
 }
 
 impl SearchIndex {
@@ -81,12 +115,24 @@ impl SearchIndex {
         fields: Vec<(SearchFieldName, SearchFieldConfig, SearchFieldType)>,
         uuid: String,
         key_field_index: usize,
+        merge_policy: SearchMergePolicy,
+        refresh_interval: u64,
+        directory_mode: SearchDirectoryMode,
+        writer_memory_budget: u64,
+        max_index_size: u64,
+        storage: Option<RemoteStorage>,
     ) -> Result<&'static mut Self, SearchIndexError> {
         writer.lock()?.request(WriterRequest::CreateIndex {
             directory: directory.clone(),
             fields,
             uuid: uuid.clone(),
             key_field_index,
+            merge_policy,
+            refresh_interval,
+            directory_mode,
+            writer_memory_budget,
+            max_index_size,
+            storage,
         })?;
 
         // As the new index instance was created in a background process, we need
@@ -97,9 +143,58 @@ impl SearchIndex {
         Ok(new_self_ref)
     }
 
-    #[allow(static_mut_refs)]
-    pub fn executor() -> &'static Executor {
-        unsafe { &SEARCH_EXECUTOR }
+    /// The Tantivy directory of an index, in object storage if the index has a `storage`
+    /// option, or on local disk read according to its directory mode. Encrypted indexes are
+    /// decrypted as they're read.
+    pub fn open_directory(
+        directory: &WriterDirectory,
+        directory_mode: SearchDirectoryMode,
+        storage: Option<&RemoteStorage>,
+        encrypted: bool,
+    ) -> Result<Box<dyn Directory>, SearchIndexError> {
+        let TantivyDirPath(tantivy_dir_path) = directory.tantivy_dir_path(true)?;
+        let index_directory: Box<dyn Directory> = match storage {
+            Some(storage) => Box::new(RemoteDirectory::open(
+                &tantivy_dir_path,
+                storage,
+                directory,
+            )?),
+            None => directory_mode.open(&tantivy_dir_path)?,
+        };
+
+        if !encrypted {
+            return Ok(index_directory);
+        }
+        Ok(Box::new(EncryptedDirectory::new(
+            index_directory,
+            EncryptionKey::load()?,
+        )))
+    }
+
+    /// The executor searching segments in parallel. Each thread collects the top documents of
+    /// the segments it searches, and the results are merged into the top documents of the index.
+    pub fn executor() -> Arc<Executor> {
+        let num_threads = match SEARCH_GUCS.search_threads.get() {
+            0 => num_cpus::get(),
+            num_threads => num_threads as usize,
+        };
+
+        let mut cached = SEARCH_EXECUTOR
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match &*cached {
+            Some((cached_threads, executor)) if *cached_threads == num_threads => executor.clone(),
+            _ => {
+                let executor = Arc::new(if num_threads == 1 {
+                    Executor::single_thread()
+                } else {
+                    Executor::multi_thread(num_threads, "pg-search-")
+                        .expect("could not create search executor")
+                });
+                *cached = Some((num_threads, executor.clone()));
+                executor
+            }
+        }
     }
 
     pub fn setup_tokenizers(underlying_index: &mut Index, schema: &SearchIndexSchema) {
@@ -183,33 +278,84 @@ impl SearchIndex {
         config: &SearchConfig,
         needs_commit: bool,
     ) -> Result<SearchState, SearchIndexError> {
+        // Apply the inserts of any prepared transaction that has been committed since the
+        // index was last written to. Most indexes have none, which is checked without
+        // waiting on the writer.
+        if PreparedInserts::any(&self.directory)? {
+            PreparedInserts::resolve(&self.directory, &mut *writer.lock()?)?;
+        }
+
         // Commit any inserts or deletes that have occurred during this transaction.
         if needs_commit {
             writer.lock()?.request(WriterRequest::Commit {
                 directory: self.directory.clone(),
-            })?
+                pid: std::process::id(),
+            })?;
+            SearcherPin::unpin(&self.directory);
         }
 
         // Prepare to perform a search.
-        // In case this is happening in the same transaction as an index build or an insert,
-        // we want to commit first so that the most recent results appear.
-
-        self.reader.reload()?;
-        Ok(SearchState::new(self, config))
+        // In case this is happening in the same transaction as an index build, we want to
+        // commit first so that the most recent results appear. Rows inserted through the
+        // index access method are buffered instead, and are searched from the pending index.
+
+        // Searches of the same snapshot share a searcher, so that they see the same generation
+        // of the index even if other transactions commit to it in the meantime.
+        let searcher = SearcherPin::get_or_pin(&self.directory, || {
+            self.reader.reload()?;
+            Ok::<_, SearchIndexError>(self.searcher())
+        })?;
+        Ok(SearchState::new(
+            self,
+            config,
+            searcher,
+            self.pending_searcher()?,
+        ))
     }
 
     pub fn searcher(&self) -> Searcher {
         self.reader.searcher()
     }
 
+    /// Whether a committed document has the same key, meaning that the row being indexed is
+    /// a new version of it, as written by an UPDATE or an INSERT ... ON CONFLICT DO UPDATE.
+    /// Deleted documents are still counted, which only costs an unnecessary delete.
+    pub fn contains_key(&self, document: &SearchDocument) -> Result<bool, SearchIndexError> {
+        let Some(key_term) = document.key_term() else {
+            return Ok(false);
+        };
+        Ok(self.searcher().doc_freq(&key_term)? > 0)
+    }
+
+    /// Documents inserted by the current transaction are buffered until it commits, so they
+    /// are not part of the on-disk index yet. To let a transaction read its own writes, they're
+    /// indexed with the same schema and tokenizers, and searched alongside the committed
+    /// index. That index lasts for the transaction, see `PendingIndex`.
+    pub fn pending_searcher(&self) -> Result<Option<Searcher>, SearchIndexError> {
+        Ok(PendingInserts::searcher(&self.directory, |directory| {
+            let mut pending_index = Index::create(
+                directory,
+                self.schema.schema.clone(),
+                IndexSettings::default(),
+            )?;
+            Self::setup_tokenizers(&mut pending_index, &self.schema);
+            Ok(pending_index)
+        })?)
+    }
+
     /// Retrieve an owned writer for a given index. This is a static method, as
     /// we expect to be called from the writer process. The return type needs to
     /// be entirely owned by the new process, with no references.
     pub fn writer(directory: &WriterDirectory) -> Result<IndexWriter, SearchIndexError> {
         let search_index: Self = directory.load_index()?;
-        let index_writer = search_index
-            .underlying_index
-            .writer(INDEX_TANTIVY_MEMORY_BUDGET)?;
+        // Tantivy flushes a new segment whenever its memory fills up, so it must be able to
+        // hold all the documents of a commit for them to end up in as few segments as possible.
+        let memory_budget =
+            INDEX_TANTIVY_MEMORY_BUDGET.max(search_index.writer_memory_budget as usize * 1_000_000);
+        let index_writer = search_index.underlying_index.writer(memory_budget)?;
+        // Merges are scheduled by the merge background worker instead of on every commit,
+        // see `SearchMergePolicy`.
+        index_writer.set_merge_policy(Box::new(NoMergePolicy));
         Ok(index_writer)
     }
 
@@ -227,31 +373,36 @@ impl SearchIndex {
         let WriterTransferPipeFilePath(pipe_path) =
             self.directory.writer_transfer_pipe_path(true)?;
 
-        writer.lock()?.transfer(pipe_path, request)?;
+        writer.lock()?.transfer(pipe_path, &request)?;
 
         Ok(())
     }
 
+    /// Delete the documents whose ctid matches `should_delete`. Deletes are sent to the
+    /// writer in batches of `DELETE_BATCH_SIZE`, and `on_batch` is called after each one with
+    /// the number of documents scanned and deleted so far, to report progress.
     pub fn delete<W: WriterClient<WriterRequest> + Send + Sync + 'static>(
         &mut self,
         writer: &Arc<Mutex<W>>,
         should_delete: impl Fn(u64) -> bool,
+        mut on_batch: impl FnMut(u32, u32),
     ) -> Result<(u32, u32), SearchIndexError> {
         let mut deleted: u32 = 0;
         let mut not_deleted: u32 = 0;
         let mut ctids_to_delete: Vec<u64> = vec![];
 
+        // The ctid is a fast field, so we can read it from the columnar storage rather than
+        // loading every stored document.
+        let ctid_field_name = self.schema.ctid_field().name.0;
         for segment_reader in self.searcher().segment_readers() {
-            let store_reader = segment_reader
-                .get_store_reader(CACHE_NUM_BLOCKS)
-                .expect("Failed to get store reader");
-
-            for (delete, ctid) in (0..segment_reader.num_docs())
-                .filter_map(|id| store_reader.get(id).ok())
-                .filter_map(|doc: TantivyDocument| {
-                    doc.get_first(self.schema.ctid_field().id.0).cloned()
-                })
-                .filter_map(|value| (&value).as_u64())
+            let ctid_column = segment_reader
+                .fast_fields()
+                .u64(&ctid_field_name)
+                .expect("Failed to get ctid fast field");
+
+            for (delete, ctid) in segment_reader
+                .doc_ids_alive()
+                .filter_map(|id| ctid_column.first(id))
                 .map(|ctid_val| (should_delete(ctid_val), ctid_val))
             {
                 if delete {
@@ -260,24 +411,57 @@ impl SearchIndex {
                 } else {
                     not_deleted += 1
                 }
+
+                if ctids_to_delete.len() >= DELETE_BATCH_SIZE {
+                    self.send_delete(writer, std::mem::take(&mut ctids_to_delete))?;
+                    on_batch(deleted + not_deleted, deleted);
+                }
             }
         }
 
+        self.send_delete(writer, ctids_to_delete)?;
+        on_batch(deleted + not_deleted, deleted);
+
+        Ok((deleted, not_deleted))
+    }
+
+    fn send_delete<W: WriterClient<WriterRequest>>(
+        &self,
+        writer: &Arc<Mutex<W>>,
+        ctids: Vec<u64>,
+    ) -> Result<(), SearchIndexError> {
+        if ctids.is_empty() {
+            return Ok(());
+        }
+
         let request = WriterRequest::Delete {
             field: self.schema.ctid_field().id.0,
-            ctids: ctids_to_delete,
+            ctids,
             directory: self.directory.clone(),
         };
         writer.lock()?.request(request)?;
-
-        Ok((deleted, not_deleted))
+        Ok(())
     }
 
+    /// Drop every directory of the index named `index_name`, including the ones left behind
+    /// by earlier file nodes of the index.
     pub fn drop_index<W: WriterClient<WriterRequest>>(
         writer: &Arc<Mutex<W>>,
         index_name: &str,
     ) -> Result<(), SearchIndexError> {
         let directory = WriterDirectory::from_index_name(index_name);
+        for sibling in directory.siblings()? {
+            Self::drop_directory(writer, sibling)?;
+        }
+        Self::drop_directory(writer, directory)
+    }
+
+    /// Drop the index in `directory`, which may belong to an index or a database that was
+    /// already dropped, see `paradedb.gc_directories`.
+    pub fn drop_directory<W: WriterClient<WriterRequest>>(
+        writer: &Arc<Mutex<W>>,
+        directory: WriterDirectory,
+    ) -> Result<(), SearchIndexError> {
         let request = WriterRequest::DropIndex {
             directory: directory.clone(),
         };
@@ -291,6 +475,191 @@ impl SearchIndex {
         Ok(())
     }
 
+    /// The values of the fast `fields` for every committed document matching `query`, as one
+    /// column per field. The columns are read in batches by `paradedb.fast_fields`.
+    pub fn fast_field_columns(
+        &self,
+        query: SearchQueryInput,
+        fields: &[String],
+    ) -> Result<Vec<FastFieldColumn>, SearchIndexError> {
+        for name in fields {
+            let search_field = self
+                .schema
+                .get_search_field(name.as_str())
+                .ok_or_else(|| anyhow!("field '{name}' does not exist in the index"))?;
+            if !self
+                .schema
+                .schema
+                .get_field_entry(search_field.id.0)
+                .is_fast()
+            {
+                return Err(anyhow!("field '{name}' is not a fast field").into());
+            }
+        }
+
+        let query = query.into_tantivy_query(&self.schema, &mut self.query_parser())?;
+        let searcher = self.searcher();
+        let columns = searcher.search_with_executor(
+            query.as_ref(),
+            &FastFieldsCollector::new(fields.to_vec()),
+            &Self::executor(),
+            EnableScoring::disabled_from_searcher(&searcher),
+        )?;
+        Ok(columns)
+    }
+
+    /// The stored document of the latest committed version of the row matching `query`, which
+    /// is read by `paradedb.stored_fields` instead of its heap row.
+    pub fn stored_document(
+        &self,
+        query: SearchQueryInput,
+    ) -> Result<Option<TantivyDocument>, SearchIndexError> {
+        let query = query.into_tantivy_query(&self.schema, &mut self.query_parser())?;
+        let searcher = self.searcher();
+        let latest = Arc::new(LatestVersions::new(
+            self.schema.clone(),
+            self.schema.key_field().name.0,
+            searcher.segment_readers().to_vec(),
+        ));
+        let weight = LatestVersionWeight::new(
+            query.weight(EnableScoring::disabled_from_searcher(&searcher))?,
+            latest,
+        );
+
+        for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+            let alive_bitset = segment_reader.alive_bitset();
+            let mut scorer = weight.scorer(segment_reader, 1.0)?;
+            let mut doc = scorer.doc();
+            while doc != TERMINATED {
+                if alive_bitset.map_or(true, |bitset| bitset.is_alive(doc)) {
+                    let address = DocAddress::new(segment_ord as u32, doc);
+                    return Ok(Some(searcher.doc(address)?));
+                }
+                doc = scorer.advance();
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read the term dictionaries and fast fields of `fields`, or of every field, and if
+    /// `docstore` is set the stored documents, so that the first queries after a restart
+    /// don't wait on disk. Term dictionaries are also kept open by this backend's searcher.
+    /// Returns the bytes of fast fields and stored documents read.
+    pub fn warm(&self, fields: Option<&[String]>, docstore: bool) -> Result<u64, SearchIndexError> {
+        let search_fields: Vec<&SearchField> = match fields {
+            None => self.schema.fields.iter().collect(),
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    self.schema
+                        .get_search_field(name.as_str())
+                        .ok_or_else(|| anyhow!("field '{name}' does not exist in the index"))
+                })
+                .collect::<Result<_, _>>()?,
+        };
+
+        let mut bytes_read = 0;
+        let searcher = self.searcher();
+        for segment_reader in searcher.segment_readers() {
+            for search_field in &search_fields {
+                let field_entry = self.schema.schema.get_field_entry(search_field.id.0);
+                if field_entry.is_indexed() {
+                    let inverted_index = segment_reader.inverted_index(search_field.id.0)?;
+                    let mut terms = inverted_index.terms().stream()?;
+                    while terms.advance() {}
+                }
+                if field_entry.is_fast() {
+                    for column in segment_reader
+                        .fast_fields()
+                        .dynamic_column_handles(&search_field.name.0)?
+                    {
+                        bytes_read += touch_pages(&column.file_slice().read_bytes()?);
+                    }
+                }
+            }
+        }
+
+        if docstore {
+            for segment in self.underlying_index.searchable_segments()? {
+                let store = segment
+                    .open_read(SegmentComponent::Store)
+                    .map_err(TantivyError::from)?;
+                bytes_read += touch_pages(&store.read_bytes()?);
+            }
+        }
+        Ok(bytes_read)
+    }
+
+    pub fn force_merge<W: WriterClient<WriterRequest>>(
+        &self,
+        writer: &Arc<Mutex<W>>,
+        max_segments: usize,
+        wait: bool,
+    ) -> Result<(), SearchIndexError> {
+        let request = WriterRequest::ForceMerge {
+            directory: self.directory.clone(),
+            max_segments,
+            wait,
+        };
+        writer.lock()?.request(request)?;
+        Ok(())
+    }
+
+    /// Commit the documents sent to the writer by committed transactions, without waiting on
+    /// the refresh interval of the index, and reload the reader so that they're searchable.
+    pub fn refresh<W: WriterClient<WriterRequest>>(
+        &self,
+        writer: &Arc<Mutex<W>>,
+    ) -> Result<(), SearchIndexError> {
+        let request = WriterRequest::Refresh {
+            directory: self.directory.clone(),
+        };
+        writer.lock()?.request(request)?;
+        SearcherPin::unpin(&self.directory);
+        self.reader.reload()?;
+        Ok(())
+    }
+
+    /// Count values of rows that couldn't be indexed, in the status of the index. See
+    /// `SearchFieldErrorPolicy`.
+    pub fn skip_values<W: WriterClient<WriterRequest>>(
+        directory: &WriterDirectory,
+        writer: &Arc<Mutex<W>>,
+        count: u64,
+    ) -> Result<(), SearchIndexError> {
+        let request = WriterRequest::SkipValues {
+            directory: directory.clone(),
+            count,
+        };
+        writer.lock()?.request(request)?;
+        Ok(())
+    }
+
+    /// Change the options of the index that don't affect its documents.
+    pub fn alter_options<W: WriterClient<WriterRequest>>(
+        &mut self,
+        writer: &Arc<Mutex<W>>,
+        merge_policy: SearchMergePolicy,
+        refresh_interval: u64,
+        writer_memory_budget: u64,
+        max_index_size: u64,
+    ) -> Result<(), SearchIndexError> {
+        let request = WriterRequest::AlterOptions {
+            directory: self.directory.clone(),
+            merge_policy: merge_policy.clone(),
+            refresh_interval,
+            writer_memory_budget,
+            max_index_size,
+        };
+        writer.lock()?.request(request)?;
+
+        self.merge_policy = merge_policy;
+        self.refresh_interval = refresh_interval;
+        self.writer_memory_budget = writer_memory_budget;
+        self.max_index_size = max_index_size;
+        Ok(())
+    }
+
     pub fn vacuum<W: WriterClient<WriterRequest>>(
         &mut self,
         writer: &Arc<Mutex<W>>,
@@ -317,6 +686,20 @@ impl<'de> Deserialize<'de> for SearchIndex {
             // to disk. Just use an empty string for backwards compatibility.
             #[serde(default)]
             uuid: String,
+            #[serde(default)]
+            merge_policy: SearchMergePolicy,
+            #[serde(default)]
+            refresh_interval: u64,
+            #[serde(default)]
+            directory_mode: SearchDirectoryMode,
+            #[serde(default)]
+            writer_memory_budget: u64,
+            #[serde(default)]
+            max_index_size: u64,
+            #[serde(default)]
+            storage: Option<RemoteStorage>,
+            #[serde(default)]
+            encrypted: bool,
         }
 
         // Deserialize into the struct with automatic handling for most fields
@@ -324,12 +707,19 @@ impl<'de> Deserialize<'de> for SearchIndex {
             schema,
             directory,
             uuid,
+            merge_policy,
+            refresh_interval,
+            directory_mode,
+            writer_memory_budget,
+            max_index_size,
+            storage,
+            encrypted,
         } = SearchIndexHelper::deserialize(deserializer)?;
 
-        let TantivyDirPath(tantivy_dir_path) = directory.tantivy_dir_path(true).unwrap();
-
-        let mut underlying_index =
-            Index::open_in_dir(tantivy_dir_path).expect("failed to open index");
+        let index_directory =
+            Self::open_directory(&directory, directory_mode, storage.as_ref(), encrypted)
+                .expect("failed to open index directory");
+        let mut underlying_index = Index::open(index_directory).expect("failed to open index");
 
         // We need to setup tokenizers again after retrieving an index from disk.
         Self::setup_tokenizers(&mut underlying_index, &schema);
@@ -344,10 +734,29 @@ impl<'de> Deserialize<'de> for SearchIndex {
             directory,
             schema,
             uuid,
+            merge_policy,
+            refresh_interval,
+            directory_mode,
+            writer_memory_budget,
+            max_index_size,
+            storage,
+            encrypted,
         })
     }
 }
 
+/// Read one byte of every page, which makes the OS load memory-mapped files into its cache.
+/// Returns the number of bytes covered.
+fn touch_pages(bytes: &[u8]) -> u64 {
+    const PAGE_SIZE: usize = 4096;
+    let sum = bytes
+        .iter()
+        .step_by(PAGE_SIZE)
+        .fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+    std::hint::black_box(sum);
+    bytes.len() as u64
+}
+
 #[derive(Error, Debug)]
 pub enum SearchIndexError {
     #[error(transparent)]
@@ -374,6 +783,15 @@ pub enum SearchIndexError {
     #[error(transparent)]
     WriterDirectoryError(#[from] SearchDirectoryError),
 
+    #[error(transparent)]
+    PendingInsertsError(#[from] PendingInsertsError),
+
+    #[error(transparent)]
+    PreparedInsertsError(#[from] PreparedInsertsError),
+
+    #[error(transparent)]
+    EncryptionError(#[from] EncryptionError),
+
     #[error("mutex lock on writer client failed: {0}")]
     WriterClientRace(String),
 
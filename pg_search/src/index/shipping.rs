@@ -0,0 +1,440 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::recovery::Resync;
+use super::storage::SearchDirectoryMode;
+use super::{SearchIndex, SearchIndexError};
+use crate::writer::{
+    SearchDirectoryError, SearchFs, ShippedStatusFilePath, TantivyDirPath, WriterDirectory,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tantivy::Directory;
+use thiserror::Error;
+
+static MANIFEST_FILE_NAME: &str = "manifest.json";
+static SEGMENTS_DIR_NAME: &str = "segments";
+static META_FILE_NAME: &str = "meta.json";
+static MANAGED_FILE_NAME: &str = ".managed.json";
+
+// Like a backup, shipping starts over if a merge removes segments while they're copied.
+const MAX_SHIPPING_ATTEMPTS: usize = 5;
+
+/// Describes the last commit of an index shipped by the primary. Saved next to the shipped
+/// segments, and replaced once the segments of a new commit are all copied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ShippingManifest {
+    pub uuid: String,
+    /// The opstamp of the shipped commit, which changes with every commit of the index.
+    pub opstamp: u64,
+    /// When the primary last found the shipped commit to be the latest, in seconds since
+    /// the Unix epoch.
+    pub shipped_at: u64,
+    pub num_docs: u64,
+    /// The files of the segments, relative to the segments directory.
+    pub files: Vec<PathBuf>,
+    /// The files of the previous commit, kept until the next one for standbys still
+    /// copying them.
+    pub previous_files: Vec<PathBuf>,
+    /// The tantivy meta of the commit.
+    pub meta: String,
+    /// The configuration of the index, as saved next to it.
+    pub config: serde_json::Value,
+}
+
+/// The shipped commit installed in an index of a standby.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ShippedStatus {
+    pub uuid: String,
+    pub opstamp: u64,
+    pub shipped_at: u64,
+}
+
+impl ShippedStatus {
+    pub fn load(directory: &WriterDirectory) -> Result<Option<Self>, SearchDirectoryError> {
+        let ShippedStatusFilePath(path) = directory.shipped_status_file_path()?;
+        let serialized = match fs::read_to_string(&path) {
+            Ok(serialized) => serialized,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(SearchDirectoryError::IndexFileRead(
+                    directory.clone(),
+                    path,
+                    err,
+                ))
+            }
+        };
+        serde_json::from_str(&serialized)
+            .map(Some)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let ShippedStatusFilePath(path) = directory.shipped_status_file_path()?;
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    /// How long ago the primary shipped the installed commit, or None if nothing was
+    /// installed. Measured with the clock of the standby.
+    pub fn staleness(
+        directory: &WriterDirectory,
+    ) -> Result<Option<Duration>, SearchDirectoryError> {
+        Ok(Self::load(directory)?
+            .map(|status| Duration::from_secs(unix_time().saturating_sub(status.shipped_at))))
+    }
+
+    /// Standbys only search indexes shipped to them, at most `max_staleness` ago if set.
+    pub fn check(
+        directory: &WriterDirectory,
+        max_staleness: Option<Duration>,
+    ) -> Result<(), ShippingError> {
+        let index_name = directory.index_name.clone();
+        match (Self::staleness(directory)?, max_staleness) {
+            (None, _) => Err(ShippingError::NotShipped(index_name)),
+            (Some(staleness), Some(max)) if staleness > max => Err(ShippingError::TooStale(
+                index_name,
+                staleness.as_secs(),
+                max.as_secs(),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Indexes aren't WAL-logged, so physical replicas can't maintain them. Instead, the primary
+/// copies the segments of the last commit of each index to a directory shared with its
+/// standbys, which install them in place of their own copy. Segments never change once
+/// written, so only the segments created since the last commit are copied each time.
+///
+/// The installed index lags behind the heap of the standby by at most the shipping interval.
+/// Physical replicas have the same ctids as the primary, and rows the index returns are
+/// checked against the snapshot of the query, so a lagging index can miss recent rows, but
+/// never returns rows the query can't see.
+pub struct SegmentShipping {}
+
+impl SegmentShipping {
+    fn shipped_path(root: &Path, directory: &WriterDirectory) -> PathBuf {
+        root.join(directory.dir_name())
+    }
+
+    pub fn manifest(
+        root: &Path,
+        directory: &WriterDirectory,
+    ) -> Result<Option<ShippingManifest>, ShippingError> {
+        let path = Self::shipped_path(root, directory).join(MANIFEST_FILE_NAME);
+        match fs::read_to_string(path) {
+            Ok(serialized) => Ok(Some(serde_json::from_str(&serialized)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The indexes shipped to `root`, as directories under `postgres_data_dir_path`.
+    pub fn list_shipped(
+        root: &Path,
+        postgres_data_dir_path: &Path,
+    ) -> Result<Vec<WriterDirectory>, ShippingError> {
+        if !root.exists() {
+            return Ok(vec![]);
+        }
+        let mut directories = vec![];
+        for entry in fs::read_dir(root)? {
+            let file_name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(directory) =
+                WriterDirectory::from_dir_name(&file_name, postgres_data_dir_path)
+            {
+                directories.push(directory);
+            }
+        }
+        Ok(directories)
+    }
+
+    /// Copy the segments of the last commit of an index to `root`, on the primary.
+    pub fn ship(
+        search_index: &SearchIndex,
+        root: &Path,
+    ) -> Result<ShippingManifest, ShippingError> {
+        // Standbys read segments in object storage from the store itself.
+        if let Some(storage) = &search_index.storage {
+            return Err(ShippingError::RemoteStorage(storage.url.clone()));
+        }
+        let directory = &search_index.directory;
+        let shipped_path = Self::shipped_path(root, directory);
+        let segments_path = shipped_path.join(SEGMENTS_DIR_NAME);
+        fs::create_dir_all(&segments_path)?;
+        let TantivyDirPath(source_path) = directory.tantivy_dir_path(false)?;
+
+        let previous =
+            Self::manifest(root, directory)?.filter(|previous| previous.uuid == search_index.uuid);
+        for _ in 0..MAX_SHIPPING_ATTEMPTS {
+            let meta = search_index.underlying_index.load_metas()?;
+            if let Some(previous) = previous.as_ref().filter(|p| p.opstamp == meta.opstamp) {
+                // Nothing was committed since, the shipped segments are still current.
+                let manifest = ShippingManifest {
+                    shipped_at: unix_time(),
+                    ..previous.clone()
+                };
+                Self::write_manifest(&shipped_path, &manifest)?;
+                return Ok(manifest);
+            }
+
+            let mut files: Vec<PathBuf> = meta
+                .segments
+                .iter()
+                .flat_map(|segment| segment.list_files())
+                .collect();
+            files.sort();
+            if !Self::copy_missing(&source_path, &segments_path, &files)? {
+                continue;
+            }
+
+            let manifest = ShippingManifest {
+                uuid: search_index.uuid.clone(),
+                opstamp: meta.opstamp,
+                shipped_at: unix_time(),
+                num_docs: meta
+                    .segments
+                    .iter()
+                    .map(|segment| segment.num_docs() as u64)
+                    .sum(),
+                files,
+                previous_files: previous.map(|p| p.files).unwrap_or_default(),
+                meta: serde_json::to_string_pretty(&meta)?,
+                config: serde_json::to_value(search_index)?,
+            };
+            Self::write_manifest(&shipped_path, &manifest)?;
+
+            let kept: HashSet<&PathBuf> = manifest
+                .files
+                .iter()
+                .chain(&manifest.previous_files)
+                .collect();
+            for entry in fs::read_dir(&segments_path)? {
+                let file = PathBuf::from(entry?.file_name());
+                if !kept.contains(&file) {
+                    fs::remove_file(segments_path.join(file))?;
+                }
+            }
+            return Ok(manifest);
+        }
+
+        Err(ShippingError::TooManyAttempts(MAX_SHIPPING_ATTEMPTS))
+    }
+
+    /// Install the commit shipped to `root` in the index of `directory`, on a standby.
+    /// Returns the installed manifest, or None if the index wasn't shipped.
+    pub fn install(
+        root: &Path,
+        directory: &WriterDirectory,
+    ) -> Result<Option<ShippingManifest>, ShippingError> {
+        let Some(manifest) = Self::manifest(root, directory)? else {
+            return Ok(None);
+        };
+        let status = ShippedStatus {
+            uuid: manifest.uuid.clone(),
+            opstamp: manifest.opstamp,
+            shipped_at: manifest.shipped_at,
+        };
+        let installed = ShippedStatus::load(directory)?;
+        if installed.is_some_and(|i| i.uuid == status.uuid && i.opstamp == status.opstamp) {
+            status.save(directory)?;
+            return Ok(Some(manifest));
+        }
+
+        let segments_path = Self::shipped_path(root, directory).join(SEGMENTS_DIR_NAME);
+        let TantivyDirPath(target_path) = directory.tantivy_dir_path(true)?;
+        if !Self::copy_missing(&segments_path, &target_path, &manifest.files)? {
+            // The primary shipped two commits since the manifest was read.
+            return Err(ShippingError::Outdated(manifest.opstamp));
+        }
+
+        // The configuration comes from the primary, but is pointed at the local directory.
+        let mut config = manifest.config.clone();
+        config["directory"] = serde_json::to_value(directory)?;
+        directory.save_index(&config)?;
+
+        // The meta file is written last, so that readers only see the new commit once all
+        // of its segments are there. Encrypted indexes encrypt it, like the writer does.
+        let encrypted = config["encrypted"].as_bool().unwrap_or(false);
+        let index_directory =
+            SearchIndex::open_directory(directory, SearchDirectoryMode::Mmap, None, encrypted)?;
+        let previous_files = match index_directory.atomic_read(Path::new(MANAGED_FILE_NAME)) {
+            Ok(managed) => serde_json::from_slice::<Vec<PathBuf>>(&managed).unwrap_or_default(),
+            Err(_) => vec![],
+        };
+        let mut managed = manifest.files.clone();
+        managed.push(META_FILE_NAME.into());
+        index_directory.atomic_write(
+            Path::new(MANAGED_FILE_NAME),
+            serde_json::to_string(&managed)?.as_bytes(),
+        )?;
+        index_directory.atomic_write(Path::new(META_FILE_NAME), manifest.meta.as_bytes())?;
+
+        // Searches that already opened the removed segments keep reading them until they end.
+        for file in previous_files.iter().filter(|file| !managed.contains(file)) {
+            match fs::remove_file(target_path.join(file)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+        }
+
+        // Once promoted, the standby can't tell how far behind its table the index is.
+        Resync::mark(directory)?;
+        status.save(directory)?;
+        Ok(Some(manifest))
+    }
+
+    /// Copy the files missing from `target_path`, and return false if one of them is
+    /// missing from `source_path` too.
+    fn copy_missing(
+        source_path: &Path,
+        target_path: &Path,
+        files: &[PathBuf],
+    ) -> Result<bool, ShippingError> {
+        for file in files {
+            let target = target_path.join(file);
+            if target.exists() {
+                continue;
+            }
+            // Copied under another name first, so that a partial copy is never mistaken for
+            // the segment file.
+            let partial = target_path.join(format!("{}.partial", file.display()));
+            match fs::copy(source_path.join(file), &partial) {
+                Ok(_) => fs::rename(&partial, &target)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    fn write_manifest(
+        shipped_path: &Path,
+        manifest: &ShippingManifest,
+    ) -> Result<(), ShippingError> {
+        let partial = shipped_path.join(format!("{MANIFEST_FILE_NAME}.partial"));
+        fs::write(&partial, serde_json::to_string_pretty(manifest)?)?;
+        fs::rename(&partial, shipped_path.join(MANIFEST_FILE_NAME))?;
+        Ok(())
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since_epoch| since_epoch.as_secs())
+}
+
+#[derive(Debug, Error)]
+pub enum ShippingError {
+    #[error("index is kept in object storage at {0}, standbys read it from the store")]
+    RemoteStorage(String),
+
+    #[error("index {0} was not shipped to this standby, see paradedb.segment_shipping_path")]
+    NotShipped(String),
+
+    #[error(
+        "index {0} was shipped {1} seconds ago, over paradedb.replica_max_staleness of {2} seconds"
+    )]
+    TooStale(String, u64, u64),
+
+    #[error("shipped commit {0} was replaced while it was installed")]
+    Outdated(u64),
+
+    #[error("segments kept changing during shipping, gave up after {0} attempts")]
+    TooManyAttempts(usize),
+
+    #[error(transparent)]
+    SearchIndexError(#[from] SearchIndexError),
+
+    #[error(transparent)]
+    SearchDirectoryError(#[from] SearchDirectoryError),
+
+    #[error(transparent)]
+    TantivyError(#[from] tantivy::TantivyError),
+
+    #[error(transparent)]
+    IOError(#[from] io::Error),
+
+    #[error(transparent)]
+    SerdeError(#[from] serde_json::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SegmentShipping, ShippedStatus};
+    use crate::fixtures::*;
+    use crate::index::recovery::Resync;
+    use crate::index::SearchIndex;
+    use crate::writer::{SearchFs, WriterDirectory};
+    use rstest::*;
+    use std::time::Duration;
+
+    #[rstest]
+    fn test_ship_and_install(default_index: MockSearchIndex, mock_dir: MockWriterDirectory) {
+        let index = default_index.index;
+        let mut document = index.schema.new_document();
+        document.insert(index.schema.key_field().id, 1i64.into());
+        document.insert(index.schema.ctid_field().id, 10u64.into());
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        writer.add_document(document.into()).unwrap();
+        writer.commit().unwrap();
+
+        let root = tempfile::tempdir().unwrap();
+        let manifest = SegmentShipping::ship(index, root.path()).unwrap();
+        assert_eq!(manifest.num_docs, 1);
+
+        // The standby has a data directory of its own.
+        let standby = WriterDirectory {
+            postgres_data_dir_path: mock_dir.writer_dir.postgres_data_dir_path.clone(),
+            ..index.directory.clone()
+        };
+        assert_eq!(
+            SegmentShipping::list_shipped(root.path(), &standby.postgres_data_dir_path).unwrap(),
+            vec![standby.clone()]
+        );
+        assert_eq!(ShippedStatus::staleness(&standby).unwrap(), None);
+        assert!(ShippedStatus::check(&standby, None).is_err());
+
+        SegmentShipping::install(root.path(), &standby).unwrap();
+        let installed: SearchIndex = standby.load_index().unwrap();
+        assert_eq!(installed.searcher().num_docs(), 1);
+        assert!(ShippedStatus::staleness(&standby).unwrap().unwrap() < Duration::from_secs(60));
+        assert!(Resync::is_needed(&standby).unwrap());
+        ShippedStatus::check(&standby, Some(Duration::from_secs(60))).unwrap();
+        ShippedStatus {
+            shipped_at: 0,
+            ..ShippedStatus::load(&standby).unwrap().unwrap()
+        }
+        .save(&standby)
+        .unwrap();
+        assert!(ShippedStatus::check(&standby, Some(Duration::from_secs(60))).is_err());
+
+        // Without new commits, the same segments are shipped again.
+        let reshipped = SegmentShipping::ship(index, root.path()).unwrap();
+        assert_eq!(reshipped.files, manifest.files);
+        assert_eq!(reshipped.opstamp, manifest.opstamp);
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::writer::{SearchDirectoryError, SourceFilePath, WriterDirectory};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// The foreign table, view or materialized view that the rows of an index are copied from.
+/// Indexes can only be built on tables, so `create_bm25` copies the rows of other relations
+/// to a table of the schema of the index, which `refresh_bm25` brings up to date. Saved next
+/// to the index, so that the merge worker finds the indexes to refresh on a schedule.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexSource {
+    /// The relation the rows are copied from, schema qualified and quoted.
+    pub source: String,
+    /// The table the rows are copied to, schema qualified and quoted.
+    pub copy: String,
+    pub key_field: String,
+    /// A column of the source holding when a row was last modified. Refreshes only copy the
+    /// rows modified since the last one copied, without removing the rows deleted from the
+    /// source. Without it, every refresh copies all of the rows.
+    pub modified_column: Option<String>,
+    /// Seconds between two refreshes by the refresh workers, or 0 to only refresh when
+    /// `refresh_bm25` is called.
+    #[serde(default)]
+    pub refresh_interval: u64,
+    /// When the rows were last refreshed, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub refreshed_at: u64,
+}
+
+impl IndexSource {
+    pub fn load(directory: &WriterDirectory) -> Result<Option<Self>, SearchDirectoryError> {
+        let SourceFilePath(path) = directory.source_file_path()?;
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let serialized = fs::read_to_string(&path)
+            .map_err(|err| SearchDirectoryError::IndexFileRead(directory.clone(), path, err))?;
+        serde_json::from_str(&serialized)
+            .map(Some)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let SourceFilePath(path) = directory.source_file_path()?;
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+
+    pub fn is_scheduled(&self) -> bool {
+        self.refresh_interval > 0
+    }
+
+    /// Whether a scheduled refresh is due at `now`, in seconds since the Unix epoch.
+    pub fn is_due(&self, now: u64) -> bool {
+        self.is_scheduled() && now >= self.refreshed_at.saturating_add(self.refresh_interval)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexSource;
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_source_schedule(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        let mut source = IndexSource {
+            source: "public.remote_items".into(),
+            copy: "items_idx.source_rows".into(),
+            key_field: "id".into(),
+            modified_column: Some("updated_at".into()),
+            refresh_interval: 0,
+            refreshed_at: 1000,
+        };
+        assert!(!source.is_due(u64::MAX));
+
+        source.refresh_interval = 60;
+        assert!(!source.is_due(1059));
+        assert!(source.is_due(1060));
+
+        assert_eq!(IndexSource::load(&directory).unwrap(), None);
+        source.save(&directory).unwrap();
+        assert_eq!(IndexSource::load(&directory).unwrap(), Some(source));
+    }
+}
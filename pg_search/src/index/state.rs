@@ -15,19 +15,36 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use super::score::SearchIndexScore;
+use super::cache::{IndexGeneration, QueryCache, QueryCacheKey, QueryPlan, QueryPlanKey};
+use super::collector::{
+    compare_sort_keys, key_reader, random_score, FieldSortTopDocs, SortKey, SortKeyReader,
+    StableTopDocs,
+};
+use super::deadline::{Deadline, DeadlineWeight};
+use super::dedup::{LatestVersionWeight, LatestVersions};
+use super::profile::SearchProfile;
 use super::SearchIndex;
+use crate::globals::SEARCHES;
+use crate::postgres::audit::audit_search;
 use crate::postgres::types::TantivyValue;
-use crate::schema::{SearchConfig, SearchFieldName, SearchFieldType, SearchIndexSchema};
+use crate::query::stats::{query_shape, record_search};
+use crate::query::{max_term_expansions, report_query_error, SearchQueryInput};
+use crate::schema::{SearchConfig, SearchFieldName, SearchIndexSchema, SortField, TotalHitsMode};
+use crate::SEARCH_GUCS;
 use derive_more::{AsRef, Display, From};
 use once_cell::sync::Lazy;
+use pgrx::{check_for_interrupts, ereport, PgLogLevel, PgSqlErrorCode};
 use serde::{Deserialize, Serialize};
 use shared::postgres::transaction::{Transaction, TransactionError};
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex, PoisonError};
-use tantivy::collector::TopDocs;
-use tantivy::schema::{FieldType, Value};
-use tantivy::{query::Query, DocAddress, Score, Searcher};
+use std::time::{Duration, Instant};
+use tantivy::collector::{Collector, SegmentCollector, TopDocs};
+use tantivy::query::{Bm25StatisticsProvider, EnableScoring, Weight};
+use tantivy::schema::{Field, FieldType, Value};
+use tantivy::tokenizer::TokenStream;
+use tantivy::{query::Query, DocAddress, DocSet, Score, Searcher, Term};
 use tantivy::{Executor, Snippet, SnippetGenerator, TantivyDocument};
 use thiserror::Error;
 
@@ -35,14 +52,19 @@ static SEARCH_STATE_MANAGER: Lazy<Arc<Mutex<SearchStateManager>>> = Lazy::new(||
     Arc::new(Mutex::new(SearchStateManager {
         state_map: HashMap::new(),
         result_map: HashMap::new(),
+        total_hits_map: HashMap::new(),
     }))
 });
 
 const TRANSACTION_CALLBACK_CACHE_ID: &str = "parade_current_search";
 
+/// The count after which searches counting a lower bound of their total hits stop.
+const DEFAULT_TOTAL_HITS_THRESHOLD: u64 = 10_000;
+
 pub struct SearchStateManager {
     state_map: HashMap<SearchAlias, SearchState>,
-    result_map: HashMap<SearchAlias, HashMap<TantivyValue, (Score, DocAddress)>>,
+    result_map: HashMap<SearchAlias, HashMap<TantivyValue, (Score, DocAddress, u64)>>,
+    total_hits_map: HashMap<SearchAlias, TotalHits>,
 }
 
 impl SearchStateManager {
@@ -55,12 +77,14 @@ impl SearchStateManager {
                 .lock()
                 .expect("could not lock current search lookup in commit callback");
             current_search.state_map.drain();
+            current_search.total_hits_map.drain();
         })?;
         Transaction::call_once_on_abort(TRANSACTION_CALLBACK_CACHE_ID.to_string(), move || {
             let mut current_search = SEARCH_STATE_MANAGER
                 .lock()
                 .expect("could not lock current search lookup in abort callback");
             current_search.state_map.drain();
+            current_search.total_hits_map.drain();
         })?;
         Ok(())
     }
@@ -85,7 +109,7 @@ impl SearchStateManager {
             .lock()
             .map_err(SearchStateError::from)?;
         let result_map = &manager.result_map;
-        let (score, _) = result_map
+        let (score, _, _) = result_map
             .get(&alias.unwrap_or_default())
             .and_then(|inner_map| inner_map.get(&key))
             .ok_or(SearchStateError::DocLookup(key))?;
@@ -93,6 +117,24 @@ impl SearchStateManager {
         Ok(*score)
     }
 
+    /// The position of the result with `key` in the ordering of its search, starting at 1
+    /// for the first result and counting the rows skipped by `offset_rows`.
+    pub fn get_rank(
+        key: TantivyValue,
+        alias: Option<SearchAlias>,
+    ) -> Result<u64, SearchStateError> {
+        let manager = SEARCH_STATE_MANAGER
+            .lock()
+            .map_err(SearchStateError::from)?;
+        let (_, _, rank) = manager
+            .result_map
+            .get(&alias.unwrap_or_default())
+            .and_then(|inner_map| inner_map.get(&key))
+            .ok_or(SearchStateError::DocLookup(key))?;
+
+        Ok(*rank)
+    }
+
     pub fn get_snippet(
         key: TantivyValue,
         field_name: &str,
@@ -110,18 +152,63 @@ impl SearchStateManager {
 
         let alias = alias.unwrap_or_default();
 
-        let (_, doc_address) = manager
+        let (_, doc_address, _) = manager
             .result_map
             .get(&alias)
             .and_then(|inner_map| inner_map.get(&key))
             .ok_or(SearchStateError::DocLookup(key))?;
-        let doc: TantivyDocument = state
-            .searcher
-            .doc(*doc_address)
-            .expect("could not find document in searcher");
+        let doc = state.doc(*doc_address);
         Ok(snippet_generator.snippet_from_doc(&doc))
     }
 
+    pub fn get_total_hits(alias: Option<SearchAlias>) -> Result<TotalHits, SearchStateError> {
+        let manager = SEARCH_STATE_MANAGER
+            .lock()
+            .map_err(SearchStateError::from)?;
+        let alias = alias.unwrap_or_default();
+        manager
+            .total_hits_map
+            .get(&alias)
+            .copied()
+            .ok_or(SearchStateError::NoTotalHits(alias))
+    }
+
+    pub fn get_highlight_offsets(
+        key: TantivyValue,
+        field_name: &str,
+        alias: Option<SearchAlias>,
+    ) -> Result<Vec<HighlightOffset>, SearchStateError> {
+        let manager = SEARCH_STATE_MANAGER
+            .lock()
+            .map_err(SearchStateError::from)?;
+        let state = manager.get_state(alias.clone())?;
+
+        let (_, doc_address, _) = manager
+            .result_map
+            .get(&alias.unwrap_or_default())
+            .and_then(|inner_map| inner_map.get(&key))
+            .ok_or(SearchStateError::DocLookup(key))?;
+        let doc = state.doc(*doc_address);
+        Ok(state.highlight_offsets(field_name, &doc))
+    }
+
+    pub fn get_matched_queries(
+        key: TantivyValue,
+        alias: Option<SearchAlias>,
+    ) -> Result<Vec<String>, SearchStateError> {
+        let manager = SEARCH_STATE_MANAGER
+            .lock()
+            .map_err(SearchStateError::from)?;
+        let state = manager.get_state(alias.clone())?;
+
+        let (_, doc_address, _) = manager
+            .result_map
+            .get(&alias.unwrap_or_default())
+            .and_then(|inner_map| inner_map.get(&key))
+            .ok_or(SearchStateError::DocLookup(key))?;
+        Ok(state.matched_queries(*doc_address))
+    }
+
     pub fn get_state(&self, alias: Option<SearchAlias>) -> Result<&SearchState, SearchStateError> {
         if let Some(alias) = alias {
             self.get_state_alias(alias)
@@ -170,6 +257,7 @@ impl SearchStateManager {
         key: TantivyValue,
         score: Score,
         doc_address: DocAddress,
+        rank: u64,
         alias: Option<SearchAlias>,
     ) -> Result<(), SearchStateError> {
         let mut manager = SEARCH_STATE_MANAGER
@@ -180,7 +268,20 @@ impl SearchStateManager {
             .result_map
             .entry(alias.unwrap_or_default())
             .or_insert_with(HashMap::new)
-            .insert(key, (score, doc_address));
+            .insert(key, (score, doc_address, rank));
+        Ok(())
+    }
+
+    pub fn set_total_hits(
+        total_hits: TotalHits,
+        alias: Option<SearchAlias>,
+    ) -> Result<(), SearchStateError> {
+        let mut manager = SEARCH_STATE_MANAGER
+            .lock()
+            .map_err(SearchStateError::from)?;
+        manager
+            .total_hits_map
+            .insert(alias.unwrap_or_default(), total_hits);
         Ok(())
     }
 }
@@ -199,6 +300,8 @@ pub enum SearchStateError {
     DocLookup(TantivyValue),
     #[error("no query found with alias: '{0}'")]
     AliasLookup(SearchAlias),
+    #[error("no query with alias '{0}' counted its total hits, pass 'exact' or 'lower_bound' as its total_hits")]
+    NoTotalHits(SearchAlias),
     #[error("could not lock the current search config lookup: {0}")]
     Lock(String),
     #[error("could not register callback for search state manager: {0}")]
@@ -211,6 +314,25 @@ impl<T> From<PoisonError<T>> for SearchStateError {
     }
 }
 
+/// The number of documents matching a search, counted when its `total_hits` is set. If the
+/// count isn't exact, it stopped at the threshold of the search, and more documents match.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TotalHits {
+    pub count: u64,
+    pub exact: bool,
+}
+
+/// Where a term of a query is in the text of a field, as offsets from the start of the text,
+/// which end after the term.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HighlightOffset {
+    pub term: String,
+    pub start_byte: usize,
+    pub end_byte: usize,
+    pub start_char: usize,
+    pub end_char: usize,
+}
+
 #[derive(Clone, Debug, Display, AsRef, Eq, PartialEq, Hash, From, Deserialize, Serialize)]
 #[as_ref(forward)]
 pub struct SearchAlias(String);
@@ -227,28 +349,109 @@ impl Default for SearchAlias {
     }
 }
 
+/// The BM25 statistics of the committed index and of the documents buffered by the current
+/// transaction together, so that both are scored alike and their results can be merged.
+/// Scored with the statistics of their own searcher, the few buffered documents would get
+/// scores unrelated to the ones of committed documents.
+struct SearchStatistics<'a> {
+    searchers: Vec<&'a Searcher>,
+}
+
+impl Bm25StatisticsProvider for SearchStatistics<'_> {
+    fn total_num_tokens(&self, field: Field) -> tantivy::Result<u64> {
+        self.searchers
+            .iter()
+            .map(|searcher| searcher.total_num_tokens(field))
+            .sum()
+    }
+
+    fn total_num_docs(&self) -> tantivy::Result<u64> {
+        self.searchers
+            .iter()
+            .map(|searcher| searcher.total_num_docs())
+            .sum()
+    }
+
+    fn doc_freq(&self, term: &Term) -> tantivy::Result<u64> {
+        self.searchers
+            .iter()
+            .map(|searcher| searcher.doc_freq(term))
+            .sum()
+    }
+}
+
 #[derive(Clone)]
 pub struct SearchState {
     pub query: Arc<dyn Query>,
     pub searcher: Searcher,
+    /// A searcher over documents inserted by the current transaction, but not yet committed.
+    /// Results from this searcher use segment ordinals that start after the last segment
+    /// of `searcher`, so that a `DocAddress` is unique across both.
+    pub pending_searcher: Option<Searcher>,
     pub config: SearchConfig,
+    /// The query that is searched, with the tenant and security filters of the search.
+    pub search_query: SearchQueryInput,
     pub schema: SearchIndexSchema,
+    /// How long parsing the query took, reported by slow searches.
+    pub parse_duration: Duration,
+    /// The named sub-queries of the query, checked against results by `matched_queries`.
+    pub named_queries: Vec<(String, Arc<dyn Query>)>,
 }
 
 impl SearchState {
-    pub fn new(search_index: &SearchIndex, config: &SearchConfig) -> Self {
+    pub fn new(
+        search_index: &SearchIndex,
+        config: &SearchConfig,
+        searcher: Searcher,
+        pending_searcher: Option<Searcher>,
+    ) -> Self {
+        let parse_start = Instant::now();
         let schema = search_index.schema.clone();
         let mut parser = search_index.query_parser();
-        let query = config
-            .query
-            .clone()
-            .into_tantivy_query(&schema, &mut parser)
-            .expect("could not parse query");
+        let search_query = config.search_query(&schema);
+        let compile = || {
+            let query = search_query
+                .clone()
+                .into_tantivy_query(&schema, &mut parser)
+                .unwrap_or_else(|err| report_query_error(err));
+            let named_queries = search_query
+                .named_queries()
+                .into_iter()
+                .map(|(name, query)| {
+                    let query = query
+                        .clone()
+                        .into_tantivy_query(&schema, &mut parser)
+                        .unwrap_or_else(|err| report_query_error(err));
+                    (name.to_string(), Arc::from(query))
+                })
+                .collect();
+            QueryPlan {
+                query: Arc::from(query),
+                named_queries,
+            }
+        };
+        let QueryPlan {
+            query,
+            named_queries,
+        } = if search_query.is_plan_cacheable() {
+            QueryCache::get_or_compile(
+                SEARCH_GUCS.query_plan_cache_size.get() as usize,
+                QueryPlanKey::new(&config.index_name, &search_index.uuid, &search_query)
+                    .max_term_expansions(max_term_expansions()),
+                compile,
+            )
+        } else {
+            compile()
+        };
         SearchState {
-            query: Arc::new(query),
+            query,
             config: config.clone(),
-            searcher: search_index.searcher(),
+            search_query,
+            searcher,
+            pending_searcher,
             schema: schema.clone(),
+            parse_duration: parse_start.elapsed(),
+            named_queries,
         }
     }
 
@@ -267,17 +470,77 @@ impl SearchState {
         }
     }
 
+    /// Where the terms of the query that the snippet generator of the field highlights are in
+    /// the text of `doc`, found by running the text through the tokenizer of the field again,
+    /// like the snippet generator does, but over the whole text instead of its best fragment.
+    pub fn highlight_offsets(
+        &self,
+        field_name: &str,
+        doc: &TantivyDocument,
+    ) -> Vec<HighlightOffset> {
+        let snippet_generator = self.snippet_generator(field_name);
+        let terms = snippet_generator.terms_text();
+        let field = self
+            .schema
+            .get_search_field(&SearchFieldName(field_name.into()))
+            .expect("cannot highlight, field does not exist")
+            .id
+            .0;
+        let mut analyzer = self
+            .searcher
+            .index()
+            .tokenizer_for_field(field)
+            .unwrap_or_else(|err| {
+                panic!("failed to get tokenizer for field: {field_name}... {err}")
+            });
+
+        let mut offsets = vec![];
+        if let Some(text) = doc.get_first(field).and_then(|value| value.as_str()) {
+            let mut token_stream = analyzer.token_stream(text);
+            while token_stream.advance() {
+                let token = token_stream.token();
+                if terms.contains_key(&token.text) {
+                    offsets.push(HighlightOffset {
+                        term: token.text.clone(),
+                        start_byte: token.offset_from,
+                        end_byte: token.offset_to,
+                        start_char: text[..token.offset_from].chars().count(),
+                        end_char: text[..token.offset_to].chars().count(),
+                    });
+                }
+            }
+        }
+        offsets
+    }
+
     /// Search the Tantivy index for matching documents. If used outside of Postgres
     /// index access methods, this may return deleted rows until a VACUUM. If you need to scan
     /// the Tantivy index without a Postgres deduplication, you should use the `search_dedup`
     /// method instead.
     pub fn search(&self, executor: &Executor) -> Vec<(Score, DocAddress, TantivyValue, u64)> {
+        self.search_versions(executor, false)
+    }
+
+    /// Search the Tantivy index, skipping the documents replaced by a later version of their
+    /// row if `latest_only` is set.
+    fn search_versions(
+        &self,
+        executor: &Executor,
+        latest_only: bool,
+    ) -> Vec<(Score, DocAddress, TantivyValue, u64)> {
+        let search_start = Instant::now();
+        let profile = SearchProfile::new(self.parse_duration);
+
         // Extract limit and offset from the query config or set defaults.
         let limit = self.config.limit_rows.unwrap_or_else(|| {
             // We use unwrap_or_else here so this block doesn't run unless
             // we actually need the default value. This is important, because there can
             // be some cost to Tantivy API calls.
-            let num_docs = self.searcher.num_docs() as usize;
+            let num_docs = self.searcher.num_docs() as usize
+                + self
+                    .pending_searcher
+                    .as_ref()
+                    .map_or(0, |pending| pending.num_docs() as usize);
             if num_docs > 0 {
                 num_docs // The collector will panic if it's passed a limit of 0.
             } else {
@@ -287,166 +550,493 @@ impl SearchState {
 
         let offset = self.config.offset_rows.unwrap_or(0);
 
-        if self.config.stable_sort.is_some_and(|stable| stable) {
-            // If the user requires a stable sort, we'll use tweak_score. This allows us to retrieve
-            // the value of a fast field and use that as a secondary sort key. In the case of a
-            // bm25 score tie, results will be ordered based on the value of their 'key_field'.
-            // This has a big performance impact, so the user needs to opt-in.
-            let key_field_name = self.config.key_field.clone();
+        // The timeout of the query, or else the default of the connection. 0 disables it.
+        let timeout_ms = self
+            .config
+            .timeout_ms
+            .unwrap_or(SEARCH_GUCS.search_timeout.get() as u64);
+        let deadline = Deadline::new((timeout_ms > 0).then(|| Duration::from_millis(timeout_ms)));
+
+        let latest = latest_only.then(|| {
+            let segments = std::iter::once(&self.searcher)
+                .chain(self.pending_searcher.as_ref())
+                .flat_map(|searcher| searcher.segment_readers().iter().cloned())
+                .collect();
+            Arc::new(LatestVersions::new(
+                self.schema.clone(),
+                self.config.key_field.clone(),
+                segments,
+            ))
+        });
+
+        let results = match &self.pending_searcher {
+            // Results depending on uncommitted documents can't be reused, so only searches
+            // of the committed index are cached.
+            None => QueryCache::get_or_search(
+                SEARCH_GUCS.query_cache_size.get() as usize,
+                QueryCacheKey::from(&self.config)
+                    .search_query(&self.search_query)
+                    .latest_only(latest_only),
+                || IndexGeneration::from(&self.searcher),
+                || {
+                    self.top_docs(
+                        &self.searcher,
+                        0,
+                        executor,
+                        limit,
+                        offset,
+                        &profile,
+                        &deadline,
+                        latest.as_ref(),
+                    )
+                },
+            ),
+            Some(pending_searcher) => {
+                // Uncommitted documents can rank anywhere among committed ones, so we collect
+                // enough results from both searchers and apply the offset after merging.
+                let segment_offset = self.searcher.segment_readers().len() as u32;
+                let mut merged = self.top_docs(
+                    &self.searcher,
+                    0,
+                    executor,
+                    limit + offset,
+                    0,
+                    &profile,
+                    &deadline,
+                    latest.as_ref(),
+                );
+                merged.extend(self.top_docs(
+                    pending_searcher,
+                    segment_offset,
+                    executor,
+                    limit + offset,
+                    0,
+                    &profile,
+                    &deadline,
+                    latest.as_ref(),
+                ));
+                if let Some(sort_by) = &self.config.sort_by {
+                    let mut keyed: Vec<_> = merged
+                        .into_iter()
+                        .map(|result| (self.sort_key(sort_by, result.1), result))
+                        .collect();
+                    keyed.sort_by(
+                        |(sort_key_a, (score_a, _, key_a, _)),
+                         (sort_key_b, (score_b, _, key_b, _))| {
+                            compare_sort_keys(sort_by, sort_key_a, sort_key_b)
+                                .then_with(|| {
+                                    score_b
+                                        .partial_cmp(score_a)
+                                        .unwrap_or(std::cmp::Ordering::Equal)
+                                })
+                                .then_with(|| {
+                                    key_a
+                                        .partial_cmp(key_b)
+                                        .unwrap_or(std::cmp::Ordering::Equal)
+                                })
+                        },
+                    );
+                    merged = keyed.into_iter().map(|(_, result)| result).collect();
+                } else {
+                    merged.sort_by(|(score_a, _, key_a, _), (score_b, _, key_b, _)| {
+                        score_b
+                            .partial_cmp(score_a)
+                            .unwrap_or(std::cmp::Ordering::Equal)
+                            .then_with(|| {
+                                key_a
+                                    .partial_cmp(key_b)
+                                    .unwrap_or(std::cmp::Ordering::Equal)
+                            })
+                    });
+                }
+                merged.into_iter().skip(offset).take(limit).collect()
+            }
+        };
+
+        if let Some(mode) = self.config.total_hits {
+            let total_hits = self.total_hits(mode).expect("failed to count total hits");
+            SearchStateManager::set_total_hits(total_hits, self.config.alias.clone())
+                .expect("could not store total hits in state manager");
+        }
+
+        for (position, (score, doc_address, key, _)) in results.iter().enumerate() {
+            // This iterator contains the results after limit + offset are applied.
+            SearchStateManager::set_result(
+                key.clone(),
+                *score,
+                *doc_address,
+                (offset + position + 1) as u64,
+                self.config.alias.clone(),
+            )
+            .expect("could not store search result in state manager");
+        }
+
+        let log_min_duration = SEARCH_GUCS.log_min_search_duration.get();
+        let duration = self.parse_duration + search_start.elapsed();
+        if log_min_duration >= 0 && duration >= Duration::from_millis(log_min_duration as u64) {
+            let query = serde_json::to_string(&self.config.query).unwrap_or_default();
+            profile.report(&self.config.index_name, &query, duration);
+        }
+        SEARCHES.get().fetch_add(1, Ordering::Relaxed);
+        record_search(
+            unsafe { pgrx::pg_sys::MyDatabaseId },
+            &self.config.index_name,
+            &query_shape(&self.config.query),
+            duration,
+            results.len() as u64,
+        );
+        audit_search(&self.config.index_name, &self.config.query, results.len());
+
+        results
+    }
+
+    /// Count the documents matching the query, segment by segment, committed ones first. A
+    /// query counts the documents of a segment without scoring them, and term queries over
+    /// segments without deletes only read the number of documents containing the term. In
+    /// `lower_bound` mode, segments stop being counted once the threshold is reached. Like
+    /// aggregates, the count includes deleted rows until they're vacuumed.
+    fn total_hits(&self, mode: TotalHitsMode) -> tantivy::Result<TotalHits> {
+        let threshold = match mode {
+            TotalHitsMode::Exact => u64::MAX,
+            TotalHitsMode::LowerBound => self
+                .config
+                .total_hits_threshold
+                .unwrap_or(DEFAULT_TOTAL_HITS_THRESHOLD),
+        };
+
+        let searchers: Vec<&Searcher> = std::iter::once(&self.searcher)
+            .chain(self.pending_searcher.as_ref())
+            .collect();
+        let num_segments: usize = searchers
+            .iter()
+            .map(|searcher| searcher.segment_readers().len())
+            .sum();
+
+        let mut count = 0;
+        let mut counted_segments = 0;
+        for searcher in searchers {
+            let weight = self
+                .query
+                .weight(EnableScoring::disabled_from_searcher(searcher))?;
+            for segment_reader in searcher.segment_readers() {
+                count += weight.count(segment_reader)? as u64;
+                counted_segments += 1;
+                if count >= threshold && counted_segments < num_segments {
+                    return Ok(TotalHits {
+                        count,
+                        exact: false,
+                    });
+                }
+            }
+        }
+        Ok(TotalHits { count, exact: true })
+    }
+
+    /// Collect the top documents from a single searcher. The segment ordinals of the returned
+    /// addresses are shifted by `segment_offset`, see `SearchState::pending_searcher`.
+    fn top_docs(
+        &self,
+        searcher: &Searcher,
+        segment_offset: u32,
+        executor: &Executor,
+        limit: usize,
+        offset: usize,
+        profile: &SearchProfile,
+        deadline: &Deadline,
+        latest: Option<&Arc<LatestVersions>>,
+    ) -> Vec<(Score, DocAddress, TantivyValue, u64)> {
+        let rebase = |doc_address: DocAddress| {
+            DocAddress::new(doc_address.segment_ord + segment_offset, doc_address.doc_id)
+        };
+
+        // Without scoring, every document gets the same score, so the collectors keep them in
+        // index order and stop at the first `limit` documents.
+        let statistics = SearchStatistics {
+            searchers: std::iter::once(&self.searcher)
+                .chain(self.pending_searcher.as_ref())
+                .collect(),
+        };
+        let scoring = if self.config.scored.unwrap_or(true) {
+            EnableScoring::Enabled {
+                searcher,
+                statistics_provider: &statistics,
+            }
+        } else {
+            EnableScoring::disabled_from_searcher(searcher)
+        };
+        // Both collectors skip documents that can't reach the top results, which lets
+        // disjunctive queries use block-max WAND, unless the query asks for exact scoring.
+        let exact = self.config.exact.is_some_and(|exact| exact);
+
+        if let Some(sort_by) = &self.config.sort_by {
+            let collector = FieldSortTopDocs::new(
+                self.schema.clone(),
+                sort_by.clone(),
+                self.config.key_field.clone(),
+                limit,
+                offset,
+            );
+            self.collect(
+                searcher,
+                segment_offset,
+                &collector,
+                executor,
+                scoring,
+                profile,
+                deadline,
+                latest,
+            )
+            .expect("failed to search")
+            .into_iter()
+            .map(|(_, score, doc_address)| {
+                let doc_address = rebase(doc_address);
+                let (key, ctid) = self.key_and_ctid_value(doc_address);
+                (score, doc_address, key, ctid)
+            })
+            .collect()
+        } else if let Some(seed) = self.config.random_seed {
+            // BM25 scores are replaced, so there's no need to compute them.
             let schema = self.schema.clone();
+            let key_field = self.config.key_field.clone();
             let collector = TopDocs::with_limit(limit).and_offset(offset).tweak_score(
-                move |segment_reader: &tantivy::SegmentReader| -> Box<dyn FnMut(tantivy::DocId, Score) -> SearchIndexScore> {
-                    let fast_fields = segment_reader
-                        .fast_fields();
-
-                    // Check the type of the field from the schema
-                    match schema.get_search_field(&key_field_name.clone().into()).unwrap_or_else(|| panic!("key field {} not found", key_field_name)).type_ {
-                        SearchFieldType::I64 => {
-                            let key_field_reader = fast_fields
-                                .i64(&key_field_name)
-                                .unwrap_or_else(|err| panic!("key field {} is not a i64: {err:?}", key_field_name))
-                                .first_or_default_col(0);
-
-                            Box::new(move |doc: tantivy::DocId, original_score: tantivy::Score| {
-                                let val = key_field_reader.get_val(doc);
-                                SearchIndexScore {
-                                    bm25: original_score,
-                                    key: TantivyValue(val.into()),
-                                }
-                            })
-                        }
-                        SearchFieldType::U64 => {
-                            let key_field_reader = fast_fields
-                                .u64(&key_field_name)
-                                .unwrap_or_else(|err| panic!("key field {} is not a u64: {err:?}", key_field_name))
-                                .first_or_default_col(0);
-
-                            Box::new(move |doc: tantivy::DocId, original_score: tantivy::Score| {
-                                SearchIndexScore {
-                                    bm25: original_score,
-                                    key: TantivyValue(key_field_reader.get_val(doc).into()),
-                                }
-                            })
-                        }
-                        SearchFieldType::F64 => {
-                            let key_field_reader = fast_fields
-                                .f64(&key_field_name)
-                                .unwrap_or_else(|err| panic!("key field {} is not a f64: {err:?}", key_field_name))
-                                .first_or_default_col(0.0);
-
-                            Box::new(move |doc: tantivy::DocId, original_score: tantivy::Score| {
-                                SearchIndexScore {
-                                    bm25: original_score,
-                                    key: TantivyValue(key_field_reader.get_val(doc).into()),
-                                }
-                            })
-                        }
-                        SearchFieldType::Text => {
-                            let key_field_reader = fast_fields
-                                .str(&key_field_name)
-                                .unwrap_or_else(|err| panic!("key field {} is not a string: {err:?}", key_field_name))
-                                .unwrap();
-
-                            Box::new(move |doc: tantivy::DocId, original_score: tantivy::Score| {
-                                let mut tok_str: String = Default::default();
-                                let ord = key_field_reader.term_ords(doc).nth(0).unwrap();
-                                key_field_reader.ord_to_str(ord, &mut tok_str).expect("no string!!");
-                                SearchIndexScore {
-                                    bm25: original_score,
-                                    key: TantivyValue(tok_str.clone().into()),
-                                }
-                            })
-                        }
-                        SearchFieldType::Bool => {
-                            let key_field_reader = fast_fields
-                                .bool(&key_field_name)
-                                .unwrap_or_else(|err| panic!("key field {} is not a bool: {err:?}", key_field_name))
-                                .first_or_default_col(false);
-
-                            Box::new(move |doc: tantivy::DocId, original_score: tantivy::Score| {
-                                SearchIndexScore {
-                                    bm25: original_score,
-                                    key: TantivyValue(key_field_reader.get_val(doc).into()),
-                                }
-                            })
-                        }
-                        SearchFieldType::Date => {
-                            let key_field_reader = fast_fields
-                                .date(&key_field_name)
-                                .unwrap_or_else(|err| panic!("key field {} is not a date: {err:?}", key_field_name))
-                                .first_or_default_col(tantivy::DateTime::MIN);
-
-                            Box::new(move |doc: tantivy::DocId, original_score: tantivy::Score| {
-                                SearchIndexScore {
-                                    bm25: original_score,
-                                    key: TantivyValue(key_field_reader.get_val(doc).into()),
-                                }
-                            })
-                        }
-                        _ => panic!("key field {} is not a supported field type", key_field_name)
-                    }
+                move |segment_reader: &tantivy::SegmentReader| {
+                    let mut key_reader = key_reader(&schema, &key_field, segment_reader);
+                    move |doc: tantivy::DocId, _: Score| random_score(seed, &key_reader(doc))
                 },
             );
-            self.searcher
-                .search_with_executor(
-                    self.query.as_ref(),
+            self.collect(
+                searcher,
+                segment_offset,
+                &collector,
+                executor,
+                EnableScoring::disabled_from_searcher(searcher),
+                profile,
+                deadline,
+                latest,
+            )
+            .expect("failed to search")
+            .into_iter()
+            .map(|(score, doc_address)| {
+                let doc_address = rebase(doc_address);
+                let (key, ctid) = self.key_and_ctid_value(doc_address);
+                (score, doc_address, key, ctid)
+            })
+            .collect()
+        } else if self.config.stable_sort() {
+            // We read the value of the 'key_field' fast field and use that as a secondary sort
+            // key. In the case of a bm25 score tie, results will be ordered based on the value
+            // of their 'key_field', which doesn't change when segments are merged, unlike the
+            // addresses of documents. Every page of a paginated search is then cut from the
+            // same order. Unscored searches opt out by default, as all their results are tied.
+            let collector = StableTopDocs::new(
+                self.schema.clone(),
+                self.config.key_field.clone(),
+                limit,
+                offset,
+                !exact,
+            );
+            self.collect(
+                searcher,
+                segment_offset,
+                &collector,
+                executor,
+                scoring,
+                profile,
+                deadline,
+                latest,
+            )
+            .expect("failed to search")
+            .into_iter()
+            .map(|(score, doc_address)| {
+                let doc_address = rebase(doc_address);
+                let ctid = self.ctid_value(doc_address);
+                (score.bm25, doc_address, score.key, ctid)
+            })
+            .collect()
+        } else {
+            let collector = TopDocs::with_limit(limit).and_offset(offset);
+            let top_docs = if exact {
+                // A tweaked score stops the collector from passing a threshold to the query.
+                let collector = collector.tweak_score(|_: &tantivy::SegmentReader| {
+                    |_: tantivy::DocId, score: Score| score
+                });
+                self.collect(
+                    searcher,
+                    segment_offset,
                     &collector,
                     executor,
-                    tantivy::query::EnableScoring::Enabled {
-                        searcher: &self.searcher,
-                        statistics_provider: &self.searcher,
-                    },
+                    scoring,
+                    profile,
+                    deadline,
+                    latest,
                 )
-                .expect("failed to search")
-                .into_iter()
-                .map(|(score, doc_address)| {
-                    // This iterator contains the results after limit + offset are applied.
-                    let ctid = self.ctid_value(doc_address);
-                    SearchStateManager::set_result(
-                        score.key.clone(),
-                        score.bm25,
-                        doc_address,
-                        self.config.alias.clone(),
-                    )
-                    .expect("could not store search result in state manager");
-                    (score.bm25, doc_address, score.key, ctid)
-                })
-                .collect()
-        } else {
-            let collector = TopDocs::with_limit(limit).and_offset(offset);
-            self.searcher
-                .search_with_executor(
-                    self.query.as_ref(),
+            } else {
+                self.collect(
+                    searcher,
+                    segment_offset,
                     &collector,
                     executor,
-                    tantivy::query::EnableScoring::Enabled {
-                        searcher: &self.searcher,
-                        statistics_provider: &self.searcher,
-                    },
+                    scoring,
+                    profile,
+                    deadline,
+                    latest,
                 )
+            };
+            top_docs
                 .expect("failed to search")
                 .into_iter()
                 .map(|(score, doc_address)| {
-                    // This iterator contains the results after limit + offset are applied.
+                    let doc_address = rebase(doc_address);
                     let (key, ctid) = self.key_and_ctid_value(doc_address);
-                    SearchStateManager::set_result(
-                        key.clone(),
-                        score,
-                        doc_address,
-                        self.config.alias.clone(),
-                    )
-                    .expect("could not store search result in state manager");
                     (score, doc_address, key, ctid)
                 })
                 .collect()
         }
     }
 
-    pub fn key_value(&self, doc_address: DocAddress) -> TantivyValue {
-        let retrieved_doc: TantivyDocument = self
-            .searcher
+    /// Run a collector over the segments of a searcher, like `Searcher::search_with_executor`,
+    /// recording the time spent in each step of the search.
+    fn collect<C, T>(
+        &self,
+        searcher: &Searcher,
+        segment_offset: u32,
+        collector: &C,
+        executor: &Executor,
+        scoring: EnableScoring,
+        profile: &SearchProfile,
+        deadline: &Deadline,
+        latest: Option<&Arc<LatestVersions>>,
+    ) -> tantivy::Result<C::Fruit>
+    where
+        C: Collector,
+        C::Child: SegmentCollector<Fruit = Vec<T>>,
+    {
+        let scoring = if collector.requires_scoring() {
+            scoring
+        } else {
+            EnableScoring::disabled_from_searcher(searcher)
+        };
+
+        let weight_start = Instant::now();
+        // Regex and fuzzy queries look up their terms as their weights are created, and as
+        // their scorers are built, which stops once the deadline has passed.
+        let weight = deadline.scope(|| self.query.weight(scoring))?;
+        let mut weight: Box<dyn Weight> = Box::new(DeadlineWeight::new(weight, deadline.clone()));
+        if let Some(latest) = latest {
+            weight = Box::new(LatestVersionWeight::new(weight, latest.clone()));
+        }
+        profile.record_weight(weight_start.elapsed());
+
+        let segment_fruits = executor.map(
+            |(segment_ord, segment_reader)| {
+                if deadline.expired() {
+                    return Ok(vec![]);
+                }
+                let segment_start = Instant::now();
+                let fruit = collector.collect_segment(
+                    weight.as_ref(),
+                    segment_ord as u32,
+                    segment_reader,
+                )?;
+                profile.record_segment(
+                    segment_ord as u32 + segment_offset,
+                    segment_start.elapsed(),
+                    fruit.len(),
+                );
+                Ok(fruit)
+            },
+            searcher.segment_readers().iter().enumerate(),
+        )?;
+
+        // Searches are cut short once the deadline has passed, or the query was canceled, so
+        // their results can't be trusted, and the query is canceled like with
+        // statement_timeout.
+        if deadline.expired() {
+            check_for_interrupts!();
+            match deadline.timeout {
+                Some(timeout) => ereport!(
+                    PgLogLevel::ERROR,
+                    PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+                    format!(
+                        "canceling bm25 search on index \"{}\" after {} ms",
+                        self.config.index_name,
+                        timeout.as_millis()
+                    ),
+                    "The search ran longer than its timeout_ms, or paradedb.search_timeout."
+                ),
+                None => ereport!(
+                    PgLogLevel::ERROR,
+                    PgSqlErrorCode::ERRCODE_QUERY_CANCELED,
+                    format!(
+                        "canceling bm25 search on index \"{}\"",
+                        self.config.index_name
+                    )
+                ),
+            }
+        }
+        collector.merge_fruits(segment_fruits)
+    }
+
+    /// Retrieve a stored document, from either the committed or the pending searcher.
+    pub fn doc(&self, doc_address: DocAddress) -> TantivyDocument {
+        let (searcher, doc_address) = self.searcher_of(doc_address);
+        searcher
             .doc(doc_address)
-            .expect("could not retrieve document by address");
+            .expect("could not retrieve document by address")
+    }
+
+    /// Read the values of the `sort_by` fields of a document, from either the committed or
+    /// the pending searcher.
+    fn sort_key(&self, sort_by: &[SortField], doc_address: DocAddress) -> SortKey {
+        let (searcher, doc_address) = self.searcher_of(doc_address);
+        SortKeyReader::new(
+            &self.schema,
+            sort_by,
+            searcher.segment_reader(doc_address.segment_ord),
+        )
+        .expect("failed to read sort fields")
+        .read(doc_address.doc_id)
+    }
+
+    /// The searcher holding a document, either the committed or the pending one, and the
+    /// address of the document in it.
+    fn searcher_of(&self, doc_address: DocAddress) -> (&Searcher, DocAddress) {
+        let committed_segments = self.searcher.segment_readers().len() as u32;
+        match &self.pending_searcher {
+            Some(pending_searcher) if doc_address.segment_ord >= committed_segments => (
+                pending_searcher,
+                DocAddress::new(
+                    doc_address.segment_ord - committed_segments,
+                    doc_address.doc_id,
+                ),
+            ),
+            _ => (&self.searcher, doc_address),
+        }
+    }
+
+    /// The names of the named sub-queries that match a document, in the order they appear in
+    /// the query. A name given to several sub-queries is returned once, if any of them match.
+    pub fn matched_queries(&self, doc_address: DocAddress) -> Vec<String> {
+        let (searcher, doc_address) = self.searcher_of(doc_address);
+        let segment_reader = searcher.segment_reader(doc_address.segment_ord);
+        let mut matched: Vec<String> = vec![];
+        for (name, query) in &self.named_queries {
+            if matched.contains(name) {
+                continue;
+            }
+            let mut scorer = query
+                .weight(EnableScoring::disabled_from_searcher(searcher))
+                .and_then(|weight| weight.scorer(segment_reader, 1.0))
+                .expect("could not match named query");
+            if scorer.seek(doc_address.doc_id) == doc_address.doc_id {
+                matched.push(name.clone());
+            }
+        }
+        matched
+    }
+
+    pub fn key_value(&self, doc_address: DocAddress) -> TantivyValue {
+        let retrieved_doc = self.doc(doc_address);
 
         let value = retrieved_doc
             .get_first(self.schema.key_field().id.0)
@@ -456,10 +1046,7 @@ impl SearchState {
     }
 
     pub fn ctid_value(&self, doc_address: DocAddress) -> u64 {
-        let retrieved_doc: TantivyDocument = self
-            .searcher
-            .doc(doc_address)
-            .expect("could not retrieve document by address");
+        let retrieved_doc = self.doc(doc_address);
 
         retrieved_doc
             .get_first(self.schema.ctid_field().id.0)
@@ -469,10 +1056,7 @@ impl SearchState {
     }
 
     pub fn key_and_ctid_value(&self, doc_address: DocAddress) -> (TantivyValue, u64) {
-        let retrieved_doc: TantivyDocument = self
-            .searcher
-            .doc(doc_address)
-            .expect("could not retrieve document by address");
+        let retrieved_doc = self.doc(doc_address);
 
         let value = retrieved_doc
             .get_first(self.schema.key_field().id.0)
@@ -492,27 +1076,15 @@ impl SearchState {
     /// searches into the Tantivy index outside of Postgres index access methods. Postgres will
     /// filter out stale rows when using the index scan, but when scanning Tantivy directly,
     /// we risk returning deleted documents if a VACUUM hasn't been performed yet.
+    ///
+    /// Only the latest version of a row is searched, so a row whose latest version doesn't
+    /// match isn't returned at all, and the limit is filled with other rows.
     pub fn search_dedup(
         &mut self,
         executor: &Executor,
     ) -> impl Iterator<Item = (Score, DocAddress)> {
-        let search_results = self.search(executor);
-        let mut dedup_map: HashMap<TantivyValue, (Score, DocAddress)> = HashMap::new();
-        let mut order_vec: Vec<TantivyValue> = Vec::new();
-
-        for (score, doc_addr, key, _) in search_results {
-            let is_new_or_higher = match dedup_map.get(&key) {
-                Some((_, existing_doc_addr)) => doc_addr > *existing_doc_addr,
-                None => true,
-            };
-            if is_new_or_higher && dedup_map.insert(key.clone(), (score, doc_addr)).is_none() {
-                // Key was not already present, remember the order of this key
-                order_vec.push(key.clone());
-            }
-        }
-
-        order_vec
+        self.search_versions(executor, true)
             .into_iter()
-            .filter_map(move |key| dedup_map.remove(&key))
+            .map(|(score, doc_address, _, _)| (score, doc_address))
     }
 }
@@ -0,0 +1,143 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use crate::writer::{
+    SearchDirectoryError, SearchFs, TantivyDirPath, WriterDirectory, WriterStatusFilePath,
+};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::time::SystemTime;
+
+/// Sizes and counts of an index, as committed, for capacity planning and monitoring.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SearchIndexStats {
+    pub num_docs: u64,
+    pub num_deleted_docs: u64,
+    pub segments: usize,
+    /// Bytes of term dictionaries, postings and positions.
+    pub postings_bytes: u64,
+    /// Bytes of stored documents.
+    pub docstore_bytes: u64,
+    pub fast_fields_bytes: u64,
+    /// Bytes of every file of the searchable segments, including field norms and deletes.
+    pub total_bytes: u64,
+    pub last_commit: Option<SystemTime>,
+}
+
+impl SearchIndexStats {
+    pub fn collect(search_index: &SearchIndex) -> Result<Self, SearchIndexError> {
+        let TantivyDirPath(tantivy_dir_path) = search_index.directory.tantivy_dir_path(false)?;
+        let mut stats = Self::default();
+
+        for meta in search_index.underlying_index.searchable_segment_metas()? {
+            stats.segments += 1;
+            stats.num_docs += meta.num_docs() as u64;
+            stats.num_deleted_docs += meta.num_deleted_docs() as u64;
+
+            for file in meta.list_files() {
+                // Files can be garbage collected by a concurrent merge, which is fine to
+                // ignore here.
+                let Ok(metadata) = fs::metadata(tantivy_dir_path.join(&file)) else {
+                    continue;
+                };
+                let bytes = metadata.len();
+                stats.total_bytes += bytes;
+                match file.extension().and_then(|extension| extension.to_str()) {
+                    Some("idx" | "pos" | "term") => stats.postings_bytes += bytes,
+                    Some("store") => stats.docstore_bytes += bytes,
+                    Some("fast") => stats.fast_fields_bytes += bytes,
+                    _ => {}
+                }
+            }
+        }
+
+        // Tantivy rewrites meta.json on every commit.
+        stats.last_commit = fs::metadata(tantivy_dir_path.join("meta.json"))
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        Ok(stats)
+    }
+}
+
+/// The counters of the writer process for an index, saved next to the index on each commit
+/// so that they can be read from any connection.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WriterStatus {
+    /// Documents added since the last commit. For indexes with a refresh interval, these
+    /// include the documents of committed transactions that aren't searchable yet.
+    pub uncommitted_documents: u64,
+    pub documents_indexed: u64,
+    pub commits: u64,
+    /// Requests for the index that failed.
+    pub errors: u64,
+    /// Values of rows that couldn't be indexed, and were left out along with their row or
+    /// indexed as NULL, as set by the `on_error` of their field.
+    pub skipped_values: u64,
+    /// The WAL timeline the index was last written on, or 0 if unknown. See `Resync`.
+    pub timeline: u32,
+}
+
+impl WriterStatus {
+    pub fn load(directory: &WriterDirectory) -> Result<Self, SearchDirectoryError> {
+        let WriterStatusFilePath(path) = directory.writer_status_file_path()?;
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let serialized = fs::read_to_string(&path)
+            .map_err(|err| SearchDirectoryError::IndexFileRead(directory.clone(), path, err))?;
+        serde_json::from_str(&serialized)
+            .map_err(|err| SearchDirectoryError::IndexDeserialize(directory.clone(), err))
+    }
+
+    pub fn save(&self, directory: &WriterDirectory) -> Result<(), SearchDirectoryError> {
+        let WriterStatusFilePath(path) = directory.writer_status_file_path()?;
+        let serialized = serde_json::to_string(self)
+            .map_err(|err| SearchDirectoryError::IndexSerialize(directory.clone(), err))?;
+        fs::write(path, serialized)
+            .map_err(|err| SearchDirectoryError::IndexFileWrite(directory.clone(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WriterStatus;
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_writer_status_roundtrip(mock_dir: MockWriterDirectory) {
+        let directory = mock_dir.writer_dir.clone();
+        assert_eq!(
+            WriterStatus::load(&directory).unwrap(),
+            WriterStatus::default()
+        );
+
+        let status = WriterStatus {
+            uncommitted_documents: 3,
+            documents_indexed: 10,
+            commits: 2,
+            errors: 1,
+            skipped_values: 4,
+            timeline: 1,
+        };
+        status.save(&directory).unwrap();
+        assert_eq!(WriterStatus::load(&directory).unwrap(), status);
+    }
+}
@@ -0,0 +1,190 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tantivy::directory::error::{DeleteError, OpenReadError, OpenWriteError};
+use tantivy::directory::{
+    Advice, FileHandle, MmapDirectory, OwnedBytes, WatchCallback, WatchHandle, WritePtr,
+};
+use tantivy::{Directory, HasLen};
+
+/// How a backend reads the files of an index when searching it. Set with the
+/// `directory_mode` index option. The writer always memory-maps files.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SearchDirectoryMode {
+    /// Memory-map files, leaving read-ahead to the OS.
+    #[default]
+    Mmap,
+    /// Memory-map files, and tell the OS that reads are random, so that page faults don't
+    /// read ahead. Best for large docstores on fast disks, where read-ahead wastes I/O.
+    MmapRandom,
+    /// Memory-map files, and tell the OS that reads are sequential, so that it reads ahead
+    /// aggressively. Best for scans over most of an index.
+    MmapSequential,
+    /// Read files with explicit reads instead of page faults, so that a cold cache slows down
+    /// the query reading it rather than stalling on faults.
+    Buffered,
+}
+
+impl SearchDirectoryMode {
+    pub fn open(&self, path: &Path) -> tantivy::Result<Box<dyn Directory>> {
+        Ok(match self {
+            Self::Mmap => Box::new(MmapDirectory::open(path)?),
+            Self::MmapRandom => Box::new(MmapDirectory::open_with_madvice(path, Advice::Random)?),
+            Self::MmapSequential => {
+                Box::new(MmapDirectory::open_with_madvice(path, Advice::Sequential)?)
+            }
+            Self::Buffered => Box::new(BufferedDirectory::open(path)?),
+        })
+    }
+}
+
+impl FromStr for SearchDirectoryMode {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+/// A directory that reads files into memory on demand. Writes and everything else go through
+/// a memory-mapped directory.
+#[derive(Clone, Debug)]
+struct BufferedDirectory {
+    root: PathBuf,
+    inner: MmapDirectory,
+}
+
+impl BufferedDirectory {
+    fn open(root: &Path) -> tantivy::Result<Self> {
+        Ok(Self {
+            root: root.to_path_buf(),
+            inner: MmapDirectory::open(root)?,
+        })
+    }
+}
+
+impl Directory for BufferedDirectory {
+    fn get_file_handle(&self, path: &Path) -> Result<Arc<dyn FileHandle>, OpenReadError> {
+        let io_error = |err: io::Error| {
+            if err.kind() == io::ErrorKind::NotFound {
+                OpenReadError::FileDoesNotExist(path.to_path_buf())
+            } else {
+                OpenReadError::IoError {
+                    io_error: Arc::new(err),
+                    filepath: path.to_path_buf(),
+                }
+            }
+        };
+        let file = File::open(self.root.join(path)).map_err(io_error)?;
+        let len = file.metadata().map_err(io_error)?.len() as usize;
+        Ok(Arc::new(BufferedFileHandle { file, len }))
+    }
+
+    fn delete(&self, path: &Path) -> Result<(), DeleteError> {
+        self.inner.delete(path)
+    }
+
+    fn exists(&self, path: &Path) -> Result<bool, OpenReadError> {
+        self.inner.exists(path)
+    }
+
+    fn open_write(&self, path: &Path) -> Result<WritePtr, OpenWriteError> {
+        self.inner.open_write(path)
+    }
+
+    fn atomic_read(&self, path: &Path) -> Result<Vec<u8>, OpenReadError> {
+        self.inner.atomic_read(path)
+    }
+
+    fn atomic_write(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        self.inner.atomic_write(path, data)
+    }
+
+    fn sync_directory(&self) -> io::Result<()> {
+        self.inner.sync_directory()
+    }
+
+    fn watch(&self, watch_callback: WatchCallback) -> tantivy::Result<WatchHandle> {
+        self.inner.watch(watch_callback)
+    }
+}
+
+#[derive(Debug)]
+struct BufferedFileHandle {
+    file: File,
+    len: usize,
+}
+
+impl HasLen for BufferedFileHandle {
+    fn len(&self) -> usize {
+        self.len
+    }
+}
+
+impl FileHandle for BufferedFileHandle {
+    fn read_bytes(&self, range: Range<usize>) -> io::Result<OwnedBytes> {
+        let mut buffer = vec![0u8; range.len()];
+        self.file.read_exact_at(&mut buffer, range.start as u64)?;
+        Ok(OwnedBytes::new(buffer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SearchDirectoryMode;
+    use std::path::Path;
+    use tantivy::Directory;
+
+    #[test]
+    fn test_directory_modes() {
+        assert_eq!(
+            "mmap_random".parse::<SearchDirectoryMode>().unwrap(),
+            SearchDirectoryMode::MmapRandom
+        );
+        assert!("mmap_backwards".parse::<SearchDirectoryMode>().is_err());
+
+        // Every mode reads back what was written.
+        let tempdir = tempfile::tempdir().unwrap();
+        tantivy::directory::MmapDirectory::open(tempdir.path())
+            .unwrap()
+            .atomic_write(Path::new("data"), b"search")
+            .unwrap();
+        for mode in [
+            SearchDirectoryMode::Mmap,
+            SearchDirectoryMode::MmapRandom,
+            SearchDirectoryMode::MmapSequential,
+            SearchDirectoryMode::Buffered,
+        ] {
+            let directory = mode.open(tempdir.path()).unwrap();
+            let bytes = directory
+                .open_read(Path::new("data"))
+                .unwrap()
+                .read_bytes()
+                .unwrap();
+            assert_eq!(bytes.as_slice(), b"search");
+        }
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::terms::VisibleDocs;
+use super::{SearchIndex, SearchIndexError};
+use crate::query::SearchQueryInput;
+use crate::schema::SearchFieldType;
+use anyhow::anyhow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use tantivy::columnar::DynamicColumn;
+use tantivy::schema::{FieldType, IndexRecordOption};
+use tantivy::{DocId, DocSet, SegmentReader, TERMINATED};
+
+/// A completion of a prefix, and how much it weighs against the others.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Suggestion {
+    pub term: String,
+    pub weight: f64,
+}
+
+/// The `size` heaviest completions of `prefix` among the terms of the text field `field_name`.
+/// Terms are read from the term dictionaries of the segments, which tantivy keeps as FSTs, so
+/// suggestions don't need an index of their own and are as fresh as the last commit. A term
+/// weighs the number of live documents that contain it, or if `weight_field` is given, the sum
+/// of that numeric fast field over those documents, as for a popularity score.
+///
+/// The prefix goes through the tokenizer of the field, and its last token is completed, so
+/// completions are terms as they were indexed: lowercased by the default tokenizer, or stems
+/// if the field is stemmed. Only the documents matching `filter` weigh, if it's given, so the
+/// terms of the others aren't suggested.
+pub fn completions(
+    search_index: &SearchIndex,
+    field_name: &str,
+    prefix: &str,
+    size: usize,
+    weight_field: Option<&str>,
+    filter: Option<SearchQueryInput>,
+) -> Result<Vec<Suggestion>, SearchIndexError> {
+    let search_field = search_index
+        .schema
+        .get_search_field(field_name)
+        .ok_or_else(|| anyhow!("field '{field_name}' does not exist in the index"))?;
+    let field = search_field.id.0;
+    let field_entry = search_index.schema.schema.get_field_entry(field);
+    if !matches!(field_entry.field_type(), FieldType::Str(_)) || !field_entry.is_indexed() {
+        return Err(anyhow!("field '{field_name}' is not an indexed text field").into());
+    }
+
+    if let Some(weight_name) = weight_field {
+        let weight_search_field = search_index
+            .schema
+            .get_search_field(weight_name)
+            .ok_or_else(|| anyhow!("field '{weight_name}' does not exist in the index"))?;
+        let numeric = matches!(
+            weight_search_field.type_,
+            SearchFieldType::I64 | SearchFieldType::U64 | SearchFieldType::F64
+        );
+        let fast = search_index
+            .schema
+            .schema
+            .get_field_entry(weight_search_field.id.0)
+            .is_fast();
+        if !numeric || !fast {
+            return Err(anyhow!("field '{weight_name}' is not a numeric fast field").into());
+        }
+    }
+
+    let mut analyzer = search_index.underlying_index.tokenizer_for_field(field)?;
+    let mut last_token = None;
+    analyzer
+        .token_stream(prefix)
+        .process(&mut |token| last_token = Some(token.text.clone()));
+    let prefix = last_token.unwrap_or_default();
+
+    let searcher = search_index.searcher();
+    let visible = VisibleDocs::new(search_index, &searcher, filter)?;
+    let mut weights: HashMap<String, f64> = HashMap::new();
+    for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+        let weight_column = weight_field
+            .map(|weight_name| weight_column(segment_reader, weight_name))
+            .transpose()?;
+        let alive_bitset = segment_reader.alive_bitset();
+        let inverted_index = segment_reader.inverted_index(field)?;
+        let mut terms = inverted_index
+            .terms()
+            .range()
+            .ge(prefix.as_bytes())
+            .into_stream()?;
+
+        while terms.advance() {
+            if !terms.key().starts_with(prefix.as_bytes()) {
+                break;
+            }
+            let term_info = terms.value();
+
+            // Without deletes, filters or weights, the document frequency is the weight.
+            let counts_all = alive_bitset.is_none() && visible.is_unfiltered();
+            let weight = if counts_all && weight_column.is_none() {
+                term_info.doc_freq as f64
+            } else {
+                let mut postings = inverted_index
+                    .read_postings_from_terminfo(term_info, IndexRecordOption::Basic)?;
+                let mut weight = 0.0;
+                let mut doc = postings.doc();
+                while doc != TERMINATED {
+                    if visible.contains(segment_ord, segment_reader, doc) {
+                        weight += match &weight_column {
+                            Some(column) => column(doc),
+                            None => 1.0,
+                        };
+                    }
+                    doc = postings.advance();
+                }
+                weight
+            };
+
+            if weight > 0.0 {
+                *weights
+                    .entry(String::from_utf8_lossy(terms.key()).into_owned())
+                    .or_default() += weight;
+            }
+        }
+    }
+
+    let mut suggestions: Vec<Suggestion> = weights
+        .into_iter()
+        .map(|(term, weight)| Suggestion { term, weight })
+        .collect();
+    suggestions.sort_by(|a, b| {
+        b.weight
+            .partial_cmp(&a.weight)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| a.term.cmp(&b.term))
+    });
+    suggestions.truncate(size);
+    Ok(suggestions)
+}
+
+/// Reads the first value of a numeric fast field of a segment as a float. Documents without
+/// a value weigh nothing.
+fn weight_column(
+    segment_reader: &SegmentReader,
+    field_name: &str,
+) -> Result<Box<dyn Fn(DocId) -> f64>, SearchIndexError> {
+    let Some(handle) = segment_reader
+        .fast_fields()
+        .dynamic_column_handles(field_name)?
+        .into_iter()
+        .next()
+    else {
+        return Ok(Box::new(|_| 0.0));
+    };
+
+    Ok(match handle.open()? {
+        DynamicColumn::I64(column) => {
+            Box::new(move |doc| column.first(doc).map_or(0.0, |value| value as f64))
+        }
+        DynamicColumn::U64(column) => {
+            Box::new(move |doc| column.first(doc).map_or(0.0, |value| value as f64))
+        }
+        DynamicColumn::F64(column) => Box::new(move |doc| column.first(doc).unwrap_or(0.0)),
+        _ => return Err(anyhow!("field '{field_name}' is not a numeric fast field").into()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::completions;
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::query::SearchQueryInput;
+    use crate::schema::SearchDocument;
+    use rstest::*;
+    use tantivy::schema::Value;
+
+    fn document(index: &SearchIndex, id: i64, description: &str, rating: i64) -> SearchDocument {
+        let field = |name: &str| index.schema.get_search_field(name).unwrap().id;
+        let mut document = index.schema.new_document();
+        document.insert(index.schema.key_field().id, id.into());
+        document.insert(field("description"), description.into());
+        document.insert(field("rating"), rating.into());
+        document
+    }
+
+    #[rstest]
+    fn test_suggest(default_index: MockSearchIndex) {
+        let index = default_index.index;
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        for (id, description, rating) in [
+            (1, "Ergonomic metal keyboard", 4),
+            (2, "Plastic Keyboard", 4),
+            (3, "Keychain", 9),
+            (4, "Sleek running shoes", 5),
+        ] {
+            writer
+                .add_document(document(index, id, description, rating).into())
+                .unwrap();
+        }
+        writer.commit().unwrap();
+        index.reader.reload().unwrap();
+
+        // The prefix is tokenized like the field, so its case doesn't matter.
+        let suggestions = completions(index, "description", "Key", 10, None, None).unwrap();
+        let terms: Vec<(&str, f64)> = suggestions
+            .iter()
+            .map(|suggestion| (suggestion.term.as_str(), suggestion.weight))
+            .collect();
+        assert_eq!(terms, vec![("keyboard", 2.0), ("keychain", 1.0)]);
+
+        // Weighted by a fast field, the most popular completion comes first.
+        let suggestions = completions(
+            index,
+            "description",
+            "ergonomic key",
+            1,
+            Some("rating"),
+            None,
+        )
+        .unwrap();
+        assert_eq!(suggestions[0].term, "keychain");
+        assert_eq!(suggestions[0].weight, 9.0);
+
+        // Documents that don't match the filter don't weigh.
+        let filter = SearchQueryInput::Term {
+            field: Some("rating".into()),
+            value: Value::I64(9),
+        };
+        let suggestions = completions(index, "description", "key", 10, None, Some(filter)).unwrap();
+        assert_eq!(suggestions.len(), 1);
+        assert_eq!(suggestions[0].term, "keychain");
+
+        assert!(completions(index, "rating", "4", 10, None, None).is_err());
+        assert!(completions(index, "description", "k", 10, Some("category"), None).is_err());
+    }
+}
@@ -0,0 +1,389 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{SearchIndex, SearchIndexError};
+use crate::query::SearchQueryInput;
+use anyhow::anyhow;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use tantivy::postings::{Postings, TermInfo};
+use tantivy::query::EnableScoring;
+use tantivy::schema::{Field, FieldType, IndexRecordOption};
+use tantivy::termdict::TermMerger;
+use tantivy::{DocId, DocSet, InvertedIndexReader, Searcher, SegmentReader, TERMINATED};
+use tantivy_common::BitSet;
+
+/// How often a term occurs in a text field of the live documents of an index.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TermFrequency {
+    pub term: String,
+    /// The number of documents that contain the term.
+    pub doc_freq: u64,
+    /// The number of times the term occurs in those documents.
+    pub total_term_freq: u64,
+}
+
+/// The documents that term statistics count: the live documents of each segment, and only
+/// those matching a filter if there is one, like the security filter of an index, so that the
+/// terms of the documents it hides don't show.
+pub struct VisibleDocs {
+    /// The live documents of each segment that match the filter.
+    filtered: Option<Vec<BitSet>>,
+}
+
+impl VisibleDocs {
+    pub fn new(
+        search_index: &SearchIndex,
+        searcher: &Searcher,
+        filter: Option<SearchQueryInput>,
+    ) -> Result<Self, SearchIndexError> {
+        let Some(filter) = filter else {
+            return Ok(Self { filtered: None });
+        };
+        let query =
+            filter.into_tantivy_query(&search_index.schema, &mut search_index.query_parser())?;
+        let weight = query.weight(EnableScoring::disabled_from_searcher(searcher))?;
+        let filtered = searcher
+            .segment_readers()
+            .iter()
+            .map(|segment_reader| {
+                let alive_bitset = segment_reader.alive_bitset();
+                let mut docs = BitSet::with_max_value(segment_reader.max_doc());
+                let mut scorer = weight.scorer(segment_reader, 1.0)?;
+                let mut doc = scorer.doc();
+                while doc != TERMINATED {
+                    if alive_bitset.map_or(true, |alive| alive.is_alive(doc)) {
+                        docs.insert(doc);
+                    }
+                    doc = scorer.advance();
+                }
+                Ok(docs)
+            })
+            .collect::<Result<Vec<_>, SearchIndexError>>()?;
+        Ok(Self {
+            filtered: Some(filtered),
+        })
+    }
+
+    /// Whether every live document is visible, so that the counts of the term dictionaries
+    /// can be used as they are when there are no deletes.
+    pub fn is_unfiltered(&self) -> bool {
+        self.filtered.is_none()
+    }
+
+    /// Whether `doc` of the segment at `segment_ord` is counted.
+    pub fn contains(&self, segment_ord: usize, segment_reader: &SegmentReader, doc: DocId) -> bool {
+        match &self.filtered {
+            Some(filtered) => filtered[segment_ord].contains(doc),
+            None => segment_reader
+                .alive_bitset()
+                .map_or(true, |alive| alive.is_alive(doc)),
+        }
+    }
+
+    /// The number of documents counted.
+    pub fn num_docs(&self, searcher: &Searcher) -> u64 {
+        match &self.filtered {
+            Some(filtered) => filtered.iter().map(|docs| docs.len() as u64).sum(),
+            None => searcher.num_docs(),
+        }
+    }
+}
+
+/// The frequency of a term of a text field, and how many live documents the index has, from
+/// which BM25 weighs the term.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TermStats {
+    pub frequency: TermFrequency,
+    pub num_docs: u64,
+}
+
+impl TermStats {
+    /// The inverse document frequency of the term, as BM25 computes it.
+    pub fn idf(&self) -> f64 {
+        let doc_freq = self.frequency.doc_freq as f64;
+        (1.0 + (self.num_docs as f64 - doc_freq + 0.5) / (doc_freq + 0.5)).ln()
+    }
+}
+
+/// The statistics of `term` in the text field `field_name`. The term is looked up as it was
+/// indexed, so it isn't tokenized: it's lowercased by the default tokenizer, or a stem if the
+/// field is stemmed. Only the documents matching `filter` are counted, if it's given.
+pub fn term_stats(
+    search_index: &SearchIndex,
+    field_name: &str,
+    term: &str,
+    filter: Option<SearchQueryInput>,
+) -> Result<TermStats, SearchIndexError> {
+    let field = text_field(search_index, field_name)?;
+    let searcher = search_index.searcher();
+    let visible = VisibleDocs::new(search_index, &searcher, filter)?;
+
+    let mut frequency = TermFrequency {
+        term: term.to_string(),
+        ..Default::default()
+    };
+    for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+        let inverted_index = segment_reader.inverted_index(field)?;
+        let tantivy_term = tantivy::Term::from_field_text(field, term);
+        if let Some(term_info) = inverted_index.get_term_info(&tantivy_term)? {
+            let (doc_freq, total_term_freq) = count_postings(&inverted_index, &term_info, |doc| {
+                visible.contains(segment_ord, segment_reader, doc)
+            })?;
+            frequency.doc_freq += doc_freq;
+            frequency.total_term_freq += total_term_freq;
+        }
+    }
+
+    Ok(TermStats {
+        frequency,
+        num_docs: visible.num_docs(&searcher),
+    })
+}
+
+/// The `size` terms of the text field `field_name` that the most live documents contain,
+/// read from the term dictionaries of the segments. Ties are ordered by term. Only the
+/// documents matching `filter` are counted, if it's given.
+pub fn top_terms(
+    search_index: &SearchIndex,
+    field_name: &str,
+    size: usize,
+    filter: Option<SearchQueryInput>,
+) -> Result<Vec<TermFrequency>, SearchIndexError> {
+    let field = text_field(search_index, field_name)?;
+    let searcher = search_index.searcher();
+    let visible = VisibleDocs::new(search_index, &searcher, filter)?;
+
+    let mut frequencies: HashMap<Vec<u8>, (u64, u64)> = HashMap::new();
+    for (segment_ord, segment_reader) in searcher.segment_readers().iter().enumerate() {
+        let inverted_index = segment_reader.inverted_index(field)?;
+        let mut terms = inverted_index.terms().stream()?;
+        while terms.advance() {
+            let (doc_freq, total_term_freq) =
+                count_postings(&inverted_index, terms.value(), |doc| {
+                    visible.contains(segment_ord, segment_reader, doc)
+                })?;
+            if doc_freq > 0 {
+                let counts = frequencies.entry(terms.key().to_vec()).or_default();
+                counts.0 += doc_freq;
+                counts.1 += total_term_freq;
+            }
+        }
+    }
+
+    let mut frequencies: Vec<TermFrequency> = frequencies
+        .into_iter()
+        .map(|(term, (doc_freq, total_term_freq))| TermFrequency {
+            term: String::from_utf8_lossy(&term).into_owned(),
+            doc_freq,
+            total_term_freq,
+        })
+        .collect();
+    frequencies.sort_by(|a, b| match b.doc_freq.cmp(&a.doc_freq) {
+        Ordering::Equal => a.term.cmp(&b.term),
+        ordering => ordering,
+    });
+    frequencies.truncate(size);
+    Ok(frequencies)
+}
+
+/// The distinct terms of the text field `field_name` that start with `prefix`, in order, with
+/// the number of live documents that contain them, for instance to list the values of a
+/// category to filter by. The term dictionaries of the segments are merged as they're read,
+/// so only the first `size` matching terms are visited. The prefix isn't tokenized. Only the
+/// documents matching `filter` are counted, if it's given, and terms none of them contain are
+/// left out.
+pub fn field_values(
+    search_index: &SearchIndex,
+    field_name: &str,
+    prefix: &str,
+    size: usize,
+    filter: Option<SearchQueryInput>,
+) -> Result<Vec<TermFrequency>, SearchIndexError> {
+    let field = text_field(search_index, field_name)?;
+    let searcher = search_index.searcher();
+    let visible = VisibleDocs::new(search_index, &searcher, filter)?;
+    let segment_readers = searcher.segment_readers();
+
+    let inverted_indexes = segment_readers
+        .iter()
+        .map(|segment_reader| segment_reader.inverted_index(field))
+        .collect::<Result<Vec<_>, _>>()?;
+    let streams = inverted_indexes
+        .iter()
+        .map(|inverted_index| {
+            inverted_index
+                .terms()
+                .range()
+                .ge(prefix.as_bytes())
+                .into_stream()
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut values = vec![];
+    let mut merger = TermMerger::new(streams);
+    while values.len() < size && merger.advance() {
+        if !merger.key().starts_with(prefix.as_bytes()) {
+            break;
+        }
+        let mut value = TermFrequency {
+            term: String::from_utf8_lossy(merger.key()).into_owned(),
+            ..Default::default()
+        };
+        for (segment_ord, term_info) in merger.matching_segments() {
+            let (doc_freq, total_term_freq) =
+                count_postings(&inverted_indexes[segment_ord], &term_info, |doc| {
+                    visible.contains(segment_ord, &segment_readers[segment_ord], doc)
+                })?;
+            value.doc_freq += doc_freq;
+            value.total_term_freq += total_term_freq;
+        }
+        // Terms can be left behind by deleted documents until their segments are merged.
+        if value.doc_freq > 0 {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+/// The field of `field_name`, if it's an indexed text field.
+fn text_field(search_index: &SearchIndex, field_name: &str) -> Result<Field, SearchIndexError> {
+    let search_field = search_index
+        .schema
+        .get_search_field(field_name)
+        .ok_or_else(|| anyhow!("field '{field_name}' does not exist in the index"))?;
+    let field = search_field.id.0;
+    let field_entry = search_index.schema.schema.get_field_entry(field);
+    if !matches!(field_entry.field_type(), FieldType::Str(_)) || !field_entry.is_indexed() {
+        return Err(anyhow!("field '{field_name}' is not an indexed text field").into());
+    }
+    Ok(field)
+}
+
+/// The number of visible documents of a segment that contain a term, and the number of times
+/// it occurs in them. Fields indexed without frequencies count each document once.
+fn count_postings(
+    inverted_index: &InvertedIndexReader,
+    term_info: &TermInfo,
+    visible: impl Fn(DocId) -> bool,
+) -> Result<(u64, u64), SearchIndexError> {
+    let mut postings =
+        inverted_index.read_postings_from_terminfo(term_info, IndexRecordOption::WithFreqs)?;
+    let (mut doc_freq, mut total_term_freq) = (0, 0);
+    let mut doc = postings.doc();
+    while doc != TERMINATED {
+        if visible(doc) {
+            doc_freq += 1;
+            total_term_freq += postings.term_freq() as u64;
+        }
+        doc = postings.advance();
+    }
+    Ok((doc_freq, total_term_freq))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{field_values, term_stats, top_terms};
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::query::SearchQueryInput;
+    use rstest::*;
+    use tantivy::schema::Value;
+
+    #[rstest]
+    fn test_term_stats(default_index: MockSearchIndex) {
+        let index = default_index.index;
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        for (id, description) in [
+            (1, "Ergonomic metal keyboard"),
+            (2, "Plastic keyboard keyboard"),
+            (3, "Metal keychain"),
+        ] {
+            let mut document = index.schema.new_document();
+            document.insert(index.schema.key_field().id, (id as i64).into());
+            document.insert(
+                index.schema.get_search_field("description").unwrap().id,
+                description.into(),
+            );
+            writer.add_document(document.into()).unwrap();
+        }
+        writer.commit().unwrap();
+        index.reader.reload().unwrap();
+
+        let stats = term_stats(index, "description", "keyboard", None).unwrap();
+        assert_eq!(
+            (stats.frequency.doc_freq, stats.frequency.total_term_freq),
+            (2, 3)
+        );
+        assert_eq!(stats.num_docs, 3);
+        // The rarer a term, the more it weighs.
+        let ergonomic = term_stats(index, "description", "ergonomic", None).unwrap();
+        assert!(ergonomic.idf() > stats.idf());
+        assert_eq!(
+            term_stats(index, "description", "missing", None)
+                .unwrap()
+                .frequency
+                .doc_freq,
+            0
+        );
+
+        let terms: Vec<(String, u64)> = top_terms(index, "description", 2, None)
+            .unwrap()
+            .into_iter()
+            .map(|frequency| (frequency.term, frequency.doc_freq))
+            .collect();
+        assert_eq!(terms, vec![("keyboard".into(), 2), ("metal".into(), 2)]);
+
+        assert!(top_terms(index, "rating", 2, None).is_err());
+
+        let values: Vec<(String, u64)> = field_values(index, "description", "key", 10, None)
+            .unwrap()
+            .into_iter()
+            .map(|value| (value.term, value.doc_freq))
+            .collect();
+        assert_eq!(values, vec![("keyboard".into(), 2), ("keychain".into(), 1)]);
+        let values = field_values(index, "description", "", 1, None).unwrap();
+        assert_eq!(values[0].term, "ergonomic");
+
+        // With a filter, only the documents it matches are counted, and the terms of the
+        // others don't show.
+        let filter = || {
+            Some(SearchQueryInput::TermSet {
+                terms: vec![("id".into(), Value::I64(1)), ("id".into(), Value::I64(3))],
+            })
+        };
+        let stats = term_stats(index, "description", "keyboard", filter()).unwrap();
+        assert_eq!(
+            (stats.frequency.doc_freq, stats.frequency.total_term_freq),
+            (1, 1)
+        );
+        assert_eq!(stats.num_docs, 2);
+        let terms: Vec<String> = top_terms(index, "description", 10, filter())
+            .unwrap()
+            .into_iter()
+            .map(|frequency| frequency.term)
+            .collect();
+        assert!(!terms.contains(&"plastic".to_string()));
+        assert_eq!(terms[0], "metal");
+        let values: Vec<String> = field_values(index, "description", "p", 10, filter())
+            .unwrap()
+            .into_iter()
+            .map(|value| value.term)
+            .collect();
+        assert!(values.is_empty());
+    }
+}
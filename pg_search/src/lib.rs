@@ -19,6 +19,8 @@ mod api;
 mod bootstrap;
 mod env;
 mod globals;
+mod gucs;
+mod http;
 mod index;
 mod postgres;
 mod query;
@@ -28,16 +30,40 @@ mod writer;
 #[cfg(test)]
 pub mod fixtures;
 
-use crate::globals::WRITER_GLOBAL;
-use pgrx::bgworkers::{BackgroundWorker, BackgroundWorkerBuilder, SignalWakeFlags};
+use crate::globals::{
+    COLD_TIER_READS, COLD_TIER_READ_NANOS, HOT_TIER_READS, HOT_TIER_READ_NANOS, QUERY_CACHE_HITS,
+    QUERY_CACHE_MISSES, QUERY_STATS, SEARCHES, WRITER_GLOBAL,
+};
+use crate::gucs::PgSearchGucSettings;
+use crate::index::recovery::Resync;
+use crate::index::shipping::{SegmentShipping, ShippingError};
+use crate::index::source::IndexSource;
+use crate::writer::{SearchFs, WriterClient};
+use pgrx::bgworkers::{
+    BackgroundWorker, BackgroundWorkerBuilder, BackgroundWorkerStatus, DynamicBackgroundWorker,
+    SignalWakeFlags,
+};
 use pgrx::*;
 use shared::gucs::PostgresGlobalGucSettings;
 use shared::telemetry::setup_telemetry_background_worker;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::process;
 use std::time::Duration;
 
 // A static variable is required to host grand unified configuration settings.
 pub static GUCS: PostgresGlobalGucSettings = PostgresGlobalGucSettings::new();
+pub static SEARCH_GUCS: PgSearchGucSettings = PgSearchGucSettings::new();
+
+/// Seconds the postmaster waits before starting a crashed background worker again.
+const WRITER_RESTART_SECONDS: u64 = 1;
+/// The least time a heal worker waits between two steps, so that it doesn't spin on tables
+/// that are empty or skipped.
+const HEAL_MIN_WAIT: Duration = Duration::from_millis(100);
+/// How often a refresh worker checks whether the sources of indexes are due for a refresh.
+const REFRESH_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the HTTP worker waits for a request before checking for SIGTERM again.
+const HTTP_POLL_INTERVAL: Duration = Duration::from_secs(1);
 
 pgrx::pg_module_magic!();
 
@@ -50,9 +76,19 @@ extension_sql!("GRANT ALL ON SCHEMA paradedb TO PUBLIC;" name = "paradedb_grant_
 pub unsafe extern "C" fn _PG_init() {
     postgres::options::init();
     GUCS.init("pg_search");
+    SEARCH_GUCS.init();
 
     // Set up the writer bgworker shared state.
     pg_shmem_init!(WRITER_GLOBAL);
+    pg_shmem_init!(QUERY_STATS);
+    pg_shmem_init!(SEARCHES);
+    pg_shmem_init!(QUERY_CACHE_HITS);
+    pg_shmem_init!(QUERY_CACHE_MISSES);
+    pg_shmem_init!(HOT_TIER_READS);
+    pg_shmem_init!(HOT_TIER_READ_NANOS);
+    pg_shmem_init!(COLD_TIER_READS);
+    pg_shmem_init!(COLD_TIER_READ_NANOS);
+    index::remote::set_read_observer(globals::record_tier_read);
 
     // We call this in a helper function to the bgworker initialization
     // can be used in test suites.
@@ -76,6 +112,9 @@ pub fn setup_background_workers() {
         // RecoveryFinished is the last available stage for bgworker startup.
         // Allows time for all bootstrapped tables to be created.
         .set_start_time(bgworkers::BgWorkerStartTime::RecoveryFinished)
+        // If the insert worker crashes, the postmaster starts it again. Connected clients
+        // find the new server address in shared memory and replay their open transactions.
+        .set_restart_time(Some(Duration::from_secs(WRITER_RESTART_SECONDS)))
         .load();
 
     // A background worker with the job of shutting down the insert worker.
@@ -93,13 +132,71 @@ pub fn setup_background_workers() {
         // It doesn't seem like bgworkers will start without this.
         .enable_spi_access()
         .load();
+
+    // A background worker that periodically asks the insert worker to merge index segments,
+    // so that merges happen at a steady rate instead of after every commit.
+    BackgroundWorkerBuilder::new("pg_search_merge_worker")
+        // Must be the name of a function in this file.
+        .set_function("pg_search_merge_worker")
+        // Must be the name of this library.
+        .set_library("pg_search")
+        // The argument will be unused. You just need to pass something.
+        .set_argument(0.into_datum())
+        .set_start_time(bgworkers::BgWorkerStartTime::RecoveryFinished)
+        .set_restart_time(Some(Duration::from_secs(WRITER_RESTART_SECONDS)))
+        .load();
+
+    // A background worker that ships index segments from the primary to its standbys. It
+    // starts during recovery, so that standbys install the segments as they're shipped.
+    BackgroundWorkerBuilder::new("pg_search_shipping_worker")
+        // Must be the name of a function in this file.
+        .set_function("pg_search_shipping_worker")
+        // Must be the name of this library.
+        .set_library("pg_search")
+        // The argument will be unused. You just need to pass something.
+        .set_argument(0.into_datum())
+        .set_start_time(bgworkers::BgWorkerStartTime::ConsistentState)
+        .set_restart_time(Some(Duration::from_secs(WRITER_RESTART_SECONDS)))
+        .load();
+
+    // A background worker answering searches over HTTP, if `paradedb.http_port` is set.
+    if SEARCH_GUCS.http_port.get() > 0 {
+        BackgroundWorkerBuilder::new("pg_search_http_worker")
+            // Must be the name of a function in this file.
+            .set_function("pg_search_http_worker")
+            // Must be the name of this library.
+            .set_library("pg_search")
+            // The argument will be unused. You just need to pass something.
+            .set_argument(0.into_datum())
+            .enable_spi_access()
+            .set_start_time(bgworkers::BgWorkerStartTime::RecoveryFinished)
+            .set_restart_time(Some(Duration::from_secs(WRITER_RESTART_SECONDS)))
+            .load();
+    }
 }
 
 #[pg_guard]
 #[no_mangle]
 pub extern "C" fn pg_search_insert_worker(_arg: pg_sys::Datum) {
     pgrx::log!("starting pg_search insert worker at PID {}", process::id());
-    let writer = writer::Writer::new();
+
+    // Indexes aren't WAL-logged, so after a failover or a crash, the ones that may have
+    // drifted from their tables are marked to be rebuilt before they're used again.
+    let timeline = env::wal_timeline();
+    match Resync::mark_stale_indexes(&env::postgres_data_dir_path(), timeline) {
+        Ok(marked) => {
+            for directory in marked {
+                log!(
+                    "bm25 index {} will be rebuilt from its table on its next use",
+                    directory.index_name
+                );
+            }
+        }
+        Err(err) => warning!("could not check bm25 indexes for recovery: {err}"),
+    }
+
+    let mut writer = writer::Writer::new();
+    writer.set_timeline(timeline);
     let mut server = writer::Server::new(writer).expect("error starting writer server");
 
     // Retrieve the assigned port and assign to global state.
@@ -147,6 +244,392 @@ pub extern "C" fn pg_search_shutdown_worker(_arg: pg_sys::Datum) {
         .unwrap_or_else(|e| log!("error shutting down bm25 writer from background worker: {e:?}"));
 }
 
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_search_merge_worker(_arg: pg_sys::Datum) {
+    pgrx::log!("starting pg_search merge worker at PID {}", process::id());
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+    let postgres_data_dir_path = env::postgres_data_dir_path();
+    let mut heal_workers = HashMap::new();
+    let mut follower_workers = HashMap::new();
+    let mut refresh_workers = HashMap::new();
+    loop {
+        let interval = Duration::from_secs(SEARCH_GUCS.merge_interval.get() as u64);
+        if !BackgroundWorker::wait_latch(Some(interval)) {
+            // We've received SIGTERM.
+            break;
+        }
+
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+        }
+
+        // The insert worker may not have started its server yet.
+        let Some(addr) = WRITER_GLOBAL.share().addr else {
+            continue;
+        };
+        let mut writer_client: writer::Client<writer::WriterRequest> = writer::Client::new(addr);
+
+        // Sent every time, as the insert worker forgets them if it restarts.
+        if let Err(err) = writer_client.request(writer::WriterRequest::SetIoLimits {
+            index_mb_per_sec: SEARCH_GUCS.index_io_limit.get() as u32,
+            merge_mb_per_sec: SEARCH_GUCS.merge_io_limit.get() as u32,
+        }) {
+            log!("error setting pg_search writer I/O limits: {err}");
+        }
+        if let Err(err) = writer_client.request(writer::WriterRequest::SetDiskLimits {
+            min_free_disk_mb: SEARCH_GUCS.min_free_disk_space.get() as u64,
+        }) {
+            log!("error setting pg_search writer disk limits: {err}");
+        }
+
+        // Heal and follower workers exit once they're disabled, and are started again once
+        // they're enabled. Refresh workers exit once no source is refreshed on a schedule.
+        match writer::WriterDirectory::list_all(&postgres_data_dir_path) {
+            Ok(directories) => {
+                if SEARCH_GUCS.heal_rows_per_second.get() > 0 {
+                    start_database_workers(
+                        &mut heal_workers,
+                        &directories,
+                        "pg_search_heal_worker",
+                    );
+                }
+                if SEARCH_GUCS.follower_interval.get() > 0 {
+                    start_database_workers(
+                        &mut follower_workers,
+                        &directories,
+                        "pg_search_follower_worker",
+                    );
+                }
+                let scheduled: Vec<_> = directories
+                    .into_iter()
+                    .filter(|directory| {
+                        IndexSource::load(directory)
+                            .is_ok_and(|source| source.is_some_and(|source| source.is_scheduled()))
+                    })
+                    .collect();
+                start_database_workers(
+                    &mut refresh_workers,
+                    &scheduled,
+                    "pg_search_refresh_worker",
+                );
+            }
+            Err(err) => log!("error listing pg_search indexes to heal, follow or refresh: {err}"),
+        }
+
+        let max_merges = SEARCH_GUCS.max_merges_per_interval.get() as usize;
+        if max_merges == 0 {
+            continue;
+        }
+
+        let directories = match writer::WriterDirectory::list_all(&postgres_data_dir_path) {
+            Ok(directories) => directories,
+            Err(err) => {
+                log!("error listing pg_search indexes to merge: {err}");
+                continue;
+            }
+        };
+
+        for directory in directories {
+            if let Err(err) = writer_client.request(writer::WriterRequest::Merge {
+                directory,
+                max_merges,
+            }) {
+                log!("error requesting pg_search index merge: {err}");
+            }
+        }
+    }
+}
+
+/// Start the worker `function` for each database with bm25 indexes, unless it's already
+/// running. Workers can only connect to a single database.
+fn start_database_workers(
+    workers: &mut HashMap<u32, DynamicBackgroundWorker>,
+    directories: &[writer::WriterDirectory],
+    function: &str,
+) {
+    let database_oids: HashSet<u32> = directories
+        .iter()
+        .map(|directory| directory.database_oid)
+        .collect();
+    for database_oid in database_oids {
+        let running = workers.get(&database_oid).is_some_and(|worker| {
+            !matches!(
+                worker.get_status(),
+                BackgroundWorkerStatus::Stopped | BackgroundWorkerStatus::PostmasterDied
+            )
+        });
+        if running {
+            continue;
+        }
+
+        match BackgroundWorkerBuilder::new(function)
+            .set_function(function)
+            .set_library("pg_search")
+            .set_argument((database_oid as i64).into_datum())
+            .enable_spi_access()
+            .set_notify_pid(unsafe { pg_sys::MyProcPid })
+            .load_dynamic()
+        {
+            Ok(worker) => {
+                workers.insert(database_oid, worker);
+            }
+            Err(_) => log!("could not start {function} for database {database_oid}"),
+        }
+    }
+}
+
+/// Compare the rows of the tables of one database with their bm25 indexes, a few blocks at
+/// a time, and reindex the rows the indexes are missing. See `heal_next_blocks`.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_search_heal_worker(arg: pg_sys::Datum) {
+    let database_oid = unsafe { i64::from_datum(arg, false) }
+        .expect("heal worker started without a database") as u32;
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnectionByOid(
+            pg_sys::Oid::from(database_oid),
+            pg_sys::InvalidOid,
+            0,
+        )
+    };
+    pgrx::log!(
+        "starting pg_search heal worker for database {database_oid} at PID {}",
+        process::id()
+    );
+
+    // An error stops the worker until the merge worker starts it again. Starting from an
+    // arbitrary index keeps an index that fails from holding back the others.
+    let mut position = process::id() as usize;
+    loop {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+        }
+        let rows_per_second = SEARCH_GUCS.heal_rows_per_second.get();
+        if rows_per_second == 0 {
+            break;
+        }
+
+        let step = BackgroundWorker::transaction(move || postgres::heal::heal_next_index(position));
+        position = position.wrapping_add(1);
+
+        let wait = Duration::from_secs_f64(step.rows_checked as f64 / rows_per_second as f64);
+        if !BackgroundWorker::wait_latch(Some(wait.max(HEAL_MIN_WAIT))) {
+            // We've received SIGTERM.
+            break;
+        }
+    }
+}
+
+/// Apply the changes of the replication slots of the follower indexes of one database to
+/// their tables, see `Follower`.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_search_follower_worker(arg: pg_sys::Datum) {
+    let database_oid = unsafe { i64::from_datum(arg, false) }
+        .expect("follower worker started without a database") as u32;
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnectionByOid(
+            pg_sys::Oid::from(database_oid),
+            pg_sys::InvalidOid,
+            0,
+        )
+    };
+    pgrx::log!(
+        "starting pg_search follower worker for database {database_oid} at PID {}",
+        process::id()
+    );
+
+    // Like for heal workers, an error stops the worker until the merge worker starts it
+    // again. Changes that weren't committed are read again from the slot.
+    loop {
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+        }
+        let interval = SEARCH_GUCS.follower_interval.get();
+        if interval == 0 {
+            break;
+        }
+
+        let followers = BackgroundWorker::transaction(postgres::follower::Follower::list);
+        for follower in followers {
+            BackgroundWorker::transaction(|| follower.follow());
+        }
+
+        if !BackgroundWorker::wait_latch(Some(Duration::from_secs(interval as u64))) {
+            // We've received SIGTERM.
+            break;
+        }
+    }
+}
+
+/// Refresh the tables of the indexes of one database built from foreign tables, views or
+/// materialized views, as scheduled by their `source_refresh_interval`. See `IndexSource`.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_search_refresh_worker(arg: pg_sys::Datum) {
+    let database_oid = unsafe { i64::from_datum(arg, false) }
+        .expect("refresh worker started without a database") as u32;
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+    unsafe {
+        pg_sys::BackgroundWorkerInitializeConnectionByOid(
+            pg_sys::Oid::from(database_oid),
+            pg_sys::InvalidOid,
+            0,
+        )
+    };
+    pgrx::log!(
+        "starting pg_search refresh worker for database {database_oid} at PID {}",
+        process::id()
+    );
+
+    let postgres_data_dir_path = env::postgres_data_dir_path();
+    loop {
+        let directories = match writer::WriterDirectory::list_all(&postgres_data_dir_path) {
+            Ok(directories) => directories,
+            Err(err) => {
+                log!("error listing pg_search indexes to refresh: {err}");
+                break;
+            }
+        };
+
+        let now = index::shipping::unix_time();
+        let mut scheduled = false;
+        for directory in directories
+            .iter()
+            .filter(|directory| directory.database_oid == database_oid)
+        {
+            let Ok(Some(source)) = IndexSource::load(directory) else {
+                continue;
+            };
+            scheduled |= source.is_scheduled();
+            if source.is_due(now) {
+                BackgroundWorker::transaction(|| {
+                    postgres::source::refresh_source(directory, false)
+                });
+            }
+        }
+
+        // The merge worker starts the worker again if a source is scheduled later on.
+        if !scheduled {
+            break;
+        }
+        if !BackgroundWorker::wait_latch(Some(REFRESH_CHECK_INTERVAL)) {
+            // We've received SIGTERM.
+            break;
+        }
+    }
+}
+
+/// Ship the segments of every bm25 index to `paradedb.segment_shipping_path` on the primary,
+/// and install the shipped segments on standbys. See `SegmentShipping`.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_search_shipping_worker(_arg: pg_sys::Datum) {
+    pgrx::log!(
+        "starting pg_search shipping worker at PID {}",
+        process::id()
+    );
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGHUP | SignalWakeFlags::SIGTERM);
+
+    let postgres_data_dir_path = env::postgres_data_dir_path();
+    loop {
+        let interval = Duration::from_secs(SEARCH_GUCS.segment_shipping_interval.get() as u64);
+        if !BackgroundWorker::wait_latch(Some(interval)) {
+            // We've received SIGTERM.
+            break;
+        }
+
+        if BackgroundWorker::sighup_received() {
+            unsafe { pg_sys::ProcessConfigFile(pg_sys::GucContext_PGC_SIGHUP) };
+        }
+
+        let Some(root) = SEARCH_GUCS
+            .segment_shipping_path
+            .get()
+            .filter(|path| !path.is_empty())
+            .map(PathBuf::from)
+        else {
+            continue;
+        };
+
+        // A standby that was promoted starts shipping its own indexes.
+        if unsafe { pg_sys::RecoveryInProgress() } {
+            let directories = match SegmentShipping::list_shipped(&root, &postgres_data_dir_path) {
+                Ok(directories) => directories,
+                Err(err) => {
+                    log!("error listing shipped pg_search indexes: {err}");
+                    continue;
+                }
+            };
+            for directory in directories {
+                if let Err(err) = SegmentShipping::install(&root, &directory) {
+                    log!(
+                        "error installing shipped bm25 index {}: {err}",
+                        directory.index_name
+                    );
+                }
+            }
+        } else {
+            let directories = match writer::WriterDirectory::list_all(&postgres_data_dir_path) {
+                Ok(directories) => directories,
+                Err(err) => {
+                    log!("error listing pg_search indexes to ship: {err}");
+                    continue;
+                }
+            };
+            for directory in directories {
+                if let Err(err) = ship_index(&root, &directory) {
+                    log!("error shipping bm25 index {}: {err}", directory.index_name);
+                }
+            }
+        }
+    }
+}
+
+/// Answer the searches of the HTTP endpoint, see `HttpServer`.
+#[pg_guard]
+#[no_mangle]
+pub extern "C" fn pg_search_http_worker(_arg: pg_sys::Datum) {
+    BackgroundWorker::attach_signal_handlers(SignalWakeFlags::SIGTERM);
+    let database = SEARCH_GUCS.http_database.get();
+    BackgroundWorker::connect_worker_to_spi(database.as_deref(), None);
+
+    let addr = format!(
+        "{}:{}",
+        SEARCH_GUCS.http_listen_address.get().unwrap_or_default(),
+        SEARCH_GUCS.http_port.get()
+    );
+    let server = http::HttpServer::new(&addr)
+        .unwrap_or_else(|err| panic!("error starting pg_search http server: {err}"));
+    pgrx::log!(
+        "starting pg_search http worker at PID {}, listening at {addr}",
+        process::id()
+    );
+
+    while !BackgroundWorker::sigterm_received() {
+        if let Err(err) = server.handle_next(HTTP_POLL_INTERVAL) {
+            log!("error answering pg_search http request: {err}");
+        }
+    }
+}
+
+/// Ship the last commit of an index, unless it's about to be rebuilt or kept in object storage.
+fn ship_index(root: &Path, directory: &writer::WriterDirectory) -> Result<(), ShippingError> {
+    if Resync::is_needed(directory)? {
+        return Ok(());
+    }
+    let search_index: index::SearchIndex = directory.load_index()?;
+    if search_index.storage.is_some() {
+        return Ok(());
+    }
+    SegmentShipping::ship(&search_index, root)?;
+    Ok(())
+}
+
 /// This module is required by `cargo pgrx test` invocations.
 /// It must be visible at the root of your extension crate.
 #[cfg(test)]
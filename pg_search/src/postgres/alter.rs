@@ -0,0 +1,71 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::globals::WriterGlobal;
+use crate::index::SearchIndex;
+use crate::postgres::options::SearchIndexCreateOptions;
+use crate::writer::WriterDirectory;
+use pgrx::*;
+
+/// Apply the options of an index changed with `ALTER INDEX ... SET`, which only updates the
+/// catalog. The options that don't affect the documents of the index are sent to the writer
+/// the next time the index is used, see `paradedb.alter_index_options`.
+pub fn sync_options(index_relation: &PgRelation) {
+    // Standbys don't have a writer, the options are applied once promoted.
+    if unsafe { pg_sys::RecoveryInProgress() } || index_relation.rd_options.is_null() {
+        return;
+    }
+
+    let rdopts =
+        unsafe { PgBox::from_pg(index_relation.rd_options as *mut SearchIndexCreateOptions) };
+    let Some(uuid) = rdopts.get_uuid() else {
+        return;
+    };
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let search_index = SearchIndex::from_cache(&directory, &uuid)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+
+    // Option validators don't see the index, so a security filter set with ALTER INDEX is
+    // checked against its fields here, the first time the index is used after.
+    if let Some(filter) = rdopts.get_security_filter() {
+        filter
+            .validate(&search_index.schema)
+            .unwrap_or_else(|err| panic!("{err}"));
+    }
+
+    let merge_policy = rdopts.get_merge_policy();
+    let refresh_interval = rdopts.get_refresh_interval();
+    let writer_memory_budget = rdopts.get_writer_memory_budget();
+    let max_index_size = rdopts.get_max_index_size();
+    if search_index.merge_policy == merge_policy
+        && search_index.refresh_interval == refresh_interval
+        && search_index.writer_memory_budget == writer_memory_budget
+        && search_index.max_index_size == max_index_size
+    {
+        return;
+    }
+
+    search_index
+        .alter_options(
+            &WriterGlobal::client(),
+            merge_policy,
+            refresh_interval,
+            writer_memory_budget,
+            max_index_size,
+        )
+        .unwrap_or_else(|err| panic!("error altering options of {directory:?}: {err}"));
+}
@@ -0,0 +1,101 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use crate::postgres::utils::bm25_index_name;
+use crate::query::SearchQueryInput;
+use crate::SEARCH_GUCS;
+use pgrx::*;
+use std::collections::hash_map::RandomState;
+use std::ffi::CStr;
+use std::hash::{BuildHasher, Hasher};
+
+/// Record that the current user ran `query` against an index, and how many rows it returned,
+/// for a sample of `paradedb.audit_sample_rate` of the searches of the indexes listed in
+/// `paradedb.audit_indexes`. Searches are inserted into
+/// `paradedb.audit_table` if it is set, or written to the Postgres log otherwise. Standbys,
+/// read-only transactions and parallel workers can't insert rows, so they always log.
+pub fn audit_search(index_name: &str, query: &SearchQueryInput, rows_returned: usize) {
+    if !sampled(SEARCH_GUCS.audit_sample_rate.get()) || !audited(index_name) {
+        return;
+    }
+
+    let query = serde_json::to_string(query).unwrap_or_default();
+    let can_insert = unsafe {
+        !pg_sys::RecoveryInProgress() && !pg_sys::XactReadOnly && pg_sys::ParallelWorkerNumber < 0
+    };
+    match SEARCH_GUCS
+        .audit_table
+        .get()
+        .filter(|table| !table.is_empty())
+    {
+        Some(table) if can_insert => {
+            Spi::run_with_args(
+                &format!(
+                    "INSERT INTO {table} (searched_at, user_name, index_name, query, rows_returned) \
+                     VALUES (now(), current_user, $1, $2::jsonb, $3)"
+                ),
+                Some(vec![
+                    (PgBuiltInOids::TEXTOID.oid(), index_name.into_datum()),
+                    (PgBuiltInOids::TEXTOID.oid(), query.into_datum()),
+                    (
+                        PgBuiltInOids::INT8OID.oid(),
+                        (rows_returned as i64).into_datum(),
+                    ),
+                ]),
+            )
+            .unwrap_or_else(|err| panic!("could not write search to audit table {table}: {err}"));
+        }
+        _ => {
+            let user_name =
+                unsafe { CStr::from_ptr(pg_sys::GetUserNameFromId(pg_sys::GetUserId(), false)) };
+            ereport!(
+                PgLogLevel::LOG,
+                PgSqlErrorCode::ERRCODE_SUCCESSFUL_COMPLETION,
+                format!(
+                    "bm25 search on index \"{index_name}\" by user \"{}\" returned {rows_returned} rows",
+                    user_name.to_string_lossy()
+                ),
+                format!("query: {query}")
+            );
+        }
+    }
+}
+
+/// Whether the searches of the bm25 index `index_name` are audited. Indexes are listed by the
+/// names they're searched with, which are looked up only once a search is sampled.
+fn audited(index_name: &str) -> bool {
+    let Some(audit_indexes) = SEARCH_GUCS.audit_indexes.get() else {
+        return false;
+    };
+    audit_indexes
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .any(|name| name == "*" || bm25_index_name(name) == index_name)
+}
+
+/// Whether to record a search, with a probability of `rate`.
+fn sampled(rate: f64) -> bool {
+    if rate <= 0.0 {
+        return false;
+    }
+    if rate >= 1.0 {
+        return true;
+    }
+    // Every RandomState is seeded with new keys, which is random enough to sample searches.
+    let random = RandomState::new().build_hasher().finish();
+    (random as f64) < rate * u64::MAX as f64
+}
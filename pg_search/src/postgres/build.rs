@@ -17,34 +17,77 @@
 
 use crate::env::register_commit_callback;
 use crate::globals::WriterGlobal;
+use crate::index::fault::FaultPoint;
+use crate::index::history::VALID_FROM_FIELD;
+use crate::index::progress::{BuildPhase, BuildProgress};
+use crate::index::readonly::ReadOnly;
 use crate::index::SearchIndex;
+use crate::postgres::extract::extract_command;
 use crate::postgres::options::SearchIndexCreateOptions;
-use crate::postgres::utils::row_to_search_document;
-use crate::schema::{SearchFieldConfig, SearchFieldName, SearchFieldType};
-use crate::writer::WriterDirectory;
+use crate::postgres::types::{is_sparse_vector_type, is_vector_type};
+use crate::postgres::utils::{row_to_search_document, IndexedRow};
+use crate::schema::{SearchFieldConfig, SearchFieldErrorPolicy, SearchFieldName, SearchFieldType};
+use crate::writer::{IndexError, WriterDirectory};
+use pgrx::pg_sys::AsPgCStr;
 use pgrx::*;
+use shared::postgres::transaction::Transaction;
 use std::collections::HashMap;
 use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, PoisonError};
 use tantivy::schema::IndexRecordOption;
 use tokenizers::{SearchNormalizer, SearchTokenizer};
 
+// Parameters of pg_stat_progress_create_index, from commands/progress.h.
+const PROGRESS_CREATEIDX_SUBPHASE: i32 = 10;
+const PROGRESS_CREATEIDX_TUPLES_TOTAL: i32 = 11;
+const PROGRESS_CREATEIDX_TUPLES_DONE: i32 = 12;
+/// How many rows are indexed between two saves of the build progress to disk.
+const PROGRESS_SAVE_INTERVAL: usize = 10_000;
+
 // For now just pass the count on the build callback state
 struct BuildState {
     count: usize,
+    /// Values that couldn't be indexed, see `SearchFieldErrorPolicy`.
+    skipped_values: u64,
     memctx: PgMemoryContexts,
     uuid: String,
+    directory: WriterDirectory,
 }
 
 impl BuildState {
-    fn new(uuid: String) -> Self {
+    fn new(uuid: String, directory: WriterDirectory) -> Self {
         BuildState {
             count: 0,
+            skipped_values: 0,
             memctx: PgMemoryContexts::new("pg_search_index_build"),
             uuid,
+            directory,
         }
     }
 }
 
+/// Report the phase of a build to `pg_stat_progress_create_index`, and along with the rows
+/// indexed so far, to `paradedb.index_build_progress`.
+fn report_progress(directory: &WriterDirectory, phase: BuildPhase, docs_indexed: usize) {
+    unsafe { pg_sys::pgstat_progress_update_param(PROGRESS_CREATEIDX_SUBPHASE, phase.subphase()) };
+    let progress = BuildProgress {
+        pid: unsafe { pg_sys::MyProcPid },
+        phase,
+        docs_indexed: docs_indexed as u64,
+    };
+    if let Err(err) = progress.save(directory) {
+        warning!("could not save build progress of {directory:?}: {err}");
+    }
+}
+
+#[pg_guard]
+pub extern "C" fn ambuildphasename(phasenum: i64) -> *mut std::os::raw::c_char {
+    match BuildPhase::from_subphase(phasenum) {
+        Some(phase) => phase.name().as_pg_cstr(),
+        None => std::ptr::null_mut(),
+    }
+}
+
 #[pg_guard]
 pub extern "C" fn ambuild(
     heaprel: pg_sys::Relation,
@@ -129,6 +172,46 @@ pub extern "C" fn ambuild(
             _ => panic!("'{name}' cannot be indexed as a datetime field"),
         });
 
+    // Vector columns aren't in the map, as arrays are mapped to the type of their elements.
+    let tupdesc = heap_relation.tuple_desc();
+    let vector_fields = rdopts
+        .get_vector_fields()
+        .into_iter()
+        .map(
+            |(name, config)| match tupdesc.iter().find(|attribute| attribute.name() == name.0) {
+                Some(attribute) if is_vector_type(attribute.type_oid()) => {
+                    (name, config, SearchFieldType::Vector)
+                }
+                _ => panic!("'{name}' cannot be indexed as a vector field"),
+            },
+        );
+
+    let sparse_fields = rdopts
+        .get_sparse_fields()
+        .into_iter()
+        .map(
+            |(name, config)| match tupdesc.iter().find(|attribute| attribute.name() == name.0) {
+                Some(attribute) if is_sparse_vector_type(attribute.type_oid()) => {
+                    (name, config, SearchFieldType::Sparse)
+                }
+                _ => panic!("'{name}' cannot be indexed as a sparse vector field"),
+            },
+        );
+
+    // Document fields are text fields whose text is extracted from a bytea column.
+    let document_fields = rdopts.get_document_fields();
+    if !document_fields.is_empty() && extract_command().is_none() {
+        panic!("paradedb.extract_command must be set to index document fields");
+    }
+    let document_fields = document_fields.into_iter().map(|(name, config)| {
+        match tupdesc.iter().find(|attribute| attribute.name() == name.0) {
+            Some(attribute) if attribute.type_oid() == PgOid::BuiltIn(PgBuiltInOids::BYTEAOID) => {
+                (name, config, SearchFieldType::Text)
+            }
+            _ => panic!("'{name}' cannot be indexed as a document field"),
+        }
+    });
+
     let uuid = rdopts
         .get_uuid()
         .expect("must specify uuid, this is done automatically in 'create_bm25'");
@@ -143,6 +226,7 @@ pub extern "C" fn ambuild(
                 indexed: true,
                 fast: true,
                 stored: true,
+                on_error: SearchFieldErrorPolicy::Fail,
             }
         }
         SearchFieldType::Text => SearchFieldConfig::Text {
@@ -153,6 +237,7 @@ pub extern "C" fn ambuild(
             tokenizer: SearchTokenizer::Raw,
             record: IndexRecordOption::Basic,
             normalizer: SearchNormalizer::Raw,
+            on_error: SearchFieldErrorPolicy::Fail,
         },
         SearchFieldType::Json => SearchFieldConfig::Json {
             indexed: true,
@@ -162,25 +247,34 @@ pub extern "C" fn ambuild(
             tokenizer: SearchTokenizer::Raw,
             record: IndexRecordOption::Basic,
             normalizer: SearchNormalizer::Raw,
+            on_error: SearchFieldErrorPolicy::Fail,
         },
         SearchFieldType::Bool => SearchFieldConfig::Boolean {
             indexed: true,
             fast: true,
             stored: true,
+            on_error: SearchFieldErrorPolicy::Fail,
         },
         SearchFieldType::Date => SearchFieldConfig::Date {
             indexed: true,
             fast: true,
             stored: true,
+            on_error: SearchFieldErrorPolicy::Fail,
         },
+        SearchFieldType::Vector | SearchFieldType::Sparse => {
+            panic!("key field cannot be a vector")
+        }
     };
 
     // Concatenate the separate lists of fields.
-    let fields: Vec<_> = text_fields
+    let mut fields: Vec<_> = text_fields
         .chain(numeric_fields)
         .chain(boolean_fields)
         .chain(json_fields)
         .chain(datetime_fields)
+        .chain(vector_fields)
+        .chain(sparse_fields)
+        .chain(document_fields)
         .chain(std::iter::once((
             key_field.clone(),
             key_config,
@@ -206,18 +300,179 @@ pub extern "C" fn ambuild(
         panic!("no fields specified")
     }
 
+    // Indexes that keep history date each version of a row, see `IndexHistory`.
+    if rdopts.get_keep_history() {
+        if fields.iter().any(|(name, _, _)| name.0 == VALID_FROM_FIELD) {
+            panic!("'{VALID_FROM_FIELD}' is reserved for indexes that keep history");
+        }
+        fields.push((
+            VALID_FROM_FIELD.into(),
+            SearchFieldConfig::Date {
+                indexed: true,
+                fast: true,
+                stored: true,
+                on_error: SearchFieldErrorPolicy::Fail,
+            },
+            SearchFieldType::Date,
+        ));
+    }
+
+    // Every search compares the values of the security filter with the terms of its field.
+    if let Some(filter) = rdopts.get_security_filter() {
+        match fields.iter().find(|(name, _, _)| name.0 == filter.field) {
+            Some((_, _, SearchFieldType::Text | SearchFieldType::I64 | SearchFieldType::U64)) => {}
+            Some(_) => panic!(
+                "security_filter field '{}' must be a text or integer field",
+                filter.field
+            ),
+            None => panic!(
+                "security_filter field '{}' is not a field of the index",
+                filter.field
+            ),
+        }
+    }
+
+    // Documents are only committed once they fill the memory budget, so they need background
+    // commits to become searchable in the meantime.
+    if rdopts.get_writer_memory_budget() > 0 && rdopts.get_refresh_interval() == 0 {
+        panic!("writer_memory_budget requires a refresh_interval")
+    }
+
     let writer_client = WriterGlobal::client();
-    let directory = WriterDirectory::from_index_name(&index_name);
+    let directory = WriterDirectory::for_build(&index_relation);
+
+    // An index that Postgres rewrites, as on TRUNCATE, REINDEX or VACUUM FULL, is built in the
+    // directory of its new file node. The directories of its earlier file nodes stay
+    // searchable by other connections, and are only dropped once the rebuild commits.
+    let earlier = directory
+        .siblings()
+        .unwrap_or_else(|err| panic!("error listing earlier directories of '{index_name}': {err}"));
+    if earlier
+        .iter()
+        .any(|sibling| ReadOnly::is_set(sibling).unwrap_or(false))
+    {
+        panic!("{}", IndexError::ReadOnly(index_name.clone()))
+    }
+
+    // The functions of an index find it by name, so a BM25 index of the same name in another
+    // schema would be mistaken for it.
+    let collides = Spi::get_one_with_args::<bool>(
+        "SELECT EXISTS (SELECT 1 FROM pg_class c JOIN pg_am a ON c.relam = a.oid \
+         WHERE a.amname = 'bm25' AND c.relname = $1 AND c.oid <> $2)",
+        vec![
+            (
+                PgBuiltInOids::TEXTOID.oid(),
+                index_name.clone().into_datum(),
+            ),
+            (
+                PgBuiltInOids::OIDOID.oid(),
+                index_relation.oid().into_datum(),
+            ),
+        ],
+    )
+    .unwrap_or_else(|err| panic!("error looking up bm25 indexes named '{index_name}': {err}"))
+    .unwrap_or(false);
+    if collides {
+        panic!("a bm25 index named '{index_name}' already exists in this database")
+    }
+
     SearchIndex::create_index(
         &writer_client,
-        directory,
+        directory.clone(),
         fields,
         uuid.clone(),
         key_field_index,
+        rdopts.get_merge_policy(),
+        rdopts.get_refresh_interval(),
+        rdopts.get_directory_mode(),
+        rdopts.get_writer_memory_budget(),
+        rdopts.get_max_index_size(),
+        rdopts.get_storage(),
     )
     .expect("error creating new index instance");
 
-    let state = do_heap_scan(index_info, &heap_relation, &index_relation, uuid);
+    // The rebuilt index keeps the settings of the index it replaces, like paused maintenance.
+    if let Some(latest) = earlier.iter().max_by_key(|sibling| sibling.relfilenode) {
+        directory
+            .copy_settings_from(latest)
+            .unwrap_or_else(|err| panic!("error copying settings of '{index_name}': {err}"));
+    }
+
+    // The heap may be empty, so we can't rely on the build callback to register the commit.
+    register_commit_callback(&writer_client, directory.clone())
+        .expect("could not register commit callbacks for build operation");
+
+    // The directories to drop are only known before the commit, when the catalog can still be
+    // read: a rebuild rolled back to a savepoint leaves the index on an earlier file node.
+    // The transaction is already committed when they're dropped, so failing to drop them only
+    // leaves them behind, see `paradedb.gc_directories`.
+    let rebuild_name = format!("rebuild {index_name}");
+    let replaced = Arc::new(Mutex::new(vec![]));
+    let precommit_replaced = replaced.clone();
+    let precommit_index_name = index_name.clone();
+    Transaction::call_once_on_precommit(rebuild_name.clone(), move || {
+        let siblings = WriterDirectory::from_index_name(&precommit_index_name)
+            .siblings()
+            .unwrap_or_else(|err| panic!("error listing replaced directories: {err}"));
+        *precommit_replaced
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = siblings;
+    })
+    .expect("could not register precommit callback for index rebuild");
+    let commit_writer = writer_client.clone();
+    Transaction::call_once_on_commit(rebuild_name, move || {
+        let siblings =
+            std::mem::take(&mut *replaced.lock().unwrap_or_else(PoisonError::into_inner));
+        for sibling in siblings {
+            if let Err(err) = SearchIndex::drop_directory(&commit_writer, sibling.clone()) {
+                warning!("could not drop the replaced directory {sibling:?}: {err}");
+            }
+        }
+    })
+    .expect("could not register commit callback for index rebuild");
+    let abort_writer = writer_client.clone();
+    let abort_directory = directory.clone();
+    Transaction::call_once_on_abort(format!("rebuild {}", directory.dir_name()), move || {
+        if let Err(err) = SearchIndex::drop_directory(&abort_writer, abort_directory.clone()) {
+            warning!("could not drop the aborted directory {abort_directory:?}: {err}");
+        }
+    })
+    .expect("could not register abort callback for index rebuild");
+
+    // The progress is only reported until the transaction of the build ends.
+    let progress_name = format!("build progress {}", directory.dir_name());
+    let commit_directory = directory.clone();
+    Transaction::call_once_on_commit(progress_name.clone(), move || {
+        let _ = BuildProgress::clear(&commit_directory);
+    })
+    .expect("could not register commit callback for build progress");
+    let abort_directory = directory.clone();
+    Transaction::call_once_on_abort(progress_name, move || {
+        let _ = BuildProgress::clear(&abort_directory);
+    })
+    .expect("could not register abort callback for build progress");
+
+    let reltuples = unsafe { (*heap_relation.rd_rel).reltuples.max(0.0) };
+    unsafe {
+        pg_sys::pgstat_progress_update_param(PROGRESS_CREATEIDX_TUPLES_TOTAL, reltuples as i64)
+    };
+    report_progress(&directory, BuildPhase::IndexingRows, 0);
+
+    let state = do_heap_scan(
+        index_info,
+        &heap_relation,
+        &index_relation,
+        uuid,
+        directory.clone(),
+    );
+    FaultPoint::BuildAfterScan.hit(&directory);
+    if state.skipped_values > 0 {
+        SearchIndex::skip_values(&directory, &WriterGlobal::client(), state.skipped_values)
+            .unwrap_or_else(|err| panic!("error counting skipped values: {err}"));
+    }
+    // The writer commits the documents when the transaction commits.
+    report_progress(&directory, BuildPhase::CommittingIndex, state.count);
+
     let mut result = unsafe { PgBox::<pg_sys::IndexBuildResult>::alloc0() };
     result.heap_tuples = state.count as f64;
     result.index_tuples = state.count as f64;
@@ -233,8 +488,9 @@ fn do_heap_scan<'a>(
     heap_relation: &'a PgRelation,
     index_relation: &'a PgRelation,
     uuid: String,
+    directory: WriterDirectory,
 ) -> BuildState {
-    let mut state = BuildState::new(uuid);
+    let mut state = BuildState::new(uuid, directory);
     let _ = panic::catch_unwind(AssertUnwindSafe(|| unsafe {
         pg_sys::IndexBuildHeapScan(
             heap_relation.as_ptr(),
@@ -300,26 +556,36 @@ unsafe fn build_callback_internal(
             let index_relation_ref: PgRelation = PgRelation::from_pg(index);
             let tupdesc = index_relation_ref.tuple_desc();
             let index_name = index_relation_ref.name();
-            let directory = WriterDirectory::from_index_name(index_name);
-            let search_index = SearchIndex::from_cache(&directory, &state.uuid)
+            let search_index = SearchIndex::from_cache(&state.directory, &state.uuid)
                 .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
-            let search_document =
-                row_to_search_document(ctid, &tupdesc, values, isnull, &search_index.schema)
-                    .unwrap_or_else(|err| {
-                        panic!("error creating index entries for index '{index_name}': {err}",)
-                    });
+            let IndexedRow {
+                document,
+                skipped_values,
+            } = row_to_search_document(ctid, &tupdesc, values, isnull, &search_index.schema)
+                .unwrap_or_else(|err| {
+                    panic!("error creating index entries for index '{index_name}': {err}",)
+                });
+            state.skipped_values += skipped_values;
 
             let writer_client = WriterGlobal::client();
 
-            search_index
-                .insert(&writer_client, search_document)
-                .unwrap_or_else(|err| {
-                    panic!("error inserting document during build callback: {err:?}")
-                });
+            if let Some(search_document) = document {
+                search_index
+                    .insert(&writer_client, search_document)
+                    .unwrap_or_else(|err| {
+                        panic!("error inserting document during build callback: {err:?}")
+                    });
+            }
 
             register_commit_callback(&writer_client, search_index.directory.clone())
                 .expect("could not register commit callbacks for build operation");
         });
         state.memctx.reset();
+
+        state.count += 1;
+        pg_sys::pgstat_progress_update_param(PROGRESS_CREATEIDX_TUPLES_DONE, state.count as i64);
+        if state.count % PROGRESS_SAVE_INTERVAL == 0 {
+            report_progress(&state.directory, BuildPhase::IndexingRows, state.count);
+        }
     }
 }
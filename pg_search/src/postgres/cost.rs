@@ -15,6 +15,7 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use super::scan::SEARCH_STRATEGY;
 use pgrx::*;
 
 #[allow(clippy::too_many_arguments)]
@@ -56,6 +57,7 @@ pub unsafe extern "C" fn amcostestimate(
     ))]
     let index_clauses = PgList::<pg_sys::IndexClause>::from_pg(path.indexclauses);
 
+    let mut has_search_clause = false;
     for clause in index_clauses.iter_ptr() {
         #[cfg(any(
             feature = "pg12",
@@ -71,6 +73,17 @@ pub unsafe extern "C" fn amcostestimate(
             .as_ref()
             .expect("restrict info in index clause is NULL");
 
+        // The comparisons with constants are only searched for alongside an `@@@` condition.
+        let opfamily = *indexinfo
+            .opfamily
+            .add(clause.as_ref().unwrap().indexcol as usize);
+        let clause_node = ri.clause as *mut pg_sys::Node;
+        if is_a(clause_node, pg_sys::NodeTag::T_OpExpr) {
+            let opno = (*(clause_node as *mut pg_sys::OpExpr)).opno;
+            has_search_clause |=
+                pg_sys::get_op_opfamily_strategy(opno, opfamily) == SEARCH_STRATEGY as i32;
+        }
+
         if ri.norm_selec > 0f64 {
             *index_selectivity = ri.norm_selec.min(*index_selectivity);
         }
@@ -79,4 +92,11 @@ pub unsafe extern "C" fn amcostestimate(
     let reltuples = heap_relation.reltuples().unwrap_or(1f32) as f64;
     *index_total_cost += *index_selectivity * reltuples * pg_sys::cpu_index_tuple_cost;
     *index_total_cost -= pg_sys::random_page_cost;
+
+    // A scan of the index needs a search query, so the planner shouldn't pick the index for
+    // conditions like `id = 5` on their own.
+    if !has_search_clause {
+        *index_startup_cost = pg_sys::disable_cost;
+        *index_total_cost = pg_sys::disable_cost;
+    }
 }
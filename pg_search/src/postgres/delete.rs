@@ -18,7 +18,8 @@
 use pgrx::{pg_sys::ItemPointerData, *};
 
 use crate::{
-    env::register_commit_callback, globals::WriterGlobal, index::SearchIndex,
+    env::register_commit_callback, globals::WriterGlobal, index::history::IndexHistory,
+    index::prepared::PreparedInserts, index::SearchIndex, postgres::resync::resync_if_needed,
     writer::WriterDirectory,
 };
 
@@ -33,8 +34,9 @@ pub extern "C" fn ambulkdelete(
     let mut stats = unsafe { PgBox::from_pg(stats) };
     let index_rel: pg_sys::Relation = info.index;
     let index_relation = unsafe { PgRelation::from_pg(index_rel) };
+    resync_if_needed(&index_relation);
     let index_name = index_relation.name();
-    let directory = WriterDirectory::from_index_name(index_name);
+    let directory = WriterDirectory::from_index_relation(&index_relation);
     let search_index = SearchIndex::from_disk(&directory)
         .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
 
@@ -50,16 +52,46 @@ pub extern "C" fn ambulkdelete(
     register_commit_callback(&writer_client, search_index.directory.clone())
         .expect("could not register commit callbacks for delete operation");
 
+    // The rows of prepared transactions are applied before their ctids can be reused by the
+    // rows inserted after this vacuum.
+    PreparedInserts::resolve(
+        &search_index.directory,
+        &mut *writer_client
+            .lock()
+            .unwrap_or_else(|err| panic!("could not lock writer client: {err}")),
+    )
+    .unwrap_or_else(|err| panic!("error resolving prepared transactions: {err}"));
+
     if let Some(actual_callback) = callback {
         let should_delete = |ctid_val| unsafe {
             let mut ctid = ItemPointerData::default();
             pgrx::u64_to_item_pointer(ctid_val, &mut ctid);
             actual_callback(&mut ctid, callback_state)
         };
-        match search_index.delete(&writer_client, should_delete) {
+        // VACUUM VERBOSE reports at INFO level, otherwise progress is only logged for debugging.
+        let verbose = info.message_level >= pg_sys::INFO as i32;
+        let on_batch = |scanned, deleted| {
+            if verbose {
+                pgrx::info!(
+                    "index \"{index_name}\": scanned {scanned} documents, {deleted} deleted"
+                );
+            } else {
+                pgrx::debug1!(
+                    "index \"{index_name}\": scanned {scanned} documents, {deleted} deleted"
+                );
+            }
+            // Honor cost-based vacuum delays, and let the vacuum be cancelled between batches.
+            unsafe { pg_sys::vacuum_delay_point() };
+        };
+        // Replaced and deleted versions of rows are kept for searches of past states.
+        if IndexHistory::keeps_history(search_index) {
+            IndexHistory::archive(search_index, &should_delete)
+                .unwrap_or_else(|err| panic!("error archiving deleted rows: {err}"));
+        }
+        match search_index.delete(&writer_client, should_delete, on_batch) {
             Ok((deleted, not_deleted)) => {
-                stats.pages_deleted += deleted;
-                stats.num_pages += not_deleted;
+                stats.tuples_removed += deleted as f64;
+                stats.num_index_tuples = not_deleted as f64;
             }
             Err(err) => {
                 panic!("error: {err:?}")
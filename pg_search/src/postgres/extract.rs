@@ -0,0 +1,103 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use crate::SEARCH_GUCS;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use thiserror::Error;
+
+/// The command configured with `paradedb.extract_command`, if any.
+pub fn extract_command() -> Option<String> {
+    SEARCH_GUCS
+        .extract_command
+        .get()
+        .filter(|command| !command.is_empty())
+}
+
+/// The text of a binary document, like a PDF or a DOCX file, as printed by the configured
+/// extraction command when given the document on its standard input.
+pub fn extract_text(content: &[u8]) -> Result<String, ExtractError> {
+    let command = extract_command().ok_or(ExtractError::NotConfigured)?;
+    run_extract_command(&command, content)
+}
+
+fn run_extract_command(command: &str, content: &[u8]) -> Result<String, ExtractError> {
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // The document is written from another thread, as the command may fill its output pipe
+    // before it has read all of its input.
+    let mut stdin = child.stdin.take().expect("stdin should be piped");
+    let output = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(content));
+        let output = child.wait_with_output();
+        // A command that exits without reading all of the document closes the pipe, which
+        // is only an error if the command fails too.
+        let _ = writer.join();
+        output
+    })?;
+
+    if !output.status.success() {
+        return Err(ExtractError::CommandFailed(
+            output.status.to_string(),
+            String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[derive(Error, Debug)]
+pub enum ExtractError {
+    #[error("paradedb.extract_command is not set")]
+    NotConfigured,
+
+    #[error("paradedb.extract_command failed with {0}: {1}")]
+    CommandFailed(String, String),
+
+    #[error(transparent)]
+    IOError(#[from] std::io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::run_extract_command;
+    use rstest::*;
+
+    #[rstest]
+    fn test_run_extract_command() {
+        let text = run_extract_command("tr a-z A-Z", b"annual report").unwrap();
+        assert_eq!(text, "ANNUAL REPORT");
+
+        // Large documents don't block on the pipes.
+        let content = vec![b'a'; 1 << 20];
+        let text = run_extract_command("cat", &content).unwrap();
+        assert_eq!(text.len(), content.len());
+
+        // Commands may ignore their input.
+        let text = run_extract_command("echo extracted", &content).unwrap();
+        assert_eq!(text, "extracted\n");
+
+        let err = run_extract_command("echo unsupported format >&2; exit 3", b"")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unsupported format"), "{err}");
+    }
+}
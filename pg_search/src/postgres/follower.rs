@@ -0,0 +1,752 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::index::SearchIndex;
+use crate::postgres::utils::bm25_index_name;
+use crate::writer::WriterDirectory;
+use pgrx::*;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use thiserror::Error;
+
+/// How many changes a follower reads from its replication slot at each step. Changes are
+/// read by whole transactions, so a step can apply more.
+const FOLLOWER_BATCH_CHANGES: u32 = 1000;
+
+/// A bm25 index following a table of another server: the changes to the table, decoded by
+/// the `pgoutput` plugin into a logical replication slot of that server, are applied to the
+/// table of the index, which indexes them like any other write. Searches then run on this
+/// server, without reading from the other one.
+#[derive(Clone, Debug)]
+pub struct Follower {
+    pub index_name: String,
+    pub conninfo: String,
+    pub slot_name: String,
+    pub publication: String,
+    /// The end of the last transaction applied, committed along with its changes. The slot
+    /// is only advanced to it once committed, so that changes are never lost.
+    pub applied_lsn: Option<u64>,
+}
+
+impl Follower {
+    /// The followers of the current database.
+    pub fn list() -> Vec<Self> {
+        Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT index_name, conninfo, slot_name, publication, applied_lsn::text \
+                     FROM paradedb.followers ORDER BY index_name",
+                    None,
+                    None,
+                )?
+                .map(|row| {
+                    Ok(Self {
+                        index_name: row.get::<String>(1)?.unwrap_or_default(),
+                        conninfo: row.get::<String>(2)?.unwrap_or_default(),
+                        slot_name: row.get::<String>(3)?.unwrap_or_default(),
+                        publication: row.get::<String>(4)?.unwrap_or_default(),
+                        applied_lsn: row.get::<String>(5)?.as_deref().and_then(parse_lsn),
+                    })
+                })
+                .collect::<Result<Vec<_>, spi::Error>>()
+        })
+        .unwrap_or_else(|err| panic!("error listing bm25 index followers: {err}"))
+    }
+
+    pub fn load(index_name: &str) -> Option<Self> {
+        Self::list()
+            .into_iter()
+            .find(|follower| follower.index_name == index_name)
+    }
+
+    /// Apply the next changes of the slot to the table of the index, and return how many
+    /// rows were changed. They're indexed when the current transaction commits.
+    pub fn follow(&self) -> u64 {
+        let table = FollowedTable::load(&self.index_name);
+
+        // Locks the follower, so that two connections don't apply the same changes.
+        let applied_lsn = Spi::get_one_with_args::<String>(
+            "SELECT applied_lsn::text FROM paradedb.followers WHERE index_name = $1 FOR UPDATE",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                self.index_name.clone().into_datum(),
+            )],
+        )
+        .unwrap_or_else(|err| panic!("error locking follower of '{}': {err}", self.index_name))
+        .as_deref()
+        .and_then(parse_lsn);
+
+        // The slot lags behind the changes committed here, as it's only advanced once they
+        // are. Transactions it decodes again are skipped.
+        if let Some(applied_lsn) = applied_lsn {
+            self.advance_slot(applied_lsn);
+        }
+
+        let mut relations = HashMap::new();
+        let mut skipping = false;
+        let mut rows_changed = 0;
+        let mut last_lsn = applied_lsn;
+        for data in self.peek_changes() {
+            let message = PgOutputMessage::decode(&data).unwrap_or_else(|err| {
+                panic!("error decoding change of slot '{}': {err}", self.slot_name)
+            });
+            match message {
+                PgOutputMessage::Begin { final_lsn } => {
+                    skipping = applied_lsn.is_some_and(|applied_lsn| final_lsn < applied_lsn);
+                }
+                PgOutputMessage::Commit { end_lsn } => {
+                    if !skipping {
+                        last_lsn = Some(end_lsn);
+                    }
+                }
+                PgOutputMessage::Relation {
+                    relation_id,
+                    namespace,
+                    name,
+                    columns,
+                } => {
+                    relations.insert(relation_id, (namespace, name, columns));
+                }
+                _ if skipping => {}
+                message => {
+                    rows_changed += table.apply(&message, &relations);
+                }
+            }
+        }
+
+        if last_lsn != applied_lsn {
+            Spi::run_with_args(
+                "UPDATE paradedb.followers SET applied_lsn = $2::pg_lsn, applied_at = now() \
+                 WHERE index_name = $1",
+                Some(vec![
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        self.index_name.clone().into_datum(),
+                    ),
+                    (
+                        PgBuiltInOids::TEXTOID.oid(),
+                        last_lsn.map(format_lsn).into_datum(),
+                    ),
+                ]),
+            )
+            .unwrap_or_else(|err| {
+                panic!(
+                    "error saving position of follower of '{}': {err}",
+                    self.index_name
+                )
+            });
+        }
+        rows_changed
+    }
+
+    /// The next changes of the slot, without consuming them.
+    fn peek_changes(&self) -> Vec<Vec<u8>> {
+        let query = format!(
+            "SELECT data FROM pg_logical_slot_peek_binary_changes({}, NULL, {FOLLOWER_BATCH_CHANGES}, \
+             'proto_version', '1', 'publication_names', {})",
+            spi::quote_literal(&self.slot_name),
+            spi::quote_literal(&self.publication)
+        );
+        Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT data FROM dblink($1, $2) AS t(data bytea)",
+                    None,
+                    Some(vec![
+                        (
+                            PgBuiltInOids::TEXTOID.oid(),
+                            self.conninfo.clone().into_datum(),
+                        ),
+                        (PgBuiltInOids::TEXTOID.oid(), query.into_datum()),
+                    ]),
+                )?
+                .filter_map(|row| row.get::<Vec<u8>>(1).transpose())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .unwrap_or_else(|err| panic!("error reading changes of slot '{}': {err}", self.slot_name))
+    }
+
+    /// Let the other server discard the changes up to `lsn`. Slots can't move backwards, so
+    /// one that's already past it is left alone.
+    fn advance_slot(&self, lsn: u64) {
+        let lsn = spi::quote_literal(format_lsn(lsn));
+        let query = format!(
+            "SELECT count(pg_replication_slot_advance(slot_name, {lsn})) \
+             FROM pg_replication_slots WHERE slot_name = {} AND confirmed_flush_lsn < {lsn}",
+            spi::quote_literal(&self.slot_name)
+        );
+        Spi::run_with_args(
+            "SELECT * FROM dblink($1, $2) AS t(advanced bigint)",
+            Some(vec![
+                (
+                    PgBuiltInOids::TEXTOID.oid(),
+                    self.conninfo.clone().into_datum(),
+                ),
+                (PgBuiltInOids::TEXTOID.oid(), query.into_datum()),
+            ]),
+        )
+        .unwrap_or_else(|err| panic!("error advancing slot '{}': {err}", self.slot_name));
+    }
+}
+
+/// The table of a follower index, which changes are applied to.
+struct FollowedTable {
+    namespace: String,
+    name: String,
+    /// The schema qualified name of the table, quoted for queries.
+    qualified_name: String,
+    key_field: String,
+    columns: Vec<String>,
+}
+
+impl FollowedTable {
+    fn load(index_name: &str) -> Self {
+        let bm25_index_name = bm25_index_name(index_name);
+        let (namespace, name, qualified_name) = Spi::get_three_with_args::<String, String, String>(
+            "SELECT n.nspname::text, t.relname::text, t.oid::regclass::text \
+             FROM pg_index i \
+             JOIN pg_class c ON c.oid = i.indexrelid \
+             JOIN pg_class t ON t.oid = i.indrelid \
+             JOIN pg_namespace n ON n.oid = t.relnamespace \
+             WHERE c.relname = $1 LIMIT 1",
+            vec![(
+                PgBuiltInOids::TEXTOID.oid(),
+                bm25_index_name.clone().into_datum(),
+            )],
+        )
+        .unwrap_or_else(|err| panic!("error looking up the table of '{index_name}': {err}"));
+        let (Some(namespace), Some(name), Some(qualified_name)) = (namespace, name, qualified_name)
+        else {
+            panic!("no bm25 index named '{index_name}' exists")
+        };
+
+        let directory = WriterDirectory::from_index_name(&bm25_index_name);
+        let search_index = SearchIndex::from_disk(&directory)
+            .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+        let key_field = search_index.schema.key_field().name.0;
+
+        let columns = Spi::connect(|client| {
+            client
+                .select(
+                    &format!(
+                        "SELECT attname::text FROM pg_attribute \
+                         WHERE attrelid = {}::regclass AND attnum > 0 AND NOT attisdropped",
+                        spi::quote_literal(&qualified_name)
+                    ),
+                    None,
+                    None,
+                )?
+                .filter_map(|row| row.get::<String>(1).transpose())
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .unwrap_or_else(|err| panic!("error listing columns of {qualified_name}: {err}"));
+
+        Self {
+            namespace,
+            name,
+            qualified_name,
+            key_field,
+            columns,
+        }
+    }
+
+    /// Apply a change to the table, if it's a change of a table with the same name, and
+    /// return how many rows it changed. Changes can be applied again without effect, as
+    /// rows are found by key.
+    fn apply(
+        &self,
+        message: &PgOutputMessage,
+        relations: &HashMap<u32, (String, String, Vec<String>)>,
+    ) -> u64 {
+        let columns_of = |relation_id: &u32| {
+            relations
+                .get(relation_id)
+                .filter(|(namespace, name, _)| *namespace == self.namespace && *name == self.name)
+                .map(|(_, _, columns)| columns)
+        };
+
+        match message {
+            PgOutputMessage::Insert { relation_id, new } => match columns_of(relation_id) {
+                Some(columns) => {
+                    let row = tuple_json(columns, new);
+                    self.delete(&row);
+                    self.insert(&row)
+                }
+                None => 0,
+            },
+            PgOutputMessage::Update {
+                relation_id,
+                old,
+                new,
+            } => match columns_of(relation_id) {
+                Some(columns) => {
+                    let row = tuple_json(columns, new);
+                    // The old values are only sent when the key changed.
+                    let key = old
+                        .as_ref()
+                        .map_or_else(|| row.clone(), |old| tuple_json(columns, old));
+                    match self.update(&key, &row) {
+                        0 => self.insert(&row),
+                        updated => updated,
+                    }
+                }
+                None => 0,
+            },
+            PgOutputMessage::Delete { relation_id, old } => match columns_of(relation_id) {
+                Some(columns) => self.delete(&tuple_json(columns, old)),
+                None => 0,
+            },
+            PgOutputMessage::Truncate { relation_ids } => {
+                if relation_ids.iter().any(|id| columns_of(id).is_some()) {
+                    self.execute(&format!("DELETE FROM {}", self.qualified_name), vec![])
+                } else {
+                    0
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    fn insert(&self, row: &Map<String, Value>) -> u64 {
+        let columns = self.quoted_columns(row);
+        self.execute(
+            &format!(
+                "INSERT INTO {table} ({columns}) SELECT {columns} \
+                 FROM json_populate_record(NULL::{table}, $1)",
+                table = self.qualified_name,
+                columns = columns.join(", ")
+            ),
+            vec![row],
+        )
+    }
+
+    fn update(&self, key: &Map<String, Value>, row: &Map<String, Value>) -> u64 {
+        let assignments: Vec<String> = self
+            .quoted_columns(row)
+            .iter()
+            .map(|column| format!("{column} = r.{column}"))
+            .collect();
+        self.execute(
+            &format!(
+                "UPDATE {table} AS t SET {assignments} \
+                 FROM json_populate_record(NULL::{table}, $1) AS r \
+                 WHERE t.{key} = (json_populate_record(NULL::{table}, $2)).{key}",
+                table = self.qualified_name,
+                assignments = assignments.join(", "),
+                key = spi::quote_identifier(&self.key_field)
+            ),
+            vec![row, key],
+        )
+    }
+
+    fn delete(&self, key: &Map<String, Value>) -> u64 {
+        self.execute(
+            &format!(
+                "DELETE FROM {table} AS t \
+                 WHERE t.{key} = (json_populate_record(NULL::{table}, $1)).{key}",
+                table = self.qualified_name,
+                key = spi::quote_identifier(&self.key_field)
+            ),
+            vec![key],
+        )
+    }
+
+    /// The columns of the row that the table has, quoted.
+    fn quoted_columns(&self, row: &Map<String, Value>) -> Vec<String> {
+        self.columns
+            .iter()
+            .filter(|column| row.contains_key(column.as_str()))
+            .map(spi::quote_identifier)
+            .collect()
+    }
+
+    /// Run a statement changing rows, and return how many it changed.
+    fn execute(&self, query: &str, rows: Vec<&Map<String, Value>>) -> u64 {
+        let args = rows
+            .into_iter()
+            .map(|row| {
+                (
+                    PgBuiltInOids::JSONOID.oid(),
+                    pgrx::Json(Value::Object(row.clone())).into_datum(),
+                )
+            })
+            .collect::<Vec<_>>();
+        Spi::connect(|mut client| {
+            client
+                .update(&format!("{query} RETURNING 1"), None, Some(args))
+                .map(|table| table.len() as u64)
+        })
+        .unwrap_or_else(|err| panic!("error applying change to {}: {err}", self.qualified_name))
+    }
+}
+
+/// A row as a JSON object of column names to their text values, as `json_populate_record`
+/// reads them. Unchanged TOASTed values aren't sent, and are left out.
+fn tuple_json(columns: &[String], tuple: &[TupleValue]) -> Map<String, Value> {
+    columns
+        .iter()
+        .zip(tuple)
+        .filter_map(|(column, value)| match value {
+            TupleValue::Null => Some((column.clone(), Value::Null)),
+            TupleValue::Text(text) => Some((column.clone(), Value::String(text.clone()))),
+            TupleValue::Unchanged => None,
+        })
+        .collect()
+}
+
+/// Parse an LSN in the `X/X` text form of `pg_lsn`.
+pub fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+    Some((u64::from_str_radix(high, 16).ok()? << 32) | u64::from_str_radix(low, 16).ok()?)
+}
+
+pub fn format_lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn as u32)
+}
+
+/// A value of a column of a row sent by `pgoutput`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TupleValue {
+    Null,
+    /// A TOASTed value that the change didn't modify, which isn't sent.
+    Unchanged,
+    Text(String),
+}
+
+/// The messages of version 1 of the `pgoutput` protocol, with the fields that followers use.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PgOutputMessage {
+    Begin {
+        /// The LSN of the commit record of the transaction.
+        final_lsn: u64,
+    },
+    Commit {
+        /// The LSN right after the transaction.
+        end_lsn: u64,
+    },
+    /// Describes a table before the first change to it in a stream.
+    Relation {
+        relation_id: u32,
+        namespace: String,
+        name: String,
+        columns: Vec<String>,
+    },
+    Insert {
+        relation_id: u32,
+        new: Vec<TupleValue>,
+    },
+    Update {
+        relation_id: u32,
+        /// The old key, or the whole old row for tables with a full replica identity, if
+        /// it changed.
+        old: Option<Vec<TupleValue>>,
+        new: Vec<TupleValue>,
+    },
+    Delete {
+        relation_id: u32,
+        old: Vec<TupleValue>,
+    },
+    Truncate {
+        relation_ids: Vec<u32>,
+    },
+    /// Origin, type and logical decoding messages, which don't change rows.
+    Other,
+}
+
+impl PgOutputMessage {
+    pub fn decode(data: &[u8]) -> Result<Self, FollowerError> {
+        let mut reader = MessageReader { data, position: 0 };
+        let message = match reader.byte()? {
+            b'B' => Self::Begin {
+                final_lsn: reader.u64()?,
+            },
+            b'C' => {
+                let _flags = reader.byte()?;
+                let _commit_lsn = reader.u64()?;
+                Self::Commit {
+                    end_lsn: reader.u64()?,
+                }
+            }
+            b'R' => {
+                let relation_id = reader.u32()?;
+                let namespace = reader.string()?;
+                let name = reader.string()?;
+                let _replica_identity = reader.byte()?;
+                let num_columns = reader.u16()?;
+                let columns = (0..num_columns)
+                    .map(|_| {
+                        let _flags = reader.byte()?;
+                        let name = reader.string()?;
+                        let _type_oid = reader.u32()?;
+                        let _type_modifier = reader.u32()?;
+                        Ok(name)
+                    })
+                    .collect::<Result<_, FollowerError>>()?;
+                Self::Relation {
+                    relation_id,
+                    namespace,
+                    name,
+                    columns,
+                }
+            }
+            b'I' => {
+                let relation_id = reader.u32()?;
+                reader.expect(b'N')?;
+                Self::Insert {
+                    relation_id,
+                    new: reader.tuple()?,
+                }
+            }
+            b'U' => {
+                let relation_id = reader.u32()?;
+                let old = match reader.byte()? {
+                    b'K' | b'O' => {
+                        let old = reader.tuple()?;
+                        reader.expect(b'N')?;
+                        Some(old)
+                    }
+                    b'N' => None,
+                    other => return Err(FollowerError::UnexpectedByte(other as char)),
+                };
+                Self::Update {
+                    relation_id,
+                    old,
+                    new: reader.tuple()?,
+                }
+            }
+            b'D' => {
+                let relation_id = reader.u32()?;
+                match reader.byte()? {
+                    b'K' | b'O' => {}
+                    other => return Err(FollowerError::UnexpectedByte(other as char)),
+                }
+                Self::Delete {
+                    relation_id,
+                    old: reader.tuple()?,
+                }
+            }
+            b'T' => {
+                let num_relations = reader.u32()?;
+                let _options = reader.byte()?;
+                let relation_ids = (0..num_relations)
+                    .map(|_| reader.u32())
+                    .collect::<Result<_, _>>()?;
+                Self::Truncate { relation_ids }
+            }
+            b'O' | b'Y' | b'M' => Self::Other,
+            other => return Err(FollowerError::UnexpectedByte(other as char)),
+        };
+        Ok(message)
+    }
+}
+
+struct MessageReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> MessageReader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], FollowerError> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + len)
+            .ok_or(FollowerError::Truncated)?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, FollowerError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), FollowerError> {
+        match self.byte()? {
+            byte if byte == expected => Ok(()),
+            other => Err(FollowerError::UnexpectedByte(other as char)),
+        }
+    }
+
+    fn u16(&mut self) -> Result<u16, FollowerError> {
+        Ok(u16::from_be_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, FollowerError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64, FollowerError> {
+        Ok(u64::from_be_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    /// A null-terminated string.
+    fn string(&mut self) -> Result<String, FollowerError> {
+        let len = self.data[self.position..]
+            .iter()
+            .position(|byte| *byte == 0)
+            .ok_or(FollowerError::Truncated)?;
+        let string = String::from_utf8(self.bytes(len)?.to_vec())?;
+        self.position += 1;
+        Ok(string)
+    }
+
+    fn tuple(&mut self) -> Result<Vec<TupleValue>, FollowerError> {
+        let num_columns = self.u16()?;
+        (0..num_columns)
+            .map(|_| match self.byte()? {
+                b'n' => Ok(TupleValue::Null),
+                b'u' => Ok(TupleValue::Unchanged),
+                b't' => {
+                    let len = self.u32()? as usize;
+                    Ok(TupleValue::Text(String::from_utf8(
+                        self.bytes(len)?.to_vec(),
+                    )?))
+                }
+                other => Err(FollowerError::UnexpectedByte(other as char)),
+            })
+            .collect()
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum FollowerError {
+    #[error("message ended unexpectedly")]
+    Truncated,
+
+    #[error("unexpected byte '{0}' in message")]
+    UnexpectedByte(char),
+
+    #[error(transparent)]
+    InvalidUtf8(#[from] std::string::FromUtf8Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    fn tuple(values: &[Option<&str>]) -> Vec<u8> {
+        let mut data = (values.len() as u16).to_be_bytes().to_vec();
+        for value in values {
+            match value {
+                Some(text) => {
+                    data.push(b't');
+                    data.extend((text.len() as u32).to_be_bytes());
+                    data.extend(text.as_bytes());
+                }
+                None => data.push(b'n'),
+            }
+        }
+        data
+    }
+
+    #[rstest]
+    fn test_decode_pgoutput_messages() {
+        let mut relation = vec![b'R'];
+        relation.extend(16384u32.to_be_bytes());
+        relation.extend(b"public\0products\0d");
+        relation.extend(2u16.to_be_bytes());
+        for name in ["id", "description"] {
+            relation.push(0);
+            relation.extend(name.as_bytes());
+            relation.push(0);
+            relation.extend(23u32.to_be_bytes());
+            relation.extend(u32::MAX.to_be_bytes());
+        }
+        assert_eq!(
+            PgOutputMessage::decode(&relation).unwrap(),
+            PgOutputMessage::Relation {
+                relation_id: 16384,
+                namespace: "public".into(),
+                name: "products".into(),
+                columns: vec!["id".into(), "description".into()],
+            }
+        );
+
+        let mut insert = vec![b'I'];
+        insert.extend(16384u32.to_be_bytes());
+        insert.push(b'N');
+        insert.extend(tuple(&[Some("1"), None]));
+        assert_eq!(
+            PgOutputMessage::decode(&insert).unwrap(),
+            PgOutputMessage::Insert {
+                relation_id: 16384,
+                new: vec![TupleValue::Text("1".into()), TupleValue::Null],
+            }
+        );
+
+        let mut update = vec![b'U'];
+        update.extend(16384u32.to_be_bytes());
+        update.push(b'K');
+        update.extend(tuple(&[Some("1"), None]));
+        update.push(b'N');
+        update.extend(tuple(&[Some("2"), Some("Plastic Keyboard")]));
+        assert_eq!(
+            PgOutputMessage::decode(&update).unwrap(),
+            PgOutputMessage::Update {
+                relation_id: 16384,
+                old: Some(vec![TupleValue::Text("1".into()), TupleValue::Null]),
+                new: vec![
+                    TupleValue::Text("2".into()),
+                    TupleValue::Text("Plastic Keyboard".into())
+                ],
+            }
+        );
+
+        let mut commit = vec![b'C', 0];
+        commit.extend(0x16B3748u64.to_be_bytes());
+        commit.extend(0x16B3778u64.to_be_bytes());
+        commit.extend(0u64.to_be_bytes());
+        assert_eq!(
+            PgOutputMessage::decode(&commit).unwrap(),
+            PgOutputMessage::Commit { end_lsn: 0x16B3778 }
+        );
+
+        assert!(matches!(
+            PgOutputMessage::decode(&insert[..insert.len() - 1]),
+            Err(FollowerError::Truncated)
+        ));
+        assert!(matches!(
+            PgOutputMessage::decode(b"X"),
+            Err(FollowerError::UnexpectedByte('X'))
+        ));
+    }
+
+    #[rstest]
+    fn test_lsn() {
+        assert_eq!(parse_lsn("16/B374D848"), Some(0x16_B374D848));
+        assert_eq!(format_lsn(0x16_B374D848), "16/B374D848");
+        assert_eq!(parse_lsn("0/0"), Some(0));
+        assert_eq!(parse_lsn("invalid"), None);
+    }
+
+    #[rstest]
+    fn test_tuple_json() {
+        let columns = vec!["id".to_string(), "description".into(), "metadata".into()];
+        let row = tuple_json(
+            &columns,
+            &[
+                TupleValue::Text("1".into()),
+                TupleValue::Null,
+                TupleValue::Unchanged,
+            ],
+        );
+        assert_eq!(
+            Value::Object(row),
+            serde_json::json!({"id": "1", "description": null})
+        );
+    }
+}
@@ -0,0 +1,258 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::env::register_commit_callback;
+use crate::globals::WriterGlobal;
+use crate::index::heal::{unindexed_documents, HealStatus};
+use crate::index::pending::{PendingDocument, PendingInserts};
+use crate::index::readonly::ReadOnly;
+use crate::index::recovery::Resync;
+use crate::index::SearchIndex;
+use crate::postgres::utils::row_to_search_document;
+use crate::schema::{SearchDocument, SearchIndexSchema};
+use crate::writer::WriterDirectory;
+use pgrx::*;
+
+/// How many heap blocks of a table are compared with its index at each step of a pass.
+const HEAL_BLOCKS_PER_STEP: u32 = 32;
+
+/// What a step of a pass over the table of an index found, see `heal_next_blocks`.
+#[derive(Default)]
+pub struct HealStep {
+    pub rows_checked: u64,
+    pub rows_repaired: u64,
+    /// Whether the step reached the end of the table, or the index was skipped.
+    pub pass_completed: bool,
+}
+
+struct HealState {
+    memctx: PgMemoryContexts,
+    schema: SearchIndexSchema,
+    documents: Vec<SearchDocument>,
+}
+
+/// Compare the next blocks of the table of one bm25 index of the current database with the
+/// index, and reindex the rows it's missing. Indexes are taken in turn, by increasing
+/// `position`.
+pub fn heal_next_index(position: usize) -> HealStep {
+    let index_oids = Spi::connect(|client| {
+        client
+            .select(
+                "SELECT c.oid FROM pg_class c \
+                 JOIN pg_am a ON a.oid = c.relam \
+                 WHERE a.amname = 'bm25' ORDER BY c.oid",
+                None,
+                None,
+            )?
+            .filter_map(|row| row.get::<pg_sys::Oid>(1).transpose())
+            .collect::<Result<Vec<_>, _>>()
+    })
+    .unwrap_or_else(|err| panic!("error listing bm25 indexes to heal: {err}"));
+    if index_oids.is_empty() {
+        return HealStep::default();
+    }
+
+    let index_oid = index_oids[position % index_oids.len()];
+    let index_relation =
+        unsafe { PgRelation::with_lock(index_oid, pg_sys::RowExclusiveLock as pg_sys::LOCKMODE) };
+    heal_next_blocks(&index_relation)
+}
+
+/// Compare the rows of the next `HEAL_BLOCKS_PER_STEP` blocks of the table with the index,
+/// see `unindexed_documents`. Rows whose write was lost, as when the writer process crashed
+/// with a commit in flight, are reindexed when the current transaction commits, without
+/// rebuilding the whole index.
+pub fn heal_next_blocks(index_relation: &PgRelation) -> HealStep {
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let skip = !directory.exists().unwrap_or(false)
+        || ReadOnly::is_set(&directory)
+            .unwrap_or_else(|err| panic!("error checking if index is read-only: {err}"))
+        // The index will be rebuilt from scratch on its next use.
+        || Resync::is_needed(&directory)
+            .unwrap_or_else(|err| panic!("error checking if index needs a resync: {err}"));
+    if skip {
+        return HealStep {
+            pass_completed: true,
+            ..Default::default()
+        };
+    }
+
+    let search_index = SearchIndex::from_disk(&directory)
+        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
+    search_index
+        .reader
+        .reload()
+        .unwrap_or_else(|err| panic!("error reloading index reader: {err}"));
+    let mut status = HealStatus::load(&directory)
+        .unwrap_or_else(|err| panic!("error loading heal status: {err}"));
+
+    let mut state = HealState {
+        memctx: PgMemoryContexts::new("pg_search_heal"),
+        schema: search_index.schema.clone(),
+        documents: vec![],
+    };
+    let heap_relation = unsafe {
+        let heap_oid = (*index_relation.rd_index).indrelid;
+        PgRelation::with_lock(heap_oid, pg_sys::AccessShareLock as pg_sys::LOCKMODE)
+    };
+    let num_blocks = unsafe {
+        pg_sys::RelationGetNumberOfBlocksInFork(
+            heap_relation.as_ptr(),
+            pg_sys::ForkNumber_MAIN_FORKNUM,
+        )
+    };
+    if num_blocks == 0 {
+        return HealStep {
+            pass_completed: true,
+            ..Default::default()
+        };
+    }
+    let start_block = if status.next_block < num_blocks {
+        status.next_block
+    } else {
+        0
+    };
+    let step_blocks = HEAL_BLOCKS_PER_STEP.min(num_blocks - start_block);
+
+    unsafe {
+        let index_info = pg_sys::BuildIndexInfo(index_relation.as_ptr());
+        // Like CREATE INDEX CONCURRENTLY, only index the rows visible to this transaction,
+        // rather than every row that isn't dead yet.
+        (*index_info).ii_Concurrent = true;
+        let table_am = (*heap_relation.as_ptr()).rd_tableam;
+        let index_build_range_scan = (*table_am)
+            .index_build_range_scan
+            .expect("table access method can't scan blocks for indexing");
+        index_build_range_scan(
+            heap_relation.as_ptr(),
+            index_relation.as_ptr(),
+            index_info,
+            false,
+            false,
+            false,
+            start_block,
+            step_blocks,
+            Some(heal_callback),
+            &mut state as *mut HealState as *mut std::os::raw::c_void,
+            std::ptr::null_mut(),
+        );
+    }
+
+    let rows_checked = state.documents.len() as u64;
+    let unindexed = unindexed_documents(search_index, state.documents)
+        .unwrap_or_else(|err| panic!("error comparing rows with index: {err}"));
+    let rows_repaired = unindexed.len() as u64;
+    if rows_repaired > 0 {
+        register_commit_callback(&WriterGlobal::client(), directory.clone())
+            .expect("could not register commit callbacks for heal operation");
+        PendingInserts::register_subxact_callback();
+        let subxact = unsafe { pg_sys::GetCurrentSubTransactionId() };
+        // Upserts also remove the stale versions of the rows.
+        PendingInserts::extend(
+            &directory,
+            unindexed.into_iter().map(|document| PendingDocument {
+                subxact,
+                document,
+                upsert: true,
+            }),
+        )
+        .unwrap_or_else(|err| panic!("error buffering rows to heal: {err}"));
+        PendingInserts::spill_over_memory_limit()
+            .unwrap_or_else(|err| panic!("error buffering rows to heal: {err}"));
+        pgrx::log!(
+            "reindexing {rows_repaired} rows missing from bm25 index {}",
+            index_relation.name()
+        );
+    }
+
+    status.next_block = start_block + step_blocks;
+    let pass_completed = status.next_block >= num_blocks;
+    if pass_completed {
+        status.next_block = 0;
+        status.passes_completed += 1;
+    }
+    status.rows_checked += rows_checked;
+    status.rows_repaired += rows_repaired;
+    status
+        .save(&directory)
+        .unwrap_or_else(|err| panic!("error saving heal status: {err}"));
+
+    HealStep {
+        rows_checked,
+        rows_repaired,
+        pass_completed,
+    }
+}
+
+#[cfg(feature = "pg12")]
+#[pg_guard]
+unsafe extern "C" fn heal_callback(
+    index: pg_sys::Relation,
+    htup: pg_sys::HeapTuple,
+    values: *mut pg_sys::Datum,
+    isnull: *mut bool,
+    _tuple_is_alive: bool,
+    state: *mut std::os::raw::c_void,
+) {
+    let htup = htup.as_ref().unwrap();
+
+    heal_callback_internal(htup.t_self, values, isnull, state, index);
+}
+
+#[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+#[pg_guard]
+unsafe extern "C" fn heal_callback(
+    index: pg_sys::Relation,
+    ctid: pg_sys::ItemPointer,
+    values: *mut pg_sys::Datum,
+    isnull: *mut bool,
+    _tuple_is_alive: bool,
+    state: *mut std::os::raw::c_void,
+) {
+    heal_callback_internal(*ctid, values, isnull, state, index);
+}
+
+#[inline(always)]
+unsafe fn heal_callback_internal(
+    ctid: pg_sys::ItemPointerData,
+    values: *mut pg_sys::Datum,
+    isnull: *mut bool,
+    state: *mut std::os::raw::c_void,
+    index: pg_sys::Relation,
+) {
+    check_for_interrupts!();
+    let state = (state as *mut HealState).as_mut().unwrap();
+
+    // See `build_callback`, the tuple descriptor is only freed with the memory context.
+    state.memctx.reset();
+    let row = state.memctx.switch_to(|_| {
+        let index_relation_ref: PgRelation = PgRelation::from_pg(index);
+        let tupdesc = index_relation_ref.tuple_desc();
+        row_to_search_document(ctid, &tupdesc, values, isnull, &state.schema).unwrap_or_else(
+            |err| {
+                panic!(
+                    "error creating index entries for index '{}': {err}",
+                    index_relation_ref.name()
+                )
+            },
+        )
+    });
+    state.memctx.reset();
+    // Rows left out of the index for a value it skips aren't missing from it. Their values
+    // were already counted as skipped when they were written.
+    state.documents.extend(row.document);
+}
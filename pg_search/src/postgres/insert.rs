@@ -15,12 +15,88 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::index::SearchIndex;
+use crate::index::pending::{PendingDocument, PendingInserts, PendingInsertsError};
+use crate::index::readonly::ReadOnly;
+use crate::index::{SearchIndex, SearchIndexError};
+use crate::postgres::alter::sync_options;
 use crate::postgres::options::SearchIndexCreateOptions;
-use crate::postgres::utils::row_to_search_document;
-use crate::writer::WriterDirectory;
+use crate::postgres::resync::resync_if_needed;
+use crate::postgres::utils::{row_to_search_document, IndexedRow};
+use crate::schema::SearchDocument;
+use crate::writer::{IndexError, SearchDirectoryError, WriterDirectory};
+use crate::SEARCH_GUCS;
 use crate::{env::register_commit_callback, globals::WriterGlobal};
+use pgrx::pg_sys::panic::ErrorReport;
 use pgrx::*;
+use shared::postgres::transaction::TransactionError;
+use thiserror::Error;
+
+/// State kept across the rows of a single INSERT or COPY command, in the `ii_AmCache` of the
+/// index info. Postgres calls `aminsert` once per row with the same index info, so the commit
+/// callbacks are only registered once, and documents are handed to the pending inserts
+/// buffer in batches. Logical replication workers, for both the initial table sync and
+/// applied changes, go through the same path, but with a new executor state per change.
+struct InsertState {
+    directory: WriterDirectory,
+    documents: Vec<PendingDocument>,
+    batch_size: usize,
+    /// Values of the command's rows that couldn't be indexed, see `SearchFieldErrorPolicy`.
+    skipped_values: u64,
+}
+
+impl InsertState {
+    fn new(directory: WriterDirectory) -> Self {
+        Self {
+            directory,
+            documents: vec![],
+            batch_size: SEARCH_GUCS.insert_batch_size.get() as usize,
+            skipped_values: 0,
+        }
+    }
+
+    fn push(&mut self, document: SearchDocument, upsert: bool) -> Result<(), InsertError> {
+        let subxact = unsafe { pg_sys::GetCurrentSubTransactionId() };
+        self.documents.push(PendingDocument {
+            subxact,
+            document,
+            upsert,
+        });
+        if self.documents.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), InsertError> {
+        PendingInserts::extend(&self.directory, self.documents.drain(..))?;
+        PendingInserts::spill_over_memory_limit()?;
+        Ok(())
+    }
+}
+
+impl Drop for InsertState {
+    fn drop(&mut self) {
+        // The state is dropped along with the executor's memory context, at the end of the
+        // command. If that's because the (sub)transaction is aborting, the remaining documents
+        // are thrown away, as they must never reach the index.
+        if !self.documents.is_empty() && unsafe { pg_sys::IsTransactionState() } {
+            if let Err(err) = PendingInserts::extend(&self.directory, self.documents.drain(..)) {
+                warning!("error buffering documents at the end of insert: {err:?}");
+            }
+        }
+
+        // Skipped values are counted once the command is done, even if its transaction
+        // aborts later on, as they're only reported for monitoring.
+        if self.skipped_values > 0 && unsafe { pg_sys::IsTransactionState() } {
+            let count = std::mem::take(&mut self.skipped_values);
+            if let Err(err) =
+                SearchIndex::skip_values(&self.directory, &WriterGlobal::client(), count)
+            {
+                warning!("error counting skipped values at the end of insert: {err:?}");
+            }
+        }
+    }
+}
 
 #[allow(clippy::too_many_arguments)]
 #[cfg(any(feature = "pg14", feature = "pg15", feature = "pg16"))]
@@ -30,10 +106,10 @@ pub unsafe extern "C" fn aminsert(
     values: *mut pg_sys::Datum,
     isnull: *mut bool,
     heap_tid: pg_sys::ItemPointer,
-    _heap_relation: pg_sys::Relation,
+    heap_relation: pg_sys::Relation,
     _check_unique: pg_sys::IndexUniqueCheck,
     _index_unchanged: bool,
-    _index_info: *mut pg_sys::IndexInfo,
+    index_info: *mut pg_sys::IndexInfo,
 ) -> bool {
     let pg_relation = unsafe { PgRelation::from_pg(index_relation) };
     let rdopts: PgBox<SearchIndexCreateOptions> = if !pg_relation.rd_options.is_null() {
@@ -43,11 +119,13 @@ pub unsafe extern "C" fn aminsert(
         ops.into_pg_boxed()
     };
 
-    let uuid = rdopts
+    rdopts
         .get_uuid()
-        .expect("uuid not specified in 'create_bm25' index build, please rebuild pg_search index");
-
-    aminsert_internal(index_relation, values, isnull, heap_tid, &uuid)
+        .ok_or(InsertError::MissingUuid)
+        .and_then(|uuid| {
+            aminsert_internal(index_relation, values, isnull, heap_tid, index_info, &uuid)
+        })
+        .unwrap_or_else(|err| err.report(index_relation, heap_relation))
 }
 
 #[cfg(any(feature = "pg12", feature = "pg13"))]
@@ -57,18 +135,19 @@ pub unsafe extern "C" fn aminsert(
     values: *mut pg_sys::Datum,
     isnull: *mut bool,
     heap_tid: pg_sys::ItemPointer,
-    _heap_relation: pg_sys::Relation,
+    heap_relation: pg_sys::Relation,
     _check_unique: pg_sys::IndexUniqueCheck,
-    _index_info: *mut pg_sys::IndexInfo,
+    index_info: *mut pg_sys::IndexInfo,
 ) -> bool {
     let rdopts = (*index_relation).rd_options as *mut SearchIndexCreateOptions;
 
-    let uuid = unsafe { rdopts.as_ref() }
-        .expect("index rd_options are unexpectedly null")
-        .get_uuid()
-        .expect("uuid not specified in 'create_bm25' index build, please rebuild pg_search index");
-
-    aminsert_internal(index_relation, values, isnull, heap_tid, &uuid)
+    unsafe { rdopts.as_ref() }
+        .and_then(|rdopts| rdopts.get_uuid())
+        .ok_or(InsertError::MissingUuid)
+        .and_then(|uuid| {
+            aminsert_internal(index_relation, values, isnull, heap_tid, index_info, &uuid)
+        })
+        .unwrap_or_else(|err| err.report(index_relation, heap_relation))
 }
 
 #[inline(always)]
@@ -77,27 +156,162 @@ unsafe fn aminsert_internal(
     values: *mut pg_sys::Datum,
     isnull: *mut bool,
     ctid: pg_sys::ItemPointer,
+    index_info: *mut pg_sys::IndexInfo,
     uuid: &str,
-) -> bool {
+) -> Result<bool, InsertError> {
     let index_relation_ref: PgRelation = PgRelation::from_pg(index_relation);
+    let index_info = index_info
+        .as_mut()
+        .expect("index info is unexpectedly null");
+    // A rebuilt index already has this row, which was inserted into the table before it.
+    if index_info.ii_AmCache.is_null() && resync_if_needed(&index_relation_ref) {
+        return Ok(false);
+    }
+
+    if index_info.ii_AmCache.is_null() {
+        sync_options(&index_relation_ref);
+    }
+
     let tupdesc = index_relation_ref.tuple_desc();
     let index_name = index_relation_ref.name();
-    let directory = WriterDirectory::from_index_name(index_name);
-    let search_index = SearchIndex::from_cache(&directory, uuid)
-        .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
-    let search_document =
-        row_to_search_document(*ctid, &tupdesc, values, isnull, &search_index.schema)
-            .unwrap_or_else(|err| {
-                panic!("error creating index entries for index '{index_name}': {err}",)
-            });
-
-    let writer_client = WriterGlobal::client();
-    register_commit_callback(&writer_client, search_index.directory.clone())
-        .expect("could not register commit callbacks for insert operation");
-
-    search_index
-        .insert(&writer_client, search_document)
-        .unwrap_or_else(|err| panic!("error inserting document during insert callback: {err:?}"));
-
-    true
+    let directory = WriterDirectory::from_index_relation(&index_relation_ref);
+    let search_index = SearchIndex::from_cache(&directory, uuid)?;
+    let IndexedRow {
+        document,
+        skipped_values,
+    } = row_to_search_document(*ctid, &tupdesc, values, isnull, &search_index.schema)?;
+
+    if index_info.ii_AmCache.is_null() {
+        // First row of this command. The writer would only reject the rows of a read-only
+        // index once they're sent at the end of the transaction.
+        if ReadOnly::is_set(&search_index.directory)? {
+            return Err(InsertError::ReadOnly(index_name.to_string()));
+        }
+
+        let writer_client = WriterGlobal::client();
+        register_commit_callback(&writer_client, search_index.directory.clone())?;
+        PendingInserts::register_subxact_callback();
+
+        // Rows committed by other transactions since this index was last read must be
+        // visible when looking for existing keys below.
+        search_index.reader.reload()?;
+
+        let state = InsertState::new(search_index.directory.clone());
+        index_info.ii_AmCache = PgMemoryContexts::For(index_info.ii_Context)
+            .leak_and_drop_on_delete(state)
+            .cast();
+    }
+
+    let state = (index_info.ii_AmCache as *mut InsertState)
+        .as_mut()
+        .expect("insert state is unexpectedly null");
+    state.skipped_values += skipped_values;
+    // A row with a value that couldn't be indexed is left out if its field skips them.
+    let Some(search_document) = document else {
+        return Ok(false);
+    };
+
+    // The document is buffered until the transaction commits, so that it can't be seen
+    // by other connections before then.
+    let upsert = search_index
+        .contains_key(&search_document)
+        .map_err(InsertError::KeyLookup)?;
+    state.push(search_document, upsert)?;
+
+    Ok(true)
+}
+
+/// Why a row couldn't be added to an index. Rather than panicking, these are raised as
+/// Postgres errors with an error code and the table, column and value of the row, see
+/// `InsertError::report`.
+#[derive(Debug, Error)]
+enum InsertError {
+    #[error("uuid not specified in 'create_bm25' index build, please rebuild pg_search index")]
+    MissingUuid,
+
+    #[error("error loading index from directory: {0}")]
+    LoadIndex(#[from] SearchIndexError),
+
+    #[error(transparent)]
+    Document(#[from] IndexError),
+
+    #[error(
+        "index '{0}' is read-only, writes can be allowed again with paradedb.set_index_readonly"
+    )]
+    ReadOnly(String),
+
+    #[error("error checking if index is read-only: {0}")]
+    Directory(#[from] SearchDirectoryError),
+
+    #[error("could not register commit callbacks for insert operation: {0}")]
+    Callback(#[from] TransactionError),
+
+    #[error("error reloading index reader: {0}")]
+    Reload(#[from] tantivy::TantivyError),
+
+    #[error("error looking up key in index: {0}")]
+    KeyLookup(#[source] SearchIndexError),
+
+    #[error("error buffering documents during insert: {0}")]
+    Buffer(#[from] PendingInsertsError),
+}
+
+impl InsertError {
+    fn sqlerrcode(&self) -> PgSqlErrorCode {
+        match self {
+            Self::Document(IndexError::KeyIdNull(_)) => PgSqlErrorCode::ERRCODE_NOT_NULL_VIOLATION,
+            Self::Document(IndexError::InvalidColumn { .. }) => {
+                PgSqlErrorCode::ERRCODE_DATA_EXCEPTION
+            }
+            Self::ReadOnly(_) => PgSqlErrorCode::ERRCODE_READ_ONLY_SQL_TRANSACTION,
+            Self::MissingUuid => PgSqlErrorCode::ERRCODE_OBJECT_NOT_IN_PREREQUISITE_STATE,
+            _ => PgSqlErrorCode::ERRCODE_INTERNAL_ERROR,
+        }
+    }
+
+    /// Raise the error, naming the index and table, and the value that couldn't be indexed.
+    /// Like the detail of Postgres's own constraint errors, the value is only shown to roles
+    /// that could read it from the table.
+    unsafe fn report(self, index_relation: pg_sys::Relation, heap_relation: pg_sys::Relation) -> ! {
+        let index_name = PgRelation::from_pg(index_relation).name().to_string();
+        let heap_relation = PgRelation::from_pg(heap_relation);
+        let table_name = heap_relation.name().to_string();
+        let detail = match &self {
+            Self::Document(IndexError::InvalidColumn { column, value, .. })
+                if can_read_column(heap_relation.oid(), column) =>
+            {
+                format!("Failing row of table \"{table_name}\" has {column} = '{value}'.")
+            }
+            Self::Document(IndexError::InvalidColumn { column, .. }) => {
+                format!("Failing row of table \"{table_name}\" has a bad value in {column}.")
+            }
+            _ => format!("Failing row is in table \"{table_name}\"."),
+        };
+        let message = match &self {
+            Self::Document(err) => {
+                format!("error creating index entries for index '{index_name}': {err}")
+            }
+            err => err.to_string(),
+        };
+        ErrorReport::new(self.sqlerrcode(), message, "")
+            .set_detail(detail)
+            .report(PgLogLevel::ERROR);
+        unreachable!("errors are raised by their report")
+    }
+}
+
+/// Whether the current role may read a column of a table, the check Postgres makes before
+/// showing the values of a row in an error: it needs SELECT on the column or its table, and
+/// the table mustn't have row level security in force for it.
+fn can_read_column(table_oid: pg_sys::Oid, column: &str) -> bool {
+    Spi::get_one_with_args::<bool>(
+        "SELECT has_column_privilege($1, $2, 'SELECT') AND NOT row_security_active($1)",
+        vec![
+            (PgBuiltInOids::OIDOID.oid(), table_oid.into_datum()),
+            (PgBuiltInOids::TEXTOID.oid(), column.into_datum()),
+        ],
+    )
+    .ok()
+    .flatten()
+    .unwrap_or(false)
 }
@@ -0,0 +1,170 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::pg_sys;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use tantivy::schema::OwnedValue;
+
+// Documents with generated keys, like ids used as object keys, would otherwise grow the
+// interned keys without bound.
+const MAX_INTERNED_KEYS: usize = 10_000;
+
+thread_local! {
+    static JSONB_READER: RefCell<JsonbReader> = RefCell::new(JsonbReader::default());
+}
+
+/// Converts jsonb datums into Tantivy values by walking their binary representation, instead
+/// of printing them as text and parsing that into a `serde_json::Value` first.
+///
+/// The reader lives for the whole backend, so that the work of indexing wide documents is
+/// shared across rows: object keys are decoded once per distinct key, and the stack used to
+/// walk nested values keeps its allocation.
+#[derive(Default)]
+pub struct JsonbReader {
+    keys: HashMap<Vec<u8>, String>,
+    stack: Vec<Container>,
+}
+
+/// A value being built while walking a jsonb datum.
+enum Container {
+    /// A top-level array, or an array nested directly in one. Tantivy can't index a
+    /// top-level array, so their elements are returned as separate values.
+    Flattened,
+    Array(Vec<OwnedValue>),
+    /// The entries of an object, and the key of the entry being read.
+    Object(Vec<(String, OwnedValue)>, Option<String>),
+}
+
+impl JsonbReader {
+    /// The Tantivy values of a jsonb datum, using this backend's reader.
+    pub unsafe fn read(datum: pg_sys::Datum) -> Vec<OwnedValue> {
+        JSONB_READER.with(|reader| reader.borrow_mut().read_datum(datum))
+    }
+
+    unsafe fn read_datum(&mut self, datum: pg_sys::Datum) -> Vec<OwnedValue> {
+        let jsonb = pg_sys::pg_detoast_datum(datum.cast_mut_ptr()) as *mut pg_sys::Jsonb;
+        let mut iterator = pg_sys::JsonbIteratorInit(&mut (*jsonb).root);
+        let mut value: pg_sys::JsonbValue = std::mem::zeroed();
+        let mut values = vec![];
+        self.stack.clear();
+
+        loop {
+            match pg_sys::JsonbIteratorNext(&mut iterator, &mut value, false) {
+                pg_sys::JsonbIteratorToken_WJB_DONE => break,
+                pg_sys::JsonbIteratorToken_WJB_BEGIN_ARRAY => {
+                    // Scalars are stored as an array of one element, which is flattened too.
+                    let container = match self.stack.last() {
+                        None | Some(Container::Flattened) => Container::Flattened,
+                        _ => Container::Array(vec![]),
+                    };
+                    self.stack.push(container);
+                }
+                pg_sys::JsonbIteratorToken_WJB_BEGIN_OBJECT => {
+                    self.stack.push(Container::Object(vec![], None));
+                }
+                pg_sys::JsonbIteratorToken_WJB_KEY => {
+                    let key = self.intern_key(string_bytes(&value));
+                    if let Some(Container::Object(_, entry_key)) = self.stack.last_mut() {
+                        *entry_key = Some(key);
+                    }
+                }
+                pg_sys::JsonbIteratorToken_WJB_VALUE | pg_sys::JsonbIteratorToken_WJB_ELEM => {
+                    let scalar = scalar_value(&value);
+                    self.push(scalar, &mut values);
+                }
+                pg_sys::JsonbIteratorToken_WJB_END_ARRAY
+                | pg_sys::JsonbIteratorToken_WJB_END_OBJECT => match self.stack.pop() {
+                    Some(Container::Array(elements)) => {
+                        self.push(OwnedValue::Array(elements), &mut values)
+                    }
+                    Some(Container::Object(entries, _)) => {
+                        self.push(OwnedValue::Object(entries), &mut values)
+                    }
+                    Some(Container::Flattened) | None => {}
+                },
+                _ => {}
+            }
+        }
+
+        values
+    }
+
+    /// Add a complete value to the value being built around it.
+    fn push(&mut self, value: OwnedValue, values: &mut Vec<OwnedValue>) {
+        match self.stack.last_mut() {
+            None | Some(Container::Flattened) => values.push(value),
+            Some(Container::Array(elements)) => elements.push(value),
+            Some(Container::Object(entries, key)) => {
+                entries.push((key.take().unwrap_or_default(), value))
+            }
+        }
+    }
+
+    fn intern_key(&mut self, bytes: &[u8]) -> String {
+        if let Some(key) = self.keys.get(bytes) {
+            return key.clone();
+        }
+
+        let key = String::from_utf8_lossy(bytes).into_owned();
+        if self.keys.len() < MAX_INTERNED_KEYS {
+            self.keys.insert(bytes.to_vec(), key.clone());
+        }
+        key
+    }
+}
+
+unsafe fn string_bytes(value: &pg_sys::JsonbValue) -> &[u8] {
+    let string = value.val.string;
+    std::slice::from_raw_parts(string.val as *const u8, string.len as usize)
+}
+
+unsafe fn scalar_value(value: &pg_sys::JsonbValue) -> OwnedValue {
+    match value.type_ {
+        pg_sys::jbvType_jbvBool => OwnedValue::Bool(value.val.boolean),
+        pg_sys::jbvType_jbvString => {
+            OwnedValue::Str(String::from_utf8_lossy(string_bytes(value)).into_owned())
+        }
+        pg_sys::jbvType_jbvNumeric => numeric_value(value.val.numeric),
+        _ => OwnedValue::Null,
+    }
+}
+
+/// Numbers get the same types as when parsing their text with `serde_json`, which the
+/// indexed values of existing indexes were read with.
+unsafe fn numeric_value(numeric: pg_sys::Numeric) -> OwnedValue {
+    let Some(text) = pgrx::direct_function_call::<&CStr>(
+        pg_sys::numeric_out,
+        &[Some(pg_sys::Datum::from(numeric))],
+    ) else {
+        return OwnedValue::Null;
+    };
+    let text = text.to_str().unwrap_or_default();
+
+    if !text.contains(['.', 'e', 'E']) {
+        if let Ok(number) = text.parse::<u64>() {
+            return OwnedValue::U64(number);
+        }
+        if let Ok(number) = text.parse::<i64>() {
+            return OwnedValue::I64(number);
+        }
+    }
+    text.parse::<f64>()
+        .map(OwnedValue::F64)
+        .unwrap_or(OwnedValue::Null)
+}
@@ -17,14 +17,25 @@
 
 use pgrx::*;
 
+pub mod alter;
+pub mod audit;
 mod build;
 mod cost;
 mod delete;
+pub mod extract;
+pub mod follower;
+pub mod heal;
 mod insert;
+mod jsonb;
 pub mod options;
+mod prefetch;
+pub mod resync;
+pub mod security;
 mod scan;
+pub mod source;
 mod vacuum;
 mod validate;
+pub mod wait;
 
 pub mod datetime;
 pub mod types;
@@ -39,7 +50,7 @@ fn bm25_handler(_fcinfo: pg_sys::FunctionCallInfo) -> PgBox<pg_sys::IndexAmRouti
     let mut amroutine =
         unsafe { PgBox::<pg_sys::IndexAmRoutine>::alloc_node(pg_sys::NodeTag::T_IndexAmRoutine) };
 
-    amroutine.amstrategies = 4;
+    amroutine.amstrategies = 6;
     amroutine.amsupport = 0;
     amroutine.amcanmulticol = true;
     amroutine.amsearcharray = true;
@@ -49,6 +60,7 @@ fn bm25_handler(_fcinfo: pg_sys::FunctionCallInfo) -> PgBox<pg_sys::IndexAmRouti
     amroutine.amvalidate = Some(validate::amvalidate);
     amroutine.ambuild = Some(build::ambuild);
     amroutine.ambuildempty = Some(build::ambuildempty);
+    amroutine.ambuildphasename = Some(build::ambuildphasename);
     amroutine.aminsert = Some(insert::aminsert);
     amroutine.ambulkdelete = Some(delete::ambulkdelete);
     amroutine.amvacuumcleanup = Some(vacuum::amvacuumcleanup);
@@ -22,6 +22,10 @@ use pgrx::*;
 use std::collections::HashMap;
 use std::ffi::CStr;
 
+use crate::index::merge::SearchMergePolicy;
+use crate::index::remote::RemoteStorage;
+use crate::index::storage::SearchDirectoryMode;
+use crate::postgres::security::SecurityFilter;
 use crate::schema::{SearchFieldConfig, SearchFieldName};
 
 /* ADDING OPTIONS
@@ -51,8 +55,20 @@ pub struct SearchIndexCreateOptions {
     boolean_fields_offset: i32,
     json_fields_offset: i32,
     datetime_fields_offset: i32,
+    vector_fields_offset: i32,
+    sparse_fields_offset: i32,
+    document_fields_offset: i32,
     key_field_offset: i32,
     uuid_offset: i32,
+    merge_policy_offset: i32,
+    directory_mode_offset: i32,
+    storage_offset: i32,
+    security_filter_offset: i32,
+    // Integer and boolean options are stored inline rather than at an offset.
+    refresh_interval: i32,
+    writer_memory_budget: i32,
+    max_index_size: i32,
+    keep_history: bool,
 }
 
 #[pg_guard]
@@ -115,6 +131,42 @@ extern "C" fn validate_datetime_fields(value: *const std::os::raw::c_char) {
     );
 }
 
+#[pg_guard]
+extern "C" fn validate_vector_fields(value: *const std::os::raw::c_char) {
+    let json_str = cstr_to_rust_str(value);
+    if json_str.is_empty() {
+        return;
+    }
+    SearchIndexCreateOptions::deserialize_config_fields(
+        json_str,
+        &SearchFieldConfig::vector_from_json,
+    );
+}
+
+#[pg_guard]
+extern "C" fn validate_sparse_fields(value: *const std::os::raw::c_char) {
+    let json_str = cstr_to_rust_str(value);
+    if json_str.is_empty() {
+        return;
+    }
+    SearchIndexCreateOptions::deserialize_config_fields(
+        json_str,
+        &SearchFieldConfig::sparse_from_json,
+    );
+}
+
+#[pg_guard]
+extern "C" fn validate_document_fields(value: *const std::os::raw::c_char) {
+    let json_str = cstr_to_rust_str(value);
+    if json_str.is_empty() {
+        return;
+    }
+    SearchIndexCreateOptions::deserialize_config_fields(
+        json_str,
+        &SearchFieldConfig::text_from_json,
+    );
+}
+
 #[pg_guard]
 extern "C" fn validate_key_field(value: *const std::os::raw::c_char) {
     cstr_to_rust_str(value);
@@ -125,6 +177,42 @@ extern "C" fn validate_uuid(value: *const std::os::raw::c_char) {
     cstr_to_rust_str(value);
 }
 
+#[pg_guard]
+extern "C" fn validate_merge_policy(value: *const std::os::raw::c_char) {
+    let json_str = cstr_to_rust_str(value);
+    if json_str.is_empty() {
+        return;
+    }
+    SearchIndexCreateOptions::deserialize_merge_policy(json_str);
+}
+
+#[pg_guard]
+extern "C" fn validate_directory_mode(value: *const std::os::raw::c_char) {
+    let mode = cstr_to_rust_str(value);
+    if mode.is_empty() {
+        return;
+    }
+    SearchIndexCreateOptions::parse_directory_mode(&mode);
+}
+
+#[pg_guard]
+extern "C" fn validate_storage(value: *const std::os::raw::c_char) {
+    let json_str = cstr_to_rust_str(value);
+    if json_str.is_empty() {
+        return;
+    }
+    SearchIndexCreateOptions::deserialize_storage(json_str);
+}
+
+#[pg_guard]
+extern "C" fn validate_security_filter(value: *const std::os::raw::c_char) {
+    let filter = cstr_to_rust_str(value);
+    if filter.is_empty() {
+        return;
+    }
+    SecurityFilter::parse(&filter).unwrap_or_else(|err| panic!("{err}"));
+}
+
 #[inline]
 fn cstr_to_rust_str(value: *const std::os::raw::c_char) -> String {
     if value.is_null() {
@@ -137,7 +225,10 @@ fn cstr_to_rust_str(value: *const std::os::raw::c_char) -> String {
         .to_string()
 }
 
-const NUM_REL_OPTS: usize = 7;
+const NUM_REL_OPTS: usize = 18;
+// Tantivy can't give an indexing thread more than 4GB of memory.
+const MAX_WRITER_MEMORY_BUDGET_MB: i32 = 4000;
+
 #[pg_guard]
 pub unsafe extern "C" fn amoptions(
     reloptions: pg_sys::Datum,
@@ -169,6 +260,21 @@ pub unsafe extern "C" fn amoptions(
             opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
             offset: offset_of!(SearchIndexCreateOptions, datetime_fields_offset) as i32,
         },
+        pg_sys::relopt_parse_elt {
+            optname: "vector_fields".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, vector_fields_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "sparse_fields".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, sparse_fields_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "document_fields".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, document_fields_offset) as i32,
+        },
         pg_sys::relopt_parse_elt {
             optname: "key_field".as_pg_cstr(),
             opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
@@ -179,6 +285,46 @@ pub unsafe extern "C" fn amoptions(
             opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
             offset: offset_of!(SearchIndexCreateOptions, uuid_offset) as i32,
         },
+        pg_sys::relopt_parse_elt {
+            optname: "merge_policy".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, merge_policy_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "directory_mode".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, directory_mode_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "storage".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, storage_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "security_filter".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_STRING,
+            offset: offset_of!(SearchIndexCreateOptions, security_filter_offset) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "refresh_interval".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(SearchIndexCreateOptions, refresh_interval) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "writer_memory_budget".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(SearchIndexCreateOptions, writer_memory_budget) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "max_index_size".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_INT,
+            offset: offset_of!(SearchIndexCreateOptions, max_index_size) as i32,
+        },
+        pg_sys::relopt_parse_elt {
+            optname: "keep_history".as_pg_cstr(),
+            opttype: pg_sys::relopt_type_RELOPT_TYPE_BOOL,
+            offset: offset_of!(SearchIndexCreateOptions, keep_history) as i32,
+        },
     ];
     build_relopts(reloptions, validate, options)
 }
@@ -305,6 +451,30 @@ impl SearchIndexCreateOptions {
         Self::deserialize_config_fields(config, &SearchFieldConfig::date_from_json)
     }
 
+    pub fn get_vector_fields(&self) -> Vec<(SearchFieldName, SearchFieldConfig)> {
+        let config = self.get_str(self.vector_fields_offset, "".to_string());
+        if config.is_empty() {
+            return Vec::new();
+        }
+        Self::deserialize_config_fields(config, &SearchFieldConfig::vector_from_json)
+    }
+
+    pub fn get_sparse_fields(&self) -> Vec<(SearchFieldName, SearchFieldConfig)> {
+        let config = self.get_str(self.sparse_fields_offset, "".to_string());
+        if config.is_empty() {
+            return Vec::new();
+        }
+        Self::deserialize_config_fields(config, &SearchFieldConfig::sparse_from_json)
+    }
+
+    pub fn get_document_fields(&self) -> Vec<(SearchFieldName, SearchFieldConfig)> {
+        let config = self.get_str(self.document_fields_offset, "".to_string());
+        if config.is_empty() {
+            return Vec::new();
+        }
+        Self::deserialize_config_fields(config, &SearchFieldConfig::text_from_json)
+    }
+
     pub fn get_key_field(&self) -> Option<SearchFieldName> {
         let key_field = self.get_str(self.key_field_offset, "".to_string());
         if key_field.is_empty() {
@@ -323,6 +493,84 @@ impl SearchIndexCreateOptions {
         }
     }
 
+    fn deserialize_merge_policy(serialized: String) -> SearchMergePolicy {
+        json5::from_str(&serialized)
+            .unwrap_or_else(|err| panic!("failed to deserialize merge_policy: {err:?}"))
+    }
+
+    pub fn get_merge_policy(&self) -> SearchMergePolicy {
+        let config = self.get_str(self.merge_policy_offset, "".to_string());
+        if config.is_empty() {
+            return SearchMergePolicy::default();
+        }
+        Self::deserialize_merge_policy(config)
+    }
+
+    /// Milliseconds between background commits of the index, or 0 if every transaction
+    /// commits its own changes.
+    pub fn get_refresh_interval(&self) -> u64 {
+        self.refresh_interval.max(0) as u64
+    }
+
+    /// Megabytes of documents the writer buffers for the index before committing them, or 0
+    /// if commits aren't triggered by memory use.
+    pub fn get_writer_memory_budget(&self) -> u64 {
+        self.writer_memory_budget.max(0) as u64
+    }
+
+    /// Megabytes of disk space the index may take up before inserts into it fail, or 0 if
+    /// its size isn't limited.
+    pub fn get_max_index_size(&self) -> u64 {
+        self.max_index_size.max(0) as u64
+    }
+
+    /// Whether vacuum moves the replaced and deleted versions of rows to a history index
+    /// instead of dropping them, see `IndexHistory`.
+    pub fn get_keep_history(&self) -> bool {
+        self.keep_history
+    }
+
+    fn parse_directory_mode(mode: &str) -> SearchDirectoryMode {
+        mode.parse().unwrap_or_else(|_| {
+            panic!("invalid directory_mode '{mode}', expected one of 'mmap', 'mmap_random', 'mmap_sequential' or 'buffered'")
+        })
+    }
+
+    pub fn get_directory_mode(&self) -> SearchDirectoryMode {
+        let mode = self.get_str(self.directory_mode_offset, "".to_string());
+        if mode.is_empty() {
+            return SearchDirectoryMode::default();
+        }
+        Self::parse_directory_mode(&mode)
+    }
+
+    fn deserialize_storage(json_str: String) -> RemoteStorage {
+        let storage: RemoteStorage = json5::from_str(&json_str)
+            .unwrap_or_else(|err| panic!("failed to deserialize storage config: {err:?}"));
+        if let Err(err) = url::Url::parse(&storage.url) {
+            panic!("invalid storage url '{}': {err}", storage.url)
+        }
+        storage
+    }
+
+    /// Object storage holding the segments of the index, or None if they're kept on local disk.
+    pub fn get_storage(&self) -> Option<RemoteStorage> {
+        let config = self.get_str(self.storage_offset, "".to_string());
+        if config.is_empty() {
+            return None;
+        }
+        Some(Self::deserialize_storage(config))
+    }
+
+    /// The filter that every search of the index is restricted by, see `SecurityFilter`.
+    pub fn get_security_filter(&self) -> Option<SecurityFilter> {
+        let filter = self.get_str(self.security_filter_offset, "".to_string());
+        if filter.is_empty() {
+            return None;
+        }
+        Some(SecurityFilter::parse(&filter).unwrap_or_else(|err| panic!("{err}")))
+    }
+
     fn get_str(&self, offset: i32, default: String) -> String {
         if offset == 0 {
             default
@@ -395,6 +643,40 @@ pub unsafe fn init() {
             pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
         },
     );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "vector_fields".as_pg_cstr(),
+        "JSON string specifying how vector fields should be indexed".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_vector_fields),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "sparse_fields".as_pg_cstr(),
+        "JSON string specifying how sparse vector fields should be indexed".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_sparse_fields),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "document_fields".as_pg_cstr(),
+        "JSON string specifying how the text extracted from document fields should be indexed"
+            .as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_document_fields),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
     pg_sys::add_string_reloption(
         RELOPT_KIND_PDB,
         "key_field".as_pg_cstr(),
@@ -417,4 +699,97 @@ pub unsafe fn init() {
             pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
         },
     );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "merge_policy".as_pg_cstr(),
+        "JSON string specifying how index segments should be merged".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_merge_policy),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "directory_mode".as_pg_cstr(),
+        "How index files are read: mmap, mmap_random, mmap_sequential or buffered".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_directory_mode),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "storage".as_pg_cstr(),
+        "JSON string specifying the object storage holding the index files".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_storage),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_string_reloption(
+        RELOPT_KIND_PDB,
+        "security_filter".as_pg_cstr(),
+        "Filter expression that restricts every search of the index".as_pg_cstr(),
+        std::ptr::null(),
+        Some(validate_security_filter),
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_PDB,
+        "refresh_interval".as_pg_cstr(),
+        "Milliseconds between background commits, or 0 to commit with each transaction"
+            .as_pg_cstr(),
+        0,
+        0,
+        i32::MAX,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_PDB,
+        "writer_memory_budget".as_pg_cstr(),
+        "Megabytes of documents buffered by the writer before a background commit, or 0 for no limit"
+            .as_pg_cstr(),
+        0,
+        0,
+        MAX_WRITER_MEMORY_BUDGET_MB,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_int_reloption(
+        RELOPT_KIND_PDB,
+        "max_index_size".as_pg_cstr(),
+        "Megabytes of disk space the index may take up before inserts fail, or 0 for no limit"
+            .as_pg_cstr(),
+        0,
+        0,
+        i32::MAX,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
+    pg_sys::add_bool_reloption(
+        RELOPT_KIND_PDB,
+        "keep_history".as_pg_cstr(),
+        "Keep replaced and deleted versions of rows for searches as of a past time".as_pg_cstr(),
+        false,
+        #[cfg(any(feature = "pg13", feature = "pg14", feature = "pg15", feature = "pg16"))]
+        {
+            pg_sys::AccessExclusiveLock as pg_sys::LOCKMODE
+        },
+    );
 }
@@ -0,0 +1,107 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::pg_sys;
+
+// The prefetch window starts small, so that scans stopped early by a LIMIT don't read
+// pages they will never fetch, and doubles up to `paradedb.heap_prefetch_distance`.
+const INITIAL_PREFETCH_DISTANCE: usize = 8;
+
+/// Asks the OS to read the heap pages of upcoming index scan results ahead of the executor.
+///
+/// The results are returned in score order, so their heap pages are spread at random across
+/// the table. Each window of upcoming results is prefetched in block order instead, which
+/// turns the reads into mostly sequential I/O.
+pub struct HeapPrefetcher {
+    /// The heap block of each result, in the order results are returned.
+    blocks: Vec<pg_sys::BlockNumber>,
+    /// How many results have been prefetched.
+    prefetched: usize,
+    distance: usize,
+    max_distance: usize,
+}
+
+impl HeapPrefetcher {
+    pub fn new(ctids: impl Iterator<Item = u64>, max_distance: usize) -> Self {
+        Self {
+            // pgrx packs the block number in the upper 32 bits of a ctid, see
+            // `pgrx::item_pointer_to_u64`.
+            blocks: ctids
+                .map(|ctid| (ctid >> 32) as pg_sys::BlockNumber)
+                .collect(),
+            prefetched: 0,
+            distance: INITIAL_PREFETCH_DISTANCE.min(max_distance),
+            max_distance,
+        }
+    }
+
+    /// The blocks to prefetch before returning the result at `position`, sorted and without
+    /// duplicates. A new window is only started once the previous one is half consumed.
+    pub fn next_window(&mut self, position: usize) -> Vec<pg_sys::BlockNumber> {
+        if self.max_distance == 0 || position + self.distance / 2 < self.prefetched {
+            return vec![];
+        }
+
+        let start = self.prefetched.max(position);
+        let end = (position + self.distance).min(self.blocks.len());
+        self.prefetched = end;
+        self.distance = (self.distance * 2).min(self.max_distance);
+
+        let mut window = self.blocks[start.min(end)..end].to_vec();
+        window.sort_unstable();
+        window.dedup();
+        window
+    }
+
+    /// Prefetch the heap pages needed by the upcoming results of `heap_relation`.
+    pub fn prefetch(&mut self, heap_relation: pg_sys::Relation, position: usize) {
+        if heap_relation.is_null() {
+            return;
+        }
+        for block in self.next_window(position) {
+            unsafe {
+                pg_sys::PrefetchBuffer(heap_relation, pg_sys::ForkNumber_MAIN_FORKNUM, block)
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeapPrefetcher;
+
+    #[test]
+    fn test_prefetch_windows() {
+        // Results on blocks 9, 3, 3, 7, ... in score order.
+        let ctids = [9u64, 3, 3, 7, 1, 8, 2, 5, 4, 6].map(|block| block << 32 | 1);
+        let mut prefetcher = HeapPrefetcher::new(ctids.into_iter(), 4);
+
+        // The first window covers 4 results, in block order and without duplicates.
+        assert_eq!(prefetcher.next_window(0), vec![3, 7, 9]);
+        // Nothing to do until half of the window is consumed.
+        assert_eq!(prefetcher.next_window(1), vec![]);
+        assert_eq!(prefetcher.next_window(2), vec![1, 8]);
+        assert_eq!(prefetcher.next_window(3), vec![]);
+        assert_eq!(prefetcher.next_window(4), vec![2, 5]);
+        assert_eq!(prefetcher.next_window(6), vec![4, 6]);
+        assert_eq!(prefetcher.next_window(8), vec![]);
+
+        // A distance of 0 disables prefetching.
+        let mut disabled = HeapPrefetcher::new(ctids.into_iter(), 0);
+        assert_eq!(disabled.next_window(0), vec![]);
+    }
+}
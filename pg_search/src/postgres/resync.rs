@@ -0,0 +1,139 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::build::ambuild;
+use crate::globals::WriterGlobal;
+use crate::index::recovery::Resync;
+use crate::index::shipping::ShippedStatus;
+use crate::index::SearchIndex;
+use crate::writer::WriterDirectory;
+use crate::SEARCH_GUCS;
+use once_cell::sync::Lazy;
+use pgrx::*;
+use shared::postgres::transaction::Transaction;
+use std::collections::HashSet;
+use std::sync::{Mutex, PoisonError};
+use std::time::Duration;
+
+/// The indexes rebuilt by the current transaction. They stay marked until it commits, but
+/// mustn't be rebuilt again in the meantime.
+static RESYNCED: Lazy<Mutex<HashSet<WriterDirectory>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+
+/// On a standby, fail searches of an index that wasn't shipped to it, or that's staler than
+/// `paradedb.replica_max_staleness`, see `SegmentShipping`.
+pub fn check_shipped(directory: &WriterDirectory) {
+    if !unsafe { pg_sys::RecoveryInProgress() } {
+        return;
+    }
+    let max_staleness = Some(SEARCH_GUCS.replica_max_staleness.get())
+        .filter(|seconds| *seconds > 0)
+        .map(|seconds| Duration::from_secs(seconds as u64));
+    ShippedStatus::check(directory, max_staleness).unwrap_or_else(|err| panic!("{err}"));
+}
+
+/// Rebuild an index from its table if it may have drifted from it, see `Resync`. Returns
+/// whether the index was rebuilt, in which case it already has every row visible to this
+/// transaction, including the ones it inserted.
+pub fn resync_if_needed(index_relation: &PgRelation) -> bool {
+    // Standbys don't maintain indexes, they're rebuilt once promoted.
+    if unsafe { pg_sys::RecoveryInProgress() } {
+        return false;
+    }
+
+    let directory = WriterDirectory::from_index_relation(&index_relation);
+    let is_needed = || {
+        !resynced(&directory)
+            && Resync::is_needed(&directory)
+                .unwrap_or_else(|err| panic!("error checking if index needs a resync: {err}"))
+    };
+    if !is_needed() {
+        return false;
+    }
+
+    // The lock conflicts with itself, so only one connection rebuilds the index, and the
+    // others find it up to date once it commits.
+    unsafe {
+        pg_sys::LockRelationOid(
+            index_relation.oid(),
+            pg_sys::ShareUpdateExclusiveLock as pg_sys::LOCKMODE,
+        )
+    };
+    if !is_needed() {
+        return false;
+    }
+
+    resync(index_relation);
+    true
+}
+
+/// Rebuild an index from scratch, like REINDEX, but without replacing the relation. Returns
+/// the number of rows indexed.
+pub fn resync(index_relation: &PgRelation) -> usize {
+    let index_name = index_relation.name().to_string();
+    let heap_relation = unsafe {
+        // Like CREATE INDEX, keep writes to the table out until the rebuild commits.
+        let heap_oid = (*index_relation.rd_index).indrelid;
+        pg_sys::LockRelationOid(heap_oid, pg_sys::ShareLock as pg_sys::LOCKMODE);
+        PgRelation::open(heap_oid)
+    };
+    pgrx::log!("rebuilding bm25 index {index_name} from its table");
+
+    // The files of the index can't be trusted, so they're removed before it's built again in
+    // the same directory. If the rebuild aborts, the index is missing and will be rebuilt again.
+    SearchIndex::drop_index(&WriterGlobal::client(), &index_name)
+        .unwrap_or_else(|err| panic!("error removing index before resync: {err}"));
+    let rows = unsafe {
+        let index_info = pg_sys::BuildIndexInfo(index_relation.as_ptr());
+        let result = ambuild(heap_relation.as_ptr(), index_relation.as_ptr(), index_info);
+        (*result).heap_tuples as usize
+    };
+
+    let directory = WriterDirectory::from_index_relation(index_relation);
+    RESYNCED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(directory.clone());
+
+    let commit_directory = directory.clone();
+    Transaction::call_once_on_commit(format!("resync {index_name}"), move || {
+        forget_resynced(&commit_directory);
+        if let Err(err) = Resync::clear(&commit_directory) {
+            warning!("could not clear the resync mark of {commit_directory:?}: {err}");
+        }
+    })
+    .expect("could not register commit callback for resync");
+    Transaction::call_once_on_abort(format!("resync {index_name}"), move || {
+        forget_resynced(&directory);
+    })
+    .expect("could not register abort callback for resync");
+
+    rows
+}
+
+fn resynced(directory: &WriterDirectory) -> bool {
+    RESYNCED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains(directory)
+}
+
+fn forget_resynced(directory: &WriterDirectory) {
+    RESYNCED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .remove(directory);
+}
@@ -15,21 +15,40 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
+use super::alter::sync_options;
+use super::prefetch::HeapPrefetcher;
+use super::resync::{check_shipped, resync_if_needed};
+use super::wait::SearchWaitEvent;
 use crate::globals::WriterGlobal;
 use crate::index::state::SearchStateManager;
 use crate::index::SearchIndex;
 use crate::postgres::types::TantivyValue;
+use crate::query::SearchQueryInput;
 use crate::schema::SearchConfig;
-use crate::{env::needs_commit, writer::WriterDirectory};
+use crate::{env::needs_commit, writer::WriterDirectory, SEARCH_GUCS};
 use pgrx::*;
+use std::ops::Bound;
 use tantivy::{DocAddress, Score};
 
+/// The state of an index scan, kept in the scan's `opaque` pointer.
+struct ScanState {
+    /// The results of the search, in score order.
+    results: std::vec::IntoIter<(Score, DocAddress, TantivyValue, u64)>,
+    /// How many results were returned.
+    position: usize,
+    prefetcher: HeapPrefetcher,
+}
+
 #[pg_guard]
 pub extern "C" fn ambeginscan(
     indexrel: pg_sys::Relation,
     nkeys: ::std::os::raw::c_int,
     norderbys: ::std::os::raw::c_int,
 ) -> pg_sys::IndexScanDesc {
+    let index_relation = unsafe { PgRelation::from_pg(indexrel) };
+    resync_if_needed(&index_relation);
+    sync_options(&index_relation);
+
     let scandesc: PgBox<pg_sys::IndexScanDescData> =
         unsafe { PgBox::from_pg(pg_sys::RelationGetIndexScan(indexrel, nkeys, norderbys)) };
 
@@ -58,18 +77,46 @@ pub extern "C" fn amrescan(
     let nkeys = nkeys as usize;
     let keys = unsafe { std::slice::from_raw_parts(keys as *const pg_sys::ScanKeyData, nkeys) };
 
-    // Convert the first scan key argument into a byte array. This is assumed to be the `::jsonb` search config.
+    // The `@@@` scan key carries the `::jsonb` search config, the others are comparisons of
+    // indexed columns with constants, which are searched for along with the query.
+    let (config_keys, filter_keys): (Vec<_>, Vec<_>) = keys
+        .iter()
+        .partition(|key| key.sk_strategy == SEARCH_STRATEGY);
+    let config_key = config_keys
+        .first()
+        .unwrap_or_else(|| panic!("no @@@ condition provided to the bm25 index scan"));
+
+    // Convert the scan key argument into a byte array. This is assumed to be the `::jsonb` search config.
     let config_jsonb = unsafe {
-        JsonB::from_datum(keys[0].sk_argument, false)
+        JsonB::from_datum(config_key.sk_argument, false)
             .expect("failed to convert query to tuple of strings")
     };
 
-    let search_config =
+    let mut search_config =
         SearchConfig::from_jsonb(config_jsonb).expect("could not parse search config");
+    if !filter_keys.is_empty() {
+        let index_relation = unsafe { PgRelation::from_pg(scan.indexRelation) };
+        let filters = filter_keys
+            .into_iter()
+            .map(|key| unsafe { scan_key_filter(&index_relation, key) });
+        search_config.query = SearchQueryInput::Boolean {
+            must: std::iter::once(search_config.query)
+                .chain(filters)
+                .collect(),
+            should: vec![],
+            must_not: vec![],
+        };
+    }
     let index_name = &search_config.index_name;
 
+    // The rows of a past state may not be in the table anymore, so they're read from the index.
+    if search_config.as_of.is_some() {
+        panic!("as_of searches can only return the stored fields of the index, use search_tab to run them");
+    }
+
     // Create the index and scan state
     let directory = WriterDirectory::from_index_name(index_name);
+    check_shipped(&directory);
     let search_index = SearchIndex::from_cache(&directory, &search_config.uuid)
         .unwrap_or_else(|err| panic!("error loading index from directory: {err}"));
     let writer_client = WriterGlobal::client();
@@ -77,18 +124,119 @@ pub extern "C" fn amrescan(
         .search_state(&writer_client, &search_config, needs_commit(index_name))
         .unwrap();
 
-    let top_docs = state.search(SearchIndex::executor());
+    let top_docs = {
+        let _wait = SearchWaitEvent::QueryExecute.start();
+        state.search(&SearchIndex::executor())
+    };
 
     SearchStateManager::set_state(state.clone()).expect("could not store search state in manager");
 
-    // Save the iterator onto the current memory context.
-    scan.opaque = PgMemoryContexts::CurrentMemoryContext
-        .leak_and_drop_on_delete(top_docs.into_iter()) as void_mut_ptr;
+    let prefetcher = HeapPrefetcher::new(
+        top_docs.iter().map(|(_, _, _, ctid)| *ctid),
+        SEARCH_GUCS.heap_prefetch_distance.get() as usize,
+    );
+    let scan_state = ScanState {
+        results: top_docs.into_iter(),
+        position: 0,
+        prefetcher,
+    };
+
+    // Save the scan state onto the current memory context.
+    scan.opaque =
+        PgMemoryContexts::CurrentMemoryContext.leak_and_drop_on_delete(scan_state) as void_mut_ptr;
 
     // Return scan state back management to Postgres.
     scan.into_pg();
 }
 
+/// The strategy number of the `@@@` operator in the `bm25` operator class.
+pub const SEARCH_STRATEGY: pg_sys::StrategyNumber = 1;
+
+/// Converts a scan key comparing an indexed column with a constant, like `rating >= 4`, into
+/// a range filter on the column's field. The comparison operators are numbered like the ones
+/// of a btree operator class, after the `@@@` operator.
+unsafe fn scan_key_filter(
+    index_relation: &PgRelation,
+    key: &pg_sys::ScanKeyData,
+) -> SearchQueryInput {
+    let tupdesc = index_relation.tuple_desc();
+    let attribute = tupdesc
+        .get((key.sk_attno - 1) as usize)
+        .unwrap_or_else(|| panic!("no index column for scan key attribute {}", key.sk_attno));
+    let field = attribute.name().to_string();
+    // The subtype is the type of the constant, which can differ from the column's type for
+    // operators like `int8 >= int4`.
+    let value_oid = if key.sk_subtype != pg_sys::InvalidOid {
+        PgOid::from(key.sk_subtype)
+    } else {
+        attribute.type_oid()
+    };
+
+    let to_range = |datum: pg_sys::Datum| {
+        let value = TantivyValue::try_from_datum(datum, value_oid)
+            .unwrap_or_else(|err| panic!("could not filter {field} in the index scan: {err}"))
+            .tantivy_schema_value();
+        let (lower_bound, upper_bound) = match key.sk_strategy {
+            2 => (Bound::Unbounded, Bound::Excluded(value)),
+            3 => (Bound::Unbounded, Bound::Included(value)),
+            4 => (Bound::Included(value.clone()), Bound::Included(value)),
+            5 => (Bound::Included(value), Bound::Unbounded),
+            6 => (Bound::Excluded(value), Bound::Unbounded),
+            strategy => panic!("unknown bm25 scan key strategy {strategy}"),
+        };
+        SearchQueryInput::ConstScore {
+            query: Box::new(SearchQueryInput::Range {
+                field: field.clone(),
+                lower_bound,
+                upper_bound,
+            }),
+            score: 0.0,
+        }
+    };
+
+    if key.sk_flags & pg_sys::SK_ISNULL as i32 != 0 {
+        // A comparison with NULL is never true.
+        SearchQueryInput::Empty
+    } else if key.sk_flags & pg_sys::SK_SEARCHARRAY as i32 != 0 {
+        // `column op ANY(array)` matches the rows that match any of the array's elements.
+        let array =
+            pg_sys::pg_detoast_datum(key.sk_argument.cast_mut_ptr()) as *mut pg_sys::ArrayType;
+        let mut typlen = 0;
+        let mut typbyval = false;
+        let mut typalign = 0;
+        pg_sys::get_typlenbyvalalign(value_oid.value(), &mut typlen, &mut typbyval, &mut typalign);
+
+        let mut elements = std::ptr::null_mut();
+        let mut nulls = std::ptr::null_mut();
+        let mut nelements = 0;
+        pg_sys::deconstruct_array(
+            array,
+            value_oid.value(),
+            typlen as i32,
+            typbyval,
+            typalign,
+            &mut elements,
+            &mut nulls,
+            &mut nelements,
+        );
+        let elements = std::slice::from_raw_parts(elements, nelements as usize);
+        let nulls = std::slice::from_raw_parts(nulls, nelements as usize);
+
+        SearchQueryInput::Boolean {
+            must: vec![],
+            should: elements
+                .iter()
+                .zip(nulls)
+                .filter(|(_, is_null)| !**is_null)
+                .map(|(datum, _)| to_range(*datum))
+                .collect(),
+            must_not: vec![],
+        }
+    } else {
+        to_range(key.sk_argument)
+    }
+}
+
 #[pg_guard]
 pub extern "C" fn amendscan(_scan: pg_sys::IndexScanDesc) {}
 
@@ -98,15 +246,16 @@ pub extern "C" fn amgettuple(
     _direction: pg_sys::ScanDirection,
 ) -> bool {
     let mut scan: PgBox<pg_sys::IndexScanDescData> = unsafe { PgBox::from_pg(scan) };
-    let iter = unsafe {
-        (scan.opaque as *mut std::vec::IntoIter<(Score, DocAddress, TantivyValue, u64)>).as_mut()
-    }
-    .expect("no scandesc state");
+    let state = unsafe { (scan.opaque as *mut ScanState).as_mut() }.expect("no scandesc state");
 
     scan.xs_recheck = false;
 
-    match iter.next() {
+    // Results are still returned in score order, only their heap pages are read ahead.
+    state.prefetcher.prefetch(scan.heapRelation, state.position);
+
+    match state.results.next() {
         Some((_, _, _, ctid)) => {
+            state.position += 1;
             #[cfg(any(
                 feature = "pg12",
                 feature = "pg13",
@@ -0,0 +1,365 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+use std::ffi::CStr;
+use thiserror::Error;
+
+use crate::query::SearchQueryInput;
+use crate::schema::config::field_value;
+use crate::schema::{SearchFieldType, SearchIndexSchema};
+
+/// A filter that an index applies to every search of it, set with the `security_filter`
+/// option as `field = value` or `field IN (value, ...)`. The values are evaluated when a
+/// search starts, so they can read settings of the session, like
+/// `current_setting('app.tenant')`. It's meant as a layer of defense along with row level
+/// security, so a search whose values are all null matches nothing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SecurityFilter {
+    pub field: String,
+    values: Vec<SecurityValue>,
+}
+
+/// A value of a security filter. The option is set by the owner of the table but evaluated
+/// by every role that searches the index, so it's limited to these forms rather than any SQL
+/// expression, which would run with the privileges of whoever searches.
+#[derive(Clone, Debug, PartialEq)]
+enum SecurityValue {
+    /// A quoted string or a number.
+    Literal(String),
+    CurrentUser,
+    SessionUser,
+    /// `current_setting(name)`, or `current_setting(name, missing_ok)`.
+    Setting {
+        name: String,
+        missing_ok: bool,
+    },
+}
+
+impl SecurityFilter {
+    pub fn parse(filter: &str) -> Result<Self, SecurityFilterError> {
+        let invalid = || SecurityFilterError::InvalidFilter(filter.trim().to_string());
+        let mut parser = Parser(filter.trim());
+
+        let field = parser.identifier().ok_or_else(invalid)?;
+        let values = if parser.symbol('=') {
+            vec![parser.value().ok_or_else(invalid)?]
+        } else if parser
+            .identifier()
+            .is_some_and(|op| op.eq_ignore_ascii_case("in"))
+            && parser.symbol('(')
+        {
+            let mut values = vec![parser.value().ok_or_else(invalid)?];
+            while parser.symbol(',') {
+                values.push(parser.value().ok_or_else(invalid)?);
+            }
+            if !parser.symbol(')') {
+                return Err(invalid());
+            }
+            values
+        } else {
+            return Err(invalid());
+        };
+        if !parser.0.trim().is_empty() {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            field: field.to_string(),
+            values,
+        })
+    }
+
+    /// The security filter of a bm25 index, if it has one.
+    pub fn for_index(index_name: &str) -> Option<Self> {
+        let filter = Spi::get_one::<String>(&format!(
+            "SELECT (SELECT o.option_value FROM pg_class c \
+             JOIN pg_am a ON a.oid = c.relam, \
+             pg_options_to_table(c.reloptions) o \
+             WHERE a.amname = 'bm25' AND c.relname = {} AND o.option_name = 'security_filter' \
+             LIMIT 1)",
+            spi::quote_literal(index_name)
+        ))
+        .unwrap_or_else(|err| {
+            panic!("error reading security_filter of index {index_name}: {err}")
+        })?;
+        if filter.trim().is_empty() {
+            return None;
+        }
+        Some(Self::parse(&filter).unwrap_or_else(|err| panic!("index {index_name} has an {err}")))
+    }
+
+    /// Check that the field of the filter is a text or integer field of the index, whose
+    /// terms the values are compared with.
+    pub fn validate(&self, schema: &SearchIndexSchema) -> Result<(), SecurityFilterError> {
+        match schema.get_search_field(&self.field) {
+            Some(search_field) => match search_field.type_ {
+                SearchFieldType::Text | SearchFieldType::I64 | SearchFieldType::U64 => Ok(()),
+                _ => Err(SecurityFilterError::InvalidFieldType(self.field.clone())),
+            },
+            None => Err(SecurityFilterError::MissingField(self.field.clone())),
+        }
+    }
+
+    /// The values a search may match, which are evaluated for the role running the search.
+    pub fn values(&self) -> Vec<String> {
+        self.values.iter().filter_map(SecurityValue::eval).collect()
+    }
+
+    /// A query matching the documents whose field has one of the values of the filter. Like
+    /// a tenant, it doesn't score.
+    pub fn query(&self, schema: &SearchIndexSchema) -> SearchQueryInput {
+        self.validate(schema).unwrap_or_else(|err| panic!("{err}"));
+        let terms = self
+            .values()
+            .iter()
+            .map(|value| {
+                let value = field_value(schema, &self.field, value)
+                    .unwrap_or_else(|err| panic!("security_filter: {err}"));
+                (self.field.clone(), value)
+            })
+            .collect();
+
+        SearchQueryInput::ConstScore {
+            query: Box::new(SearchQueryInput::TermSet { terms }),
+            score: 0.0,
+        }
+    }
+
+    /// `query`, restricted to the documents whose field has one of the values of the filter.
+    pub fn restrict(
+        &self,
+        schema: &SearchIndexSchema,
+        query: SearchQueryInput,
+    ) -> SearchQueryInput {
+        SearchQueryInput::Boolean {
+            must: vec![query, self.query(schema)],
+            should: vec![],
+            must_not: vec![],
+        }
+    }
+}
+
+impl SecurityValue {
+    /// The value as text, or None if it's null, like a setting that isn't set.
+    fn eval(&self) -> Option<String> {
+        match self {
+            Self::Literal(value) => Some(value.clone()),
+            Self::CurrentUser => Some(role_name(unsafe { pg_sys::GetUserId() })),
+            Self::SessionUser => Some(role_name(unsafe { pg_sys::GetSessionUserId() })),
+            // The name is a parameter, so Postgres checks whether the role may read it.
+            Self::Setting { name, missing_ok } => Spi::get_one_with_args::<String>(
+                "SELECT current_setting($1, $2)",
+                vec![
+                    (PgBuiltInOids::TEXTOID.oid(), name.as_str().into_datum()),
+                    (PgBuiltInOids::BOOLOID.oid(), (*missing_ok).into_datum()),
+                ],
+            )
+            .unwrap_or_else(|err| panic!("error evaluating security_filter: {err}")),
+        }
+    }
+}
+
+fn role_name(role_oid: pg_sys::Oid) -> String {
+    unsafe { CStr::from_ptr(pg_sys::GetUserNameFromId(role_oid, false)) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Reads a security filter from the start of the text that's left.
+struct Parser<'a>(&'a str);
+
+impl<'a> Parser<'a> {
+    fn identifier(&mut self) -> Option<&'a str> {
+        self.0 = self.0.trim_start();
+        let len = self
+            .0
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.0.len());
+        let (identifier, rest) = self.0.split_at(len);
+        if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        self.0 = rest;
+        Some(identifier)
+    }
+
+    /// Consume `symbol` if it's next.
+    fn symbol(&mut self, symbol: char) -> bool {
+        match self.0.trim_start().strip_prefix(symbol) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A quoted string, with quotes doubled inside it.
+    fn string(&mut self) -> Option<String> {
+        let mut rest = self.0.trim_start().strip_prefix('\'')?;
+        let mut value = String::new();
+        loop {
+            let end = rest.find('\'')?;
+            value.push_str(&rest[..end]);
+            rest = &rest[end + 1..];
+            match rest.strip_prefix('\'') {
+                Some(after) => {
+                    value.push('\'');
+                    rest = after;
+                }
+                None => break,
+            }
+        }
+        self.0 = rest;
+        Some(value)
+    }
+
+    fn number(&mut self) -> Option<String> {
+        let rest = self.0.trim_start();
+        let len = rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_digit() || c == '.' || (i == 0 && c == '-')))
+            .map_or(rest.len(), |(i, _)| i);
+        let (number, rest) = rest.split_at(len);
+        if !number.contains(|c: char| c.is_ascii_digit()) {
+            return None;
+        }
+        self.0 = rest;
+        Some(number.to_string())
+    }
+
+    fn value(&mut self) -> Option<SecurityValue> {
+        if let Some(value) = self.string().or_else(|| self.number()) {
+            return Some(SecurityValue::Literal(value));
+        }
+        let function = self.identifier()?.to_ascii_lowercase();
+        match function.as_str() {
+            "current_user" => Some(SecurityValue::CurrentUser),
+            "session_user" => Some(SecurityValue::SessionUser),
+            "current_setting" => {
+                if !self.symbol('(') {
+                    return None;
+                }
+                let name = self.string()?;
+                let missing_ok = if self.symbol(',') {
+                    match self.identifier()?.to_ascii_lowercase().as_str() {
+                        "true" => true,
+                        "false" => false,
+                        _ => return None,
+                    }
+                } else {
+                    false
+                };
+                self.symbol(')')
+                    .then_some(SecurityValue::Setting { name, missing_ok })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// `query`, restricted by the security filter of the bm25 index named `index_name`, if any.
+pub fn secure_query(
+    index_name: &str,
+    schema: &SearchIndexSchema,
+    query: SearchQueryInput,
+) -> SearchQueryInput {
+    match SecurityFilter::for_index(index_name) {
+        Some(filter) => filter.restrict(schema, query),
+        None => query,
+    }
+}
+
+/// The documents that the security filter of the bm25 index named `index_name` lets a search
+/// see, if it has one, for functions that read terms without running a query.
+pub fn security_query(index_name: &str, schema: &SearchIndexSchema) -> Option<SearchQueryInput> {
+    SecurityFilter::for_index(index_name).map(|filter| filter.query(schema))
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SecurityFilterError {
+    #[error("invalid security_filter '{0}', expected 'field = value' or 'field IN (value, ...)', where values are quoted strings, numbers, current_user, session_user or current_setting('name'[, missing_ok])")]
+    InvalidFilter(String),
+
+    #[error("security_filter field '{0}' is not a field of the index")]
+    MissingField(String),
+
+    #[error("security_filter field '{0}' must be a text or integer field")]
+    InvalidFieldType(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_parse_equals() {
+        let filter = SecurityFilter::parse("security_label = current_setting('app.tenant')")
+            .expect("filter should parse");
+        assert_eq!(filter.field, "security_label");
+        assert_eq!(
+            filter.values,
+            vec![SecurityValue::Setting {
+                name: "app.tenant".into(),
+                missing_ok: false
+            }]
+        );
+    }
+
+    #[rstest]
+    fn test_parse_in() {
+        let filter = SecurityFilter::parse(
+            "team_id IN ('1', -2, 'it''s', current_user, current_setting('app.team', true))",
+        )
+        .unwrap();
+        assert_eq!(filter.field, "team_id");
+        assert_eq!(
+            filter.values,
+            vec![
+                SecurityValue::Literal("1".into()),
+                SecurityValue::Literal("-2".into()),
+                SecurityValue::Literal("it's".into()),
+                SecurityValue::CurrentUser,
+                SecurityValue::Setting {
+                    name: "app.team".into(),
+                    missing_ok: true
+                },
+            ]
+        );
+    }
+
+    #[rstest]
+    #[case("")]
+    #[case("= 'a'")]
+    #[case("label")]
+    #[case("label =")]
+    #[case("label IN ()")]
+    #[case("label IN 'a'")]
+    #[case("label IN ('a'")]
+    #[case("label LIKE 'a'")]
+    #[case("label = 'a' OR true")]
+    #[case("label = (SELECT secret FROM secrets)")]
+    #[case("label = pg_read_file('/etc/passwd')")]
+    #[case("label = current_setting('a'); DROP TABLE t")]
+    #[case("label = current_setting('a', 'yes')")]
+    #[case("label = 'unterminated")]
+    fn test_parse_invalid(#[case] filter: &str) {
+        assert!(SecurityFilter::parse(filter).is_err());
+    }
+}
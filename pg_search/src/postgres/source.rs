@@ -0,0 +1,81 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::index::shipping::unix_time;
+use crate::index::source::IndexSource;
+use crate::writer::WriterDirectory;
+use pgrx::*;
+
+/// Copy the rows of the source of an index modified since the last refresh to the table of
+/// the index, or all of them if `full` or if the source has no modified column. Returns the
+/// number of rows copied, which are indexed when the transaction commits.
+pub fn refresh_source(directory: &WriterDirectory, full: bool) -> u64 {
+    let mut source = IndexSource::load(directory)
+        .unwrap_or_else(|err| panic!("error loading source of index: {err}"))
+        .unwrap_or_else(|| {
+            panic!(
+                "index {} isn't built from a foreign table, view or materialized view",
+                directory.index_name
+            )
+        });
+    let IndexSource {
+        source: source_name,
+        copy,
+        ..
+    } = &source;
+
+    // Refreshes of the same index wait on each other, without blocking searches.
+    Spi::run(&format!("LOCK TABLE {copy} IN SHARE ROW EXCLUSIVE MODE"))
+        .unwrap_or_else(|err| panic!("error locking {copy}: {err}"));
+
+    let copied = match &source.modified_column {
+        Some(modified_column) if !full => {
+            let modified_column = spi::quote_identifier(modified_column);
+            let key_field = spi::quote_identifier(&source.key_field);
+            // The rows modified at the time of the last row copied are copied again, as
+            // some of them may have been modified after the last refresh. Rows are
+            // replaced by key, so copying a row again leaves a single version of it.
+            Spi::get_one::<i64>(&format!(
+                "WITH changed AS MATERIALIZED (
+                     SELECT * FROM {source_name}
+                     WHERE ({modified_column} >= (SELECT max({modified_column}) FROM {copy})) IS NOT FALSE
+                 ), deleted AS (
+                     DELETE FROM {copy} AS c USING changed WHERE c.{key_field} = changed.{key_field}
+                 ), inserted AS (
+                     INSERT INTO {copy} SELECT * FROM changed RETURNING 1
+                 )
+                 SELECT count(*) FROM inserted"
+            ))
+        }
+        _ => Spi::run(&format!("DELETE FROM {copy}")).and_then(|_| {
+            Spi::get_one::<i64>(&format!(
+                "WITH inserted AS (INSERT INTO {copy} SELECT * FROM {source_name} RETURNING 1) \
+                 SELECT count(*) FROM inserted"
+            ))
+        }),
+    }
+    .unwrap_or_else(|err| panic!("error copying rows of {source_name} to {copy}: {err}"))
+    .unwrap_or_default();
+
+    // Saved before the transaction commits, so a refresh that fails waits for the next
+    // interval rather than being retried right away.
+    source.refreshed_at = unix_time();
+    source
+        .save(directory)
+        .unwrap_or_else(|err| panic!("error saving source of index: {err}"));
+    copied as u64
+}
@@ -16,13 +16,15 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 use crate::postgres::datetime::{datetime_components_to_tantivy_date, MICROSECONDS_IN_SECOND};
+use crate::postgres::jsonb::JsonbReader;
+use crate::schema::{encode_vector, sparse_tokens, sparse_weights_from_json, SparseVectorError};
 use ordered_float::OrderedFloat;
 use pgrx::datum::datetime_support::DateTimeConversionError;
 use pgrx::pg_sys::Datum;
 use pgrx::pg_sys::Oid;
 use pgrx::IntoDatum;
 use pgrx::PostgresType;
-use pgrx::{FromDatum, PgBuiltInOids, PgOid};
+use pgrx::{pg_sys, FromDatum, PgBuiltInOids, PgOid, Spi};
 use serde::ser::{Serialize, SerializeStruct, Serializer};
 use serde::{Deserialize, Deserializer};
 use serde_json::Value;
@@ -33,6 +35,41 @@ use std::num::ParseFloatError;
 use std::str::FromStr;
 use thiserror::Error;
 
+/// Whether columns of a type can be indexed as vector fields: arrays of `real` or `double
+/// precision`, and the `vector` type of pgvector, which has no fixed oid.
+pub fn is_vector_type(oid: PgOid) -> bool {
+    let element_oid = unsafe { pg_sys::get_element_type(oid.value()) };
+    if element_oid == PgBuiltInOids::FLOAT4OID.value()
+        || element_oid == PgBuiltInOids::FLOAT8OID.value()
+    {
+        return true;
+    }
+    match oid {
+        PgOid::Custom(oid) => Spi::get_one::<String>(&format!(
+            "SELECT typname::text FROM pg_type WHERE oid = {}",
+            oid.as_u32()
+        ))
+        .unwrap_or_else(|err| panic!("error looking up type {oid:?}: {err}"))
+        .is_some_and(|typname| typname == "vector"),
+        _ => false,
+    }
+}
+
+/// Whether columns of a type can be indexed as sparse vector fields: JSON objects of terms
+/// and weights, and the `sparsevec` type of pgvector.
+pub fn is_sparse_vector_type(oid: PgOid) -> bool {
+    match oid {
+        PgOid::BuiltIn(PgBuiltInOids::JSONBOID | PgBuiltInOids::JSONOID) => true,
+        PgOid::Custom(oid) => Spi::get_one::<String>(&format!(
+            "SELECT typname::text FROM pg_type WHERE oid = {}",
+            oid.as_u32()
+        ))
+        .unwrap_or_else(|err| panic!("error looking up type {oid:?}: {err}"))
+        .is_some_and(|typname| typname == "sparsevec"),
+        _ => false,
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, PostgresType)]
 pub struct TantivyValue(pub tantivy::schema::OwnedValue);
 
@@ -97,17 +134,98 @@ impl TantivyValue {
 
     fn json_value_to_tantivy_value(value: Value) -> Vec<TantivyValue> {
         let mut tantivy_values = vec![];
+        Self::flatten_json_value(value, &mut tantivy_values);
+        tantivy_values
+    }
+
+    fn flatten_json_value(value: Value, tantivy_values: &mut Vec<TantivyValue>) {
         match value {
             // A tantivy JSON value can't be a top-level array, so we have to make
             // separate values out of each entry.
             Value::Array(value_vec) => {
                 for value in value_vec {
-                    tantivy_values.extend_from_slice(&Self::json_value_to_tantivy_value(value));
+                    Self::flatten_json_value(value, tantivy_values);
                 }
             }
             _ => tantivy_values.push(TantivyValue(tantivy::schema::OwnedValue::from(value))),
         }
-        tantivy_values
+    }
+
+    /// The value of a vector field, from an array of `real` or `double precision`, or from the
+    /// `vector` type of pgvector, see `is_vector_type`.
+    pub unsafe fn try_from_datum_vector(
+        datum: Datum,
+        oid: PgOid,
+    ) -> Result<Self, TantivyValueError> {
+        let element_oid = pg_sys::get_element_type(oid.value());
+        let vector: Vec<f32> = if element_oid == PgBuiltInOids::FLOAT4OID.value() {
+            let array: pgrx::Array<f32> =
+                pgrx::Array::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?;
+            array
+                .iter()
+                .map(|value| value.ok_or(TantivyValueError::NullVectorElement))
+                .collect::<Result<_, _>>()?
+        } else if element_oid == PgBuiltInOids::FLOAT8OID.value() {
+            let array: pgrx::Array<f64> =
+                pgrx::Array::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?;
+            array
+                .iter()
+                .map(|value| value.map(|value| value as f32))
+                .map(|value| value.ok_or(TantivyValueError::NullVectorElement))
+                .collect::<Result<_, _>>()?
+        } else {
+            // A pgvector vector is a varlena holding its number of dimensions as an int16 and
+            // an unused int16, followed by its elements as float4.
+            let varlena = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+            let data = pgrx::varlena::vardata_any(varlena) as *const u8;
+            let dimensions = (data as *const i16).read_unaligned().max(0) as usize;
+            (0..dimensions)
+                .map(|index| (data.add(4) as *const f32).add(index).read_unaligned())
+                .collect()
+        };
+        Ok(Self(tantivy::schema::OwnedValue::Bytes(encode_vector(
+            &vector,
+        ))))
+    }
+
+    /// The value of a sparse vector field, from a JSON object or from the `sparsevec` type of
+    /// pgvector, see `is_sparse_vector_type`.
+    pub unsafe fn try_from_datum_sparse(
+        datum: Datum,
+        oid: PgOid,
+    ) -> Result<Self, TantivyValueError> {
+        let weights = match oid {
+            PgOid::BuiltIn(PgBuiltInOids::JSONBOID) => {
+                let pgrx::JsonB(value) =
+                    pgrx::JsonB::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?;
+                sparse_weights_from_json(&value)?
+            }
+            PgOid::BuiltIn(PgBuiltInOids::JSONOID) => {
+                let pgrx::Json(value) =
+                    pgrx::Json::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?;
+                sparse_weights_from_json(&value)?
+            }
+            _ => {
+                // A pgvector sparsevec is a varlena holding its number of dimensions, its
+                // number of nonzero elements and an unused int32, followed by the 0-based
+                // indices of the nonzero elements as int32 and then their values as float4.
+                let varlena = pg_sys::pg_detoast_datum(datum.cast_mut_ptr());
+                let data = pgrx::varlena::vardata_any(varlena) as *const i32;
+                let nonzero = data.add(1).read_unaligned().max(0) as usize;
+                let indices = data.add(3);
+                let values = indices.add(nonzero) as *const f32;
+                (0..nonzero)
+                    .map(|element| {
+                        let index = indices.add(element).read_unaligned();
+                        let value = values.add(element).read_unaligned();
+                        ((index + 1).to_string(), value)
+                    })
+                    .collect()
+            }
+        };
+        Ok(Self(tantivy::schema::OwnedValue::PreTokStr(sparse_tokens(
+            &weights,
+        )?)))
     }
 
     pub unsafe fn try_from_datum_array(
@@ -155,18 +273,14 @@ impl TantivyValue {
                 // Tantivy has a limitation that prevents JSON top-level arrays from being
                 // inserted into the index. Therefore, we need to flatten the array elements
                 // individually before converting them into Tantivy values.
-                PgBuiltInOids::JSONBOID => {
-                    let pgrx_value = pgrx::JsonB::from_datum(datum, false)
-                        .ok_or(TantivyValueError::DatumDeref)?;
-                    let json_value: Value =
-                        serde_json::from_slice(&serde_json::to_vec(&pgrx_value.0)?)?;
-                    Ok(Self::json_value_to_tantivy_value(json_value))
-                }
+                PgBuiltInOids::JSONBOID => Ok(JsonbReader::read(datum)
+                    .into_iter()
+                    .map(TantivyValue)
+                    .collect()),
                 PgBuiltInOids::JSONOID => {
-                    let pgrx_value = pgrx::JsonB::from_datum(datum, false)
+                    // A json datum is parsed straight from its text, unlike jsonb.
+                    let pgrx::Json(json_value) = pgrx::Json::from_datum(datum, false)
                         .ok_or(TantivyValueError::DatumDeref)?;
-                    let json_value: Value =
-                        serde_json::from_slice(&serde_json::to_vec(&pgrx_value.0)?)?;
                     Ok(Self::json_value_to_tantivy_value(json_value))
                 }
                 _ => Err(TantivyValueError::UnsupportedJsonOid(oid.value())),
@@ -199,12 +313,20 @@ impl TantivyValue {
                 PgBuiltInOids::FLOAT8OID => TantivyValue::try_from(
                     f64::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?,
                 ),
+                // Numerics are indexed as f64, so they are converted in place rather than
+                // copied into an `AnyNumeric` first.
                 PgBuiltInOids::NUMERICOID => TantivyValue::try_from(
-                    pgrx::AnyNumeric::from_datum(datum, false)
-                        .ok_or(TantivyValueError::DatumDeref)?,
+                    pgrx::direct_function_call::<f64>(
+                        pgrx::pg_sys::numeric_float8_no_overflow,
+                        &[Some(datum)],
+                    )
+                    .ok_or(TantivyValueError::DatumDeref)?,
                 ),
+                // The text is borrowed from the datum, and only copied once into the value.
                 PgBuiltInOids::TEXTOID | PgBuiltInOids::VARCHAROID => TantivyValue::try_from(
-                    String::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?,
+                    <&str>::from_datum(datum, false)
+                        .ok_or(TantivyValueError::DatumDeref)?
+                        .to_owned(),
                 ),
                 PgBuiltInOids::DATEOID => TantivyValue::try_from(
                     pgrx::datum::Date::from_datum(datum, false)
@@ -657,8 +779,7 @@ impl TryFrom<pgrx::JsonB> for TantivyValue {
     type Error = TantivyValueError;
 
     fn try_from(val: pgrx::JsonB) -> Result<Self, Self::Error> {
-        let json_value: Value = serde_json::from_slice(&serde_json::to_vec(&val.0)?)?;
-        Ok(TantivyValue(tantivy::schema::OwnedValue::from(json_value)))
+        Ok(TantivyValue(tantivy::schema::OwnedValue::from(val.0)))
     }
 }
 
@@ -1010,6 +1131,12 @@ pub enum TantivyValueError {
     #[error("Type {0} is not yet supported")]
     UnsupportedOid(Oid),
 
+    #[error("Vectors cannot have NULL elements")]
+    NullVectorElement,
+
+    #[error(transparent)]
+    SparseVectorError(#[from] SparseVectorError),
+
     #[error("Arrays of type {0} are not yet supported")]
     UnsupportedArrayOid(Oid),
 
@@ -15,34 +15,45 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use crate::postgres::types::TantivyValue;
-use crate::schema::{SearchDocument, SearchFieldName, SearchIndexSchema};
+use crate::index::history::{transaction_timestamp, VALID_FROM_FIELD};
+use crate::postgres::extract::extract_text;
+use crate::postgres::types::{TantivyValue, TantivyValueError};
+use crate::schema::{SearchDocument, SearchFieldErrorPolicy, SearchFieldType, SearchIndexSchema};
 use crate::writer::IndexError;
 use pgrx::pg_sys::{BuiltinOid, ItemPointerData};
 use pgrx::*;
 
+/// A row of a table, as it's indexed. Values that can't be indexed fail the row, unless the
+/// `on_error` of their field says otherwise, in which case they're counted as skipped.
+pub struct IndexedRow {
+    /// The document of the row, or `None` if the row is left out of the index.
+    pub document: Option<SearchDocument>,
+    pub skipped_values: u64,
+}
+
 pub unsafe fn row_to_search_document(
     ctid: ItemPointerData,
     tupdesc: &PgTupleDesc,
     values: *mut pg_sys::Datum,
     isnull: *mut bool,
     schema: &SearchIndexSchema,
-) -> Result<SearchDocument, IndexError> {
+) -> Result<IndexedRow, IndexError> {
     let mut document = schema.new_document();
+    let mut skipped_values = 0;
 
-    // Create a vector of index entries from the postgres row.
+    // This runs for every row being indexed, so values are moved into the document as they are
+    // converted, without cloning them or allocating anything else per field.
     for (attno, attribute) in tupdesc.iter().enumerate() {
-        let attname = attribute.name().to_string();
+        let attname = attribute.name();
         let attribute_type_oid = attribute.type_oid();
 
         // If we can't lookup the attribute name in the field_lookup parameter,
         // it means that this field is not part of the index. We should skip it.
-        let search_field =
-            if let Some(index_field) = schema.get_search_field(&attname.clone().into()) {
-                index_field
-            } else {
-                continue;
-            };
+        let search_field = if let Some(index_field) = schema.get_search_field(attname) {
+            index_field
+        } else {
+            continue;
+        };
 
         let array_type = unsafe { pg_sys::get_element_type(attribute_type_oid.value()) };
         let (base_oid, is_array) = if array_type != pg_sys::InvalidOid {
@@ -59,28 +70,62 @@ pub unsafe fn row_to_search_document(
         let datum = *values.add(attno);
         let isnull = *isnull.add(attno);
 
-        let SearchFieldName(key_field_name) = schema.key_field().name;
-        if key_field_name == attname && isnull {
-            return Err(IndexError::KeyIdNull(key_field_name));
+        if search_field.id == document.key && isnull {
+            return Err(IndexError::KeyIdNull(attname.to_string()));
         }
 
         if isnull {
             continue;
         }
 
-        if is_array {
-            for value in TantivyValue::try_from_datum_array(datum, base_oid)? {
-                document.insert(search_field.id, value.tantivy_schema_value());
+        let mut insert = || -> Result<(), IndexError> {
+            if search_field.type_ == SearchFieldType::Vector {
+                let TantivyValue(value) =
+                    TantivyValue::try_from_datum_vector(datum, attribute_type_oid)?;
+                document.insert(search_field.id, value);
+            } else if base_oid == PgOid::BuiltIn(BuiltinOid::BYTEAOID) {
+                // Only document fields index bytea columns, as the text of their content.
+                let content =
+                    Vec::<u8>::from_datum(datum, false).ok_or(TantivyValueError::DatumDeref)?;
+                document.insert(search_field.id, extract_text(&content)?.into());
+            } else if search_field.type_ == SearchFieldType::Sparse {
+                let TantivyValue(value) =
+                    TantivyValue::try_from_datum_sparse(datum, attribute_type_oid)?;
+                document.insert(search_field.id, value);
+            } else if is_array {
+                for TantivyValue(value) in TantivyValue::try_from_datum_array(datum, base_oid)? {
+                    document.insert(search_field.id, value);
+                }
+            } else if is_json {
+                for TantivyValue(value) in TantivyValue::try_from_datum_json(datum, base_oid)? {
+                    document.insert(search_field.id, value);
+                }
+            } else {
+                let TantivyValue(value) = TantivyValue::try_from_datum(datum, base_oid)?;
+                document.insert(search_field.id, value);
             }
-        } else if is_json {
-            for value in TantivyValue::try_from_datum_json(datum, base_oid)? {
-                document.insert(search_field.id, value.tantivy_schema_value());
+            Ok(())
+        };
+        // Values are only added to the document once all of them are converted, so a value
+        // that fails leaves nothing behind, as if it were NULL.
+        if let Err(err) = insert() {
+            match search_field.config.on_error() {
+                SearchFieldErrorPolicy::Fail => {
+                    return Err(IndexError::InvalidColumn {
+                        column: attname.to_string(),
+                        type_name: type_name(attribute_type_oid),
+                        value: datum_to_text(datum, attribute_type_oid),
+                        source: Box::new(err),
+                    })
+                }
+                SearchFieldErrorPolicy::Skip => {
+                    return Ok(IndexedRow {
+                        document: None,
+                        skipped_values: skipped_values + 1,
+                    })
+                }
+                SearchFieldErrorPolicy::Null => skipped_values += 1,
             }
-        } else {
-            document.insert(
-                search_field.id,
-                TantivyValue::try_from_datum(datum, base_oid)?.tantivy_schema_value(),
-            );
         }
     }
 
@@ -88,5 +133,75 @@ pub unsafe fn row_to_search_document(
     let ctid_index_value = pgrx::item_pointer_to_u64(ctid);
     document.insert(schema.ctid_field().id, ctid_index_value.into());
 
-    Ok(document)
+    // Indexes that keep history date each version of a row with the transaction writing it.
+    if let Some(valid_from_field) = schema.get_search_field(VALID_FROM_FIELD) {
+        document.insert(valid_from_field.id, transaction_timestamp().into());
+    }
+
+    Ok(IndexedRow {
+        document: Some(document),
+        skipped_values,
+    })
+}
+
+/// The name of a type as Postgres displays it, like `timestamp with time zone`.
+fn type_name(type_oid: PgOid) -> String {
+    unsafe { std::ffi::CStr::from_ptr(pg_sys::format_type_be(type_oid.value())) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The text of a value that couldn't be indexed, to show in the error. Long values are cut
+/// short, so that a large document doesn't flood the log.
+unsafe fn datum_to_text(datum: pg_sys::Datum, type_oid: PgOid) -> String {
+    const MAX_CHARS: usize = 100;
+
+    let mut output_func = pg_sys::InvalidOid;
+    let mut is_varlena = false;
+    pg_sys::getTypeOutputInfo(type_oid.value(), &mut output_func, &mut is_varlena);
+    let text = std::ffi::CStr::from_ptr(pg_sys::OidOutputFunctionCall(output_func, datum))
+        .to_string_lossy()
+        .into_owned();
+    if text.chars().count() > MAX_CHARS {
+        format!("{}...", text.chars().take(MAX_CHARS).collect::<String>())
+    } else {
+        text
+    }
+}
+
+/// The config that the functions of the schema `index_name` search with, from its row of
+/// `paradedb.bm25_indexes`: the name of the bm25 index, its table, key field and uuid.
+pub fn index_config(index_name: &str) -> Option<serde_json::Value> {
+    Spi::get_one_with_args::<JsonB>(
+        "SELECT config FROM paradedb.bm25_indexes WHERE index_name = $1",
+        vec![(PgBuiltInOids::TEXTOID.oid(), index_name.into_datum())],
+    )
+    .unwrap_or_else(|err| panic!("error reading the config of index '{index_name}': {err}"))
+    .map(|JsonB(config)| config)
+}
+
+/// The name of the bm25 index that the functions of the schema `index_name` search. That's
+/// `{index_name}_bm25_index`, unless `paradedb.swap_bm25` swapped it with another index, and
+/// functions that take the name of an index resolve it here so that they follow the swap.
+pub fn bm25_index_name(index_name: &str) -> String {
+    index_config(index_name)
+        .and_then(|config| config["index_name"].as_str().map(String::from))
+        .unwrap_or_else(|| format!("{index_name}_bm25_index"))
+}
+
+/// The name of the index whose functions search the bm25 index `bm25_index_name`, the
+/// inverse of `bm25_index_name`, to show indexes by the names they're searched with.
+pub fn index_name_of(bm25_index_name: &str) -> String {
+    Spi::get_one_with_args::<String>(
+        "SELECT index_name FROM paradedb.bm25_indexes WHERE config ->> 'index_name' = $1",
+        vec![(PgBuiltInOids::TEXTOID.oid(), bm25_index_name.into_datum())],
+    )
+    .unwrap_or_else(|err| panic!("error reading the name of index '{bm25_index_name}': {err}"))
+    .unwrap_or_else(|| {
+        // Indexes created with CREATE INDEX instead of create_bm25 have no functions.
+        bm25_index_name
+            .strip_suffix("_bm25_index")
+            .unwrap_or(bm25_index_name)
+            .to_string()
+    })
 }
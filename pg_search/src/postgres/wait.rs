@@ -0,0 +1,114 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use pgrx::*;
+
+/// What a backend waits on while it searches or writes to an index, reported to
+/// `pg_stat_activity`. Before Postgres 17, extensions can't register names for their wait
+/// events, so they all show up as `Extension`, and `paradedb.wait_events` names them.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SearchWaitEvent {
+    /// Documents are sent to the writer process.
+    WriterSend = 1,
+    /// The writer process commits the changes of a transaction.
+    WriterCommit = 2,
+    /// A search runs on the threads of the search pool.
+    QueryExecute = 3,
+}
+
+impl SearchWaitEvent {
+    const ALL: [Self; 3] = [Self::WriterSend, Self::WriterCommit, Self::QueryExecute];
+
+    fn wait_event_info(&self) -> u32 {
+        pg_sys::PG_WAIT_EXTENSION | *self as u32
+    }
+
+    pub fn from_wait_event_info(wait_event_info: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|event| event.wait_event_info() == wait_event_info)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::WriterSend => "SearchWriterSend",
+            Self::WriterCommit => "SearchWriterCommit",
+            Self::QueryExecute => "SearchQueryExecute",
+        }
+    }
+
+    /// Report the wait event until the returned guard is dropped.
+    pub fn start(self) -> SearchWaitGuard {
+        report_wait_event_info(self.wait_event_info());
+        SearchWaitGuard {}
+    }
+
+    /// The wait event reported by the backend `pid`, if it's waiting on search.
+    pub fn of_backend(pid: i32) -> Option<Self> {
+        let proc = unsafe { pg_sys::BackendPidGetProc(pid) };
+        if proc.is_null() {
+            return None;
+        }
+        let wait_event_info = unsafe { std::ptr::read_volatile(&(*proc).wait_event_info) };
+        Self::from_wait_event_info(wait_event_info)
+    }
+}
+
+/// Ends the wait event when dropped, including when a panic unwinds through its scope.
+pub struct SearchWaitGuard {}
+
+impl Drop for SearchWaitGuard {
+    fn drop(&mut self) {
+        report_wait_event_info(0);
+    }
+}
+
+/// pgstat_report_wait_start and pgstat_report_wait_end are inline functions.
+#[cfg(any(feature = "pg14", feature = "pg15", feature = "pg16"))]
+fn report_wait_event_info(wait_event_info: u32) {
+    unsafe { std::ptr::write_volatile(pg_sys::my_wait_event_info, wait_event_info) }
+}
+
+#[cfg(any(feature = "pg12", feature = "pg13"))]
+fn report_wait_event_info(wait_event_info: u32) {
+    unsafe {
+        if !pg_sys::MyProc.is_null() {
+            std::ptr::write_volatile(&mut (*pg_sys::MyProc).wait_event_info, wait_event_info)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SearchWaitEvent;
+
+    #[test]
+    fn test_wait_event_info() {
+        for event in SearchWaitEvent::ALL {
+            assert_eq!(
+                SearchWaitEvent::from_wait_event_info(event.wait_event_info()),
+                Some(event)
+            );
+        }
+        // Not waiting, or waiting on something else.
+        assert_eq!(SearchWaitEvent::from_wait_event_info(0), None);
+        assert_eq!(
+            SearchWaitEvent::from_wait_event_info(pgrx::pg_sys::PG_WAIT_EXTENSION),
+            None
+        );
+    }
+}
@@ -0,0 +1,297 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use crate::index::deadline::{Deadline, DeadlineAutomaton};
+use levenshtein_automata::{Distance, LevenshteinAutomatonBuilder, DFA, SINK_STATE};
+use std::collections::HashMap;
+use std::fmt;
+use tantivy::query::{AutomatonWeight, EnableScoring, Query, TermSetQuery, Weight};
+use tantivy::schema::Field;
+use tantivy::{Searcher, TantivyError, Term};
+use tantivy_fst::{Automaton, Regex};
+
+/// Like the fuzzy term queries of tantivy, whose automata are only built up to this distance.
+const MAX_FUZZY_DISTANCE: u8 = 2;
+
+/// The terms that a regex or fuzzy query expands to, which are looked up in the term
+/// dictionaries of the segments that it searches.
+#[derive(Clone, Debug)]
+pub enum Expansion {
+    Regex {
+        pattern: String,
+    },
+    Fuzzy {
+        value: String,
+        distance: u8,
+        transposition_cost_one: bool,
+        prefix: bool,
+    },
+}
+
+impl fmt::Display for Expansion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Regex { pattern } => write!(f, "regex '{pattern}'"),
+            Self::Fuzzy {
+                value, distance, ..
+            } => write!(f, "fuzzy term '{value}' with distance {distance}"),
+        }
+    }
+}
+
+impl Expansion {
+    /// The automaton matching the terms of the expansion.
+    fn automaton(&self) -> tantivy::Result<ExpansionAutomaton> {
+        match self {
+            Self::Regex { pattern } => Regex::new(pattern)
+                .map(ExpansionAutomaton::Regex)
+                .map_err(|err| TantivyError::InvalidArgument(err.to_string())),
+            Self::Fuzzy {
+                value,
+                distance,
+                transposition_cost_one,
+                prefix,
+            } => {
+                if *distance > MAX_FUZZY_DISTANCE {
+                    return Err(TantivyError::InvalidArgument(format!(
+                        "Levenshtein distance of {distance} is not allowed. Choose a value \
+                         between 0 and {MAX_FUZZY_DISTANCE}"
+                    )));
+                }
+                let builder = LevenshteinAutomatonBuilder::new(*distance, *transposition_cost_one);
+                let dfa = match prefix {
+                    true => builder.build_prefix_dfa(value),
+                    false => builder.build_dfa(value),
+                };
+                Ok(ExpansionAutomaton::Levenshtein(dfa))
+            }
+        }
+    }
+}
+
+/// The automaton of a regex, or the Levenshtein automaton of a fuzzy term, over the bytes
+/// of terms. The states of a regex are optional, and those of a Levenshtein automaton never
+/// are.
+enum ExpansionAutomaton {
+    Regex(Regex),
+    Levenshtein(DFA),
+}
+
+impl Automaton for ExpansionAutomaton {
+    type State = Option<usize>;
+
+    fn start(&self) -> Option<usize> {
+        match self {
+            Self::Regex(regex) => regex.start(),
+            Self::Levenshtein(dfa) => Some(dfa.initial_state() as usize),
+        }
+    }
+
+    fn is_match(&self, state: &Option<usize>) -> bool {
+        match (self, state) {
+            (Self::Regex(regex), _) => regex.is_match(state),
+            (Self::Levenshtein(dfa), Some(state)) => {
+                matches!(dfa.distance(*state as u32), Distance::Exact(_))
+            }
+            (Self::Levenshtein(_), None) => false,
+        }
+    }
+
+    fn can_match(&self, state: &Option<usize>) -> bool {
+        match (self, state) {
+            (Self::Regex(regex), _) => regex.can_match(state),
+            (Self::Levenshtein(_), Some(state)) => *state as u32 != SINK_STATE,
+            (Self::Levenshtein(_), None) => false,
+        }
+    }
+
+    fn accept(&self, state: &Option<usize>, byte: u8) -> Option<usize> {
+        match (self, state) {
+            (Self::Regex(regex), _) => regex.accept(state, byte),
+            (Self::Levenshtein(dfa), Some(state)) => {
+                Some(dfa.transition(*state as u32, byte) as usize)
+            }
+            (Self::Levenshtein(_), None) => None,
+        }
+    }
+}
+
+/// A regex or fuzzy term query, scored as a constant like those of tantivy. Their terms are
+/// only looked up as the scorer of each segment is built, which stops once the deadline of
+/// the search has passed.
+#[derive(Clone, Debug)]
+pub struct ExpansionQuery {
+    field: Field,
+    expansion: Expansion,
+}
+
+impl ExpansionQuery {
+    /// Fails if the regex doesn't parse, or the fuzzy distance is too large.
+    pub fn new(field: Field, expansion: Expansion) -> tantivy::Result<Self> {
+        expansion.automaton()?;
+        Ok(Self { field, expansion })
+    }
+}
+
+impl Query for ExpansionQuery {
+    fn weight(&self, _enable_scoring: EnableScoring<'_>) -> tantivy::Result<Box<dyn Weight>> {
+        let automaton = DeadlineAutomaton::new(self.expansion.automaton()?, Deadline::current());
+        Ok(Box::new(AutomatonWeight::new(self.field, automaton)))
+    }
+}
+
+/// Limits a regex or fuzzy query to `limit` terms. If it expands to more terms in the
+/// segments searched, the search fails, or with `rewrite` the query is rewritten to the
+/// `limit` terms that are found in the most documents. Like the query, they're scored as
+/// constants.
+#[derive(Debug)]
+pub struct ExpansionLimitQuery {
+    query: Box<dyn Query>,
+    field: Field,
+    expansion: Expansion,
+    limit: usize,
+    rewrite: bool,
+}
+
+impl ExpansionLimitQuery {
+    pub fn new(
+        query: Box<dyn Query>,
+        field: Field,
+        expansion: Expansion,
+        limit: usize,
+        rewrite: bool,
+    ) -> Self {
+        Self {
+            query,
+            field,
+            expansion,
+            limit,
+            rewrite,
+        }
+    }
+
+    /// The number of documents of each term that the query expands to. Without `rewrite`,
+    /// the lookup stops as soon as there are too many terms.
+    /// The lookup also stops once the deadline of the search has passed, which the search
+    /// then reports.
+    fn doc_freqs(&self, searcher: &Searcher) -> tantivy::Result<HashMap<Vec<u8>, u64>> {
+        let automaton = DeadlineAutomaton::new(self.expansion.automaton()?, Deadline::current());
+        let mut doc_freqs: HashMap<Vec<u8>, u64> = HashMap::new();
+        for segment_reader in searcher.segment_readers() {
+            let inverted_index = segment_reader.inverted_index(self.field)?;
+            let mut terms = inverted_index.terms().search(&automaton).into_stream()?;
+            while terms.advance() {
+                *doc_freqs.entry(terms.key().to_vec()).or_default() +=
+                    terms.value().doc_freq as u64;
+                if !self.rewrite && doc_freqs.len() > self.limit {
+                    return Ok(doc_freqs);
+                }
+            }
+        }
+        Ok(doc_freqs)
+    }
+}
+
+impl Clone for ExpansionLimitQuery {
+    fn clone(&self) -> Self {
+        Self {
+            query: self.query.box_clone(),
+            field: self.field,
+            expansion: self.expansion.clone(),
+            limit: self.limit,
+            rewrite: self.rewrite,
+        }
+    }
+}
+
+impl Query for ExpansionLimitQuery {
+    fn weight(&self, enable_scoring: EnableScoring<'_>) -> tantivy::Result<Box<dyn Weight>> {
+        // The query is built first, so that its own errors are reported before the terms are
+        // looked up.
+        let weight = self.query.weight(enable_scoring)?;
+        let Some(searcher) = enable_scoring.searcher() else {
+            return Ok(weight);
+        };
+
+        let doc_freqs = self.doc_freqs(searcher)?;
+        if doc_freqs.len() <= self.limit {
+            return Ok(weight);
+        }
+        if !self.rewrite {
+            return Err(TantivyError::InvalidArgument(format!(
+                "{} expands to more than {} terms of field '{}', narrow it, raise its max_expansions, or set paradedb.rewrite_term_expansions to search its most frequent terms",
+                self.expansion,
+                self.limit,
+                searcher.schema().get_field_name(self.field)
+            )));
+        }
+
+        let mut doc_freqs: Vec<_> = doc_freqs.into_iter().collect();
+        doc_freqs.sort_by(|(term_a, freq_a), (term_b, freq_b)| {
+            freq_b.cmp(freq_a).then_with(|| term_a.cmp(term_b))
+        });
+        let terms = doc_freqs
+            .into_iter()
+            .take(self.limit)
+            .map(|(term, _)| Term::from_field_text(self.field, &String::from_utf8_lossy(&term)));
+        TermSetQuery::new(terms).weight(enable_scoring)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Expansion, ExpansionLimitQuery, ExpansionQuery};
+    use tantivy::collector::Count;
+    use tantivy::schema::{Schema, TEXT};
+    use tantivy::{doc, Index};
+
+    #[test]
+    fn test_expansion_limit() {
+        let mut schema = Schema::builder();
+        let field = schema.add_text_field("description", TEXT);
+        let index = Index::create_in_ram(schema.build());
+        let mut writer = index.writer_with_num_threads(1, 15_000_000).unwrap();
+        for text in ["keyboard", "keycap", "keycap", "keychain", "mouse"] {
+            writer.add_document(doc!(field => text)).unwrap();
+        }
+        writer.commit().unwrap();
+        let searcher = index.reader().unwrap().searcher();
+
+        let query = |limit, rewrite| {
+            let expansion = Expansion::Regex {
+                pattern: "key.*".into(),
+            };
+            ExpansionLimitQuery::new(
+                Box::new(ExpansionQuery::new(field, expansion.clone()).unwrap()),
+                field,
+                expansion,
+                limit,
+                rewrite,
+            )
+        };
+
+        // Within the limit, the query is searched as it is.
+        assert_eq!(searcher.search(&query(3, false), &Count).unwrap(), 4);
+
+        // Beyond it, the search fails, or only searches the most frequent terms.
+        let err = searcher.search(&query(2, false), &Count).unwrap_err();
+        assert!(err.to_string().contains("more than 2 terms"), "{err}");
+        assert_eq!(searcher.search(&query(1, true), &Count).unwrap(), 2);
+        // Keycap, and keyboard before keychain, which are as frequent.
+        assert_eq!(searcher.search(&query(2, true), &Count).unwrap(), 3);
+    }
+}
@@ -0,0 +1,283 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{AsFieldType, SearchQueryInput};
+use once_cell::unsync::OnceCell;
+use pgrx::*;
+use std::collections::BTreeMap;
+use tantivy::schema::{Field, FieldType};
+
+/// The field group of every indexed text field of an index, with a boost of 1.
+pub const ALL_FIELDS_GROUP: &str = "_all";
+
+/// The members of a field group, with the boost of each.
+pub type FieldGroup = BTreeMap<String, f32>;
+
+/// The field groups of an index, saved by `save_field_group`, on top of the fields of
+/// `field_lookup`. Groups are only loaded once a query names a field that the index doesn't
+/// have, so that searches on fields don't read them.
+pub struct FieldGroups<'a, L: AsFieldType<String>> {
+    field_lookup: &'a L,
+    index_name: &'a str,
+    groups: OnceCell<BTreeMap<String, FieldGroup>>,
+}
+
+impl<'a, L: AsFieldType<String>> FieldGroups<'a, L> {
+    pub fn new(field_lookup: &'a L, index_name: &'a str) -> Self {
+        Self {
+            field_lookup,
+            index_name,
+            groups: OnceCell::new(),
+        }
+    }
+
+    /// The field groups of the bm25 index `index_name`.
+    pub fn load(index_name: &str) -> BTreeMap<String, FieldGroup> {
+        let groups = Spi::connect(|client| {
+            client
+                .select(
+                    "SELECT name, fields FROM paradedb.field_groups WHERE index_name = $1",
+                    None,
+                    Some(vec![(
+                        PgBuiltInOids::TEXTOID.oid(),
+                        index_name.into_datum(),
+                    )]),
+                )?
+                .map(|row| Ok((row.get::<String>(1)?, row.get::<JsonB>(2)?)))
+                .collect::<Result<Vec<_>, spi::Error>>()
+        })
+        .unwrap_or_else(|err| panic!("error loading field groups of index '{index_name}': {err}"));
+
+        groups
+            .into_iter()
+            .filter_map(|(name, fields)| {
+                let (Some(name), Some(JsonB(fields))) = (name, fields) else {
+                    return None;
+                };
+                let group = serde_json::from_value(fields)
+                    .unwrap_or_else(|err| panic!("invalid field group '{name}': {err}"));
+                Some((name, group))
+            })
+            .collect()
+    }
+}
+
+impl<L: AsFieldType<String>> AsFieldType<String> for FieldGroups<'_, L> {
+    fn fields(&self) -> Vec<(FieldType, Field)> {
+        self.field_lookup.fields()
+    }
+
+    fn as_field_type(&self, from: &String) -> Option<(FieldType, Field)> {
+        self.field_lookup.as_field_type(from)
+    }
+
+    fn field_group(&self, from: &String) -> Option<Vec<(String, f32)>> {
+        if let Some(members) = self.field_lookup.field_group(from) {
+            return Some(members);
+        }
+        if self.field_lookup.as_field_type(from).is_some() {
+            return None;
+        }
+        self.groups
+            .get_or_init(|| Self::load(self.index_name))
+            .get(from)
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|(field, boost)| (field.clone(), *boost))
+                    .collect()
+            })
+    }
+}
+
+impl SearchQueryInput {
+    /// The query, with the clauses on a field group searching each of its members instead.
+    pub fn expand_field_groups(self, field_lookup: &impl AsFieldType<String>) -> Self {
+        let expand = |query: Box<Self>| Box::new(query.expand_field_groups(field_lookup));
+        let expand_all = |queries: Vec<Self>| {
+            queries
+                .into_iter()
+                .map(|query| query.expand_field_groups(field_lookup))
+                .collect()
+        };
+        match self {
+            Self::Boolean {
+                must,
+                should,
+                must_not,
+            } => Self::Boolean {
+                must: expand_all(must),
+                should: expand_all(should),
+                must_not: expand_all(must_not),
+            },
+            Self::Boost { query, boost } => Self::Boost {
+                query: expand(query),
+                boost,
+            },
+            Self::ConstScore { query, score } => Self::ConstScore {
+                query: expand(query),
+                score,
+            },
+            Self::DisjunctionMax {
+                disjuncts,
+                tie_breaker,
+            } => Self::DisjunctionMax {
+                disjuncts: expand_all(disjuncts),
+                tie_breaker,
+            },
+            Self::Named { name, query } => Self::Named {
+                name,
+                query: expand(query),
+            },
+            Self::RankingProfile { query, profile } => Self::RankingProfile {
+                query: expand(query),
+                profile,
+            },
+            Self::RerankByVector {
+                query,
+                field,
+                vector,
+                metric,
+                top_n,
+            } => Self::RerankByVector {
+                query: expand(query),
+                field,
+                vector,
+                metric,
+                top_n,
+            },
+            query => query.field_group_query(field_lookup).unwrap_or(query),
+        }
+    }
+
+    /// A query on a field group, as a disjunction max query of the same query on each
+    /// member, boosted by the boost of the member. A document matching several members
+    /// scores as its best match, instead of adding up the scores of every member.
+    pub(super) fn field_group_query(
+        &self,
+        field_lookup: &impl AsFieldType<String>,
+    ) -> Option<Self> {
+        let [field] = self.fields()[..] else {
+            return None;
+        };
+        let disjuncts = field_lookup
+            .field_group(field)?
+            .into_iter()
+            .map(|(member, boost)| {
+                let query = self.with_field(member)?;
+                Some(if boost == 1.0 {
+                    query
+                } else {
+                    Self::Boost {
+                        query: Box::new(query),
+                        boost,
+                    }
+                })
+            })
+            .collect::<Option<_>>()?;
+        Some(Self::DisjunctionMax {
+            disjuncts,
+            tie_breaker: None,
+        })
+    }
+
+    /// The same query on another field, for the queries that compare a single field with
+    /// values that any text field can have.
+    fn with_field(&self, field: String) -> Option<Self> {
+        let mut query = self.clone();
+        match &mut query {
+            Self::FuzzyTerm { field: from, .. }
+            | Self::Phrase { field: from, .. }
+            | Self::PhrasePrefix { field: from, .. }
+            | Self::Range { field: from, .. }
+            | Self::Regex { field: from, .. }
+            | Self::Term {
+                field: Some(from), ..
+            } => *from = field,
+            _ => return None,
+        }
+        Some(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    /// A lookup without fields, with a single field group.
+    struct Groups;
+
+    impl AsFieldType<String> for Groups {
+        fn fields(&self) -> Vec<(FieldType, Field)> {
+            vec![]
+        }
+
+        fn as_field_type(&self, _from: &String) -> Option<(FieldType, Field)> {
+            None
+        }
+
+        fn field_group(&self, from: &String) -> Option<Vec<(String, f32)>> {
+            (from == "content").then(|| vec![("body".into(), 1.0), ("title".into(), 2.0)])
+        }
+    }
+
+    fn phrase(field: &str) -> SearchQueryInput {
+        SearchQueryInput::Phrase {
+            field: field.into(),
+            phrases: vec!["running".into(), "shoes".into()],
+            slop: None,
+        }
+    }
+
+    #[rstest]
+    fn test_expand_field_groups() {
+        let query = SearchQueryInput::Boolean {
+            must: vec![phrase("content")],
+            should: vec![phrase("category")],
+            must_not: vec![],
+        };
+        assert_eq!(
+            query.expand_field_groups(&Groups),
+            SearchQueryInput::Boolean {
+                must: vec![SearchQueryInput::DisjunctionMax {
+                    disjuncts: vec![
+                        phrase("body"),
+                        SearchQueryInput::Boost {
+                            query: Box::new(phrase("title")),
+                            boost: 2.0,
+                        },
+                    ],
+                    tie_breaker: None,
+                }],
+                should: vec![phrase("category")],
+                must_not: vec![],
+            }
+        );
+    }
+
+    #[rstest]
+    fn test_expand_field_groups_unsupported_query() {
+        // Joins look up keys, which can't be searched across several fields.
+        let query = SearchQueryInput::KeyIn {
+            field: "content".into(),
+            index: "orders".into(),
+            query: Box::new(SearchQueryInput::All),
+        };
+        assert_eq!(query.clone().expand_field_groups(&Groups), query);
+    }
+}
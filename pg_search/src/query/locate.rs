@@ -0,0 +1,192 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use serde::Serialize;
+use tantivy::query::QueryParser;
+
+/// Where a query string fails to parse, as a character offset from 0 and the token there.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct ParseErrorLocation {
+    pub offset: usize,
+    pub token: String,
+}
+
+impl ParseErrorLocation {
+    fn at(chars: &[char], offset: usize) -> Self {
+        let token = chars[offset..]
+            .iter()
+            .take_while(|c| !c.is_whitespace())
+            .collect();
+        Self { offset, token }
+    }
+
+    /// The query string, with a caret under the offending token, like Postgres shows the
+    /// position of a syntax error in a statement.
+    pub fn pointer(&self, query_string: &str) -> String {
+        format!("{query_string}\n{}^", " ".repeat(self.offset))
+    }
+}
+
+/// Find where `query_string`, which `parser` failed to parse, goes wrong. Quotes, parentheses
+/// and brackets have to be balanced, boolean operators need clauses on both sides, and each
+/// clause must parse on its own. `None` if the error is none of those.
+pub fn locate_parse_error(query_string: &str, parser: &QueryParser) -> Option<ParseErrorLocation> {
+    let chars: Vec<char> = query_string.chars().collect();
+    if let Some(offset) = unbalanced(&chars) {
+        return Some(ParseErrorLocation::at(&chars, offset));
+    }
+
+    let clauses = clauses(&chars);
+    let is_operator = |clause: &str| matches!(clause, "AND" | "OR" | "&&" | "||");
+    for (index, (offset, clause)) in clauses.iter().enumerate() {
+        if is_operator(clause) {
+            let dangling = index == 0
+                || index == clauses.len() - 1
+                || is_operator(&clauses[index + 1].1)
+                || clauses[index + 1].1 == "NOT" && index + 2 == clauses.len();
+            if dangling {
+                return Some(ParseErrorLocation::at(&chars, *offset));
+            }
+        } else if clause != "NOT" && parser.parse_query(clause).is_err() {
+            return Some(ParseErrorLocation::at(&chars, *offset));
+        }
+    }
+    None
+}
+
+/// The offset of the first quote, parenthesis or bracket that isn't closed, or closes
+/// nothing. Ranges can mix brackets and braces, like `rating:[1 TO 5}`.
+fn unbalanced(chars: &[char]) -> Option<usize> {
+    let mut open: Vec<(char, usize)> = vec![];
+    let mut quote: Option<usize> = None;
+    let mut escaped = false;
+    for (offset, c) in chars.iter().copied().enumerate() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (_, '\\') => escaped = true,
+            (Some(_), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '"') => quote = Some(offset),
+            (None, '(' | '[' | '{') => open.push((c, offset)),
+            (None, ')') => match open.pop() {
+                Some(('(', _)) => {}
+                _ => return Some(offset),
+            },
+            (None, ']' | '}') => match open.pop() {
+                Some(('[' | '{', _)) => {}
+                _ => return Some(offset),
+            },
+            _ => {}
+        }
+    }
+    quote.or_else(|| open.first().map(|(_, offset)| *offset))
+}
+
+/// The clauses of a query string, split on whitespace outside of quotes and ranges, without
+/// the parentheses, required and excluded markers around them, with their offsets.
+fn clauses(chars: &[char]) -> Vec<(usize, String)> {
+    let mut clauses = vec![];
+    let mut start = None;
+    let mut in_quote = false;
+    let mut in_range = false;
+    for offset in 0..=chars.len() {
+        let c = chars.get(offset).copied();
+        let ends = match c {
+            None => true,
+            Some(c) if c.is_whitespace() => !in_quote && !in_range,
+            Some('"') => {
+                in_quote = !in_quote;
+                false
+            }
+            Some('[' | '{') if !in_quote => {
+                in_range = true;
+                false
+            }
+            Some(']' | '}') if !in_quote => {
+                in_range = false;
+                false
+            }
+            _ => false,
+        };
+        match (start, ends) {
+            (Some(clause_start), true) => {
+                let clause = &chars[clause_start..offset];
+                let leading = clause
+                    .iter()
+                    .take_while(|c| matches!(c, '(' | '+' | '-'))
+                    .count();
+                let trailing = clause[leading..]
+                    .iter()
+                    .rev()
+                    .take_while(|c| **c == ')')
+                    .count();
+                let trimmed = &clause[leading..clause.len() - trailing];
+                if !trimmed.is_empty() {
+                    clauses.push((clause_start + leading, trimmed.iter().collect()));
+                }
+                start = None;
+            }
+            (None, false) => start = Some(offset),
+            _ => {}
+        }
+    }
+    clauses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{locate_parse_error, ParseErrorLocation};
+    use crate::fixtures::*;
+    use rstest::*;
+
+    #[rstest]
+    fn test_locate_parse_error(default_index: MockSearchIndex) {
+        let parser = default_index.index.query_parser();
+        let locate = |query_string: &str| {
+            locate_parse_error(query_string, &parser)
+                .map(|ParseErrorLocation { offset, token }| (offset, token))
+        };
+
+        assert_eq!(
+            locate("description:shoes AND (rating:4"),
+            Some((22, "(rating:4".into()))
+        );
+        assert_eq!(
+            locate(r#"description:"running shoes"#),
+            Some((12, r#""running"#.into()))
+        );
+        assert_eq!(
+            locate("rating:[1 TO 5] AND category:electronics)"),
+            Some((40, ")".into()))
+        );
+        assert_eq!(locate("description:shoes AND"), Some((18, "AND".into())));
+        assert_eq!(
+            locate("description:shoes AND (color:red OR rating:4)"),
+            Some((23, "color:red".into()))
+        );
+        assert_eq!(locate("description:shoes AND rating:[1 TO 5]"), None);
+
+        let location = ParseErrorLocation {
+            offset: 4,
+            token: "AND".into(),
+        };
+        assert_eq!(location.pointer("id:1 AND"), "id:1 AND\n    ^");
+    }
+}
@@ -1,15 +1,34 @@
 #![allow(dead_code)]
 
+pub mod expansion;
+pub mod groups;
+pub mod locate;
+pub mod ranking;
+pub mod rerank;
+pub mod rewrite;
+pub mod sparse;
+pub mod stats;
+pub mod template;
+pub mod validate;
+
+use crate::schema::VectorMetric;
+use crate::SEARCH_GUCS;
 use anyhow::{bail, Result};
 use core::panic;
-use pgrx::PostgresType;
+use expansion::{Expansion, ExpansionLimitQuery, ExpansionQuery};
+use locate::{locate_parse_error, ParseErrorLocation};
+use pgrx::pg_sys::panic::ErrorReport;
+use pgrx::{PgLogLevel, PgSqlErrorCode, PostgresType};
+use ranking::{DecayQuery, RankingProfile};
+use rerank::RerankByVectorQuery;
 use serde::{Deserialize, Serialize};
+use sparse::SparseVectorQuery;
 use std::{collections::HashMap, ops::Bound};
 use tantivy::{
     query::{
         AllQuery, BooleanQuery, BoostQuery, ConstScoreQuery, DisjunctionMaxQuery, EmptyQuery,
-        FastFieldRangeWeight, FuzzyTermQuery, MoreLikeThisQuery, PhrasePrefixQuery, PhraseQuery,
-        Query, QueryParser, RangeQuery, RegexQuery, TermQuery, TermSetQuery,
+        FastFieldRangeWeight, MoreLikeThisQuery, PhrasePrefixQuery, PhraseQuery, Query,
+        QueryParser, RangeQuery, TermQuery, TermSetQuery,
     },
     query_grammar::Occur,
     schema::{Field, FieldType, IndexRecordOption, Value},
@@ -17,7 +36,9 @@ use tantivy::{
 };
 use thiserror::Error;
 
-#[derive(Debug, PostgresType, Deserialize, Serialize, Clone, PartialEq, Default)]
+#[derive(
+    Debug, PostgresType, Deserialize, Serialize, Clone, PartialEq, Default, utoipa::ToSchema,
+)]
 pub enum SearchQueryInput {
     All,
     Boolean {
@@ -39,10 +60,15 @@ pub enum SearchQueryInput {
     },
     #[default]
     Empty,
+    /// A range of a fast numeric or date field, read from its column instead of its terms.
+    /// The bounds are converted to the type of the field when the query is built, which also
+    /// reads the plain numbers that queries saved with unsigned bounds hold.
     FastFieldRangeWeight {
         field: String,
-        lower_bound: std::ops::Bound<u64>,
-        upper_bound: std::ops::Bound<u64>,
+        #[schema(value_type = Object)]
+        lower_bound: std::ops::Bound<tantivy::schema::Value>,
+        #[schema(value_type = Object)]
+        upper_bound: std::ops::Bound<tantivy::schema::Value>,
     },
     FuzzyTerm {
         field: String,
@@ -50,6 +76,14 @@ pub enum SearchQueryInput {
         distance: Option<u8>,
         tranposition_cost_one: Option<bool>,
         prefix: Option<bool>,
+        max_expansions: Option<u32>,
+    },
+    /// The documents whose `field` is the key of a row of another index that matches `query`,
+    /// for semi-joins between indexed tables.
+    KeyIn {
+        field: String,
+        index: String,
+        query: Box<SearchQueryInput>,
     },
     MoreLikeThis {
         min_doc_frequency: Option<u64>,
@@ -60,10 +94,21 @@ pub enum SearchQueryInput {
         max_word_length: Option<usize>,
         boost_factor: Option<f32>,
         stop_words: Option<Vec<String>>,
+        #[schema(value_type = Vec<Object>)]
         fields: Vec<(String, tantivy::schema::Value)>,
     },
+    /// A query labeled with a name, which `paradedb.matched_queries` returns for the results
+    /// that it matches.
+    Named {
+        name: String,
+        query: Box<SearchQueryInput>,
+    },
+    /// A query string in the query language of the parser. Terms without an operator are
+    /// combined with AND when `conjunction` is true, and with OR otherwise, which defaults to
+    /// the `conjunction_by_default` of the index.
     Parse {
         query_string: String,
+        conjunction: Option<bool>,
     },
     Phrase {
         field: String,
@@ -77,18 +122,45 @@ pub enum SearchQueryInput {
     },
     Range {
         field: String,
+        #[schema(value_type = Object)]
         lower_bound: std::ops::Bound<tantivy::schema::Value>,
+        #[schema(value_type = Object)]
         upper_bound: std::ops::Bound<tantivy::schema::Value>,
     },
+    /// A query ranked by the boosts and decay of a ranking profile of the index, which
+    /// searches select with their `profile` parameter.
+    RankingProfile {
+        query: Box<SearchQueryInput>,
+        #[schema(value_type = Object)]
+        profile: RankingProfile,
+    },
     Regex {
         field: String,
         pattern: String,
+        max_expansions: Option<u32>,
+    },
+    /// The `top_n` best results of a query, scored by the similarity of their vector to
+    /// `vector` instead of their score. Results without a vector are left out.
+    RerankByVector {
+        query: Box<SearchQueryInput>,
+        field: Option<String>,
+        vector: Vec<f32>,
+        metric: VectorMetric,
+        top_n: usize,
+    },
+    /// The documents sharing terms with a sparse vector, scored by the dot product of their
+    /// weights.
+    SparseVector {
+        field: String,
+        weights: Vec<(String, f32)>,
     },
     Term {
         field: Option<String>,
+        #[schema(value_type = Object)]
         value: tantivy::schema::Value,
     },
     TermSet {
+        #[schema(value_type = Vec<Object>)]
         terms: Vec<(String, tantivy::schema::Value)>,
     },
 }
@@ -98,6 +170,12 @@ pub trait AsFieldType<T> {
 
     fn as_field_type(&self, from: &T) -> Option<(FieldType, Field)>;
 
+    /// The members of the field group `from`, with their boosts, if it's a group and not a
+    /// field. See `SearchQueryInput::expand_field_groups`.
+    fn field_group(&self, _from: &T) -> Option<Vec<(T, f32)>> {
+        None
+    }
+
     fn is_field_type(&self, from: &T, value: &Value) -> bool {
         matches!(
             (self.as_field_type(from), value),
@@ -176,12 +254,218 @@ pub trait AsFieldType<T> {
     }
 }
 
+/// Combines queries with `&&` in SQL. Conjunctions are flattened, so that chains of `&&`
+/// make a single boolean query.
+impl std::ops::BitAnd for SearchQueryInput {
+    type Output = Self;
+
+    fn bitand(self, other: Self) -> Self {
+        let (mut must, mut must_not) = (vec![], vec![]);
+        for query in [self, other] {
+            match query {
+                Self::Boolean {
+                    must: query_must,
+                    should,
+                    must_not: query_must_not,
+                } if should.is_empty() => {
+                    must.extend(query_must);
+                    must_not.extend(query_must_not);
+                }
+                query => must.push(query),
+            }
+        }
+
+        // Negations match everything else, which the other clauses already restrict.
+        if must.iter().any(|query| query != &Self::All) {
+            must.retain(|query| query != &Self::All);
+        }
+        Self::Boolean {
+            must,
+            should: vec![],
+            must_not,
+        }
+    }
+}
+
+/// Combines queries with `||` in SQL. Disjunctions are flattened, so that chains of `||`
+/// make a single boolean query.
+impl std::ops::BitOr for SearchQueryInput {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        let mut should = vec![];
+        for query in [self, other] {
+            match query {
+                Self::Boolean {
+                    must,
+                    should: query_should,
+                    must_not,
+                } if must.is_empty() && must_not.is_empty() => should.extend(query_should),
+                query => should.push(query),
+            }
+        }
+        Self::Boolean {
+            must: vec![],
+            should,
+            must_not: vec![],
+        }
+    }
+}
+
+/// Negates a query with `!!` in SQL. A boolean query with only excluded clauses matches
+/// nothing, so the negation matches every document but those of the query.
+impl std::ops::Not for SearchQueryInput {
+    type Output = Self;
+
+    fn not(self) -> Self {
+        Self::Boolean {
+            must: vec![Self::All],
+            should: vec![],
+            must_not: vec![self],
+        }
+    }
+}
+
 impl SearchQueryInput {
+    /// The named queries of the query tree, outermost first.
+    pub fn named_queries(&self) -> Vec<(&str, &SearchQueryInput)> {
+        let mut named = vec![];
+        self.collect_named(&mut named);
+        named
+    }
+
+    fn collect_named<'a>(&'a self, named: &mut Vec<(&'a str, &'a SearchQueryInput)>) {
+        match self {
+            Self::Named { name, query } => {
+                named.push((name, query));
+                query.collect_named(named);
+            }
+            Self::Boolean {
+                must,
+                should,
+                must_not,
+            } => must
+                .iter()
+                .chain(should)
+                .chain(must_not)
+                .for_each(|query| query.collect_named(named)),
+            Self::Boost { query, .. }
+            | Self::ConstScore { query, .. }
+            | Self::RankingProfile { query, .. }
+            | Self::RerankByVector { query, .. } => query.collect_named(named),
+            Self::DisjunctionMax { disjuncts, .. } => disjuncts
+                .iter()
+                .for_each(|query| query.collect_named(named)),
+            _ => {}
+        }
+    }
+
+    /// Whether the compiled query only depends on the query and on the index searched, so
+    /// that it can be reused by the next search of the same generation of the index. Joins
+    /// read another index, and decays are relative to the time of the search.
+    pub fn is_plan_cacheable(&self) -> bool {
+        match self {
+            Self::KeyIn { .. } => false,
+            Self::RankingProfile { query, profile } => {
+                profile.decay.is_none() && query.is_plan_cacheable()
+            }
+            Self::Boolean {
+                must,
+                should,
+                must_not,
+            } => must
+                .iter()
+                .chain(should)
+                .chain(must_not)
+                .all(|query| query.is_plan_cacheable()),
+            Self::Boost { query, .. }
+            | Self::ConstScore { query, .. }
+            | Self::Named { query, .. }
+            | Self::RerankByVector { query, .. } => query.is_plan_cacheable(),
+            Self::DisjunctionMax { disjuncts, .. } => {
+                disjuncts.iter().all(|query| query.is_plan_cacheable())
+            }
+            _ => true,
+        }
+    }
+
+    /// Parse the query strings of the tree that don't choose an operator with AND, for
+    /// indexes created with `conjunction_by_default`. The query of a join searches another
+    /// index, which has its own default.
+    pub fn conjunction_by_default(self) -> Self {
+        let with_default = |query: Box<Self>| Box::new(query.conjunction_by_default());
+        let with_defaults = |queries: Vec<Self>| {
+            queries
+                .into_iter()
+                .map(Self::conjunction_by_default)
+                .collect()
+        };
+        match self {
+            Self::Parse {
+                query_string,
+                conjunction,
+            } => Self::Parse {
+                query_string,
+                conjunction: conjunction.or(Some(true)),
+            },
+            Self::Boolean {
+                must,
+                should,
+                must_not,
+            } => Self::Boolean {
+                must: with_defaults(must),
+                should: with_defaults(should),
+                must_not: with_defaults(must_not),
+            },
+            Self::Boost { query, boost } => Self::Boost {
+                query: with_default(query),
+                boost,
+            },
+            Self::ConstScore { query, score } => Self::ConstScore {
+                query: with_default(query),
+                score,
+            },
+            Self::DisjunctionMax {
+                disjuncts,
+                tie_breaker,
+            } => Self::DisjunctionMax {
+                disjuncts: with_defaults(disjuncts),
+                tie_breaker,
+            },
+            Self::Named { name, query } => Self::Named {
+                name,
+                query: with_default(query),
+            },
+            Self::RankingProfile { query, profile } => Self::RankingProfile {
+                query: with_default(query),
+                profile,
+            },
+            Self::RerankByVector {
+                query,
+                field,
+                vector,
+                metric,
+                top_n,
+            } => Self::RerankByVector {
+                query: with_default(query),
+                field,
+                vector,
+                metric,
+                top_n,
+            },
+            query => query,
+        }
+    }
+
     pub fn into_tantivy_query(
         self,
         field_lookup: &impl AsFieldType<String>,
         parser: &mut QueryParser,
     ) -> Result<Box<dyn Query>> {
+        if let Some(query) = self.field_group_query(field_lookup) {
+            return query.into_tantivy_query(field_lookup, parser);
+        }
+
         match self {
             Self::All => Ok(Box::new(AllQuery)),
             Self::Boolean {
@@ -238,16 +522,31 @@ impl SearchQueryInput {
                 lower_bound,
                 upper_bound,
             } => {
-                field_lookup
-                    .as_u64(&field)
-                    .or_else(|| field_lookup.as_i64(&field))
+                let (field_type, _) = field_lookup
+                    .as_field_type(&field)
                     .ok_or_else(|| QueryError::WrongFieldType(field.clone()))?;
 
-                Ok(Box::new(FastFieldRangeWeight::new(
-                    field,
-                    lower_bound,
-                    upper_bound,
-                )))
+                macro_rules! fast_field_range {
+                    ($variant:ident) => {{
+                        let typed = |value: Value| match value {
+                            Value::$variant(value) => Some(value),
+                            _ => None,
+                        };
+                        Box::new(FastFieldRangeWeight::new(
+                            field,
+                            typed_bound(lower_bound, &field_type, typed)?,
+                            typed_bound(upper_bound, &field_type, typed)?,
+                        ))
+                    }};
+                }
+
+                Ok(match field_type {
+                    FieldType::U64(_) => fast_field_range!(U64),
+                    FieldType::I64(_) => fast_field_range!(I64),
+                    FieldType::F64(_) => fast_field_range!(F64),
+                    FieldType::Date(_) => fast_field_range!(Date),
+                    _ => bail!("{}", QueryError::WrongFieldType(field)),
+                })
             }
             Self::FuzzyTerm {
                 field,
@@ -255,27 +554,35 @@ impl SearchQueryInput {
                 distance,
                 tranposition_cost_one,
                 prefix,
+                max_expansions,
             } => {
                 let field = field_lookup
                     .as_str(&field)
                     .ok_or_else(|| QueryError::WrongFieldType(field.clone()))?;
 
-                let term = Term::from_field_text(field, &value);
                 let distance = distance.unwrap_or(1);
                 let tranposition_cost_one = tranposition_cost_one.unwrap_or(false);
-                if prefix.unwrap_or(false) {
-                    Ok(Box::new(FuzzyTermQuery::new(
-                        term,
-                        distance,
-                        tranposition_cost_one,
-                    )))
-                } else {
-                    Ok(Box::new(FuzzyTermQuery::new_prefix(
-                        term,
-                        distance,
-                        tranposition_cost_one,
-                    )))
-                }
+                // As when this built a fuzzy term query of tantivy, `prefix` matches whole
+                // terms, and its absence the terms that start with the value.
+                let expansion = Expansion::Fuzzy {
+                    value,
+                    distance,
+                    transposition_cost_one: tranposition_cost_one,
+                    prefix: !prefix.unwrap_or(false),
+                };
+                let query = Box::new(ExpansionQuery::new(field, expansion.clone())?);
+                Ok(limit_expansions(query, field, expansion, max_expansions))
+            }
+            Self::KeyIn {
+                field,
+                index,
+                query,
+            } => {
+                let (_, field) = field_lookup
+                    .as_field_type(&field)
+                    .ok_or_else(|| QueryError::NonIndexedField(field))?;
+                let terms = crate::index::join::key_terms(&index, *query, field)?;
+                Ok(Box::new(TermSetQuery::new(terms)))
             }
             Self::MoreLikeThis {
                 min_doc_frequency,
@@ -317,14 +624,14 @@ impl SearchQueryInput {
 
                 let mut fields_map = HashMap::new();
                 for (field_name, value) in fields {
+                    let (field_type, field) = field_lookup
+                        .as_field_type(&field_name)
+                        .ok_or_else(|| QueryError::WrongFieldType(field_name.clone()))?;
+                    let value = coerce_value(value, &field_type)?;
                     if !field_lookup.is_field_type(&field_name, &value) {
                         bail!("{}", QueryError::WrongFieldType(field_name))
                     }
 
-                    let (_, field) = field_lookup
-                        .as_field_type(&field_name)
-                        .ok_or_else(|| QueryError::WrongFieldType(field_name.clone()))?;
-
                     fields_map.entry(field).or_insert_with(std::vec::Vec::new);
 
                     if let Some(vec) = fields_map.get_mut(&field) {
@@ -353,10 +660,27 @@ impl SearchQueryInput {
                 }
                 Ok(Box::new(query))
             }
-            Self::Parse { query_string } => {
-                Ok(Box::new(parser.parse_query(&query_string).map_err(
-                    |err| QueryError::ParseError(err, query_string),
-                )?))
+            Self::Named { query, .. } => query.into_tantivy_query(field_lookup, parser),
+            Self::Parse {
+                query_string,
+                conjunction,
+            } => {
+                // The parser can't be switched back to OR, so AND queries parse with a copy.
+                let mut conjunction_parser;
+                let parser = if conjunction.unwrap_or(false) {
+                    conjunction_parser = parser.clone();
+                    conjunction_parser.set_conjunction_by_default();
+                    &mut conjunction_parser
+                } else {
+                    parser
+                };
+                match parser.parse_query(&query_string) {
+                    Ok(query) => Ok(Box::new(query)),
+                    Err(err) => {
+                        let location = locate_parse_error(&query_string, parser);
+                        Err(QueryError::ParseError(err, query_string, location).into())
+                    }
+                }
             }
             Self::Phrase {
                 field,
@@ -412,15 +736,104 @@ impl SearchQueryInput {
                     &upper_bound,
                 )))
             }
-            Self::Regex { field, pattern } => Ok(Box::new(
-                RegexQuery::from_pattern(
-                    &pattern,
-                    field_lookup
-                        .as_str(&field)
+            Self::RankingProfile { query, profile } => {
+                let boosts = profile
+                    .boosts
+                    .iter()
+                    .map(|(name, boost)| {
+                        field_lookup
+                            .as_field_type(name)
+                            .map(|(_, field)| (field, *boost))
+                            .ok_or_else(|| QueryError::NonIndexedField(name.clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                // Query strings can search any field, so the parser boosts their fields while
+                // the rest of the query is boosted clause by clause.
+                for (field, boost) in &boosts {
+                    parser.set_field_boost(*field, *boost);
+                }
+                let query = profile
+                    .boost(*query)
+                    .into_tantivy_query(field_lookup, parser);
+                for (field, _) in &boosts {
+                    parser.set_field_boost(*field, 1.0);
+                }
+                let query = query?;
+
+                match profile.decay {
+                    Some(decay) => {
+                        field_lookup
+                            .as_date(&decay.field)
+                            .ok_or_else(|| QueryError::WrongFieldType(decay.field.clone()))?;
+                        let now = tantivy::DateTime::from_timestamp_micros(
+                            chrono::Utc::now().timestamp_micros(),
+                        );
+                        Ok(Box::new(DecayQuery::new(query, decay, now)))
+                    }
+                    None => Ok(query),
+                }
+            }
+            Self::Regex {
+                field,
+                pattern,
+                max_expansions,
+            } => {
+                let field = field_lookup
+                    .as_str(&field)
+                    .ok_or_else(|| QueryError::WrongFieldType(field.clone()))?;
+                let expansion = Expansion::Regex {
+                    pattern: pattern.clone(),
+                };
+                let query = ExpansionQuery::new(field, expansion.clone())
+                    .map_err(|err| QueryError::RegexError(err, pattern))?;
+                Ok(limit_expansions(
+                    Box::new(query),
+                    field,
+                    expansion,
+                    max_expansions,
+                ))
+            }
+            Self::RerankByVector {
+                query,
+                field,
+                vector,
+                metric,
+                top_n,
+            } => {
+                let field = match field {
+                    Some(field) => field_lookup
+                        .as_bytes(&field)
                         .ok_or_else(|| QueryError::WrongFieldType(field.clone()))?,
-                )
-                .map_err(|err| QueryError::RegexError(err, pattern.clone()))?,
-            )),
+                    None => {
+                        // Vectors are the only bytes fields, so an index with a single vector
+                        // field doesn't need it to be named.
+                        let vector_fields: Vec<_> = field_lookup
+                            .fields()
+                            .into_iter()
+                            .filter(|(field_type, _)| matches!(field_type, FieldType::Bytes(_)))
+                            .map(|(_, field)| field)
+                            .collect();
+                        match vector_fields[..] {
+                            [field] => field,
+                            _ => bail!("{}", QueryError::AmbiguousVectorField(vector_fields.len())),
+                        }
+                    }
+                };
+                Ok(Box::new(RerankByVectorQuery::new(
+                    query.into_tantivy_query(field_lookup, parser)?,
+                    field,
+                    vector,
+                    metric,
+                    top_n,
+                )))
+            }
+            Self::SparseVector { field, weights } => Ok(Box::new(SparseVectorQuery::new(
+                field_lookup
+                    .as_str(&field)
+                    .ok_or_else(|| QueryError::WrongFieldType(field.clone()))?,
+                weights,
+            ))),
             Self::Term { field, value } => {
                 let record_option = IndexRecordOption::WithFreqsAndPositions;
                 if let Some(field) = field {
@@ -459,28 +872,14 @@ impl SearchQueryInput {
 
 fn value_to_term(field: Field, value: Value, field_type: &FieldType) -> Result<Term> {
     Ok(match value {
-        Value::Str(text) => {
-            match field_type {
-                FieldType::Date(_) => {
-                    // Serialization turns date into string, so we have to turn it back into a Tantivy date
-                    // First try with no precision beyond seconds, then try with precision
-                    let datetime =
-                        match chrono::NaiveDateTime::parse_from_str(&text, "%Y-%m-%dT%H:%M:%SZ") {
-                            Ok(dt) => dt,
-                            Err(_) => chrono::NaiveDateTime::parse_from_str(
-                                &text,
-                                "%Y-%m-%dT%H:%M:%S%.fZ",
-                            )
-                            .map_err(|_| QueryError::FieldTypeMismatch)?,
-                        };
-                    let tantivy_datetime = tantivy::DateTime::from_timestamp_micros(
-                        datetime.and_utc().timestamp_micros(),
-                    );
-                    Term::from_field_date(field, tantivy_datetime)
-                }
-                _ => Term::from_field_text(field, &text),
-            }
-        }
+        Value::Str(text) => match field_type {
+            FieldType::Date(_) => Term::from_field_date(field, parse_date(&text)?),
+            // Text bounds of `paradedb.range` are read as the type of a numeric field.
+            FieldType::I64(_) => Term::from_field_i64(field, parse_number(&text)?),
+            FieldType::U64(_) => Term::from_field_u64(field, parse_number(&text)?),
+            FieldType::F64(_) => Term::from_field_f64(field, parse_number(&text)?),
+            _ => Term::from_field_text(field, &text),
+        },
         Value::PreTokStr(_) => panic!("pre-tokenized text cannot be converted to term"),
         Value::U64(u64) => {
             // Positive numbers seem to be automatically turned into u64s even if they are i64s,
@@ -502,6 +901,86 @@ fn value_to_term(field: Field, value: Value, field_type: &FieldType) -> Result<T
     })
 }
 
+/// Serialization turns dates into strings, so they have to be turned back into a Tantivy date.
+/// First try with no precision beyond seconds, then try with precision.
+fn parse_date(text: &str) -> Result<tantivy::DateTime> {
+    let datetime = match chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%SZ") {
+        Ok(dt) => dt,
+        Err(_) => chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.fZ")
+            .map_err(|_| QueryError::FieldTypeMismatch)?,
+    };
+    Ok(tantivy::DateTime::from_timestamp_micros(
+        datetime.and_utc().timestamp_micros(),
+    ))
+}
+
+/// A number given as text, such as a text bound of a range over a numeric field.
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T> {
+    text.trim()
+        .parse()
+        .map_err(|_| QueryError::FieldTypeMismatch)
+}
+
+/// The value as the type of its field, for the values that serialization or json don't keep
+/// the type of: dates given as strings, and numbers given as another kind of number.
+fn coerce_value(value: Value, field_type: &FieldType) -> Result<Value> {
+    Ok(match (value, field_type) {
+        (Value::Str(text), FieldType::Date(_)) => Value::Date(parse_date(&text)?),
+        (Value::U64(u64), FieldType::I64(_)) => {
+            Value::I64(i64::try_from(u64).map_err(|_| QueryError::FieldTypeMismatch)?)
+        }
+        (Value::I64(i64), FieldType::U64(_)) => {
+            Value::U64(u64::try_from(i64).map_err(|_| QueryError::FieldTypeMismatch)?)
+        }
+        (Value::U64(u64), FieldType::F64(_)) => Value::F64(u64 as f64),
+        (Value::I64(i64), FieldType::F64(_)) => Value::F64(i64 as f64),
+        (value, _) => value,
+    })
+}
+
+/// The bound as the type of its field, which `typed` reads from the value once it's coerced.
+fn typed_bound<T>(
+    bound: Bound<Value>,
+    field_type: &FieldType,
+    typed: impl Fn(Value) -> Option<T>,
+) -> Result<Bound<T>> {
+    let typed = |value| -> Result<T> {
+        Ok(typed(coerce_value(value, field_type)?).ok_or(QueryError::FieldTypeMismatch)?)
+    };
+    Ok(match bound {
+        Bound::Included(value) => Bound::Included(typed(value)?),
+        Bound::Excluded(value) => Bound::Excluded(typed(value)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// Limit the terms that a regex or fuzzy query expands to, to its own `max_expansions` or
+/// else to `paradedb.max_term_expansions`, unless neither is set.
+fn limit_expansions(
+    query: Box<dyn Query>,
+    field: Field,
+    expansion: Expansion,
+    max_expansions: Option<u32>,
+) -> Box<dyn Query> {
+    let (default_limit, rewrite) = max_term_expansions();
+    match max_expansions.map(|limit| limit as usize).or(default_limit) {
+        Some(limit) => Box::new(ExpansionLimitQuery::new(
+            query, field, expansion, limit, rewrite,
+        )),
+        None => query,
+    }
+}
+
+/// The limit of `paradedb.max_term_expansions`, if it's set, and whether queries beyond it
+/// are rewritten to their most frequent terms instead of failing.
+pub fn max_term_expansions() -> (Option<usize>, bool) {
+    let limit = SEARCH_GUCS.max_term_expansions.get();
+    (
+        (limit > 0).then_some(limit as usize),
+        SEARCH_GUCS.rewrite_term_expansions.get(),
+    )
+}
+
 #[derive(Debug, Error)]
 enum QueryError {
     #[error("wrong field type for field: {0}")]
@@ -514,11 +993,176 @@ enum QueryError {
     NonIndexedField(String),
     #[error("wrong type given for field")]
     FieldTypeMismatch,
+    #[error("the index has {0} vector fields, pass the field to rerank by")]
+    AmbiguousVectorField(usize),
     #[error("could not build regex with pattern '{1}': {0}")]
     RegexError(#[source] tantivy::TantivyError, String),
     #[error(
-        r#"could not parse query string '{1}'.
-           make sure to use column:term pairs, and to capitalize AND/OR."#
+        r#"could not parse query string '{1}'{}.
+           make sure to use column:term pairs, and to capitalize AND/OR."#,
+        near(.2)
     )]
-    ParseError(#[source] tantivy::query::QueryParserError, String),
+    ParseError(
+        #[source] tantivy::query::QueryParserError,
+        String,
+        Option<ParseErrorLocation>,
+    ),
+}
+
+fn near(location: &Option<ParseErrorLocation>) -> String {
+    location.as_ref().map_or(String::new(), |location| {
+        format!(
+            " at character {} near '{}'",
+            location.offset + 1,
+            location.token
+        )
+    })
+}
+
+/// Raise the error of a query that can't be built. A query string that fails to parse is
+/// reported as a syntax error, with the position of the offending token in its DETAIL, like
+/// Postgres does for statements.
+pub fn report_query_error(err: anyhow::Error) -> ! {
+    if let Some(QueryError::ParseError(source, query_string, location)) =
+        err.downcast_ref::<QueryError>()
+    {
+        let report = ErrorReport::new(
+            PgSqlErrorCode::ERRCODE_SYNTAX_ERROR,
+            match location {
+                Some(_) => format!("could not parse query string{}: {source}", near(location)),
+                None => format!("could not parse query string '{query_string}': {source}"),
+            },
+            "",
+        );
+        let report = match location {
+            Some(location) => report.set_detail(location.pointer(query_string)),
+            None => report,
+        };
+        report
+            .set_hint("make sure to use column:term pairs, and to capitalize AND/OR.")
+            .report(PgLogLevel::ERROR);
+    }
+    panic!("could not parse query: {err:?}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{typed_bound, SearchQueryInput};
+    use rstest::*;
+    use std::ops::Bound;
+    use tantivy::schema::{FieldType, NumericOptions, Value};
+
+    fn parse(query_string: &str) -> SearchQueryInput {
+        SearchQueryInput::Parse {
+            query_string: query_string.into(),
+            conjunction: None,
+        }
+    }
+
+    #[rstest]
+    fn test_combine_queries() {
+        assert_eq!(
+            parse("a") & parse("b") & parse("c"),
+            SearchQueryInput::Boolean {
+                must: vec![parse("a"), parse("b"), parse("c")],
+                should: vec![],
+                must_not: vec![],
+            }
+        );
+        assert_eq!(
+            parse("a") | (parse("b") | parse("c")),
+            SearchQueryInput::Boolean {
+                must: vec![],
+                should: vec![parse("a"), parse("b"), parse("c")],
+                must_not: vec![],
+            }
+        );
+
+        // A negation on its own matches everything else, but only narrows a conjunction.
+        assert_eq!(
+            !parse("a"),
+            SearchQueryInput::Boolean {
+                must: vec![SearchQueryInput::All],
+                should: vec![],
+                must_not: vec![parse("a")],
+            }
+        );
+        assert_eq!(
+            parse("a") & !parse("b"),
+            SearchQueryInput::Boolean {
+                must: vec![parse("a")],
+                should: vec![],
+                must_not: vec![parse("b")],
+            }
+        );
+
+        // A disjunction is kept whole in a conjunction.
+        let either = parse("a") | parse("b");
+        assert_eq!(
+            either.clone() & parse("c"),
+            SearchQueryInput::Boolean {
+                must: vec![either, parse("c")],
+                should: vec![],
+                must_not: vec![],
+            }
+        );
+    }
+
+    #[rstest]
+    fn test_named_queries() {
+        let named = |name: &str, query: SearchQueryInput| SearchQueryInput::Named {
+            name: name.into(),
+            query: Box::new(query),
+        };
+        let query = named("keyboards", parse("description:keyboard"))
+            | SearchQueryInput::Boost {
+                query: Box::new(named(
+                    "electronics",
+                    named("cheap", parse("rating:1")) & parse("category:electronics"),
+                )),
+                boost: 2.0,
+            };
+
+        let names: Vec<_> = query
+            .named_queries()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
+        assert_eq!(names, vec!["keyboards", "electronics", "cheap"]);
+        assert_eq!(query.named_queries()[0].1, &parse("description:keyboard"));
+    }
+
+    #[rstest]
+    fn test_fast_field_range_u64_bounds() {
+        // Queries saved before the bounds were typed hold them as u64s, which are still read
+        // and converted to the type of the field.
+        let json = r#"{"FastFieldRangeWeight":{"field":"rating","lower_bound":{"Included":2},"upper_bound":"Unbounded"}}"#;
+        let query: SearchQueryInput = serde_json::from_str(json).unwrap();
+        let SearchQueryInput::FastFieldRangeWeight {
+            lower_bound,
+            upper_bound,
+            ..
+        } = query
+        else {
+            panic!("should read a fast field range");
+        };
+        assert_eq!(lower_bound, Bound::Included(Value::U64(2)));
+        assert_eq!(upper_bound, Bound::Unbounded);
+
+        let typed = |field_type: FieldType| {
+            typed_bound(lower_bound.clone(), &field_type, |value| match value {
+                Value::I64(_) | Value::F64(_) => Some(value),
+                _ => None,
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            typed(FieldType::I64(NumericOptions::default())),
+            Bound::Included(Value::I64(2))
+        );
+        assert_eq!(
+            typed(FieldType::F64(NumericOptions::default())),
+            Bound::Included(Value::F64(2.0))
+        );
+    }
 }
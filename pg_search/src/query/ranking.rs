@@ -0,0 +1,307 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::SearchQueryInput;
+use pgrx::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tantivy::columnar::Column;
+use tantivy::query::{EnableScoring, Explanation, Query, QueryClone, Scorer, Weight};
+use tantivy::{DateTime, DocId, DocSet, Score, SegmentReader, Term};
+
+const MICROS_PER_DAY: f64 = 86_400_000_000.0;
+
+/// The relevance settings of a named ranking profile of an index, which a search selects
+/// with its `profile` parameter, so that ranking can change without changing the queries.
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RankingProfile {
+    /// Factors that multiply the scores of the clauses on each field.
+    #[serde(default)]
+    pub boosts: BTreeMap<String, f32>,
+    /// Lowers the scores of documents with the age of a date field.
+    #[serde(default)]
+    pub decay: Option<ScoreDecay>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ScoreDecay {
+    /// A fast date field.
+    pub field: String,
+    /// Every `scale_days` of age multiply scores by `decay` once more.
+    pub scale_days: f64,
+    #[serde(default = "ScoreDecay::default_decay")]
+    pub decay: f32,
+}
+
+impl ScoreDecay {
+    fn default_decay() -> f32 {
+        0.5
+    }
+}
+
+impl RankingProfile {
+    /// The profile `name` of the bm25 index `index_name`, saved by `save_ranking_profile`.
+    pub fn load(index_name: &str, name: &str) -> Self {
+        let JsonB(profile) = Spi::get_one::<JsonB>(&format!(
+            "SELECT (SELECT profile FROM paradedb.ranking_profiles \
+             WHERE index_name = {} AND name = {})",
+            spi::quote_literal(index_name),
+            spi::quote_literal(name)
+        ))
+        .unwrap_or_else(|err| panic!("error loading ranking profile '{name}': {err}"))
+        .unwrap_or_else(|| panic!("index {index_name} has no ranking profile '{name}'"));
+        serde_json::from_value(profile)
+            .unwrap_or_else(|err| panic!("invalid ranking profile '{name}': {err}"))
+    }
+
+    /// `query` with its clauses on boosted fields wrapped in boosts. The fields of query
+    /// strings are boosted by the query parser instead.
+    pub fn boost(&self, query: SearchQueryInput) -> SearchQueryInput {
+        let boost_all = |queries: Vec<SearchQueryInput>| -> Vec<SearchQueryInput> {
+            queries.into_iter().map(|query| self.boost(query)).collect()
+        };
+        match query {
+            SearchQueryInput::Boolean {
+                must,
+                should,
+                must_not,
+            } => SearchQueryInput::Boolean {
+                must: boost_all(must),
+                should: boost_all(should),
+                must_not,
+            },
+            SearchQueryInput::Boost { query, boost } => SearchQueryInput::Boost {
+                query: Box::new(self.boost(*query)),
+                boost,
+            },
+            SearchQueryInput::DisjunctionMax {
+                disjuncts,
+                tie_breaker,
+            } => SearchQueryInput::DisjunctionMax {
+                disjuncts: boost_all(disjuncts),
+                tie_breaker,
+            },
+            SearchQueryInput::Named { name, query } => SearchQueryInput::Named {
+                name,
+                query: Box::new(self.boost(*query)),
+            },
+            SearchQueryInput::RerankByVector {
+                query,
+                field,
+                vector,
+                metric,
+                top_n,
+            } => SearchQueryInput::RerankByVector {
+                query: Box::new(self.boost(*query)),
+                field,
+                vector,
+                metric,
+                top_n,
+            },
+            query => match query.fields()[..] {
+                [field] => match self.boosts.get(field) {
+                    Some(boost) => SearchQueryInput::Boost {
+                        boost: *boost,
+                        query: Box::new(query),
+                    },
+                    None => query,
+                },
+                _ => query,
+            },
+        }
+    }
+}
+
+/// Scales the scores of a query down by `decay` for every `scale_days` between the date of a
+/// fast field and `origin`, so that older documents rank lower. Documents without a date, or
+/// with a date after `origin`, keep their score.
+#[derive(Debug)]
+pub struct DecayQuery {
+    query: Box<dyn Query>,
+    field: String,
+    origin: DateTime,
+    scale_days: f64,
+    decay: f32,
+}
+
+impl DecayQuery {
+    pub fn new(query: Box<dyn Query>, decay: ScoreDecay, origin: DateTime) -> Self {
+        Self {
+            query,
+            field: decay.field,
+            origin,
+            scale_days: decay.scale_days,
+            decay: decay.decay,
+        }
+    }
+}
+
+impl Clone for DecayQuery {
+    fn clone(&self) -> Self {
+        Self {
+            query: self.query.box_clone(),
+            field: self.field.clone(),
+            origin: self.origin,
+            scale_days: self.scale_days,
+            decay: self.decay,
+        }
+    }
+}
+
+impl Query for DecayQuery {
+    fn weight(&self, enable_scoring: EnableScoring<'_>) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(DecayWeight {
+            inner: self.query.weight(enable_scoring)?,
+            decay: self.clone(),
+        }))
+    }
+
+    fn query_terms<'a>(&'a self, visitor: &mut dyn FnMut(&'a Term, bool)) {
+        self.query.query_terms(visitor)
+    }
+}
+
+impl DecayQuery {
+    fn factor(&self, date: Option<DateTime>) -> Score {
+        let Some(date) = date else {
+            return 1.0;
+        };
+        let age_micros = self.origin.into_timestamp_micros() - date.into_timestamp_micros();
+        if age_micros <= 0 || self.scale_days <= 0.0 {
+            return 1.0;
+        }
+        let scales = age_micros as f64 / MICROS_PER_DAY / self.scale_days;
+        (self.decay as f64).powf(scales) as Score
+    }
+}
+
+struct DecayWeight {
+    inner: Box<dyn Weight>,
+    decay: DecayQuery,
+}
+
+impl Weight for DecayWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        Ok(Box::new(DecayScorer {
+            inner: self.inner.scorer(reader, boost)?,
+            dates: reader.fast_fields().date(&self.decay.field)?,
+            decay: self.decay.clone(),
+        }))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        let explanation = self.inner.explain(reader, doc)?;
+        let factor = self
+            .decay
+            .factor(reader.fast_fields().date(&self.decay.field)?.first(doc));
+        let mut decayed = Explanation::new("decay", explanation.value() * factor);
+        decayed.add_const(format!("decay of {}", self.decay.field), factor);
+        decayed.add_detail(explanation);
+        Ok(decayed)
+    }
+}
+
+struct DecayScorer {
+    inner: Box<dyn Scorer>,
+    dates: Column<DateTime>,
+    decay: DecayQuery,
+}
+
+impl DocSet for DecayScorer {
+    fn advance(&mut self) -> DocId {
+        self.inner.advance()
+    }
+
+    fn seek(&mut self, target: DocId) -> DocId {
+        self.inner.seek(target)
+    }
+
+    fn doc(&self) -> DocId {
+        self.inner.doc()
+    }
+
+    fn size_hint(&self) -> u32 {
+        self.inner.size_hint()
+    }
+}
+
+impl Scorer for DecayScorer {
+    fn score(&mut self) -> Score {
+        let factor = self.decay.factor(self.dates.first(self.inner.doc()));
+        self.inner.score() * factor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use tantivy::query::AllQuery;
+
+    fn term(field: &str, value: &str) -> SearchQueryInput {
+        SearchQueryInput::Term {
+            field: Some(field.into()),
+            value: tantivy::schema::Value::Str(value.into()),
+        }
+    }
+
+    #[rstest]
+    fn test_boost_fields() {
+        let profile = RankingProfile {
+            boosts: BTreeMap::from([("title".into(), 3.0)]),
+            decay: None,
+        };
+        let query = SearchQueryInput::Boolean {
+            must: vec![term("title", "keyboard")],
+            should: vec![term("body", "keyboard")],
+            must_not: vec![term("title", "broken")],
+        };
+        assert_eq!(
+            profile.boost(query),
+            SearchQueryInput::Boolean {
+                must: vec![SearchQueryInput::Boost {
+                    query: Box::new(term("title", "keyboard")),
+                    boost: 3.0,
+                }],
+                should: vec![term("body", "keyboard")],
+                must_not: vec![term("title", "broken")],
+            }
+        );
+    }
+
+    #[rstest]
+    fn test_decay_factor() {
+        let day = |days: i64| DateTime::from_timestamp_micros(days * MICROS_PER_DAY as i64);
+        let query = DecayQuery::new(
+            Box::new(AllQuery),
+            ScoreDecay {
+                field: "created_at".into(),
+                scale_days: 7.0,
+                decay: 0.5,
+            },
+            day(28),
+        );
+        assert_eq!(query.factor(Some(day(28))), 1.0);
+        assert_eq!(query.factor(Some(day(21))), 0.5);
+        assert_eq!(query.factor(Some(day(14))), 0.25);
+        // Documents from the future, or without a date, aren't decayed.
+        assert_eq!(query.factor(Some(day(35))), 1.0);
+        assert_eq!(query.factor(None), 1.0);
+    }
+}
@@ -0,0 +1,296 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use crate::schema::{decode_vector, VectorMetric};
+use std::collections::HashMap;
+use tantivy::collector::TopDocs;
+use tantivy::query::{EnableScoring, Explanation, Query, QueryClone, Scorer, Weight};
+use tantivy::schema::Field;
+use tantivy::{DocId, DocSet, Score, SegmentId, SegmentReader, TantivyError, Term, TERMINATED};
+
+/// Rescores the `top_n` best documents of a query by the similarity of their vector, read
+/// from the bytes fast field of `field`, to a query vector. Only those documents match, so
+/// it's the last stage of a search: it's the similarity that orders the results.
+#[derive(Debug)]
+pub struct RerankByVectorQuery {
+    query: Box<dyn Query>,
+    field: Field,
+    vector: Vec<f32>,
+    metric: VectorMetric,
+    top_n: usize,
+}
+
+impl RerankByVectorQuery {
+    pub fn new(
+        query: Box<dyn Query>,
+        field: Field,
+        vector: Vec<f32>,
+        metric: VectorMetric,
+        top_n: usize,
+    ) -> Self {
+        Self {
+            query,
+            field,
+            vector,
+            metric,
+            top_n,
+        }
+    }
+}
+
+impl Clone for RerankByVectorQuery {
+    fn clone(&self) -> Self {
+        Self {
+            query: self.query.box_clone(),
+            field: self.field,
+            vector: self.vector.clone(),
+            metric: self.metric,
+            top_n: self.top_n,
+        }
+    }
+}
+
+impl Query for RerankByVectorQuery {
+    fn weight(&self, enable_scoring: EnableScoring<'_>) -> tantivy::Result<Box<dyn Weight>> {
+        // The top documents are picked across all segments, so they're found up front rather
+        // than segment by segment.
+        let searcher = enable_scoring.searcher().ok_or_else(|| {
+            TantivyError::InvalidArgument("reranking by vector needs a searcher".into())
+        })?;
+        let field_name = searcher.schema().get_field_name(self.field).to_string();
+        let top_docs = searcher.search(&*self.query, &TopDocs::with_limit(self.top_n))?;
+
+        let mut scores: HashMap<SegmentId, Vec<(DocId, Score)>> = HashMap::new();
+        let mut bytes = vec![];
+        for (_, address) in top_docs {
+            let segment_reader = searcher.segment_reader(address.segment_ord);
+            let Some(column) = segment_reader.fast_fields().bytes(&field_name)? else {
+                continue;
+            };
+            let Some(ord) = column.term_ords(address.doc_id).next() else {
+                continue;
+            };
+            bytes.clear();
+            if !column.ord_to_bytes(ord, &mut bytes)? {
+                continue;
+            }
+            // A vector with another number of dimensions can't be compared to the query's.
+            let vector = decode_vector(&bytes);
+            if vector.len() != self.vector.len() {
+                continue;
+            }
+            scores
+                .entry(segment_reader.segment_id())
+                .or_default()
+                .push((
+                    address.doc_id,
+                    self.metric.similarity(&vector, &self.vector),
+                ));
+        }
+        for docs in scores.values_mut() {
+            docs.sort_unstable_by_key(|(doc, _)| *doc);
+        }
+
+        Ok(Box::new(RerankByVectorWeight { scores }))
+    }
+
+    fn query_terms<'a>(&'a self, visitor: &mut dyn FnMut(&'a Term, bool)) {
+        self.query.query_terms(visitor)
+    }
+}
+
+struct RerankByVectorWeight {
+    scores: HashMap<SegmentId, Vec<(DocId, Score)>>,
+}
+
+impl RerankByVectorWeight {
+    fn segment_scores(&self, reader: &SegmentReader) -> Vec<(DocId, Score)> {
+        self.scores
+            .get(&reader.segment_id())
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+impl Weight for RerankByVectorWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        Ok(Box::new(SortedScorer::new(
+            self.segment_scores(reader),
+            boost,
+        )))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        match self
+            .segment_scores(reader)
+            .into_iter()
+            .find(|(other, _)| *other == doc)
+        {
+            Some((_, score)) => Ok(Explanation::new("vector similarity", score)),
+            None => Err(TantivyError::InvalidArgument(format!(
+                "document #({doc}) is not among the reranked documents"
+            ))),
+        }
+    }
+}
+
+/// Documents scored up front, in the order of their ids.
+pub(super) struct SortedScorer {
+    scores: Vec<(DocId, Score)>,
+    position: usize,
+    boost: Score,
+}
+
+impl SortedScorer {
+    pub(super) fn new(scores: Vec<(DocId, Score)>, boost: Score) -> Self {
+        Self {
+            scores,
+            position: 0,
+            boost,
+        }
+    }
+}
+
+impl DocSet for SortedScorer {
+    fn advance(&mut self) -> DocId {
+        if self.position < self.scores.len() {
+            self.position += 1;
+        }
+        self.doc()
+    }
+
+    fn doc(&self) -> DocId {
+        self.scores
+            .get(self.position)
+            .map_or(TERMINATED, |(doc, _)| *doc)
+    }
+
+    fn size_hint(&self) -> u32 {
+        (self.scores.len() - self.position) as u32
+    }
+}
+
+impl Scorer for SortedScorer {
+    fn score(&mut self) -> Score {
+        self.scores
+            .get(self.position)
+            .map_or(0.0, |(_, score)| score * self.boost)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RerankByVectorQuery;
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::schema::{encode_vector, SearchFieldConfig, SearchFieldName, SearchFieldType};
+    use crate::schema::{SearchIndexSchema, VectorMetric};
+    use rstest::*;
+    use tantivy::collector::TopDocs;
+    use tantivy::query::AllQuery;
+    use tantivy::schema::{OwnedValue, Value};
+    use tantivy::TantivyDocument;
+
+    #[fixture]
+    fn vector_index(
+        mut default_fields: Vec<(SearchFieldName, SearchFieldConfig, SearchFieldType)>,
+    ) -> MockSearchIndex {
+        default_fields.push((
+            "embedding".into(),
+            SearchFieldConfig::default_vector(),
+            SearchFieldType::Vector,
+        ));
+        MockSearchIndex::new(default_fields, 0)
+    }
+
+    fn ids(
+        schema: &SearchIndexSchema,
+        index: &SearchIndex,
+        query: RerankByVectorQuery,
+    ) -> Vec<i64> {
+        let searcher = index.searcher();
+        let key_field = schema.key_field().id.0;
+        searcher
+            .search(&query, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(_, address)| {
+                let document: TantivyDocument = searcher.doc(address).unwrap();
+                document.get_first(key_field).unwrap().as_i64().unwrap()
+            })
+            .collect()
+    }
+
+    #[rstest]
+    fn test_rerank_by_vector(vector_index: MockSearchIndex) {
+        let index = vector_index.index;
+        let schema = index.schema.clone();
+        let field = |name: &str| schema.get_search_field(name).unwrap().id;
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        for (id, description, vector) in [
+            (1, "Ergonomic metal keyboard", Some(vec![1.0, 0.0])),
+            (2, "Plastic keyboard", Some(vec![0.0, 1.0])),
+            (3, "Wireless keyboard", Some(vec![0.6, 0.8])),
+            (4, "Mechanical keyboard", None),
+            (
+                5,
+                "Keyboard with three dimensions",
+                Some(vec![0.0, 1.0, 0.0]),
+            ),
+        ] {
+            let mut document = schema.new_document();
+            document.insert(schema.key_field().id, id.into());
+            document.insert(field("description"), description.into());
+            if let Some(vector) = vector {
+                document.insert(
+                    field("embedding"),
+                    OwnedValue::Bytes(encode_vector(&vector)),
+                );
+            }
+            writer.add_document(document.into()).unwrap();
+        }
+        writer.commit().unwrap();
+        index.reader.reload().unwrap();
+
+        let embedding = field("embedding").0;
+        let rerank = |vector: Vec<f32>, top_n: usize| {
+            RerankByVectorQuery::new(
+                Box::new(AllQuery),
+                embedding,
+                vector,
+                VectorMetric::Cosine,
+                top_n,
+            )
+        };
+        // Documents without a vector, or with a vector of another size, are left out.
+        assert_eq!(
+            ids(&schema, index, rerank(vec![0.0, 2.0], 10)),
+            vec![2, 3, 1]
+        );
+        assert_eq!(
+            ids(&schema, index, rerank(vec![2.0, 0.0], 10)),
+            vec![1, 3, 2]
+        );
+        // Only the top documents of the query are reranked.
+        let query = index
+            .query_parser()
+            .parse_query("description:plastic")
+            .unwrap();
+        let query =
+            RerankByVectorQuery::new(query, embedding, vec![1.0, 0.0], VectorMetric::Dot, 10);
+        assert_eq!(ids(&schema, index, query), vec![2]);
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::SearchQueryInput;
+
+impl SearchQueryInput {
+    /// Simplifies the query tree before it's compiled, for the deeply nested trees that
+    /// query builders generate. Nested boolean queries are flattened into their parent where
+    /// that matches the same documents with the same scores, clauses that match nothing are
+    /// dropped, and where the scores of a subtree don't count, like under a `ConstScore` or
+    /// in `must_not`, boosts are dropped and the field terms of a disjunction are merged into
+    /// a single `TermSet`.
+    pub fn rewrite(self, scored: bool) -> Self {
+        match self {
+            Self::Boolean {
+                must,
+                should,
+                must_not,
+            } => rewrite_boolean(must, should, must_not, scored),
+            Self::Boost { query, boost } => match query.rewrite(scored) {
+                Self::Empty => Self::Empty,
+                query if !scored => query,
+                query => Self::Boost {
+                    query: Box::new(query),
+                    boost,
+                },
+            },
+            Self::ConstScore { query, score } => match query.rewrite(false) {
+                Self::Empty => Self::Empty,
+                query if !scored => query,
+                query => Self::ConstScore {
+                    query: Box::new(query),
+                    score,
+                },
+            },
+            // Without scores, a disjunction max query is a disjunction.
+            Self::DisjunctionMax { disjuncts, .. } if !scored => {
+                rewrite_boolean(vec![], disjuncts, vec![], scored)
+            }
+            Self::DisjunctionMax {
+                disjuncts,
+                tie_breaker,
+            } => {
+                let disjuncts: Vec<_> = disjuncts
+                    .into_iter()
+                    .map(|query| query.rewrite(scored))
+                    .filter(|query| query != &Self::Empty)
+                    .collect();
+                if disjuncts.is_empty() {
+                    Self::Empty
+                } else {
+                    Self::DisjunctionMax {
+                        disjuncts,
+                        tie_breaker,
+                    }
+                }
+            }
+            // The keys of the other index are all that the join reads from its query.
+            Self::KeyIn {
+                field,
+                index,
+                query,
+            } => Self::KeyIn {
+                field,
+                index,
+                query: Box::new(query.rewrite(false)),
+            },
+            // Named queries are kept, even when they match nothing, for `matched_queries`.
+            Self::Named { name, query } => Self::Named {
+                name,
+                query: Box::new(query.rewrite(scored)),
+            },
+            Self::RankingProfile { query, profile } => Self::RankingProfile {
+                query: Box::new(query.rewrite(scored)),
+                profile,
+            },
+            // The best results of the query are reranked, so its scores count either way.
+            Self::RerankByVector {
+                query,
+                field,
+                vector,
+                metric,
+                top_n,
+            } => Self::RerankByVector {
+                query: Box::new(query.rewrite(true)),
+                field,
+                vector,
+                metric,
+                top_n,
+            },
+            query => query,
+        }
+    }
+}
+
+/// A boolean query scores the sum of the scores of its `must` and `should` clauses, so a
+/// nested conjunction can join the `must` clauses of its parent, and a nested disjunction
+/// the `should` clauses. A boolean query without `must` or `should` clauses matches nothing.
+fn rewrite_boolean(
+    must: Vec<SearchQueryInput>,
+    should: Vec<SearchQueryInput>,
+    must_not: Vec<SearchQueryInput>,
+    scored: bool,
+) -> SearchQueryInput {
+    let (mut flat_must, mut flat_should, mut flat_must_not) = (vec![], vec![], vec![]);
+
+    for query in must {
+        match query.rewrite(scored) {
+            SearchQueryInput::Empty => return SearchQueryInput::Empty,
+            SearchQueryInput::Boolean {
+                must,
+                should,
+                must_not,
+            } if should.is_empty() && !must.is_empty() => {
+                flat_must.extend(must);
+                flat_must_not.extend(must_not);
+            }
+            query => flat_must.push(query),
+        }
+    }
+
+    for query in should {
+        match query.rewrite(scored) {
+            SearchQueryInput::Empty => {}
+            SearchQueryInput::Boolean {
+                must,
+                should,
+                must_not,
+            } if must.is_empty() && must_not.is_empty() => flat_should.extend(should),
+            query => flat_should.push(query),
+        }
+    }
+
+    // Excluding any document of a disjunction excludes the documents of each of its clauses.
+    for query in must_not {
+        match query.rewrite(false) {
+            SearchQueryInput::Empty => {}
+            SearchQueryInput::Boolean {
+                must,
+                should,
+                must_not,
+            } if must.is_empty() && must_not.is_empty() => flat_must_not.extend(should),
+            query => flat_must_not.push(query),
+        }
+    }
+
+    if flat_must.is_empty() && flat_should.is_empty() {
+        return SearchQueryInput::Empty;
+    }
+
+    // A term set scores its documents alike, so it only replaces the terms of a disjunction
+    // where they aren't scored.
+    if !scored {
+        flat_should = merge_terms(flat_should);
+    }
+    flat_must_not = merge_terms(flat_must_not);
+
+    match (flat_must.len(), flat_should.len(), flat_must_not.len()) {
+        (1, 0, 0) => flat_must.pop().unwrap(),
+        (0, 1, 0) => flat_should.pop().unwrap(),
+        _ => SearchQueryInput::Boolean {
+            must: flat_must,
+            should: flat_should,
+            must_not: flat_must_not,
+        },
+    }
+}
+
+/// Merges the terms and term sets of a disjunction into a single term set, in place of the
+/// first of them.
+fn merge_terms(queries: Vec<SearchQueryInput>) -> Vec<SearchQueryInput> {
+    let is_term = |query: &SearchQueryInput| {
+        matches!(
+            query,
+            SearchQueryInput::Term { field: Some(_), .. } | SearchQueryInput::TermSet { .. }
+        )
+    };
+    if queries.iter().filter(|query| is_term(query)).count() < 2 {
+        return queries;
+    }
+
+    let mut merged = vec![];
+    let mut terms = vec![];
+    let mut position = None;
+    for query in queries {
+        match query {
+            SearchQueryInput::Term {
+                field: Some(field),
+                value,
+            } => {
+                position.get_or_insert(merged.len());
+                terms.push((field, value));
+            }
+            SearchQueryInput::TermSet { terms: set } => {
+                position.get_or_insert(merged.len());
+                terms.extend(set);
+            }
+            query => merged.push(query),
+        }
+    }
+    merged.insert(
+        position.unwrap_or_default(),
+        SearchQueryInput::TermSet { terms },
+    );
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+    use tantivy::schema::Value;
+
+    fn term(field: &str, value: &str) -> SearchQueryInput {
+        SearchQueryInput::Term {
+            field: Some(field.into()),
+            value: Value::Str(value.into()),
+        }
+    }
+
+    fn boolean(
+        must: Vec<SearchQueryInput>,
+        should: Vec<SearchQueryInput>,
+        must_not: Vec<SearchQueryInput>,
+    ) -> SearchQueryInput {
+        SearchQueryInput::Boolean {
+            must,
+            should,
+            must_not,
+        }
+    }
+
+    #[rstest]
+    fn test_flatten_nested_booleans() {
+        let query = boolean(
+            vec![
+                boolean(
+                    vec![term("a", "1"), term("b", "2")],
+                    vec![],
+                    vec![term("c", "3")],
+                ),
+                boolean(vec![term("d", "4")], vec![], vec![]),
+            ],
+            vec![boolean(
+                vec![],
+                vec![term("e", "5"), term("f", "6")],
+                vec![],
+            )],
+            vec![],
+        );
+        assert_eq!(
+            query.rewrite(true),
+            boolean(
+                vec![term("a", "1"), term("b", "2"), term("d", "4")],
+                vec![term("e", "5"), term("f", "6")],
+                vec![term("c", "3")],
+            )
+        );
+
+        // Only excluded clauses match nothing, which the parent can't take over.
+        let negation = boolean(vec![], vec![], vec![term("a", "1")]);
+        let query = boolean(vec![term("b", "2"), negation.clone()], vec![], vec![]);
+        assert_eq!(query.clone().rewrite(true), query);
+        assert_eq!(negation.rewrite(true), SearchQueryInput::Empty);
+    }
+
+    #[rstest]
+    fn test_drop_empty_clauses() {
+        let query = boolean(
+            vec![term("a", "1")],
+            vec![SearchQueryInput::Empty],
+            vec![SearchQueryInput::Empty],
+        );
+        assert_eq!(query.rewrite(true), term("a", "1"));
+
+        let query = boolean(
+            vec![term("a", "1"), SearchQueryInput::Empty],
+            vec![],
+            vec![],
+        );
+        assert_eq!(query.rewrite(true), SearchQueryInput::Empty);
+
+        let query = SearchQueryInput::Boost {
+            query: Box::new(boolean(vec![], vec![SearchQueryInput::Empty], vec![])),
+            boost: 2.0,
+        };
+        assert_eq!(query.rewrite(true), SearchQueryInput::Empty);
+    }
+
+    #[rstest]
+    fn test_merge_unscored_terms() {
+        let query = boolean(
+            vec![term("a", "1")],
+            vec![term("b", "2"), term("b", "3")],
+            vec![term("c", "4"), term("c", "5")],
+        );
+        // The terms of `should` are scored.
+        assert_eq!(
+            query.clone().rewrite(true),
+            boolean(
+                vec![term("a", "1")],
+                vec![term("b", "2"), term("b", "3")],
+                vec![SearchQueryInput::TermSet {
+                    terms: vec![
+                        ("c".into(), Value::Str("4".into())),
+                        ("c".into(), Value::Str("5".into())),
+                    ]
+                }],
+            )
+        );
+
+        let query = SearchQueryInput::ConstScore {
+            query: Box::new(query),
+            score: 1.0,
+        };
+        let SearchQueryInput::ConstScore { query, .. } = query.rewrite(true) else {
+            panic!("the constant score should be kept");
+        };
+        let SearchQueryInput::Boolean { should, .. } = *query else {
+            panic!("the boolean query should be kept");
+        };
+        assert_eq!(
+            should,
+            vec![SearchQueryInput::TermSet {
+                terms: vec![
+                    ("b".into(), Value::Str("2".into())),
+                    ("b".into(), Value::Str("3".into())),
+                ]
+            }]
+        );
+    }
+
+    #[rstest]
+    fn test_push_down_const_score() {
+        let query = SearchQueryInput::ConstScore {
+            query: Box::new(SearchQueryInput::ConstScore {
+                query: Box::new(SearchQueryInput::Boost {
+                    query: Box::new(term("a", "1")),
+                    boost: 2.0,
+                }),
+                score: 3.0,
+            }),
+            score: 1.0,
+        };
+        assert_eq!(
+            query.rewrite(true),
+            SearchQueryInput::ConstScore {
+                query: Box::new(term("a", "1")),
+                score: 1.0,
+            }
+        );
+    }
+}
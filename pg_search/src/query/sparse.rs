@@ -0,0 +1,179 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use super::rerank::SortedScorer;
+use crate::schema::SPARSE_WEIGHT_SCALE;
+use std::collections::BTreeMap;
+use tantivy::postings::Postings;
+use tantivy::query::{EnableScoring, Explanation, Query, Scorer, Weight};
+use tantivy::schema::{Field, IndexRecordOption};
+use tantivy::{DocId, DocSet, Score, SegmentReader, Term, TERMINATED};
+
+/// Scores the documents of a sparse vector field by the dot product of their weights with
+/// the weights of a query, read from the frequencies of the terms in their postings. The
+/// documents that share no term with the query don't match.
+#[derive(Clone, Debug)]
+pub struct SparseVectorQuery {
+    terms: Vec<(Term, Score)>,
+}
+
+impl SparseVectorQuery {
+    pub fn new(field: Field, weights: Vec<(String, f32)>) -> Self {
+        Self {
+            terms: weights
+                .into_iter()
+                .filter(|(_, weight)| *weight != 0.0)
+                .map(|(term, weight)| (Term::from_field_text(field, &term), weight))
+                .collect(),
+        }
+    }
+}
+
+impl Query for SparseVectorQuery {
+    fn weight(&self, _enable_scoring: EnableScoring<'_>) -> tantivy::Result<Box<dyn Weight>> {
+        Ok(Box::new(SparseVectorWeight {
+            terms: self.terms.clone(),
+        }))
+    }
+
+    fn query_terms<'a>(&'a self, visitor: &mut dyn FnMut(&'a Term, bool)) {
+        for (term, _) in &self.terms {
+            visitor(term, false);
+        }
+    }
+}
+
+struct SparseVectorWeight {
+    terms: Vec<(Term, Score)>,
+}
+
+impl SparseVectorWeight {
+    /// The dot products of the documents of a segment, in the order of their ids.
+    fn segment_scores(&self, reader: &SegmentReader) -> tantivy::Result<Vec<(DocId, Score)>> {
+        let mut scores = BTreeMap::new();
+        for (term, weight) in &self.terms {
+            let inverted_index = reader.inverted_index(term.field())?;
+            let Some(mut postings) =
+                inverted_index.read_postings(term, IndexRecordOption::WithFreqs)?
+            else {
+                continue;
+            };
+            let mut doc = postings.doc();
+            while doc != TERMINATED {
+                let document_weight = postings.term_freq() as Score / SPARSE_WEIGHT_SCALE;
+                *scores.entry(doc).or_insert(0.0) += weight * document_weight;
+                doc = postings.advance();
+            }
+        }
+        Ok(scores.into_iter().collect())
+    }
+}
+
+impl Weight for SparseVectorWeight {
+    fn scorer(&self, reader: &SegmentReader, boost: Score) -> tantivy::Result<Box<dyn Scorer>> {
+        Ok(Box::new(SortedScorer::new(
+            self.segment_scores(reader)?,
+            boost,
+        )))
+    }
+
+    fn explain(&self, reader: &SegmentReader, doc: DocId) -> tantivy::Result<Explanation> {
+        match self
+            .segment_scores(reader)?
+            .into_iter()
+            .find(|(other, _)| *other == doc)
+        {
+            Some((_, score)) => Ok(Explanation::new("sparse vector dot product", score)),
+            None => Err(tantivy::TantivyError::InvalidArgument(format!(
+                "document #({doc}) does not match"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SparseVectorQuery;
+    use crate::fixtures::*;
+    use crate::index::SearchIndex;
+    use crate::schema::{sparse_tokens, SearchFieldConfig, SearchFieldName, SearchFieldType};
+    use rstest::*;
+    use tantivy::collector::TopDocs;
+    use tantivy::schema::{OwnedValue, Value};
+    use tantivy::TantivyDocument;
+
+    #[fixture]
+    fn sparse_index(
+        mut default_fields: Vec<(SearchFieldName, SearchFieldConfig, SearchFieldType)>,
+    ) -> MockSearchIndex {
+        default_fields.push((
+            "splade".into(),
+            SearchFieldConfig::default_sparse(),
+            SearchFieldType::Sparse,
+        ));
+        MockSearchIndex::new(default_fields, 0)
+    }
+
+    #[rstest]
+    fn test_sparse_vector_query(sparse_index: MockSearchIndex) {
+        let index = sparse_index.index;
+        let schema = index.schema.clone();
+        let splade = schema.get_search_field("splade").unwrap().id;
+        let mut writer = SearchIndex::writer(&index.directory).unwrap();
+        for (id, weights) in [
+            (1, vec![("keyboard", 1.5), ("metal", 0.5)]),
+            (2, vec![("keyboard", 0.5), ("plastic", 2.0)]),
+            (3, vec![("shoes", 2.5)]),
+        ] {
+            let weights: Vec<_> = weights
+                .into_iter()
+                .map(|(term, weight)| (term.to_string(), weight))
+                .collect();
+            let mut document = schema.new_document();
+            document.insert(schema.key_field().id, id.into());
+            document.insert(
+                splade,
+                OwnedValue::PreTokStr(sparse_tokens(&weights).unwrap()),
+            );
+            writer.add_document(document.into()).unwrap();
+        }
+        writer.commit().unwrap();
+        index.reader.reload().unwrap();
+
+        let searcher = index.searcher();
+        let query = SparseVectorQuery::new(
+            splade.0,
+            vec![("keyboard".into(), 1.0), ("plastic".into(), 0.25)],
+        );
+        let results: Vec<(i64, f32)> = searcher
+            .search(&query, &TopDocs::with_limit(10))
+            .unwrap()
+            .into_iter()
+            .map(|(score, address)| {
+                let document: TantivyDocument = searcher.doc(address).unwrap();
+                let id = document.get_first(schema.key_field().id.0).unwrap();
+                (id.as_i64().unwrap(), score)
+            })
+            .collect();
+        // 1.0 * 1.5 for the first, 1.0 * 0.5 + 0.25 * 2.0 for the second, and the third
+        // shares no term with the query.
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, 1);
+        assert!((results[0].1 - 1.5).abs() < 1e-6);
+        assert_eq!(results[1].0, 2);
+        assert!((results[1].1 - 1.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,441 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::SearchQueryInput;
+use crate::globals::QUERY_STATS;
+use once_cell::sync::Lazy;
+use pgrx::{pg_guard, pg_sys, PGRXSharedMemory};
+use serde_json::Value;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::ffi::c_int;
+use std::hash::{Hash, Hasher};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How many query shapes are tracked. When the table is full, the least called shape is
+/// evicted to make room for a new one. Must be a power of two.
+pub const MAX_TRACKED_QUERIES: usize = 512;
+/// Longer query shapes are truncated, but still tracked apart by their fingerprint.
+const MAX_QUERY_LEN: usize = 256;
+/// Postgres' NAMEDATALEN, the longest an index name can be.
+const MAX_INDEX_NAME_LEN: usize = 64;
+/// Latencies are counted in buckets that grow by a factor of 2^(1/4), so that percentiles
+/// are estimated to within 19%. The last bucket ends after about an hour.
+const LATENCY_BUCKETS_PER_DOUBLING: f64 = 4.0;
+const LATENCY_BUCKETS: usize = 128;
+/// How often a connection adds the statistics of its searches to the shared table, so that
+/// searches don't all wait on its lock.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The statistics of the searches of this connection since it last added them to
+/// `QUERY_STATS`.
+static PENDING_QUERY_STATS: Lazy<Mutex<PendingQueryStats>> = Lazy::new(|| {
+    // The last searches of a connection are added when it exits.
+    unsafe { pg_sys::before_shmem_exit(Some(flush_on_exit), pg_sys::Datum::from(0usize)) };
+    Mutex::new(PendingQueryStats::default())
+});
+
+/// Count a search of `query` against `index_name` that took `duration` and returned `rows`
+/// rows. It's added to the statistics of every connection within `FLUSH_INTERVAL`.
+pub fn record_search(
+    database: pg_sys::Oid,
+    index_name: &str,
+    query: &str,
+    duration: Duration,
+    rows: u64,
+) {
+    let mut pending = PENDING_QUERY_STATS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    pending.record(database, index_name, query, duration, rows);
+    if pending.last_flush.elapsed() >= FLUSH_INTERVAL {
+        pending.flush_into(&mut QUERY_STATS.exclusive());
+    }
+}
+
+/// Add the statistics of the searches of this connection to the shared table right away,
+/// so that they're included when it reads the table.
+pub fn flush_pending_searches() {
+    let mut pending = PENDING_QUERY_STATS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    if !pending.entries.is_empty() {
+        pending.flush_into(&mut QUERY_STATS.exclusive());
+    }
+}
+
+/// Forget the statistics of the searches of this connection in `database` that weren't
+/// added to the shared table, when it's reset.
+pub fn discard_pending_searches(database: pg_sys::Oid) {
+    PENDING_QUERY_STATS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entries
+        .retain(|_, entry| entry.database != database);
+}
+
+#[pg_guard]
+unsafe extern "C" fn flush_on_exit(_code: c_int, _arg: pg_sys::Datum) {
+    flush_pending_searches();
+}
+
+/// Statistics of the searches run against each query shape, kept in shared memory so that
+/// they cover every connection. See `query_shape` for how queries are grouped. Indexes of
+/// different databases can have the same name, so they are tracked apart.
+#[derive(Default)]
+pub struct QueryStatsTable {
+    entries: heapless::FnvIndexMap<u64, QueryStatsEntry, MAX_TRACKED_QUERIES>,
+}
+
+unsafe impl PGRXSharedMemory for QueryStatsTable {}
+
+#[derive(Clone)]
+pub struct QueryStatsEntry {
+    pub database: pg_sys::Oid,
+    pub index_name: heapless::String<MAX_INDEX_NAME_LEN>,
+    pub query: heapless::String<MAX_QUERY_LEN>,
+    pub calls: u64,
+    pub total_time: Duration,
+    pub rows: u64,
+    /// Counts are 32 bits wide to keep the table small, and saturate.
+    latency_histogram: [u32; LATENCY_BUCKETS],
+}
+
+impl Default for QueryStatsEntry {
+    fn default() -> Self {
+        Self {
+            database: pg_sys::InvalidOid,
+            index_name: heapless::String::new(),
+            query: heapless::String::new(),
+            calls: 0,
+            total_time: Duration::ZERO,
+            rows: 0,
+            latency_histogram: [0; LATENCY_BUCKETS],
+        }
+    }
+}
+
+impl QueryStatsTable {
+    /// Count a search of `query` against `index_name` that took `duration` and returned
+    /// `rows` rows.
+    pub fn record(
+        &mut self,
+        database: pg_sys::Oid,
+        index_name: &str,
+        query: &str,
+        duration: Duration,
+        rows: u64,
+    ) {
+        let mut entry = QueryStatsEntry::new(database, index_name, query);
+        entry.record(duration, rows);
+        self.add(fingerprint(database, index_name, query), &entry);
+    }
+
+    /// Add the statistics of `entry` to those of the same query shape.
+    fn add(&mut self, fingerprint: u64, entry: &QueryStatsEntry) {
+        if !self.entries.contains_key(&fingerprint) && self.entries.len() == MAX_TRACKED_QUERIES {
+            let least_called = self
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.calls)
+                .map(|(fingerprint, _)| *fingerprint);
+            if let Some(least_called) = least_called {
+                self.entries.remove(&least_called);
+            }
+        }
+
+        if !self.entries.contains_key(&fingerprint) {
+            let empty = QueryStatsEntry {
+                database: entry.database,
+                index_name: entry.index_name.clone(),
+                query: entry.query.clone(),
+                ..QueryStatsEntry::default()
+            };
+            // There is room for the entry, since one was evicted if the table was full.
+            let _ = self.entries.insert(fingerprint, empty);
+        }
+
+        if let Some(existing) = self.entries.get_mut(&fingerprint) {
+            existing.merge(entry);
+        }
+    }
+
+    pub fn reset(&mut self, database: pg_sys::Oid) {
+        let fingerprints: Vec<u64> = self
+            .entries(database)
+            .map(|(fingerprint, _)| fingerprint)
+            .collect();
+        for fingerprint in fingerprints {
+            self.entries.remove(&fingerprint);
+        }
+    }
+
+    pub fn entries(&self, database: pg_sys::Oid) -> impl Iterator<Item = (u64, &QueryStatsEntry)> {
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| entry.database == database)
+            .map(|(fingerprint, entry)| (*fingerprint, entry))
+    }
+}
+
+impl QueryStatsEntry {
+    fn new(database: pg_sys::Oid, index_name: &str, query: &str) -> Self {
+        Self {
+            database,
+            index_name: truncated(index_name),
+            query: truncated(query),
+            ..Self::default()
+        }
+    }
+
+    fn record(&mut self, duration: Duration, rows: u64) {
+        self.calls += 1;
+        self.total_time += duration;
+        self.rows += rows;
+        let count = &mut self.latency_histogram[latency_bucket(duration)];
+        *count = count.saturating_add(1);
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.calls += other.calls;
+        self.total_time += other.total_time;
+        self.rows += other.rows;
+        for (count, other) in self
+            .latency_histogram
+            .iter_mut()
+            .zip(other.latency_histogram.iter())
+        {
+            *count = count.saturating_add(*other);
+        }
+    }
+
+    pub fn mean_time(&self) -> Duration {
+        self.total_time / self.calls.max(1) as u32
+    }
+
+    /// The latency under which a `fraction` of the calls completed, estimated as the upper
+    /// bound of the histogram bucket that holds it.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let rank = (self.calls as f64 * fraction).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (bucket, count) in self.latency_histogram.iter().enumerate() {
+            seen += *count as u64;
+            if seen >= rank {
+                return latency_bucket_bound(bucket);
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+/// The statistics of the searches of a connection, waiting to be added to the shared table.
+struct PendingQueryStats {
+    entries: HashMap<u64, QueryStatsEntry>,
+    last_flush: Instant,
+}
+
+impl Default for PendingQueryStats {
+    fn default() -> Self {
+        Self {
+            entries: HashMap::new(),
+            last_flush: Instant::now(),
+        }
+    }
+}
+
+impl PendingQueryStats {
+    fn record(
+        &mut self,
+        database: pg_sys::Oid,
+        index_name: &str,
+        query: &str,
+        duration: Duration,
+        rows: u64,
+    ) {
+        self.entries
+            .entry(fingerprint(database, index_name, query))
+            .or_insert_with(|| QueryStatsEntry::new(database, index_name, query))
+            .record(duration, rows);
+    }
+
+    fn flush_into(&mut self, table: &mut QueryStatsTable) {
+        for (fingerprint, entry) in self.entries.drain() {
+            table.add(fingerprint, &entry);
+        }
+        self.last_flush = Instant::now();
+    }
+}
+
+/// The shape of a search query: the query with its values replaced by `?`, so that searches
+/// which only differ by their terms, bounds or boosts are counted together. Field names are
+/// kept, since queries over different fields usually perform differently.
+pub fn query_shape(query: &SearchQueryInput) -> String {
+    let mut value = serde_json::to_value(query).unwrap_or_default();
+    normalize(&mut value, None);
+    value.to_string()
+}
+
+/// Replace the values of a serialized query. `key` is the name of the field holding `value`,
+/// or `None` where a query is expected, like at the top level or in a list of clauses.
+fn normalize(value: &mut Value, key: Option<&str>) {
+    match value {
+        Value::Object(entries) => {
+            for (key, value) in entries.iter_mut() {
+                normalize(value, Some(key));
+            }
+        }
+        // Clauses of a boolean or disjunction query are queries themselves.
+        Value::Array(items)
+            if matches!(key, Some("must" | "should" | "must_not" | "disjuncts")) =>
+        {
+            for item in items.iter_mut() {
+                normalize(item, None);
+            }
+        }
+        // Unit variants like "All", unbounded range bounds, and the names of named queries are
+        // part of the shape.
+        Value::String(_)
+            if matches!(
+                key,
+                None | Some("field" | "lower_bound" | "upper_bound" | "name")
+            ) => {}
+        Value::Null => {}
+        _ => *value = Value::String("?".into()),
+    }
+}
+
+fn fingerprint(database: pg_sys::Oid, index_name: &str, query: &str) -> u64 {
+    // The default hasher uses the same keys in every process, which is required for the
+    // backends to agree on fingerprints.
+    let mut hasher = DefaultHasher::new();
+    database.hash(&mut hasher);
+    index_name.hash(&mut hasher);
+    query.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn truncated<const N: usize>(text: &str) -> heapless::String<N> {
+    let mut end = text.len().min(N);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut truncated = heapless::String::new();
+    let _ = truncated.push_str(&text[..end]);
+    truncated
+}
+
+fn latency_bucket(duration: Duration) -> usize {
+    let micros = duration.as_micros().max(1) as f64;
+    ((micros.log2() * LATENCY_BUCKETS_PER_DOUBLING) as usize).min(LATENCY_BUCKETS - 1)
+}
+
+fn latency_bucket_bound(bucket: usize) -> Duration {
+    let micros = 2f64.powf((bucket + 1) as f64 / LATENCY_BUCKETS_PER_DOUBLING);
+    Duration::from_secs_f64(micros / 1_000_000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{query_shape, PendingQueryStats, QueryStatsTable};
+    use crate::query::SearchQueryInput;
+    use pgrx::pg_sys;
+    use std::time::Duration;
+
+    #[test]
+    fn test_query_shape() {
+        let keyboard = SearchQueryInput::Boolean {
+            must: vec![SearchQueryInput::All],
+            should: vec![SearchQueryInput::Phrase {
+                field: "description".into(),
+                phrases: vec!["ergonomic".into(), "keyboard".into()],
+                slop: Some(1),
+            }],
+            must_not: vec![],
+        };
+        let shoes = SearchQueryInput::Boolean {
+            must: vec![SearchQueryInput::All],
+            should: vec![SearchQueryInput::Phrase {
+                field: "description".into(),
+                phrases: vec!["running".into(), "shoes".into(), "sale".into()],
+                slop: Some(3),
+            }],
+            must_not: vec![],
+        };
+        assert_eq!(query_shape(&keyboard), query_shape(&shoes));
+        assert!(query_shape(&keyboard).contains("description"));
+        assert!(!query_shape(&keyboard).contains("keyboard"));
+
+        let category = SearchQueryInput::Phrase {
+            field: "category".into(),
+            phrases: vec!["keyboard".into()],
+            slop: None,
+        };
+        assert_ne!(query_shape(&category), query_shape(&keyboard));
+    }
+
+    #[test]
+    fn test_query_stats() {
+        let (database, other_database) = (pg_sys::Oid::from(1), pg_sys::Oid::from(2));
+        let mut table = QueryStatsTable::default();
+        for millis in 1..=100 {
+            table.record(database, "index", "{}", Duration::from_millis(millis), 2);
+        }
+        table.record(database, "other_index", "{}", Duration::from_millis(1), 0);
+        table.record(other_database, "index", "{}", Duration::from_millis(1), 0);
+
+        let (_, entry) = table
+            .entries(database)
+            .find(|(_, entry)| entry.index_name == "index")
+            .unwrap();
+        assert_eq!(entry.calls, 100);
+        assert_eq!(entry.rows, 200);
+        assert_eq!(entry.mean_time(), Duration::from_micros(50_500));
+        // Percentiles are upper bounds, within a bucket of the exact value.
+        let p50 = entry.percentile(0.5);
+        assert!(p50 >= Duration::from_millis(50) && p50 < Duration::from_millis(60));
+        let p99 = entry.percentile(0.99);
+        assert!(p99 >= Duration::from_millis(99) && p99 < Duration::from_millis(118));
+
+        // Resetting a database keeps the statistics of the others.
+        table.reset(database);
+        assert_eq!(table.entries(database).count(), 0);
+        assert_eq!(table.entries(other_database).count(), 1);
+    }
+
+    #[test]
+    fn test_pending_query_stats() {
+        let database = pg_sys::Oid::from(1);
+        let mut table = QueryStatsTable::default();
+        table.record(database, "index", "{}", Duration::from_millis(1), 1);
+
+        let mut pending = PendingQueryStats::default();
+        for millis in 2..=3 {
+            pending.record(database, "index", "{}", Duration::from_millis(millis), 2);
+        }
+        pending.flush_into(&mut table);
+        assert!(pending.entries.is_empty());
+
+        // The searches of a connection are added to those of the others.
+        let (_, entry) = table.entries(database).next().unwrap();
+        assert_eq!(entry.calls, 3);
+        assert_eq!(entry.rows, 5);
+        assert_eq!(entry.total_time, Duration::from_millis(6));
+        let p99 = entry.percentile(0.99);
+        assert!(p99 >= Duration::from_millis(3) && p99 < Duration::from_millis(4));
+    }
+}
@@ -0,0 +1,390 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::SearchQueryInput;
+use crate::schema::{SearchFieldType, SearchIndexSchema};
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+use thiserror::Error;
+
+/// A query saved with parameters, as `paradedb.save_query` stores it. A parameter is a string
+/// of the query that is exactly `$` followed by its name, like the value of
+/// `paradedb.term('description', '$term')`, and is replaced by the JSON value given for it,
+/// so that numbers stay numbers. Parameters inside longer strings, like query strings, are
+/// not replaced, so that values can't change the structure of the query.
+pub struct QueryTemplate(pub Value);
+
+impl QueryTemplate {
+    /// The names of the parameters of the query, in order.
+    pub fn parameters(&self) -> BTreeSet<String> {
+        let mut parameters = BTreeSet::new();
+        collect_parameters(&self.0, &mut parameters);
+        parameters
+    }
+
+    /// The query with each parameter replaced by its value in `params`.
+    pub fn bind(&self, params: &Map<String, Value>) -> Result<SearchQueryInput, TemplateError> {
+        let missing: Vec<String> = self
+            .parameters()
+            .into_iter()
+            .filter(|name| !params.contains_key(name))
+            .collect();
+        if !missing.is_empty() {
+            return Err(TemplateError::MissingParameters(missing.join(", ")));
+        }
+
+        let bound = substitute(self.0.clone(), params);
+        serde_json::from_value(bound).map_err(TemplateError::InvalidQuery)
+    }
+}
+
+/// A query string with `{{name}}` placeholders, as `paradedb.search_template` binds them. A
+/// placeholder must be the value of a field, like `description:{{term}}`, or a bound of a
+/// range of a field, like `rating:[{{min}} TO {{max}}]`. Values are checked against the type
+/// of their field, and strings are quoted, so that values can't change the structure of the
+/// query string.
+pub struct QueryStringTemplate<'a>(pub &'a str);
+
+impl QueryStringTemplate<'_> {
+    /// The query string with each placeholder replaced by its value in `params`.
+    pub fn bind(
+        &self,
+        schema: &SearchIndexSchema,
+        params: &Map<String, Value>,
+    ) -> Result<String, TemplateError> {
+        let mut bound = String::new();
+        let mut missing = vec![];
+        let mut rest = self.0;
+        while let Some(start) = rest.find("{{") {
+            let end = rest[start..]
+                .find("}}")
+                .map(|end| start + end)
+                .ok_or(TemplateError::UnclosedPlaceholder)?;
+            let name = rest[start + 2..end].trim();
+            if !is_parameter_name(name) {
+                return Err(TemplateError::InvalidParameterName(name.to_string()));
+            }
+            bound.push_str(&rest[..start]);
+            rest = &rest[end + 2..];
+
+            let (field_name, in_range) = placeholder_field(&bound)
+                .ok_or_else(|| TemplateError::UnfieldedParameter(name.to_string()))?;
+            let field_type = field_type(schema, field_name).ok_or_else(|| {
+                TemplateError::UnknownField(name.to_string(), field_name.to_string())
+            })?;
+            match params.get(name) {
+                Some(value) => {
+                    let rendered = render(name, field_name, field_type, value, in_range)?;
+                    bound.push_str(&rendered);
+                }
+                None if !missing.iter().any(|missing| missing == name) => {
+                    missing.push(name.to_string())
+                }
+                None => {}
+            }
+        }
+        bound.push_str(rest);
+
+        if !missing.is_empty() {
+            return Err(TemplateError::MissingParameters(missing.join(", ")));
+        }
+        Ok(bound)
+    }
+}
+
+/// The field of a placeholder starting right after `prefix`, and whether it's a bound of a
+/// range, or `None` if it isn't the value of a field.
+fn placeholder_field(prefix: &str) -> Option<(&str, bool)> {
+    // The placeholder is the lower bound of a range, or the upper one after `TO`.
+    if let Some(open) = prefix.rfind(['[', '{']) {
+        let inside = &prefix[open + 1..];
+        let is_bound = !inside.contains([']', '}'])
+            && (inside.trim().is_empty() || inside.trim_end().ends_with(" TO"));
+        if is_bound {
+            let field_name = field_before_colon(&prefix[..open])?;
+            return Some((field_name, true));
+        }
+    }
+    field_before_colon(prefix).map(|field_name| (field_name, false))
+}
+
+/// The field name that `text` ends with, followed by a colon.
+fn field_before_colon(text: &str) -> Option<&str> {
+    let before = text.strip_suffix(':')?;
+    let start = before
+        .rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+        .map_or(0, |position| position + 1);
+    let field_name = &before[start..];
+    (!field_name.is_empty()).then_some(field_name)
+}
+
+/// The type of a field of the index, where a path into a JSON field, like `metadata.color`,
+/// has the type of the JSON field.
+fn field_type(schema: &SearchIndexSchema, field_name: &str) -> Option<SearchFieldType> {
+    if let Some(search_field) = schema.get_search_field(field_name) {
+        return Some(search_field.type_);
+    }
+    let (root, _) = field_name.split_once('.')?;
+    schema
+        .get_search_field(root)
+        .map(|search_field| search_field.type_)
+        .filter(|type_| *type_ == SearchFieldType::Json)
+}
+
+fn render(
+    name: &str,
+    field_name: &str,
+    field_type: SearchFieldType,
+    value: &Value,
+    in_range: bool,
+) -> Result<String, TemplateError> {
+    let mismatch = |expected: &str| TemplateError::TypeMismatch {
+        name: name.to_string(),
+        field: field_name.to_string(),
+        expected: expected.to_string(),
+        value: value.to_string(),
+    };
+
+    let literal = match (field_type, value) {
+        (SearchFieldType::I64, Value::Number(number)) if number.is_i64() => number.to_string(),
+        (SearchFieldType::I64, _) => return Err(mismatch("an integer")),
+        (SearchFieldType::U64, Value::Number(number)) if number.is_u64() => number.to_string(),
+        (SearchFieldType::U64, _) => return Err(mismatch("a non-negative integer")),
+        (SearchFieldType::F64, Value::Number(number)) => number.to_string(),
+        (SearchFieldType::F64, _) => return Err(mismatch("a number")),
+        (SearchFieldType::Bool, Value::Bool(bool)) if !in_range => bool.to_string(),
+        (SearchFieldType::Bool, _) => return Err(mismatch("a boolean")),
+        (SearchFieldType::Date, Value::String(date))
+            if chrono::DateTime::parse_from_rfc3339(date).is_ok() =>
+        {
+            date.clone()
+        }
+        (SearchFieldType::Date, _) => return Err(mismatch("an RFC 3339 timestamp")),
+        (SearchFieldType::Text | SearchFieldType::Json, _) if in_range => {
+            return Err(mismatch("a number or a date, to be a bound of a range"))
+        }
+        (SearchFieldType::Text | SearchFieldType::Json, Value::String(string)) => string.clone(),
+        (SearchFieldType::Json, Value::Number(number)) => number.to_string(),
+        (SearchFieldType::Json, Value::Bool(bool)) => bool.to_string(),
+        (SearchFieldType::Text, _) => return Err(mismatch("a string")),
+        (SearchFieldType::Json, _) => return Err(mismatch("a string, a number or a boolean")),
+        // The terms of a sparse vector can be searched like the terms of a text field.
+        (SearchFieldType::Sparse, _) if in_range => {
+            return Err(mismatch("a number or a date, to be a bound of a range"))
+        }
+        (SearchFieldType::Sparse, Value::String(string)) => string.clone(),
+        (SearchFieldType::Sparse, _) => return Err(mismatch("a string")),
+        (SearchFieldType::Vector, _) => {
+            return Err(mismatch("of a searchable field rather than a vector"))
+        }
+    };
+
+    // Bounds of ranges can't be quoted, but they're only numbers and dates, which can't hold
+    // the syntax of a query string.
+    if in_range {
+        Ok(literal)
+    } else {
+        Ok(quote(&literal))
+    }
+}
+
+fn quote(literal: &str) -> String {
+    format!("\"{}\"", literal.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn is_parameter_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parameter_name(value: &str) -> Option<&str> {
+    let name = value.strip_prefix('$')?;
+    is_parameter_name(name).then_some(name)
+}
+
+fn collect_parameters(value: &Value, parameters: &mut BTreeSet<String>) {
+    match value {
+        Value::String(string) => {
+            if let Some(name) = parameter_name(string) {
+                parameters.insert(name.to_string());
+            }
+        }
+        Value::Array(values) => values
+            .iter()
+            .for_each(|value| collect_parameters(value, parameters)),
+        Value::Object(map) => map
+            .values()
+            .for_each(|value| collect_parameters(value, parameters)),
+        _ => {}
+    }
+}
+
+fn substitute(value: Value, params: &Map<String, Value>) -> Value {
+    match value {
+        Value::String(string) => match parameter_name(&string).and_then(|name| params.get(name)) {
+            Some(param) => param.clone(),
+            None => Value::String(string),
+        },
+        Value::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(|value| substitute(value, params))
+                .collect(),
+        ),
+        Value::Object(map) => Value::Object(
+            map.into_iter()
+                .map(|(key, value)| (key, substitute(value, params)))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum TemplateError {
+    #[error("no value given for parameters: {0}")]
+    MissingParameters(String),
+
+    #[error("the parameters don't make a valid query: {0}")]
+    InvalidQuery(#[source] serde_json::Error),
+
+    #[error("a placeholder of the template is not closed with '}}}}'")]
+    UnclosedPlaceholder,
+
+    #[error("'{0}' is not a valid parameter name")]
+    InvalidParameterName(String),
+
+    #[error("parameter '{0}' must be the value of a field, like field:{{{{{0}}}}}, or a bound of its range")]
+    UnfieldedParameter(String),
+
+    #[error("field '{1}' of parameter '{0}' does not exist in the index")]
+    UnknownField(String, String),
+
+    #[error("parameter '{name}' of field '{field}' must be {expected}, got {value}")]
+    TypeMismatch {
+        name: String,
+        field: String,
+        expected: String,
+        value: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueryStringTemplate, QueryTemplate, TemplateError};
+    use crate::fixtures::*;
+    use crate::query::SearchQueryInput;
+    use rstest::*;
+    use serde_json::json;
+
+    #[rstest]
+    fn test_bind_parameters() {
+        let query = SearchQueryInput::Boolean {
+            must: vec![SearchQueryInput::Term {
+                field: Some("description".into()),
+                value: tantivy::schema::OwnedValue::Str("$term".into()),
+            }],
+            should: vec![],
+            must_not: vec![SearchQueryInput::Parse {
+                query_string: "category:$term".into(),
+                conjunction: None,
+            }],
+        };
+        let template = QueryTemplate(serde_json::to_value(&query).unwrap());
+        assert_eq!(
+            template.parameters().into_iter().collect::<Vec<_>>(),
+            vec!["term"]
+        );
+
+        // Only whole strings are parameters.
+        let bound = template
+            .bind(json!({"term": "shoes"}).as_object().unwrap())
+            .unwrap();
+        assert_eq!(
+            bound,
+            SearchQueryInput::Boolean {
+                must: vec![SearchQueryInput::Term {
+                    field: Some("description".into()),
+                    value: tantivy::schema::OwnedValue::Str("shoes".into()),
+                }],
+                should: vec![],
+                must_not: vec![SearchQueryInput::Parse {
+                    query_string: "category:$term".into(),
+                    conjunction: None,
+                    conjunction: None,
+                }],
+            }
+        );
+
+        assert!(template.bind(json!({}).as_object().unwrap()).is_err());
+    }
+
+    #[rstest]
+    fn test_bind_query_string(default_index: MockSearchIndex) {
+        let schema = &default_index.index.schema;
+        let bind = |template: &str, params: serde_json::Value| {
+            QueryStringTemplate(template).bind(schema, params.as_object().unwrap())
+        };
+
+        assert_eq!(
+            bind(
+                "description:{{term}} AND rating:[{{min}} TO {{ max }}] AND in_stock:{{stock}}",
+                json!({"term": "shoes", "min": 2, "max": 5, "stock": true})
+            )
+            .unwrap(),
+            r#"description:"shoes" AND rating:[2 TO 5] AND in_stock:"true""#
+        );
+
+        // Strings are quoted, so they can't add clauses to the query.
+        assert_eq!(
+            bind(
+                "metadata.color:{{color}}",
+                json!({"color": "red\" OR description:*"})
+            )
+            .unwrap(),
+            r#"metadata.color:"red\" OR description:*""#
+        );
+
+        assert!(matches!(
+            bind("rating:{{rating}}", json!({"rating": "4 OR rating:5"})),
+            Err(TemplateError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            bind("description:[{{min}} TO *]", json!({"min": "a"})),
+            Err(TemplateError::TypeMismatch { .. })
+        ));
+        assert!(matches!(
+            bind("{{term}}", json!({"term": "shoes"})),
+            Err(TemplateError::UnfieldedParameter(_))
+        ));
+        assert!(matches!(
+            bind("color:{{color}}", json!({"color": "red"})),
+            Err(TemplateError::UnknownField(..))
+        ));
+        assert!(matches!(
+            bind("description:{{term}} OR category:{{term}}", json!({})),
+            Err(TemplateError::MissingParameters(missing)) if missing == "term"
+        ));
+        assert!(matches!(
+            bind("description:{{term", json!({"term": "shoes"})),
+            Err(TemplateError::UnclosedPlaceholder)
+        ));
+    }
+}
@@ -0,0 +1,464 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::{AsFieldType, QueryError, SearchQueryInput};
+use serde::Serialize;
+use std::ops::Bound;
+use tantivy::query::QueryParser;
+use tantivy::schema::{FieldType, Value};
+
+/// What is wrong with a part of a query.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryProblemKind {
+    UnknownField,
+    TypeMismatch,
+    InvalidRegex,
+    InvalidBounds,
+    InvalidArgument,
+    ParseError,
+}
+
+/// A part of a query that can't be searched, found by `validate`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct QueryProblem {
+    pub kind: QueryProblemKind,
+    /// Where the part is in the query, like `must[1].query` for the query boosted by the
+    /// second required clause of a boolean query, or empty for the query itself.
+    pub path: String,
+    pub field: Option<String>,
+    pub message: String,
+}
+
+impl SearchQueryInput {
+    /// Every problem that would make this query fail to search an index with the fields of
+    /// `field_lookup`, without searching it. Unlike `into_tantivy_query`, which stops at the
+    /// first error, each clause is checked on its own, and ranges whose bounds can't match
+    /// anything are reported too.
+    pub fn validate(
+        &self,
+        field_lookup: &impl AsFieldType<String>,
+        parser: &mut QueryParser,
+    ) -> Vec<QueryProblem> {
+        let mut problems = vec![];
+        self.validate_at("", field_lookup, parser, &mut problems);
+        problems
+    }
+
+    fn validate_at(
+        &self,
+        path: &str,
+        field_lookup: &impl AsFieldType<String>,
+        parser: &mut QueryParser,
+        problems: &mut Vec<QueryProblem>,
+    ) {
+        let join = |name: &str| match path {
+            "" => name.to_string(),
+            path => format!("{path}.{name}"),
+        };
+
+        match self {
+            Self::Boolean {
+                must,
+                should,
+                must_not,
+            } => {
+                for (name, queries) in [("must", must), ("should", should), ("must_not", must_not)]
+                {
+                    for (index, query) in queries.iter().enumerate() {
+                        query.validate_at(
+                            &join(&format!("{name}[{index}]")),
+                            field_lookup,
+                            parser,
+                            problems,
+                        );
+                    }
+                }
+                return;
+            }
+            Self::Boost { query, .. }
+            | Self::ConstScore { query, .. }
+            | Self::Named { query, .. } => {
+                query.validate_at(&join("query"), field_lookup, parser, problems);
+                return;
+            }
+            Self::RankingProfile { query, profile } => {
+                query.validate_at(&join("query"), field_lookup, parser, problems);
+                let decay_field = profile.decay.as_ref().map(|decay| &decay.field);
+                for field in profile.boosts.keys().chain(decay_field) {
+                    if field_lookup.as_field_type(field).is_none() {
+                        problems.push(QueryProblem {
+                            kind: QueryProblemKind::UnknownField,
+                            path: join("profile"),
+                            field: Some(field.clone()),
+                            message: format!("field '{field}' is not part of the pg_search index"),
+                        });
+                    }
+                }
+                return;
+            }
+            Self::RerankByVector {
+                query,
+                field,
+                vector,
+                ..
+            } => {
+                query.validate_at(&join("query"), field_lookup, parser, problems);
+                // The inner query is checked on its own, so only the vector is built here.
+                let vector_fields = field_lookup
+                    .fields()
+                    .into_iter()
+                    .filter(|(field_type, _)| matches!(field_type, FieldType::Bytes(_)))
+                    .count();
+                let (kind, message) = match field {
+                    Some(name) if field_lookup.as_field_type(name).is_none() => (
+                        QueryProblemKind::UnknownField,
+                        format!("field '{name}' is not part of the pg_search index"),
+                    ),
+                    Some(name) if field_lookup.as_bytes(name).is_none() => (
+                        QueryProblemKind::TypeMismatch,
+                        format!("field '{name}' is not a vector field"),
+                    ),
+                    None if vector_fields != 1 => (
+                        QueryProblemKind::InvalidArgument,
+                        QueryError::AmbiguousVectorField(vector_fields).to_string(),
+                    ),
+                    _ if vector.is_empty() => (
+                        QueryProblemKind::InvalidArgument,
+                        "the vector to rerank by is empty".to_string(),
+                    ),
+                    _ => return,
+                };
+                problems.push(QueryProblem {
+                    kind,
+                    path: path.to_string(),
+                    field: field.clone(),
+                    message,
+                });
+                return;
+            }
+            Self::DisjunctionMax { disjuncts, .. } => {
+                for (index, query) in disjuncts.iter().enumerate() {
+                    query.validate_at(
+                        &join(&format!("disjuncts[{index}]")),
+                        field_lookup,
+                        parser,
+                        problems,
+                    );
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        let mut problem = |kind, field: Option<&String>, message: String| {
+            problems.push(QueryProblem {
+                kind,
+                path: path.to_string(),
+                field: field.cloned(),
+                message,
+            })
+        };
+
+        let empty_range =
+            |field: &String| format!("range of field '{field}' is empty, its bounds are crossed");
+
+        // Check the fields first, as a missing field is reported as a wrong type when the
+        // query is built.
+        let mut fields_exist = true;
+        for field in self.fields() {
+            if field_lookup.as_field_type(field).is_none()
+                && field_lookup.field_group(field).is_none()
+            {
+                problem(
+                    QueryProblemKind::UnknownField,
+                    Some(field),
+                    format!("field '{field}' is not part of the pg_search index"),
+                );
+                fields_exist = false;
+            }
+        }
+        if !fields_exist {
+            return;
+        }
+
+        // Building a term out of these values panics, so they're reported before.
+        for (field, value) in self.values() {
+            if matches!(value, Value::JsonObject(_) | Value::PreTokStr(_)) {
+                problem(
+                    QueryProblemKind::TypeMismatch,
+                    field,
+                    "json objects and pre-tokenized text can't be searched as terms".into(),
+                );
+                return;
+            }
+        }
+
+        match self {
+            Self::FuzzyTerm {
+                field,
+                distance: Some(distance),
+                ..
+            } if *distance > 2 => {
+                problem(
+                    QueryProblemKind::InvalidArgument,
+                    Some(field),
+                    format!("fuzzy distance must be at most 2, got {distance}"),
+                );
+                return;
+            }
+            Self::Range {
+                field,
+                lower_bound,
+                upper_bound,
+            }
+            | Self::FastFieldRangeWeight {
+                field,
+                lower_bound,
+                upper_bound,
+            } if is_empty_range(lower_bound, upper_bound) => {
+                problem(
+                    QueryProblemKind::InvalidBounds,
+                    Some(field),
+                    empty_range(field),
+                );
+                return;
+            }
+            _ => {}
+        }
+
+        if let Err(err) = self.clone().into_tantivy_query(field_lookup, parser) {
+            let kind = match err.downcast_ref::<QueryError>() {
+                Some(QueryError::RegexError(..)) => QueryProblemKind::InvalidRegex,
+                Some(QueryError::ParseError(..)) => QueryProblemKind::ParseError,
+                Some(QueryError::NonIndexedField(_)) => QueryProblemKind::UnknownField,
+                Some(QueryError::WrongFieldType(_) | QueryError::FieldTypeMismatch) => {
+                    QueryProblemKind::TypeMismatch
+                }
+                _ => QueryProblemKind::InvalidArgument,
+            };
+            problem(kind, self.fields().first().copied(), err.to_string());
+        }
+    }
+
+    /// The fields a query without subqueries searches.
+    pub(super) fn fields(&self) -> Vec<&String> {
+        match self {
+            Self::FastFieldRangeWeight { field, .. }
+            | Self::FuzzyTerm { field, .. }
+            | Self::KeyIn { field, .. }
+            | Self::Phrase { field, .. }
+            | Self::PhrasePrefix { field, .. }
+            | Self::Range { field, .. }
+            | Self::Regex { field, .. }
+            | Self::SparseVector { field, .. }
+            | Self::Term {
+                field: Some(field), ..
+            } => vec![field],
+            Self::TermSet { terms } => terms.iter().map(|(field, _)| field).collect(),
+            Self::MoreLikeThis { fields, .. } => fields.iter().map(|(field, _)| field).collect(),
+            _ => vec![],
+        }
+    }
+
+    /// The values a query without subqueries compares fields to, along with their field.
+    fn values(&self) -> Vec<(Option<&String>, &Value)> {
+        let bound = |bound: &'_ Bound<Value>| match bound {
+            Bound::Included(value) | Bound::Excluded(value) => Some(value),
+            Bound::Unbounded => None,
+        };
+        match self {
+            Self::Term { field, value } => vec![(field.as_ref(), value)],
+            Self::TermSet { terms } => terms
+                .iter()
+                .map(|(field, value)| (Some(field), value))
+                .collect(),
+            Self::Range {
+                field,
+                lower_bound,
+                upper_bound,
+            }
+            | Self::FastFieldRangeWeight {
+                field,
+                lower_bound,
+                upper_bound,
+            } => [bound(lower_bound), bound(upper_bound)]
+                .into_iter()
+                .flatten()
+                .map(|value| (Some(field), value))
+                .collect(),
+            _ => vec![],
+        }
+    }
+}
+
+/// Whether no value can be within both bounds.
+fn is_empty_range(lower_bound: &Bound<Value>, upper_bound: &Bound<Value>) -> bool {
+    let (lower, upper, inclusive) = match (lower_bound, upper_bound) {
+        (Bound::Included(lower), Bound::Included(upper)) => (lower, upper, true),
+        (
+            Bound::Included(lower) | Bound::Excluded(lower),
+            Bound::Included(upper) | Bound::Excluded(upper),
+        ) => (lower, upper, false),
+        _ => return false,
+    };
+    let ordering = match (lower, upper) {
+        (Value::Str(lower), Value::Str(upper)) => lower.partial_cmp(upper),
+        (Value::Date(lower), Value::Date(upper)) => lower.partial_cmp(upper),
+        (Value::Bool(lower), Value::Bool(upper)) => lower.partial_cmp(upper),
+        (lower, upper) => as_f64(lower).partial_cmp(&as_f64(upper)),
+    };
+    match ordering {
+        Some(std::cmp::Ordering::Greater) => true,
+        Some(std::cmp::Ordering::Equal) => !inclusive,
+        _ => false,
+    }
+}
+
+fn as_f64(value: &Value) -> Option<f64> {
+    match value {
+        Value::I64(value) => Some(*value as f64),
+        Value::U64(value) => Some(*value as f64),
+        Value::F64(value) => Some(*value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueryProblemKind, SearchQueryInput};
+    use crate::fixtures::*;
+    use rstest::*;
+    use std::ops::Bound;
+    use tantivy::schema::Value;
+
+    fn term(field: &str, value: Value) -> SearchQueryInput {
+        SearchQueryInput::Term {
+            field: Some(field.into()),
+            value,
+        }
+    }
+
+    #[rstest]
+    fn test_validate(default_index: MockSearchIndex) {
+        let search_index = default_index.index;
+        let mut parser = search_index.query_parser();
+        let problems = |query: SearchQueryInput| {
+            query
+                .validate(&search_index.schema, &mut search_index.query_parser())
+                .into_iter()
+                .map(|problem| (problem.kind, problem.path, problem.field))
+                .collect::<Vec<_>>()
+        };
+
+        let valid = term("description", Value::Str("keyboard".into()))
+            & SearchQueryInput::Range {
+                field: "rating".into(),
+                lower_bound: Bound::Included(Value::I64(2)),
+                upper_bound: Bound::Excluded(Value::I64(5)),
+            };
+        assert!(valid.validate(&search_index.schema, &mut parser).is_empty());
+
+        // Each clause is checked, and problems are located in the query.
+        let invalid = SearchQueryInput::Boolean {
+            must: vec![
+                term("color", Value::Str("red".into())),
+                SearchQueryInput::Regex {
+                    field: "description".into(),
+                    pattern: "key(board".into(),
+                    max_expansions: None,
+                },
+            ],
+            should: vec![SearchQueryInput::Boost {
+                query: Box::new(SearchQueryInput::Range {
+                    field: "rating".into(),
+                    lower_bound: Bound::Included(Value::I64(5)),
+                    upper_bound: Bound::Excluded(Value::I64(5)),
+                }),
+                boost: 2.0,
+            }],
+            must_not: vec![
+                SearchQueryInput::Phrase {
+                    field: "rating".into(),
+                    phrases: vec!["a".into(), "b".into()],
+                    slop: None,
+                },
+                SearchQueryInput::Parse {
+                    query_string: "description:(keyboard".into(),
+                    conjunction: None,
+                },
+            ],
+        };
+        assert_eq!(
+            problems(invalid),
+            vec![
+                (
+                    QueryProblemKind::UnknownField,
+                    "must[0]".into(),
+                    Some("color".into())
+                ),
+                (
+                    QueryProblemKind::InvalidRegex,
+                    "must[1]".into(),
+                    Some("description".into())
+                ),
+                (
+                    QueryProblemKind::InvalidBounds,
+                    "should[0].query".into(),
+                    Some("rating".into())
+                ),
+                (
+                    QueryProblemKind::TypeMismatch,
+                    "must_not[0]".into(),
+                    Some("rating".into())
+                ),
+                (QueryProblemKind::ParseError, "must_not[1]".into(), None),
+            ]
+        );
+    }
+
+    #[rstest]
+    fn test_validate_fast_field_range(default_index: MockSearchIndex) {
+        let search_index = default_index.index;
+        let problems = |field: &str, lower_bound: Value, upper_bound: Value| {
+            SearchQueryInput::FastFieldRangeWeight {
+                field: field.into(),
+                lower_bound: Bound::Included(lower_bound),
+                upper_bound: Bound::Excluded(upper_bound),
+            }
+            .validate(&search_index.schema, &mut search_index.query_parser())
+            .into_iter()
+            .map(|problem| problem.kind)
+            .collect::<Vec<_>>()
+        };
+
+        // Bounds are converted to the type of the field, like the unsigned integers of json.
+        assert_eq!(problems("rating", Value::U64(2), Value::I64(5)), vec![]);
+        assert_eq!(
+            problems("rating", Value::U64(2), Value::Str("five".into())),
+            vec![QueryProblemKind::TypeMismatch]
+        );
+        assert_eq!(
+            problems("rating", Value::I64(5), Value::F64(2.5)),
+            vec![QueryProblemKind::InvalidBounds]
+        );
+        assert_eq!(
+            problems("description", Value::I64(2), Value::I64(5)),
+            vec![QueryProblemKind::TypeMismatch]
+        );
+    }
+}
@@ -19,6 +19,10 @@ use pgrx::JsonB;
 use serde::{de::DeserializeOwned, Deserialize, Deserializer};
 use std::str::FromStr;
 
+use crate::postgres::security::secure_query;
+use crate::query::groups::FieldGroups;
+use crate::query::ranking::RankingProfile;
+use crate::schema::{SearchFieldType, SearchIndexSchema};
 use crate::{index::state::SearchAlias, query::SearchQueryInput};
 
 #[derive(Debug, Deserialize, Default, Clone, PartialEq)]
@@ -33,14 +37,145 @@ pub struct SearchConfig {
     pub prefix: Option<String>,
     pub postfix: Option<String>,
     pub alias: Option<SearchAlias>,
+    /// Order equally-scored results by key, see `SearchConfig::stable_sort`.
     pub stable_sort: Option<bool>,
+    /// Score every matching document, instead of skipping the ones that can't make it
+    /// into the top `limit_rows` results.
+    pub exact: Option<bool>,
+    /// Skip BM25 scoring, for queries that only filter rows. Results are returned in index
+    /// order, and all have the same score.
+    pub scored: Option<bool>,
+    /// Count the documents matching the query along with the search, to be read with
+    /// `paradedb.total_hits`.
+    pub total_hits: Option<TotalHitsMode>,
+    /// The count after which `lower_bound` counting stops.
+    pub total_hits_threshold: Option<u64>,
+    /// Milliseconds after which the search is canceled, overriding `paradedb.search_timeout`.
+    pub timeout_ms: Option<u64>,
+    /// Order results by fast fields instead of by score, which then breaks ties.
+    pub sort_by: Option<Vec<SortField>>,
+    /// Replace scores with pseudo-random ones derived from this seed and the key of each
+    /// document, which shuffles results the same way for the same seed.
+    pub random_seed: Option<i64>,
+    /// The field that `tenant` filters the index by, set by `create_bm25`.
+    pub tenant_field: Option<String>,
+    /// Whether query strings combine terms with AND instead of OR unless they choose, set by
+    /// `create_bm25`.
+    pub conjunction_by_default: Option<bool>,
+    /// Only search the documents whose `tenant_field` is this tenant.
+    pub tenant: Option<String>,
+    /// Search the rows as they were at this time, in indexes that keep history.
+    pub as_of: Option<String>,
+    /// The ranking profile of the index that ranks the results, see `RankingProfile`.
+    pub profile: Option<String>,
     pub uuid: String,
 }
 
+/// A fast field to order results by. Documents without a value come last in either order.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+pub struct SortField {
+    pub field: String,
+    #[serde(default)]
+    pub order: SortOrder,
+}
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    #[default]
+    Asc,
+    Desc,
+}
+
+/// How accurately a search counts the documents matching its query.
+#[derive(Copy, Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TotalHitsMode {
+    /// Count every matching document.
+    Exact,
+    /// Stop counting once the count reaches `total_hits_threshold`, which is then a lower
+    /// bound of the total, so that broad queries don't have to count all their matches.
+    LowerBound,
+}
+
 impl SearchConfig {
     pub fn from_jsonb(JsonB(config_json_value): JsonB) -> Result<Self, serde_json::Error> {
         serde_json::from_value(config_json_value)
     }
+
+    /// Whether equally-scored results are ordered by key, which is the default for scored
+    /// searches, so that pages of results neither overlap nor skip rows.
+    pub fn stable_sort(&self) -> bool {
+        self.stable_sort
+            .unwrap_or_else(|| self.scored.unwrap_or(true))
+    }
+
+    /// The time of an `as_of` search, which Postgres passes as an RFC 3339 timestamp.
+    pub fn as_of_datetime(&self) -> Option<tantivy::DateTime> {
+        self.as_of.as_ref().map(|as_of| {
+            let as_of = chrono::DateTime::parse_from_rfc3339(as_of)
+                .unwrap_or_else(|err| panic!("invalid as_of timestamp '{as_of}': {err}"));
+            tantivy::DateTime::from_timestamp_micros(as_of.timestamp_micros())
+        })
+    }
+
+    /// The query that the search runs: `query`, with the default operator of the index and
+    /// its field groups expanded, ranked by `profile`, and restricted to the documents of `tenant` and to the ones that
+    /// the security filter of the index lets the search see, simplified by
+    /// `SearchQueryInput::rewrite`.
+    pub fn search_query(&self, schema: &SearchIndexSchema) -> SearchQueryInput {
+        let query = match &self.profile {
+            Some(profile) => SearchQueryInput::RankingProfile {
+                query: Box::new(self.query.clone()),
+                profile: RankingProfile::load(&self.index_name, profile),
+            },
+            None => self.query.clone(),
+        };
+        let query = match self.conjunction_by_default {
+            Some(true) => query.conjunction_by_default(),
+            _ => query,
+        }
+        .expand_field_groups(&FieldGroups::new(schema, &self.index_name));
+        secure_query(&self.index_name, schema, self.tenant_query(schema, query))
+            .rewrite(self.scored.unwrap_or(true))
+    }
+
+    /// `query`, restricted to the documents of `tenant` if it is set. The tenant is a filter
+    /// on a single shared index: a required clause on the tenant's term that doesn't score.
+    /// Documents of other tenants are skipped rather than partitioned away, and BM25
+    /// statistics are those of the whole index.
+    fn tenant_query(
+        &self,
+        schema: &SearchIndexSchema,
+        query: SearchQueryInput,
+    ) -> SearchQueryInput {
+        let Some(tenant) = &self.tenant else {
+            return query;
+        };
+        let field = self.tenant_field.as_ref().unwrap_or_else(|| {
+            panic!(
+                "cannot search tenant '{tenant}', index {} has no tenant_field",
+                self.index_name
+            )
+        });
+        let value = field_value(schema, field, tenant)
+            .unwrap_or_else(|err| panic!("tenant_field {field}: {err}"));
+
+        SearchQueryInput::Boolean {
+            must: vec![
+                query,
+                SearchQueryInput::ConstScore {
+                    query: Box::new(SearchQueryInput::Term {
+                        field: Some(field.clone()),
+                        value,
+                    }),
+                    score: 0.0,
+                },
+            ],
+            should: vec![],
+            must_not: vec![],
+        }
+    }
 }
 
 impl FromStr for SearchConfig {
@@ -216,3 +351,27 @@ mod tests {
         assert_ne!(expected.is_fast(), text_options.is_fast());
     }
 }
+
+/// The term value of `field` for a value given as text, like a tenant. Only text and integer
+/// fields can be compared this way.
+pub fn field_value(
+    schema: &SearchIndexSchema,
+    field: &str,
+    value: &str,
+) -> Result<tantivy::schema::Value, String> {
+    let search_field = schema
+        .get_search_field(field)
+        .ok_or_else(|| format!("{field} is not a field of the index"))?;
+    match search_field.type_ {
+        SearchFieldType::Text => Ok(tantivy::schema::Value::Str(value.to_string())),
+        SearchFieldType::I64 => value
+            .parse()
+            .map(tantivy::schema::Value::I64)
+            .map_err(|err| format!("'{value}' is not an integer: {err}")),
+        SearchFieldType::U64 => value
+            .parse()
+            .map(tantivy::schema::Value::U64)
+            .map_err(|err| format!("'{value}' is not an integer: {err}")),
+        _ => Err(format!("{field} must be a text or integer field")),
+    }
+}
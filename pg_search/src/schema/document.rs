@@ -17,7 +17,7 @@
 
 use serde::{de::Visitor, Deserialize, Deserializer, Serialize, Serializer};
 use tantivy::schema::{Field, FieldValue, OwnedValue, Value};
-use tantivy::TantivyDocument;
+use tantivy::{TantivyDocument, Term};
 
 use crate::schema::SearchFieldId;
 
@@ -34,6 +34,35 @@ impl SearchDocument {
     pub fn insert(&mut self, SearchFieldId(key): SearchFieldId, value: OwnedValue) {
         self.doc.add_field_value(key, value)
     }
+
+    /// The term identifying the row of this document, used to replace older versions of it.
+    /// Key fields are indexed without tokenization, so the term matches the value as is.
+    pub fn key_term(&self) -> Option<Term> {
+        let SearchFieldId(field) = self.key;
+        value_term(field, self.doc.get_first(field)?)
+    }
+
+    /// The ctid of the row of this document, as encoded by `pgrx::item_pointer_to_u64`.
+    pub fn ctid(&self) -> Option<u64> {
+        let SearchFieldId(field) = self.ctid;
+        match self.doc.get_first(field)? {
+            OwnedValue::U64(ctid) => Some(*ctid),
+            _ => None,
+        }
+    }
+}
+
+/// The term of a key field's value.
+pub fn value_term(field: Field, value: &OwnedValue) -> Option<Term> {
+    match value {
+        OwnedValue::Str(value) => Some(Term::from_field_text(field, value)),
+        OwnedValue::I64(value) => Some(Term::from_field_i64(field, *value)),
+        OwnedValue::U64(value) => Some(Term::from_field_u64(field, *value)),
+        OwnedValue::F64(value) => Some(Term::from_field_f64(field, *value)),
+        OwnedValue::Bool(value) => Some(Term::from_field_bool(field, *value)),
+        OwnedValue::Date(value) => Some(Term::from_field_date(field, *value)),
+        _ => None,
+    }
 }
 
 impl From<SearchDocument> for TantivyDocument {
@@ -17,6 +17,8 @@
 
 mod config;
 mod document;
+mod sparse;
+mod vector;
 
 use anyhow::{Context, Result};
 pub use config::*;
@@ -25,14 +27,19 @@ pub use document::*;
 use pgrx::{PgBuiltInOids, PgOid};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+pub use sparse::*;
+use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::hash::Hash;
 use tantivy::schema::{
-    DateOptions, Field, IndexRecordOption, JsonObjectOptions, NumericOptions, Schema,
+    BytesOptions, DateOptions, Field, IndexRecordOption, JsonObjectOptions, NumericOptions, Schema,
     TextFieldIndexing, TextOptions, FAST, INDEXED, STORED,
 };
 use thiserror::Error;
 use tokenizers::{SearchNormalizer, SearchTokenizer};
+pub use vector::*;
 
+use crate::query::groups::ALL_FIELDS_GROUP;
 use crate::query::AsFieldType;
 
 /// The id of a field, stored in the index.
@@ -40,6 +47,13 @@ use crate::query::AsFieldType;
 #[from(forward)]
 pub struct SearchFieldName(pub String);
 
+// Lets fields be looked up by the name of a Postgres attribute without allocating a String.
+impl Borrow<str> for SearchFieldName {
+    fn borrow(&self) -> &str {
+        &self.0
+    }
+}
+
 /// The name of a field, as it appears to Postgres.
 #[derive(Debug, Copy, Clone, From, PartialEq, Eq, Serialize, Deserialize)]
 #[from(forward)]
@@ -58,6 +72,10 @@ pub enum SearchFieldType {
     Bool,
     Json,
     Date,
+    /// An array of floats, see `encode_vector`.
+    Vector,
+    /// Terms with weights, see `sparse_tokens`.
+    Sparse,
 }
 
 impl TryFrom<&PgOid> for SearchFieldType {
@@ -89,6 +107,20 @@ impl TryFrom<&PgOid> for SearchFieldType {
     }
 }
 
+/// What happens to a value of a field that can't be indexed, like a malformed date in a JSON
+/// column. Values that aren't failed on are counted in the `skipped_values` of the index.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchFieldErrorPolicy {
+    /// Fail the statement writing the row.
+    #[default]
+    Fail,
+    /// Leave the whole row out of the index.
+    Skip,
+    /// Index the row as if the value were NULL.
+    Null,
+}
+
 #[derive(Deserialize, Serialize, Clone, Debug, utoipa::ToSchema, PartialEq, Eq)]
 pub enum SearchFieldConfig {
     Text {
@@ -107,6 +139,8 @@ pub enum SearchFieldConfig {
         record: IndexRecordOption,
         #[serde(default)]
         normalizer: SearchNormalizer,
+        #[serde(default)]
+        on_error: SearchFieldErrorPolicy,
     },
     Json {
         #[serde(default = "default_as_true")]
@@ -124,6 +158,8 @@ pub enum SearchFieldConfig {
         record: IndexRecordOption,
         #[serde(default)]
         normalizer: SearchNormalizer,
+        #[serde(default)]
+        on_error: SearchFieldErrorPolicy,
     },
     Numeric {
         #[serde(default = "default_as_true")]
@@ -132,6 +168,8 @@ pub enum SearchFieldConfig {
         fast: bool,
         #[serde(default = "default_as_true")]
         stored: bool,
+        #[serde(default)]
+        on_error: SearchFieldErrorPolicy,
     },
     Boolean {
         #[serde(default = "default_as_true")]
@@ -140,6 +178,8 @@ pub enum SearchFieldConfig {
         fast: bool,
         #[serde(default = "default_as_true")]
         stored: bool,
+        #[serde(default)]
+        on_error: SearchFieldErrorPolicy,
     },
     Date {
         #[serde(default = "default_as_true")]
@@ -148,11 +188,33 @@ pub enum SearchFieldConfig {
         fast: bool,
         #[serde(default = "default_as_true")]
         stored: bool,
+        #[serde(default)]
+        on_error: SearchFieldErrorPolicy,
+    },
+    /// Vectors are always fast fields, as they're read to rerank results.
+    Vector {
+        #[serde(default)]
+        stored: bool,
     },
+    /// Sparse vectors are only indexed, as the weights of their terms are read from postings.
+    Sparse {},
     Ctid,
 }
 
 impl SearchFieldConfig {
+    /// What happens to the values of the field that can't be indexed. Vectors and the fields
+    /// that every index has always fail.
+    pub fn on_error(&self) -> SearchFieldErrorPolicy {
+        match self {
+            Self::Text { on_error, .. }
+            | Self::Json { on_error, .. }
+            | Self::Numeric { on_error, .. }
+            | Self::Boolean { on_error, .. }
+            | Self::Date { on_error, .. } => *on_error,
+            Self::Vector { .. } | Self::Sparse {} | Self::Ctid => SearchFieldErrorPolicy::Fail,
+        }
+    }
+
     pub fn text_from_json(value: serde_json::Value) -> Result<Self> {
         let obj = value
             .as_object()
@@ -201,6 +263,13 @@ impl SearchFieldConfig {
             None => Ok(SearchNormalizer::Raw),
         }?;
 
+        let on_error = match obj.get("on_error") {
+            Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                anyhow::anyhow!("'on_error' field should be 'fail', 'skip' or 'null'")
+            }),
+            None => Ok(SearchFieldErrorPolicy::Fail),
+        }?;
+
         Ok(SearchFieldConfig::Text {
             indexed,
             fast,
@@ -209,6 +278,7 @@ impl SearchFieldConfig {
             tokenizer,
             record,
             normalizer,
+            on_error,
         })
     }
 
@@ -260,6 +330,13 @@ impl SearchFieldConfig {
             None => Ok(SearchNormalizer::Raw),
         }?;
 
+        let on_error = match obj.get("on_error") {
+            Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                anyhow::anyhow!("'on_error' field should be 'fail', 'skip' or 'null'")
+            }),
+            None => Ok(SearchFieldErrorPolicy::Fail),
+        }?;
+
         Ok(SearchFieldConfig::Json {
             indexed,
             fast,
@@ -268,6 +345,7 @@ impl SearchFieldConfig {
             tokenizer,
             record,
             normalizer,
+            on_error,
         })
     }
 
@@ -297,10 +375,18 @@ impl SearchFieldConfig {
             None => Ok(true),
         }?;
 
+        let on_error = match obj.get("on_error") {
+            Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                anyhow::anyhow!("'on_error' field should be 'fail', 'skip' or 'null'")
+            }),
+            None => Ok(SearchFieldErrorPolicy::Fail),
+        }?;
+
         Ok(SearchFieldConfig::Numeric {
             indexed,
             fast,
             stored,
+            on_error,
         })
     }
 
@@ -330,10 +416,18 @@ impl SearchFieldConfig {
             None => Ok(true),
         }?;
 
+        let on_error = match obj.get("on_error") {
+            Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                anyhow::anyhow!("'on_error' field should be 'fail', 'skip' or 'null'")
+            }),
+            None => Ok(SearchFieldErrorPolicy::Fail),
+        }?;
+
         Ok(SearchFieldConfig::Boolean {
             indexed,
             fast,
             stored,
+            on_error,
         })
     }
 
@@ -363,12 +457,43 @@ impl SearchFieldConfig {
             None => Ok(true),
         }?;
 
+        let on_error = match obj.get("on_error") {
+            Some(v) => serde_json::from_value(v.clone()).map_err(|_| {
+                anyhow::anyhow!("'on_error' field should be 'fail', 'skip' or 'null'")
+            }),
+            None => Ok(SearchFieldErrorPolicy::Fail),
+        }?;
+
         Ok(SearchFieldConfig::Date {
             indexed,
             fast,
             stored,
+            on_error,
         })
     }
+
+    pub fn vector_from_json(value: serde_json::Value) -> Result<Self> {
+        let obj = value
+            .as_object()
+            .context("Expected a JSON object for Vector configuration")?;
+
+        let stored = match obj.get("stored") {
+            Some(v) => v
+                .as_bool()
+                .ok_or_else(|| anyhow::anyhow!("'stored' field should be a boolean")),
+            None => Ok(false),
+        }?;
+
+        Ok(SearchFieldConfig::Vector { stored })
+    }
+
+    pub fn sparse_from_json(value: serde_json::Value) -> Result<Self> {
+        value
+            .as_object()
+            .context("Expected a JSON object for Sparse configuration")?;
+
+        Ok(SearchFieldConfig::Sparse {})
+    }
 }
 
 impl SearchFieldConfig {
@@ -395,6 +520,14 @@ impl SearchFieldConfig {
     pub fn default_date() -> Self {
         Self::from_json(json!({"Date": {}}))
     }
+
+    pub fn default_vector() -> Self {
+        Self::from_json(json!({"Vector": {}}))
+    }
+
+    pub fn default_sparse() -> Self {
+        Self::from_json(json!({"Sparse": {}}))
+    }
 }
 
 impl From<SearchFieldConfig> for TextOptions {
@@ -409,6 +542,7 @@ impl From<SearchFieldConfig> for TextOptions {
                 tokenizer,
                 record,
                 normalizer,
+                ..
             } => {
                 if stored {
                     text_options = text_options.set_stored();
@@ -425,6 +559,15 @@ impl From<SearchFieldConfig> for TextOptions {
                     text_options = text_options.set_indexing_options(text_field_indexing);
                 }
             }
+            SearchFieldConfig::Sparse {} => {
+                // Fieldnorms would only be used by BM25, which doesn't score sparse vectors.
+                let text_field_indexing = TextFieldIndexing::default()
+                    .set_index_option(IndexRecordOption::WithFreqs)
+                    .set_fieldnorms(false)
+                    .set_tokenizer(&SearchTokenizer::Raw.name());
+
+                text_options = text_options.set_indexing_options(text_field_indexing);
+            }
             _ => panic!("attemped to convert non-text search field config to tantivy text config"),
         }
         text_options
@@ -439,9 +582,10 @@ impl From<SearchFieldConfig> for NumericOptions {
                 indexed,
                 fast,
                 stored,
+                ..
             }
             // Following the example of Quickwit, which uses NumericOptions for boolean options.
-            | SearchFieldConfig::Boolean { indexed, fast, stored } => {
+            | SearchFieldConfig::Boolean { indexed, fast, stored, .. } => {
                 if stored {
                     numeric_options = numeric_options.set_stored();
                 }
@@ -474,6 +618,7 @@ impl From<SearchFieldConfig> for JsonObjectOptions {
                 tokenizer,
                 record,
                 normalizer,
+                ..
             } => {
                 if stored {
                     json_options = json_options.set_stored();
@@ -509,6 +654,7 @@ impl From<SearchFieldConfig> for DateOptions {
                 indexed,
                 fast,
                 stored,
+                ..
             } => {
                 if stored {
                     date_options = date_options.set_stored();
@@ -528,6 +674,23 @@ impl From<SearchFieldConfig> for DateOptions {
     }
 }
 
+impl From<SearchFieldConfig> for BytesOptions {
+    fn from(config: SearchFieldConfig) -> Self {
+        let mut bytes_options = BytesOptions::default().set_fast();
+        match config {
+            SearchFieldConfig::Vector { stored } => {
+                if stored {
+                    bytes_options = bytes_options.set_stored();
+                }
+            }
+            _ => {
+                panic!("attemped to convert non-vector search field config to tantivy bytes config")
+            }
+        }
+        bytes_options
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SearchField {
     /// The id of the field, stored in the index.
@@ -588,6 +751,12 @@ impl SearchIndexSchema {
                     SearchFieldType::Bool => builder.add_bool_field(name.as_ref(), config.clone()),
                     SearchFieldType::Json => builder.add_json_field(name.as_ref(), config.clone()),
                     SearchFieldType::Date => builder.add_date_field(name.as_ref(), config.clone()),
+                    SearchFieldType::Vector => {
+                        builder.add_bytes_field(name.as_ref(), config.clone())
+                    }
+                    SearchFieldType::Sparse => {
+                        builder.add_text_field(name.as_ref(), config.clone())
+                    }
                 },
             }
             .into();
@@ -644,12 +813,17 @@ impl SearchIndexSchema {
         SearchDocument { doc, key, ctid }
     }
 
-    pub fn get_search_field(&self, name: &SearchFieldName) -> Option<&SearchField> {
+    pub fn get_search_field<Q>(&self, name: &Q) -> Option<&SearchField>
+    where
+        SearchFieldName: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
         if let Some(lookup) = &self.lookup {
             lookup.get(name).and_then(|idx| self.fields.get(*idx))
         } else {
-            let lookup = Self::build_lookup(&self.fields);
-            lookup.get(name).and_then(|idx| self.fields.get(*idx))
+            // The lookup isn't deserialized, and indexes have few enough fields that scanning
+            // them is cheaper than building it on every call.
+            self.fields.iter().find(|field| field.name.borrow() == name)
         }
     }
 }
@@ -719,4 +893,23 @@ impl AsFieldType<String> for SearchIndexSchema {
                 (field_type, field)
             })
     }
+    /// `_all` searches every indexed text field but the key, unless the index has a field
+    /// with that name.
+    fn field_group(&self, from: &String) -> Option<Vec<(String, f32)>> {
+        if from != ALL_FIELDS_GROUP || self.get_search_field(from.as_str()).is_some() {
+            return None;
+        }
+        let members = self
+            .fields
+            .iter()
+            .enumerate()
+            .filter(|(idx, search_field)| {
+                *idx != self.key
+                    && search_field.type_ == SearchFieldType::Text
+                    && self.schema.get_field_entry(search_field.id.0).is_indexed()
+            })
+            .map(|(_, search_field)| (search_field.name.0.clone(), 1.0))
+            .collect();
+        Some(members)
+    }
 }
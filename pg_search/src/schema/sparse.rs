@@ -0,0 +1,105 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+use tantivy::tokenizer::{PreTokenizedString, Token};
+use thiserror::Error;
+
+/// Weights are indexed as the frequency of their term, in hundredths, so that the postings
+/// of a term hold the weights of the documents that have it.
+pub const SPARSE_WEIGHT_SCALE: f32 = 100.0;
+
+/// The largest weight that can be indexed. A term of a document is indexed as many times as
+/// its quantized weight, so it bounds the size of a document.
+pub const MAX_SPARSE_WEIGHT: f32 = 100.0;
+
+/// The terms of a sparse vector, like the output of a SPLADE model, with their weights. The
+/// dimensions of a pgvector `sparsevec` are terms too, numbered from 1 as in its text format.
+pub fn sparse_weights_from_json(
+    value: &serde_json::Value,
+) -> Result<Vec<(String, f32)>, SparseVectorError> {
+    let object = value.as_object().ok_or(SparseVectorError::NotAnObject)?;
+    object
+        .iter()
+        .map(|(term, weight)| {
+            let weight = weight
+                .as_f64()
+                .ok_or_else(|| SparseVectorError::NotANumber(term.clone()))?;
+            Ok((term.clone(), weight as f32))
+        })
+        .collect()
+}
+
+/// The weights of a sparse vector as tokens, each term repeated as many times as its
+/// quantized weight. Terms whose weight rounds to zero are left out.
+pub fn sparse_tokens(weights: &[(String, f32)]) -> Result<PreTokenizedString, SparseVectorError> {
+    let mut tokens = vec![];
+    for (term, weight) in weights {
+        if !(0.0..=MAX_SPARSE_WEIGHT).contains(weight) {
+            return Err(SparseVectorError::InvalidWeight(term.clone(), *weight));
+        }
+        let frequency = (weight * SPARSE_WEIGHT_SCALE).round() as usize;
+        for _ in 0..frequency {
+            tokens.push(Token {
+                offset_from: 0,
+                offset_to: 0,
+                position: tokens.len(),
+                text: term.clone(),
+                position_length: 1,
+            });
+        }
+    }
+    Ok(PreTokenizedString {
+        text: String::new(),
+        tokens,
+    })
+}
+
+#[derive(Error, Debug)]
+pub enum SparseVectorError {
+    #[error("a sparse vector must be a JSON object of terms and their weights")]
+    NotAnObject,
+
+    #[error("the weight of term '{0}' is not a number")]
+    NotANumber(String),
+
+    #[error("the weight of term '{0}' is {1}, weights must be between 0 and {MAX_SPARSE_WEIGHT}")]
+    InvalidWeight(String, f32),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sparse_tokens, sparse_weights_from_json};
+    use rstest::*;
+    use serde_json::json;
+
+    #[rstest]
+    fn test_sparse_tokens() {
+        let weights =
+            sparse_weights_from_json(&json!({"keyboard": 0.03, "metal": 0.0, "typing": 0.011}))
+                .unwrap();
+        let tokens = sparse_tokens(&weights).unwrap().tokens;
+        let count = |term: &str| tokens.iter().filter(|token| token.text == term).count();
+        assert_eq!(count("keyboard"), 3);
+        assert_eq!(count("metal"), 0);
+        assert_eq!(count("typing"), 1);
+        assert_eq!(tokens.len(), 4);
+
+        assert!(sparse_weights_from_json(&json!([0.5])).is_err());
+        assert!(sparse_weights_from_json(&json!({"keyboard": "high"})).is_err());
+        assert!(sparse_tokens(&[("keyboard".into(), -1.0)]).is_err());
+        assert!(sparse_tokens(&[("keyboard".into(), f32::NAN)]).is_err());
+    }
+}
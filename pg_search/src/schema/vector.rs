@@ -0,0 +1,102 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// How the similarity of a document's vector to the vector of a query is measured. Higher
+/// is more similar, so that it can be used as a score.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize, utoipa::ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum VectorMetric {
+    /// The cosine of the angle between the vectors, from -1 to 1.
+    #[default]
+    Cosine,
+    /// The dot product of the vectors, which is the cosine similarity for normalized vectors,
+    /// and is cheaper to compute.
+    Dot,
+}
+
+impl VectorMetric {
+    /// The similarity of two vectors of the same number of dimensions.
+    pub fn similarity(&self, left: &[f32], right: &[f32]) -> f32 {
+        let dot: f32 = left
+            .iter()
+            .zip(right)
+            .map(|(left, right)| left * right)
+            .sum();
+        match self {
+            Self::Dot => dot,
+            Self::Cosine => {
+                let norm = |vector: &[f32]| vector.iter().map(|value| value * value).sum::<f32>();
+                let norms = (norm(left) * norm(right)).sqrt();
+                // A zero vector has no direction, so it's no closer to one vector than another.
+                if norms == 0.0 {
+                    0.0
+                } else {
+                    dot / norms
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for VectorMetric {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+    }
+}
+
+/// Vectors are indexed as the little-endian bytes of their elements in a bytes fast field,
+/// which keeps the order of the elements, unlike a multivalued numeric field.
+pub fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+pub fn decode_vector(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_vector, encode_vector, VectorMetric};
+    use rstest::*;
+
+    #[rstest]
+    fn test_vector_roundtrip() {
+        let vector = vec![0.5, -1.25, 3.0];
+        assert_eq!(decode_vector(&encode_vector(&vector)), vector);
+    }
+
+    #[rstest]
+    fn test_vector_similarity() {
+        let (left, right) = ([1.0, 0.0], [3.0, 4.0]);
+        assert_eq!(VectorMetric::Dot.similarity(&left, &right), 3.0);
+        assert_eq!(VectorMetric::Cosine.similarity(&left, &right), 0.6);
+        assert_eq!(VectorMetric::Cosine.similarity(&left, &[0.0, 0.0]), 0.0);
+        assert_eq!("dot".parse::<VectorMetric>().unwrap(), VectorMetric::Dot);
+        assert!("l2".parse::<VectorMetric>().is_err());
+    }
+}
@@ -19,13 +19,21 @@ use crate::globals::WRITER_GLOBAL;
 
 use super::{transfer::WriterTransferProducer, ServerRequest, WriterClient};
 use serde::Serialize;
-use std::{marker::PhantomData, net::SocketAddr, panic, path::Path};
+use std::{marker::PhantomData, net::SocketAddr, panic, path::Path, thread, time::Duration};
 use thiserror::Error;
 
+/// How many times, and how often, a client tries to reach the writer server again after it
+/// became unreachable, which happens while the writer background worker is restarting.
+const RECONNECT_ATTEMPTS: usize = 30;
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
 pub struct Client<T: Serialize> {
     addr: std::net::SocketAddr,
     http: reqwest::blocking::Client,
     producer: Option<WriterTransferProducer<T>>,
+    /// Looks up the current address of the writer server, which changes when it restarts.
+    /// Without it, the client gives up as soon as the server is unreachable.
+    resolve_addr: Option<fn() -> Option<SocketAddr>>,
     marker: PhantomData<T>,
 }
 
@@ -55,6 +63,7 @@ impl<T: Serialize> Client<T> {
             addr,
             http,
             producer: None,
+            resolve_addr: None,
             marker: PhantomData,
         }
     }
@@ -69,19 +78,48 @@ impl<T: Serialize> Client<T> {
             }
         };
 
-        Self::new(addr)
+        Self {
+            resolve_addr: Some(|| WRITER_GLOBAL.share().addr),
+            ..Self::new(addr)
+        }
     }
 
     fn url(&self) -> String {
         format!("http://{}", self.addr)
     }
 
+    /// Wait for the writer server to come back after it became unreachable. If it was
+    /// restarted, any state it held for this client, like uncommitted documents or an open
+    /// transfer, is gone, which is reported as `ClientError::WriterRestarted` so that the
+    /// caller can replay its operations.
+    fn reconnect(&mut self, err: ClientError) -> Result<(), ClientError> {
+        let Some(resolve_addr) = self.resolve_addr else {
+            return Err(err);
+        };
+
+        for _ in 0..RECONNECT_ATTEMPTS {
+            if let Some(addr) = resolve_addr() {
+                if addr != self.addr {
+                    self.addr = addr;
+                    return Err(ClientError::WriterRestarted(addr));
+                }
+            }
+            thread::sleep(RECONNECT_INTERVAL);
+        }
+
+        Err(err)
+    }
+
     fn send_request(&mut self, request: ServerRequest<T>) -> Result<(), ClientError> {
         // If there is an open pending transfer, stop it so that we can continue
         // with more requests.
         self.stop_transfer();
         let bytes = bincode::serialize(&request).unwrap();
-        let response = self.http.post(self.url()).body::<Vec<u8>>(bytes).send()?;
+        let response = match self.http.post(self.url()).body::<Vec<u8>>(bytes).send() {
+            Ok(response) => response,
+            Err(err) if err.is_connect() => return self.reconnect(err.into()),
+            Err(err) => return Err(err.into()),
+        };
 
         match response.status() {
             reqwest::StatusCode::OK => Ok(()),
@@ -95,7 +133,7 @@ impl<T: Serialize> Client<T> {
     fn send_transfer<P: AsRef<Path>>(
         &mut self,
         pipe_path: P,
-        request: T,
+        request: &T,
     ) -> Result<(), ClientError> {
         if self.producer.is_none() {
             // Send a request to open a transfer to the server.
@@ -108,7 +146,11 @@ impl<T: Serialize> Client<T> {
         }
 
         // There is an existing producer in client state, use it to send the request.
-        self.producer.as_mut().unwrap().write_message(&request)?;
+        // If the server stopped reading from the pipe, it may have crashed.
+        if let Err(err) = self.producer.as_mut().unwrap().write_message(request) {
+            self.stop_transfer();
+            return self.reconnect(err.into());
+        }
         Ok(())
     }
 
@@ -135,7 +177,7 @@ impl<T: Serialize> WriterClient<T> for Client<T> {
         self.send_request(ServerRequest::Request(request))
     }
 
-    fn transfer<P: AsRef<Path>>(&mut self, pipe_path: P, request: T) -> Result<(), ClientError> {
+    fn transfer<P: AsRef<Path>>(&mut self, pipe_path: P, request: &T) -> Result<(), ClientError> {
         self.send_transfer(pipe_path, request)
     }
 }
@@ -156,6 +198,9 @@ pub enum ClientError {
 
     #[error(transparent)]
     SerdeError(#[from] serde_json::Error),
+
+    #[error("writer server restarted at {0}, uncommitted operations were lost")]
+    WriterRestarted(SocketAddr),
 }
 
 #[cfg(test)]
@@ -170,8 +215,12 @@ mod tests {
         directory: mock_dir().writer_dir,
         document: simple_doc(simple_schema(default_fields())),
     })]
-    #[case::commit_request(WriterRequest::Commit { directory: mock_dir().writer_dir })]
-    #[case::abort_request(WriterRequest::Abort {directory: mock_dir().writer_dir})]
+    #[case::insert_many_request(WriterRequest::InsertMany {
+        directory: mock_dir().writer_dir,
+        documents: vec![simple_doc(simple_schema(default_fields())); 3],
+    })]
+    #[case::commit_request(WriterRequest::Commit { directory: mock_dir().writer_dir, pid: 1 })]
+    #[case::abort_request(WriterRequest::Abort {directory: mock_dir().writer_dir, pid: 1})]
     #[case::vacuum_request(WriterRequest::Vacuum { directory: mock_dir().writer_dir })]
     #[case::drop_index_request(WriterRequest::DropIndex { directory: mock_dir().writer_dir })]
     /// Test request serialization and transfer between client and server.
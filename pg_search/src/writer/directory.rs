@@ -18,6 +18,7 @@
 use crate::env;
 use derive_more::AsRef;
 use fs2::FileExt;
+use pgrx::PgRelation;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
     fs::{self, File},
@@ -32,6 +33,18 @@ static SEARCH_DIR_NAME: &str = "pg_search";
 static SEARCH_INDEX_CONFIG_FILE_NAME: &str = "search-index.json";
 static TANTIVY_DIR_NAME: &str = "tantivy";
 static WRITER_TRANSFER_DIR_NAME: &str = "writer_transfer";
+static PREPARED_INSERTS_DIR_NAME: &str = "prepared_inserts";
+static MERGE_STATUS_FILE_NAME: &str = "merge-status.json";
+static MAINTENANCE_PAUSED_FILE_NAME: &str = "maintenance-paused";
+static READ_ONLY_FILE_NAME: &str = "read-only";
+static WRITER_STATUS_FILE_NAME: &str = "writer-status.json";
+static NEEDS_RESYNC_FILE_NAME: &str = "needs-resync";
+static BUILD_PROGRESS_FILE_NAME: &str = "build-progress.json";
+static HEAL_STATUS_FILE_NAME: &str = "heal-status.json";
+static SHIPPED_STATUS_FILE_NAME: &str = "shipped.json";
+static FAULTS_FILE_NAME: &str = "faults.json";
+static SOURCE_FILE_NAME: &str = "source.json";
+static HISTORY_DIR_NAME: &str = "history";
 
 /// The top-level folder name for ParadeDB extension inside the Postgres data directory.
 #[derive(AsRef)]
@@ -49,6 +62,54 @@ pub struct TantivyDirPath(pub PathBuf);
 #[derive(AsRef)]
 #[as_ref(forward)]
 pub struct WriterTransferPipeFilePath(pub PathBuf);
+/// The name of the directory where inserts of prepared transactions are kept until they resolve.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct PreparedInsertsDirPath(pub PathBuf);
+/// The name of the file where the writer process reports the progress of merges on an index.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct MergeStatusFilePath(pub PathBuf);
+/// The name of the file whose presence pauses background maintenance of an index.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct MaintenancePausedFilePath(pub PathBuf);
+/// The name of the file whose presence makes the writer reject changes to an index.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct ReadOnlyFilePath(pub PathBuf);
+/// The name of the file where the heal worker saves its progress through the table of an index.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct HealStatusFilePath(pub PathBuf);
+/// The name of the file where a standby records the commit shipped from the primary it installed.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct ShippedStatusFilePath(pub PathBuf);
+/// The name of the file listing the faults injected into the processes that use an index.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct FaultsFilePath(pub PathBuf);
+/// The name of the file describing the source that the table of an index is copied from.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct SourceFilePath(pub PathBuf);
+/// The name of the file where the writer process reports the changes waiting to be committed.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct WriterStatusFilePath(pub PathBuf);
+/// The name of the file whose presence marks an index as out of date with its table.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct NeedsResyncFilePath(pub PathBuf);
+/// The name of the file where a connection building the index reports its progress.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct BuildProgressFilePath(pub PathBuf);
+/// The name of the folder holding the replaced and deleted versions of the rows of an index.
+#[derive(AsRef)]
+#[as_ref(forward)]
+pub struct HistoryDirPath(pub PathBuf);
 
 pub trait SearchFs {
     /// Load a persisted index from disk, so it can be reused between connections.
@@ -65,51 +126,220 @@ pub trait SearchFs {
         &self,
         ensure_exists: bool,
     ) -> Result<WriterTransferPipeFilePath, SearchDirectoryError>;
+    // Return and ensure the existence of the prepared inserts path.
+    fn prepared_inserts_dir_path(
+        &self,
+        ensure_exists: bool,
+    ) -> Result<PreparedInsertsDirPath, SearchDirectoryError>;
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, Hash, Eq, PartialEq)]
 pub struct WriterDirectory {
     pub index_name: String,
     pub database_oid: u32,
+    /// The file node of the index relation, so that an index that Postgres rewrites into a new
+    /// file, as on TRUNCATE or REINDEX, is built in a new directory. Zero for directories
+    /// created before file nodes were part of their name.
+    #[serde(default)]
+    pub relfilenode: u32,
     pub postgres_data_dir_path: PathBuf,
 }
 
 impl WriterDirectory {
+    /// Useful in a connection process that has the index relation open.
+    pub fn from_index_relation(index_relation: &PgRelation) -> Self {
+        let directory = Self {
+            index_name: index_relation.name().into(),
+            database_oid: env::postgres_database_oid(),
+            relfilenode: env::relation_filenode(index_relation),
+            postgres_data_dir_path: env::postgres_data_dir_path(),
+        };
+        directory.upgrade_legacy();
+        directory
+    }
+
+    /// Useful when building an index. The directory of its file node is never moved from the
+    /// directory of an earlier file node, which stays searchable until the build commits.
+    pub fn for_build(index_relation: &PgRelation) -> Self {
+        Self {
+            index_name: index_relation.name().into(),
+            database_oid: env::postgres_database_oid(),
+            relfilenode: env::relation_filenode(index_relation),
+            postgres_data_dir_path: env::postgres_data_dir_path(),
+        }
+    }
+
     /// Useful in a connection process, where the database oid is available in the environment.
+    /// The file node of the index is looked up in the catalog. An index that was dropped
+    /// already has the directory it left behind, if any.
     pub fn from_index_name(index_name: &str) -> Self {
         let database_oid = env::postgres_database_oid();
         let postgres_data_dir_path = env::postgres_data_dir_path();
-        Self {
+        let relfilenode = env::index_filenode(index_name).unwrap_or_else(|| {
+            Self::list_all(&postgres_data_dir_path)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|directory| {
+                    directory.database_oid == database_oid && directory.index_name == index_name
+                })
+                .map(|directory| directory.relfilenode)
+                .max()
+                .unwrap_or_default()
+        });
+        let directory = Self {
             index_name: index_name.into(),
             database_oid,
+            relfilenode,
             postgres_data_dir_path,
-        }
+        };
+        directory.upgrade_legacy();
+        directory
     }
 
     /// Useful in a background process where the database oid must be specified.
     #[allow(dead_code)]
-    pub fn from_db_id_and_index_name(database_oid: u32, index_name: &str) -> Self {
+    pub fn from_db_id_and_index_name(
+        database_oid: u32,
+        relfilenode: u32,
+        index_name: &str,
+    ) -> Self {
         let postgres_data_dir_path = env::postgres_data_dir_path();
         Self {
             index_name: index_name.into(),
             database_oid,
+            relfilenode,
             postgres_data_dir_path,
         }
     }
 
+    /// The name of the directory, "<database_oid>_<relfilenode>_<index_name>", or
+    /// "<database_oid>_<index_name>" for directories created before file nodes were recorded.
+    pub fn dir_name(&self) -> String {
+        let database_oid = &self.database_oid;
+        let index_name = &self.index_name;
+        match self.relfilenode {
+            0 => format!("{database_oid}_{index_name}"),
+            relfilenode => format!("{database_oid}_{relfilenode}_{index_name}"),
+        }
+    }
+
+    /// The directory named `dir_name`, see `WriterDirectory::dir_name`, or None if the name
+    /// isn't the name of an index directory, like the name of the writer transfer directory.
+    pub fn from_dir_name(dir_name: &str, postgres_data_dir_path: &Path) -> Option<Self> {
+        let (database_oid, rest) = dir_name.split_once('_')?;
+        let database_oid = database_oid.parse::<u32>().ok()?;
+        let (relfilenode, index_name) = match rest.split_once('_') {
+            Some((relfilenode, index_name)) => match relfilenode.parse::<u32>() {
+                Ok(relfilenode) if relfilenode > 0 => (relfilenode, index_name),
+                _ => (0, rest),
+            },
+            None => (0, rest),
+        };
+        Some(Self {
+            index_name: index_name.into(),
+            database_oid,
+            relfilenode,
+            postgres_data_dir_path: postgres_data_dir_path.to_path_buf(),
+        })
+    }
+
+    /// Move the directory of this index from where it was kept before file nodes were part of
+    /// the name, and point its config at the new location.
+    fn upgrade_legacy(&self) {
+        if self.relfilenode == 0 || self.exists().unwrap_or(true) {
+            return;
+        }
+        let legacy = Self {
+            relfilenode: 0,
+            ..self.clone()
+        };
+        let (Ok(SearchIndexDirPath(legacy_path)), Ok(SearchIndexDirPath(path))) = (
+            legacy.search_index_dir_path(false),
+            self.search_index_dir_path(false),
+        ) else {
+            return;
+        };
+        // Another connection may have moved it first.
+        if fs::rename(legacy_path, path).is_err() {
+            return;
+        }
+        if let Ok(mut config) = self.load_index::<serde_json::Value>() {
+            if let (Some(directory), Ok(moved)) =
+                (config.get_mut("directory"), serde_json::to_value(self))
+            {
+                *directory = moved;
+                let _ = self.save_index(&config);
+            }
+        }
+    }
+
+    /// Every index directory under the Postgres data directory, across all databases.
+    /// Useful in a background process that maintains all indexes.
+    pub fn list_all(postgres_data_dir_path: &Path) -> Result<Vec<Self>, SearchDirectoryError> {
+        let search_dir_path = postgres_data_dir_path
+            .join(PARADE_DATA_DIR_NAME)
+            .join(SEARCH_DIR_NAME);
+        if !search_dir_path.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut directories = vec![];
+        for entry in fs::read_dir(&search_dir_path)
+            .map_err(|err| SearchDirectoryError::ReadDirectoryEntry(search_dir_path.clone(), err))?
+        {
+            let entry = entry.map_err(|err| {
+                SearchDirectoryError::ReadDirectoryEntry(search_dir_path.clone(), err)
+            })?;
+            let file_name = entry.file_name().to_string_lossy().into_owned();
+            if let Some(directory) = Self::from_dir_name(&file_name, postgres_data_dir_path) {
+                directories.push(directory);
+            }
+        }
+        Ok(directories)
+    }
+
+    /// The other directories of the same index, left behind when Postgres rewrote it into a
+    /// new file, or created before file nodes were part of the name.
+    pub fn siblings(&self) -> Result<Vec<Self>, SearchDirectoryError> {
+        Ok(Self::list_all(&self.postgres_data_dir_path)?
+            .into_iter()
+            .filter(|directory| {
+                directory.database_oid == self.database_oid
+                    && directory.index_name == self.index_name
+                    && directory.relfilenode != self.relfilenode
+            })
+            .collect())
+    }
+
+    /// Copy the settings kept next to the index, like paused maintenance, injected faults or
+    /// the source of its rows, from another directory of the same index, see `siblings`.
+    pub fn copy_settings_from(&self, other: &Self) -> Result<(), SearchDirectoryError> {
+        let SearchIndexDirPath(from_path) = other.search_index_dir_path(false)?;
+        let SearchIndexDirPath(to_path) = self.search_index_dir_path(true)?;
+        for file_name in [
+            MAINTENANCE_PAUSED_FILE_NAME,
+            FAULTS_FILE_NAME,
+            SOURCE_FILE_NAME,
+        ] {
+            match fs::copy(from_path.join(file_name), to_path.join(file_name)) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(SearchDirectoryError::IndexFileWrite(self.clone(), err)),
+            }
+        }
+        Ok(())
+    }
+
     /// The root path for the directory tree.
     fn search_index_dir_path(
         &self,
         ensure_exists: bool,
     ) -> Result<SearchIndexDirPath, SearchDirectoryError> {
-        let database_oid = &self.database_oid;
-        let index_name = &self.index_name;
-        let unique_index_dir_name = format!("{database_oid}_{index_name}");
         let search_index_dir_path = &self
             .postgres_data_dir_path
             .join(PARADE_DATA_DIR_NAME)
             .join(SEARCH_DIR_NAME)
-            .join(unique_index_dir_name);
+            .join(self.dir_name());
 
         if ensure_exists {
             Self::ensure_dir(search_index_dir_path)?;
@@ -133,6 +363,91 @@ impl WriterDirectory {
         Ok(SearchIndexConfigFilePath(search_index_config_file_path))
     }
 
+    pub fn merge_status_file_path(&self) -> Result<MergeStatusFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(MergeStatusFilePath(index_path.join(MERGE_STATUS_FILE_NAME)))
+    }
+
+    pub fn maintenance_paused_file_path(
+        &self,
+    ) -> Result<MaintenancePausedFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(MaintenancePausedFilePath(
+            index_path.join(MAINTENANCE_PAUSED_FILE_NAME),
+        ))
+    }
+
+    pub fn read_only_file_path(&self) -> Result<ReadOnlyFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(ReadOnlyFilePath(index_path.join(READ_ONLY_FILE_NAME)))
+    }
+
+    pub fn writer_status_file_path(&self) -> Result<WriterStatusFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(WriterStatusFilePath(
+            index_path.join(WRITER_STATUS_FILE_NAME),
+        ))
+    }
+
+    pub fn needs_resync_file_path(&self) -> Result<NeedsResyncFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(NeedsResyncFilePath(index_path.join(NEEDS_RESYNC_FILE_NAME)))
+    }
+
+    pub fn build_progress_file_path(&self) -> Result<BuildProgressFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(BuildProgressFilePath(
+            index_path.join(BUILD_PROGRESS_FILE_NAME),
+        ))
+    }
+
+    pub fn heal_status_file_path(&self) -> Result<HealStatusFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(HealStatusFilePath(index_path.join(HEAL_STATUS_FILE_NAME)))
+    }
+
+    pub fn shipped_status_file_path(&self) -> Result<ShippedStatusFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(ShippedStatusFilePath(
+            index_path.join(SHIPPED_STATUS_FILE_NAME),
+        ))
+    }
+
+    pub fn faults_file_path(&self) -> Result<FaultsFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(FaultsFilePath(index_path.join(FAULTS_FILE_NAME)))
+    }
+
+    pub fn source_file_path(&self) -> Result<SourceFilePath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(true)?;
+        Ok(SourceFilePath(index_path.join(SOURCE_FILE_NAME)))
+    }
+
+    pub fn history_dir_path(
+        &self,
+        ensure_exists: bool,
+    ) -> Result<HistoryDirPath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(ensure_exists)?;
+        let history_dir_path = index_path.join(HISTORY_DIR_NAME);
+
+        if ensure_exists {
+            Self::ensure_dir(&history_dir_path)?;
+        }
+        Ok(HistoryDirPath(history_dir_path))
+    }
+
+    /// Bytes taken up on local disk by every file of the directory.
+    pub fn total_bytes(&self) -> Result<u64, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(false)?;
+        Ok(WalkDir::new(index_path)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.metadata().ok())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum())
+    }
+
     fn ensure_dir(path: &Path) -> Result<(), SearchDirectoryError> {
         if !path.exists() {
             Self::create_dir_all(path)?
@@ -292,6 +607,19 @@ impl SearchFs for WriterDirectory {
 
         Ok(WriterTransferPipeFilePath(transfer_pipe_file.to_path_buf()))
     }
+
+    fn prepared_inserts_dir_path(
+        &self,
+        ensure_exists: bool,
+    ) -> Result<PreparedInsertsDirPath, SearchDirectoryError> {
+        let SearchIndexDirPath(index_path) = self.search_index_dir_path(ensure_exists)?;
+        let prepared_inserts_dir_path = index_path.join(PREPARED_INSERTS_DIR_NAME);
+
+        if ensure_exists {
+            Self::ensure_dir(&prepared_inserts_dir_path)?;
+        }
+        Ok(PreparedInsertsDirPath(prepared_inserts_dir_path))
+    }
 }
 
 #[derive(Debug, Error)]
@@ -383,4 +711,36 @@ mod tests {
 
         Ok(())
     }
+
+    #[rstest]
+    fn test_list_all_directories(mock_dir: MockWriterDirectory) -> Result<()> {
+        mock_dir.writer_dir.tantivy_dir_path(true)?;
+        mock_dir.writer_dir.writer_transfer_pipe_path(true)?;
+
+        let listed = WriterDirectory::list_all(&mock_dir.writer_dir.postgres_data_dir_path)?;
+        assert_eq!(listed, vec![mock_dir.writer_dir.clone()]);
+
+        Ok(())
+    }
+
+    #[rstest]
+    fn test_copy_settings_from_sibling(mock_dir: MockWriterDirectory) -> Result<()> {
+        let SearchIndexDirPath(root) = mock_dir.writer_dir.search_index_dir_path(true)?;
+        File::create(root.join(MAINTENANCE_PAUSED_FILE_NAME))?;
+        File::create(root.join(READ_ONLY_FILE_NAME))?;
+
+        let rebuilt = WriterDirectory {
+            relfilenode: mock_dir.writer_dir.relfilenode + 1,
+            ..mock_dir.writer_dir.clone()
+        };
+        assert_eq!(rebuilt.siblings()?, vec![mock_dir.writer_dir.clone()]);
+
+        rebuilt.copy_settings_from(&mock_dir.writer_dir)?;
+        let SearchIndexDirPath(rebuilt_root) = rebuilt.search_index_dir_path(false)?;
+        assert!(rebuilt_root.join(MAINTENANCE_PAUSED_FILE_NAME).exists());
+        assert!(!rebuilt_root.join(READ_ONLY_FILE_NAME).exists());
+        assert!(!rebuilt_root.join(SOURCE_FILE_NAME).exists());
+
+        Ok(())
+    }
 }
@@ -15,9 +15,19 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
-use super::{Handler, IndexError, SearchFs, WriterDirectory, WriterRequest};
+use super::throttle::IoThrottle;
+use super::{Handler, IndexError, SearchFs, TantivyDirPath, WriterDirectory, WriterRequest};
 use crate::{
-    index::SearchIndex,
+    index::{
+        encryption::EncryptionKey,
+        fault::FaultPoint,
+        merge::{Maintenance, MergeStatus, SearchMergePolicy},
+        readonly::ReadOnly,
+        remote::{RemoteDirectory, RemoteStorage},
+        stats::WriterStatus,
+        storage::SearchDirectoryMode,
+        SearchIndex,
+    },
     schema::{
         SearchDocument, SearchFieldConfig, SearchFieldName, SearchFieldType, SearchIndexSchema,
     },
@@ -25,23 +35,108 @@ use crate::{
 use anyhow::{Context, Result};
 use std::collections::{
     hash_map::Entry::{Occupied, Vacant},
-    HashMap,
+    HashMap, HashSet,
+};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tantivy::{
+    indexer::UserOperation, query::TermSetQuery, schema::Field, Index, IndexWriter, SegmentId,
+    SegmentMeta, Term,
 };
-use tantivy::{schema::Field, Index, IndexWriter};
+
+/// How often the segments of tiered indexes are checked for any that are due to be moved to
+/// object storage, see `RemoteDirectory::move_cold_segments`.
+const TIERING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long the measured size of an index is trusted before it's measured again, see
+/// `Writer::index_size`. Commits always measure it again.
+const INDEX_SIZE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
 /// The entity that interfaces with Tantivy indexes.
 pub struct Writer {
     /// Map of index directory path to Tantivy writer instance.
     tantivy_writers: HashMap<WriterDirectory, IndexWriter>,
+    /// Map of index directory path to the merge policy it was created with.
+    merge_policies: HashMap<WriterDirectory, SearchMergePolicy>,
+    /// Map of index directory path to the progress of its merges. Merges complete on
+    /// their own threads, so this is shared with them.
+    merge_statuses: Arc<Mutex<HashMap<WriterDirectory, MergeStatus>>>,
+    /// Map of index directory path to the interval between its background commits, for
+    /// indexes that don't commit with each transaction.
+    refresh_intervals: HashMap<WriterDirectory, Option<Duration>>,
+    /// Indexes with deletes that haven't been committed yet, see `reclaim_deletes`.
+    uncommitted_deletes: HashSet<WriterDirectory>,
+    /// Map of index directory path to the time its next background commit is due.
+    pending_refreshes: HashMap<WriterDirectory, Instant>,
+    /// Map of index directory path to the bytes of uncommitted documents after which it is
+    /// committed, for indexes with a memory budget.
+    memory_budgets: HashMap<WriterDirectory, Option<u64>>,
+    /// Map of index directory path to the bytes of documents added since its last commit.
+    uncommitted_bytes: HashMap<WriterDirectory, u64>,
+    /// Map of index directory path to the counters reported to backends, see `WriterStatus`.
+    writer_statuses: HashMap<WriterDirectory, WriterStatus>,
+    /// Map of index directory path to the object storage holding its segments, if any.
+    storages: HashMap<WriterDirectory, Option<RemoteStorage>>,
+    /// When segments of tiered indexes are next checked, see `move_cold_segments`.
+    next_tiering: Instant,
+    /// Map of index directory path to the bytes it may take up on disk, for indexes with a
+    /// maximum size.
+    max_index_sizes: HashMap<WriterDirectory, Option<u64>>,
+    /// Map of index directory path to its last measured size on disk, and when it was measured.
+    index_sizes: HashMap<WriterDirectory, (u64, Instant)>,
+    /// The bytes of disk space below which inserts fail, and merges are skipped.
+    min_free_disk_space: u64,
+    /// The WAL timeline of the cluster, stamped on the status of new indexes. See `Resync`.
+    timeline: u32,
+    /// Limits the rate at which inserted documents are written.
+    index_throttle: IoThrottle,
+    /// Limits the rate at which background merges are started.
+    merge_throttle: IoThrottle,
+    /// Map of index directory path and backend process to the documents that backend
+    /// transferred to the index, until it commits. See `Writer::stage`.
+    staged: HashMap<(WriterDirectory, u32), Vec<StagedDocument>>,
+    /// The backend process that sent the transfer being handled, if any.
+    transfer_pid: Option<u32>,
+}
+
+/// A document held back until the transaction that inserted it commits, see `Writer::stage`.
+struct StagedDocument {
+    document: SearchDocument,
+    /// Whether the document replaces the documents that have the same key.
+    upsert: bool,
 }
 
 impl Writer {
     pub fn new() -> Self {
         Self {
             tantivy_writers: HashMap::new(),
+            merge_policies: HashMap::new(),
+            merge_statuses: Arc::new(Mutex::new(HashMap::new())),
+            refresh_intervals: HashMap::new(),
+            uncommitted_deletes: HashSet::new(),
+            pending_refreshes: HashMap::new(),
+            memory_budgets: HashMap::new(),
+            uncommitted_bytes: HashMap::new(),
+            writer_statuses: HashMap::new(),
+            storages: HashMap::new(),
+            next_tiering: Instant::now(),
+            max_index_sizes: HashMap::new(),
+            index_sizes: HashMap::new(),
+            min_free_disk_space: 0,
+            timeline: 0,
+            index_throttle: IoThrottle::new(0),
+            merge_throttle: IoThrottle::new(0),
+            staged: HashMap::new(),
+            transfer_pid: None,
         }
     }
 
+    pub fn set_timeline(&mut self, timeline: u32) {
+        self.timeline = timeline;
+    }
+
     /// Check the writer server cache for an existing IndexWriter. If it does not exist,
     /// then retrieve the SearchIndex and use it to create a new IndexWriter, caching it.
     fn get_writer(&mut self, directory: WriterDirectory) -> Result<&mut IndexWriter, IndexError> {
@@ -60,6 +155,10 @@ impl Writer {
         directory: WriterDirectory,
         document: SearchDocument,
     ) -> Result<(), IndexError> {
+        self.check_disk_space(&directory)?;
+        self.throttle_insert(&document);
+        self.track_memory(&directory, &document)?;
+        self.writer_status(&directory).uncommitted_documents += 1;
         let writer = self.get_writer(directory)?;
         // Add the Tantivy document to the index.
         writer.add_document(document.into())?;
@@ -67,22 +166,235 @@ impl Writer {
         Ok(())
     }
 
+    /// Insert a batch of documents, looking up the index writer only once.
+    fn insert_many(
+        &mut self,
+        directory: WriterDirectory,
+        documents: Vec<SearchDocument>,
+    ) -> Result<(), IndexError> {
+        self.check_disk_space(&directory)?;
+        for document in &documents {
+            self.throttle_insert(document);
+            self.track_memory(&directory, document)?;
+        }
+        self.writer_status(&directory).uncommitted_documents += documents.len() as u64;
+
+        let writer = self.get_writer(directory)?;
+        writer.run(
+            documents
+                .into_iter()
+                .map(|document| UserOperation::Add(document.into()))
+                .collect::<Vec<_>>(),
+        )?;
+        Ok(())
+    }
+
+    /// Insert a new version of a row. The older versions are deleted in the same batch of
+    /// operations, so that no commit can hold both versions, or neither of them.
+    fn upsert(
+        &mut self,
+        directory: WriterDirectory,
+        document: SearchDocument,
+    ) -> Result<(), IndexError> {
+        self.check_disk_space(&directory)?;
+        self.throttle_insert(&document);
+        self.track_memory(&directory, &document)?;
+        self.writer_status(&directory).uncommitted_documents += 1;
+        let mut operations = vec![];
+        if let Some(key_term) = document.key_term() {
+            operations.push(UserOperation::Delete(key_term));
+        }
+        operations.push(UserOperation::Add(document.into()));
+
+        let writer = self.get_writer(directory)?;
+        writer.run(operations)?;
+        Ok(())
+    }
+
+    /// Inserts fail while the index is over its maximum size, or while the disk is nearly
+    /// full, rather than filling the disk in the middle of a commit or merge. Deletes and
+    /// vacuums still work, so space can be freed by deleting rows.
+    fn check_disk_space(&mut self, directory: &WriterDirectory) -> Result<(), IndexError> {
+        if self.min_free_disk_space > 0 {
+            let free = Self::free_disk_space(directory)?;
+            if free < self.min_free_disk_space {
+                return Err(IndexError::LowDiskSpace(
+                    free / 1_000_000,
+                    self.min_free_disk_space / 1_000_000,
+                ));
+            }
+        }
+
+        if let Some(max_size) = self.max_index_size(directory)? {
+            let size = self.index_size(directory)?;
+            if size > max_size {
+                return Err(IndexError::IndexSizeExceeded(
+                    directory.index_name.clone(),
+                    size / 1_000_000,
+                    max_size / 1_000_000,
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    fn free_disk_space(directory: &WriterDirectory) -> Result<u64, IndexError> {
+        let TantivyDirPath(tantivy_dir_path) = directory.tantivy_dir_path(false)?;
+        Ok(fs2::available_space(tantivy_dir_path)?)
+    }
+
+    /// Whether writing `bytes` to the disk of an index leaves the minimum free space.
+    fn has_disk_space(&self, directory: &WriterDirectory, bytes: u64) -> Result<bool, IndexError> {
+        Ok(Self::free_disk_space(directory)? >= bytes + self.min_free_disk_space)
+    }
+
+    /// The size on disk of an index. Measuring it lists every file of the index, so it's
+    /// only measured again after a commit, or once `INDEX_SIZE_CHECK_INTERVAL` has passed.
+    fn index_size(&mut self, directory: &WriterDirectory) -> Result<u64, IndexError> {
+        if let Some((size, measured)) = self.index_sizes.get(directory) {
+            if measured.elapsed() < INDEX_SIZE_CHECK_INTERVAL {
+                return Ok(*size);
+            }
+        }
+
+        let TantivyDirPath(tantivy_dir_path) = directory.tantivy_dir_path(false)?;
+        let mut size = 0;
+        for entry in std::fs::read_dir(tantivy_dir_path)? {
+            // Files may be garbage collected in the meantime.
+            size += entry?.metadata().map_or(0, |m| m.len());
+        }
+        self.index_sizes
+            .insert(directory.clone(), (size, Instant::now()));
+        Ok(size)
+    }
+
+    /// Slowing down here applies backpressure to the client transferring the documents.
+    fn throttle_insert(&mut self, document: &SearchDocument) {
+        if self.index_throttle.is_limited() {
+            let bytes = bincode::serialized_size(document).unwrap_or_default();
+            self.index_throttle.consume(bytes);
+            thread::sleep(self.index_throttle.delay());
+        }
+    }
+
+    /// Indexes with a memory budget are committed as soon as the documents added since their
+    /// last commit fill it, instead of waiting for their refresh interval. Under frequent
+    /// small transactions, this writes fewer and larger segments, which need less merging.
+    fn track_memory(
+        &mut self,
+        directory: &WriterDirectory,
+        document: &SearchDocument,
+    ) -> Result<(), IndexError> {
+        let Some(budget) = self.memory_budget(directory)? else {
+            return Ok(());
+        };
+
+        let uncommitted = self.uncommitted_bytes.entry(directory.clone()).or_default();
+        *uncommitted += bincode::serialized_size(document).unwrap_or_default();
+        if *uncommitted >= budget {
+            // The commit is run when the server ticks, right after this request.
+            self.pending_refreshes
+                .insert(directory.clone(), Instant::now());
+        }
+        Ok(())
+    }
+
+    /// Hold back the documents transferred by a backend to an index with a refresh interval,
+    /// until that backend commits. The background commits of the index could otherwise
+    /// commit the documents of a transaction that only partly reached the writer before it
+    /// aborted, and rolling back the writer would lose the documents of committed
+    /// transactions that are still waiting for a background commit. The staged documents
+    /// are kept in memory, and lost if the writer restarts, like uncommitted documents.
+    fn stage(
+        &mut self,
+        directory: WriterDirectory,
+        pid: u32,
+        documents: Vec<SearchDocument>,
+        upsert: bool,
+    ) -> Result<(), IndexError> {
+        self.check_disk_space(&directory)?;
+        self.staged.entry((directory, pid)).or_default().extend(
+            documents
+                .into_iter()
+                .map(|document| StagedDocument { document, upsert }),
+        );
+        Ok(())
+    }
+
+    /// The backend whose transferred documents are staged for `directory`, if any.
+    fn staging_pid(&mut self, directory: &WriterDirectory) -> Result<Option<u32>, IndexError> {
+        match self.transfer_pid {
+            Some(pid) if self.refresh_interval(directory)?.is_some() => Ok(Some(pid)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Add the documents staged by a backend as a single batch of operations, so that a
+    /// background commit holds either all of them or none of them.
+    fn add_staged(&mut self, directory: &WriterDirectory, pid: u32) -> Result<(), IndexError> {
+        let Some(staged) = self.staged.remove(&(directory.clone(), pid)) else {
+            return Ok(());
+        };
+
+        let mut operations = vec![];
+        let count = staged.len() as u64;
+        for StagedDocument { document, upsert } in staged {
+            self.throttle_insert(&document);
+            self.track_memory(directory, &document)?;
+            if let Some(key_term) = document.key_term().filter(|_| upsert) {
+                operations.push(UserOperation::Delete(key_term));
+            }
+            operations.push(UserOperation::Add(document.into()));
+        }
+        self.writer_status(directory).uncommitted_documents += count;
+
+        let writer = self.get_writer(directory.clone())?;
+        writer.run(operations)?;
+        Ok(())
+    }
+
     fn delete(
         &mut self,
         directory: WriterDirectory,
         ctid_field: &Field,
         ctid_values: &[u64],
     ) -> Result<(), IndexError> {
-        let writer = self.get_writer(directory)?;
-        for ctid in ctid_values {
-            let ctid_term = tantivy::Term::from_field_u64(*ctid_field, *ctid);
-            writer.delete_term(ctid_term);
+        if ctid_values.is_empty() {
+            return Ok(());
         }
+
+        // A bulk delete can remove millions of rows at once. Queuing a single delete query
+        // for the whole set is much cheaper for Tantivy to apply than one delete per term.
+        self.uncommitted_deletes.insert(directory.clone());
+        let writer = self.get_writer(directory)?;
+        let ctid_terms = ctid_values
+            .iter()
+            .map(|ctid| Term::from_field_u64(*ctid_field, *ctid));
+        writer.delete_query(Box::new(TermSetQuery::new(ctid_terms)))?;
         Ok(())
     }
 
-    fn commit(&mut self, directory: WriterDirectory) -> Result<()> {
+    /// Commit the changes made to an index. Indexes with a refresh interval are committed
+    /// in the background instead, at most one interval later, so that transactions don't
+    /// wait on Tantivy commits and many of them are batched into a single commit.
+    fn commit(&mut self, directory: WriterDirectory, pid: u32) -> Result<()> {
+        self.add_staged(&directory, pid)?;
+        if let Some(interval) = self.refresh_interval(&directory)? {
+            self.pending_refreshes
+                .entry(directory.clone())
+                .or_insert_with(|| Instant::now() + interval);
+            self.save_writer_status(&directory);
+            return Ok(());
+        }
+        self.commit_now(directory)
+    }
+
+    fn commit_now(&mut self, directory: WriterDirectory) -> Result<()> {
+        self.pending_refreshes.remove(&directory);
+        self.uncommitted_bytes.remove(&directory);
+        self.index_sizes.remove(&directory);
         if directory.exists()? {
+            FaultPoint::WriterBeforeCommit.hit(&directory);
             let writer = self.get_writer(directory.clone())?;
             writer
                 .prepare_commit()
@@ -90,6 +402,20 @@ impl Writer {
             writer
                 .commit()
                 .context("error committing to tantivy index")?;
+            FaultPoint::WriterAfterCommit.hit(&directory);
+
+            let status = self.writer_status(&directory);
+            status.documents_indexed += std::mem::take(&mut status.uncommitted_documents);
+            status.commits += 1;
+            self.save_writer_status(&directory);
+
+            // The commit succeeded, so a failure to start merging must not be reported to
+            // the committing transaction.
+            if self.uncommitted_deletes.remove(&directory) {
+                if let Err(err) = self.reclaim_deletes(directory.clone()) {
+                    tracing::error!("could not reclaim deleted documents in {directory:?}: {err}");
+                }
+            }
         } else {
             // If the directory doesn't exist, then the index doesn't exist anymore.
             // Rare, but possible if a previous delete failed. Drop it to free the space.
@@ -98,7 +424,23 @@ impl Writer {
         Ok(())
     }
 
-    fn abort(&mut self, directory: WriterDirectory) -> Result<(), IndexError> {
+    fn abort(&mut self, directory: WriterDirectory, pid: u32) -> Result<(), IndexError> {
+        // The documents of the transaction never reached the Tantivy writer.
+        self.staged.remove(&(directory.clone(), pid));
+
+        // Changes from committed transactions that are waiting on a background commit must
+        // not be rolled back. The documents of transactions are staged on indexes with a
+        // refresh interval for that reason, see `stage`.
+        if self.pending_refreshes.contains_key(&directory) {
+            return Ok(());
+        }
+
+        self.uncommitted_deletes.remove(&directory);
+        self.uncommitted_bytes.remove(&directory);
+        if let Some(status) = self.writer_statuses.get_mut(&directory) {
+            status.uncommitted_documents = 0;
+        }
+
         // If the transaction was aborted, we should roll back the writer to the last commit.
         // Otherwise, partialy written data could stick around for the next transaction.
         if let Some(writer) = self.tantivy_writers.get_mut(&directory) {
@@ -108,25 +450,387 @@ impl Writer {
         Ok(())
     }
 
+    /// The counters of an index. They start from the ones saved before the writer process
+    /// last restarted, so that they only ever go up.
+    fn writer_status(&mut self, directory: &WriterDirectory) -> &mut WriterStatus {
+        let timeline = self.timeline;
+        self.writer_statuses
+            .entry(directory.clone())
+            .or_insert_with(|| {
+                let status = WriterStatus::load(directory).unwrap_or_default();
+                WriterStatus {
+                    // Uncommitted documents were lost with the previous writer process.
+                    uncommitted_documents: 0,
+                    // Indexes created since the writer started are on the current timeline.
+                    timeline: if status.timeline == 0 {
+                        timeline
+                    } else {
+                        status.timeline
+                    },
+                    ..status
+                }
+            })
+    }
+
+    /// Report the counters of an index to backends. This is only for monitoring, so failing
+    /// to save them must not fail the transaction.
+    fn save_writer_status(&mut self, directory: &WriterDirectory) {
+        if let Err(err) = self.writer_status(directory).save(directory) {
+            tracing::error!("could not save writer status of {directory:?}: {err}");
+        }
+    }
+
+    fn skip_values(&mut self, directory: WriterDirectory, count: u64) {
+        self.writer_status(&directory).skipped_values += count;
+        self.save_writer_status(&directory);
+    }
+
     fn vacuum(&mut self, directory: WriterDirectory) -> Result<(), IndexError> {
         let writer = self.get_writer(directory)?;
         writer.garbage_collect_files().wait()?;
         Ok(())
     }
 
+    fn refresh_interval(
+        &mut self,
+        directory: &WriterDirectory,
+    ) -> Result<Option<Duration>, IndexError> {
+        if let Some(interval) = self.refresh_intervals.get(directory) {
+            return Ok(*interval);
+        }
+        if !directory.exists()? {
+            return Ok(None);
+        }
+
+        let search_index: SearchIndex = directory.load_index()?;
+        let interval = Some(search_index.refresh_interval)
+            .filter(|millis| *millis > 0)
+            .map(Duration::from_millis);
+        self.refresh_intervals.insert(directory.clone(), interval);
+        Ok(interval)
+    }
+
+    fn memory_budget(&mut self, directory: &WriterDirectory) -> Result<Option<u64>, IndexError> {
+        if let Some(budget) = self.memory_budgets.get(directory) {
+            return Ok(*budget);
+        }
+        if !directory.exists()? {
+            return Ok(None);
+        }
+
+        let search_index: SearchIndex = directory.load_index()?;
+        let budget = Some(search_index.writer_memory_budget)
+            .filter(|megabytes| *megabytes > 0)
+            .map(|megabytes| megabytes * 1_000_000);
+        self.memory_budgets.insert(directory.clone(), budget);
+        Ok(budget)
+    }
+
+    fn max_index_size(&mut self, directory: &WriterDirectory) -> Result<Option<u64>, IndexError> {
+        if let Some(max_size) = self.max_index_sizes.get(directory) {
+            return Ok(*max_size);
+        }
+        if !directory.exists()? {
+            return Ok(None);
+        }
+
+        let search_index: SearchIndex = directory.load_index()?;
+        let max_size = Some(search_index.max_index_size)
+            .filter(|megabytes| *megabytes > 0)
+            .map(|megabytes| megabytes * 1_000_000);
+        self.max_index_sizes.insert(directory.clone(), max_size);
+        Ok(max_size)
+    }
+
+    fn storage(
+        &mut self,
+        directory: &WriterDirectory,
+    ) -> Result<Option<RemoteStorage>, IndexError> {
+        if let Some(storage) = self.storages.get(directory) {
+            return Ok(storage.clone());
+        }
+        if !directory.exists()? {
+            return Ok(None);
+        }
+
+        let search_index: SearchIndex = directory.load_index()?;
+        self.storages
+            .insert(directory.clone(), search_index.storage.clone());
+        Ok(search_index.storage)
+    }
+
+    /// Move the segments of tiered indexes that are due to object storage, at most once per
+    /// `TIERING_INTERVAL`. Only indexes written since the writer started are checked, as no
+    /// others have new segments. Returns how long until the next check, if any index is tiered.
+    fn move_cold_segments(&mut self) -> Option<Duration> {
+        let directories: Vec<WriterDirectory> = self.tantivy_writers.keys().cloned().collect();
+        let mut tiered = vec![];
+        for directory in directories {
+            match self.storage(&directory) {
+                Ok(Some(storage)) if storage.is_tiered() => tiered.push((directory, storage)),
+                Ok(_) => {}
+                Err(err) => tracing::error!("could not load storage of {directory:?}: {err}"),
+            }
+        }
+        if tiered.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        if now >= self.next_tiering {
+            self.next_tiering = now + TIERING_INTERVAL;
+            for (directory, storage) in tiered {
+                match Self::move_cold_segments_of(&directory, &storage) {
+                    Ok(0) => {}
+                    Ok(moved) => {
+                        tracing::info!("moved {moved} segments of {directory:?} to object storage")
+                    }
+                    Err(err) => tracing::error!(
+                        "could not move segments of {directory:?} to object storage: {err}"
+                    ),
+                }
+            }
+        }
+        Some(self.next_tiering.saturating_duration_since(now))
+    }
+
+    fn move_cold_segments_of(
+        directory: &WriterDirectory,
+        storage: &RemoteStorage,
+    ) -> Result<usize> {
+        let search_index: SearchIndex = directory.load_index()?;
+        let metas = search_index.underlying_index.searchable_segment_metas()?;
+        let TantivyDirPath(path) = directory.tantivy_dir_path(false)?;
+        let remote = RemoteDirectory::open(&path, storage, directory)?;
+        Ok(remote.move_cold_segments(storage, &metas)?)
+    }
+
+    /// Run the background commits that are due, or all of them if `force` is set.
+    /// Returns how long until the next one is due.
+    fn refresh(&mut self, force: bool) -> Result<Option<Duration>> {
+        let now = Instant::now();
+        let due: Vec<WriterDirectory> = self
+            .pending_refreshes
+            .iter()
+            .filter(|(_, deadline)| force || **deadline <= now)
+            .map(|(directory, _)| directory.clone())
+            .collect();
+        for directory in due {
+            self.commit_now(directory)?;
+        }
+
+        Ok(self
+            .pending_refreshes
+            .values()
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now)))
+    }
+
+    /// Start merging the segments of an index, according to its merge policy. Merges run on
+    /// Tantivy's merge threads, so we don't wait for them to finish.
+    fn merge(&mut self, directory: WriterDirectory, max_merges: usize) -> Result<(), IndexError> {
+        if !directory.exists()?
+            || Maintenance::is_paused(&directory)?
+            || ReadOnly::is_set(&directory)?
+        {
+            return Ok(());
+        }
+
+        let merge_policy = self.merge_policy(&directory)?;
+
+        let writer = self.get_writer(directory.clone())?;
+        let segments = writer.index().searchable_segment_metas()?;
+        for segment_ids in merge_policy.merge_candidates(&segments, max_merges) {
+            // Merges over the I/O limit are deferred to a later request.
+            if !self.merge_throttle.has_budget() {
+                break;
+            }
+            // A merge writes about as much as the segments it merges, and they are only
+            // removed once it completes.
+            let size = Self::segments_size(&directory, &segments, &segment_ids)?;
+            if !self.has_disk_space(&directory, size)? {
+                tracing::warn!("not enough disk space to merge segments of {directory:?}");
+                break;
+            }
+            self.merge_throttle.consume(size);
+
+            // If some of these segments are still being merged from a previous request,
+            // Tantivy refuses to start the merge and we'll try again on the next one.
+            std::mem::drop(self.start_merge(directory.clone(), &segment_ids)?);
+        }
+        Ok(())
+    }
+
+    /// Rewrite the segments left with many deleted documents after a vacuum, instead of
+    /// waiting for them to be picked by the merge policy. Deleted documents keep taking up
+    /// space, and slowing down searches, until their segment is merged.
+    fn reclaim_deletes(&mut self, directory: WriterDirectory) -> Result<(), IndexError> {
+        if Maintenance::is_paused(&directory)?
+            || ReadOnly::is_set(&directory)?
+            || !self.merge_throttle.has_budget()
+        {
+            return Ok(());
+        }
+
+        let merge_policy = self.merge_policy(&directory)?;
+        let writer = self.get_writer(directory.clone())?;
+        let segments = writer.index().searchable_segment_metas()?;
+        let segment_ids = merge_policy.reclaim_candidate(&segments);
+        if segment_ids.is_empty() {
+            return Ok(());
+        }
+
+        let size = Self::segments_size(&directory, &segments, &segment_ids)?;
+        if !self.has_disk_space(&directory, size)? {
+            tracing::warn!("not enough disk space to reclaim deletes of {directory:?}");
+            return Ok(());
+        }
+        self.merge_throttle.consume(size);
+        std::mem::drop(self.start_merge(directory, &segment_ids)?);
+        Ok(())
+    }
+
+    fn merge_policy(
+        &mut self,
+        directory: &WriterDirectory,
+    ) -> Result<SearchMergePolicy, IndexError> {
+        Ok(match self.merge_policies.entry(directory.clone()) {
+            Vacant(entry) => {
+                let search_index: SearchIndex = directory.load_index()?;
+                entry.insert(search_index.merge_policy).clone()
+            }
+            Occupied(entry) => entry.get().clone(),
+        })
+    }
+
+    /// The size on disk of the given segments, which is roughly what merging them writes.
+    fn segments_size(
+        directory: &WriterDirectory,
+        segments: &[SegmentMeta],
+        segment_ids: &[SegmentId],
+    ) -> Result<u64, IndexError> {
+        let TantivyDirPath(tantivy_dir_path) = directory.tantivy_dir_path(false)?;
+        let mut size = 0;
+        for meta in segments
+            .iter()
+            .filter(|meta| segment_ids.contains(&meta.id()))
+        {
+            for file in meta.list_files() {
+                // Files may be garbage collected in the meantime.
+                size += std::fs::metadata(tantivy_dir_path.join(file)).map_or(0, |m| m.len());
+            }
+        }
+        Ok(size)
+    }
+
+    /// Merge the smallest segments of an index together, so that at most `max_segments`
+    /// remain. Forced merges are explicitly requested, so they are neither throttled nor
+    /// affected by paused maintenance. If `wait` is set, the writer doesn't serve other requests until it's done.
+    fn force_merge(
+        &mut self,
+        directory: WriterDirectory,
+        max_segments: usize,
+        wait: bool,
+    ) -> Result<(), IndexError> {
+        let writer = self.get_writer(directory.clone())?;
+        let segments = writer.index().searchable_segment_metas()?;
+        let segment_ids = SearchMergePolicy::force_merge_candidate(&segments, max_segments);
+        if segment_ids.is_empty() {
+            return Ok(());
+        }
+
+        let size = Self::segments_size(&directory, &segments, &segment_ids)?;
+        if !self.has_disk_space(&directory, size)? {
+            return Err(IndexError::MergeFailed(format!(
+                "merging needs {} MB of free disk space, on top of paradedb.min_free_disk_space",
+                size / 1_000_000
+            )));
+        }
+
+        let merge = self.start_merge(directory, &segment_ids)?;
+        if wait {
+            merge
+                .join()
+                .map_err(|_| IndexError::MergeFailed("merge thread panicked".into()))?
+                .map_err(|err| IndexError::MergeFailed(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Start a merge on Tantivy's merge threads. The returned thread waits for the merge to
+    /// complete, and keeps the merge status of the index up to date.
+    fn start_merge(
+        &mut self,
+        directory: WriterDirectory,
+        segment_ids: &[SegmentId],
+    ) -> Result<JoinHandle<tantivy::Result<()>>, IndexError> {
+        let merge = self.get_writer(directory.clone())?.merge(segment_ids);
+
+        let merge_statuses = self.merge_statuses.clone();
+        Self::update_merge_status(&merge_statuses, &directory, |status| {
+            status.merges_in_progress += 1
+        })?;
+
+        Ok(thread::spawn(move || {
+            let result = merge.wait().map(|_| ());
+            let update = Self::update_merge_status(&merge_statuses, &directory, |status| {
+                status.merges_in_progress = status.merges_in_progress.saturating_sub(1);
+                match &result {
+                    Ok(()) => status.merges_completed += 1,
+                    Err(err) => status.last_error = Some(err.to_string()),
+                }
+            });
+            if let Err(err) = update {
+                tracing::error!("could not update merge status for {directory:?}: {err}");
+            }
+            result
+        }))
+    }
+
+    fn update_merge_status(
+        merge_statuses: &Mutex<HashMap<WriterDirectory, MergeStatus>>,
+        directory: &WriterDirectory,
+        update: impl FnOnce(&mut MergeStatus),
+    ) -> Result<(), IndexError> {
+        let mut merge_statuses = merge_statuses
+            .lock()
+            .map_err(|err| IndexError::MergeFailed(err.to_string()))?;
+        let status = merge_statuses.entry(directory.clone()).or_default();
+        update(status);
+
+        // The index may have been dropped while it was merging.
+        if directory.exists()? {
+            status.save(directory)?;
+        }
+        Ok(())
+    }
+
     pub fn create_index(
         &mut self,
         directory: WriterDirectory,
         fields: Vec<(SearchFieldName, SearchFieldConfig, SearchFieldType)>,
         uuid: String,
         key_field_index: usize,
+        merge_policy: SearchMergePolicy,
+        refresh_interval: u64,
+        directory_mode: SearchDirectoryMode,
+        writer_memory_budget: u64,
+        max_index_size: u64,
+        storage: Option<RemoteStorage>,
     ) -> Result<()> {
         let schema = SearchIndexSchema::new(fields, key_field_index)?;
 
-        let tantivy_dir_path = directory.tantivy_dir_path(true)?;
+        // The writer always memory-maps files, whatever the directory mode of the index.
+        let encrypted = EncryptionKey::is_configured();
+        let index_directory = SearchIndex::open_directory(
+            &directory,
+            SearchDirectoryMode::Mmap,
+            storage.as_ref(),
+            encrypted,
+        )?;
         let mut underlying_index = Index::builder()
             .schema(schema.schema.clone())
-            .create_in_dir(tantivy_dir_path)
+            .create(index_directory)
             .expect("failed to create index");
 
         SearchIndex::setup_tokenizers(&mut underlying_index, &schema);
@@ -137,6 +841,13 @@ impl Writer {
             directory: directory.clone(),
             schema,
             uuid,
+            merge_policy,
+            refresh_interval,
+            directory_mode,
+            writer_memory_budget,
+            max_index_size,
+            storage,
+            encrypted,
         };
 
         // Serialize SearchIndex to disk so it can be initialized by other connections.
@@ -144,23 +855,95 @@ impl Writer {
         Ok(())
     }
 
+    /// Save options changed with ALTER INDEX next to the index, where they're read again on
+    /// their next use. The tantivy writer keeps the heap it was created with, but commits
+    /// follow the new memory budget right away.
+    fn alter_options(
+        &mut self,
+        directory: WriterDirectory,
+        merge_policy: SearchMergePolicy,
+        refresh_interval: u64,
+        writer_memory_budget: u64,
+        max_index_size: u64,
+    ) -> Result<(), IndexError> {
+        let mut search_index: SearchIndex = directory.load_index()?;
+        search_index.merge_policy = merge_policy;
+        search_index.refresh_interval = refresh_interval;
+        search_index.writer_memory_budget = writer_memory_budget;
+        search_index.max_index_size = max_index_size;
+        directory.save_index(&search_index)?;
+
+        self.merge_policies.remove(&directory);
+        self.refresh_intervals.remove(&directory);
+        self.memory_budgets.remove(&directory);
+        self.max_index_sizes.remove(&directory);
+        Ok(())
+    }
+
     fn drop_index(&mut self, directory: WriterDirectory) -> Result<(), IndexError> {
         if let Some(writer) = self.tantivy_writers.remove(&directory) {
             std::mem::drop(writer);
         };
+        self.merge_policies.remove(&directory);
+        self.refresh_intervals.remove(&directory);
+        self.pending_refreshes.remove(&directory);
+        self.memory_budgets.remove(&directory);
+        self.storages.remove(&directory);
+        self.max_index_sizes.remove(&directory);
+        self.index_sizes.remove(&directory);
+        self.uncommitted_bytes.remove(&directory);
+        self.writer_statuses.remove(&directory);
+        self.staged
+            .retain(|(staged_directory, _), _| staged_directory != &directory);
+        self.uncommitted_deletes.remove(&directory);
+        if let Ok(mut merge_statuses) = self.merge_statuses.lock() {
+            merge_statuses.remove(&directory);
+        }
+
+        // Segments in object storage outlive the local directory, so they're removed first.
+        if let Ok(SearchIndex {
+            storage: Some(storage),
+            ..
+        }) = directory.load_index()
+        {
+            if let Err(err) = storage.remove_all(&directory) {
+                tracing::error!("could not remove {directory:?} from object storage: {err}");
+            }
+        }
 
         directory.remove()?;
         Ok(())
     }
 }
 
-impl Handler<WriterRequest> for Writer {
-    fn handle(&mut self, request: WriterRequest) -> Result<()> {
+impl Writer {
+    fn handle_request(&mut self, request: WriterRequest) -> Result<()> {
+        // A read-only index that was dropped in the meantime can be recreated.
+        if let Some(directory) = request.directory().filter(|_| request.is_write()) {
+            if directory.exists()? && ReadOnly::is_set(directory)? {
+                return Err(IndexError::ReadOnly(directory.index_name.clone()).into());
+            }
+        }
+
         match request {
             WriterRequest::Insert {
                 directory,
                 document,
             } => Ok(self.insert(directory, document)?),
+            WriterRequest::InsertMany {
+                directory,
+                documents,
+            } => match self.staging_pid(&directory)? {
+                Some(pid) => Ok(self.stage(directory, pid, documents, false)?),
+                None => Ok(self.insert_many(directory, documents)?),
+            },
+            WriterRequest::Upsert {
+                directory,
+                document,
+            } => match self.staging_pid(&directory)? {
+                Some(pid) => Ok(self.stage(directory, pid, vec![document], true)?),
+                None => Ok(self.upsert(directory, document)?),
+            },
             WriterRequest::Delete {
                 directory,
                 field,
@@ -171,18 +954,109 @@ impl Handler<WriterRequest> for Writer {
                 fields,
                 uuid,
                 key_field_index,
+                merge_policy,
+                refresh_interval,
+                directory_mode,
+                writer_memory_budget,
+                max_index_size,
+                storage,
             } => {
-                // If the writer directory exists, remove it. We need a fresh directory to
-                // create an index. This can happen after a VACUUM FULL, where the index needs
-                // to be rebuilt and this method is called again.
+                // If the writer directory exists, as when an index is resynced, remove it.
+                // We need a fresh directory to create an index.
                 self.drop_index(directory.clone())?;
-                self.create_index(directory, fields, uuid, key_field_index)?;
+                self.create_index(
+                    directory,
+                    fields,
+                    uuid,
+                    key_field_index,
+                    merge_policy,
+                    refresh_interval,
+                    directory_mode,
+                    writer_memory_budget,
+                    max_index_size,
+                    storage,
+                )?;
                 Ok(())
             }
             WriterRequest::DropIndex { directory } => Ok(self.drop_index(directory)?),
-            WriterRequest::Commit { directory } => Ok(self.commit(directory)?),
-            WriterRequest::Abort { directory } => Ok(self.abort(directory)?),
+            WriterRequest::AlterOptions {
+                directory,
+                merge_policy,
+                refresh_interval,
+                writer_memory_budget,
+                max_index_size,
+            } => Ok(self.alter_options(
+                directory,
+                merge_policy,
+                refresh_interval,
+                writer_memory_budget,
+                max_index_size,
+            )?),
+            WriterRequest::Commit { directory, pid } => Ok(self.commit(directory, pid)?),
+            WriterRequest::Refresh { directory } => Ok(self.commit_now(directory)?),
+            WriterRequest::Abort { directory, pid } => Ok(self.abort(directory, pid)?),
             WriterRequest::Vacuum { directory } => Ok(self.vacuum(directory)?),
+            WriterRequest::SkipValues { directory, count } => {
+                self.skip_values(directory, count);
+                Ok(())
+            }
+            WriterRequest::Merge {
+                directory,
+                max_merges,
+            } => Ok(self.merge(directory, max_merges)?),
+            WriterRequest::ForceMerge {
+                directory,
+                max_segments,
+                wait,
+            } => Ok(self.force_merge(directory, max_segments, wait)?),
+            WriterRequest::SetIoLimits {
+                index_mb_per_sec,
+                merge_mb_per_sec,
+            } => {
+                self.index_throttle.set_limit(index_mb_per_sec);
+                self.merge_throttle.set_limit(merge_mb_per_sec);
+                Ok(())
+            }
+            WriterRequest::SetDiskLimits { min_free_disk_mb } => {
+                self.min_free_disk_space = min_free_disk_mb * 1_000_000;
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Handler<WriterRequest> for Writer {
+    fn handle(&mut self, request: WriterRequest) -> Result<()> {
+        let directory = request.directory().cloned();
+        let result = self.handle_request(request);
+        // A dropped index has no status to report its errors to.
+        if let (Err(_), Some(directory)) = (&result, directory) {
+            if directory.exists().unwrap_or(false) {
+                self.writer_status(&directory).errors += 1;
+                self.save_writer_status(&directory);
+            }
         }
+        result
+    }
+
+    fn handle_transfer(&mut self, pipe_path: &Path, request: WriterRequest) -> Result<()> {
+        // The pipe of a transfer is named after the backend process that sends it, see
+        // `writer_transfer_pipe_path`. Single inserts come from index builds, which aren't
+        // staged, as the directory of a build is dropped if it aborts.
+        self.transfer_pid = pipe_path
+            .file_name()
+            .and_then(|name| name.to_str()?.parse().ok());
+        let result = self.handle(request);
+        self.transfer_pid = None;
+        result
+    }
+
+    fn tick(&mut self, shutdown: bool) -> Result<Option<Duration>> {
+        let next_refresh = self.refresh(shutdown)?;
+        let next_tiering = self.move_cold_segments();
+        Ok(match (next_refresh, next_tiering) {
+            (Some(refresh), Some(tiering)) => Some(refresh.min(tiering)),
+            (refresh, tiering) => refresh.or(tiering),
+        })
     }
 }
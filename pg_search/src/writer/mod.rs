@@ -19,8 +19,13 @@ mod client;
 mod directory;
 mod index;
 mod server;
+mod throttle;
 mod transfer;
 
+use crate::index::merge::SearchMergePolicy;
+use crate::index::remote::RemoteStorage;
+use crate::index::storage::SearchDirectoryMode;
+use crate::postgres::extract::ExtractError;
 use crate::schema::{SearchDocument, SearchFieldConfig, SearchFieldType};
 use crate::{postgres::types::TantivyValueError, schema::SearchFieldName};
 pub use client::{Client, ClientError};
@@ -29,17 +34,28 @@ pub use index::Writer;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 pub use server::{Server, ServerError};
 use std::path::Path;
+use std::time::Duration;
 use tantivy::schema::Field;
 use thiserror::Error;
 
 // A layer of the client-server request structure that handles
 // details about the action to be performed by the index writer.
-#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub enum WriterRequest {
     Insert {
         directory: WriterDirectory,
         document: SearchDocument,
     },
+    /// Insert a batch of documents at once.
+    InsertMany {
+        directory: WriterDirectory,
+        documents: Vec<SearchDocument>,
+    },
+    /// Insert a document, replacing the documents that have the same key.
+    Upsert {
+        directory: WriterDirectory,
+        document: SearchDocument,
+    },
     Delete {
         directory: WriterDirectory,
         field: Field,
@@ -50,19 +66,102 @@ pub enum WriterRequest {
         fields: Vec<(SearchFieldName, SearchFieldConfig, SearchFieldType)>,
         uuid: String,
         key_field_index: usize,
+        merge_policy: SearchMergePolicy,
+        refresh_interval: u64,
+        directory_mode: SearchDirectoryMode,
+        writer_memory_budget: u64,
+        max_index_size: u64,
+        storage: Option<RemoteStorage>,
     },
     DropIndex {
         directory: WriterDirectory,
     },
+    /// Change the options of an index that don't affect its documents, after an ALTER INDEX.
+    AlterOptions {
+        directory: WriterDirectory,
+        merge_policy: SearchMergePolicy,
+        refresh_interval: u64,
+        writer_memory_budget: u64,
+        max_index_size: u64,
+    },
     Abort {
         directory: WriterDirectory,
+        /// The backend process of the aborted transaction, whose staged documents are
+        /// discarded, see `Writer::stage`.
+        pid: u32,
     },
     Commit {
         directory: WriterDirectory,
+        /// The backend process of the committing transaction, whose staged documents are
+        /// added, see `Writer::stage`.
+        pid: u32,
+    },
+    /// Commit right away, even for an index with a refresh interval.
+    Refresh {
+        directory: WriterDirectory,
     },
     Vacuum {
         directory: WriterDirectory,
     },
+    /// Count values of rows that couldn't be indexed, see `SearchFieldErrorPolicy`.
+    SkipValues {
+        directory: WriterDirectory,
+        count: u64,
+    },
+    Merge {
+        directory: WriterDirectory,
+        max_merges: usize,
+    },
+    ForceMerge {
+        directory: WriterDirectory,
+        max_segments: usize,
+        wait: bool,
+    },
+    /// Limit the I/O rate of indexing and background merges, in MB/s. 0 means unlimited.
+    SetIoLimits {
+        index_mb_per_sec: u32,
+        merge_mb_per_sec: u32,
+    },
+    /// Fail inserts while less than this many MB of disk space are free. 0 means no minimum.
+    SetDiskLimits {
+        min_free_disk_mb: u64,
+    },
+}
+
+impl WriterRequest {
+    /// The index the request is for, if any.
+    pub fn directory(&self) -> Option<&WriterDirectory> {
+        match self {
+            Self::Insert { directory, .. }
+            | Self::InsertMany { directory, .. }
+            | Self::Upsert { directory, .. }
+            | Self::Delete { directory, .. }
+            | Self::CreateIndex { directory, .. }
+            | Self::DropIndex { directory }
+            | Self::AlterOptions { directory, .. }
+            | Self::Abort { directory, .. }
+            | Self::Commit { directory, .. }
+            | Self::Refresh { directory }
+            | Self::Vacuum { directory }
+            | Self::SkipValues { directory, .. }
+            | Self::Merge { directory, .. }
+            | Self::ForceMerge { directory, .. } => Some(directory),
+            Self::SetIoLimits { .. } | Self::SetDiskLimits { .. } => None,
+        }
+    }
+
+    /// Whether the request changes the documents of its index, which read-only indexes
+    /// reject, see `ReadOnly`.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Insert { .. }
+                | Self::InsertMany { .. }
+                | Self::Upsert { .. }
+                | Self::Delete { .. }
+                | Self::CreateIndex { .. }
+        )
+    }
 }
 
 // A layer of the client-server request structure that handles
@@ -83,12 +182,27 @@ enum ServerRequest<T: Serialize> {
 /// and re-used independently.
 pub trait Handler<T: DeserializeOwned> {
     fn handle(&mut self, request: T) -> Result<(), anyhow::Error>;
+
+    /// Handle a request received through the data pipe at `pipe_path`, see
+    /// `WriterClient::transfer`.
+    fn handle_transfer(&mut self, _pipe_path: &Path, request: T) -> Result<(), anyhow::Error> {
+        self.handle(request)
+    }
+
+    /// Perform work that isn't tied to a request, like background commits. Called while
+    /// the server is idle, and before it shuts down. Returns how long the server may wait
+    /// before calling it again, if it needs to be called at all.
+    fn tick(&mut self, _shutdown: bool) -> Result<Option<Duration>, anyhow::Error> {
+        Ok(None)
+    }
 }
 
 pub trait WriterClient<T: Serialize> {
     fn request(&mut self, request: T) -> Result<(), ClientError>;
 
-    fn transfer<P: AsRef<Path>>(&mut self, pipe_path: P, request: T) -> Result<(), ClientError>;
+    /// Send `request` through the data pipe at `pipe_path`. It's only borrowed, so that the
+    /// requests of a transaction can be sent again if the writer restarts.
+    fn transfer<P: AsRef<Path>>(&mut self, pipe_path: P, request: &T) -> Result<(), ClientError>;
 }
 
 #[derive(Error, Debug)]
@@ -114,8 +228,38 @@ pub enum IndexError {
     #[error("couldn't remove index files on drop_index: {0}")]
     DeleteDirectory(#[from] SearchDirectoryError),
 
+    #[error(transparent)]
+    ExtractError(#[from] ExtractError),
+
     #[error("key_field column '{0}' cannot be NULL")]
     KeyIdNull(String),
+
+    /// A value of a row that can't be converted to the field of its column. The value is
+    /// kept as text, cut short if it's long, to be reported along with the error.
+    #[error("column '{column}' of type {type_name} cannot be indexed: {source}")]
+    InvalidColumn {
+        column: String,
+        type_name: String,
+        value: String,
+        #[source]
+        source: Box<IndexError>,
+    },
+
+    #[error("error merging index segments: {0}")]
+    MergeFailed(String),
+
+    #[error("index {0} is {1} MB, which is over its max_index_size of {2} MB")]
+    IndexSizeExceeded(String, u64, u64),
+
+    #[error(
+        "only {0} MB of disk space is free, which is below paradedb.min_free_disk_space of {1} MB"
+    )]
+    LowDiskSpace(u64, u64),
+
+    #[error(
+        "index {0} is read-only, writes can be allowed again with paradedb.set_index_readonly"
+    )]
+    ReadOnly(String),
 }
 
 #[cfg(test)]
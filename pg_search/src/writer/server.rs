@@ -22,10 +22,14 @@ use serde::Serialize;
 use std::io;
 use std::marker::PhantomData;
 use std::path::Path;
+use std::time::Duration;
 use std::{cell::RefCell, io::Cursor};
 use thiserror::Error;
 use tracing::{error, info};
 
+/// How long to wait before retrying background work that failed.
+const TICK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
 /// A generic server for receiving requests and transfers from a client.
 pub struct Server<'a, T, H>
 where
@@ -75,10 +79,11 @@ where
 
     fn listen_transfer<P: AsRef<Path>>(&self, pipe_path: P) -> Result<(), ServerError> {
         // Our consumer will receive messages suitable for our handler.
-        for incoming in transfer::read_stream::<T, P>(pipe_path)? {
+        let pipe_path = pipe_path.as_ref();
+        for incoming in transfer::read_stream::<T, &Path>(pipe_path)? {
             self.handler
                 .borrow_mut()
-                .handle(incoming?)
+                .handle_transfer(pipe_path, incoming?)
                 .map_err(ServerError::Anyhow)?;
         }
         Ok(())
@@ -92,50 +97,78 @@ where
         tiny_http::Response::from_string(err.to_string()).with_status_code(500)
     }
 
+    /// Let the handler do its background work. Returns how long to wait for a request
+    /// before doing it again.
+    fn tick(&self, shutdown: bool) -> Option<Duration> {
+        match self.handler.borrow_mut().tick(shutdown) {
+            Ok(wait) => wait,
+            Err(err) => {
+                error!("error performing background work in writer server: {err}");
+                Some(TICK_RETRY_INTERVAL)
+            }
+        }
+    }
+
     fn listen_request(&mut self) -> Result<(), ServerError> {
         info!("listening to incoming requests at {:?}", self.addr);
-        for mut incoming in self.http.incoming_requests() {
-            let reader = incoming.as_reader();
-            let request: Result<ServerRequest<T>, ServerError> = bincode::deserialize_from(reader)
-                .map_err(|err| ServerError::Unexpected(err.into()));
-
-            match request {
-                Ok(req) => match req {
-                    ServerRequest::Shutdown => {
-                        if let Err(err) = incoming.respond(Self::response_ok()) {
-                            error!("server error responding to shutdown: {err}");
-                        }
-                        return Ok(());
+        let mut wait = None;
+        loop {
+            let incoming = match wait {
+                Some(timeout) => self.http.recv_timeout(timeout)?,
+                None => Some(self.http.recv()?),
+            };
+            if let Some(incoming) = incoming {
+                if !self.handle_incoming(incoming) {
+                    return Ok(());
+                }
+            }
+            wait = self.tick(false);
+        }
+    }
+
+    /// Handle a request, returning false if the server should shut down.
+    fn handle_incoming(&self, mut incoming: tiny_http::Request) -> bool {
+        let reader = incoming.as_reader();
+        let request: Result<ServerRequest<T>, ServerError> =
+            bincode::deserialize_from(reader).map_err(|err| ServerError::Unexpected(err.into()));
+
+        match request {
+            Ok(req) => match req {
+                ServerRequest::Shutdown => {
+                    self.tick(true);
+                    if let Err(err) = incoming.respond(Self::response_ok()) {
+                        error!("server error responding to shutdown: {err}");
                     }
-                    ServerRequest::Transfer(pipe_path) => {
-                        // We must respond with OK before initiating the transfer.
-                        if let Err(err) = incoming.respond(Self::response_ok()) {
-                            error!("server error responding to transfer: {err}");
-                        } else if let Err(err) = self.listen_transfer(pipe_path) {
-                            error!("error listening to transfer: {err}")
-                        }
+                    return false;
+                }
+                ServerRequest::Transfer(pipe_path) => {
+                    // We must respond with OK before initiating the transfer.
+                    if let Err(err) = incoming.respond(Self::response_ok()) {
+                        error!("server error responding to transfer: {err}");
+                    } else if let Err(err) = self.listen_transfer(pipe_path) {
+                        error!("error listening to transfer: {err}")
                     }
-                    ServerRequest::Request(req) => {
-                        if let Err(err) = self.handler.borrow_mut().handle(req) {
-                            if let Err(err) =
-                                incoming.respond(Self::response_err(ServerError::Anyhow(err)))
-                            {
-                                error!("server error responding to handler error: {err}");
-                            }
-                        } else if let Err(err) = incoming.respond(Self::response_ok()) {
-                            error!("server error responding to handler success: {err}")
+                }
+                ServerRequest::Request(req) => {
+                    if let Err(err) = self.handler.borrow_mut().handle(req) {
+                        if let Err(err) =
+                            incoming.respond(Self::response_err(ServerError::Anyhow(err)))
+                        {
+                            error!("server error responding to handler error: {err}");
                         }
-                    }
-                },
-                Err(err) => {
-                    if let Err(err) = incoming.respond(Self::response_err(err)) {
-                        error!("server error responding to client on deserialize error: {err}");
+                    } else if let Err(err) = incoming.respond(Self::response_ok()) {
+                        error!("server error responding to handler success: {err}")
                     }
                 }
-            };
-        }
+            },
+            Err(err) => {
+                if let Err(err) = incoming.respond(Self::response_err(err)) {
+                    error!("server error responding to client on deserialize error: {err}");
+                }
+            }
+        };
 
-        unreachable!("server should never stop listening");
+        true
     }
 }
 
@@ -0,0 +1,111 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use std::time::{Duration, Instant};
+
+const BYTES_PER_MB: f64 = 1024.0 * 1024.0;
+
+/// Limits the average rate of I/O performed by the writer, in megabytes per second.
+///
+/// The throttle keeps a balance of bytes that refills at the configured rate, up to one
+/// second worth of I/O. Work is allowed to start as long as the balance isn't negative,
+/// and its size is then taken from the balance, so a large merge is paid for by waiting
+/// before the next one.
+#[derive(Debug)]
+pub struct IoThrottle {
+    bytes_per_sec: f64,
+    balance: f64,
+    refilled_at: Instant,
+}
+
+impl IoThrottle {
+    /// A throttle allowing `mb_per_sec` megabytes per second, or unlimited if 0.
+    pub fn new(mb_per_sec: u32) -> Self {
+        let bytes_per_sec = mb_per_sec as f64 * BYTES_PER_MB;
+        Self {
+            bytes_per_sec,
+            balance: bytes_per_sec,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    pub fn set_limit(&mut self, mb_per_sec: u32) {
+        let bytes_per_sec = mb_per_sec as f64 * BYTES_PER_MB;
+        if bytes_per_sec != self.bytes_per_sec {
+            *self = Self::new(mb_per_sec);
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.bytes_per_sec > 0.0
+    }
+
+    /// Whether work can start now without exceeding the limit.
+    pub fn has_budget(&mut self) -> bool {
+        self.refill();
+        !self.is_limited() || self.balance >= 0.0
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        if self.is_limited() {
+            self.refill();
+            self.balance -= bytes as f64;
+        }
+    }
+
+    /// How long to wait until work can start again.
+    pub fn delay(&mut self) -> Duration {
+        if self.has_budget() {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.balance / self.bytes_per_sec)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.balance = (self.balance + elapsed * self.bytes_per_sec).min(self.bytes_per_sec);
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoThrottle, BYTES_PER_MB};
+    use std::time::Duration;
+
+    #[test]
+    fn test_io_throttle() {
+        // No limit, nothing ever waits.
+        let mut unlimited = IoThrottle::new(0);
+        unlimited.consume(u64::MAX);
+        assert!(unlimited.has_budget());
+        assert_eq!(unlimited.delay(), Duration::ZERO);
+
+        // One second worth of I/O is allowed right away, the next second has to wait.
+        let mut throttle = IoThrottle::new(1);
+        assert!(throttle.has_budget());
+        throttle.consume(2 * BYTES_PER_MB as u64);
+        assert!(!throttle.has_budget());
+        let delay = throttle.delay();
+        assert!(delay > Duration::from_millis(900) && delay <= Duration::from_secs(1));
+
+        // Changing the limit starts over with a full balance.
+        throttle.set_limit(2);
+        assert!(throttle.has_budget());
+    }
+}
@@ -86,6 +86,20 @@ fn with_rank_bm25(mut conn: PgConnection) {
     assert_eq!(ranks, expected);
 }
 
+#[rstest]
+fn with_rank_position(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let rows: Vec<(i32, i64)> = "SELECT id, paradedb.rank_position(id) FROM bm25_search.search('category:electronics OR description:keyboard') ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(1, 2), (2, 1), (12, 3), (22, 4), (32, 5)]);
+
+    // Positions count the rows skipped by the offset.
+    let rows: Vec<(i32, i64)> = "SELECT id, paradedb.rank_position(id) FROM bm25_search.search('category:electronics OR description:keyboard', limit_rows => 2, offset_rows => 2)"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(12, 3), (22, 4)]);
+}
+
 #[rstest]
 fn json_search(mut conn: PgConnection) {
     SimpleProductsTable::setup().execute(&mut conn);
@@ -334,6 +348,42 @@ fn json_arrays(mut conn: PgConnection) {
     }
 }
 
+#[rstest]
+fn jsonb_documents(mut conn: PgConnection) {
+    r#"CREATE TABLE jsonb_table (
+        id SERIAL PRIMARY KEY,
+        document JSONB
+    );
+    INSERT INTO jsonb_table (document) VALUES
+    ('{"name": "Teapot", "tags": ["kitchen", "tea"], "stock": {"count": 3, "price": 12.5}}'),
+    ('{"name": "Kettle", "tags": ["kitchen"], "stock": {"count": -1, "price": 20}}'),
+    ('[{"name": "Mug"}, [{"name": "Cup"}]]'),
+    ('"Saucer"');"#
+        .execute(&mut conn);
+
+    "CALL paradedb.create_bm25(
+        index_name => 'jsonb_table',
+        table_name => 'jsonb_table',
+        key_field => 'id',
+        json_fields => paradedb.field('document')
+    )"
+    .execute(&mut conn);
+
+    for (query, expected) in [
+        ("document.name:teapot", vec![(1,)]),
+        ("document.tags:tea", vec![(1,)]),
+        ("document.stock.count:3", vec![(1,)]),
+        ("document.stock.price:12.5", vec![(1,)]),
+        // Elements of top-level arrays, even nested ones, are indexed as separate values.
+        ("document.name:cup", vec![(3,)]),
+    ] {
+        let rows: Vec<(i32,)> =
+            format!("SELECT id FROM jsonb_table.search('{query}', stable_sort => true)")
+                .fetch(&mut conn);
+        assert_eq!(rows, expected, "{query}");
+    }
+}
+
 #[rstest]
 fn uuid(mut conn: PgConnection) {
     r#"
@@ -521,6 +571,67 @@ fn highlight(mut conn: PgConnection) {
     assert_relative_eq!(row.2, 2.8772602, epsilon = 1e-6);
 }
 
+#[rstest]
+fn highlight_offsets(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // Offsets are into the text of the column, which keeps its case.
+    let rows: Vec<(i32, String, i32, i32, i32, i32)> = "
+        SELECT s.id, o.*
+        FROM bm25_search.search('description:keyboard', stable_sort => true) s,
+            paradedb.highlight_offsets(s.id, 'description') o
+        ORDER BY s.id"
+        .fetch(&mut conn);
+    assert_eq!(
+        rows,
+        vec![
+            (1, "keyboard".into(), 16, 24, 16, 24),
+            (2, "keyboard".into(), 8, 16, 8, 16),
+        ]
+    );
+
+    let (highlighted,): (String,) = "
+        SELECT substr(s.description, o.start_char + 1, o.end_char - o.start_char)
+        FROM bm25_search.search('description:keyboard', stable_sort => true) s,
+            paradedb.highlight_offsets(s.id, 'description') o
+        WHERE s.id = 2"
+        .fetch_one(&mut conn);
+    assert_eq!(highlighted, "Keyboard");
+}
+
+#[rstest]
+fn matched_queries(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let rows: Vec<(i32, Vec<String>)> = "
+        SELECT id, paradedb.matched_queries(id)
+        FROM bm25_search.search(
+            query => paradedb.boolean(should => ARRAY[
+                paradedb.named('keyboard', paradedb.parse('description:keyboard')),
+                paradedb.named('electronics', paradedb.parse('category:electronics'))
+            ])
+        )
+        ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(
+        rows,
+        vec![
+            (1, vec!["keyboard".into(), "electronics".into()]),
+            (2, vec!["keyboard".into(), "electronics".into()]),
+            (12, vec!["electronics".into()]),
+            (22, vec!["electronics".into()]),
+            (32, vec!["electronics".into()]),
+        ]
+    );
+
+    // Searches without named queries have none to match.
+    let (matched,): (Vec<String>,) = "
+        SELECT paradedb.matched_queries(id)
+        FROM bm25_search.search('description:keyboard', limit_rows => 1)"
+        .fetch_one(&mut conn);
+    assert!(matched.is_empty());
+}
+
 #[rstest]
 fn hybrid_with_complex_key_field_name(mut conn: PgConnection) {
     // Create a test table.
@@ -762,11 +873,13 @@ fn update_non_indexed_column(mut conn: PgConnection) -> Result<()> {
     // Build the index directory path.
     let (db_oid,) = "SELECT oid::int4 FROM pg_database WHERE datname = current_database();"
         .fetch_one::<(i32,)>(&mut conn);
+    let (relfilenode,) = "SELECT pg_relation_filenode(oid)::int8 FROM pg_class WHERE relname = 'search_idx_bm25_index'"
+        .fetch_one::<(i64,)>(&mut conn);
     let data_directory = "SHOW data_directory;".fetch_one::<(String,)>(&mut conn).0;
     let index_dir_path = PathBuf::from(data_directory)
         .join("paradedb")
         .join("pg_search")
-        .join(format!("{db_oid}_search_idx_bm25_index"))
+        .join(format!("{db_oid}_{relfilenode}_search_idx_bm25_index"))
         .join("tantivy");
 
     assert!(index_dir_path.exists());
@@ -1255,3 +1368,759 @@ fn high_limit_rows(mut conn: PgConnection) {
             .fetch(&mut conn);
     assert_eq!(rows.len(), 200000);
 }
+
+#[rstest]
+fn fast_fields_in_batches(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let rows: Vec<(i64, String, String)> = "SELECT batch, field, field_values::text FROM paradedb.fast_fields('bm25_search', paradedb.parse('description:keyboard'), fields => ARRAY['id', 'rating'], batch_size => 1)"
+        .fetch(&mut conn);
+
+    // One row per field and batch, with one document per batch.
+    assert_eq!(rows.len(), 4);
+    assert_eq!(
+        rows.iter()
+            .map(|(batch, field, _)| (*batch, field.as_str()))
+            .collect::<Vec<_>>(),
+        vec![(0, "id"), (0, "rating"), (1, "id"), (1, "rating")]
+    );
+
+    let mut ids: Vec<&str> = rows
+        .iter()
+        .filter(|(_, field, _)| field == "id")
+        .map(|(_, _, values)| values.as_str())
+        .collect();
+    ids.sort();
+    assert_eq!(ids, vec!["[1]", "[2]"]);
+}
+
+#[rstest]
+fn stored_fields_by_key(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (doc,): (String,) = "SELECT paradedb.stored_fields('bm25_search', '3', fields => ARRAY['description', 'rating'])::text"
+        .fetch_one(&mut conn);
+    assert_eq!(doc, r#"{"rating": 5, "description": "Sleek running shoes"}"#);
+
+    // The latest version of the row is read, and every stored field without fields.
+    "UPDATE paradedb.bm25_search SET description = 'Sleek trail shoes' WHERE id = 3"
+        .execute(&mut conn);
+    let (description, category, id): (String, String, String) = "
+    SELECT doc->>'description', doc->>'category', doc->>'id'
+    FROM paradedb.stored_fields('bm25_search', '3') AS doc"
+        .fetch_one(&mut conn);
+    assert_eq!(
+        (description.as_str(), category.as_str(), id.as_str()),
+        ("Sleek trail shoes", "Footwear", "3")
+    );
+
+    let (missing,): (bool,) =
+        "SELECT paradedb.stored_fields('bm25_search', '1000') IS NULL".fetch_one(&mut conn);
+    assert!(missing);
+
+    match "SELECT paradedb.stored_fields('bm25_search', '3', fields => ARRAY['color'])"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("color is not a field of the index"),
+        Err(err) => assert!(err.to_string().contains("does not exist"), "{err}"),
+    };
+}
+
+#[rstest]
+fn aggregate_with_limits(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SET paradedb.search_threads = 4".execute(&mut conn);
+
+    let (result,): (String,) =
+        r#"SELECT bm25_search.aggregate('{"max_rating": {"max": {"field": "rating"}}}')::text"#
+            .fetch_one(&mut conn);
+    assert_eq!(result, r#"{"max_rating": {"value": 5.0}}"#);
+
+    // A histogram with a bucket per thousandth of a rating goes over the bucket limit.
+    "SET paradedb.aggregate_bucket_limit = 100".execute(&mut conn);
+    match r#"SELECT bm25_search.aggregate('{"ratings": {"histogram": {"field": "rating", "interval": 0.001}}}')"#
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should fail over the bucket limit"),
+        Err(err) => assert!(err.to_string().contains("bucket"), "{err}"),
+    };
+}
+
+#[rstest]
+fn suggest_completions(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let rows: Vec<(String, f64)> =
+        "SELECT suggestion, weight FROM paradedb.suggest('bm25_search', 'description', 'Sh')"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![("shoes".into(), 3.0), ("shirt".into(), 1.0)]);
+
+    let rows: Vec<(String, f64)> =
+        "SELECT suggestion, weight FROM paradedb.suggest('bm25_search', 'description', 'sh', size => 1, weight_field => 'rating')"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![("shoes".into(), 12.0)]);
+
+    // Deleted rows stop counting once they're vacuumed from the index.
+    "DELETE FROM paradedb.bm25_search WHERE description = 'Generic shoes'".execute(&mut conn);
+    "VACUUM paradedb.bm25_search".execute(&mut conn);
+    let rows: Vec<(String, f64)> =
+        "SELECT suggestion, weight FROM paradedb.suggest('bm25_search', 'description', 'shoe')"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![("shoes".into(), 2.0)]);
+
+    match "SELECT * FROM paradedb.suggest('bm25_search', 'rating', '4')".execute_result(&mut conn) {
+        Ok(_) => panic!("should only suggest from text fields"),
+        Err(err) => assert!(
+            err.to_string().contains("not an indexed text field"),
+            "{err}"
+        ),
+    };
+}
+
+#[rstest]
+fn term_statistics(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (doc_freq, total_term_freq, num_docs, idf): (i64, i64, i64, f64) =
+        "SELECT * FROM paradedb.term_stats('bm25_search', 'description', 'shoes')"
+            .fetch_one(&mut conn);
+    let (count,): (i64,) = "SELECT count(*) FROM paradedb.bm25_search".fetch_one(&mut conn);
+    assert_eq!((doc_freq, total_term_freq, num_docs), (3, 3, count));
+    assert!(idf > 0.0);
+
+    let (doc_freq,): (i64,) =
+        "SELECT doc_freq FROM paradedb.term_stats('bm25_search', 'description', 'Shoes')"
+            .fetch_one(&mut conn);
+    assert_eq!(doc_freq, 0, "terms are looked up as they were indexed");
+
+    // The most frequent terms come first, with the same frequencies as term_stats.
+    let rows: Vec<(String, i64, i64)> =
+        "SELECT * FROM paradedb.top_terms('bm25_search', 'category', size => 3)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+    assert!(rows.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+    for (term, doc_freq, total_term_freq) in rows {
+        let stats: (i64, i64) = format!(
+            "SELECT doc_freq, total_term_freq FROM paradedb.term_stats('bm25_search', 'category', '{term}')"
+        )
+        .fetch_one(&mut conn);
+        assert_eq!(stats, (doc_freq, total_term_freq));
+    }
+
+    match "SELECT * FROM paradedb.top_terms('bm25_search', 'rating')".execute_result(&mut conn) {
+        Ok(_) => panic!("should only read terms of text fields"),
+        Err(err) => assert!(
+            err.to_string().contains("not an indexed text field"),
+            "{err}"
+        ),
+    };
+}
+
+#[rstest]
+fn field_values_by_prefix(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let rows: Vec<(String, i64)> =
+        "SELECT * FROM paradedb.field_values('bm25_search', 'category', prefix => 'e')"
+            .fetch(&mut conn);
+    let (count,): (i64,) =
+        "SELECT count(*) FROM paradedb.bm25_search WHERE category = 'Electronics'"
+            .fetch_one(&mut conn);
+    assert_eq!(rows, vec![("electronics".into(), count)]);
+
+    // Values are in order, and the first ones of every value are returned without a prefix.
+    let all: Vec<(String, i64)> =
+        "SELECT * FROM paradedb.field_values('bm25_search', 'category')".fetch(&mut conn);
+    let first: Vec<(String, i64)> =
+        "SELECT * FROM paradedb.field_values('bm25_search', 'category', size => 2)"
+            .fetch(&mut conn);
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(first, all[..2]);
+
+    match "SELECT * FROM paradedb.field_values('bm25_search', 'rating')".execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only list values of text fields"),
+        Err(err) => assert!(
+            err.to_string().contains("not an indexed text field"),
+            "{err}"
+        ),
+    };
+}
+
+#[rstest]
+fn search_tab_stored_fields(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'mock_items', schema_name => 'public');"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+            index_name => 'search_idx',
+            schema_name => 'public',
+            table_name => 'mock_items',
+            key_field => 'id',
+            text_fields => paradedb.field('description'),
+            numeric_fields => paradedb.field('rating'),
+            json_fields => paradedb.field('metadata'),
+            search_tab_fields => ARRAY['description', 'rating', 'metadata']
+    )"
+    .execute(&mut conn);
+
+    let rows: Vec<(i32, f32, String, i32, serde_json::Value)> =
+        "SELECT id, rank_bm25, description, rating, metadata FROM search_idx.search_tab('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    let ids: Vec<i32> = rows.iter().map(|row| row.0).collect();
+    assert_eq!(ids, vec![2, 1]);
+    assert!(rows.iter().all(|row| row.1 > 0.0));
+    assert_eq!(rows[0].2, "Plastic Keyboard");
+    assert_eq!(rows[0].3, 4);
+    assert_eq!(rows[0].4["color"], "Black");
+
+    // Fields that aren't in the index can't be returned.
+    match "CALL paradedb.create_bm25(
+            index_name => 'other_idx',
+            schema_name => 'public',
+            table_name => 'mock_items',
+            key_field => 'id',
+            text_fields => paradedb.field('description'),
+            search_tab_fields => ARRAY['category']
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only return fields of the index"),
+        Err(err) => assert!(err.to_string().contains("search_tab_fields"), "{err}"),
+    };
+}
+
+#[rstest]
+fn search_tab_latest_versions(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'mock_items', schema_name => 'public');"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+            index_name => 'search_idx',
+            schema_name => 'public',
+            table_name => 'mock_items',
+            key_field => 'id',
+            text_fields => paradedb.field('description'),
+            numeric_fields => paradedb.field('rating'),
+            search_tab_fields => ARRAY['description', 'rating']
+    )"
+    .execute(&mut conn);
+
+    // Until the transaction commits, the index holds both versions of the updated rows.
+    "BEGIN".execute(&mut conn);
+    "UPDATE mock_items SET rating = 1 WHERE id = 2".execute(&mut conn);
+    "UPDATE mock_items SET description = 'Ergonomic metal mouse' WHERE id = 1".execute(&mut conn);
+
+    // Only the latest version of a row is returned, and a row whose latest version doesn't
+    // match is left out, instead of returning its older version.
+    let rows: Vec<(i32, i32)> =
+        "SELECT id, rating FROM search_idx.search_tab('description:keyboard')".fetch(&mut conn);
+    assert_eq!(rows, vec![(2, 1)]);
+
+    // Replaced versions don't take up the limit.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM search_idx.search_tab('description:keyboard OR description:shoes', limit_rows => 3, stable_sort => true)"
+            .fetch(&mut conn);
+    let mut ids: Vec<i32> = rows.into_iter().map(|(id,)| id).collect();
+    assert_eq!(ids.len(), 3);
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 3);
+    assert!(!ids.contains(&1));
+    "COMMIT".execute(&mut conn);
+}
+
+#[rstest]
+fn evaluate_relevance_judgments(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    r#"
+    SELECT paradedb.add_judgment('keyboards', paradedb.parse('description:keyboard'), '1', 3);
+    SELECT paradedb.add_judgment('keyboards', paradedb.parse('description:keyboard'), '2', 1);
+    SELECT paradedb.add_judgment('keyboards', paradedb.parse('description:shoes'), '3', 2);
+    SELECT paradedb.add_judgment('keyboards', paradedb.parse('description:shoes'), '4', 2);
+    SELECT paradedb.add_judgment('keyboards', paradedb.parse('description:shoes'), '5', 2);
+    "#
+    .execute(&mut conn);
+
+    let rows: Vec<(f64, f64, f64, f64)> = "
+    SELECT ndcg, mrr, precision, recall FROM paradedb.evaluate_relevance('bm25_search', 'keyboards', k => 2)
+    ORDER BY query->'Parse'->>'query_string'"
+        .fetch(&mut conn);
+    assert_eq!(rows.len(), 2);
+
+    // Both keyboards are found, in either order.
+    let (ndcg, mrr, precision, recall) = rows[0];
+    assert!(ndcg > 0.0 && ndcg <= 1.0);
+    assert_eq!((mrr, precision, recall), (1.0, 1.0, 1.0));
+
+    // Only two of the three relevant shoes fit in the top 2.
+    let (ndcg, _, precision, recall) = rows[1];
+    assert_eq!(ndcg, 1.0);
+    assert_eq!(precision, 1.0);
+    assert_eq!(recall, 2.0 / 3.0);
+
+    let (dropped,): (i64,) = "SELECT paradedb.drop_judgments('keyboards')".fetch_one(&mut conn);
+    assert_eq!(dropped, 5);
+}
+
+#[rstest]
+fn compare_rankings_of_profiles(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    r#"
+    SELECT paradedb.save_ranking_profile('bm25_search', 'descriptions', '{"boosts": {"description": 100}}');
+    SELECT paradedb.save_ranking_profile('bm25_search', 'categories', '{"boosts": {"category": 100}}');
+    "#
+    .execute(&mut conn);
+    let query = "paradedb.parse('description:shoes OR category:electronics')";
+
+    // A ranking agrees with itself.
+    let (ranking_a, ranking_b, overlap, kendall_tau, rbo): (Vec<String>, Vec<String>, f64, Option<f64>, f64) =
+        format!("SELECT * FROM paradedb.compare_rankings('bm25_search', {query}, 'descriptions', 'descriptions')")
+            .fetch_one(&mut conn);
+    assert_eq!(ranking_a, ranking_b);
+    assert_eq!((overlap, kendall_tau), (1.0, Some(1.0)));
+    assert!((rbo - 1.0).abs() < 1e-9);
+
+    // Shoes rank first by description, and electronics by category, but both rankings
+    // have the same results.
+    let (ranking_a, ranking_b, overlap, kendall_tau, rbo): (Vec<String>, Vec<String>, f64, Option<f64>, f64) =
+        format!("SELECT * FROM paradedb.compare_rankings('bm25_search', {query}, 'descriptions', 'categories')")
+            .fetch_one(&mut conn);
+    let shoes = ["3", "4", "5"];
+    assert!(ranking_a[..3].iter().all(|key| shoes.contains(&key.as_str())));
+    assert!(ranking_b[..3].iter().all(|key| !shoes.contains(&key.as_str())));
+    assert_eq!(overlap, 1.0);
+    assert!(kendall_tau.unwrap() < 0.0);
+    assert!(rbo < 1.0);
+}
+
+#[rstest]
+fn rerank_by_vector(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE items (id SERIAL PRIMARY KEY, description TEXT, embedding REAL[]);
+    INSERT INTO items (description, embedding) VALUES
+        ('Ergonomic metal keyboard', ARRAY[1.0, 0.0]),
+        ('Plastic keyboard', ARRAY[0.0, 1.0]),
+        ('Wireless keyboard', ARRAY[0.6, 0.8]),
+        ('Mechanical keyboard', NULL),
+        ('Sleek running shoes', ARRAY[0.0, 1.0]);
+    CALL paradedb.create_bm25(
+        index_name => 'items_idx',
+        table_name => 'items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        vector_fields => '{embedding: {}}'
+    );
+    "#
+    .execute(&mut conn);
+
+    // Only keyboards are reranked, and the one without a vector is left out.
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM items_idx.search(
+        query => paradedb.rerank_by_vector(
+            paradedb.parse('description:keyboard'), ARRAY[0.0, 2.0]::real[]
+        ),
+        stable_sort => true
+    )"
+    .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (3,), (1,)]);
+
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM items_idx.search(
+        query => paradedb.rerank_by_vector(
+            paradedb.parse('description:keyboard'),
+            ARRAY[1.0, 0.0]::real[],
+            metric => 'dot',
+            field => 'embedding'
+        ),
+        stable_sort => true
+    )"
+    .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (3,), (2,)]);
+
+    // The similarity is the score of the results.
+    let (score,): (f32,) = "
+    SELECT paradedb.rank_bm25(id) FROM items_idx.search(
+        query => paradedb.rerank_by_vector(paradedb.parse('description:plastic'), ARRAY[0.0, 3.0]::real[])
+    )"
+    .fetch_one(&mut conn);
+    assert!((score - 1.0).abs() < 1e-6);
+
+    match "SELECT * FROM items_idx.search(query => paradedb.rerank_by_vector(paradedb.parse('description:keyboard'), ARRAY[1.0]::real[], field => 'description'))"
+        .fetch_result::<(i32,)>(&mut conn)
+    {
+        Ok(_) => panic!("should only rerank by vector fields"),
+        Err(err) => assert!(err.to_string().contains("description"), "{err}"),
+    };
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'other_idx',
+        table_name => 'items',
+        key_field => 'id',
+        vector_fields => '{description: {}}'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only index arrays of floats as vectors"),
+        Err(err) => assert!(
+            err.to_string()
+                .contains("cannot be indexed as a vector field"),
+            "{err}"
+        ),
+    };
+}
+
+#[rstest]
+fn sparse_vector_search(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE items (id SERIAL PRIMARY KEY, description TEXT, splade JSONB);
+    INSERT INTO items (description, splade) VALUES
+        ('Ergonomic metal keyboard', '{"keyboard": 1.5, "metal": 0.5}'),
+        ('Plastic keyboard', '{"keyboard": 0.5, "plastic": 2.0}'),
+        ('Sleek running shoes', '{"shoes": 2.5, "running": 1.0}'),
+        ('White jogging shoes', NULL);
+    CALL paradedb.create_bm25(
+        index_name => 'items_idx',
+        table_name => 'items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        sparse_fields => '{splade: {}}'
+    );
+    "#
+    .execute(&mut conn);
+
+    let rows: Vec<(i32, f32)> = r#"
+    SELECT id, paradedb.rank_bm25(id) FROM items_idx.search(
+        query => paradedb.sparse_vector('splade', '{"keyboard": 1.0, "plastic": 0.25}'),
+        stable_sort => true
+    )"#
+    .fetch(&mut conn);
+    let ids: Vec<i32> = rows.iter().map(|row| row.0).collect();
+    assert_eq!(ids, vec![1, 2]);
+    assert!((rows[0].1 - 1.5).abs() < 1e-6);
+    assert!((rows[1].1 - 1.0).abs() < 1e-6);
+
+    // Sparse vectors and BM25 can be combined in the same query.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM items_idx.search(
+        query => paradedb.boolean(should => ARRAY[
+            paradedb.parse('description:jogging'),
+            paradedb.sparse_vector('splade', '{"running": 1.0}')
+        ]),
+        stable_sort => true
+    )"#
+    .fetch(&mut conn);
+    let mut ids: Vec<i32> = rows.into_iter().map(|(id,)| id).collect();
+    ids.sort();
+    assert_eq!(ids, vec![3, 4]);
+
+    "UPDATE items SET splade = '{\"plastic\": 1.0}' WHERE id = 1".execute(&mut conn);
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM items_idx.search(
+        query => paradedb.sparse_vector('splade', '{"plastic": 1.0}'),
+        stable_sort => true
+    )"#
+    .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+
+    match r#"INSERT INTO items (description, splade) VALUES ('Broken', '{"keyboard": -1.0}')"#
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should reject negative weights"),
+        Err(err) => assert!(err.to_string().contains("weights must be between"), "{err}"),
+    };
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'other_idx',
+        table_name => 'items',
+        key_field => 'id',
+        sparse_fields => '{description: {}}'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only index json and sparsevec columns as sparse vectors"),
+        Err(err) => assert!(
+            err.to_string()
+                .contains("cannot be indexed as a sparse vector field"),
+            "{err}"
+        ),
+    };
+}
+
+#[rstest]
+fn key_in_semi_join(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE customers (id SERIAL PRIMARY KEY, bio TEXT);
+    INSERT INTO customers (bio) VALUES
+        ('Loves mechanical keyboards'),
+        ('Runs marathons in sleek shoes'),
+        ('Collects vintage keyboards');
+    CREATE TABLE orders (id SERIAL PRIMARY KEY, customer_id INT, description TEXT);
+    INSERT INTO orders (customer_id, description) VALUES
+        (1, 'Keycaps'),
+        (2, 'Running shoes'),
+        (3, 'Keyboard cable'),
+        (3, 'Wrist rest'),
+        (2, 'Socks');
+    CALL paradedb.create_bm25(
+        index_name => 'customers_idx',
+        table_name => 'customers',
+        key_field => 'id',
+        text_fields => '{bio: {}}'
+    );
+    CALL paradedb.create_bm25(
+        index_name => 'orders_idx',
+        table_name => 'orders',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        numeric_fields => '{customer_id: {}}'
+    );
+    "#
+    .execute(&mut conn);
+
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM orders_idx.search(
+        query => paradedb.key_in('customer_id', 'customers_idx', paradedb.parse('bio:keyboards')),
+        stable_sort => true
+    ) ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (3,), (4,)]);
+
+    // The keys come from the latest version of each customer.
+    "UPDATE customers SET bio = 'Runs marathons now' WHERE id = 1".execute(&mut conn);
+    "UPDATE customers SET bio = 'Types on keyboards too' WHERE id = 2".execute(&mut conn);
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM orders_idx.search(
+        query => paradedb.key_in('customer_id', 'customers_idx', paradedb.parse('bio:keyboards')),
+        stable_sort => true
+    ) ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (3,), (4,), (5,)]);
+
+    // A query that matches no customers matches no orders.
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM orders_idx.search(
+        query => paradedb.key_in('customer_id', 'customers_idx', paradedb.parse('bio:surfing'))
+    )"
+    .fetch(&mut conn);
+    assert_eq!(rows, vec![]);
+}
+
+#[rstest]
+fn keep_history_as_of(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE items (id SERIAL PRIMARY KEY, description TEXT);
+    INSERT INTO items (description) VALUES
+        ('Ergonomic metal keyboard'),
+        ('Plastic keyboard'),
+        ('Sleek running shoes');
+    CALL paradedb.create_bm25(
+        index_name => 'items_idx',
+        table_name => 'items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        search_tab_fields => ARRAY['description'],
+        keep_history => true
+    );
+    "#
+    .execute(&mut conn);
+
+    let (before,): (String,) = "SELECT now()::text".fetch_one(&mut conn);
+    "UPDATE items SET description = 'Wireless keyboard' WHERE id = 1".execute(&mut conn);
+    "DELETE FROM items WHERE id = 2".execute(&mut conn);
+
+    let as_of = |timestamp: &str, conn: &mut PgConnection| -> Vec<(i32, String)> {
+        format!(
+            "SELECT id, description FROM items_idx.search_tab('description:keyboard', as_of => '{timestamp}', stable_sort => true) ORDER BY id"
+        )
+        .fetch(conn)
+    };
+    let past = vec![
+        (1, "Ergonomic metal keyboard".to_string()),
+        (2, "Plastic keyboard".to_string()),
+    ];
+
+    // Replaced versions are searched until vacuum moves them to the history.
+    assert_eq!(as_of(&before, &mut conn), past);
+    "VACUUM items".execute(&mut conn);
+    assert_eq!(as_of(&before, &mut conn), past);
+
+    let rows: Vec<(i32, String)> =
+        "SELECT id, description FROM items_idx.search_tab('description:keyboard')".fetch(&mut conn);
+    assert_eq!(rows, vec![(1, "Wireless keyboard".to_string())]);
+
+    // Deleted rows are dated by the vacuum that removed them.
+    let (now,): (String,) = "SELECT now()::text".fetch_one(&mut conn);
+    assert_eq!(as_of(&now, &mut conn), vec![(1, "Wireless keyboard".to_string())]);
+
+    match format!("SELECT * FROM items_idx.search('description:keyboard', as_of => '{before}')")
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only search past states with search_tab"),
+        Err(err) => assert!(err.to_string().contains("use search_tab"), "{err}"),
+    };
+}
+
+#[rstest]
+fn document_fields_extract_text(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE contracts (id SERIAL PRIMARY KEY, title TEXT, content BYTEA);
+    INSERT INTO contracts (title, content) VALUES
+        ('Lease', convert_to('draft lease agreement', 'UTF8')),
+        ('Employment', convert_to('signed employment contract', 'UTF8')),
+        ('Empty', NULL);
+    "#
+    .execute(&mut conn);
+
+    // The extraction command has to be configured before document fields are indexed.
+    match "CALL paradedb.create_bm25(
+        index_name => 'contracts_idx',
+        table_name => 'contracts',
+        key_field => 'id',
+        document_fields => '{content: {}}'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should require paradedb.extract_command"),
+        Err(err) => assert!(err.to_string().contains("paradedb.extract_command"), "{err}"),
+    };
+
+    // The text printed by the command is indexed, not the content of the column.
+    r#"
+    SET paradedb.extract_command = 'sed s/draft/final/';
+    CALL paradedb.create_bm25(
+        index_name => 'contracts_idx',
+        table_name => 'contracts',
+        key_field => 'id',
+        text_fields => '{title: {}}',
+        document_fields => '{content: {}}'
+    );
+    "#
+    .execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM contracts_idx.search('content:final', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,)]);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM contracts_idx.search('content:draft', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![]);
+
+    "INSERT INTO contracts (title, content) VALUES ('Loan', convert_to('draft loan', 'UTF8'))"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM contracts_idx.search('content:final', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 2);
+
+    // A failing command fails the write, rather than indexing the row without its text.
+    "SET paradedb.extract_command = 'echo unreadable document >&2; exit 1'".execute(&mut conn);
+    match "INSERT INTO contracts (title, content) VALUES ('Broken', '\\x00')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should fail when the extraction fails"),
+        Err(err) => assert!(err.to_string().contains("unreadable document"), "{err}"),
+    };
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'titles_idx',
+        table_name => 'contracts',
+        key_field => 'id',
+        document_fields => '{title: {}}'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only index bytea columns as documents"),
+        Err(err) => assert!(
+            err.to_string().contains("cannot be indexed as a document field"),
+            "{err}"
+        ),
+    };
+}
+
+#[rstest]
+fn migrate_from_tsvector(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE articles (
+        id SERIAL PRIMARY KEY,
+        title TEXT,
+        body TEXT,
+        fts TSVECTOR GENERATED ALWAYS AS (
+            setweight(to_tsvector('english', coalesce(title, '')), 'A') ||
+            setweight(to_tsvector('english', coalesce(body, '')), 'B')
+        ) STORED
+    );
+    INSERT INTO articles (title, body) VALUES
+        ('Running shoes', 'Shoes for runners'),
+        ('Keyboards', 'Typing on mechanical keyboards');
+    "#
+    .execute(&mut conn);
+
+    let (statement, differences): (String, Vec<String>) =
+        "SELECT * FROM paradedb.migrate_from_tsvector('articles', 'fts')".fetch_one(&mut conn);
+    assert!(statement.contains("key_field => 'id'"), "{statement}");
+    assert!(statement.contains(r#""title""#), "{statement}");
+    assert!(statement.contains(r#""body""#), "{statement}");
+    assert!(statement.contains(r#""language":"English""#), "{statement}");
+    assert!(differences.iter().any(|difference| difference.contains("stopwords")));
+    assert!(differences.iter().any(|difference| difference.contains("setweight")));
+
+    // The generated call creates an index that stems like the configuration did.
+    statement.execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM articles_idx.search('body:runner', stable_sort => true)".fetch(&mut conn);
+    assert_eq!(rows, vec![(1,)]);
+
+    // Columns kept up to date by a trigger are found through its arguments.
+    r#"
+    CREATE TABLE notes (id SERIAL PRIMARY KEY, content TEXT, fts TSVECTOR);
+    CREATE TRIGGER notes_fts BEFORE INSERT OR UPDATE ON notes
+        FOR EACH ROW EXECUTE FUNCTION tsvector_update_trigger(fts, 'pg_catalog.simple', content);
+    "#
+    .execute(&mut conn);
+    let (statement, _): (String, Vec<String>) =
+        "SELECT * FROM paradedb.migrate_from_tsvector('notes', 'fts')".fetch_one(&mut conn);
+    assert!(statement.contains(r#""content""#), "{statement}");
+    assert!(statement.contains(r#""type":"default""#), "{statement}");
+
+    match "SELECT * FROM paradedb.migrate_from_tsvector('articles', 'title')"
+        .fetch_result::<(String, Vec<String>)>(&mut conn)
+    {
+        Ok(_) => panic!("should only migrate tsvector columns"),
+        Err(err) => assert!(err.to_string().contains("not a tsvector column"), "{err}"),
+    };
+}
+
+#[rstest]
+fn search_config_with_filters(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SET enable_seqscan = off".execute(&mut conn);
+
+    let expected: Vec<(i32,)> = "
+    SELECT id FROM bm25_search.search('description:shoes OR category:electronics')
+    WHERE rating >= 4 ORDER BY id"
+        .fetch(&mut conn);
+    assert!(!expected.is_empty());
+
+    // The comparison is searched for along with the query by the index scan.
+    let query = "
+    SELECT id FROM paradedb.bm25_search
+    WHERE id @@@ paradedb.search_config('bm25_search', paradedb.parse('description:shoes OR category:electronics'))
+    AND rating >= 4 ORDER BY id";
+    let rows: Vec<(i32,)> = query.fetch(&mut conn);
+    assert_eq!(rows, expected);
+
+    let plan: Vec<(String,)> = format!("EXPLAIN {query}").fetch(&mut conn);
+    let index_cond = plan
+        .iter()
+        .find(|(line,)| line.contains("Index Cond"))
+        .expect("the query should scan the bm25 index");
+    assert!(index_cond.0.contains("rating >= 4"), "{}", index_cond.0);
+
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM paradedb.bm25_search
+    WHERE id @@@ paradedb.search_config('bm25_search', paradedb.parse('description:shoes OR category:electronics'))
+    AND rating = ANY(ARRAY[4, 5]) AND id < 1000 ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, expected);
+}
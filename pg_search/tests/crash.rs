@@ -0,0 +1,108 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+#![cfg(feature = "fault_injection")]
+
+mod fixtures;
+
+use async_std::task::block_on;
+use fixtures::*;
+use rstest::*;
+
+#[rstest]
+#[case::backend_crash_before_send("backend_before_send", "crash")]
+#[case::backend_crash_before_commit("backend_before_commit", "crash")]
+#[case::backend_error_before_commit("backend_before_commit", "error")]
+#[case::writer_crash_before_commit("writer_before_commit", "crash")]
+#[case::writer_crash_after_commit("writer_after_commit", "crash")]
+#[case::writer_error_before_commit("writer_before_commit", "error")]
+fn insert_recovers_from_fault(database: Db, #[case] point: &str, #[case] action: &str) {
+    let mut conn = block_on(database.connection());
+    "CREATE EXTENSION pg_search".execute(&mut conn);
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let test = CrashTest {
+        index_name: "bm25_search",
+        table_name: "paradedb.bm25_search",
+        key_field: "id",
+        point,
+        action,
+    };
+    let (mut conn, _) = test.run(
+        &database,
+        conn,
+        "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+            VALUES ('Faulty keyboard', 2, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')",
+    );
+    test.assert_consistent(
+        &mut conn,
+        "description:keyboard",
+        "description ILIKE '%keyboard%'",
+    );
+
+    // The index keeps taking writes after it recovered.
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Recovered keyboard', 5, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    test.assert_consistent(
+        &mut conn,
+        "description:keyboard",
+        "description ILIKE '%keyboard%'",
+    );
+}
+
+#[rstest]
+fn refresh_interval_discards_aborted_transfer(database: Db) {
+    let mut conn = block_on(database.connection());
+    "CREATE EXTENSION pg_search".execute(&mut conn);
+    "CALL paradedb.create_bm25_test_table(table_name => 'async_faults', schema_name => 'public')"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'async_faults',
+        table_name => 'async_faults',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        refresh_interval => 600000
+    )"
+    .execute(&mut conn);
+
+    // A committed insert is waiting for a background commit when the next transaction aborts
+    // after sending its documents to the writer.
+    "INSERT INTO async_faults (description, rating, category) VALUES ('Committed keyboard', 5, 'Electronics')"
+        .execute(&mut conn);
+    let test = CrashTest {
+        index_name: "async_faults",
+        table_name: "public.async_faults",
+        key_field: "id",
+        point: "backend_before_commit",
+        action: "error",
+    };
+    let (mut conn, failed) = test.run(
+        &database,
+        conn,
+        "INSERT INTO async_faults (description, rating, category) VALUES ('Faulty keyboard', 2, 'Electronics')",
+    );
+    assert!(failed);
+
+    // Only the committed insert reaches the index.
+    "SELECT paradedb.refresh_index('async_faults')".execute(&mut conn);
+    test.assert_consistent(
+        &mut conn,
+        "description:keyboard",
+        "description ILIKE '%keyboard%'",
+    );
+}
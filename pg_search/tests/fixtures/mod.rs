@@ -20,6 +20,7 @@ use async_std::task::block_on;
 use rstest::*;
 use sqlx::{self, PgConnection};
 
+pub use shared::fixtures::crash::*;
 pub use shared::fixtures::db::*;
 pub use shared::fixtures::tables::*;
 
@@ -21,6 +21,7 @@ mod fixtures;
 use fixtures::*;
 use pretty_assertions::assert_eq;
 use rstest::*;
+use sqlx::postgres::PgDatabaseError;
 use sqlx::PgConnection;
 
 fn fmt_err<T: std::error::Error>(err: T) -> String {
@@ -506,6 +507,134 @@ fn null_key_field_insert(mut conn: PgConnection) {
     };
 }
 
+#[rstest]
+fn insert_error_code_and_detail(mut conn: PgConnection) {
+    "CREATE TABLE paradedb.index_config(id INTEGER, description TEXT)".execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'index_config',
+        table_name => 'index_config',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        text_fields => paradedb.field('description')
+    )"
+    .execute(&mut conn);
+
+    // A failed insert is a regular Postgres error, with a code and the table of the row.
+    let err = "INSERT INTO paradedb.index_config VALUES (NULL, 'Null Item')"
+        .execute_result(&mut conn)
+        .expect_err("should fail with null key_field");
+    let err = err
+        .as_database_error()
+        .expect("should be a database error")
+        .downcast_ref::<PgDatabaseError>();
+    assert_eq!(err.code(), "23502");
+    assert_eq!(
+        err.detail(),
+        Some("Failing row is in table \"index_config\".")
+    );
+}
+
+#[rstest]
+fn insert_error_detail_privileges(mut conn: PgConnection) {
+    "CREATE TABLE paradedb.index_config(id INTEGER, created_at DATE)".execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'index_config',
+        table_name => 'index_config',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        datetime_fields => paradedb.field('created_at')
+    )"
+    .execute(&mut conn);
+    let insert = "INSERT INTO paradedb.index_config VALUES (1, '5000000-01-01')";
+    let detail = |conn: &mut PgConnection| {
+        let err = insert
+            .execute_result(conn)
+            .expect_err("should fail to index the date");
+        err.as_database_error()
+            .expect("should be a database error")
+            .downcast_ref::<PgDatabaseError>()
+            .detail()
+            .map(str::to_string)
+    };
+
+    // The value is shown to a role that can read the column.
+    assert_eq!(
+        detail(&mut conn).as_deref(),
+        Some("Failing row of table \"index_config\" has created_at = '5000000-01-01'.")
+    );
+
+    // A role that can only insert into the table doesn't see it.
+    "DO $$ BEGIN
+        CREATE ROLE index_config_writer;
+    EXCEPTION WHEN duplicate_object THEN NULL;
+    END $$"
+        .execute(&mut conn);
+    "GRANT USAGE ON SCHEMA paradedb TO index_config_writer".execute(&mut conn);
+    "GRANT INSERT ON paradedb.index_config TO index_config_writer".execute(&mut conn);
+    "SET ROLE index_config_writer".execute(&mut conn);
+    assert_eq!(
+        detail(&mut conn).as_deref(),
+        Some("Failing row of table \"index_config\" has a bad value in created_at.")
+    );
+    "RESET ROLE".execute(&mut conn);
+}
+
+#[rstest]
+fn field_on_error(mut conn: PgConnection) {
+    "CREATE TABLE paradedb.index_config(id INTEGER, created_at DATE, updated_at DATE)"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'index_config',
+        table_name => 'index_config',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        datetime_fields => paradedb.field('created_at', on_error => 'skip')
+            || paradedb.field('updated_at', on_error => 'null')
+    )"
+    .execute(&mut conn);
+
+    // Dates past the range of the index can't be indexed. The second row is left out, and
+    // the third is indexed without its `updated_at`.
+    "INSERT INTO paradedb.index_config VALUES
+        (1, '2024-01-01', '2024-01-02'),
+        (2, '5000000-01-01', '2024-01-02'),
+        (3, '2024-01-01', '5000000-01-01')"
+        .execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM index_config.search(query => paradedb.all(), stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (3,)]);
+    let rows: Vec<(i32,)> = "SELECT id FROM index_config.search(
+            query => paradedb.range(field => 'updated_at', range => '[2024-01-01,)'::daterange),
+            stable_sort => true
+        )"
+    .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,)]);
+
+    let (skipped_values,): (i64,) = "SELECT skipped_values FROM paradedb.pg_search_indexes
+        WHERE index_name = 'index_config'"
+        .fetch_one(&mut conn);
+    assert_eq!(skipped_values, 2);
+
+    // Fields fail on values that can't be indexed by default.
+    "CREATE TABLE paradedb.index_config_fail(id INTEGER, created_at DATE)".execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'index_config_fail',
+        table_name => 'index_config_fail',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        datetime_fields => paradedb.field('created_at')
+    )"
+    .execute(&mut conn);
+    let err = "INSERT INTO paradedb.index_config_fail VALUES (1, '5000000-01-01')"
+        .execute_result(&mut conn)
+        .expect_err("should fail with a date out of range");
+    assert!(err
+        .to_string()
+        .contains("column 'created_at' of type date cannot be indexed"));
+}
+
 #[rstest]
 fn column_name_camelcase(mut conn: PgConnection) {
     "CREATE TABLE paradedb.index_config(\"IdName\" INTEGER, \"ColumnName\" TEXT)"
@@ -583,3 +712,152 @@ fn index_name_too_long(mut conn: PgConnection) {
         ),
     };
 }
+
+#[rstest]
+fn directory_mode(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'index_config', schema_name => 'paradedb')"
+        .execute(&mut conn);
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'index_config',
+        table_name => 'index_config',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        text_fields => paradedb.field('description'),
+        directory_mode => 'mmap_backwards'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should fail with invalid directory_mode"),
+        Err(err) => assert!(
+            err.to_string().contains("invalid directory_mode"),
+            "{}",
+            fmt_err(err)
+        ),
+    };
+
+    for mode in ["mmap_random", "buffered"] {
+        format!(
+            "CALL paradedb.create_bm25(
+                index_name => 'index_config',
+                table_name => 'index_config',
+                schema_name => 'paradedb',
+                key_field => 'id',
+                text_fields => paradedb.field('description'),
+                directory_mode => '{mode}'
+            )"
+        )
+        .execute(&mut conn);
+
+        let rows: Vec<(i32,)> =
+            "SELECT id FROM index_config.search('description:keyboard') ORDER BY id"
+                .fetch(&mut conn);
+        assert_eq!(rows, vec![(1,), (2,)], "directory_mode {mode}");
+
+        "CALL paradedb.drop_bm25('index_config', schema_name => 'paradedb')".execute(&mut conn);
+    }
+}
+
+#[rstest]
+fn dump_and_restore_index_definition(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // The config that the search functions read is dumped with the uuid of the index.
+    let (uuid,): (String,) =
+        "SELECT split_part(option, '=', 2) FROM pg_class, unnest(reloptions) option
+        WHERE relname = 'bm25_search_bm25_index' AND option LIKE 'uuid=%'"
+            .fetch_one(&mut conn);
+    let (config_uuid,): (String,) = "SELECT config ->> 'uuid' FROM paradedb.bm25_indexes
+        WHERE index_name = 'bm25_search'"
+        .fetch_one(&mut conn);
+    assert_eq!(config_uuid, uuid);
+    let (dumped,): (bool,) = "SELECT 'paradedb.bm25_indexes'::regclass = ANY(extconfig)
+        FROM pg_extension WHERE extname = 'pg_search'"
+        .fetch_one(&mut conn);
+    assert!(dumped);
+
+    // pg_restore recreates the index from its definition, after the rows of its table.
+    let (indexdef,): (String,) =
+        "SELECT pg_get_indexdef('paradedb.bm25_search_bm25_index'::regclass)".fetch_one(&mut conn);
+    "DROP INDEX paradedb.bm25_search_bm25_index".execute(&mut conn);
+    indexdef.execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+}
+
+#[rstest]
+fn swap_bm25_indexes(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // A new version of the index, built next to the live one, without the category field.
+    r#"CALL paradedb.create_bm25(
+        index_name => 'bm25_search_v2',
+        table_name => 'bm25_search',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        text_fields => paradedb.field('description')
+    )"#
+    .execute(&mut conn);
+
+    "CALL paradedb.swap_bm25('bm25_search', 'bm25_search_v2')".execute(&mut conn);
+
+    // The swap points each name at the other bm25 index.
+    let (mapped,): (String,) = "SELECT config ->> 'index_name' FROM paradedb.bm25_indexes
+        WHERE index_name = 'bm25_search'"
+        .fetch_one(&mut conn);
+    assert_eq!(mapped, "bm25_search_v2_bm25_index");
+
+    let fields: Vec<(String,)> = "SELECT name FROM bm25_search.schema()".fetch(&mut conn);
+    assert!(fields.contains(&("description".into(),)));
+    assert!(!fields.contains(&("category".into(),)));
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard') ORDER BY id".fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search_v2.search('category:footwear')".fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+
+    // Functions that take the name of an index follow the swap.
+    let (valid,): (bool,) =
+        "SELECT (paradedb.validate_query('bm25_search', paradedb.term(field => 'category', value => 'footwear')) ->> 'valid')::bool"
+            .fetch_one(&mut conn);
+    assert!(!valid);
+
+    // Swapping again rolls back.
+    "CALL paradedb.swap_bm25('bm25_search_v2', 'bm25_search')".execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('category:footwear')".fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+
+    // Dropping the previous version keeps the live one.
+    "CALL paradedb.swap_bm25('bm25_search', 'bm25_search_v2')".execute(&mut conn);
+    "CALL paradedb.drop_bm25('bm25_search_v2', schema_name => 'paradedb')".execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard') ORDER BY id".fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+    let (count,): (i64,) = "SELECT count(*) FROM pg_class WHERE relname = 'bm25_search_bm25_index'"
+        .fetch_one(&mut conn);
+    assert_eq!(count, 0);
+
+    // Indexes whose functions return other columns can't be swapped.
+    r#"CALL paradedb.create_bm25(
+        index_name => 'bm25_search_tab',
+        table_name => 'bm25_search',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        text_fields => paradedb.field('description'),
+        search_tab_fields => ARRAY['description']
+    )"#
+    .execute(&mut conn);
+    match "CALL paradedb.swap_bm25('bm25_search', 'bm25_search_tab')".execute_result(&mut conn) {
+        Ok(_) => panic!("should reject indexes with other search_tab_fields"),
+        Err(err) => assert!(
+            err.to_string()
+                .contains("same key_field and search_tab_fields"),
+            "{err}"
+        ),
+    };
+}
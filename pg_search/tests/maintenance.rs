@@ -0,0 +1,733 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+mod fixtures;
+
+use fixtures::*;
+use rstest::*;
+use sqlx::PgConnection;
+use std::path::PathBuf;
+
+#[rstest]
+fn force_merge_to_one_segment(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // Every committed transaction creates a new segment.
+    for _ in 0..3 {
+        "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+            VALUES ('Merged teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+            .execute(&mut conn);
+    }
+
+    let (segments, num_docs): (i64, i64) =
+        "SELECT segments, num_docs FROM paradedb.merge_status('bm25_search')".fetch_one(&mut conn);
+    assert!(segments > 1);
+
+    let (segments_before, segments_after): (i64, Option<i64>) =
+        "SELECT * FROM paradedb.force_merge('bm25_search', max_segments => 1, wait => true)"
+            .fetch_one(&mut conn);
+    assert_eq!(segments_before, segments);
+    assert_eq!(segments_after, Some(1));
+
+    let (merged_segments, merged_num_docs, merges_in_progress): (i64, i64, i64) =
+        "SELECT segments, num_docs, merges_in_progress FROM paradedb.merge_status('bm25_search')"
+            .fetch_one(&mut conn);
+    assert_eq!(merged_segments, 1);
+    assert_eq!(merged_num_docs, num_docs);
+    assert_eq!(merges_in_progress, 0);
+}
+
+#[rstest]
+fn pause_and_resume_maintenance(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "SELECT paradedb.pause_maintenance('bm25_search')".execute(&mut conn);
+    let (paused,): (bool,) =
+        "SELECT maintenance_paused FROM paradedb.merge_status('bm25_search')".fetch_one(&mut conn);
+    assert!(paused);
+
+    // A forced merge still goes through while maintenance is paused.
+    for _ in 0..2 {
+        "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+            VALUES ('Paused teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+            .execute(&mut conn);
+    }
+    "SELECT paradedb.force_merge('bm25_search', max_segments => 1, wait => true)"
+        .execute(&mut conn);
+    let (segments,): (i64,) =
+        "SELECT segments FROM paradedb.merge_status('bm25_search')".fetch_one(&mut conn);
+    assert_eq!(segments, 1);
+
+    "SELECT paradedb.resume_maintenance('bm25_search')".execute(&mut conn);
+    let (paused,): (bool,) =
+        "SELECT maintenance_paused FROM paradedb.merge_status('bm25_search')".fetch_one(&mut conn);
+    assert!(!paused);
+}
+
+#[rstest]
+fn vacuum_reclaims_deleted_documents(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SELECT paradedb.force_merge('bm25_search', max_segments => 1, wait => true)"
+        .execute(&mut conn);
+
+    "DELETE FROM paradedb.bm25_search WHERE id > 5".execute(&mut conn);
+    "VACUUM paradedb.bm25_search".execute(&mut conn);
+
+    // Most of the only segment was deleted, so it's rewritten in the background.
+    let mut num_deleted_docs: i64 = -1;
+    for _ in 0..50 {
+        (num_deleted_docs,) = "SELECT num_deleted_docs FROM paradedb.merge_status('bm25_search')"
+            .fetch_one(&mut conn);
+        if num_deleted_docs == 0 {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    assert_eq!(num_deleted_docs, 0);
+
+    let (num_docs,): (i64,) =
+        "SELECT num_docs FROM paradedb.merge_status('bm25_search')".fetch_one(&mut conn);
+    assert_eq!(num_docs, 5);
+}
+
+#[rstest]
+fn warm_index(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (bytes_read,): (i64,) = "SELECT paradedb.warm_index('bm25_search')".fetch_one(&mut conn);
+    let (fast_bytes_read,): (i64,) =
+        "SELECT paradedb.warm_index('bm25_search', fields => ARRAY['description', 'rating'], docstore => false)"
+            .fetch_one(&mut conn);
+    assert!(fast_bytes_read > 0);
+    assert!(bytes_read > fast_bytes_read);
+
+    let rows: SimpleProductsTableVec =
+        "SELECT * FROM bm25_search.search('category:electronics', stable_sort => true)"
+            .fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+
+    match "SELECT paradedb.warm_index('bm25_search', fields => ARRAY['missing'])"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("warming a missing field should fail"),
+        Err(err) => assert!(err.to_string().contains("does not exist")),
+    }
+    match "SELECT paradedb.warm_index('missing')".execute_result(&mut conn) {
+        Ok(_) => panic!("warming a missing index should fail"),
+        Err(err) => assert!(err.to_string().contains("no bm25 index named 'missing'")),
+    }
+}
+
+#[rstest]
+fn set_index_readonly(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "SELECT paradedb.set_index_readonly('bm25_search', true)".execute(&mut conn);
+    let (read_only,): (bool,) =
+        "SELECT read_only FROM paradedb.pg_search_indexes WHERE index_name = 'bm25_search'"
+            .fetch_one(&mut conn);
+    assert!(read_only);
+
+    match "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Read-only teapot', 5, 'Kitchen')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("inserts into a read-only index should fail"),
+        Err(err) => assert!(err.to_string().contains("is read-only")),
+    }
+
+    // Searches keep working.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:shoes')".fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+
+    "SELECT paradedb.set_index_readonly('bm25_search', false)".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Writable teapot', 5, 'Kitchen')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+
+    match "SELECT paradedb.set_index_readonly('missing', true)".execute_result(&mut conn) {
+        Ok(_) => panic!("a missing index can't be made read-only"),
+        Err(err) => assert!(err.to_string().contains("no bm25 index named 'missing'")),
+    }
+}
+
+#[rstest]
+fn maintenance_requires_index_owner(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "DO $$ BEGIN
+        CREATE ROLE maintenance_reader;
+    EXCEPTION WHEN duplicate_object THEN NULL;
+    END $$"
+        .execute(&mut conn);
+    "GRANT USAGE ON SCHEMA paradedb TO maintenance_reader".execute(&mut conn);
+    "GRANT SELECT ON paradedb.bm25_search TO maintenance_reader".execute(&mut conn);
+    "SET ROLE maintenance_reader".execute(&mut conn);
+
+    for statement in [
+        "SELECT paradedb.set_index_readonly('bm25_search', true)",
+        "SELECT paradedb.pause_maintenance('bm25_search')",
+        "SELECT paradedb.resume_maintenance('bm25_search')",
+        "SELECT * FROM paradedb.force_merge('bm25_search')",
+        "SELECT paradedb.resync_index('bm25_search')",
+        "SELECT * FROM paradedb.heal_index('bm25_search')",
+    ] {
+        match statement.execute_result(&mut conn) {
+            Ok(_) => panic!("'{statement}' should require ownership of the index"),
+            Err(err) => assert!(err.to_string().contains("must be owner of index")),
+        }
+    }
+    "RESET ROLE".execute(&mut conn);
+}
+
+#[rstest]
+fn pg_search_indexes_view(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (num_docs, num_deleted_docs, segments, writer_queue_depth): (i64, i64, i64, i64) =
+        "SELECT num_docs, num_deleted_docs, segments, writer_queue_depth
+         FROM paradedb.pg_search_indexes WHERE index_name = 'bm25_search'"
+            .fetch_one(&mut conn);
+    assert_eq!(num_docs, 41);
+    assert_eq!(num_deleted_docs, 0);
+    assert!(segments >= 1);
+    assert_eq!(writer_queue_depth, 0);
+
+    // Every component of the index takes space, and adds up to less than the whole.
+    let (postings_bytes, docstore_bytes, fast_fields_bytes, total_bytes, committed): (
+        i64,
+        i64,
+        i64,
+        i64,
+        bool,
+    ) = "SELECT postings_bytes, docstore_bytes, fast_fields_bytes, total_bytes, last_commit IS NOT NULL
+         FROM paradedb.pg_search_indexes WHERE index_name = 'bm25_search'"
+        .fetch_one(&mut conn);
+    assert!(postings_bytes > 0);
+    assert!(docstore_bytes > 0);
+    assert!(fast_fields_bytes > 0);
+    assert!(postings_bytes + docstore_bytes + fast_fields_bytes <= total_bytes);
+    assert!(committed);
+}
+
+#[rstest]
+fn stat_queries_by_shape(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SELECT paradedb.stat_queries_reset()".execute(&mut conn);
+
+    // Searches for different terms of the same field have the same shape.
+    for term in ["keyboard", "shoes", "speaker"] {
+        format!("SELECT * FROM bm25_search.search(query => paradedb.term(field => 'description', value => '{term}'))")
+            .execute(&mut conn);
+    }
+    "SELECT * FROM bm25_search.search(query => paradedb.term(field => 'category', value => 'electronics'))"
+        .execute(&mut conn);
+
+    let rows: Vec<(String, i64, bool)> = "SELECT query, calls, p99_time_ms >= p50_time_ms
+         FROM paradedb.stat_queries() WHERE index_name = 'bm25_search' ORDER BY calls DESC"
+        .fetch(&mut conn);
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0].1, 3);
+    assert!(rows[0].0.contains("description"));
+    assert!(!rows[0].0.contains("keyboard"));
+    assert_eq!(rows[1].1, 1);
+    assert!(rows.iter().all(|(_, _, ordered)| *ordered));
+
+    "SELECT paradedb.stat_queries_reset()".execute(&mut conn);
+    let rows: Vec<(i64,)> = "SELECT calls FROM paradedb.stat_queries()".fetch(&mut conn);
+    assert!(rows.is_empty());
+}
+
+#[rstest]
+fn metrics_report_writes_and_searches(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Metered teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    "SELECT * FROM bm25_search.search('description:teapot')".execute(&mut conn);
+
+    let metric = |conn: &mut PgConnection, name: &str| -> f64 {
+        let (value,): (f64,) = format!(
+            "SELECT value FROM paradedb.metrics()
+             WHERE metric = '{name}' AND index_name IS NOT DISTINCT FROM 'bm25_search'"
+        )
+        .fetch_one(conn);
+        value
+    };
+    assert_eq!(metric(&mut conn, "pg_search_documents_indexed_total"), 42.0);
+    assert!(metric(&mut conn, "pg_search_commits_total") >= 2.0);
+    assert_eq!(metric(&mut conn, "pg_search_writer_queue_depth"), 0.0);
+    assert_eq!(metric(&mut conn, "pg_search_docs"), 42.0);
+
+    let (searches,): (f64,) =
+        "SELECT value FROM paradedb.metrics() WHERE metric = 'pg_search_searches_total'"
+            .fetch_one(&mut conn);
+    assert!(searches >= 1.0);
+}
+
+#[rstest]
+fn check_index_finds_no_problems(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "ANALYZE paradedb.bm25_search".execute(&mut conn);
+
+    let checks: Vec<(String, String)> =
+        "SELECT check, status FROM paradedb.check_index('bm25_search', sample_size => 10)"
+            .fetch(&mut conn);
+    assert_eq!(
+        checks,
+        vec![
+            ("files".into(), "ok".into()),
+            ("checksums".into(), "ok".into()),
+            ("doc_count".into(), "ok".into()),
+            ("keys".into(), "ok".into()),
+        ]
+    );
+
+    let (status,): (String,) =
+        "SELECT status FROM paradedb.check_index('bm25_search', sample_size => 0) WHERE check = 'keys'"
+            .fetch_one(&mut conn);
+    assert_eq!(status, "skipped");
+}
+
+#[rstest]
+fn resync_rebuilds_stale_index(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (db_oid,) = "SELECT oid::int4 FROM pg_database WHERE datname = current_database();"
+        .fetch_one::<(i32,)>(&mut conn);
+    let (relfilenode,) = "SELECT pg_relation_filenode(oid)::int8 FROM pg_class WHERE relname = 'bm25_search_bm25_index'"
+        .fetch_one::<(i64,)>(&mut conn);
+    let data_directory = "SHOW data_directory;".fetch_one::<(String,)>(&mut conn).0;
+    let index_dir_path = PathBuf::from(data_directory)
+        .join("paradedb")
+        .join("pg_search")
+        .join(format!("{db_oid}_{relfilenode}_bm25_search_bm25_index"));
+    let marker_path = index_dir_path.join("needs-resync");
+
+    // As after a failover, the index is marked and rebuilt by the next search.
+    std::fs::write(&marker_path, "").unwrap();
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+    assert!(!marker_path.exists());
+
+    // An index whose files are missing is rebuilt too, including by writes.
+    std::fs::remove_dir_all(&index_dir_path).unwrap();
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Resynced keyboard', 5, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+}
+
+#[rstest]
+fn backup_and_restore_index(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let backup_dir = tempfile::tempdir().unwrap();
+    let backup_path = backup_dir.path().join("bm25_search");
+
+    let (backed_up,): (i64,) = format!(
+        "SELECT paradedb.backup_index('bm25_search', '{}')",
+        backup_path.display()
+    )
+    .fetch_one(&mut conn);
+    assert_eq!(backed_up, 41);
+
+    // A backup must go to a new directory.
+    let result = format!(
+        "SELECT paradedb.backup_index('bm25_search', '{}')",
+        backup_path.display()
+    )
+    .execute_result(&mut conn);
+    assert!(result.is_err());
+
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Unbacked keyboard', 5, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard')".fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+
+    // The restored index is back to the state of the backup.
+    let (restored,): (i64,) = format!(
+        "SELECT paradedb.restore_index('bm25_search', '{}')",
+        backup_path.display()
+    )
+    .fetch_one(&mut conn);
+    assert_eq!(restored, 41);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+}
+
+#[rstest]
+fn export_index_to_parquet(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let export_dir = tempfile::tempdir().unwrap();
+    let export_path = export_dir.path().join("keyboards.parquet");
+
+    let (exported,): (i64,) = format!(
+        "SELECT paradedb.export_index('bm25_search', '{}', query => paradedb.parse('description:keyboard'))",
+        export_path.display()
+    )
+    .fetch_one(&mut conn);
+    assert_eq!(exported, 2);
+    assert!(export_path.exists());
+
+    // An export never overwrites a file.
+    let result = format!(
+        "SELECT paradedb.export_index('bm25_search', '{}')",
+        export_path.display()
+    )
+    .execute_result(&mut conn);
+    assert!(result.is_err());
+
+    let (exported,): (i64,) = format!(
+        "SELECT paradedb.export_index('bm25_search', '{}', format => 'arrow')",
+        export_dir.path().join("all.arrow").display()
+    )
+    .fetch_one(&mut conn);
+    assert_eq!(exported, 41);
+
+    let result = format!(
+        "SELECT paradedb.export_index('bm25_search', '{}', format => 'csv')",
+        export_dir.path().join("all.csv").display()
+    )
+    .execute_result(&mut conn);
+    assert!(result.is_err());
+}
+
+#[rstest]
+fn heal_index_reindexes_missing_rows(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let backup_dir = tempfile::tempdir().unwrap();
+    let backup_path = backup_dir.path().join("bm25_search");
+
+    let (checked, repaired): (i64, i64) =
+        "SELECT rows_checked, rows_repaired FROM paradedb.heal_index('bm25_search')"
+            .fetch_one(&mut conn);
+    assert_eq!((checked, repaired), (41, 0));
+
+    // Restoring an older backup loses the rows inserted since, like a lost write would.
+    format!(
+        "SELECT paradedb.backup_index('bm25_search', '{}')",
+        backup_path.display()
+    )
+    .execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Healed keyboard', 5, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    format!(
+        "SELECT paradedb.restore_index('bm25_search', '{}')",
+        backup_path.display()
+    )
+    .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard')".fetch(&mut conn);
+    assert_eq!(rows.len(), 2);
+
+    let (checked, repaired): (i64, i64) =
+        "SELECT rows_checked, rows_repaired FROM paradedb.heal_index('bm25_search')"
+            .fetch_one(&mut conn);
+    assert_eq!((checked, repaired), (42, 1));
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard')".fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+}
+
+#[rstest]
+fn index_build_progress_view(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // Once every row is indexed, the build waits on the commit of its transaction.
+    "BEGIN".execute(&mut conn);
+    "REINDEX INDEX paradedb.bm25_search_bm25_index".execute(&mut conn);
+    let (phase, docs_indexed, own_pid): (String, i64, bool) =
+        "SELECT phase, docs_indexed, pid = pg_backend_pid()
+         FROM paradedb.index_build_progress WHERE index_name = 'bm25_search'"
+            .fetch_one(&mut conn);
+    assert_eq!(phase, "committing index");
+    assert_eq!(docs_indexed, 41);
+    assert!(own_pid);
+    "COMMIT".execute(&mut conn);
+
+    let rows: Vec<(String,)> =
+        "SELECT index_name FROM paradedb.index_build_progress".fetch(&mut conn);
+    assert!(rows.is_empty());
+}
+
+#[rstest]
+fn gc_orphaned_directories(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (db_oid,) = "SELECT oid::int4 FROM pg_database WHERE datname = current_database();"
+        .fetch_one::<(i32,)>(&mut conn);
+    let (relfilenode,) = "SELECT pg_relation_filenode(oid)::int8 FROM pg_class WHERE relname = 'bm25_search_bm25_index'"
+        .fetch_one::<(i64,)>(&mut conn);
+    let data_directory = "SHOW data_directory;".fetch_one::<(String,)>(&mut conn).0;
+    let search_dir_path = PathBuf::from(data_directory)
+        .join("paradedb")
+        .join("pg_search");
+
+    // Left behind by an index dropped while the writer was down, by a dropped database, and
+    // by an earlier file node of a live index.
+    let dropped_index_path = search_dir_path.join(format!("{db_oid}_1_ghost_bm25_index"));
+    let dropped_database_path = search_dir_path.join("4000000000_1_ghost_bm25_index");
+    let rebuilt_index_path = search_dir_path.join(format!("{db_oid}_1_bm25_search_bm25_index"));
+    for path in [
+        &dropped_index_path,
+        &dropped_database_path,
+        &rebuilt_index_path,
+    ] {
+        std::fs::create_dir_all(path.join("tantivy")).unwrap();
+        std::fs::write(path.join("tantivy").join("meta.json"), "{}").unwrap();
+    }
+
+    let rows: Vec<(String, String, i64)> =
+        "SELECT index_name, reason, total_bytes FROM paradedb.orphaned_directories()
+         WHERE relfilenode = 1 ORDER BY index_name, reason"
+            .fetch(&mut conn);
+    assert_eq!(
+        rows,
+        vec![
+            ("bm25_search_bm25_index".into(), "index rebuilt".into(), 2),
+            ("ghost_bm25_index".into(), "database dropped".into(), 2),
+            ("ghost_bm25_index".into(), "index dropped".into(), 2),
+        ]
+    );
+
+    // The directories of other databases can only be removed by superusers.
+    "DO $$ BEGIN
+        CREATE ROLE gc_directories_user;
+    EXCEPTION WHEN duplicate_object THEN NULL;
+    END $$"
+        .execute(&mut conn);
+    "GRANT USAGE ON SCHEMA paradedb TO gc_directories_user".execute(&mut conn);
+    "SET ROLE gc_directories_user".execute(&mut conn);
+    match "SELECT paradedb.gc_directories()".execute_result(&mut conn) {
+        Ok(_) => panic!("gc_directories should require superuser"),
+        Err(err) => assert!(err.to_string().contains("must be superuser")),
+    };
+    "RESET ROLE".execute(&mut conn);
+
+    // Other tests may have left databases behind too.
+    let (removed,): (i64,) = "SELECT paradedb.gc_directories()".fetch_one(&mut conn);
+    assert!(removed >= 3);
+    assert!(!dropped_index_path.exists());
+    assert!(!dropped_database_path.exists());
+    assert!(!rebuilt_index_path.exists());
+    assert!(search_dir_path
+        .join(format!("{db_oid}_{relfilenode}_bm25_search_bm25_index"))
+        .exists());
+
+    // The index still works.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+}
+
+#[rstest]
+fn wait_events_view(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+
+    // Once its search is done, the backend isn't waiting anymore.
+    let (wait_event,): (Option<String>,) =
+        "SELECT paradedb.search_wait_event(pg_backend_pid())".fetch_one(&mut conn);
+    assert_eq!(wait_event, None);
+
+    let rows: Vec<(i32, String)> =
+        "SELECT pid, wait_event FROM paradedb.wait_events WHERE pid = pg_backend_pid()"
+            .fetch(&mut conn);
+    assert!(rows.is_empty());
+}
+
+#[rstest]
+fn alter_index_options(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // Hashes barely compress, so this takes up more than a megabyte once committed.
+    "INSERT INTO paradedb.bm25_search (description, rating, category)
+        SELECT (SELECT string_agg(md5(i::text || '-' || j::text), ' ') FROM generate_series(1, 100) j), 5, 'Kitchen'
+        FROM generate_series(1, 1000) i"
+        .execute(&mut conn);
+
+    "SELECT paradedb.alter_index_options('bm25_search', max_index_size => 1, refresh_interval => 1000)"
+        .execute(&mut conn);
+    let (options,): (Vec<String>,) =
+        "SELECT reloptions FROM pg_class WHERE relname = 'bm25_search_bm25_index'"
+            .fetch_one(&mut conn);
+    assert!(options.contains(&"max_index_size=1".to_string()));
+    assert!(options.contains(&"refresh_interval=1000".to_string()));
+
+    match "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Oversized teapot', 5, 'Kitchen')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("inserts should fail once the index is over its new max_index_size"),
+        Err(err) => assert!(err.to_string().contains("max_index_size of 1 MB")),
+    };
+
+    // Options changed with ALTER INDEX apply the next time the index is used.
+    "ALTER INDEX paradedb.bm25_search_bm25_index SET (max_index_size = 0)".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Unlimited teapot', 5, 'Kitchen')"
+        .execute(&mut conn);
+
+    match "SELECT paradedb.alter_index_options('bm25_search', refresh_interval => 0, writer_memory_budget => 64)"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("writer_memory_budget should require a refresh_interval"),
+        Err(err) => assert!(err.to_string().contains("requires a refresh_interval")),
+    };
+    match "SELECT paradedb.alter_index_options('bm25_search')".execute_result(&mut conn) {
+        Ok(_) => panic!("altering no options should fail"),
+        Err(err) => assert!(err.to_string().contains("no options to alter")),
+    };
+}
+
+#[rstest]
+fn refresh_index_of_view(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE remote_items (id serial PRIMARY KEY, description text, updated_at timestamp);
+    INSERT INTO remote_items (description, updated_at) VALUES
+        ('Ergonomic metal keyboard', TIMESTAMP '2023-05-01 09:12:34'),
+        ('Plastic Keyboard', TIMESTAMP '2023-05-02 09:12:34'),
+        ('Sleek running shoes', TIMESTAMP '2023-05-03 09:12:34');
+    CREATE VIEW remote_items_view AS SELECT * FROM remote_items;
+
+    CALL paradedb.create_bm25(
+        index_name => 'remote_items_idx',
+        table_name => 'remote_items_view',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        modified_column => 'updated_at'
+    );
+    "#
+    .execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM remote_items_idx.search('description:keyboard') ORDER BY id"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    // Only the rows modified since the last one copied are copied again.
+    "UPDATE remote_items SET description = 'Wireless keyboard', updated_at = TIMESTAMP '2023-05-04 09:12:34' WHERE id = 3"
+        .execute(&mut conn);
+    let (copied,): (i64,) = "SELECT paradedb.refresh_bm25('remote_items_idx')".fetch_one(&mut conn);
+    assert_eq!(copied, 1);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM remote_items_idx.search('description:keyboard') ORDER BY id"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,), (3,)]);
+
+    // Deleted rows are only removed by a full refresh.
+    "DELETE FROM remote_items WHERE id = 1".execute(&mut conn);
+    let (copied,): (i64,) =
+        "SELECT paradedb.refresh_bm25('remote_items_idx', full => true)".fetch_one(&mut conn);
+    assert_eq!(copied, 2);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM remote_items_idx.search('description:keyboard') ORDER BY id"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (3,)]);
+
+    // Tables are indexed as they're written, so they can't be refreshed.
+    let result = "CALL paradedb.create_bm25(
+        index_name => 'remote_items_table_idx',
+        table_name => 'remote_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        modified_column => 'updated_at'
+    )"
+    .execute_result(&mut conn);
+    assert!(result.is_err());
+}
+
+#[rstest]
+fn index_report(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SELECT paradedb.force_merge('bm25_search', max_segments => 1, wait => true)"
+        .execute(&mut conn);
+
+    let rows: Vec<(String, Option<String>, i64, Option<String>, i64)> =
+        "SELECT kind, field, bytes, action, estimated_savings FROM paradedb.index_report('bm25_search')"
+            .fetch(&mut conn);
+
+    let (_, _, total_bytes, action, _) = rows
+        .iter()
+        .find(|(kind, ..)| kind == "segments")
+        .expect("the report should cover segments");
+    assert!(*total_bytes > 0);
+    assert_eq!(action, &None, "a single segment needs no merge");
+
+    for kind in ["postings", "positions", "stored"] {
+        assert!(
+            rows.iter().any(|(row_kind, field, bytes, ..)| row_kind == kind
+                && field.as_deref() == Some("description")
+                && *bytes > 0),
+            "no {kind} entry for description"
+        );
+    }
+    for (kind, field, bytes, action, estimated_savings) in &rows {
+        assert!(bytes <= total_bytes, "{kind} of {field:?}");
+        match action {
+            Some(_) => assert!(*estimated_savings > 0 || kind == "segments"),
+            None => assert_eq!(*estimated_savings, 0),
+        }
+    }
+
+    // Deleting most rows suggests merging them away.
+    "DELETE FROM paradedb.bm25_search WHERE id > 5".execute(&mut conn);
+    "SELECT paradedb.pause_maintenance('bm25_search')".execute(&mut conn);
+    "VACUUM paradedb.bm25_search".execute(&mut conn);
+    let (detail, action, estimated_savings): (String, Option<String>, i64) =
+        "SELECT detail, action, estimated_savings FROM paradedb.index_report('bm25_search') WHERE kind = 'deletes'"
+            .fetch_one(&mut conn);
+    assert!(detail.contains("are deleted"), "{detail}");
+    assert!(action.unwrap().contains("force_merge"));
+    assert!(estimated_savings > 0);
+}
+
+#[rstest]
+fn index_generation_advances_with_commits(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (opstamp, committed, pending): (i64, bool, i64) =
+        "SELECT opstamp, last_commit IS NOT NULL, pending_operations FROM paradedb.index_generation('bm25_search')"
+            .fetch_one(&mut conn);
+    assert!(committed);
+    assert_eq!(pending, 0);
+
+    "INSERT INTO paradedb.bm25_search (description, category, rating, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+     VALUES ('generation keyboard', 'Electronics', 4, true, '{}', now(), current_date, current_time)"
+        .execute(&mut conn);
+
+    let (next_opstamp, pending): (i64, i64) =
+        "SELECT opstamp, pending_operations FROM paradedb.index_generation('bm25_search')"
+            .fetch_one(&mut conn);
+    assert!(next_opstamp > opstamp);
+    assert_eq!(pending, 0);
+}
@@ -22,6 +22,7 @@ use core::panic;
 use fixtures::*;
 use pretty_assertions::assert_eq;
 use rstest::*;
+use sqlx::postgres::PgDatabaseError;
 use sqlx::PgConnection;
 
 #[rstest]
@@ -285,3 +286,423 @@ fn single_queries(mut conn: PgConnection) {
     .fetch_collect(&mut conn);
     assert_eq!(columns.len(), 5);
 }
+
+#[rstest]
+fn combine_with_operators(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let columns: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search(
+        query => paradedb.term(field => 'description', value => 'shoes')
+            || paradedb.term(field => 'description', value => 'keyboard'),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(columns.id, vec![1, 2, 3, 4, 5]);
+
+    let columns: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search(
+        query => (paradedb.term(field => 'description', value => 'shoes')
+            || paradedb.term(field => 'description', value => 'keyboard'))
+            && !!paradedb.term(field => 'category', value => 'footwear'),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(columns.id, vec![1, 2]);
+
+    // Chains of the same operator make a single boolean query.
+    let (flattened,): (bool,) = r#"
+    SELECT (paradedb.parse('description:shoes') && paradedb.parse('rating:>3') && paradedb.parse('in_stock:true'))::text
+        = paradedb.boolean(must => ARRAY[
+            paradedb.parse('description:shoes'),
+            paradedb.parse('rating:>3'),
+            paradedb.parse('in_stock:true')
+        ])::text"#
+        .fetch_one(&mut conn);
+    assert!(flattened);
+}
+
+#[rstest]
+fn more_like_this_terms(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let columns: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search(
+        query => paradedb.more_like_this(
+            min_doc_frequency => 1,
+            min_term_frequency => 1,
+            fields => ARRAY[paradedb.term(field => 'description', value => 'shoes')]
+        ),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(columns.id, vec![3, 4, 5]);
+}
+
+#[rstest]
+fn more_like_this_jsonb(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let columns: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search(
+        query => paradedb.more_like_this(
+            '{"description": ["shoes", null], "rating": 4, "in_stock": null}'::jsonb,
+            min_doc_frequency => 1,
+            min_term_frequency => 1
+        ),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    let expected: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search('description:shoes OR rating:4', stable_sort => true)
+    ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(columns.id, expected.id);
+
+    match r#"
+    SELECT * FROM bm25_search.search(
+        query => paradedb.more_like_this('{"description": {"text": "shoes"}}'::jsonb)
+    )"#
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not search a json object"),
+        Err(err) => assert!(err.to_string().contains("must be a scalar"), "{err}"),
+    };
+}
+
+#[rstest]
+fn saved_queries(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "SELECT paradedb.save_query(
+        'in_stock_description',
+        paradedb.term(field => 'description', value => '$term') && paradedb.term(field => 'in_stock', value => true),
+        description => 'Products in stock by a term of their description'
+    )"
+    .execute(&mut conn);
+
+    let (parameters,): (Vec<String>,) =
+        "SELECT parameters FROM paradedb.saved_queries WHERE name = 'in_stock_description'"
+            .fetch_one(&mut conn);
+    assert_eq!(parameters, vec!["term"]);
+
+    let columns: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search(
+        query => paradedb.run_saved('in_stock_description', '{"term": "keyboard"}'),
+        stable_sort => true
+    )"#
+    .fetch_collect(&mut conn);
+    assert_eq!(columns.id, vec![1]);
+
+    match "SELECT paradedb.run_saved('in_stock_description')".execute_result(&mut conn) {
+        Ok(_) => panic!("should require a value for every parameter"),
+        Err(err) => assert!(err.to_string().contains("term"), "{err}"),
+    };
+
+    let (dropped,): (bool,) =
+        "SELECT paradedb.drop_saved_query('in_stock_description')".fetch_one(&mut conn);
+    assert!(dropped);
+    match "SELECT paradedb.run_saved('in_stock_description', '{\"term\": \"keyboard\"}')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not run a dropped query"),
+        Err(err) => assert!(err.to_string().contains("no query is saved"), "{err}"),
+    };
+}
+
+#[rstest]
+fn search_templates(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let columns: SimpleProductsTableVec = r#"
+    SELECT * FROM bm25_search.search(
+        query => paradedb.search_template(
+            'bm25_search',
+            'description:{{term}} AND rating:[{{min}} TO {{max}}]',
+            '{"term": "keyboard", "min": 4, "max": 5}'
+        ),
+        stable_sort => true
+    )"#
+    .fetch_collect(&mut conn);
+    assert_eq!(columns.id, vec![1, 2]);
+
+    // Values are quoted, so they can't add clauses to the query string.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM bm25_search.search(
+        query => paradedb.search_template(
+            'bm25_search',
+            'description:{{term}}',
+            '{"term": "keyboard OR description:shoes"}'
+        )
+    )"#
+    .fetch(&mut conn);
+    assert!(rows.is_empty());
+
+    match r#"SELECT paradedb.search_template('bm25_search', 'rating:{{rating}}', '{"rating": "4"}')"#
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should check values against the type of their field"),
+        Err(err) => assert!(err.to_string().contains("must be an integer"), "{err}"),
+    };
+}
+
+#[rstest]
+fn parse_error_position(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let err = "SELECT * FROM bm25_search.search('description:shoes AND (rating:4')"
+        .execute_result(&mut conn)
+        .expect_err("should not parse an unclosed parenthesis");
+    let err = err
+        .as_database_error()
+        .expect("should be a database error")
+        .downcast_ref::<PgDatabaseError>();
+    assert_eq!(err.code(), "42601");
+    assert!(
+        err.message().contains("at character 23 near '(rating:4'"),
+        "{err}"
+    );
+    assert_eq!(
+        err.detail(),
+        Some("description:shoes AND (rating:4\n                      ^")
+    );
+    assert!(err.hint().is_some_and(|hint| hint.contains("column:term")));
+}
+
+#[rstest]
+fn percolate_documents(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    r#"
+    SELECT paradedb.register_query('bm25_search', 'keyboards', paradedb.term(field => 'description', value => 'keyboard'));
+    SELECT paradedb.register_query('bm25_search', 'shoes', paradedb.term(field => 'description', value => 'shoes'));
+    SELECT paradedb.register_query('bm25_search', 'in_stock', paradedb.term(field => 'in_stock', value => true));
+    "#
+    .execute(&mut conn);
+
+    // Queries without anchors run for every document.
+    let anchors: Vec<(String, Option<Vec<String>>)> =
+        "SELECT name, anchors FROM paradedb.percolator_queries ORDER BY name".fetch(&mut conn);
+    assert_eq!(
+        anchors,
+        vec![
+            ("in_stock".into(), None),
+            (
+                "keyboards".into(),
+                Some(vec!["description:keyboard".into()])
+            ),
+            ("shoes".into(), Some(vec!["description:shoes".into()])),
+        ]
+    );
+
+    let matches: Vec<(String,)> = r#"
+    SELECT query_name FROM paradedb.percolate(
+        'bm25_search',
+        '{"description": "Plastic Keyboard", "rating": 4, "in_stock": false, "color": "red"}'
+    )"#
+    .fetch(&mut conn);
+    assert_eq!(matches, vec![("keyboards".into(),)]);
+
+    r#"
+    CREATE TABLE alerts (id integer, query_name text);
+    CREATE FUNCTION alert_on_insert() RETURNS trigger AS $$
+    BEGIN
+        INSERT INTO alerts SELECT NEW.id, query_name FROM paradedb.percolate('bm25_search', to_jsonb(NEW));
+        RETURN NEW;
+    END;
+    $$ LANGUAGE plpgsql;
+    CREATE TRIGGER alert_on_insert AFTER INSERT ON paradedb.bm25_search
+        FOR EACH ROW EXECUTE FUNCTION alert_on_insert();
+    INSERT INTO paradedb.bm25_search (id, description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+    VALUES (42, 'Running shoes', 5, 'Footwear', true, '{"color": "blue"}', now(), current_date, '10:00:00');
+    "#
+    .execute(&mut conn);
+
+    let alerts: Vec<(i32, String)> =
+        "SELECT id, query_name FROM alerts ORDER BY query_name".fetch(&mut conn);
+    assert_eq!(alerts, vec![(42, "in_stock".into()), (42, "shoes".into())]);
+
+    let (unregistered,): (bool,) =
+        "SELECT paradedb.unregister_query('bm25_search', 'shoes')".fetch_one(&mut conn);
+    assert!(unregistered);
+}
+
+#[rstest]
+fn validate_query(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let (valid, query_tree): (bool, Option<String>) = "
+    SELECT (v->>'valid')::bool, v->>'query_tree' FROM paradedb.validate_query(
+        'bm25_search', paradedb.parse('description:keyboard AND category:electronics')
+    ) AS v"
+        .fetch_one(&mut conn);
+    assert!(valid);
+    assert!(query_tree.unwrap().contains("TermQuery"));
+
+    let problems: Vec<(String, String, Option<String>)> = "
+    SELECT p->>'kind', p->>'path', p->>'field'
+    FROM jsonb_array_elements(paradedb.validate_query(
+        'bm25_search',
+        paradedb.term(field => 'color', value => 'red')
+            && paradedb.regex(field => 'description', pattern => 'key(board')
+            && paradedb.range(field => 'rating', range => 'empty'::int4range)
+    )->'problems') AS p"
+        .fetch(&mut conn);
+    assert_eq!(
+        problems,
+        vec![
+            (
+                "unknown_field".into(),
+                "must[0]".into(),
+                Some("color".into())
+            ),
+            (
+                "invalid_regex".into(),
+                "must[1]".into(),
+                Some("description".into())
+            ),
+            (
+                "invalid_bounds".into(),
+                "must[2]".into(),
+                Some("rating".into())
+            ),
+        ]
+    );
+}
+
+#[rstest]
+fn term_expansion_limits(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // keyboard and shoes are the only terms of the pattern.
+    let query = "SELECT id FROM bm25_search.search(
+        query => paradedb.regex(field => 'description', pattern => 'key.*|shoes', max_expansions => 1)
+    ) ORDER BY id";
+    match query.fetch_result::<(i32,)>(&mut conn) {
+        Err(err) => assert!(
+            err.to_string().contains("expands to more than 1 terms"),
+            "{err}"
+        ),
+        _ => panic!("regex should expand to more terms than allowed"),
+    }
+
+    // The most frequent term is searched instead.
+    "SET paradedb.rewrite_term_expansions = on".execute(&mut conn);
+    let rows: Vec<(i32,)> = query.fetch(&mut conn);
+    assert_eq!(rows, vec![(3,), (4,), (5,)]);
+
+    // The setting limits queries that don't set their own limit.
+    "SET paradedb.max_term_expansions = 1".execute(&mut conn);
+    let rows: Vec<(i32,)> = "SELECT id FROM bm25_search.search(
+        query => paradedb.regex(field => 'description', pattern => 'key.*|shoes')
+    ) ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(3,), (4,), (5,)]);
+    let rows: Vec<(i32,)> = "SELECT id FROM bm25_search.search(
+        query => paradedb.fuzzy_term(field => 'description', value => 'keyboarx')
+    ) ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+    let rows: Vec<(i32,)> = "SELECT id FROM bm25_search.search(
+        query => paradedb.regex(field => 'description', pattern => 'key.*|shoes', max_expansions => 2)
+    ) ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,), (3,), (4,), (5,)]);
+}
+
+#[rstest]
+fn rewritten_query_tree(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // A nested tree with empty clauses, like query builders generate, ranks like its flat form.
+    let nested: Vec<(i32, f32)> = r#"
+    SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search(
+        query => paradedb.boolean(
+            should => ARRAY[
+                paradedb.boolean(should => ARRAY[
+                    paradedb.term(field => 'description', value => 'shoes'),
+                    paradedb.boolean(should => ARRAY[
+                        paradedb.term(field => 'description', value => 'speaker'),
+                        paradedb.empty()
+                    ])
+                ]),
+                paradedb.empty()
+            ],
+            must_not => ARRAY[
+                paradedb.boolean(should => ARRAY[
+                    paradedb.term(field => 'description', value => 'white'),
+                    paradedb.term(field => 'description', value => 'running')
+                ])
+            ]
+        ),
+        stable_sort => true
+    )"#
+    .fetch(&mut conn);
+    let flat: Vec<(i32, f32)> = r#"
+    SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search(
+        query => paradedb.boolean(
+            should => ARRAY[
+                paradedb.term(field => 'description', value => 'shoes'),
+                paradedb.term(field => 'description', value => 'speaker')
+            ],
+            must_not => ARRAY[
+                paradedb.term(field => 'description', value => 'white'),
+                paradedb.term(field => 'description', value => 'running')
+            ]
+        ),
+        stable_sort => true
+    )"#
+    .fetch(&mut conn);
+    assert!(!flat.is_empty());
+    assert_eq!(nested, flat);
+
+    // A conjunction with a clause that matches nothing matches nothing.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM bm25_search.search(
+        query => paradedb.boolean(must => ARRAY[
+            paradedb.term(field => 'description', value => 'shoes'),
+            paradedb.boolean(should => ARRAY[paradedb.empty()])
+        ])
+    )"#
+    .fetch(&mut conn);
+    assert!(rows.is_empty());
+}
+
+#[rstest]
+fn parse_conjunction(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let either: Vec<(i32,)> = "
+    SELECT id FROM bm25_search.search('description:running description:shoes', stable_sort => true)"
+        .fetch(&mut conn);
+    let both: Vec<(i32,)> = "
+    SELECT id FROM bm25_search.search(
+        query => paradedb.parse('description:running description:shoes', conjunction => true),
+        stable_sort => true
+    )"
+    .fetch(&mut conn);
+    assert_eq!(both, vec![(3,)]);
+    assert!(either.len() > both.len());
+
+    // Indexes can combine terms with AND by default, which queries can still override.
+    r#"CALL paradedb.create_bm25(
+        index_name => 'conjunction_search',
+        table_name => 'bm25_search',
+        schema_name => 'paradedb',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        conjunction_by_default => true
+    )"#
+    .execute(&mut conn);
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM conjunction_search.search('description:running description:shoes', stable_sort => true)"
+        .fetch(&mut conn);
+    assert_eq!(rows, both);
+    let rows: Vec<(i32,)> = "
+    SELECT id FROM conjunction_search.search(
+        query => paradedb.parse('description:running description:shoes', conjunction => false),
+        stable_sort => true
+    )"
+    .fetch(&mut conn);
+    assert_eq!(rows, either);
+}
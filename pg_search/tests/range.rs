@@ -186,3 +186,175 @@ fn datetime_range(mut conn: PgConnection) {
     .fetch_collect(&mut conn);
     assert_eq!(rows.len(), 3);
 }
+
+#[rstest]
+fn bounds_range(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE test_table (
+        id SERIAL PRIMARY KEY,
+        value_int4 INTEGER,
+        value_numeric NUMERIC,
+        value_date DATE
+    );
+
+    INSERT INTO test_table (value_int4, value_numeric, value_date) VALUES
+        (-1111, -111.11111, DATE '2020-06-28'),
+        (2222, 222.22222, DATE '2021-04-30'),
+        (3333, 333.33333, DATE '2022-07-14'),
+        (4444, 444.44444, DATE '2023-05-03');
+    "#
+    .execute(&mut conn);
+
+    r#"
+    CALL paradedb.create_bm25(
+        table_name => 'test_table',
+        index_name => 'test_index',
+        key_field => 'id',
+        numeric_fields => paradedb.field('value_int4') || paradedb.field('value_numeric'),
+        datetime_fields => paradedb.field('value_date')
+    );
+    "#
+    .execute(&mut conn);
+
+    // Bounds default to '[)'.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM test_index.search(
+        query => paradedb.range('value_int4', 2222, 4444),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(rows, vec![(2,), (3,)]);
+
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM test_index.search(
+        query => paradedb.range('value_int4', 2222, 4444, bounds => '(]'),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(rows, vec![(3,), (4,)]);
+
+    // A NULL bound is unbounded.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM test_index.search(
+        query => paradedb.range('value_numeric', NULL, 300.5),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM test_index.search(
+        query => paradedb.range('value_date', DATE '2021-04-30', NULL, bounds => '()'),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(rows, vec![(3,), (4,)]);
+
+    // Untyped NULLs resolve to the text overload, which matches every row.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM test_index.search(
+        query => paradedb.range('value_int4', NULL, NULL),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,), (3,), (4,)]);
+
+    // Text bounds are read as the type of the field.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM test_index.search(
+        query => paradedb.range('value_int4', '2222', NULL),
+        stable_sort => true
+    ) ORDER BY id"#
+        .fetch_collect(&mut conn);
+    assert_eq!(rows, vec![(2,), (3,), (4,)]);
+
+    match "SELECT paradedb.range('value_numeric', 'NaN'::numeric, NULL)".execute_result(&mut conn) {
+        Ok(_) => panic!("should reject a NaN bound"),
+        Err(err) => assert!(
+            err.to_string()
+                .contains("numeric bound NaN of field 'value_numeric' can't be converted"),
+            "{err}"
+        ),
+    };
+
+    match "SELECT paradedb.range('value_int4', 1, 2, bounds => '[[')".execute_result(&mut conn) {
+        Ok(_) => panic!("should reject invalid bounds"),
+        Err(err) => assert!(err.to_string().contains("invalid range bounds"), "{err}"),
+    };
+}
+
+#[rstest]
+fn fast_field_range(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE test_table (
+        id SERIAL PRIMARY KEY,
+        value_int4 INTEGER,
+        value_int8 BIGINT,
+        value_numeric NUMERIC,
+        value_date DATE,
+        value_timestamptz TIMESTAMP WITH TIME ZONE
+    );
+
+    INSERT INTO test_table (value_int4, value_int8, value_numeric, value_date, value_timestamptz) VALUES
+        (-1111, -11111111, -111.11111, DATE '2020-06-28', TIMESTAMP WITH TIME ZONE '2020-07-09 15:52:13 MST'),
+        (2222, 22222222, 222.22222, DATE '2021-04-30', TIMESTAMP WITH TIME ZONE '2021-06-08 08:49:21 CST'),
+        (3333, 33333333, 333.33333, DATE '2022-07-14', TIMESTAMP WITH TIME ZONE '2022-05-16 07:38:43 EST'),
+        (4444, 44444444, 444.44444, DATE '2023-05-03', TIMESTAMP WITH TIME ZONE '2023-04-15 13:27:09 PST');
+    "#
+    .execute(&mut conn);
+
+    r#"
+    CALL paradedb.create_bm25(
+        table_name => 'test_table',
+        index_name => 'test_index',
+        key_field => 'id',
+        numeric_fields => paradedb.field('value_int4') || paradedb.field('value_int8') || paradedb.field('value_numeric'),
+        datetime_fields => paradedb.field('value_date') || paradedb.field('value_timestamptz')
+    );
+    "#
+    .execute(&mut conn);
+
+    let search = |query: &str, conn: &mut PgConnection| -> Vec<(i32,)> {
+        format!(
+            "SELECT id FROM test_index.search(query => {query}, stable_sort => true) ORDER BY id"
+        )
+        .fetch_collect(conn)
+    };
+
+    let rows = search(
+        "paradedb.fast_field_range_weight('value_int4', '[-2000,3000)'::int4range)",
+        &mut conn,
+    );
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    let rows = search(
+        "paradedb.fast_field_range_weight('value_int8', '(22222222,)'::int8range)",
+        &mut conn,
+    );
+    assert_eq!(rows, vec![(3,), (4,)]);
+
+    let rows = search(
+        "paradedb.fast_field_range_weight('value_numeric', '[0,400)'::numrange)",
+        &mut conn,
+    );
+    assert_eq!(rows, vec![(2,), (3,)]);
+
+    let rows = search(
+        "paradedb.fast_field_range_weight('value_date', '[2020-05-20,2022-06-13]'::daterange)",
+        &mut conn,
+    );
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    let rows = search(
+        "paradedb.fast_field_range_weight('value_timestamptz', '[2020-07-09 17:52:13 EST, 2022-05-16 04:38:43 PST]'::tstzrange)",
+        &mut conn,
+    );
+    assert_eq!(rows, vec![(1,), (2,), (3,)]);
+
+    // An empty range matches nothing.
+    let rows = search(
+        "paradedb.fast_field_range_weight('value_int4', 'empty'::int4range)",
+        &mut conn,
+    );
+    assert_eq!(rows, vec![]);
+}
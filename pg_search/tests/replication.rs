@@ -270,3 +270,67 @@ async fn test_ephemeral_postgres() -> Result<()> {
 
     Ok(())
 }
+
+#[rstest]
+async fn test_logical_replication_initial_sync_and_resync() -> Result<()> {
+    let source_postgres = EphemeralPostgres::new();
+    let target_postgres = EphemeralPostgres::new();
+
+    let mut source_conn = source_postgres.connection().await?;
+    let mut target_conn = target_postgres.connection().await?;
+
+    "CREATE EXTENSION pg_search".execute(&mut source_conn);
+    "CREATE EXTENSION pg_search".execute(&mut target_conn);
+
+    let schema = "CREATE TABLE mock_items (id SERIAL PRIMARY KEY, description TEXT)";
+    schema.execute(&mut source_conn);
+    schema.execute(&mut target_conn);
+
+    // Rows that exist before the subscription are copied by the initial table sync.
+    "INSERT INTO mock_items (description) VALUES ('Red sports shoes'), ('Blue running shoes'), ('Green hat')"
+        .execute(&mut source_conn);
+
+    "CALL paradedb.create_bm25(
+        table_name => 'mock_items',
+        index_name => 'mock_items',
+        schema_name => 'public',
+        key_field => 'id',
+        text_fields => paradedb.field('description')
+    )"
+    .execute(&mut target_conn);
+
+    "CREATE PUBLICATION mock_items_pub FOR TABLE mock_items".execute(&mut source_conn);
+    format!(
+        "CREATE SUBSCRIPTION mock_items_sub
+         CONNECTION 'host={} port={} dbname={}'
+         PUBLICATION mock_items_pub;",
+        source_postgres.host, source_postgres.port, source_postgres.dbname
+    )
+    .execute(&mut target_conn);
+
+    // Wait for the initial table sync to complete
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let target_results: Vec<(String,)> =
+        "SELECT description FROM mock_items.search('description:shoes')".fetch(&mut target_conn);
+    assert_eq!(target_results.len(), 2);
+
+    // Changes applied after the sync reach the index too.
+    "INSERT INTO mock_items (description) VALUES ('White tennis shoes')".execute(&mut source_conn);
+    "DELETE FROM mock_items WHERE description = 'Red sports shoes'".execute(&mut source_conn);
+
+    std::thread::sleep(std::time::Duration::from_secs(1));
+    let target_results: Vec<(String,)> =
+        "SELECT description FROM mock_items.search('description:shoes', stable_sort => true)"
+            .fetch(&mut target_conn);
+    assert_eq!(target_results.len(), 2);
+
+    // Resyncing rebuilds the index from the table, with the same results.
+    let (rows,): (i64,) = "SELECT paradedb.resync_index('mock_items')".fetch_one(&mut target_conn);
+    assert_eq!(rows, 3);
+    let resynced_results: Vec<(String,)> =
+        "SELECT description FROM mock_items.search('description:shoes', stable_sort => true)"
+            .fetch(&mut target_conn);
+    assert_eq!(resynced_results, target_results);
+
+    Ok(())
+}
@@ -51,6 +51,180 @@ fn with_limit_and_offset(mut conn: PgConnection) {
     assert_eq!(rows.id, vec![2, 12]);
 }
 
+#[rstest]
+fn with_tied_pages(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // Every row rated 4 has the same score, so they're ordered by key, and pages of results
+    // line up without stable_sort.
+    let all: Vec<(i32,)> = "SELECT id FROM bm25_search.search('rating:4')".fetch(&mut conn);
+    let mut sorted = all.clone();
+    sorted.sort();
+    assert_eq!(all, sorted);
+
+    let mut paged: Vec<(i32,)> = vec![];
+    for page in 0..=(all.len() / 3) {
+        let rows: Vec<(i32,)> = format!(
+            "SELECT id FROM bm25_search.search('rating:4', limit_rows => 3, offset_rows => {})",
+            page * 3
+        )
+        .fetch(&mut conn);
+        paged.extend(rows);
+    }
+    assert_eq!(paged, all);
+}
+
+#[rstest]
+fn with_limit_and_exact(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // Skipping documents that can't make the top results must not change them.
+    for query in [
+        "description:shoes OR description:keyboard OR category:electronics",
+        "rating:4 OR rating:5",
+    ] {
+        for stable_sort in [true, false] {
+            let pruned: Vec<(i32, f32)> = format!(
+                "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('{query}', limit_rows => 3, offset_rows => 1, stable_sort => {stable_sort})"
+            )
+            .fetch(&mut conn);
+            let exact: Vec<(i32, f32)> = format!(
+                "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('{query}', limit_rows => 3, offset_rows => 1, stable_sort => {stable_sort}, exact => true)"
+            )
+            .fetch(&mut conn);
+
+            assert_eq!(pruned.len(), 3);
+            if stable_sort {
+                assert_eq!(pruned, exact);
+            } else {
+                let scores = |rows: &Vec<(i32, f32)>| {
+                    rows.iter().map(|(_, score)| *score).collect::<Vec<_>>()
+                };
+                assert_eq!(scores(&pruned), scores(&exact));
+            }
+        }
+    }
+}
+
+#[rstest]
+fn without_scoring(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let mut rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard OR category:electronics', scored => false)"
+            .fetch(&mut conn);
+    rows.sort();
+    assert_eq!(rows, vec![(1,), (2,), (12,), (22,), (32,)]);
+
+    // Every row gets the same score.
+    let rows: Vec<(i32, f32)> =
+        "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('description:keyboard OR category:electronics', scored => false)"
+            .fetch(&mut conn);
+    assert!(rows.iter().all(|(_, score)| *score == rows[0].1));
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard OR category:electronics', limit_rows => 2, scored => false)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 2);
+}
+
+#[rstest]
+fn with_search_threads(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let query = "SELECT * FROM bm25_search.search('category:electronics', stable_sort => true)";
+
+    "SET paradedb.search_threads = 1".execute(&mut conn);
+    let serial: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+
+    "SET paradedb.search_threads = 4".execute(&mut conn);
+    let parallel: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+
+    assert_eq!(serial.id, vec![1, 2, 12, 22, 32]);
+    assert_eq!(serial.id, parallel.id);
+}
+
+#[rstest]
+fn with_heap_prefetch(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let query = "SELECT * FROM bm25_search.search('category:electronics', stable_sort => true)";
+
+    "SET paradedb.heap_prefetch_distance = 0".execute(&mut conn);
+    let unprefetched: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+
+    // Prefetching reads pages in block order, but rows are still returned in score order.
+    "SET paradedb.heap_prefetch_distance = 2".execute(&mut conn);
+    let prefetched: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+
+    assert_eq!(unprefetched.id, vec![1, 2, 12, 22, 32]);
+    assert_eq!(unprefetched.id, prefetched.id);
+}
+
+#[rstest]
+fn with_slow_search_logging(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let query = "SELECT * FROM bm25_search.search('category:electronics', stable_sort => true)";
+
+    // Profiling every search doesn't change its results, with or without a limit.
+    "SET paradedb.log_min_search_duration = 0".execute(&mut conn);
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+
+    let rows: SimpleProductsTableVec =
+        "SELECT * FROM bm25_search.search('category:electronics', stable_sort => false, limit_rows => 2)"
+            .fetch_collect(&mut conn);
+    assert_eq!(rows.id.len(), 2);
+
+    "SET paradedb.log_min_search_duration = -1".execute(&mut conn);
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+}
+
+#[rstest]
+fn with_query_cache(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SET paradedb.query_cache_size = 10".execute(&mut conn);
+    let query = "SELECT * FROM bm25_search.search('category:electronics', stable_sort => true)";
+
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+
+    // A commit to the index invalidates the cached results.
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('New keyboard', 5, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    "DELETE FROM paradedb.bm25_search WHERE id = 1".execute(&mut conn);
+
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![2, 12, 22, 32, 42]);
+}
+
+#[rstest]
+fn with_query_plan_cache(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "SET paradedb.query_plan_cache_size = 10".execute(&mut conn);
+    let query = "SELECT * FROM bm25_search.search(
+        query => paradedb.boolean(
+            must => ARRAY[paradedb.parse('category:electronics')],
+            must_not => ARRAY[paradedb.term(field => 'description', value => 'monitor')]
+        ),
+        stable_sort => true
+    )";
+
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32]);
+
+    // The compiled query is reused by the next generation of the index, compiled again.
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('New keyboard', 5, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    let rows: SimpleProductsTableVec = query.fetch_collect(&mut conn);
+    assert_eq!(rows.id, vec![1, 2, 12, 22, 32, 42]);
+}
+
 #[rstest]
 fn default_tokenizer_config(mut conn: PgConnection) {
     "CALL paradedb.create_bm25_test_table(table_name => 'tokenizer_config', schema_name => 'paradedb')"
@@ -138,3 +312,486 @@ fn chinese_compatible_tokenizer_config(mut conn: PgConnection) {
 
     assert_eq!(rows[0], (42,));
 }
+
+#[rstest]
+fn with_total_hits(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Compact keyboard', 4, 'Electronics')"
+        .execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Wireless keyboard', 5, 'Electronics')"
+        .execute(&mut conn);
+
+    // The count covers every match, not just the page.
+    "BEGIN".execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:keyboard', limit_rows => 1, total_hits => 'exact')"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+    let (total, exact): (i64, bool) = "SELECT * FROM paradedb.total_hits()".fetch_one(&mut conn);
+    assert_eq!((total, exact), (4, true));
+    "COMMIT".execute(&mut conn);
+
+    // A lower bound stops counting at the first segment that reaches the threshold, so it
+    // can only be exact if no segment was left.
+    "BEGIN".execute(&mut conn);
+    "SELECT id FROM bm25_search.search('description:keyboard', limit_rows => 1, total_hits => 'lower_bound', total_hits_threshold => 1)"
+        .execute(&mut conn);
+    let (total, exact): (i64, bool) = "SELECT * FROM paradedb.total_hits()".fetch_one(&mut conn);
+    assert!((1..=4).contains(&total));
+    assert!(!exact || total == 4);
+    "COMMIT".execute(&mut conn);
+
+    match "SELECT * FROM paradedb.total_hits()".execute_result(&mut conn) {
+        Ok(_) => panic!("should only return the total hits of a search in the transaction"),
+        Err(err) => assert!(err.to_string().contains("total_hits"), "{err}"),
+    };
+}
+
+#[rstest]
+fn with_timeout(mut conn: PgConnection) {
+    "CREATE TABLE timeout_series (id SERIAL PRIMARY KEY, description TEXT)".execute(&mut conn);
+    "INSERT INTO timeout_series (description) SELECT 'Product ' || i FROM generate_series(1, 500000) i"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        table_name => 'timeout_series',
+        schema_name => 'public',
+        index_name => 'timeout_series',
+        key_field => 'id',
+        text_fields => paradedb.field('description')
+    )"
+    .execute(&mut conn);
+    // Scoring every match with stable sorting takes well over a millisecond.
+    let query = "SELECT id FROM timeout_series.search('description:product', limit_rows => 10, exact => true, stable_sort => true";
+
+    match format!("{query}, timeout_ms => 1)").execute_result(&mut conn) {
+        Ok(_) => panic!("search should exceed its timeout"),
+        Err(err) => assert!(err.to_string().contains("canceling bm25 search"), "{err}"),
+    };
+
+    "SET paradedb.search_timeout = 1".execute(&mut conn);
+    match format!("{query})").execute_result(&mut conn) {
+        Ok(_) => panic!("search should exceed the default timeout"),
+        Err(err) => assert!(err.to_string().contains("canceling bm25 search"), "{err}"),
+    };
+
+    // A search can lift the default timeout, or pick its own.
+    let rows: Vec<(i32,)> = format!("{query}, timeout_ms => 0)").fetch(&mut conn);
+    assert_eq!(rows.len(), 10);
+    let rows: Vec<(i32,)> = format!("{query}, timeout_ms => 600000)").fetch(&mut conn);
+    assert_eq!(rows.len(), 10);
+}
+
+#[rstest]
+fn with_sort_by(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Compact keyboard', 4, 'Electronics')"
+        .execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category) VALUES ('Wireless keyboard', 5, 'Electronics')"
+        .execute(&mut conn);
+
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM bm25_search.search(
+        'description:keyboard',
+        sort_by => '[{"field": "rating", "order": "desc"}, {"field": "id", "order": "asc"}]'
+    )"#
+    .fetch(&mut conn);
+    let ids: Vec<_> = rows.into_iter().map(|(id,)| id).collect();
+    assert_eq!(ids, vec![43, 1, 2, 42]);
+
+    // The order defaults to ascending, and offsets apply to the sorted results.
+    let rows: Vec<(i32,)> = r#"
+    SELECT id FROM bm25_search.search(
+        'description:keyboard',
+        sort_by => '[{"field": "rating"}, {"field": "id", "order": "desc"}]',
+        offset_rows => 1,
+        limit_rows => 2
+    )"#
+    .fetch(&mut conn);
+    let ids: Vec<_> = rows.into_iter().map(|(id,)| id).collect();
+    assert_eq!(ids, vec![2, 1]);
+
+    match r#"SELECT id FROM bm25_search.search('description:keyboard', sort_by => '[{"field": "color"}]')"#
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not sort by a field missing from the index"),
+        Err(err) => assert!(err.to_string().contains("sort field 'color'"), "{err}"),
+    };
+}
+
+#[rstest]
+fn with_random_seed(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    let shuffle = |seed: i64, conn: &mut PgConnection| -> Vec<(i32, f32)> {
+        format!(
+            "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('rating:[1 TO 5]', random_seed => {seed})"
+        )
+        .fetch(conn)
+    };
+
+    // The same seed always gives the same order, with scores between 0 and 1.
+    let first = shuffle(7, &mut conn);
+    assert_eq!(first.len(), 41);
+    assert_eq!(first, shuffle(7, &mut conn));
+    assert!(first.iter().all(|(_, score)| (0.0..1.0).contains(score)));
+    assert!(first.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+
+    let ids = |rows: Vec<(i32, f32)>| rows.into_iter().map(|(id, _)| id).collect::<Vec<_>>();
+    assert_ne!(ids(first.clone()), ids(shuffle(8, &mut conn)));
+
+    // Limits take a sample of the shuffled rows.
+    let sample: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('rating:[1 TO 5]', random_seed => 7, limit_rows => 5)"
+            .fetch(&mut conn);
+    assert_eq!(
+        sample.into_iter().map(|(id,)| id).collect::<Vec<_>>(),
+        ids(first)[..5]
+    );
+}
+
+#[rstest]
+fn with_tenant(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE tickets (id SERIAL PRIMARY KEY, org TEXT, org_id INT, body TEXT);
+    INSERT INTO tickets (org, org_id, body) VALUES
+        ('acme', 1, 'Keyboard is broken'),
+        ('acme', 1, 'Mouse is broken'),
+        ('globex', 2, 'Keyboard arrived late'),
+        ('initech', 3, 'Printer is broken');
+    CALL paradedb.create_bm25(
+        index_name => 'tickets_idx',
+        table_name => 'tickets',
+        key_field => 'id',
+        text_fields => paradedb.field('body') || paradedb.field('org', tokenizer => paradedb.tokenizer('raw')),
+        tenant_field => 'org'
+    );
+    CREATE TABLE tickets_by_id AS SELECT * FROM tickets;
+    ALTER TABLE tickets_by_id ADD PRIMARY KEY (id);
+    CALL paradedb.create_bm25(
+        index_name => 'tickets_by_id_idx',
+        table_name => 'tickets_by_id',
+        key_field => 'id',
+        text_fields => '{body: {}}',
+        numeric_fields => '{org_id: {}}',
+        tenant_field => 'org_id'
+    );
+    "#
+    .execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM tickets_idx.search('body:broken', tenant => 'acme', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM tickets_idx.search('body:keyboard', tenant => 'globex')".fetch(&mut conn);
+    assert_eq!(rows, vec![(3,)]);
+
+    // The tenant doesn't change the scores of its results.
+    let (tenant_score,): (f32,) = "
+    SELECT paradedb.rank_bm25(id) FROM tickets_idx.search('body:printer', tenant => 'initech')"
+        .fetch_one(&mut conn);
+    let (score,): (f32,) =
+        "SELECT paradedb.rank_bm25(id) FROM tickets_idx.search('body:printer')".fetch_one(&mut conn);
+    assert_eq!(tenant_score, score);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM tickets_by_id_idx.search('body:keyboard', tenant => '1')".fetch(&mut conn);
+    assert_eq!(rows, vec![(1,)]);
+
+    match "SELECT id FROM tickets_by_id_idx.search('body:keyboard', tenant => 'acme')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not search a tenant of the wrong type"),
+        Err(err) => assert!(err.to_string().contains("is not an integer"), "{err}"),
+    };
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'tickets_other_idx',
+        table_name => 'tickets',
+        key_field => 'id',
+        text_fields => '{body: {}}',
+        tenant_field => 'org'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only filter by an indexed field"),
+        Err(err) => assert!(err.to_string().contains("tenant_field must be a field"), "{err}"),
+    };
+
+    // Tokenized tenants wouldn't match as a single term.
+    match "CALL paradedb.create_bm25(
+        index_name => 'tickets_other_idx',
+        table_name => 'tickets',
+        key_field => 'id',
+        text_fields => '{body: {}, org: {}}',
+        tenant_field => 'org'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only filter by a text field with the raw tokenizer"),
+        Err(err) => assert!(err.to_string().contains("must use the raw tokenizer"), "{err}"),
+    };
+}
+
+#[rstest]
+fn with_audit_table(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+    "CREATE TABLE search_audit (searched_at timestamptz, user_name text, index_name text, query jsonb, rows_returned bigint)"
+        .execute(&mut conn);
+    "SET paradedb.audit_table = 'search_audit'".execute(&mut conn);
+
+    // Nothing is audited until a sample rate and the audited indexes are set.
+    "SELECT * FROM bm25_search.search('description:keyboard')".execute(&mut conn);
+    let (count,): (i64,) = "SELECT count(*) FROM search_audit".fetch_one(&mut conn);
+    assert_eq!(count, 0);
+
+    "SET paradedb.audit_sample_rate = 1".execute(&mut conn);
+    "SELECT * FROM bm25_search.search('description:keyboard')".execute(&mut conn);
+    let (count,): (i64,) = "SELECT count(*) FROM search_audit".fetch_one(&mut conn);
+    assert_eq!(count, 0);
+
+    // Then only the searches of the listed indexes are audited.
+    "SET paradedb.audit_indexes = 'other_index, bm25_search'".execute(&mut conn);
+    let rows: SimpleProductsTableVec =
+        "SELECT * FROM bm25_search.search('description:keyboard')".fetch_collect(&mut conn);
+    let (index_name, by_current_user, rows_returned): (String, bool, i64) =
+        "SELECT index_name, user_name = current_user, rows_returned FROM search_audit"
+            .fetch_one(&mut conn);
+    assert_eq!(index_name, "bm25_search_bm25_index");
+    assert!(by_current_user);
+    assert_eq!(rows_returned, rows.id.len() as i64);
+}
+
+#[rstest]
+fn with_security_filter(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE reports (id SERIAL PRIMARY KEY, team_id INT, body TEXT);
+    INSERT INTO reports (team_id, body) VALUES
+        (1, 'Quarterly revenue'),
+        (1, 'Revenue forecast'),
+        (2, 'Revenue by region'),
+        (3, 'Hiring plan');
+    CALL paradedb.create_bm25(
+        index_name => 'reports_idx',
+        table_name => 'reports',
+        key_field => 'id',
+        text_fields => '{body: {}}',
+        numeric_fields => '{team_id: {}}',
+        security_filter => 'team_id = current_setting(''app.team'', true)'
+    );
+    "#
+    .execute(&mut conn);
+
+    // Without a team, the filter has no value and nothing matches.
+    let rows: Vec<(i32,)> = "SELECT id FROM reports_idx.search('body:revenue')".fetch(&mut conn);
+    assert_eq!(rows, vec![]);
+
+    "SET app.team = '1'".execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM reports_idx.search('body:revenue', stable_sort => true)".fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    // Cached results of another team aren't reused.
+    "SET paradedb.query_cache_size = 10".execute(&mut conn);
+    "SET app.team = '2'".execute(&mut conn);
+    let rows: Vec<(i32,)> = "SELECT id FROM reports_idx.search('body:revenue')".fetch(&mut conn);
+    assert_eq!(rows, vec![(3,)]);
+
+    "ALTER INDEX reports_idx_bm25_index SET (security_filter = 'team_id IN (2, 3)')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM reports_idx.search('body:revenue OR body:hiring', stable_sort => true)"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![(3,), (4,)]);
+
+    match "ALTER INDEX reports_idx_bm25_index SET (security_filter = 'team_id LIKE 1')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only allow = and IN filters"),
+        Err(err) => assert!(err.to_string().contains("invalid security_filter"), "{err}"),
+    };
+
+    // Values are literals, the role names or settings, not SQL run by whoever searches.
+    match "ALTER INDEX reports_idx_bm25_index SET (security_filter = 'team_id = (SELECT 1)')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not allow queries as values"),
+        Err(err) => assert!(err.to_string().contains("invalid security_filter"), "{err}"),
+    };
+
+    // A field the index doesn't have is caught on the next use of the index.
+    "ALTER INDEX reports_idx_bm25_index SET (security_filter = 'missing_id = 1')"
+        .execute(&mut conn);
+    match "SELECT id FROM reports_idx.search('body:revenue')".execute_result(&mut conn) {
+        Ok(_) => panic!("should not filter by a missing field"),
+        Err(err) => assert!(err.to_string().contains("is not a field of the index"), "{err}"),
+    };
+    "ALTER INDEX reports_idx_bm25_index SET (security_filter = 'team_id IN (2, 3)')"
+        .execute(&mut conn);
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'reports_other_idx',
+        table_name => 'reports',
+        key_field => 'id',
+        text_fields => '{body: {}}',
+        security_filter => 'team_id = 1'
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only filter by an indexed field"),
+        Err(err) => assert!(err.to_string().contains("is not a field of the index"), "{err}"),
+    };
+}
+
+#[rstest]
+fn with_security_filter_terms(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE reports (id SERIAL PRIMARY KEY, team_id INT, body TEXT);
+    INSERT INTO reports (team_id, body) VALUES
+        (1, 'Quarterly revenue'),
+        (1, 'Revenue forecast'),
+        (2, 'Revenue by region'),
+        (3, 'Hiring plan');
+    CALL paradedb.create_bm25(
+        index_name => 'reports_idx',
+        table_name => 'reports',
+        key_field => 'id',
+        text_fields => '{body: {}}',
+        numeric_fields => '{team_id: {}}',
+        security_filter => 'team_id = current_setting(''app.team'', true)'
+    );
+    SET app.team = '1';
+    "#
+    .execute(&mut conn);
+
+    // Functions reading the terms of the index only see the documents of the filter.
+    let rows: Vec<(String, i64, i64)> =
+        "SELECT * FROM paradedb.top_terms('reports_idx', 'body', size => 10)".fetch(&mut conn);
+    let terms: Vec<&str> = rows.iter().map(|(term, _, _)| term.as_str()).collect();
+    assert_eq!(terms, vec!["revenue", "forecast", "quarterly"]);
+
+    let (doc_freq, num_docs): (i64, i64) =
+        "SELECT doc_freq, num_docs FROM paradedb.term_stats('reports_idx', 'body', 'hiring')"
+            .fetch_one(&mut conn);
+    assert_eq!((doc_freq, num_docs), (0, 2));
+
+    let rows: Vec<(String, i64)> =
+        "SELECT * FROM paradedb.field_values('reports_idx', 'body', prefix => 'r')"
+            .fetch(&mut conn);
+    assert_eq!(rows, vec![("revenue".into(), 2)]);
+
+    let rows: Vec<(String, f64)> =
+        "SELECT * FROM paradedb.suggest('reports_idx', 'body', 're')".fetch(&mut conn);
+    assert_eq!(rows, vec![("revenue".into(), 2.0)]);
+
+    // Offsets are only read from the results of a search, which the filter restricts.
+    match "SELECT o.* FROM reports_idx.search('body:revenue') s,
+            LATERAL paradedb.highlight_offsets(3 + 0 * s.id, 'body') o"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not find offsets of a row the filter hides"),
+        Err(err) => assert!(err.to_string().contains("could not find offsets"), "{err}"),
+    };
+}
+
+#[rstest]
+fn with_ranking_profile(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE articles (id SERIAL PRIMARY KEY, title TEXT, body TEXT, published_at TIMESTAMP);
+    INSERT INTO articles (title, body, published_at) VALUES
+        ('Keyboard review', 'A look at mechanical switches', now() - interval '30 days'),
+        ('Mechanical switches', 'A keyboard review of every switch', now());
+    CALL paradedb.create_bm25(
+        index_name => 'articles_idx',
+        table_name => 'articles',
+        key_field => 'id',
+        text_fields => '{title: {}, body: {}}',
+        datetime_fields => '{published_at: {fast: true}}'
+    );
+    "#
+    .execute(&mut conn);
+    let query = "SELECT id FROM articles_idx.search('title:keyboard OR body:keyboard'";
+
+    "SELECT paradedb.save_ranking_profile('articles_idx', 'titles', '{\"boosts\": {\"title\": 10}}')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> = format!("{query}, profile => 'titles')").fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    // Saving the profile again rolls out the new ranking, and a rollback restores the old one.
+    "SELECT paradedb.save_ranking_profile('articles_idx', 'titles', '{\"decay\": {\"field\": \"published_at\", \"scale_days\": 1}}')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> = format!("{query}, profile => 'titles')").fetch(&mut conn);
+    assert_eq!(rows, vec![(2,), (1,)]);
+
+    "SELECT paradedb.rollback_ranking_profile('articles_idx', 'titles')".execute(&mut conn);
+    let rows: Vec<(i32,)> = format!("{query}, profile => 'titles')").fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    match "SELECT paradedb.save_ranking_profile('articles_idx', 'other', '{\"decay\": {\"field\": \"title\", \"scale_days\": 1}}')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should only decay by a fast date field"),
+        Err(err) => assert!(err.to_string().contains("not a fast date field"), "{err}"),
+    };
+
+    let (dropped,): (bool,) =
+        "SELECT paradedb.drop_ranking_profile('articles_idx', 'titles')".fetch_one(&mut conn);
+    assert!(dropped);
+    match format!("{query}, profile => 'titles')").execute_result(&mut conn) {
+        Ok(_) => panic!("should not search with a dropped profile"),
+        Err(err) => assert!(err.to_string().contains("has no ranking profile"), "{err}"),
+    };
+}
+
+#[rstest]
+fn with_field_groups(mut conn: PgConnection) {
+    r#"
+    CREATE TABLE articles (id SERIAL PRIMARY KEY, title TEXT, body TEXT, tags TEXT);
+    INSERT INTO articles (title, body, tags) VALUES
+        ('Keyboard review', 'A look at mechanical switches', 'hardware'),
+        ('Mechanical switches', 'A keyboard review of every switch', 'hardware'),
+        ('Desk setup', 'Monitors and lamps', 'keyboard');
+    CALL paradedb.create_bm25(
+        index_name => 'articles_idx',
+        table_name => 'articles',
+        key_field => 'id',
+        text_fields => '{title: {}, body: {}, tags: {}}'
+    );
+    "#
+    .execute(&mut conn);
+
+    // `_all` searches every text field.
+    let rows: Vec<(i32,)> = "SELECT id FROM articles_idx.search(
+        query => paradedb.term(field => '_all', value => 'keyboard'), stable_sort => true
+    ) ORDER BY id"
+        .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,), (3,)]);
+
+    // A group only searches its members, and ranks them by their boosts.
+    "SELECT paradedb.save_field_group('articles_idx', 'content', '{\"title\": 10, \"body\": 1}')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> = "SELECT id FROM articles_idx.search(
+        query => paradedb.term(field => 'content', value => 'keyboard'), stable_sort => true
+    )"
+    .fetch(&mut conn);
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    match "SELECT paradedb.save_field_group('articles_idx', 'title', '{\"body\": 1}')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not name a group after a field"),
+        Err(err) => assert!(err.to_string().contains("already searches it"), "{err}"),
+    };
+
+    let (dropped,): (bool,) =
+        "SELECT paradedb.drop_field_group('articles_idx', 'content')".fetch_one(&mut conn);
+    assert!(dropped);
+    match "SELECT id FROM articles_idx.search(
+        query => paradedb.term(field => 'content', value => 'keyboard')
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should not search a dropped group"),
+        Err(err) => assert!(err.to_string().contains("content"), "{err}"),
+    };
+}
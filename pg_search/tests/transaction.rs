@@ -0,0 +1,425 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+mod fixtures;
+
+use async_std::task::block_on;
+use fixtures::*;
+use pretty_assertions::assert_eq;
+use rstest::*;
+use sqlx::PgConnection;
+
+fn connect(database: &Db) -> PgConnection {
+    block_on(async { database.connection().await })
+}
+
+#[rstest]
+fn uncommitted_insert_not_visible_to_other_connections(database: Db) {
+    let mut conn = connect(&database);
+    "CREATE EXTENSION pg_search".execute(&mut conn);
+    SimpleProductsTable::setup().execute(&mut conn);
+    let mut other_conn = connect(&database);
+
+    "BEGIN".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Uncommitted teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+
+    // The owning transaction can read its own writes.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+
+    // Other connections can't see the row until the transaction commits.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut other_conn);
+    assert_eq!(rows.len(), 0);
+
+    "COMMIT".execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut other_conn);
+    assert_eq!(rows.len(), 1);
+}
+
+#[rstest]
+fn repeatable_read_pins_index_generation(database: Db) {
+    let mut conn = connect(&database);
+    "CREATE EXTENSION pg_search".execute(&mut conn);
+    SimpleProductsTable::setup().execute(&mut conn);
+    let mut other_conn = connect(&database);
+
+    "BEGIN ISOLATION LEVEL REPEATABLE READ".execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 0);
+
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Concurrent teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut other_conn);
+
+    // The transaction keeps searching the generation of the index it started with.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 0);
+    "COMMIT".execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+}
+
+#[rstest]
+fn aborted_insert_never_visible(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "BEGIN".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Aborted teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    "ROLLBACK".execute(&mut conn);
+
+    let rows: Vec<(i32, f32)> =
+        "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('description:teapot')"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 0);
+}
+
+#[rstest]
+fn rollback_to_savepoint_discards_inserts(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "BEGIN".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Committed teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    "SAVEPOINT before_rollback".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Rolled back teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+    "ROLLBACK TO SAVEPOINT before_rollback".execute(&mut conn);
+
+    let rows: Vec<(i32, f32)> =
+        "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('description:teapot')"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+
+    "COMMIT".execute(&mut conn);
+
+    // Only the document inserted outside of the savepoint reaches the index.
+    let rows: Vec<(i32, f32)> =
+        "SELECT id, paradedb.rank_bm25(id) FROM bm25_search.search('description:teapot')"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+}
+
+#[rstest]
+fn truncate_is_transactional(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let count_query = "SELECT id FROM bm25_search.search('description:keyboard')";
+    let before: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert!(!before.is_empty());
+
+    "BEGIN".execute(&mut conn);
+    "TRUNCATE paradedb.bm25_search".execute(&mut conn);
+    "ROLLBACK".execute(&mut conn);
+
+    // Rolling back the TRUNCATE keeps the existing documents.
+    let rows: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert_eq!(rows.len(), before.len());
+
+    "TRUNCATE paradedb.bm25_search".execute(&mut conn);
+    let rows: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert_eq!(rows.len(), 0);
+}
+
+#[rstest]
+fn truncate_rolled_back_to_savepoint(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    let count_query = "SELECT id FROM bm25_search.search('description:keyboard')";
+    let before: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert!(!before.is_empty());
+
+    "BEGIN".execute(&mut conn);
+    "SAVEPOINT before_truncate".execute(&mut conn);
+    "TRUNCATE paradedb.bm25_search".execute(&mut conn);
+    "ROLLBACK TO SAVEPOINT before_truncate".execute(&mut conn);
+    "COMMIT".execute(&mut conn);
+
+    // The index is back on the file node it had before the TRUNCATE, which is kept.
+    let rows: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert_eq!(rows.len(), before.len());
+}
+
+#[rstest]
+fn truncate_survives_background_commit(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'truncated_items', schema_name => 'public')"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'truncated_items',
+        table_name => 'truncated_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        refresh_interval => 100
+    )"
+    .execute(&mut conn);
+
+    let count_query = "SELECT id FROM truncated_items.search('description:keyboard')";
+    let before: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert!(!before.is_empty());
+
+    // The index is committed in the background while the TRUNCATE is still uncommitted.
+    "BEGIN".execute(&mut conn);
+    "TRUNCATE truncated_items".execute(&mut conn);
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    "ROLLBACK".execute(&mut conn);
+
+    let rows: Vec<(i32,)> = count_query.fetch(&mut conn);
+    assert_eq!(rows.len(), before.len());
+}
+
+#[rstest]
+fn bulk_delete_removed_on_vacuum(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "DELETE FROM paradedb.bm25_search WHERE category = 'Electronics'".execute(&mut conn);
+    "VACUUM paradedb.bm25_search".execute(&mut conn);
+
+    let rows: Vec<(String,)> =
+        "SELECT category FROM bm25_search.search('description:keyboard OR category:electronics')"
+            .fetch(&mut conn);
+    assert!(rows.iter().all(|(category,)| category != "Electronics"));
+}
+
+#[rstest]
+fn bulk_insert_batches_flushed_at_command_end(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // A batch size smaller than the number of inserted rows, so that some rows are
+    // buffered mid-command and the rest when the command ends.
+    "SET paradedb.insert_batch_size = 7".execute(&mut conn);
+    "BEGIN".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        SELECT 'Bulk teapot', 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10'
+        FROM generate_series(1, 20)"
+        .execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot', limit_rows => 100)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 20);
+
+    "COMMIT".execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot', limit_rows => 100)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 20);
+}
+
+#[rstest]
+fn pending_inserts_spill_past_memory_limit(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // The transaction buffers more than the limit, so its rows are moved to disk.
+    "SET paradedb.pending_inserts_memory_limit = 1".execute(&mut conn);
+    "BEGIN".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        SELECT 'spilled ' || (SELECT string_agg(md5(i::text || '-' || j::text), ' ') FROM generate_series(1, 100) j), 5, 'Kitchen', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10'
+        FROM generate_series(1, 1000) i"
+        .execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:spilled', limit_rows => 2000)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 1000);
+
+    "COMMIT".execute(&mut conn);
+
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:spilled', limit_rows => 2000)"
+            .fetch(&mut conn);
+    assert_eq!(rows.len(), 1000);
+}
+
+#[rstest]
+fn pending_inserts_scored_with_index_statistics(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    // A buffered copy of a committed row scores the same as the committed row.
+    "BEGIN".execute(&mut conn);
+    "INSERT INTO paradedb.bm25_search (description, rating, category, in_stock, metadata, created_at, last_updated_date, latest_available_time)
+        VALUES ('Ergonomic metal keyboard', 4, 'Electronics', true, '{}', TIMESTAMP '2023-05-04 11:09:12', DATE '2023-05-06', TIME '10:07:10')"
+        .execute(&mut conn);
+
+    let rows: Vec<(String, f32)> =
+        "SELECT description, paradedb.rank_bm25(id) FROM bm25_search.search('description:keyboard', limit_rows => 100)"
+            .fetch(&mut conn);
+    let scores: Vec<f32> = rows
+        .into_iter()
+        .filter(|(description, _)| description == "Ergonomic metal keyboard")
+        .map(|(_, score)| score)
+        .collect();
+    assert_eq!(scores.len(), 2);
+    assert!((scores[0] - scores[1]).abs() < 1e-6);
+
+    "ROLLBACK".execute(&mut conn);
+}
+
+#[rstest]
+fn refresh_interval_commits_in_background(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'async_items', schema_name => 'public')"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'async_items',
+        table_name => 'async_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        refresh_interval => 100
+    )"
+    .execute(&mut conn);
+
+    "INSERT INTO async_items (description, rating, category) VALUES ('Eventual teapot', 5, 'Kitchen')"
+        .execute(&mut conn);
+
+    // The insert is acknowledged right away, and becomes searchable within the interval.
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM async_items.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+}
+
+#[rstest]
+fn refresh_index_commits_right_away(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'refreshed_items', schema_name => 'public')"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'refreshed_items',
+        table_name => 'refreshed_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        refresh_interval => 600000
+    )"
+    .execute(&mut conn);
+
+    let (opstamp,): (i64,) =
+        "SELECT opstamp FROM paradedb.index_generation('refreshed_items')".fetch_one(&mut conn);
+    "INSERT INTO refreshed_items (description, rating, category) VALUES ('Refreshed teapot', 5, 'Kitchen')"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM refreshed_items.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 0);
+
+    // The insert is searchable as soon as the index is refreshed, long before the interval.
+    let (refreshed,): (i64,) =
+        "SELECT paradedb.refresh_index('refreshed_items')".fetch_one(&mut conn);
+    assert!(refreshed > opstamp);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM refreshed_items.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 1);
+}
+
+#[rstest]
+fn writer_memory_budget_commits_early(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'budget_items', schema_name => 'public')"
+        .execute(&mut conn);
+
+    match "CALL paradedb.create_bm25(
+        index_name => 'budget_items',
+        table_name => 'budget_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        writer_memory_budget => 1
+    )"
+    .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("should fail without a refresh_interval"),
+        Err(err) => assert!(err.to_string().contains("requires a refresh_interval")),
+    };
+
+    "CALL paradedb.create_bm25(
+        index_name => 'budget_items',
+        table_name => 'budget_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        refresh_interval => 600000,
+        writer_memory_budget => 1
+    )"
+    .execute(&mut conn);
+
+    // More than a megabyte of documents is committed without waiting for the interval.
+    "INSERT INTO budget_items (description, rating, category)
+        SELECT repeat('Budget teapot ', 10000), 5, 'Kitchen' FROM generate_series(1, 20)"
+        .execute(&mut conn);
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM budget_items.search('description:teapot')".fetch(&mut conn);
+    assert_eq!(rows.len(), 20);
+}
+
+#[rstest]
+fn max_index_size_fails_inserts(mut conn: PgConnection) {
+    "CALL paradedb.create_bm25_test_table(table_name => 'sized_items', schema_name => 'public')"
+        .execute(&mut conn);
+    "CALL paradedb.create_bm25(
+        index_name => 'sized_items',
+        table_name => 'sized_items',
+        key_field => 'id',
+        text_fields => '{description: {}}',
+        max_index_size => 1
+    )"
+    .execute(&mut conn);
+
+    // Hashes barely compress, so this takes up more than a megabyte once committed.
+    "INSERT INTO sized_items (description, rating, category)
+        SELECT (SELECT string_agg(md5(i::text || '-' || j::text), ' ') FROM generate_series(1, 100) j), 5, 'Kitchen'
+        FROM generate_series(1, 1000) i"
+        .execute(&mut conn);
+
+    match "INSERT INTO sized_items (description, rating, category) VALUES ('Oversized teapot', 5, 'Kitchen')"
+        .execute_result(&mut conn)
+    {
+        Ok(_) => panic!("inserts should fail once the index is over its max_index_size"),
+        Err(err) => assert!(err.to_string().contains("max_index_size of 1 MB")),
+    };
+
+    // The index can still be searched.
+    let rows: Vec<(i32,)> =
+        "SELECT id FROM sized_items.search('description:shoes')".fetch(&mut conn);
+    assert_eq!(rows.len(), 3);
+}
+
+#[rstest]
+fn upsert_replaces_previous_version(mut conn: PgConnection) {
+    SimpleProductsTable::setup().execute(&mut conn);
+
+    "INSERT INTO paradedb.bm25_search (id, description, rating, category) VALUES (1, 'Upserted teapot', 5, 'Kitchen')
+        ON CONFLICT (id) DO UPDATE SET description = EXCLUDED.description"
+        .execute(&mut conn);
+    "UPDATE paradedb.bm25_search SET description = 'Updated teapot' WHERE id = 2"
+        .execute(&mut conn);
+
+    // The old versions are gone from the index right away, without waiting for a vacuum.
+    let mut rows: Vec<(i32,)> =
+        "SELECT id FROM bm25_search.search('description:teapot')".fetch(&mut conn);
+    rows.sort();
+    assert_eq!(rows, vec![(1,), (2,)]);
+
+    let rows: Vec<(i32,)> = "SELECT id FROM bm25_search.search('id:1 OR id:2')".fetch(&mut conn);
+    assert_eq!(rows.len(), 2);
+}
@@ -0,0 +1,107 @@
+// Copyright (c) 2023-2024 Retake, Inc.
+//
+// This file is part of ParadeDB - Postgres for Search and Analytics
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <http://www.gnu.org/licenses/>.
+
+use super::db::*;
+use async_std::task::block_on;
+use sqlx::PgConnection;
+use std::time::Duration;
+
+/// How long Postgres is given to restart and recover after a crash.
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A crash-recovery test of a bm25 index, for pg_search builds with the `fault_injection`
+/// feature. It injects a fault at a point of the write path of the index, runs a workload
+/// that reaches it, and then checks that the index agrees with its table once Postgres
+/// recovered. Points and actions are those of `paradedb.inject_fault`.
+pub struct CrashTest<'a> {
+    /// The name of the index, as passed to `paradedb.create_bm25`.
+    pub index_name: &'a str,
+    /// The table of the index, qualified with its schema.
+    pub table_name: &'a str,
+    pub key_field: &'a str,
+    pub point: &'a str,
+    pub action: &'a str,
+}
+
+impl CrashTest<'_> {
+    /// Inject the fault and run `workload`. The connection doesn't survive a crash, so a new
+    /// one is returned, along with whether the workload failed.
+    pub fn run(
+        &self,
+        database: &Db,
+        mut connection: PgConnection,
+        workload: &str,
+    ) -> (PgConnection, bool) {
+        format!(
+            "SELECT paradedb.inject_fault('{}', '{}', '{}')",
+            self.index_name, self.point, self.action
+        )
+        .execute(&mut connection);
+
+        let failed = workload.execute_result(&mut connection).is_err();
+        drop(connection);
+
+        let mut connection = block_on(database.connection_after_recovery(RECOVERY_TIMEOUT));
+        let pending: Vec<(String, String)> =
+            format!("SELECT * FROM paradedb.index_faults('{}')", self.index_name)
+                .fetch(&mut connection);
+        assert!(
+            pending.is_empty(),
+            "the workload didn't reach fault point {}",
+            self.point
+        );
+        (connection, failed)
+    }
+
+    /// Assert that the rows of the table matching `predicate` are exactly the rows the index
+    /// finds for `query`, and that `paradedb.check_index` finds no damaged files and no
+    /// rows missing from the index. The search also rebuilds the index first if the crash
+    /// left it behind its table.
+    pub fn assert_consistent(&self, connection: &mut PgConnection, query: &str, predicate: &str) {
+        let key_field = self.key_field;
+        let indexed: Vec<(String,)> = format!(
+            "SELECT {key_field}::text FROM {}.search('{query}') ORDER BY 1",
+            self.index_name
+        )
+        .fetch(connection);
+        let stored: Vec<(String,)> = format!(
+            "SELECT {key_field}::text FROM {} WHERE {predicate} ORDER BY 1",
+            self.table_name
+        )
+        .fetch(connection);
+        assert_eq!(
+            indexed, stored,
+            "index and table disagree after a fault at {}",
+            self.point
+        );
+
+        // Documents of transactions that aborted stay in the index until the table is
+        // vacuumed, so the document count of the index can be ahead of the table.
+        format!("ANALYZE {}", self.table_name).execute(connection);
+        let failed: Vec<(String, String)> = format!(
+            "SELECT check, detail FROM paradedb.check_index('{}', sample_size => 1000) \
+             WHERE status = 'failed' AND check <> 'doc_count'",
+            self.index_name
+        )
+        .fetch(connection);
+        assert!(
+            failed.is_empty(),
+            "index damaged after a fault at {}: {failed:?}",
+            self.point
+        );
+    }
+}
@@ -26,7 +26,7 @@ use sqlx::{
     testing::{TestArgs, TestContext, TestSupport},
     ConnectOptions, Decode, Executor, FromRow, PgConnection, Postgres, Type,
 };
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 pub struct Db {
     context: TestContext<Postgres>,
@@ -56,6 +56,21 @@ impl Db {
             .await
             .unwrap_or_else(|err| panic!("failed to connect to test database: {err:#?}"))
     }
+
+    /// Connect to the test database once Postgres accepts connections again, as after a
+    /// backend crashed and every other backend was restarted to recover.
+    pub async fn connection_after_recovery(&self, timeout: Duration) -> PgConnection {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match self.context.connect_opts.connect().await {
+                Ok(connection) => return connection,
+                Err(err) if Instant::now() >= deadline => {
+                    panic!("test database did not recover after {timeout:?}: {err:#?}")
+                }
+                Err(_) => async_std::task::sleep(Duration::from_millis(100)).await,
+            }
+        }
+    }
 }
 
 impl Drop for Db {
@@ -16,6 +16,7 @@
 // along with this program. If not, see <http://www.gnu.org/licenses/>.
 
 pub mod arrow;
+pub mod crash;
 pub mod db;
 pub mod tables;
 pub mod utils;
@@ -36,6 +36,9 @@ static TRANSACTION_CALL_ONCE_ON_COMMIT_CACHE: TransactionCallbackCache =
 static TRANSACTION_CALL_ONCE_ON_ABORT_CACHE: TransactionCallbackCache =
     Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
 
+static TRANSACTION_CALL_ONCE_ON_PRE_PREPARE_CACHE: TransactionCallbackCache =
+    Lazy::new(|| Arc::new(Mutex::new(HashSet::new())));
+
 pub struct Transaction {}
 
 impl Transaction {
@@ -58,6 +61,10 @@ impl Transaction {
             .clone()
             .lock()?
             .remove(id);
+        TRANSACTION_CALL_ONCE_ON_PRE_PREPARE_CACHE
+            .clone()
+            .lock()?
+            .remove(id);
         Ok(())
     }
 
@@ -133,6 +140,29 @@ impl Transaction {
 
         Ok(())
     }
+
+    pub fn call_once_on_pre_prepare<F>(id: String, callback: F) -> Result<(), TransactionError>
+    where
+        F: FnOnce() + Send + UnwindSafe + RefUnwindSafe + 'static,
+    {
+        let mut cache = TRANSACTION_CALL_ONCE_ON_PRE_PREPARE_CACHE.lock()?;
+        if !cache.contains(&id) {
+            let cloned_id = id.clone();
+            register_xact_callback(PgXactCallbackEvent::PrePrepare, move || {
+                // A prepared transaction is detached from this backend, and neither the
+                // precommit, commit nor abort events will fire for it here. Clear every
+                // cache so callbacks can be registered on next transaction.
+                Self::clear_commit_abort_caches(&cloned_id)
+                    .expect("could not acquire lock in register transaction preprepare callback");
+                // Actually call the callback.
+                callback();
+            });
+
+            cache.insert(id);
+        }
+
+        Ok(())
+    }
 }
 
 #[derive(Error, Debug)]